@@ -0,0 +1,624 @@
+//! Translating the HIR for a single POU into C statements.
+
+use crate::items::{
+    integer_type_name, unsigned_type_name, FieldType, Items, PouKind, Storage,
+};
+use crate::{CodegenError, Writer};
+use iec::analysis::reachable_blocks;
+use iec::codegen::{
+    BoolOp, Comparison, IntegerOp, Lower, RealOp, Tables, Trap, Typed,
+};
+use iec::const_eval::{IntegerType, Scalar};
+use iec::ecs::EntityId;
+use iec::hir::{Symbol, Terminator};
+use iec::layout::VariableType;
+use iec::passes::register_builtins::Shift;
+use std::collections::HashSet;
+
+/// Write out the definition of a POU's function.
+pub(crate) fn define(
+    out: &mut Writer,
+    tables: &Tables<'_>,
+    items: &Items,
+    index: usize,
+) -> Result<(), CodegenError> {
+    let pou = &items.pous()[index];
+
+    match pou.kind {
+        PouKind::Program => {
+            out.line(format_args!("uint32_t {}(void) {{", pou.function_name()));
+            out.indent();
+            out.line(format_args!(
+                "{} *self = &{};",
+                pou.type_name(),
+                pou.instance_name()
+            ));
+            out.line("(void)self;");
+        }
+        PouKind::Function | PouKind::FunctionBlock => {
+            out.line(format_args!(
+                "uint32_t {}({} *self) {{",
+                pou.function_name(),
+                pou.type_name()
+            ));
+            out.indent();
+        }
+    }
+
+    let mut translator = Translator {
+        out: &mut Writer::default(),
+        tables,
+        items,
+        unread: HashSet::new(),
+    };
+    let order = reachable_blocks(pou.entry_block, tables.blocks);
+
+    // optimisations can leave temporaries which are written to but never
+    // read, so we skip them entirely
+    let read = translator.read_variables(&order);
+    let temporaries: Vec<_> = pou
+        .temporaries
+        .iter()
+        .map(|t| items.location(t.variable))
+        .filter(|t| read.contains(&t.name))
+        .collect();
+    for temporary in &temporaries {
+        out.line(format_args!(
+            "{} {} = {};",
+            items.type_name(temporary.ty),
+            temporary.name,
+            zero(temporary.ty)
+        ));
+    }
+    if pou.kind == PouKind::Program || !temporaries.is_empty() {
+        out.blank();
+    }
+
+    let mut body = Writer::default();
+    body.indent();
+    translator.out = &mut body;
+    translator.unread = pou
+        .temporaries
+        .iter()
+        .map(|t| items.location(t.variable).name.clone())
+        .filter(|name| !read.contains(name))
+        .collect();
+    translator.body(&order)?;
+    out.raw(&body.finish());
+
+    out.dedent();
+    out.line("}");
+
+    Ok(())
+}
+
+fn zero(ty: FieldType) -> &'static str {
+    match ty {
+        FieldType::Bool => "false",
+        FieldType::Integer(_) => "0",
+        FieldType::Real => "0.0",
+        FieldType::Instance(_) => "{0}",
+    }
+}
+
+/// A C expression and its type.
+///
+/// To avoid precedence issues, the code is always either a (possibly
+/// dotted) variable name or wrapped in parentheses.
+#[derive(Debug, Clone, PartialEq)]
+struct Expr {
+    code: String,
+    ty: FieldType,
+}
+
+impl Expr {
+    fn new<S: Into<String>>(code: S, ty: FieldType) -> Expr {
+        Expr {
+            code: code.into(),
+            ty,
+        }
+    }
+}
+
+/// An integer as a `uint64_t`, using C's modular conversion rules (i.e.
+/// sign extend signed integers and zero extend unsigned ones).
+fn wide(code: &str) -> String {
+    format!("(uint64_t){}", code)
+}
+
+struct Translator<'t, 'a> {
+    out: &'t mut Writer,
+    tables: &'t Tables<'a>,
+    items: &'t Items,
+    /// Local variables which are never read.
+    unread: HashSet<String>,
+}
+
+impl<'t, 'a> Translator<'t, 'a> {
+    fn body(&mut self, order: &[EntityId]) -> Result<(), CodegenError> {
+        // only blocks we actually jump to get a label, otherwise the C
+        // compiler complains about unused labels
+        let mut labelled = HashSet::new();
+        for (i, &block) in order.iter().enumerate() {
+            let next = order.get(i + 1).cloned();
+            let terminator = &self.tables.block(block).terminator;
+            labelled.extend(goto_targets(terminator, next));
+        }
+
+        for (i, &block) in order.iter().enumerate() {
+            if labelled.contains(&block) {
+                self.out.label(format_args!("{}:", label(order, block)));
+            }
+
+            let next = order.get(i + 1).cloned();
+            self.block(order, block, next)?;
+        }
+
+        Ok(())
+    }
+
+    /// The names of every variable read by the reachable blocks.
+    fn read_variables(&self, order: &[EntityId]) -> HashSet<String> {
+        let mut read = HashSet::new();
+
+        for &block in order {
+            let block = self.tables.block(block);
+
+            for &instruction in &block.instructions {
+                let instruction = self
+                    .tables
+                    .instructions
+                    .get(instruction)
+                    .expect("blocks only contain known instructions");
+                read.extend(instruction.reads());
+            }
+            if let Terminator::Branch { condition, .. } = block.terminator {
+                read.extend(condition.variable());
+            }
+        }
+
+        read.into_iter()
+            .map(|id| self.items.location(id).name.clone())
+            .collect()
+    }
+
+    fn block(
+        &mut self,
+        order: &[EntityId],
+        id: EntityId,
+        next: Option<EntityId>,
+    ) -> Result<(), CodegenError> {
+        let block = self.tables.block(id);
+        self.instructions(block)?;
+
+        match block.terminator {
+            Terminator::Jump(target) => {
+                if next != Some(target) {
+                    self.goto(order, target);
+                }
+            }
+            Terminator::Branch {
+                condition,
+                then,
+                otherwise,
+            } => {
+                let condition = self.condition(condition)?;
+
+                if next == Some(then) {
+                    self.out.line(format_args!("if (!{}) {{", condition));
+                    self.out.indent();
+                    self.goto(order, otherwise);
+                    self.out.dedent();
+                    self.out.line("}");
+                } else {
+                    self.out.line(format_args!("if ({}) {{", condition));
+                    self.out.indent();
+                    self.goto(order, then);
+                    self.out.dedent();
+                    self.out.line("}");
+                    if next != Some(otherwise) {
+                        self.goto(order, otherwise);
+                    }
+                }
+            }
+            Terminator::Return => self.out.line("return IEC_OK;"),
+        }
+
+        Ok(())
+    }
+
+    fn goto(&mut self, order: &[EntityId], target: EntityId) {
+        self.out
+            .line(format_args!("goto {};", label(order, target)));
+    }
+
+    /// Write `dest = value;`, unless `dest` is never read.
+    fn assign(&mut self, dest: &Expr, value: &str) {
+        if !self.unread.contains(&dest.code) {
+            self.out.line(format_args!("{} = {};", dest.code, value));
+        }
+    }
+
+    /// Return `status` if `condition` is true.
+    fn return_if(&mut self, condition: &str, status: &str) {
+        self.out
+            .line(format_args!("if ({}) {{", unparenthesise(condition)));
+        self.out.indent();
+        self.out.line(format_args!("return {};", status));
+        self.out.dedent();
+        self.out.line("}");
+    }
+}
+
+impl<'t, 'a> Lower<'a> for Translator<'t, 'a> {
+    type Value = String;
+    type Place = Expr;
+
+    fn tables(&self) -> &Tables<'a> {
+        self.tables
+    }
+
+    fn place(&mut self, variable: EntityId) -> Expr {
+        let location = self.items.location(variable);
+
+        let code = match location.storage {
+            Storage::Field => format!("self->{}", location.name),
+            Storage::Global => format!("iec_globals.{}", location.name),
+            Storage::Local => location.name.clone(),
+        };
+
+        Expr::new(code, location.ty)
+    }
+
+    fn member(&mut self, object: &Expr, member: EntityId) -> Expr {
+        let location = self.items.location(member);
+        Expr::new(format!("{}.{}", object.code, location.name), location.ty)
+    }
+
+    fn place_type(&self, place: &Expr) -> VariableType {
+        place.ty.into()
+    }
+
+    fn load(&mut self, place: &Expr, _ty: Scalar) -> String {
+        place.code.clone()
+    }
+
+    fn store(&mut self, place: &Expr, value: &Typed<String>) {
+        self.assign(place, &value.value);
+    }
+
+    fn copy(&mut self, dest: &Expr, src: &Expr) {
+        self.assign(dest, &src.code);
+    }
+
+    fn boolean(&mut self, value: bool) -> String {
+        value.to_string()
+    }
+
+    fn integer(&mut self, value: i64, ty: IntegerType) -> String {
+        match ty.bits() {
+            64 if value == i64::MIN => String::from("INT64_MIN"),
+            64 => format!("INT64_C({})", value),
+            _ => format!("(({}){})", integer_type_name(ty), value),
+        }
+    }
+
+    fn real(&mut self, value: f64) -> String {
+        format!("({:?})", value)
+    }
+
+    fn resize(&mut self, value: &Typed<String>, to: IntegerType) -> String {
+        format!("(({}){})", integer_type_name(to), value.value)
+    }
+
+    fn int_to_real(&mut self, value: &Typed<String>) -> String {
+        format!("((double){})", value.value)
+    }
+
+    fn is_zero(&mut self, value: &Typed<String>) -> String {
+        match value.ty {
+            Scalar::Real => format!("({} == 0.0)", value.value),
+            _ => format!("({} == 0)", value.value),
+        }
+    }
+
+    fn is_negative(&mut self, value: &Typed<String>) -> String {
+        format!("({} < 0)", value.value)
+    }
+
+    fn trap_if(&mut self, condition: String, trap: Trap) {
+        let status = match trap {
+            Trap::DivisionByZero => "IEC_DIVISION_BY_ZERO",
+            Trap::NegativeExponent => "IEC_NEGATIVE_EXPONENT",
+            Trap::InvalidOperands => "IEC_INVALID_OPERANDS",
+        };
+        self.return_if(&condition, status);
+    }
+
+    /// Integers are compared as 64-bit values so C's promotion rules don't
+    /// get involved.
+    fn compare_integers(
+        &mut self,
+        comparison: Comparison,
+        left: &Typed<String>,
+        right: &Typed<String>,
+        unsigned: bool,
+    ) -> String {
+        let cast = if unsigned { "uint64_t" } else { "int64_t" };
+        format!(
+            "(({}){} {} ({}){})",
+            cast,
+            left.value,
+            operator(comparison),
+            cast,
+            right.value
+        )
+    }
+
+    fn compare_reals(
+        &mut self,
+        comparison: Comparison,
+        left: &String,
+        right: &String,
+    ) -> String {
+        format!("({} {} {})", left, operator(comparison), right)
+    }
+
+    fn bool_binary(
+        &mut self,
+        op: BoolOp,
+        left: &String,
+        right: &String,
+    ) -> String {
+        let symbol = match op {
+            BoolOp::And => "&&",
+            BoolOp::Or => "||",
+            BoolOp::Xor | BoolOp::NotEqual => "!=",
+            BoolOp::Equal => "==",
+        };
+
+        format!("({} {} {})", left, symbol, right)
+    }
+
+    /// Integer arithmetic is done using `uint64_t` then truncated, so it
+    /// wraps the same way the interpreter does instead of being undefined
+    /// behaviour.
+    fn integer_arithmetic(
+        &mut self,
+        op: IntegerOp,
+        left: &Typed<String>,
+        right: &Typed<String>,
+        ty: IntegerType,
+        unsigned: bool,
+    ) -> String {
+        let (l, r) = (wide(&left.value), wide(&right.value));
+
+        let value = match op {
+            IntegerOp::Add => format!("{} + {}", l, r),
+            IntegerOp::Subtract => format!("{} - {}", l, r),
+            IntegerOp::Multiply => format!("{} * {}", l, r),
+            IntegerOp::And => format!("{} & {}", l, r),
+            IntegerOp::Or => format!("{} | {}", l, r),
+            IntegerOp::Xor => format!("{} ^ {}", l, r),
+            IntegerOp::Divide | IntegerOp::Modulo => {
+                let divide = op == IntegerOp::Divide;
+
+                if unsigned {
+                    let symbol = if divide { "/" } else { "%" };
+                    format!("{} {} {}", l, symbol, r)
+                } else {
+                    let helper = if divide { "iec_sdiv" } else { "iec_srem" };
+                    format!(
+                        "(uint64_t){}((int64_t){}, (int64_t){})",
+                        helper, left.value, right.value
+                    )
+                }
+            }
+            IntegerOp::Exponent => format!("iec_pow({}, {})", l, r),
+        };
+
+        format!("(({})({}))", integer_type_name(ty), value)
+    }
+
+    fn real_arithmetic(
+        &mut self,
+        op: RealOp,
+        left: &String,
+        right: &String,
+    ) -> String {
+        let symbol = match op {
+            RealOp::Add => "+",
+            RealOp::Subtract => "-",
+            RealOp::Multiply => "*",
+            RealOp::Divide => "/",
+        };
+
+        format!("({} {} {})", left, symbol, right)
+    }
+
+    fn not(&mut self, value: &Typed<String>) -> String {
+        match value.ty {
+            Scalar::Integer(int) => {
+                format!("(({})~{})", integer_type_name(int), wide(&value.value))
+            }
+            _ => format!("(!{})", value.value),
+        }
+    }
+
+    fn negate(&mut self, value: &Typed<String>) -> String {
+        match value.ty {
+            Scalar::Integer(int) => format!(
+                "(({})(0 - {}))",
+                integer_type_name(int),
+                wide(&value.value)
+            ),
+            _ => format!("(-{})", value.value),
+        }
+    }
+
+    fn abs(&mut self, value: &Typed<String>) -> String {
+        let x = &value.value;
+
+        match value.ty {
+            Scalar::Integer(int) => format!(
+                "(({})({} < 0 ? 0 - {} : {}))",
+                integer_type_name(int),
+                x,
+                wide(x),
+                wide(x)
+            ),
+            _ => format!("({} < 0.0 ? -{} : {})", x, x, x),
+        }
+    }
+
+    fn select(
+        &mut self,
+        condition: &String,
+        if_true: &Typed<String>,
+        if_false: &Typed<String>,
+    ) -> String {
+        format!("({} ? {} : {})", condition, if_true.value, if_false.value)
+    }
+
+    /// Shifts are guarded against the cases C leaves undefined.
+    fn shift(
+        &mut self,
+        shift: Shift,
+        value: &Typed<String>,
+        amount: &Typed<String>,
+    ) -> String {
+        let ty = match value.ty {
+            Scalar::Integer(ty) => ty,
+            _ => unreachable!("only integers are shifted"),
+        };
+        let bits = ty.bits();
+        let bits_value =
+            format!("(uint64_t)({}){}", unsigned_type_name(ty), value.value);
+        let amount = wide(&amount.value);
+
+        let value = match shift {
+            Shift::Left => format!(
+                "{} >= {} ? 0 : {} << {}",
+                amount, bits, bits_value, amount
+            ),
+            Shift::Right => format!(
+                "{} >= {} ? 0 : {} >> {}",
+                amount, bits, bits_value, amount
+            ),
+            Shift::RotateLeft | Shift::RotateRight => {
+                let (forwards, backwards) = if shift.is_left() {
+                    ("<<", ">>")
+                } else {
+                    (">>", "<<")
+                };
+                // the bits shifted past the type's width are truncated away
+                format!(
+                    "{x} {f} ({n} % {bits}) | {x} {b} (({bits} - {n} % {bits}) % {bits})",
+                    x = bits_value,
+                    f = forwards,
+                    b = backwards,
+                    n = amount,
+                    bits = bits
+                )
+            }
+        };
+
+        format!("(({})({}))", integer_type_name(ty), value)
+    }
+
+    fn pou_index(&self, symbol: Symbol) -> Option<usize> {
+        self.items.pou_index(symbol)
+    }
+
+    /// Function block instances are called in place, while functions get a
+    /// fresh frame every time.
+    fn begin_call(&mut self, pou: usize, instance: Option<Expr>) -> Expr {
+        self.out.line("{");
+        self.out.indent();
+
+        match instance {
+            Some(instance) => instance,
+            None => {
+                self.out.line(format_args!(
+                    "{} frame = {{0}};",
+                    self.items.pous()[pou].type_name()
+                ));
+                Expr::new("frame", FieldType::Instance(pou))
+            }
+        }
+    }
+
+    fn invoke(&mut self, pou: usize, frame: &Expr) {
+        self.out.line(format_args!(
+            "uint32_t status = {}(&{});",
+            self.items.pous()[pou].function_name(),
+            frame.code
+        ));
+        self.return_if("status != IEC_OK", "status");
+    }
+
+    fn end_call(&mut self, _pou: usize) {
+        self.out.dedent();
+        self.out.line("}");
+    }
+}
+
+fn label(order: &[EntityId], block: EntityId) -> String {
+    let index = order
+        .iter()
+        .position(|&b| b == block)
+        .expect("every reachable block is written");
+    format!("bb{}", index)
+}
+
+/// The blocks a terminator will need to `goto`, given the block which is
+/// written immediately after it.
+fn goto_targets(
+    terminator: &Terminator,
+    next: Option<EntityId>,
+) -> Vec<EntityId> {
+    match *terminator {
+        Terminator::Jump(target) if next != Some(target) => vec![target],
+        Terminator::Branch {
+            then, otherwise, ..
+        } => [then, otherwise]
+            .iter()
+            .cloned()
+            .filter(|&target| next != Some(target))
+            .collect(),
+        Terminator::Jump(_) | Terminator::Return => Vec::new(),
+    }
+}
+
+fn operator(comparison: Comparison) -> &'static str {
+    match comparison {
+        Comparison::Equal => "==",
+        Comparison::NotEqual => "!=",
+        Comparison::Less => "<",
+        Comparison::LessOrEqual => "<=",
+        Comparison::Greater => ">",
+        Comparison::GreaterOrEqual => ">=",
+    }
+}
+
+/// Remove the parentheses around an expression, so `if ((x == 0))` doesn't
+/// trigger a warning about redundant parentheses.
+fn unparenthesise(code: &str) -> &str {
+    let inner = match code.strip_prefix('(').and_then(|c| c.strip_suffix(')')) {
+        Some(inner) => inner,
+        None => return code,
+    };
+
+    // make sure the outer parentheses actually match (e.g. not "(a) + (b)")
+    let mut depth = 0;
+    for c in inner.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return code,
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+
+    inner
+}
@@ -0,0 +1,192 @@
+//! Working out which C types and variables are needed for a
+//! [`CompilationUnit`].
+
+use crate::CodegenError;
+use iec::const_eval::IntegerType;
+use iec::ecs::EntityId;
+use iec::hir::{CompilationUnit, Symbol};
+use iec::layout::{Declaration, Declarations, PouDeclarations};
+pub(crate) use iec::layout::{FieldType, PouKind};
+use std::collections::HashMap;
+
+/// Every POU and variable which needs to be declared in C.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Items {
+    pub declarations: Declarations,
+    locations: HashMap<EntityId, Location>,
+}
+
+impl Items {
+    pub fn new(unit: &CompilationUnit) -> Result<Items, CodegenError> {
+        let declarations = Declarations::new(unit)?;
+        let mut locations = HashMap::new();
+
+        for pou in &declarations.pous {
+            for declaration in &pou.variables {
+                let location = Location::new(
+                    Storage::Field,
+                    field_name(declaration),
+                    declaration,
+                )?;
+                locations.insert(declaration.variable, location);
+            }
+
+            // compiler-generated temporaries become local variables
+            for (i, declaration) in pou.temporaries.iter().enumerate() {
+                // ST identifiers can't contain "__", so this never clashes
+                let name = format!("tmp__{}", i);
+                let location =
+                    Location::new(Storage::Local, name, declaration)?;
+                locations.insert(declaration.variable, location);
+            }
+        }
+
+        for declaration in &declarations.globals {
+            let location = Location::new(
+                Storage::Global,
+                field_name(declaration),
+                declaration,
+            )?;
+            locations.insert(declaration.variable, location);
+        }
+
+        Ok(Items {
+            declarations,
+            locations,
+        })
+    }
+
+    /// Programs, then function blocks, then functions.
+    pub fn pous(&self) -> &[PouDeclarations] {
+        &self.declarations.pous
+    }
+
+    pub fn pou_index(&self, symbol: Symbol) -> Option<usize> {
+        self.declarations.pou_index(symbol)
+    }
+
+    pub fn location(&self, variable: EntityId) -> &Location {
+        self.locations
+            .get(&variable)
+            .expect("every variable has a location")
+    }
+
+    /// The C type a [`FieldType`] is written as.
+    pub fn type_name(&self, ty: FieldType) -> String {
+        match ty {
+            FieldType::Bool => String::from("bool"),
+            FieldType::Integer(int) => integer_type_name(int).to_string(),
+            FieldType::Real => String::from("double"),
+            FieldType::Instance(pou) => self.pous()[pou].type_name(),
+        }
+    }
+}
+
+fn field_name(declaration: &Declaration) -> String {
+    let name = declaration
+        .name
+        .as_ref()
+        .expect("declared variables always have a name");
+
+    identifier(name)
+}
+
+/// Where a variable is stored.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Location {
+    pub storage: Storage,
+    /// The variable's name, as a valid C identifier.
+    pub name: String,
+    pub ty: FieldType,
+}
+
+impl Location {
+    fn new(
+        storage: Storage,
+        name: String,
+        declaration: &Declaration,
+    ) -> Result<Location, CodegenError> {
+        Ok(Location {
+            storage,
+            name,
+            ty: declaration.ty.field_type()?,
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Storage {
+    /// A field in the current POU's struct (i.e. `self->name`).
+    Field,
+    /// A member of the `iec_globals` struct.
+    Global,
+    /// A local variable.
+    Local,
+}
+
+pub(crate) fn integer_type_name(ty: IntegerType) -> &'static str {
+    match (ty.bits(), ty.is_signed()) {
+        (8, true) => "int8_t",
+        (16, true) => "int16_t",
+        (32, true) => "int32_t",
+        (64, true) => "int64_t",
+        (8, false) => "uint8_t",
+        (16, false) => "uint16_t",
+        (32, false) => "uint32_t",
+        _ => "uint64_t",
+    }
+}
+
+/// The unsigned type with the same width as `ty`.
+pub(crate) fn unsigned_type_name(ty: IntegerType) -> &'static str {
+    match ty.bits() {
+        8 => "uint8_t",
+        16 => "uint16_t",
+        32 => "uint32_t",
+        _ => "uint64_t",
+    }
+}
+
+/// Turn an ST identifier into a C one.
+///
+/// Identifiers are case-insensitive in ST so everything is lowercased, and
+/// anything which would clash with a C keyword gets a trailing underscore
+/// (which isn't allowed in ST).
+pub(crate) fn identifier(name: &str) -> String {
+    const KEYWORDS: &[&str] = &[
+        "auto", "bool", "break", "case", "char", "const", "continue",
+        "default", "do", "double", "else", "enum", "extern", "float", "for",
+        "goto", "if", "inline", "int", "long", "register", "restrict",
+        "return", "short", "signed", "sizeof", "static", "struct", "switch",
+        "typedef", "union", "unsigned", "void", "volatile", "while", "true",
+        "false",
+    ];
+
+    let name = name.to_lowercase();
+
+    if KEYWORDS.contains(&name.as_str()) {
+        format!("{}_", name)
+    } else {
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identifiers_never_clash_with_c_keywords() {
+        let inputs = vec![
+            ("Counter", "counter"),
+            ("double", "double_"),
+            ("INT", "int_"),
+            ("While", "while_"),
+            ("tmp_1", "tmp_1"),
+        ];
+
+        for (src, should_be) in inputs {
+            assert_eq!(identifier(src), should_be);
+        }
+    }
+}
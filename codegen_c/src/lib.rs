@@ -0,0 +1,690 @@
+//! A backend for the `iec` compiler which generates portable C99.
+//!
+//! Every POU in a fully analysed [`CompilationUnit`] gets a struct containing
+//! its variables and a function which executes its body:
+//!
+//! ```c
+//! uint32_t iec_fb_counter(iec_fb_counter_t *self);
+//! uint32_t iec_fn_double(iec_fn_double_t *self);
+//! uint32_t iec_program_main(void);
+//! ```
+//!
+//! Function blocks are called using a pointer to their instance data, while
+//! each call to a function uses a fresh, zeroed frame. Programs have a
+//! statically allocated instance (e.g. `iec_instance_main`) so their entry
+//! point doesn't take any arguments, and `iec_run_cycle()` calls every
+//! program once. Global variables live in the `iec_globals` struct.
+//!
+//! Each function returns `IEC_OK` (`0`) on success, or a non-zero [`status`]
+//! code when execution was aborted (e.g. division by zero).
+//!
+//! The generated header declares all of this so it can be used from host
+//! code, while the source file contains the definitions. Integer arithmetic
+//! wraps on overflow, the same as [`iec::interpreter::Overflow::Wrap`].
+//!
+//! [`CompilationUnit`]: iec::CompilationUnit
+
+mod body;
+mod items;
+
+use crate::items::{Items, PouKind};
+use iec::codegen::{self, LowerError, Tables};
+use iec::layout::{Declaration, LayoutError};
+use iec::CompilationUnit;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+/// Helper functions used by the generated code.
+const PRELUDE: &str = include_str!("prelude.c");
+
+/// The codes returned by generated functions.
+pub mod status {
+    pub const OK: u32 = 0;
+    pub const DIVISION_BY_ZERO: u32 = 1;
+    pub const NEGATIVE_EXPONENT: u32 = 2;
+    pub const INVALID_OPERANDS: u32 = 3;
+
+    /// The name of the macro each status code is defined as.
+    pub(crate) const MACROS: &[(&str, u32)] = &[
+        ("IEC_OK", OK),
+        ("IEC_DIVISION_BY_ZERO", DIVISION_BY_ZERO),
+        ("IEC_NEGATIVE_EXPONENT", NEGATIVE_EXPONENT),
+        ("IEC_INVALID_OPERANDS", INVALID_OPERANDS),
+    ];
+}
+
+/// The generated code.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Output {
+    /// The header, which should be saved as `<name>.h`.
+    pub header: String,
+    pub source: String,
+}
+
+/// Generate C code for a [`CompilationUnit`].
+///
+/// The `name` is used when the source file `#include`s the header, so the
+/// header should be saved alongside it as `<name>.h`.
+///
+/// # Examples
+///
+/// ```rust
+/// use iec::passes::PassContext;
+/// use iec::Diagnostics;
+///
+/// let src = "
+///     PROGRAM main
+///         VAR
+///             count: int;
+///         END_VAR
+///         count := count + 1;
+///     END_PROGRAM";
+/// let ast: iec_syntax::File = src.parse().unwrap();
+/// let mut diags = Diagnostics::new();
+/// let cu = iec::process(&ast, &mut PassContext::new_nop_logger(&mut diags));
+///
+/// let output = iec_codegen_c::generate(&cu, "counter").unwrap();
+///
+/// assert!(output.header.contains("uint32_t iec_program_main(void);"));
+/// assert!(output.source.contains("#include \"counter.h\""));
+/// ```
+pub fn generate(
+    unit: &CompilationUnit,
+    name: &str,
+) -> Result<Output, CodegenError> {
+    let items = Items::new(unit)?;
+
+    codegen::with_tables(unit, |tables| {
+        Ok(Output {
+            header: header(&items, name)?,
+            source: source(tables, &items, name)?,
+        })
+    })
+}
+
+fn header(items: &Items, name: &str) -> Result<String, CodegenError> {
+    let guard: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    let mut out = Writer::default();
+    out.line("/* Generated by iecc. Do not edit. */");
+    out.blank();
+    out.line(format_args!("#ifndef IEC_{}_H", guard));
+    out.line(format_args!("#define IEC_{}_H", guard));
+    out.blank();
+    out.line("#include <stdbool.h>");
+    out.line("#include <stdint.h>");
+    out.blank();
+    out.line("#ifdef __cplusplus");
+    out.line("extern \"C\" {");
+    out.line("#endif");
+    out.blank();
+
+    for &(name, value) in status::MACROS {
+        out.line(format_args!("#define {} {}", name, value));
+    }
+    out.blank();
+
+    for &index in items.declarations.definition_order() {
+        let pou = &items.pous()[index];
+        out.line(format_args!("/* {} {} */", pou.kind, pou.name));
+        struct_definition(&mut out, items, &pou.variables, &pou.type_name());
+        out.blank();
+    }
+
+    let globals = &items.declarations.globals;
+    out.line("/* VAR_GLOBAL */");
+    struct_definition(&mut out, items, globals, "iec_globals_t");
+    out.blank();
+
+    out.line("extern iec_globals_t iec_globals;");
+    for pou in items.pous().iter().filter(|p| p.kind == PouKind::Program) {
+        out.line(format_args!(
+            "extern {} {};",
+            pou.type_name(),
+            pou.instance_name()
+        ));
+    }
+    out.blank();
+
+    for pou in items.pous() {
+        match pou.kind {
+            PouKind::Program => out
+                .line(format_args!("uint32_t {}(void);", pou.function_name())),
+            PouKind::Function | PouKind::FunctionBlock => {
+                out.line(format_args!(
+                    "uint32_t {}({} *self);",
+                    pou.function_name(),
+                    pou.type_name()
+                ));
+            }
+        }
+    }
+    out.blank();
+
+    out.line("/* Execute every program once, returning the first non-zero");
+    out.line(" * status code. */");
+    out.line("uint32_t iec_run_cycle(void);");
+    out.blank();
+    out.line("#ifdef __cplusplus");
+    out.line("}");
+    out.line("#endif");
+    out.blank();
+    out.line("#endif");
+
+    Ok(out.finish())
+}
+
+fn struct_definition(
+    out: &mut Writer,
+    items: &Items,
+    fields: &[Declaration],
+    type_name: &str,
+) {
+    out.line("typedef struct {");
+    out.indent();
+
+    for field in fields {
+        let location = items.location(field.variable);
+        out.line(format_args!(
+            "{} {};",
+            items.type_name(location.ty),
+            location.name
+        ));
+    }
+    if fields.is_empty() {
+        // C doesn't allow empty structs
+        out.line("char unused__;");
+    }
+
+    out.dedent();
+    out.line(format_args!("}} {};", type_name));
+}
+
+fn source(
+    tables: &Tables<'_>,
+    items: &Items,
+    name: &str,
+) -> Result<String, CodegenError> {
+    let mut out = Writer::default();
+    out.line("/* Generated by iecc. Do not edit. */");
+    out.blank();
+    out.line(format_args!("#include \"{}.h\"", name));
+    out.blank();
+    out.raw(PRELUDE);
+    out.blank();
+
+    out.line("iec_globals_t iec_globals;");
+    let programs: Vec<_> = items
+        .pous()
+        .iter()
+        .filter(|p| p.kind == PouKind::Program)
+        .collect();
+    for pou in &programs {
+        out.line(format_args!("{} {};", pou.type_name(), pou.instance_name()));
+    }
+
+    for index in 0..items.pous().len() {
+        out.blank();
+        body::define(&mut out, tables, items, index)?;
+    }
+
+    out.blank();
+    out.line("uint32_t iec_run_cycle(void) {");
+    out.indent();
+    for pou in &programs {
+        out.line("{");
+        out.indent();
+        out.line(format_args!("uint32_t status = {}();", pou.function_name()));
+        out.line("if (status != IEC_OK) {");
+        out.indent();
+        out.line("return status;");
+        out.dedent();
+        out.line("}");
+        out.dedent();
+        out.line("}");
+    }
+    out.line("return IEC_OK;");
+    out.dedent();
+    out.line("}");
+
+    Ok(out.finish())
+}
+
+/// A buffer of C code which keeps track of indentation.
+#[derive(Debug, Default)]
+pub(crate) struct Writer {
+    buffer: String,
+    indent: usize,
+}
+
+impl Writer {
+    const INDENT: &'static str = "    ";
+
+    pub fn line<D: Display>(&mut self, text: D) {
+        for _ in 0..self.indent {
+            self.buffer.push_str(Writer::INDENT);
+        }
+        self.buffer.push_str(&text.to_string());
+        self.buffer.push('\n');
+    }
+
+    /// Labels are written one level further out than the surrounding code.
+    pub fn label<D: Display>(&mut self, text: D) {
+        let indent = self.indent;
+        self.indent = indent.saturating_sub(1);
+        self.line(text);
+        self.indent = indent;
+    }
+
+    pub fn blank(&mut self) {
+        self.buffer.push('\n');
+    }
+
+    /// Append some text verbatim.
+    pub fn raw(&mut self, text: &str) {
+        self.buffer.push_str(text);
+    }
+
+    pub fn indent(&mut self) {
+        self.indent += 1;
+    }
+
+    pub fn dedent(&mut self) {
+        self.indent -= 1;
+    }
+
+    pub fn finish(self) -> String {
+        self.buffer
+    }
+}
+
+/// Reasons code generation can fail.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CodegenError {
+    /// The program uses something this backend can't translate yet.
+    Unsupported(String),
+    /// An operation was applied to values of the wrong type. This should
+    /// have been caught during semantic analysis.
+    TypeMismatch { expected: String, found: String },
+}
+
+impl From<LayoutError> for CodegenError {
+    fn from(other: LayoutError) -> CodegenError {
+        match other {
+            LayoutError::Unsupported(what) => CodegenError::Unsupported(what),
+        }
+    }
+}
+
+impl From<LowerError> for CodegenError {
+    fn from(other: LowerError) -> CodegenError {
+        match other {
+            LowerError::Unsupported(what) => CodegenError::Unsupported(what),
+            LowerError::TypeMismatch { expected, found } => {
+                CodegenError::TypeMismatch { expected, found }
+            }
+        }
+    }
+}
+
+impl Display for CodegenError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            CodegenError::Unsupported(ref what) => {
+                write!(f, "{} aren't supported by the C backend", what)
+            }
+            CodegenError::TypeMismatch {
+                ref expected,
+                ref found,
+            } => write!(f, "Expected a {} but found a {}", expected, found),
+        }
+    }
+}
+
+impl Error for CodegenError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iec::testing::{self, Snapshot};
+    use std::path::PathBuf;
+    use std::process::{Command, Output as ProcessOutput};
+
+    fn have_c_compiler() -> bool {
+        Command::new("cc").arg("--version").output().is_ok()
+    }
+
+    /// Compile the generated code with a `main()` which runs `cycles` cycles
+    /// (see [`testing::snapshot_main()`]).
+    fn compile_and_run(
+        test_name: &str,
+        cu: &CompilationUnit,
+        cycles: u64,
+    ) -> ProcessOutput {
+        let output = generate(cu, "program").unwrap();
+
+        let dir = temp_dir(test_name);
+        std::fs::write(dir.join("program.h"), &output.header).unwrap();
+        std::fs::write(dir.join("program.c"), &output.source).unwrap();
+        std::fs::write(dir.join("main.c"), testing::snapshot_main(cu, cycles))
+            .unwrap();
+
+        let compiled = Command::new("cc")
+            .current_dir(&dir)
+            .args([
+                "-std=c99",
+                "-pedantic",
+                "-Wall",
+                "-Wextra",
+                "-Werror",
+                "program.c",
+                "main.c",
+                "-o",
+                "program",
+            ])
+            .output()
+            .unwrap();
+        assert!(
+            compiled.status.success(),
+            "{}\n{}",
+            String::from_utf8_lossy(&compiled.stderr),
+            output.source
+        );
+
+        let output = Command::new(dir.join("program")).output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        output
+    }
+
+    fn temp_dir(test_name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "iec-codegen-c-{}-{}",
+            test_name,
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn compare(test_name: &str, src: &str, cycles: u64, paths: &[&str]) {
+        testing::compare(src, cycles, paths, |cu| {
+            if !have_c_compiler() {
+                return None;
+            }
+
+            let output = compile_and_run(test_name, cu, cycles);
+            let stdout = String::from_utf8(output.stdout).unwrap();
+            assert!(output.status.success(), "{}", stdout);
+
+            Some(Snapshot::parse(cu, &stdout))
+        });
+    }
+
+    #[test]
+    fn function_blocks_and_functions() {
+        let src = "
+            FUNCTION_BLOCK counter
+                VAR_INPUT
+                    step: int;
+                END_VAR
+                VAR_OUTPUT
+                    total: int;
+                END_VAR
+            BEGIN
+                total := total + step;
+            END_FUNCTION_BLOCK
+
+            FUNCTION double : int
+            BEGIN
+                VAR_INPUT
+                    x: int;
+                END_VAR
+                double := x * 2;
+            END_FUNCTION
+
+            PROGRAM main
+                VAR
+                    slow: counter;
+                    fast: counter;
+                    copy: int;
+                    direct: int;
+                END_VAR
+                slow(1);
+                fast(step := double(5), total => copy);
+                direct := fast.total + slow.total;
+            END_PROGRAM";
+
+        compare(
+            "fb",
+            src,
+            3,
+            &[
+                "main.slow.total",
+                "main.fast.total",
+                "main.copy",
+                "main.direct",
+            ],
+        );
+    }
+
+    #[test]
+    fn loops_and_branches() {
+        let src = "
+            PROGRAM main
+                VAR
+                    i: int;
+                    total: dint;
+                    evens: int;
+                    repeats: int;
+                    countdown: int;
+                END_VAR
+                total := 0;
+                evens := 0;
+                FOR i := 1 TO 100 DO
+                    IF i > 50 THEN
+                        EXIT;
+                    END_IF;
+                    IF i % 2 = 0 THEN
+                        evens := evens + 1;
+                    END_IF;
+                    total := total + i * i;
+                END_FOR;
+
+                countdown := 0;
+                FOR i := 10 TO 0 BY -3 DO
+                    countdown := countdown + i;
+                END_FOR;
+
+                repeats := 0;
+                WHILE repeats < 7 DO
+                    repeats := repeats + 1;
+                END_WHILE;
+            END_PROGRAM";
+
+        compare(
+            "loops",
+            src,
+            2,
+            &[
+                "main.i",
+                "main.total",
+                "main.evens",
+                "main.countdown",
+                "main.repeats",
+            ],
+        );
+    }
+
+    #[test]
+    fn integer_arithmetic_wraps() {
+        let src = "
+            PROGRAM main
+                VAR
+                    small: sint;
+                    unsigned: usint;
+                    word_value: word;
+                    quotient: int;
+                    remainder: int;
+                    power: dint;
+                    wrapped: sint;
+                    negated: int;
+                    inverted: byte;
+                    flag: bool;
+                    mixed: bool;
+                END_VAR
+                small := small + 100;
+                unsigned := unsigned - 3;
+                word_value := word_value - 1;
+                quotient := -17 / 5;
+                remainder := -17 % 5;
+                power := 3 ** 5;
+                wrapped := small * 3;
+                negated := -(small);
+                inverted := NOT unsigned;
+                flag := (small < 0) XOR (unsigned > 200);
+                mixed := NOT flag AND (power >= 243);
+            END_PROGRAM";
+
+        compare(
+            "arithmetic",
+            src,
+            3,
+            &[
+                "main.small",
+                "main.unsigned",
+                "main.word_value",
+                "main.quotient",
+                "main.remainder",
+                "main.power",
+                "main.wrapped",
+                "main.negated",
+                "main.inverted",
+                "main.flag",
+                "main.mixed",
+            ],
+        );
+    }
+
+    #[test]
+    fn builtin_functions_are_inlined() {
+        let src = "
+            PROGRAM main
+                VAR
+                    a: int;
+                    b: int;
+                    c: int;
+                    d: int;
+                    e: int;
+                    bits: byte;
+                    shifted: word;
+                    cleared: byte;
+                END_VAR
+                a := abs(-42);
+                b := min(a, 7);
+                c := max(limit(0, a * 100, 500), 7);
+                d := sel(a > 10, 1, 2);
+                bits := 1;
+                bits := ror(bits, 1);
+                e := bits;
+                shifted := 3;
+                shifted := shl(shifted, 4);
+                cleared := 255;
+                cleared := shr(cleared, 9);
+            END_PROGRAM";
+
+        compare(
+            "builtins",
+            src,
+            1,
+            &[
+                "main.a",
+                "main.b",
+                "main.c",
+                "main.d",
+                "main.e",
+                "main.bits",
+                "main.shifted",
+                "main.cleared",
+            ],
+        );
+    }
+
+    #[test]
+    fn globals_and_names_which_are_c_keywords() {
+        let src = "
+            VAR_GLOBAL
+                shared: int;
+            END_VAR
+
+            FUNCTION register : int
+            BEGIN
+                VAR_INPUT
+                    goto: int;
+                END_VAR
+                register := goto + 1;
+            END_FUNCTION
+
+            PROGRAM first
+                VAR
+                    static: int;
+                END_VAR
+                shared := shared + 5;
+                static := register(shared);
+            END_PROGRAM
+
+            PROGRAM second
+                VAR
+                    seen: int;
+                END_VAR
+                seen := shared * 2;
+            END_PROGRAM";
+
+        compare(
+            "globals",
+            src,
+            4,
+            &["shared", "first.static", "second.seen"],
+        );
+    }
+
+    #[test]
+    fn division_by_zero_aborts_the_cycle() {
+        if !have_c_compiler() {
+            return;
+        }
+
+        let src = "
+            FUNCTION divide : int
+            BEGIN
+                VAR_INPUT
+                    x: int;
+                END_VAR
+                divide := 10 / x;
+            END_FUNCTION
+
+            PROGRAM main
+                VAR
+                    countdown: int;
+                    y: int;
+                END_VAR
+                countdown := countdown - 1;
+                y := divide(countdown + 3);
+            END_PROGRAM";
+        let (_, cu) = testing::analyse(src);
+
+        let output = compile_and_run("trap", &cu, 5);
+
+        assert!(!output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(stdout.trim(), format!("3: {}", status::DIVISION_BY_ZERO));
+    }
+}
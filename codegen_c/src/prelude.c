@@ -0,0 +1,28 @@
+/*
+ * Integer arithmetic is done using uint64_t so overflow wraps instead of
+ * being undefined behaviour. Converting the result back to a signed type
+ * relies on the two's complement behaviour every mainstream compiler uses.
+ */
+
+static inline int64_t iec_sdiv(int64_t left, int64_t right) {
+    /* INT64_MIN / -1 would overflow */
+    return right == -1 ? (int64_t)(0 - (uint64_t)left) : left / right;
+}
+
+static inline int64_t iec_srem(int64_t left, int64_t right) {
+    return right == -1 ? 0 : left % right;
+}
+
+static inline uint64_t iec_pow(uint64_t base, uint64_t exponent) {
+    uint64_t result = 1;
+
+    while (exponent != 0) {
+        if (exponent & 1) {
+            result *= base;
+        }
+        base *= base;
+        exponent >>= 1;
+    }
+
+    return result;
+}
@@ -0,0 +1,477 @@
+use crate::{status, translate, CodegenError};
+use byteorder::NativeEndian;
+use codespan::ByteSpan;
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Linkage};
+use iec::const_eval::EvalError;
+use iec::interpreter::Value;
+use iec::layout::{self, Layout, PouKind, Struct};
+use iec::CompilationUnit;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+/// The signature of a POU's generated code (see [`translate::pou_signature`]).
+type ScanFn = unsafe extern "C" fn(*mut u8, *mut u8) -> u32;
+
+/// Programs which have been compiled to machine code in memory.
+///
+/// # Examples
+///
+/// ```rust
+/// use iec::passes::PassContext;
+/// use iec::Diagnostics;
+/// use iec_codegen_cranelift::Jit;
+///
+/// let src = "
+///     PROGRAM main
+///         VAR
+///             count: int;
+///         END_VAR
+///         count := count + 1;
+///     END_PROGRAM";
+/// let ast: iec_syntax::File = src.parse().unwrap();
+/// let mut diags = Diagnostics::new();
+/// let cu = iec::process(&ast, &mut PassContext::new_nop_logger(&mut diags));
+///
+/// let mut jit = Jit::compile(&cu).unwrap();
+/// jit.run_cycles(3).unwrap();
+///
+/// assert_eq!(jit.get("main.count").and_then(|v| v.as_integer()), Some(3));
+/// ```
+pub struct Jit {
+    /// Owns the generated code. This is only ever `None` while being
+    /// dropped.
+    module: Option<JITModule>,
+    layout: Layout,
+    globals: Vec<u64>,
+    programs: Vec<CompiledProgram>,
+}
+
+struct CompiledProgram {
+    /// The index of the program's [`crate::PouLayout`].
+    pou: usize,
+    scan: ScanFn,
+    instance: Vec<u64>,
+}
+
+impl Jit {
+    /// Generate machine code for every program, function, and function
+    /// block in a [`CompilationUnit`].
+    ///
+    /// Every variable starts off zeroed.
+    pub fn compile(unit: &CompilationUnit) -> Result<Jit, CodegenError> {
+        let layout = Layout::new(unit)?;
+        let isa = crate::host_isa(false)?;
+        let mut module =
+            JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+
+        let ids = translate::define_pous(&mut module, unit, &layout, |_| {
+            Linkage::Local
+        })?;
+        module.finalize_definitions()?;
+
+        let programs = layout
+            .pous
+            .iter()
+            .enumerate()
+            .filter(|(_, pou)| pou.kind == PouKind::Program)
+            .map(|(index, pou)| {
+                let code = module.get_finalized_function(ids[index]);
+                // SAFETY: every POU is declared with the same signature
+                let scan =
+                    unsafe { std::mem::transmute::<*const u8, ScanFn>(code) };
+
+                CompiledProgram {
+                    pou: index,
+                    scan,
+                    instance: zeroed(&pou.frame),
+                }
+            })
+            .collect();
+
+        Ok(Jit {
+            module: Some(module),
+            globals: zeroed(&layout.globals),
+            layout,
+            programs,
+        })
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// Execute every program once.
+    pub fn run_cycle(&mut self) -> Result<(), Trap> {
+        let globals = self.globals.as_mut_ptr() as *mut u8;
+
+        for program in &mut self.programs {
+            let instance = program.instance.as_mut_ptr() as *mut u8;
+            // SAFETY: the memory was allocated using the program's layout
+            // and is large enough for every field
+            let code = unsafe { (program.scan)(globals, instance) };
+
+            if code != status::OK {
+                return Err(Trap {
+                    program: self.layout.pous[program.pou].name.clone(),
+                    error: status::to_error(code)
+                        .unwrap_or(EvalError::InvalidOperands),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn run_cycles(&mut self, cycles: u64) -> Result<(), Trap> {
+        for _ in 0..cycles {
+            self.run_cycle()?;
+        }
+
+        Ok(())
+    }
+
+    /// Read a variable using its dotted path (e.g. `main.counter.total` or
+    /// the name of a global).
+    ///
+    /// Function block instances can't be read directly, only their fields.
+    pub fn get(&self, path: &str) -> Option<Value> {
+        let resolved = self.layout.resolve(path)?;
+        let memory = match resolved.program {
+            Some(pou) => &self.programs.iter().find(|p| p.pou == pou)?.instance,
+            None => &self.globals,
+        };
+
+        layout::read::<NativeEndian>(
+            bytes(memory),
+            resolved.offset as usize,
+            resolved.ty,
+        )
+    }
+}
+
+impl Drop for Jit {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            // SAFETY: nothing else can refer to the generated code once
+            // we're gone
+            unsafe { module.free_memory() }
+        }
+    }
+}
+
+/// Allocate zeroed memory for a [`Struct`], making sure it is suitably
+/// aligned.
+fn zeroed(layout: &Struct) -> Vec<u64> {
+    vec![0; (layout.size as usize).div_ceil(8).max(1)]
+}
+
+fn bytes(words: &[u64]) -> &[u8] {
+    // SAFETY: a u8 has no alignment requirements and every bit pattern is
+    // valid
+    unsafe {
+        std::slice::from_raw_parts(words.as_ptr() as *const u8, words.len() * 8)
+    }
+}
+
+/// Execution of a program was aborted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trap {
+    pub program: String,
+    pub error: EvalError,
+}
+
+impl Display for Trap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let message = self.error.to_diagnostic(ByteSpan::default()).message;
+        write!(f, "{} (in \"{}\")", message, self.program)
+    }
+}
+
+impl Error for Trap {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iec::testing::{self, Execution};
+
+    impl Execution for Jit {
+        fn get(&self, path: &str) -> Option<Value> {
+            Jit::get(self, path)
+        }
+    }
+
+    fn compare(src: &str, cycles: u64, paths: &[&str]) -> Jit {
+        testing::compare(src, cycles, paths, |cu| {
+            let mut jit = Jit::compile(cu).unwrap();
+            jit.run_cycles(cycles).unwrap();
+            Some(jit)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn function_blocks_and_functions() {
+        let src = "
+            FUNCTION_BLOCK counter
+                VAR_INPUT
+                    step: int;
+                END_VAR
+                VAR_OUTPUT
+                    total: int;
+                END_VAR
+            BEGIN
+                total := total + step;
+            END_FUNCTION_BLOCK
+
+            FUNCTION double : int
+            BEGIN
+                VAR_INPUT
+                    x: int;
+                END_VAR
+                double := x * 2;
+            END_FUNCTION
+
+            PROGRAM main
+                VAR
+                    slow: counter;
+                    fast: counter;
+                    copy: int;
+                    direct: int;
+                END_VAR
+                slow(1);
+                fast(step := double(5), total => copy);
+                direct := fast.total + slow.total;
+            END_PROGRAM";
+
+        compare(
+            src,
+            3,
+            &[
+                "main.slow.total",
+                "main.fast.total",
+                "main.copy",
+                "main.direct",
+            ],
+        );
+    }
+
+    #[test]
+    fn loops_and_branches() {
+        let src = "
+            PROGRAM main
+                VAR
+                    i: int;
+                    total: dint;
+                    evens: int;
+                    repeats: int;
+                    countdown: int;
+                END_VAR
+                total := 0;
+                evens := 0;
+                FOR i := 1 TO 100 DO
+                    IF i > 50 THEN
+                        EXIT;
+                    END_IF;
+                    IF i % 2 = 0 THEN
+                        evens := evens + 1;
+                    END_IF;
+                    total := total + i * i;
+                END_FOR;
+
+                countdown := 0;
+                FOR i := 10 TO 0 BY -3 DO
+                    countdown := countdown + i;
+                END_FOR;
+
+                repeats := 0;
+                WHILE repeats < 7 DO
+                    repeats := repeats + 1;
+                END_WHILE;
+            END_PROGRAM";
+
+        compare(
+            src,
+            2,
+            &[
+                "main.i",
+                "main.total",
+                "main.evens",
+                "main.countdown",
+                "main.repeats",
+            ],
+        );
+    }
+
+    #[test]
+    fn integer_arithmetic_wraps() {
+        let src = "
+            PROGRAM main
+                VAR
+                    small: sint;
+                    unsigned: usint;
+                    word_value: word;
+                    quotient: int;
+                    remainder: int;
+                    power: dint;
+                    wrapped: sint;
+                    negated: int;
+                    inverted: byte;
+                    flag: bool;
+                    mixed: bool;
+                END_VAR
+                small := small + 100;
+                unsigned := unsigned - 3;
+                word_value := word_value - 1;
+                quotient := -17 / 5;
+                remainder := -17 % 5;
+                power := 3 ** 5;
+                wrapped := small * 3;
+                negated := -(small);
+                inverted := NOT unsigned;
+                flag := (small < 0) XOR (unsigned > 200);
+                mixed := NOT flag AND (power >= 243);
+            END_PROGRAM";
+
+        compare(
+            src,
+            3,
+            &[
+                "main.small",
+                "main.unsigned",
+                "main.word_value",
+                "main.quotient",
+                "main.remainder",
+                "main.power",
+                "main.wrapped",
+                "main.negated",
+                "main.inverted",
+                "main.flag",
+                "main.mixed",
+            ],
+        );
+    }
+
+    #[test]
+    fn builtin_functions_are_inlined() {
+        let src = "
+            PROGRAM main
+                VAR
+                    a: int;
+                    b: int;
+                    c: int;
+                    d: int;
+                    e: int;
+                    bits: byte;
+                    shifted: word;
+                    cleared: byte;
+                END_VAR
+                a := abs(-42);
+                b := min(a, 7);
+                c := max(limit(0, a * 100, 500), 7);
+                d := sel(a > 10, 1, 2);
+                bits := 1;
+                bits := ror(bits, 1);
+                e := bits;
+                shifted := 3;
+                shifted := shl(shifted, 4);
+                cleared := 255;
+                cleared := shr(cleared, 9);
+            END_PROGRAM";
+
+        compare(
+            src,
+            1,
+            &[
+                "main.a",
+                "main.b",
+                "main.c",
+                "main.d",
+                "main.e",
+                "main.bits",
+                "main.shifted",
+                "main.cleared",
+            ],
+        );
+    }
+
+    #[test]
+    fn globals_are_shared_between_programs() {
+        let src = "
+            VAR_GLOBAL
+                shared: int;
+            END_VAR
+
+            PROGRAM first
+                VAR
+                    seen: int;
+                END_VAR
+                shared := shared + 5;
+                seen := shared;
+            END_PROGRAM
+
+            PROGRAM second
+                VAR
+                    seen: int;
+                END_VAR
+                seen := shared * 2;
+            END_PROGRAM";
+
+        compare(src, 4, &["shared", "first.seen", "second.seen"]);
+    }
+
+    #[test]
+    fn division_by_zero_aborts_the_cycle() {
+        let src = "
+            FUNCTION divide : int
+            BEGIN
+                VAR_INPUT
+                    x: int;
+                END_VAR
+                divide := 10 / x;
+            END_FUNCTION
+
+            PROGRAM main
+                VAR
+                    countdown: int;
+                    y: int;
+                END_VAR
+                countdown := countdown - 1;
+                y := divide(countdown + 3);
+            END_PROGRAM";
+        let (_, cu) = testing::analyse(src);
+        let mut jit = Jit::compile(&cu).unwrap();
+
+        jit.run_cycles(2).unwrap();
+        assert_eq!(jit.get("main.y").and_then(|v| v.as_integer()), Some(10));
+
+        let err = jit.run_cycle().unwrap_err();
+        assert_eq!(
+            err,
+            Trap {
+                program: String::from("main"),
+                error: EvalError::DivisionByZero,
+            }
+        );
+        assert_eq!(err.to_string(), "Division by zero (in \"main\")");
+    }
+
+    #[test]
+    fn undeclared_direct_addresses() {
+        let src = "
+            PROGRAM main
+                %QW4 := %IW2 + 1;
+                IF NOT %IX0.1 THEN
+                    %QX0.1 := TRUE;
+                END_IF;
+            END_PROGRAM";
+
+        let jit = compare(src, 2, &["%QW4", "%QX0.1", "%IX0.1"]);
+
+        let expected = Value::Integer {
+            value: 1,
+            ty: iec::const_eval::IntegerType::Word,
+        };
+        assert_eq!(jit.get("%QW4"), Some(expected));
+    }
+}
@@ -0,0 +1,165 @@
+//! A [Cranelift] backend for the `iec` compiler.
+//!
+//! Each program, function, and function block in a fully analysed
+//! [`CompilationUnit`] is translated into a native function with the
+//! signature
+//!
+//! ```c
+//! uint32_t pou(void *globals, void *instance);
+//! ```
+//!
+//! where `instance` points at the POU's instance data (see [`Layout`]) and
+//! the return value is `0` on success, or a non-zero [`status`] code when
+//! execution was aborted (e.g. division by zero). Function blocks are
+//! stored inline inside whoever declares them, while functions get a fresh,
+//! zeroed frame on the stack every time they are called.
+//!
+//! The generated code can either be executed in-process with the [`Jit`], or
+//! saved as an object file with [`emit_object()`] and linked against the
+//! small C runtime in [`RUNTIME`].
+//!
+//! Integer arithmetic wraps on overflow, the same as
+//! [`iec::interpreter::Overflow::Wrap`].
+//!
+//! [Cranelift]: https://github.com/bytecodealliance/wasmtime/tree/main/cranelift
+//! [`CompilationUnit`]: iec::CompilationUnit
+
+mod jit;
+mod object;
+mod translate;
+
+pub use crate::jit::{Jit, Trap};
+pub use crate::object::{emit_object, RUN_CYCLE_SYMBOL};
+pub use iec::layout::{
+    Field, FieldType, Layout, LayoutError, PouKind, PouLayout, Struct,
+};
+
+use cranelift_codegen::isa::OwnedTargetIsa;
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_module::ModuleError;
+use iec::codegen::LowerError;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+/// A minimal C runtime which repeatedly calls the [`RUN_CYCLE_SYMBOL`]
+/// exported by [`emit_object()`].
+///
+/// The resulting executable takes the number of cycles to run as its only
+/// argument.
+pub const RUNTIME: &str = include_str!("runtime.c");
+
+/// The codes returned by generated functions.
+pub mod status {
+    use iec::const_eval::EvalError;
+
+    pub const OK: u32 = 0;
+    pub const DIVISION_BY_ZERO: u32 = 1;
+    pub const NEGATIVE_EXPONENT: u32 = 2;
+    pub const INVALID_OPERANDS: u32 = 3;
+
+    /// The error a non-zero status code corresponds to.
+    pub fn to_error(status: u32) -> Option<EvalError> {
+        match status {
+            DIVISION_BY_ZERO => Some(EvalError::DivisionByZero),
+            NEGATIVE_EXPONENT => Some(EvalError::NegativeExponent),
+            INVALID_OPERANDS => Some(EvalError::InvalidOperands),
+            _ => None,
+        }
+    }
+}
+
+/// Create a [`cranelift_codegen::isa::TargetIsa`] for the current machine.
+fn host_isa(
+    position_independent: bool,
+) -> Result<OwnedTargetIsa, CodegenError> {
+    let mut flags = settings::builder();
+    flags
+        .set(
+            "is_pic",
+            if position_independent {
+                "true"
+            } else {
+                "false"
+            },
+        )
+        .and_then(|_| flags.set("opt_level", "speed"))
+        .expect("these settings always exist");
+
+    cranelift_native::builder()
+        .map_err(|e| CodegenError::UnsupportedHost(e.to_string()))?
+        .finish(settings::Flags::new(flags))
+        .map_err(|e| CodegenError::UnsupportedHost(e.to_string()))
+}
+
+/// Reasons code generation can fail.
+#[derive(Debug)]
+pub enum CodegenError {
+    /// The program uses something this backend can't translate yet.
+    Unsupported(String),
+    /// An operation was applied to values of the wrong type. This should
+    /// have been caught during semantic analysis.
+    TypeMismatch {
+        expected: String,
+        found: String,
+    },
+    /// Cranelift doesn't know how to generate code for this machine.
+    UnsupportedHost(String),
+    Module(Box<ModuleError>),
+    /// The object file couldn't be written.
+    Object(String),
+}
+
+impl From<LayoutError> for CodegenError {
+    fn from(other: LayoutError) -> CodegenError {
+        match other {
+            LayoutError::Unsupported(what) => CodegenError::Unsupported(what),
+        }
+    }
+}
+
+impl From<LowerError> for CodegenError {
+    fn from(other: LowerError) -> CodegenError {
+        match other {
+            LowerError::Unsupported(what) => CodegenError::Unsupported(what),
+            LowerError::TypeMismatch { expected, found } => {
+                CodegenError::TypeMismatch { expected, found }
+            }
+        }
+    }
+}
+
+impl From<ModuleError> for CodegenError {
+    fn from(other: ModuleError) -> CodegenError {
+        CodegenError::Module(Box::new(other))
+    }
+}
+
+impl Display for CodegenError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            CodegenError::Unsupported(ref what) => {
+                write!(f, "{} aren't supported by the Cranelift backend", what)
+            }
+            CodegenError::TypeMismatch {
+                ref expected,
+                ref found,
+            } => write!(f, "Expected a {} but found a {}", expected, found),
+            CodegenError::UnsupportedHost(ref msg) => {
+                write!(f, "Unable to generate code for this machine: {}", msg)
+            }
+            CodegenError::Module(ref e) => write!(f, "{}", e),
+            CodegenError::Object(ref msg) => {
+                write!(f, "Unable to write the object file: {}", msg)
+            }
+        }
+    }
+}
+
+impl Error for CodegenError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            CodegenError::Module(ref e) => Some(&**e),
+            _ => None,
+        }
+    }
+}
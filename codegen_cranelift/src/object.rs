@@ -0,0 +1,182 @@
+use crate::{status, translate, CodegenError};
+use cranelift_codegen::ir::{types, AbiParam, InstBuilder};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_module::{
+    default_libcall_names, DataDescription, DataId, Linkage, Module,
+};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+use iec::layout::{Layout, PouKind, Struct};
+use iec::CompilationUnit;
+
+/// The function which executes every program once, `uint32_t
+/// iec_run_cycle(void)`. It returns the first non-zero [`status`] code
+/// encountered.
+pub const RUN_CYCLE_SYMBOL: &str = "iec_run_cycle";
+
+/// Compile a [`CompilationUnit`] to an object file for the current machine.
+///
+/// Global variables and the instance data for each program are statically
+/// allocated and zero initialised. They are exported as `iec_globals` and
+/// `iec_instance_<program>` respectively, alongside each program's
+/// `iec_program_<program>` function and the [`RUN_CYCLE_SYMBOL`].
+pub fn emit_object(
+    unit: &CompilationUnit,
+    name: &str,
+) -> Result<Vec<u8>, CodegenError> {
+    let layout = Layout::new(unit)?;
+    let isa = crate::host_isa(true)?;
+    let mut module = ObjectModule::new(ObjectBuilder::new(
+        isa,
+        name,
+        default_libcall_names(),
+    )?);
+
+    let ids =
+        translate::define_pous(
+            &mut module,
+            unit,
+            &layout,
+            |kind| match kind {
+                PouKind::Program => Linkage::Export,
+                PouKind::Function | PouKind::FunctionBlock => Linkage::Local,
+            },
+        )?;
+
+    let globals = define_zeroed(&mut module, "iec_globals", &layout.globals)?;
+    let mut programs = Vec::new();
+
+    for (index, pou) in layout.pous.iter().enumerate() {
+        if pou.kind == PouKind::Program {
+            let symbol = format!("iec_instance_{}", pou.name.to_lowercase());
+            let instance = define_zeroed(&mut module, &symbol, &pou.frame)?;
+            programs.push((ids[index], instance));
+        }
+    }
+
+    define_run_cycle(&mut module, globals, &programs)?;
+
+    module
+        .finish()
+        .emit()
+        .map_err(|e| CodegenError::Object(e.to_string()))
+}
+
+fn define_zeroed(
+    module: &mut ObjectModule,
+    name: &str,
+    layout: &Struct,
+) -> Result<DataId, CodegenError> {
+    let id = module.declare_data(name, Linkage::Export, true, false)?;
+
+    let mut data = DataDescription::new();
+    data.define_zeroinit(layout.size.max(1) as usize);
+    data.set_align(u64::from(layout.align.max(8)));
+    module.define_data(id, &data)?;
+
+    Ok(id)
+}
+
+/// Generate a function which calls each program in turn, stopping at the
+/// first error.
+fn define_run_cycle(
+    module: &mut ObjectModule,
+    globals: DataId,
+    programs: &[(cranelift_module::FuncId, DataId)],
+) -> Result<(), CodegenError> {
+    let ptr = module.target_config().pointer_type();
+    let mut sig = module.make_signature();
+    sig.returns.push(AbiParam::new(types::I32));
+    let id =
+        module.declare_function(RUN_CYCLE_SYMBOL, Linkage::Export, &sig)?;
+
+    let mut ctx = module.make_context();
+    ctx.func.signature = sig;
+    let mut builder_ctx = FunctionBuilderContext::new();
+    let mut builder = FunctionBuilder::new(&mut ctx.func, &mut builder_ctx);
+
+    let entry = builder.create_block();
+    let exit = builder.create_block();
+    let status = builder.append_block_param(exit, types::I32);
+    builder.switch_to_block(entry);
+
+    let globals = module.declare_data_in_func(globals, builder.func);
+    let globals = builder.ins().global_value(ptr, globals);
+
+    for &(program, instance) in programs {
+        let instance = module.declare_data_in_func(instance, builder.func);
+        let instance = builder.ins().global_value(ptr, instance);
+        let program = module.declare_func_in_func(program, builder.func);
+
+        let call = builder.ins().call(program, &[globals, instance]);
+        let code = builder.inst_results(call)[0];
+        let next = builder.create_block();
+        builder.ins().brif(code, exit, &[code], next, &[]);
+        builder.switch_to_block(next);
+    }
+
+    let ok = builder.ins().iconst(types::I32, i64::from(status::OK));
+    builder.ins().return_(&[ok]);
+
+    builder.switch_to_block(exit);
+    builder.ins().return_(&[status]);
+    builder.seal_all_blocks();
+    builder.finalize();
+
+    module.define_function(id, &mut ctx)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iec::passes::PassContext;
+    use iec::Diagnostics;
+    use std::process::Command;
+
+    #[test]
+    fn link_with_the_c_runtime() {
+        if Command::new("cc").arg("--version").output().is_err() {
+            // no C compiler available
+            return;
+        }
+
+        let src = "
+            PROGRAM main
+                VAR
+                    countdown: int;
+                    y: int;
+                END_VAR
+                countdown := countdown - 1;
+                y := 10 / (countdown + 3);
+            END_PROGRAM";
+        let ast: iec_syntax::File = src.parse().unwrap();
+        let mut diags = Diagnostics::new();
+        let cu =
+            iec::process(&ast, &mut PassContext::new_nop_logger(&mut diags));
+        let object = emit_object(&cu, "main").unwrap();
+
+        let dir = std::env::temp_dir()
+            .join(format!("iec-codegen-cranelift-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("runtime.c"), crate::RUNTIME).unwrap();
+        std::fs::write(dir.join("main.o"), object).unwrap();
+
+        let status = Command::new("cc")
+            .current_dir(&dir)
+            .args(["runtime.c", "main.o", "-o", "main"])
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let ok = Command::new(dir.join("main")).arg("2").output().unwrap();
+        assert!(ok.status.success());
+
+        let failed = Command::new(dir.join("main")).arg("3").output().unwrap();
+        assert!(!failed.status.success());
+        let stderr = String::from_utf8(failed.stderr).unwrap();
+        assert_eq!(stderr.trim(), "Cycle 3 failed: Division by zero");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,47 @@
+/*
+ * A minimal runtime for programs compiled by iec_codegen_cranelift.
+ *
+ * Usage: ./program [cycles]
+ *
+ * Every program is executed once per cycle (1 cycle by default). If a
+ * program is aborted, the error is printed and we exit with a non-zero
+ * status code.
+ */
+
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+uint32_t iec_run_cycle(void);
+
+static const char *describe(uint32_t status) {
+    switch (status) {
+    case 1:
+        return "Division by zero";
+    case 2:
+        return "Integers can't be raised to a negative power";
+    case 3:
+        return "Invalid operands";
+    default:
+        return "Unknown error";
+    }
+}
+
+int main(int argc, char **argv) {
+    unsigned long cycles = 1;
+
+    if (argc > 1) {
+        cycles = strtoul(argv[1], NULL, 10);
+    }
+
+    for (unsigned long i = 0; i < cycles; i++) {
+        uint32_t status = iec_run_cycle();
+
+        if (status != 0) {
+            fprintf(stderr, "Cycle %lu failed: %s\n", i + 1, describe(status));
+            return 1;
+        }
+    }
+
+    return 0;
+}
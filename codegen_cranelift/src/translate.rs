@@ -0,0 +1,684 @@
+//! Translating the HIR for a single POU into Cranelift IR.
+
+use crate::{status, CodegenError};
+use cranelift_codegen::ir::condcodes::{FloatCC, IntCC};
+use cranelift_codegen::ir::{
+    types, AbiParam, Block, FuncRef, InstBuilder, MemFlags, Signature,
+    StackSlotData, StackSlotKind, Type as ClifType, Value,
+};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_module::{FuncId, Linkage, Module};
+use iec::analysis::reachable_blocks;
+use iec::codegen::{
+    self, BoolOp, Comparison, IntegerOp, Lower, RealOp, Tables, Trap, Typed,
+};
+use iec::const_eval::{IntegerType, Scalar};
+use iec::ecs::EntityId;
+use iec::hir::{CompilationUnit, Symbol, Terminator};
+use iec::layout::{FieldType, Layout, PouKind, VariableType};
+use iec::passes::register_builtins::Shift;
+use std::collections::HashMap;
+
+/// The signature shared by every POU, `uint32_t (void *globals, void
+/// *instance)`.
+pub(crate) fn pou_signature<M: Module>(module: &M) -> Signature {
+    let ptr = module.target_config().pointer_type();
+    let mut sig = module.make_signature();
+    sig.params.push(AbiParam::new(ptr));
+    sig.params.push(AbiParam::new(ptr));
+    sig.returns.push(AbiParam::new(types::I32));
+
+    sig
+}
+
+/// Declare and define a function for every POU in the [`Layout`], returning
+/// their IDs in the same order.
+pub(crate) fn define_pous<M: Module>(
+    module: &mut M,
+    unit: &CompilationUnit,
+    layout: &Layout,
+    linkage: fn(PouKind) -> Linkage,
+) -> Result<Vec<FuncId>, CodegenError> {
+    codegen::with_tables(unit, |tables| {
+        let sig = pou_signature(module);
+        let ids = layout
+            .pous
+            .iter()
+            .map(|pou| {
+                let name = pou.kind.function_name(&pou.name);
+                module
+                    .declare_function(&name, linkage(pou.kind), &sig)
+                    .map_err(CodegenError::from)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut ctx = module.make_context();
+        let mut builder_ctx = FunctionBuilderContext::new();
+
+        for (index, pou) in layout.pous.iter().enumerate() {
+            ctx.func.signature = sig.clone();
+
+            let mut builder =
+                FunctionBuilder::new(&mut ctx.func, &mut builder_ctx);
+            let start = builder.create_block();
+            builder.append_block_params_for_function_params(start);
+            builder.switch_to_block(start);
+            let params = builder.block_params(start).to_vec();
+            let exit = builder.create_block();
+            builder.append_block_param(exit, types::I32);
+
+            let translator = Translator {
+                builder,
+                module: &mut *module,
+                tables,
+                layout,
+                pou: index,
+                pou_ids: &ids,
+                callees: HashMap::new(),
+                blocks: HashMap::new(),
+                globals: params[0],
+                this: params[1],
+                exit,
+            };
+            translator.translate(pou.entry_block)?;
+
+            module.define_function(ids[index], &mut ctx)?;
+            module.clear_context(&mut ctx);
+        }
+
+        Ok(ids)
+    })
+}
+
+fn clif_type(scalar: Scalar) -> ClifType {
+    match scalar {
+        Scalar::Bool => types::I8,
+        Scalar::Integer(int) => integer_type(int.bits()),
+        Scalar::Real => types::F64,
+    }
+}
+
+fn integer_type(bits: u32) -> ClifType {
+    match bits {
+        8 => types::I8,
+        16 => types::I16,
+        32 => types::I32,
+        _ => types::I64,
+    }
+}
+
+/// A location in memory.
+#[derive(Debug, Copy, Clone)]
+struct Place {
+    base: Value,
+    offset: u32,
+    ty: FieldType,
+}
+
+struct Translator<'t, 'a, M: Module> {
+    builder: FunctionBuilder<'t>,
+    module: &'t mut M,
+    tables: &'t Tables<'a>,
+    layout: &'t Layout,
+    pou: usize,
+    pou_ids: &'t [FuncId],
+    callees: HashMap<usize, FuncRef>,
+    blocks: HashMap<EntityId, Block>,
+    globals: Value,
+    this: Value,
+    /// A block which returns its argument as the status code.
+    exit: Block,
+}
+
+impl<'t, 'a, M: Module> Translator<'t, 'a, M> {
+    fn translate(mut self, entry: EntityId) -> Result<(), CodegenError> {
+        let order = reachable_blocks(entry, self.tables.blocks);
+        for &block in &order {
+            let b = self.builder.create_block();
+            self.blocks.insert(block, b);
+        }
+
+        self.builder.ins().jump(self.blocks[&entry], &[]);
+
+        for &block in &order {
+            self.builder.switch_to_block(self.blocks[&block]);
+            self.block(block)?;
+        }
+
+        self.builder.switch_to_block(self.exit);
+        let status = self.builder.block_params(self.exit)[0];
+        self.builder.ins().return_(&[status]);
+
+        self.builder.seal_all_blocks();
+        self.builder.finalize();
+
+        Ok(())
+    }
+
+    fn block(&mut self, id: EntityId) -> Result<(), CodegenError> {
+        let block = self.tables.block(id);
+        self.instructions(block)?;
+
+        match block.terminator {
+            Terminator::Jump(target) => {
+                self.builder.ins().jump(self.blocks[&target], &[]);
+            }
+            Terminator::Branch {
+                condition,
+                then,
+                otherwise,
+            } => {
+                let condition = self.condition(condition)?;
+                self.builder.ins().brif(
+                    condition,
+                    self.blocks[&then],
+                    &[],
+                    self.blocks[&otherwise],
+                    &[],
+                );
+            }
+            Terminator::Return => {
+                let ok = self
+                    .builder
+                    .ins()
+                    .iconst(types::I32, i64::from(status::OK));
+                self.builder.ins().return_(&[ok]);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sign or zero extend (or truncate) an integer to `bits` bits.
+    fn cast(&mut self, value: Value, from: IntegerType, bits: u32) -> Value {
+        let ty = integer_type(bits);
+
+        if bits > from.bits() && from.is_signed() {
+            self.builder.ins().sextend(ty, value)
+        } else if bits > from.bits() {
+            self.builder.ins().uextend(ty, value)
+        } else if bits < from.bits() {
+            self.builder.ins().ireduce(ty, value)
+        } else {
+            value
+        }
+    }
+
+    /// Widen an integer to 64 bits so it can be combined with another
+    /// integer of a different type.
+    fn wide(&mut self, value: &Typed<Value>) -> Value {
+        match value.ty {
+            Scalar::Integer(int) => self.cast(value.value, int, 64),
+            _ => value.value,
+        }
+    }
+
+    fn return_if_nonzero(&mut self, condition: Value, status: Value) {
+        let next = self.builder.create_block();
+        self.builder
+            .ins()
+            .brif(condition, self.exit, &[status], next, &[]);
+        self.builder.switch_to_block(next);
+    }
+
+    /// Division which never traps, assuming the divisor isn't zero.
+    fn divide(
+        &mut self,
+        op: IntegerOp,
+        l: Value,
+        r: Value,
+        unsigned: bool,
+    ) -> Value {
+        if unsigned {
+            return match op {
+                IntegerOp::Divide => self.builder.ins().udiv(l, r),
+                _ => self.builder.ins().urem(l, r),
+            };
+        }
+
+        // i64::MIN / -1 overflows, which the hardware treats as an error
+        let minus_one = self.builder.ins().icmp_imm(IntCC::Equal, r, -1);
+        let one = self.builder.ins().iconst(types::I64, 1);
+        let divisor = self.builder.ins().select(minus_one, one, r);
+
+        match op {
+            IntegerOp::Divide => {
+                let quotient = self.builder.ins().sdiv(l, divisor);
+                let negated = self.builder.ins().ineg(l);
+                self.builder.ins().select(minus_one, negated, quotient)
+            }
+            _ => {
+                let remainder = self.builder.ins().srem(l, divisor);
+                let zero = self.builder.ins().iconst(types::I64, 0);
+                self.builder.ins().select(minus_one, zero, remainder)
+            }
+        }
+    }
+
+    /// Calculate `base ** exponent` with wrapping multiplication, using
+    /// exponentiation by squaring.
+    fn power(&mut self, base: Value, exponent: Value) -> Value {
+        let header = self.builder.create_block();
+        let header_base = self.builder.append_block_param(header, types::I64);
+        let header_exp = self.builder.append_block_param(header, types::I64);
+        let header_acc = self.builder.append_block_param(header, types::I64);
+        let step = self.builder.create_block();
+        let done = self.builder.create_block();
+        let result = self.builder.append_block_param(done, types::I64);
+
+        let one = self.builder.ins().iconst(types::I64, 1);
+        self.builder.ins().jump(header, &[base, exponent, one]);
+
+        self.builder.switch_to_block(header);
+        self.builder
+            .ins()
+            .brif(header_exp, step, &[], done, &[header_acc]);
+
+        self.builder.switch_to_block(step);
+        let odd = self.builder.ins().band_imm(header_exp, 1);
+        let multiplied = self.builder.ins().imul(header_acc, header_base);
+        let acc = self.builder.ins().select(odd, multiplied, header_acc);
+        let squared = self.builder.ins().imul(header_base, header_base);
+        let halved = self.builder.ins().ushr_imm(header_exp, 1);
+        self.builder.ins().jump(header, &[squared, halved, acc]);
+
+        self.builder.switch_to_block(done);
+        result
+    }
+
+    /// Allocate a zeroed frame on the stack for a function call.
+    fn new_frame(&mut self, pou: usize) -> Place {
+        let size = self.layout.pous[pou].frame.size.div_ceil(8) * 8;
+        let slot = self.builder.create_sized_stack_slot(StackSlotData::new(
+            StackSlotKind::ExplicitSlot,
+            size,
+            3,
+        ));
+        let ptr = self.module.target_config().pointer_type();
+        let base = self.builder.ins().stack_addr(ptr, slot, 0);
+
+        let zero = self.builder.ins().iconst(types::I64, 0);
+        for offset in (0..size).step_by(8) {
+            self.builder.ins().store(
+                MemFlags::trusted(),
+                zero,
+                base,
+                offset as i32,
+            );
+        }
+
+        Place {
+            base,
+            offset: 0,
+            ty: FieldType::Instance(pou),
+        }
+    }
+
+    fn callee(&mut self, pou: usize) -> FuncRef {
+        if let Some(&callee) = self.callees.get(&pou) {
+            return callee;
+        }
+
+        let callee = self
+            .module
+            .declare_func_in_func(self.pou_ids[pou], self.builder.func);
+        self.callees.insert(pou, callee);
+        callee
+    }
+}
+
+impl<'t, 'a, M: Module> Lower<'a> for Translator<'t, 'a, M> {
+    type Value = Value;
+    type Place = Place;
+
+    fn tables(&self) -> &Tables<'a> {
+        self.tables
+    }
+
+    fn place(&mut self, variable: EntityId) -> Place {
+        let location = self
+            .layout
+            .location(variable)
+            .expect("every variable has a location");
+        debug_assert!(location.owner.is_none_or(|pou| pou == self.pou));
+
+        let field = self.layout.field(location);
+        let base = match location.owner {
+            Some(_) => self.this,
+            None => self.globals,
+        };
+
+        Place {
+            base,
+            offset: field.offset,
+            ty: field.ty,
+        }
+    }
+
+    fn member(&mut self, object: &Place, member: EntityId) -> Place {
+        let location = self
+            .layout
+            .location(member)
+            .expect("every variable has a location");
+        let field = self.layout.field(location);
+
+        Place {
+            base: object.base,
+            offset: object.offset + field.offset,
+            ty: field.ty,
+        }
+    }
+
+    fn place_type(&self, place: &Place) -> VariableType {
+        place.ty.into()
+    }
+
+    fn load(&mut self, place: &Place, ty: Scalar) -> Value {
+        self.builder.ins().load(
+            clif_type(ty),
+            MemFlags::trusted(),
+            place.base,
+            place.offset as i32,
+        )
+    }
+
+    fn store(&mut self, place: &Place, value: &Typed<Value>) {
+        self.builder.ins().store(
+            MemFlags::trusted(),
+            value.value,
+            place.base,
+            place.offset as i32,
+        );
+    }
+
+    /// Copy an aggregate using the widest loads and stores possible.
+    fn copy(&mut self, dest: &Place, src: &Place) {
+        let size = self.layout.size_of(dest.ty);
+        let mut copied = 0;
+
+        for &(width, ty) in &[
+            (8, types::I64),
+            (4, types::I32),
+            (2, types::I16),
+            (1, types::I8),
+        ] {
+            while size - copied >= width {
+                let value = self.builder.ins().load(
+                    ty,
+                    MemFlags::new(),
+                    src.base,
+                    (src.offset + copied) as i32,
+                );
+                self.builder.ins().store(
+                    MemFlags::new(),
+                    value,
+                    dest.base,
+                    (dest.offset + copied) as i32,
+                );
+                copied += width;
+            }
+        }
+    }
+
+    fn boolean(&mut self, value: bool) -> Value {
+        self.builder.ins().iconst(types::I8, i64::from(value))
+    }
+
+    fn integer(&mut self, value: i64, ty: IntegerType) -> Value {
+        self.builder.ins().iconst(integer_type(ty.bits()), value)
+    }
+
+    fn real(&mut self, value: f64) -> Value {
+        self.builder.ins().f64const(value)
+    }
+
+    fn resize(&mut self, value: &Typed<Value>, to: IntegerType) -> Value {
+        match value.ty {
+            Scalar::Integer(from) => self.cast(value.value, from, to.bits()),
+            _ => unreachable!("only integers are resized"),
+        }
+    }
+
+    fn int_to_real(&mut self, value: &Typed<Value>) -> Value {
+        let wide = self.wide(value);
+
+        match value.ty {
+            Scalar::Integer(int) if int.is_signed() => {
+                self.builder.ins().fcvt_from_sint(types::F64, wide)
+            }
+            _ => self.builder.ins().fcvt_from_uint(types::F64, wide),
+        }
+    }
+
+    fn is_zero(&mut self, value: &Typed<Value>) -> Value {
+        match value.ty {
+            Scalar::Real => {
+                let zero = self.builder.ins().f64const(0.0);
+                self.builder.ins().fcmp(FloatCC::Equal, value.value, zero)
+            }
+            _ => self.builder.ins().icmp_imm(IntCC::Equal, value.value, 0),
+        }
+    }
+
+    fn is_negative(&mut self, value: &Typed<Value>) -> Value {
+        self.builder
+            .ins()
+            .icmp_imm(IntCC::SignedLessThan, value.value, 0)
+    }
+
+    /// Abort execution, returning the trap's status code, if `condition` is
+    /// true.
+    fn trap_if(&mut self, condition: Value, trap: Trap) {
+        let code = match trap {
+            Trap::DivisionByZero => status::DIVISION_BY_ZERO,
+            Trap::NegativeExponent => status::NEGATIVE_EXPONENT,
+            Trap::InvalidOperands => status::INVALID_OPERANDS,
+        };
+        let code = self.builder.ins().iconst(types::I32, i64::from(code));
+        self.return_if_nonzero(condition, code);
+    }
+
+    fn compare_integers(
+        &mut self,
+        comparison: Comparison,
+        left: &Typed<Value>,
+        right: &Typed<Value>,
+        unsigned: bool,
+    ) -> Value {
+        let l = self.wide(left);
+        let r = self.wide(right);
+        self.builder
+            .ins()
+            .icmp(integer_comparison(comparison, unsigned), l, r)
+    }
+
+    fn compare_reals(
+        &mut self,
+        comparison: Comparison,
+        left: &Value,
+        right: &Value,
+    ) -> Value {
+        self.builder
+            .ins()
+            .fcmp(float_comparison(comparison), *left, *right)
+    }
+
+    fn bool_binary(
+        &mut self,
+        op: BoolOp,
+        left: &Value,
+        right: &Value,
+    ) -> Value {
+        let ins = self.builder.ins();
+
+        match op {
+            BoolOp::And => ins.band(*left, *right),
+            BoolOp::Or => ins.bor(*left, *right),
+            BoolOp::Xor => ins.bxor(*left, *right),
+            BoolOp::Equal => ins.icmp(IntCC::Equal, *left, *right),
+            BoolOp::NotEqual => ins.icmp(IntCC::NotEqual, *left, *right),
+        }
+    }
+
+    /// Integer arithmetic is done using 64 bits then truncated, so it wraps
+    /// the same way the interpreter does.
+    fn integer_arithmetic(
+        &mut self,
+        op: IntegerOp,
+        left: &Typed<Value>,
+        right: &Typed<Value>,
+        ty: IntegerType,
+        unsigned: bool,
+    ) -> Value {
+        let l = self.wide(left);
+        let r = self.wide(right);
+
+        let value = match op {
+            IntegerOp::Add => self.builder.ins().iadd(l, r),
+            IntegerOp::Subtract => self.builder.ins().isub(l, r),
+            IntegerOp::Multiply => self.builder.ins().imul(l, r),
+            IntegerOp::And => self.builder.ins().band(l, r),
+            IntegerOp::Or => self.builder.ins().bor(l, r),
+            IntegerOp::Xor => self.builder.ins().bxor(l, r),
+            IntegerOp::Divide | IntegerOp::Modulo => {
+                self.divide(op, l, r, unsigned)
+            }
+            IntegerOp::Exponent => self.power(l, r),
+        };
+
+        self.cast(value, IntegerType::LInt, ty.bits())
+    }
+
+    fn real_arithmetic(
+        &mut self,
+        op: RealOp,
+        left: &Value,
+        right: &Value,
+    ) -> Value {
+        let ins = self.builder.ins();
+
+        match op {
+            RealOp::Add => ins.fadd(*left, *right),
+            RealOp::Subtract => ins.fsub(*left, *right),
+            RealOp::Multiply => ins.fmul(*left, *right),
+            RealOp::Divide => ins.fdiv(*left, *right),
+        }
+    }
+
+    fn not(&mut self, value: &Typed<Value>) -> Value {
+        match value.ty {
+            Scalar::Bool => self.builder.ins().bxor_imm(value.value, 1),
+            _ => self.builder.ins().bnot(value.value),
+        }
+    }
+
+    fn negate(&mut self, value: &Typed<Value>) -> Value {
+        match value.ty {
+            Scalar::Real => self.builder.ins().fneg(value.value),
+            _ => self.builder.ins().ineg(value.value),
+        }
+    }
+
+    fn abs(&mut self, value: &Typed<Value>) -> Value {
+        match value.ty {
+            Scalar::Real => self.builder.ins().fabs(value.value),
+            _ => self.builder.ins().iabs(value.value),
+        }
+    }
+
+    fn select(
+        &mut self,
+        condition: &Value,
+        if_true: &Typed<Value>,
+        if_false: &Typed<Value>,
+    ) -> Value {
+        self.builder
+            .ins()
+            .select(*condition, if_true.value, if_false.value)
+    }
+
+    fn shift(
+        &mut self,
+        shift: Shift,
+        value: &Typed<Value>,
+        amount: &Typed<Value>,
+    ) -> Value {
+        let bits = match value.ty {
+            Scalar::Integer(int) => int.bits(),
+            _ => unreachable!("only integers are shifted"),
+        };
+        let n = self.wide(amount);
+
+        match shift {
+            Shift::RotateLeft => self.builder.ins().rotl(value.value, n),
+            Shift::RotateRight => self.builder.ins().rotr(value.value, n),
+            Shift::Left | Shift::Right => {
+                // shifting by the type's width (or more) clears every bit,
+                // but cranelift only uses the bottom few bits of n
+                let too_far = self.builder.ins().icmp_imm(
+                    IntCC::UnsignedGreaterThanOrEqual,
+                    n,
+                    i64::from(bits),
+                );
+                let shifted = if shift.is_left() {
+                    self.builder.ins().ishl(value.value, n)
+                } else {
+                    self.builder.ins().ushr(value.value, n)
+                };
+                let zero = self.builder.ins().iconst(integer_type(bits), 0);
+                self.builder.ins().select(too_far, zero, shifted)
+            }
+        }
+    }
+
+    fn pou_index(&self, symbol: Symbol) -> Option<usize> {
+        self.layout.pou_index(symbol)
+    }
+
+    fn begin_call(&mut self, pou: usize, instance: Option<Place>) -> Place {
+        match instance {
+            Some(instance) => instance,
+            None => self.new_frame(pou),
+        }
+    }
+
+    fn invoke(&mut self, pou: usize, frame: &Place) {
+        let callee = self.callee(pou);
+        let address = if frame.offset == 0 {
+            frame.base
+        } else {
+            self.builder
+                .ins()
+                .iadd_imm(frame.base, i64::from(frame.offset))
+        };
+        let call = self.builder.ins().call(callee, &[self.globals, address]);
+        let status = self.builder.inst_results(call)[0];
+        self.return_if_nonzero(status, status);
+    }
+}
+
+fn integer_comparison(comparison: Comparison, unsigned: bool) -> IntCC {
+    match (comparison, unsigned) {
+        (Comparison::Equal, _) => IntCC::Equal,
+        (Comparison::NotEqual, _) => IntCC::NotEqual,
+        (Comparison::Less, false) => IntCC::SignedLessThan,
+        (Comparison::Less, true) => IntCC::UnsignedLessThan,
+        (Comparison::LessOrEqual, false) => IntCC::SignedLessThanOrEqual,
+        (Comparison::LessOrEqual, true) => IntCC::UnsignedLessThanOrEqual,
+        (Comparison::Greater, false) => IntCC::SignedGreaterThan,
+        (Comparison::Greater, true) => IntCC::UnsignedGreaterThan,
+        (Comparison::GreaterOrEqual, false) => IntCC::SignedGreaterThanOrEqual,
+        (Comparison::GreaterOrEqual, true) => IntCC::UnsignedGreaterThanOrEqual,
+    }
+}
+
+fn float_comparison(comparison: Comparison) -> FloatCC {
+    match comparison {
+        Comparison::Equal => FloatCC::Equal,
+        Comparison::NotEqual => FloatCC::NotEqual,
+        Comparison::Less => FloatCC::LessThan,
+        Comparison::LessOrEqual => FloatCC::LessThanOrEqual,
+        Comparison::Greater => FloatCC::GreaterThan,
+        Comparison::GreaterOrEqual => FloatCC::GreaterThanOrEqual,
+    }
+}
@@ -0,0 +1,829 @@
+//! Translating the HIR for a single POU into an LLVM function.
+//!
+//! Variables are always accessed through memory (the POU's struct, the
+//! globals, or an `alloca` for temporaries), so the generated code doesn't
+//! need to be in SSA form. Running LLVM's `mem2reg` pass (e.g. `opt -O2`)
+//! will promote them to registers.
+
+use crate::items::{Items, PouKind, Storage, VariableType};
+use crate::{runtime, status, CodegenError, Writer};
+use iec::analysis::reachable_blocks;
+use iec::codegen::{
+    BoolOp, Comparison, IntegerOp, Lower, LowerError, RealOp, Tables, Trap,
+    Typed,
+};
+use iec::const_eval::{IntegerType, Scalar};
+use iec::ecs::EntityId;
+use iec::hir::{BinaryOp, Symbol, Terminator};
+use iec::passes::register_builtins::Shift;
+use std::collections::BTreeSet;
+use std::fmt;
+
+/// Write out the definition of a POU's function, adding any intrinsics it
+/// uses to `intrinsics`.
+pub(crate) fn define(
+    out: &mut Writer,
+    tables: &Tables<'_>,
+    items: &Items,
+    intrinsics: &mut BTreeSet<String>,
+    index: usize,
+) -> Result<(), CodegenError> {
+    let pou = &items.pous()[index];
+
+    let this = match pou.kind {
+        PouKind::Program => {
+            out.line(format_args!("define i32 @{}() {{", pou.function_name()));
+            format!("@{}", pou.instance_name())
+        }
+        PouKind::Function | PouKind::FunctionBlock => {
+            out.line(format_args!(
+                "define i32 @{}(ptr %self) {{",
+                pou.function_name()
+            ));
+            String::from("%self")
+        }
+    };
+
+    let mut translator = Translator {
+        out: Writer::indented(),
+        tables,
+        items,
+        intrinsics,
+        this,
+        this_type: pou.type_name(),
+        frames: Vec::new(),
+        next_value: 0,
+        next_label: 0,
+        current_label: String::from("entry"),
+    };
+    let order = reachable_blocks(pou.entry_block, tables.blocks);
+    translator.body(&order)?;
+
+    // allocas go in the entry block so they're only allocated once
+    out.indent();
+    out.label("entry:");
+    for (i, temporary) in pou.temporaries.iter().enumerate() {
+        let ty = items.type_name(temporary.ty);
+        out.line(format_args!("%tmp{} = alloca {}", i, ty));
+        out.line(format_args!(
+            "store {} {}, ptr %tmp{}",
+            ty,
+            zero(temporary.ty),
+            i
+        ));
+    }
+    for (i, &frame) in translator.frames.iter().enumerate() {
+        out.line(format_args!(
+            "%frame{} = alloca %{}",
+            i,
+            items.pous()[frame].type_name()
+        ));
+    }
+    out.line("br label %bb0");
+    out.raw(&translator.out.finish());
+    out.dedent();
+    out.line("}");
+
+    Ok(())
+}
+
+fn zero(ty: VariableType) -> &'static str {
+    match ty {
+        VariableType::Bool | VariableType::Integer(_) => "0",
+        VariableType::Real => "0.0",
+        VariableType::String | VariableType::Instance(_) => "zeroinitializer",
+    }
+}
+
+/// The LLVM type used for a [`Scalar`] while it's in a register (booleans
+/// are an `i1`).
+fn register_type(scalar: Scalar) -> String {
+    match scalar {
+        Scalar::Bool => String::from("i1"),
+        Scalar::Integer(int) => format!("i{}", int.bits()),
+        Scalar::Real => String::from("double"),
+    }
+}
+
+/// A pointer to something in memory.
+#[derive(Debug, Clone, PartialEq)]
+struct Place {
+    ptr: String,
+    ty: VariableType,
+}
+
+/// A register or constant, and its type.
+type Value = Typed<String>;
+
+struct Translator<'t, 'a> {
+    out: Writer,
+    tables: &'t Tables<'a>,
+    items: &'t Items,
+    intrinsics: &'t mut BTreeSet<String>,
+    /// A pointer to the POU's own struct.
+    this: String,
+    this_type: String,
+    /// The POU each function call frame is for.
+    frames: Vec<usize>,
+    next_value: usize,
+    next_label: usize,
+    /// The block currently being written, used by `phi` nodes.
+    current_label: String,
+}
+
+impl<'t, 'a> Translator<'t, 'a> {
+    fn body(&mut self, order: &[EntityId]) -> Result<(), CodegenError> {
+        for &block in order {
+            self.start_block(block_label(order, block));
+            self.block(order, block)?;
+        }
+
+        Ok(())
+    }
+
+    fn start_block(&mut self, label: String) {
+        self.out.label(format_args!("{}:", label));
+        self.current_label = label;
+    }
+
+    /// Emit an instruction which produces a value, returning the register
+    /// it was saved to.
+    fn emit<D: fmt::Display>(&mut self, instruction: D) -> String {
+        let register = self.fresh_value();
+        self.assign(&register, instruction);
+        register
+    }
+
+    fn fresh_value(&mut self) -> String {
+        let register = format!("%v{}", self.next_value);
+        self.next_value += 1;
+        register
+    }
+
+    fn assign<D: fmt::Display>(&mut self, register: &str, instruction: D) {
+        self.out
+            .line(format_args!("{} = {}", register, instruction));
+    }
+
+    fn fresh_label(&mut self) -> String {
+        let label = format!("l{}", self.next_label);
+        self.next_label += 1;
+        label
+    }
+
+    fn block(
+        &mut self,
+        order: &[EntityId],
+        id: EntityId,
+    ) -> Result<(), CodegenError> {
+        let block = self.tables.block(id);
+        self.instructions(block)?;
+
+        match block.terminator {
+            Terminator::Jump(target) => {
+                self.out.line(format_args!(
+                    "br label %{}",
+                    block_label(order, target)
+                ));
+            }
+            Terminator::Branch {
+                condition,
+                then,
+                otherwise,
+            } => {
+                let condition = self.condition(condition)?;
+                self.out.line(format_args!(
+                    "br i1 {}, label %{}, label %{}",
+                    condition,
+                    block_label(order, then),
+                    block_label(order, otherwise)
+                ));
+            }
+            Terminator::Return => {
+                self.out.line(format_args!("ret i32 {}", status::OK));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn field_pointer(&mut self, ty: &str, ptr: &str, index: usize) -> String {
+        self.emit(format_args!(
+            "getelementptr inbounds %{}, ptr {}, i32 0, i32 {}",
+            ty, ptr, index
+        ))
+    }
+
+    /// Sign or zero extend (or truncate) an integer to `bits` bits.
+    fn extend(&mut self, value: &Value, bits: u32) -> String {
+        let from = match value.ty {
+            Scalar::Integer(from) => from,
+            _ => unreachable!("only integers are resized"),
+        };
+        let instruction = if bits > from.bits() && from.is_signed() {
+            "sext"
+        } else if bits > from.bits() {
+            "zext"
+        } else if bits < from.bits() {
+            "trunc"
+        } else {
+            return value.value.clone();
+        };
+
+        self.emit(format_args!(
+            "{} i{} {} to i{}",
+            instruction,
+            from.bits(),
+            value.value,
+            bits
+        ))
+    }
+
+    /// Abort execution, returning `status`, if `condition` is true.
+    fn return_if<D: fmt::Display>(&mut self, condition: &str, status: D) {
+        let (failed, ok) = (self.fresh_label(), self.fresh_label());
+        self.out.line(format_args!(
+            "br i1 {}, label %{}, label %{}",
+            condition, failed, ok
+        ));
+        self.start_block(failed);
+        self.out.line(format_args!("ret i32 {}", status));
+        self.start_block(ok);
+    }
+
+    /// Division which is always defined, assuming the divisor isn't zero.
+    fn divide(
+        &mut self,
+        op: IntegerOp,
+        l: &str,
+        r: &str,
+        unsigned: bool,
+    ) -> String {
+        if unsigned {
+            let instruction = match op {
+                IntegerOp::Divide => "udiv",
+                _ => "urem",
+            };
+            return self.emit(format_args!("{} i64 {}, {}", instruction, l, r));
+        }
+
+        // i64::MIN / -1 overflows, which is undefined behaviour in LLVM
+        let minus_one = self.emit(format_args!("icmp eq i64 {}, -1", r));
+        let divisor = self
+            .emit(format_args!("select i1 {}, i64 1, i64 {}", minus_one, r));
+
+        let (instruction, special_case) = match op {
+            IntegerOp::Divide => {
+                ("sdiv", self.emit(format_args!("sub i64 0, {}", l)))
+            }
+            _ => ("srem", String::from("0")),
+        };
+        let result =
+            self.emit(format_args!("{} i64 {}, {}", instruction, l, divisor));
+
+        self.emit(format_args!(
+            "select i1 {}, i64 {}, i64 {}",
+            minus_one, special_case, result
+        ))
+    }
+
+    /// Calculate `base ** exponent` with wrapping multiplication, using
+    /// exponentiation by squaring.
+    fn power(&mut self, base: &str, exponent: &str) -> String {
+        let entry = self.current_label.clone();
+        let (header, step, done) =
+            (self.fresh_label(), self.fresh_label(), self.fresh_label());
+        // the loop body's results are used by the phi nodes before they're
+        // defined
+        let (next_b, next_e, next_acc) =
+            (self.fresh_value(), self.fresh_value(), self.fresh_value());
+
+        self.out.line(format_args!("br label %{}", header));
+        self.start_block(header.clone());
+        let b = self.emit(format_args!(
+            "phi i64 [ {}, %{} ], [ {}, %{} ]",
+            base, entry, next_b, step
+        ));
+        let e = self.emit(format_args!(
+            "phi i64 [ {}, %{} ], [ {}, %{} ]",
+            exponent, entry, next_e, step
+        ));
+        let acc = self.emit(format_args!(
+            "phi i64 [ 1, %{} ], [ {}, %{} ]",
+            entry, next_acc, step
+        ));
+        let finished = self.emit(format_args!("icmp eq i64 {}, 0", e));
+        self.out.line(format_args!(
+            "br i1 {}, label %{}, label %{}",
+            finished, done, step
+        ));
+
+        self.start_block(step);
+        let odd = self.emit(format_args!("trunc i64 {} to i1", e));
+        let multiplied = self.emit(format_args!("mul i64 {}, {}", acc, b));
+        self.assign(&next_b, format_args!("mul i64 {}, {}", b, b));
+        self.assign(&next_e, format_args!("lshr i64 {}, 1", e));
+        self.assign(
+            &next_acc,
+            format_args!("select i1 {}, i64 {}, i64 {}", odd, multiplied, acc),
+        );
+        self.out.line(format_args!("br label %{}", header));
+
+        self.start_block(done);
+        acc
+    }
+
+    /// Get a zeroed frame for calling a function.
+    fn new_frame(&mut self, pou: usize) -> Place {
+        let ptr = format!("%frame{}", self.frames.len());
+        self.frames.push(pou);
+        self.out.line(format_args!(
+            "store %{} zeroinitializer, ptr {}",
+            self.items.pous()[pou].type_name(),
+            ptr
+        ));
+
+        Place {
+            ptr,
+            ty: VariableType::Instance(pou),
+        }
+    }
+
+    /// Call an LLVM intrinsic, making sure it gets declared.
+    fn intrinsic(
+        &mut self,
+        name: &str,
+        ty: &str,
+        params: &[&str],
+        args: &[String],
+    ) -> String {
+        self.intrinsics.insert(format!(
+            "declare {} @{}({})",
+            ty,
+            name,
+            params.join(", ")
+        ));
+
+        let args: Vec<String> = params
+            .iter()
+            .zip(args)
+            .map(|(param, arg)| format!("{} {}", param, arg))
+            .collect();
+        self.emit(format_args!("call {} @{}({})", ty, name, args.join(", ")))
+    }
+}
+
+impl<'t, 'a> Lower<'a> for Translator<'t, 'a> {
+    type Value = String;
+    type Place = Place;
+
+    fn tables(&self) -> &Tables<'a> {
+        self.tables
+    }
+
+    fn place(&mut self, variable: EntityId) -> Place {
+        let location = self.items.location(variable);
+
+        let ptr = match location.storage {
+            Storage::Field(index) => {
+                let (this, ty) = (self.this.clone(), self.this_type.clone());
+                self.field_pointer(&ty, &this, index)
+            }
+            Storage::Global(index) => {
+                self.field_pointer("iec_globals_t", "@iec_globals", index)
+            }
+            Storage::Local(index) => format!("%tmp{}", index),
+        };
+
+        Place {
+            ptr,
+            ty: location.ty,
+        }
+    }
+
+    fn member(&mut self, object: &Place, member: EntityId) -> Place {
+        let location = self.items.location(member);
+        let (ty, index) = match (object.ty, location.storage) {
+            (VariableType::Instance(pou), Storage::Field(index)) => {
+                (self.items.pous()[pou].type_name(), index)
+            }
+            _ => unreachable!("only function blocks have members"),
+        };
+
+        Place {
+            ptr: self.field_pointer(&ty, &object.ptr, index),
+            ty: location.ty,
+        }
+    }
+
+    fn place_type(&self, place: &Place) -> VariableType {
+        place.ty
+    }
+
+    fn load(&mut self, place: &Place, ty: Scalar) -> String {
+        let stored = self.items.type_name(place.ty);
+        let value =
+            self.emit(format_args!("load {}, ptr {}", stored, place.ptr));
+
+        match ty {
+            Scalar::Bool => self.emit(format_args!("trunc i8 {} to i1", value)),
+            _ => value,
+        }
+    }
+
+    fn store(&mut self, place: &Place, value: &Value) {
+        let stored = match value.ty {
+            Scalar::Bool => {
+                self.emit(format_args!("zext i1 {} to i8", value.value))
+            }
+            _ => value.value.clone(),
+        };
+        self.out.line(format_args!(
+            "store {} {}, ptr {}",
+            self.items.type_name(place.ty),
+            stored,
+            place.ptr
+        ));
+    }
+
+    fn copy(&mut self, dest: &Place, src: &Place) {
+        if dest.ty == VariableType::String {
+            self.out.line(format_args!(
+                "call void @{}(ptr {}, ptr {})",
+                runtime::STRING_ASSIGN,
+                dest.ptr,
+                src.ptr
+            ));
+        } else {
+            let ty = self.items.type_name(dest.ty);
+            let value = self.emit(format_args!("load {}, ptr {}", ty, src.ptr));
+            self.out
+                .line(format_args!("store {} {}, ptr {}", ty, value, dest.ptr));
+        }
+    }
+
+    fn boolean(&mut self, value: bool) -> String {
+        value.to_string()
+    }
+
+    fn integer(&mut self, value: i64, _ty: IntegerType) -> String {
+        value.to_string()
+    }
+
+    /// Hexadecimal is the only way to write a double exactly.
+    fn real(&mut self, value: f64) -> String {
+        format!("0x{:016X}", value.to_bits())
+    }
+
+    fn resize(&mut self, value: &Value, to: IntegerType) -> String {
+        self.extend(value, to.bits())
+    }
+
+    fn int_to_real(&mut self, value: &Value) -> String {
+        let instruction = match value.ty {
+            Scalar::Integer(int) if int.is_signed() => "sitofp",
+            _ => "uitofp",
+        };
+
+        self.emit(format_args!(
+            "{} {} {} to double",
+            instruction,
+            register_type(value.ty),
+            value.value
+        ))
+    }
+
+    fn is_zero(&mut self, value: &Value) -> String {
+        match value.ty {
+            Scalar::Real => {
+                self.emit(format_args!("fcmp oeq double {}, 0.0", value.value))
+            }
+            ty => self.emit(format_args!(
+                "icmp eq {} {}, 0",
+                register_type(ty),
+                value.value
+            )),
+        }
+    }
+
+    fn is_negative(&mut self, value: &Value) -> String {
+        self.emit(format_args!(
+            "icmp slt {} {}, 0",
+            register_type(value.ty),
+            value.value
+        ))
+    }
+
+    fn trap_if(&mut self, condition: String, trap: Trap) {
+        let status = match trap {
+            Trap::DivisionByZero => status::DIVISION_BY_ZERO,
+            Trap::NegativeExponent => status::NEGATIVE_EXPONENT,
+            Trap::InvalidOperands => status::INVALID_OPERANDS,
+        };
+        self.return_if(&condition, status);
+    }
+
+    /// Integers are compared as 64-bit values so both sides have the same
+    /// width.
+    fn compare_integers(
+        &mut self,
+        comparison: Comparison,
+        left: &Value,
+        right: &Value,
+        unsigned: bool,
+    ) -> String {
+        let l = self.extend(left, 64);
+        let r = self.extend(right, 64);
+
+        self.emit(format_args!(
+            "icmp {} i64 {}, {}",
+            integer_comparison(comparison, unsigned),
+            l,
+            r
+        ))
+    }
+
+    fn compare_reals(
+        &mut self,
+        comparison: Comparison,
+        left: &String,
+        right: &String,
+    ) -> String {
+        self.emit(format_args!(
+            "fcmp {} double {}, {}",
+            float_comparison(comparison),
+            left,
+            right
+        ))
+    }
+
+    fn bool_binary(
+        &mut self,
+        op: BoolOp,
+        left: &String,
+        right: &String,
+    ) -> String {
+        let instruction = match op {
+            BoolOp::And => "and",
+            BoolOp::Or => "or",
+            BoolOp::Xor => "xor",
+            BoolOp::Equal => "icmp eq",
+            BoolOp::NotEqual => "icmp ne",
+        };
+
+        self.emit(format_args!("{} i1 {}, {}", instruction, left, right))
+    }
+
+    /// Integer arithmetic is done using 64 bits then truncated to the
+    /// operands' common type, so it wraps the same way the interpreter does.
+    fn integer_arithmetic(
+        &mut self,
+        op: IntegerOp,
+        left: &Value,
+        right: &Value,
+        ty: IntegerType,
+        unsigned: bool,
+    ) -> String {
+        let l = self.extend(left, 64);
+        let r = self.extend(right, 64);
+
+        let value = match op {
+            IntegerOp::Add => self.emit(format_args!("add i64 {}, {}", l, r)),
+            IntegerOp::Subtract => {
+                self.emit(format_args!("sub i64 {}, {}", l, r))
+            }
+            IntegerOp::Multiply => {
+                self.emit(format_args!("mul i64 {}, {}", l, r))
+            }
+            IntegerOp::And => self.emit(format_args!("and i64 {}, {}", l, r)),
+            IntegerOp::Or => self.emit(format_args!("or i64 {}, {}", l, r)),
+            IntegerOp::Xor => self.emit(format_args!("xor i64 {}, {}", l, r)),
+            IntegerOp::Divide | IntegerOp::Modulo => {
+                self.divide(op, &l, &r, unsigned)
+            }
+            IntegerOp::Exponent => self.power(&l, &r),
+        };
+
+        let wide = Typed::new(value, Scalar::Integer(IntegerType::LInt));
+        self.extend(&wide, ty.bits())
+    }
+
+    fn real_arithmetic(
+        &mut self,
+        op: RealOp,
+        left: &String,
+        right: &String,
+    ) -> String {
+        let instruction = match op {
+            RealOp::Add => "fadd",
+            RealOp::Subtract => "fsub",
+            RealOp::Multiply => "fmul",
+            RealOp::Divide => "fdiv",
+        };
+
+        self.emit(format_args!("{} double {}, {}", instruction, left, right))
+    }
+
+    fn not(&mut self, value: &Value) -> String {
+        match value.ty {
+            Scalar::Bool => {
+                self.emit(format_args!("xor i1 {}, true", value.value))
+            }
+            ty => self.emit(format_args!(
+                "xor {} {}, -1",
+                register_type(ty),
+                value.value
+            )),
+        }
+    }
+
+    fn negate(&mut self, value: &Value) -> String {
+        match value.ty {
+            Scalar::Real => {
+                self.emit(format_args!("fneg double {}", value.value))
+            }
+            ty => self.emit(format_args!(
+                "sub {} 0, {}",
+                register_type(ty),
+                value.value
+            )),
+        }
+    }
+
+    fn abs(&mut self, value: &Value) -> String {
+        match value.ty {
+            Scalar::Real => self.intrinsic(
+                "llvm.fabs.f64",
+                "double",
+                &["double"],
+                std::slice::from_ref(&value.value),
+            ),
+            ty => {
+                let ty = register_type(ty);
+                self.intrinsic(
+                    &format!("llvm.abs.{}", ty),
+                    &ty,
+                    &[&ty, "i1"],
+                    &[value.value.clone(), String::from("false")],
+                )
+            }
+        }
+    }
+
+    fn select(
+        &mut self,
+        condition: &String,
+        if_true: &Value,
+        if_false: &Value,
+    ) -> String {
+        let ty = register_type(if_true.ty);
+        self.emit(format_args!(
+            "select i1 {}, {} {}, {} {}",
+            condition, ty, if_true.value, ty, if_false.value
+        ))
+    }
+
+    /// Lower a [`Shift`], using the funnel shift intrinsics for rotations.
+    fn shift(&mut self, shift: Shift, value: &Value, amount: &Value) -> String {
+        let ty = match value.ty {
+            Scalar::Integer(ty) => ty,
+            _ => unreachable!("only integers are shifted"),
+        };
+        let ir_type = register_type(value.ty);
+
+        let wide = self.extend(amount, 64);
+        // the bit widths are all powers of two, so truncating doesn't
+        // change the amount modulo the width
+        let wide_amount =
+            Typed::new(wide.clone(), Scalar::Integer(IntegerType::ULInt));
+        let amount = self.extend(&wide_amount, ty.bits());
+
+        match shift {
+            Shift::RotateLeft | Shift::RotateRight => {
+                let funnel_shift =
+                    if shift.is_left() { "fshl" } else { "fshr" };
+                self.intrinsic(
+                    &format!("llvm.{}.{}", funnel_shift, ir_type),
+                    &ir_type,
+                    &[&ir_type, &ir_type, &ir_type],
+                    &[value.value.clone(), value.value.clone(), amount],
+                )
+            }
+            Shift::Left | Shift::Right => {
+                // shifting by the type's width (or more) clears every bit,
+                // but LLVM says the result is poison
+                let instruction = if shift.is_left() { "shl" } else { "lshr" };
+                let too_far = self.emit(format_args!(
+                    "icmp uge i64 {}, {}",
+                    wide,
+                    ty.bits()
+                ));
+                let shifted = self.emit(format_args!(
+                    "{} {} {}, {}",
+                    instruction, ir_type, value.value, amount
+                ));
+                self.emit(format_args!(
+                    "select i1 {}, {} 0, {} {}",
+                    too_far, ir_type, ir_type, shifted
+                ))
+            }
+        }
+    }
+
+    fn pou_index(&self, symbol: Symbol) -> Option<usize> {
+        self.items.pou_index(symbol)
+    }
+
+    fn begin_call(&mut self, pou: usize, instance: Option<Place>) -> Place {
+        match instance {
+            Some(instance) => instance,
+            None => self.new_frame(pou),
+        }
+    }
+
+    fn invoke(&mut self, pou: usize, frame: &Place) {
+        let status = self.emit(format_args!(
+            "call i32 @{}(ptr {})",
+            self.items.pous()[pou].function_name(),
+            frame.ptr
+        ));
+        let failed = self.emit(format_args!("icmp ne i32 {}, 0", status));
+        self.return_if(&failed, &status);
+    }
+
+    /// Strings are compared by the runtime library, which returns a
+    /// negative number, zero, or a positive number like `strcmp()`.
+    fn aggregate_binary(
+        &mut self,
+        op: BinaryOp,
+        left: &Place,
+        right: &Place,
+    ) -> Result<Value, LowerError> {
+        let strings =
+            left.ty == VariableType::String && right.ty == VariableType::String;
+        let comparison = match Comparison::from_op(op) {
+            Some(comparison) if strings => comparison,
+            Some(_) => {
+                return Err(LowerError::TypeMismatch {
+                    expected: String::from("value"),
+                    found: left.ty.to_string(),
+                })
+            }
+            None => {
+                return Err(LowerError::TypeMismatch {
+                    expected: format!("operand for {:?}", op),
+                    found: left.ty.to_string(),
+                })
+            }
+        };
+
+        let ordering = self.emit(format_args!(
+            "call i32 @{}(ptr {}, ptr {})",
+            runtime::STRING_COMPARE,
+            left.ptr,
+            right.ptr
+        ));
+        let value = self.emit(format_args!(
+            "icmp {} i32 {}, 0",
+            integer_comparison(comparison, false),
+            ordering
+        ));
+
+        Ok(Typed::new(value, Scalar::Bool))
+    }
+}
+
+fn block_label(order: &[EntityId], block: EntityId) -> String {
+    let index = order
+        .iter()
+        .position(|&b| b == block)
+        .expect("every block we jump to is reachable");
+
+    format!("bb{}", index)
+}
+
+fn integer_comparison(comparison: Comparison, unsigned: bool) -> &'static str {
+    match (comparison, unsigned) {
+        (Comparison::Equal, _) => "eq",
+        (Comparison::NotEqual, _) => "ne",
+        (Comparison::Less, false) => "slt",
+        (Comparison::Less, true) => "ult",
+        (Comparison::LessOrEqual, false) => "sle",
+        (Comparison::LessOrEqual, true) => "ule",
+        (Comparison::Greater, false) => "sgt",
+        (Comparison::Greater, true) => "ugt",
+        (Comparison::GreaterOrEqual, false) => "sge",
+        (Comparison::GreaterOrEqual, true) => "uge",
+    }
+}
+
+fn float_comparison(comparison: Comparison) -> &'static str {
+    match comparison {
+        Comparison::Equal => "oeq",
+        Comparison::NotEqual => "une",
+        Comparison::Less => "olt",
+        Comparison::LessOrEqual => "ole",
+        Comparison::Greater => "ogt",
+        Comparison::GreaterOrEqual => "oge",
+    }
+}
@@ -0,0 +1,153 @@
+//! Working out which LLVM types and variables are needed for a
+//! [`CompilationUnit`].
+
+use crate::CodegenError;
+use iec::ecs::EntityId;
+use iec::hir::{CompilationUnit, Symbol};
+use iec::layout::{Declarations, PouDeclarations};
+pub(crate) use iec::layout::{PouKind, VariableType};
+use std::collections::HashMap;
+
+/// Every POU and variable which needs to be declared in the module.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Items {
+    pub declarations: Declarations,
+    locations: HashMap<EntityId, Location>,
+}
+
+impl Items {
+    pub fn new(unit: &CompilationUnit) -> Result<Items, CodegenError> {
+        let declarations = Declarations::new(unit)?;
+        let mut locations = HashMap::new();
+
+        for pou in &declarations.pous {
+            for (i, declaration) in pou.variables.iter().enumerate() {
+                let location = Location {
+                    storage: Storage::Field(i),
+                    ty: declaration.ty,
+                };
+                locations.insert(declaration.variable, location);
+            }
+            // compiler-generated temporaries are stored on the stack
+            for (i, declaration) in pou.temporaries.iter().enumerate() {
+                let location = Location {
+                    storage: Storage::Local(i),
+                    ty: declaration.ty,
+                };
+                locations.insert(declaration.variable, location);
+            }
+        }
+
+        for (i, declaration) in declarations.globals.iter().enumerate() {
+            let location = Location {
+                storage: Storage::Global(i),
+                ty: declaration.ty,
+            };
+            locations.insert(declaration.variable, location);
+        }
+
+        Ok(Items {
+            declarations,
+            locations,
+        })
+    }
+
+    /// Programs, then function blocks, then functions.
+    pub fn pous(&self) -> &[PouDeclarations] {
+        &self.declarations.pous
+    }
+
+    pub fn pou_index(&self, symbol: Symbol) -> Option<usize> {
+        self.declarations.pou_index(symbol)
+    }
+
+    pub fn location(&self, variable: EntityId) -> Location {
+        self.locations
+            .get(&variable)
+            .cloned()
+            .expect("every variable has a location")
+    }
+
+    /// The LLVM type a variable is stored as.
+    pub fn type_name(&self, ty: VariableType) -> String {
+        match ty {
+            // like C's bool, this is either 0 or 1
+            VariableType::Bool => String::from("i8"),
+            VariableType::Integer(int) => format!("i{}", int.bits()),
+            VariableType::Real => String::from("double"),
+            VariableType::String => String::from(STRING_TYPE),
+            VariableType::Instance(pou) => {
+                format!("%{}", self.pous()[pou].type_name())
+            }
+        }
+    }
+}
+
+/// The runtime library's string type, a length followed by a fixed-size
+/// buffer.
+pub(crate) const STRING_TYPE: &str = "%iec_string";
+
+/// The number of characters a `STRING` can hold.
+pub const STRING_CAPACITY: u32 = 254;
+
+/// Where a variable is stored.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct Location {
+    pub storage: Storage,
+    pub ty: VariableType,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Storage {
+    /// A field in the current POU's struct.
+    Field(usize),
+    /// A field in the `@iec_globals` struct.
+    Global(usize),
+    /// A temporary, stored in an `alloca`.
+    Local(usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iec::const_eval::IntegerType;
+    use iec::passes::PassContext;
+    use iec::Diagnostics;
+
+    #[test]
+    fn strings_and_times_can_be_stored() {
+        let src = "
+            VAR_GLOBAL
+                name: string;
+            END_VAR
+
+            PROGRAM main
+                VAR
+                    timeout: time;
+                    last_name: string;
+                END_VAR
+                last_name := name;
+            END_PROGRAM";
+        let ast: iec_syntax::File = src.parse().unwrap();
+        let mut diags = Diagnostics::new();
+        let cu =
+            iec::process(&ast, &mut PassContext::new_nop_logger(&mut diags));
+        assert!(!diags.has_errors(), "{:?}", diags);
+
+        let items = Items::new(&cu).unwrap();
+
+        let main = &items.pous()[0];
+        assert_eq!(main.function_name(), "iec_program_main");
+        let types: Vec<_> = main.variables.iter().map(|v| v.ty).collect();
+        assert_eq!(
+            types,
+            vec![
+                VariableType::Integer(IntegerType::LInt),
+                VariableType::String
+            ]
+        );
+        let name = &items.declarations.globals[0];
+        assert_eq!(items.location(name.variable).storage, Storage::Global(0));
+        assert_eq!(items.type_name(name.ty), "%iec_string");
+    }
+}
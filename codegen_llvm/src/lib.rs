@@ -0,0 +1,771 @@
+//! A backend for the `iec` compiler which generates textual LLVM IR.
+//!
+//! The generated module follows the same conventions as the C backend. Every
+//! POU gets a named struct type containing its variables and a function which
+//! executes its body:
+//!
+//! ```llvm
+//! define i32 @iec_fb_counter(ptr %self)
+//! define i32 @iec_fn_double(ptr %self)
+//! define i32 @iec_program_main()
+//! ```
+//!
+//! Function blocks are called using a pointer to their instance data, while
+//! each call to a function uses a fresh, zeroed frame on the stack. Programs
+//! have a statically allocated instance (e.g. `@iec_instance_main`) and
+//! `@iec_run_cycle()` calls every program once, saving how long the cycle
+//! took (in milliseconds) to `@iec_cycle_time`. Global variables live in
+//! `@iec_globals`.
+//!
+//! Each function returns `0` on success, or a non-zero [`status`] code when
+//! execution was aborted (e.g. division by zero). Integer arithmetic wraps on
+//! overflow, the same as [`iec::interpreter::Overflow::Wrap`].
+//!
+//! Strings and the clock are handled by a small [`runtime`] library written
+//! in C, so the compiler itself never needs to link against LLVM. The module
+//! uses opaque pointers, which need `-opaque-pointers` before LLVM 15.
+
+mod body;
+mod items;
+
+pub use crate::items::STRING_CAPACITY;
+
+use crate::items::{Items, PouKind, STRING_TYPE};
+use iec::codegen::{self, LowerError};
+use iec::layout::{Declaration, LayoutError, PouDeclarations};
+use iec::CompilationUnit;
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+/// The codes returned by generated functions.
+pub mod status {
+    pub const OK: u32 = 0;
+    pub const DIVISION_BY_ZERO: u32 = 1;
+    pub const NEGATIVE_EXPONENT: u32 = 2;
+    pub const INVALID_OPERANDS: u32 = 3;
+}
+
+/// The runtime library generated code calls into.
+pub mod runtime {
+    /// The library's source code, which should be compiled and linked with
+    /// the generated module.
+    pub const SOURCE: &str = include_str!("runtime.c");
+
+    /// `i64 ()`, milliseconds since some arbitrary point in time.
+    pub const CLOCK_MS: &str = "iec_rt_clock_ms";
+    /// `void (ptr dest, ptr src)`.
+    pub const STRING_ASSIGN: &str = "iec_rt_string_assign";
+    /// `i32 (ptr left, ptr right)`, returning a negative number, zero, or a
+    /// positive number like `strcmp()`.
+    pub const STRING_COMPARE: &str = "iec_rt_string_compare";
+
+    pub(crate) const DECLARATIONS: &[&str] = &[
+        "declare i64 @iec_rt_clock_ms()",
+        "declare void @iec_rt_string_assign(ptr, ptr)",
+        "declare i32 @iec_rt_string_compare(ptr, ptr)",
+    ];
+}
+
+/// Generate an LLVM module for a [`CompilationUnit`].
+///
+/// The `name` is used as the module's `source_filename`.
+///
+/// # Examples
+///
+/// ```rust
+/// use iec::passes::PassContext;
+/// use iec::Diagnostics;
+///
+/// let src = "
+///     PROGRAM main
+///         VAR
+///             count: int;
+///         END_VAR
+///         count := count + 1;
+///     END_PROGRAM";
+/// let ast: iec_syntax::File = src.parse().unwrap();
+/// let mut diags = Diagnostics::new();
+/// let cu = iec::process(&ast, &mut PassContext::new_nop_logger(&mut diags));
+///
+/// let module = iec_codegen_llvm::generate(&cu, "counter").unwrap();
+///
+/// assert!(module.contains("%iec_program_main_t = type {"));
+/// assert!(module.contains("define i32 @iec_program_main() {"));
+/// ```
+pub fn generate(
+    unit: &CompilationUnit,
+    name: &str,
+) -> Result<String, CodegenError> {
+    let items = Items::new(unit)?;
+
+    codegen::with_tables(unit, |tables| {
+        let mut out = Writer::default();
+        out.line("; Generated by iecc. Do not edit.");
+        out.line(format_args!("source_filename = \"{}\"", escape(name)));
+        out.blank();
+
+        out.line(format_args!(
+            "{} = type {{ i16, [{} x i8] }}",
+            STRING_TYPE, STRING_CAPACITY
+        ));
+        out.blank();
+        for pou in items.pous() {
+            out.line(format_args!("; {} {}", pou.kind, pou.name));
+            struct_definition(
+                &mut out,
+                &items,
+                &pou.variables,
+                &pou.type_name(),
+            );
+            out.blank();
+        }
+        let globals = &items.declarations.globals;
+        out.line("; VAR_GLOBAL");
+        struct_definition(&mut out, &items, globals, "iec_globals_t");
+        out.blank();
+
+        out.line("@iec_globals = global %iec_globals_t zeroinitializer");
+        let programs: Vec<_> = items
+            .pous()
+            .iter()
+            .filter(|p| p.kind == PouKind::Program)
+            .collect();
+        for pou in &programs {
+            out.line(format_args!(
+                "@{} = global %{} zeroinitializer",
+                pou.instance_name(),
+                pou.type_name()
+            ));
+        }
+        out.line("@iec_cycle_time = global i64 0");
+
+        let mut intrinsics = BTreeSet::new();
+        for index in 0..items.pous().len() {
+            out.blank();
+            body::define(&mut out, tables, &items, &mut intrinsics, index)?;
+        }
+
+        out.blank();
+        run_cycle(&mut out, &programs);
+
+        out.blank();
+        for declaration in runtime::DECLARATIONS {
+            out.line(declaration);
+        }
+        for declaration in &intrinsics {
+            out.line(declaration);
+        }
+
+        Ok(out.finish())
+    })
+}
+
+fn struct_definition(
+    out: &mut Writer,
+    items: &Items,
+    fields: &[Declaration],
+    type_name: &str,
+) {
+    if fields.is_empty() {
+        // the same layout as the C backend, which can't have empty structs
+        out.line(format_args!("%{} = type {{ i8 }}", type_name));
+        return;
+    }
+
+    out.line(format_args!("%{} = type {{", type_name));
+    out.indent();
+    for (i, field) in fields.iter().enumerate() {
+        let separator = if i + 1 < fields.len() { "," } else { "" };
+        let name = field.name.as_deref().unwrap_or_default();
+        out.line(format_args!(
+            "{}{} ; {}",
+            items.type_name(field.ty),
+            separator,
+            name.to_lowercase()
+        ));
+    }
+    out.dedent();
+    out.line("}");
+}
+
+/// Execute every program once, returning the first non-zero status code.
+fn run_cycle(out: &mut Writer, programs: &[&PouDeclarations]) {
+    out.line("define i32 @iec_run_cycle() {");
+    out.indent();
+    out.label("entry:");
+    out.line(format_args!("%start = call i64 @{}()", runtime::CLOCK_MS));
+
+    for (i, pou) in programs.iter().enumerate() {
+        out.line(format_args!(
+            "%status{} = call i32 @{}()",
+            i,
+            pou.function_name()
+        ));
+        out.line(format_args!("%failed{} = icmp ne i32 %status{}, 0", i, i));
+        out.line(format_args!(
+            "br i1 %failed{}, label %failed_{}, label %ok_{}",
+            i, i, i
+        ));
+        out.label(format_args!("failed_{}:", i));
+        out.line(format_args!("ret i32 %status{}", i));
+        out.label(format_args!("ok_{}:", i));
+    }
+
+    out.line(format_args!("%end = call i64 @{}()", runtime::CLOCK_MS));
+    out.line("%elapsed = sub i64 %end, %start");
+    out.line("store i64 %elapsed, ptr @iec_cycle_time");
+    out.line(format_args!("ret i32 {}", status::OK));
+    out.dedent();
+    out.line("}");
+}
+
+/// Escape a string so it can be used inside double quotes.
+fn escape(s: &str) -> String {
+    let mut escaped = String::new();
+
+    for byte in s.bytes() {
+        if byte.is_ascii_graphic() && byte != b'"' && byte != b'\\' {
+            escaped.push(char::from(byte));
+        } else if byte == b' ' {
+            escaped.push(' ');
+        } else {
+            escaped.push_str(&format!("\\{:02X}", byte));
+        }
+    }
+
+    escaped
+}
+
+/// A buffer of LLVM IR which keeps track of indentation.
+#[derive(Debug, Default)]
+pub(crate) struct Writer {
+    buffer: String,
+    indent: usize,
+}
+
+impl Writer {
+    const INDENT: &'static str = "  ";
+
+    /// A writer for the contents of a function.
+    pub fn indented() -> Writer {
+        Writer {
+            buffer: String::new(),
+            indent: 1,
+        }
+    }
+
+    pub fn line<D: Display>(&mut self, text: D) {
+        for _ in 0..self.indent {
+            self.buffer.push_str(Writer::INDENT);
+        }
+        self.buffer.push_str(&text.to_string());
+        self.buffer.push('\n');
+    }
+
+    /// Labels are written one level further out than the surrounding code.
+    pub fn label<D: Display>(&mut self, text: D) {
+        let indent = self.indent;
+        self.indent = indent.saturating_sub(1);
+        self.line(text);
+        self.indent = indent;
+    }
+
+    pub fn blank(&mut self) {
+        self.buffer.push('\n');
+    }
+
+    /// Append some text verbatim.
+    pub fn raw(&mut self, text: &str) {
+        self.buffer.push_str(text);
+    }
+
+    pub fn indent(&mut self) {
+        self.indent += 1;
+    }
+
+    pub fn dedent(&mut self) {
+        self.indent -= 1;
+    }
+
+    pub fn finish(self) -> String {
+        self.buffer
+    }
+}
+
+/// Reasons code generation can fail.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CodegenError {
+    /// The program uses something this backend can't translate yet.
+    Unsupported(String),
+    /// An operation was applied to values of the wrong type. This should
+    /// have been caught during semantic analysis.
+    TypeMismatch { expected: String, found: String },
+}
+
+impl From<LayoutError> for CodegenError {
+    fn from(other: LayoutError) -> CodegenError {
+        match other {
+            LayoutError::Unsupported(what) => CodegenError::Unsupported(what),
+        }
+    }
+}
+
+impl From<LowerError> for CodegenError {
+    fn from(other: LowerError) -> CodegenError {
+        match other {
+            LowerError::Unsupported(what) => CodegenError::Unsupported(what),
+            LowerError::TypeMismatch { expected, found } => {
+                CodegenError::TypeMismatch { expected, found }
+            }
+        }
+    }
+}
+
+impl Display for CodegenError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            CodegenError::Unsupported(ref what) => {
+                write!(f, "{} aren't supported by the LLVM backend", what)
+            }
+            CodegenError::TypeMismatch {
+                ref expected,
+                ref found,
+            } => write!(f, "Expected a {} but found a {}", expected, found),
+        }
+    }
+}
+
+impl Error for CodegenError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iec::testing::{self, Snapshot};
+    use std::path::{Path, PathBuf};
+    use std::process::{Command, Output as ProcessOutput};
+
+    fn have_toolchain() -> bool {
+        Command::new("llc").arg("--version").output().is_ok()
+            && Command::new("cc").arg("--version").output().is_ok()
+    }
+
+    /// LLVM 14 needs to be told about opaque pointers, while LLVM 17 removed
+    /// the flag altogether.
+    fn llc_flags() -> Vec<&'static str> {
+        let help = Command::new("llc").arg("--help-hidden").output().unwrap();
+        let mut flags = vec!["-relocation-model=pic", "-filetype=obj"];
+
+        if String::from_utf8_lossy(&help.stdout).contains("-opaque-pointers") {
+            flags.push("-opaque-pointers");
+        }
+
+        flags
+    }
+
+    fn temp_dir(test_name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "iec-codegen-llvm-{}-{}",
+            test_name,
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Compile the module with `llc` then link it with the runtime library
+    /// and a `main.c`.
+    fn build(dir: &Path, module: &str, main: &str) -> PathBuf {
+        std::fs::write(dir.join("program.ll"), module).unwrap();
+        std::fs::write(dir.join("runtime.c"), runtime::SOURCE).unwrap();
+        std::fs::write(dir.join("main.c"), main).unwrap();
+
+        let compiled = Command::new("llc")
+            .current_dir(dir)
+            .args(llc_flags())
+            .args(["program.ll", "-o", "program.o"])
+            .output()
+            .unwrap();
+        assert!(
+            compiled.status.success(),
+            "{}\n{}",
+            String::from_utf8_lossy(&compiled.stderr),
+            module
+        );
+
+        let linked = Command::new("cc")
+            .current_dir(dir)
+            .args([
+                "-std=c99",
+                "-Wall",
+                "-Werror",
+                "main.c",
+                "runtime.c",
+                "program.o",
+                "-o",
+                "program",
+            ])
+            .output()
+            .unwrap();
+        assert!(
+            linked.status.success(),
+            "{}",
+            String::from_utf8_lossy(&linked.stderr)
+        );
+
+        dir.join("program")
+    }
+
+    /// Link the module with a `main()` which runs `cycles` cycles (see
+    /// [`testing::snapshot_main()`]).
+    fn compile_and_run(
+        test_name: &str,
+        cu: &CompilationUnit,
+        cycles: u64,
+    ) -> ProcessOutput {
+        let module = generate(cu, "program").unwrap();
+        let main = testing::snapshot_main(cu, cycles);
+
+        let dir = temp_dir(test_name);
+        let program = build(&dir, &module, &main);
+
+        let output = Command::new(program).output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        output
+    }
+
+    fn compare(test_name: &str, src: &str, cycles: u64, paths: &[&str]) {
+        testing::compare(src, cycles, paths, |cu| {
+            if !have_toolchain() {
+                return None;
+            }
+
+            let output = compile_and_run(test_name, cu, cycles);
+            let stdout = String::from_utf8(output.stdout).unwrap();
+            assert!(output.status.success(), "{}", stdout);
+
+            Some(Snapshot::parse(cu, &stdout))
+        });
+    }
+
+    #[test]
+    fn integer_widths_and_struct_layouts() {
+        let src = "
+            FUNCTION_BLOCK counter
+                VAR_INPUT
+                    step: int;
+                END_VAR
+                VAR_OUTPUT
+                    total: int;
+                    overflowed: bool;
+                END_VAR
+            BEGIN
+                total := total + step;
+            END_FUNCTION_BLOCK
+
+            PROGRAM main
+                VAR
+                    small: sint;
+                    large: lint;
+                    c: counter;
+                END_VAR
+                c(3);
+            END_PROGRAM";
+        let (_, cu) = testing::analyse(src);
+
+        let module = generate(&cu, "program").unwrap();
+
+        assert!(module.contains(
+            "%iec_fb_counter_t = type {\n  i16, ; step\n  i16, ; total\n  \
+             i8 ; overflowed\n}"
+        ));
+        assert!(module.contains(
+            "%iec_program_main_t = type {\n  i8, ; small\n  i64, ; large\n  \
+             %iec_fb_counter_t ; c\n}"
+        ));
+        assert!(module.contains("define i32 @iec_fb_counter(ptr %self) {"));
+        assert!(module.contains(
+            "@iec_instance_main = global %iec_program_main_t zeroinitializer"
+        ));
+    }
+
+    #[test]
+    fn function_blocks_and_functions() {
+        let src = "
+            FUNCTION_BLOCK counter
+                VAR_INPUT
+                    step: int;
+                END_VAR
+                VAR_OUTPUT
+                    total: int;
+                END_VAR
+            BEGIN
+                total := total + step;
+            END_FUNCTION_BLOCK
+
+            FUNCTION double : int
+            BEGIN
+                VAR_INPUT
+                    x: int;
+                END_VAR
+                double := x * 2;
+            END_FUNCTION
+
+            PROGRAM main
+                VAR
+                    slow: counter;
+                    fast: counter;
+                    copy: int;
+                    direct: int;
+                END_VAR
+                slow(1);
+                fast(step := double(5), total => copy);
+                direct := fast.total + slow.total;
+            END_PROGRAM";
+
+        compare(
+            "fb",
+            src,
+            3,
+            &[
+                "main.slow.total",
+                "main.fast.total",
+                "main.copy",
+                "main.direct",
+            ],
+        );
+    }
+
+    #[test]
+    fn loops_and_branches() {
+        let src = "
+            PROGRAM main
+                VAR
+                    i: int;
+                    total: dint;
+                    evens: int;
+                    repeats: int;
+                    countdown: int;
+                END_VAR
+                total := 0;
+                evens := 0;
+                FOR i := 1 TO 100 DO
+                    IF i > 50 THEN
+                        EXIT;
+                    END_IF;
+                    IF i % 2 = 0 THEN
+                        evens := evens + 1;
+                    END_IF;
+                    total := total + i * i;
+                END_FOR;
+
+                countdown := 0;
+                FOR i := 10 TO 0 BY -3 DO
+                    countdown := countdown + i;
+                END_FOR;
+
+                repeats := 0;
+                WHILE repeats < 7 DO
+                    repeats := repeats + 1;
+                END_WHILE;
+            END_PROGRAM";
+
+        compare(
+            "loops",
+            src,
+            2,
+            &[
+                "main.i",
+                "main.total",
+                "main.evens",
+                "main.countdown",
+                "main.repeats",
+            ],
+        );
+    }
+
+    #[test]
+    fn integer_arithmetic_wraps() {
+        let src = "
+            PROGRAM main
+                VAR
+                    small: sint;
+                    small_unsigned: usint;
+                    word_value: word;
+                    quotient: int;
+                    remainder: int;
+                    power: dint;
+                    wrapped: sint;
+                    negated: int;
+                    inverted: byte;
+                    flag: bool;
+                    mixed: bool;
+                END_VAR
+                small := small + 100;
+                small_unsigned := small_unsigned - 3;
+                word_value := word_value - 1;
+                quotient := -17 / 5;
+                remainder := -17 % 5;
+                power := 3 ** 5;
+                wrapped := small * 3;
+                negated := -(small);
+                inverted := NOT small_unsigned;
+                flag := (small < 0) XOR (small_unsigned > 200);
+                mixed := NOT flag AND (power >= 243);
+            END_PROGRAM";
+
+        compare(
+            "arithmetic",
+            src,
+            3,
+            &[
+                "main.small",
+                "main.small_unsigned",
+                "main.word_value",
+                "main.quotient",
+                "main.remainder",
+                "main.power",
+                "main.wrapped",
+                "main.negated",
+                "main.inverted",
+                "main.flag",
+                "main.mixed",
+            ],
+        );
+    }
+
+    #[test]
+    fn builtin_functions_are_inlined() {
+        let src = "
+            VAR_GLOBAL
+                shared: int;
+            END_VAR
+
+            PROGRAM main
+                VAR
+                    a: int;
+                    b: int;
+                    c: int;
+                    d: int;
+                    e: int;
+                    bits: byte;
+                    shifted: word;
+                    cleared: byte;
+                END_VAR
+                shared := shared + 5;
+                a := abs(-42);
+                b := min(a, 7);
+                c := max(limit(0, a * 100, 500), 7);
+                d := sel(a > 10, 1, 2);
+                bits := 1;
+                bits := ror(bits, 1);
+                e := bits;
+                shifted := 3;
+                shifted := shl(shifted, 4);
+                cleared := 255;
+                cleared := shr(cleared, 9);
+            END_PROGRAM";
+
+        compare(
+            "builtins",
+            src,
+            2,
+            &[
+                "shared",
+                "main.a",
+                "main.b",
+                "main.c",
+                "main.d",
+                "main.e",
+                "main.bits",
+                "main.shifted",
+                "main.cleared",
+            ],
+        );
+    }
+
+    #[test]
+    fn division_by_zero_aborts_the_cycle() {
+        if !have_toolchain() {
+            return;
+        }
+
+        let src = "
+            PROGRAM main
+                VAR
+                    countdown: int;
+                    result: int;
+                END_VAR
+                countdown := countdown + 1;
+                result := 10 / (3 - countdown);
+            END_PROGRAM";
+        let (_, cu) = testing::analyse(src);
+
+        let output = compile_and_run("division", &cu, 5);
+
+        assert!(!output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "3: 1\n");
+    }
+
+    #[test]
+    fn strings_use_the_runtime_library() {
+        if !have_toolchain() {
+            return;
+        }
+
+        let src = "
+            VAR_GLOBAL
+                first: string;
+                second: string;
+                same: bool;
+            END_VAR
+
+            PROGRAM main
+                first := second;
+                same := first = second;
+            END_PROGRAM";
+        let (_, cu) = testing::analyse(src);
+        let module = generate(&cu, "program").unwrap();
+        assert!(module.contains("call void @iec_rt_string_assign("));
+
+        let main = "
+            #include <stdbool.h>
+            #include <stdint.h>
+            #include <stdio.h>
+
+            typedef struct {
+                uint16_t length;
+                char data[254];
+            } iec_string;
+
+            extern struct {
+                iec_string first;
+                iec_string second;
+                bool same;
+            } iec_globals;
+
+            uint32_t iec_run_cycle(void);
+
+            int main(void) {
+                iec_globals.second.length = 5;
+                iec_globals.second.data[0] = 'h';
+                iec_globals.second.data[1] = 'e';
+                iec_globals.second.data[2] = 'l';
+                iec_globals.second.data[3] = 'l';
+                iec_globals.second.data[4] = 'o';
+                iec_run_cycle();
+                printf(\"%.*s %d\\n\", iec_globals.first.length,
+                    iec_globals.first.data, iec_globals.same);
+                return 0;
+            }
+        ";
+        let dir = temp_dir("strings");
+        let program = build(&dir, &module, main);
+
+        let output = Command::new(program).output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "hello 1\n");
+    }
+}
@@ -0,0 +1,52 @@
+/*
+ * The runtime library used by code from the LLVM backend.
+ *
+ * Anything which would be awkward to write in LLVM IR by hand (strings,
+ * reading the clock) is implemented here, and the generated module only
+ * contains calls to these functions. Compile it with any C99 compiler and
+ * link it alongside the object file produced by `llc`.
+ */
+
+#define _POSIX_C_SOURCE 199309L
+
+#include <stdint.h>
+#include <string.h>
+#include <time.h>
+
+#define IEC_STRING_CAPACITY 254
+
+/* Matches `%iec_string = type { i16, [254 x i8] }`. */
+typedef struct {
+    uint16_t length;
+    char data[IEC_STRING_CAPACITY];
+} iec_string;
+
+/* A monotonic clock, in milliseconds. */
+int64_t iec_rt_clock_ms(void) {
+    struct timespec now;
+    clock_gettime(CLOCK_MONOTONIC, &now);
+    return (int64_t)now.tv_sec * 1000 + now.tv_nsec / 1000000;
+}
+
+void iec_rt_string_assign(iec_string *dest, const iec_string *src) {
+    uint16_t length = src->length;
+
+    if (length > IEC_STRING_CAPACITY) {
+        length = IEC_STRING_CAPACITY;
+    }
+    memmove(dest->data, src->data, length);
+    dest->length = length;
+}
+
+/* Compare two strings byte-by-byte, like strcmp(). */
+int32_t iec_rt_string_compare(const iec_string *left,
+                              const iec_string *right) {
+    uint16_t shortest =
+        left->length < right->length ? left->length : right->length;
+    int ordering = memcmp(left->data, right->data, shortest);
+
+    if (ordering != 0) {
+        return ordering;
+    }
+    return (int32_t)left->length - (int32_t)right->length;
+}
@@ -0,0 +1,819 @@
+//! A backend for the `iec` compiler which generates a WebAssembly module,
+//! so untrusted programs can be run inside a sandbox or a browser.
+//!
+//! Every variable lives in the module's linear memory (exported as
+//! `memory`), using the same [`Layout`] as the native backends. Global
+//! variables are stored at the address in the `iec_globals` export, and each
+//! program has a statically allocated instance whose address is exported as
+//! `iec_instance_<name>`. Function call frames are allocated from a small
+//! stack at the end of memory.
+//!
+//! The module exports a function for each program (e.g. `iec_program_main`)
+//! and `iec_run_cycle`, which performs a complete scan:
+//!
+//! 1. Call the [`abi::READ_INPUTS`] import so the host can copy its inputs
+//!    into memory
+//! 2. Execute every program once
+//! 3. Call the [`abi::WRITE_OUTPUTS`] import so the host can read the
+//!    results
+//!
+//! The [`abi::CLOCK`] import is used to time each scan, with the duration of
+//! the last successful cycle being saved in the `iec_cycle_time` global.
+//!
+//! Each function returns `0` on success, or a non-zero [`status`] code when
+//! execution was aborted (e.g. division by zero). A scan which fails won't
+//! write its outputs. Integer arithmetic wraps on overflow, the same as
+//! [`iec::interpreter::Overflow::Wrap`].
+//!
+//! [`CompilationUnit`]: iec::CompilationUnit
+
+mod translate;
+
+use byteorder::LittleEndian;
+use iec::codegen::LowerError;
+use iec::interpreter::Value;
+use iec::layout::{self, FieldType, Layout, LayoutError, PouKind};
+use iec::CompilationUnit;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use wasm_encoder::{
+    CodeSection, ConstExpr, EntityType, ExportKind, ExportSection,
+    Function as WasmFunction, FunctionSection, GlobalSection, GlobalType,
+    ImportSection, Instruction, MemorySection, MemoryType, Module, TypeSection,
+    ValType,
+};
+
+/// The size of the stack used for function call frames, in bytes.
+pub const STACK_SIZE: u32 = 64 * 1024;
+
+const PAGE_SIZE: u32 = 64 * 1024;
+
+/// The names of everything the generated module imports and exports.
+pub mod abi {
+    /// The module every import is taken from.
+    pub const IMPORT_MODULE: &str = "iec";
+    /// A function, `() -> i64`, which returns the current time in
+    /// milliseconds.
+    pub const CLOCK: &str = "clock_ms";
+    /// A function, `() -> ()`, called at the start of each scan.
+    pub const READ_INPUTS: &str = "read_inputs";
+    /// A function, `() -> ()`, called at the end of each successful scan.
+    pub const WRITE_OUTPUTS: &str = "write_outputs";
+
+    pub const MEMORY: &str = "memory";
+    /// A function, `() -> i32`, which executes every program once.
+    pub const RUN_CYCLE: &str = "iec_run_cycle";
+    /// An `i32` global containing the address of the global variables.
+    pub const GLOBALS: &str = "iec_globals";
+    /// A mutable `i64` global containing how long the last successful scan
+    /// took, in milliseconds.
+    pub const CYCLE_TIME: &str = "iec_cycle_time";
+
+    /// The function, `() -> i32`, which executes a program.
+    pub fn program(name: &str) -> String {
+        format!("iec_program_{}", name.to_lowercase())
+    }
+
+    /// The `i32` global containing the address of a program's instance.
+    pub fn instance(name: &str) -> String {
+        format!("iec_instance_{}", name.to_lowercase())
+    }
+}
+
+/// The codes returned by generated functions.
+pub mod status {
+    use iec::const_eval::EvalError;
+
+    pub const OK: i32 = 0;
+    pub const DIVISION_BY_ZERO: i32 = 1;
+    pub const NEGATIVE_EXPONENT: i32 = 2;
+    pub const INVALID_OPERANDS: i32 = 3;
+
+    /// The error a non-zero status code corresponds to.
+    pub fn to_error(status: i32) -> Option<EvalError> {
+        match status {
+            DIVISION_BY_ZERO => Some(EvalError::DivisionByZero),
+            NEGATIVE_EXPONENT => Some(EvalError::NegativeExponent),
+            INVALID_OPERANDS => Some(EvalError::InvalidOperands),
+            _ => None,
+        }
+    }
+}
+
+/// Where everything is stored in linear memory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryMap {
+    /// The address of the global variables.
+    pub globals: u32,
+    /// The address of each program's instance data, indexed the same as
+    /// [`Layout::pous`].
+    pub instances: Vec<Option<u32>>,
+    /// The bottom of the stack used for function call frames.
+    pub stack: u32,
+    /// The size of linear memory, in 64 KiB pages.
+    pub pages: u32,
+}
+
+impl MemoryMap {
+    pub fn new(layout: &Layout) -> MemoryMap {
+        // leave the first few bytes empty so nothing is stored at address 0
+        let globals = 8;
+        let mut end = globals + layout.globals.size;
+        let mut instances = Vec::new();
+
+        for pou in &layout.pous {
+            if pou.kind == PouKind::Program {
+                let address = end.div_ceil(8) * 8;
+                instances.push(Some(address));
+                end = address + pou.frame.size;
+            } else {
+                instances.push(None);
+            }
+        }
+
+        let stack = end.div_ceil(8) * 8;
+
+        MemoryMap {
+            globals,
+            instances,
+            stack,
+            pages: (stack + STACK_SIZE).div_ceil(PAGE_SIZE),
+        }
+    }
+}
+
+/// A generated WebAssembly module.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Output {
+    /// The module, in the WebAssembly binary format.
+    pub wasm: Vec<u8>,
+    pub layout: Layout,
+    pub memory: MemoryMap,
+}
+
+impl Output {
+    /// The address of a variable in linear memory, using its dotted path
+    /// (e.g. `main.counter.total` or the name of a global).
+    pub fn address_of(&self, path: &str) -> Option<(u32, FieldType)> {
+        let resolved = self.layout.resolve(path)?;
+        let base = match resolved.program {
+            Some(pou) => self.memory.instances[pou]?,
+            None => self.memory.globals,
+        };
+
+        Some((base + resolved.offset, resolved.ty))
+    }
+
+    /// Read a variable from a copy of the module's linear memory.
+    ///
+    /// Function block instances can't be read directly, only their fields.
+    pub fn get(&self, memory: &[u8], path: &str) -> Option<Value> {
+        let (address, ty) = self.address_of(path)?;
+        layout::read::<LittleEndian>(memory, address as usize, ty)
+    }
+}
+
+/// The indices of the function types used by the module.
+mod types {
+    pub const CLOCK: u32 = 0;
+    pub const HOOK: u32 = 1;
+    /// `(i32) -> i32`, used by function blocks and functions.
+    pub const POU: u32 = 2;
+    /// `() -> i32`, used by programs and `iec_run_cycle`.
+    pub const ENTRY_POINT: u32 = 3;
+}
+
+/// The indices of the imported functions.
+mod imports {
+    pub const CLOCK: u32 = 0;
+    pub const READ_INPUTS: u32 = 1;
+    pub const WRITE_OUTPUTS: u32 = 2;
+    pub const COUNT: u32 = 3;
+}
+
+/// The `iec_cycle_time` global (after the stack pointer).
+const CYCLE_TIME_GLOBAL: u32 = 1;
+
+/// Generate a WebAssembly module for a [`CompilationUnit`].
+///
+/// # Examples
+///
+/// ```rust
+/// use iec::passes::PassContext;
+/// use iec::Diagnostics;
+///
+/// let src = "
+///     PROGRAM main
+///         VAR
+///             count: int;
+///         END_VAR
+///         count := count + 1;
+///     END_PROGRAM";
+/// let ast: iec_syntax::File = src.parse().unwrap();
+/// let mut diags = Diagnostics::new();
+/// let cu = iec::process(&ast, &mut PassContext::new_nop_logger(&mut diags));
+///
+/// let output = iec_codegen_wasm::generate(&cu).unwrap();
+///
+/// assert!(output.wasm.starts_with(b"\0asm"));
+/// assert!(output.address_of("main.count").is_some());
+/// ```
+pub fn generate(unit: &CompilationUnit) -> Result<Output, CodegenError> {
+    let layout = Layout::new(unit)?;
+    let memory = MemoryMap::new(&layout);
+    let bodies =
+        translate::translate_pous(unit, &layout, &memory, imports::COUNT)?;
+
+    let mut types = TypeSection::new();
+    types.ty().function([], [ValType::I64]);
+    types.ty().function([], []);
+    types.ty().function([ValType::I32], [ValType::I32]);
+    types.ty().function([], [ValType::I32]);
+
+    let mut import_section = ImportSection::new();
+    for (name, ty) in &[
+        (abi::CLOCK, types::CLOCK),
+        (abi::READ_INPUTS, types::HOOK),
+        (abi::WRITE_OUTPUTS, types::HOOK),
+    ] {
+        import_section.import(
+            abi::IMPORT_MODULE,
+            name,
+            EntityType::Function(*ty),
+        );
+    }
+
+    let mut functions = FunctionSection::new();
+    let mut code = CodeSection::new();
+    let mut exports = ExportSection::new();
+    exports.export(abi::MEMORY, ExportKind::Memory, 0);
+
+    for (index, (pou, body)) in layout.pous.iter().zip(&bodies).enumerate() {
+        let function_index = imports::COUNT + index as u32;
+
+        if pou.kind == PouKind::Program {
+            functions.function(types::ENTRY_POINT);
+            exports.export(
+                &abi::program(&pou.name),
+                ExportKind::Func,
+                function_index,
+            );
+        } else {
+            functions.function(types::POU);
+        }
+        code.function(body);
+    }
+
+    let run_cycle_index = imports::COUNT + layout.pous.len() as u32;
+    functions.function(types::ENTRY_POINT);
+    code.function(&run_cycle(&layout));
+    exports.export(abi::RUN_CYCLE, ExportKind::Func, run_cycle_index);
+
+    let mut memories = MemorySection::new();
+    memories.memory(MemoryType {
+        minimum: u64::from(memory.pages),
+        maximum: None,
+        memory64: false,
+        shared: false,
+        page_size_log2: None,
+    });
+
+    let mut globals = GlobalSection::new();
+    let mutable = |val_type| GlobalType {
+        val_type,
+        mutable: true,
+        shared: false,
+    };
+    let constant = GlobalType {
+        val_type: ValType::I32,
+        mutable: false,
+        shared: false,
+    };
+    globals.global(
+        mutable(ValType::I32),
+        &ConstExpr::i32_const(memory.stack as i32),
+    );
+    globals.global(mutable(ValType::I64), &ConstExpr::i64_const(0));
+    exports.export(abi::CYCLE_TIME, ExportKind::Global, CYCLE_TIME_GLOBAL);
+    globals.global(constant, &ConstExpr::i32_const(memory.globals as i32));
+    exports.export(abi::GLOBALS, ExportKind::Global, globals.len() - 1);
+    for (pou, address) in layout.pous.iter().zip(&memory.instances) {
+        if let Some(address) = address {
+            globals.global(constant, &ConstExpr::i32_const(*address as i32));
+            exports.export(
+                &abi::instance(&pou.name),
+                ExportKind::Global,
+                globals.len() - 1,
+            );
+        }
+    }
+
+    let mut module = Module::new();
+    module
+        .section(&types)
+        .section(&import_section)
+        .section(&functions)
+        .section(&memories)
+        .section(&globals)
+        .section(&exports)
+        .section(&code);
+
+    Ok(Output {
+        wasm: module.finish(),
+        layout,
+        memory,
+    })
+}
+
+/// The body of `iec_run_cycle()`.
+fn run_cycle(layout: &Layout) -> WasmFunction {
+    // locals: the time the scan started and the last status code
+    let mut f = WasmFunction::new([(1, ValType::I64), (1, ValType::I32)]);
+    let (start, status) = (0, 1);
+
+    f.instruction(&Instruction::Call(imports::CLOCK));
+    f.instruction(&Instruction::LocalSet(start));
+    f.instruction(&Instruction::Call(imports::READ_INPUTS));
+
+    for (index, pou) in layout.pous.iter().enumerate() {
+        if pou.kind == PouKind::Program {
+            f.instruction(&Instruction::Call(imports::COUNT + index as u32));
+            f.instruction(&Instruction::LocalTee(status));
+            f.instruction(&Instruction::If(wasm_encoder::BlockType::Empty));
+            f.instruction(&Instruction::LocalGet(status));
+            f.instruction(&Instruction::Return);
+            f.instruction(&Instruction::End);
+        }
+    }
+
+    f.instruction(&Instruction::Call(imports::WRITE_OUTPUTS));
+    f.instruction(&Instruction::Call(imports::CLOCK));
+    f.instruction(&Instruction::LocalGet(start));
+    f.instruction(&Instruction::I64Sub);
+    f.instruction(&Instruction::GlobalSet(CYCLE_TIME_GLOBAL));
+    f.instruction(&Instruction::I32Const(status::OK));
+    f.instruction(&Instruction::End);
+
+    f
+}
+
+/// Reasons code generation can fail.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CodegenError {
+    /// The program uses something this backend can't translate yet.
+    Unsupported(String),
+    /// An operation was applied to values of the wrong type. This should
+    /// have been caught during semantic analysis.
+    TypeMismatch { expected: String, found: String },
+}
+
+impl From<LayoutError> for CodegenError {
+    fn from(other: LayoutError) -> CodegenError {
+        match other {
+            LayoutError::Unsupported(what) => CodegenError::Unsupported(what),
+        }
+    }
+}
+
+impl From<LowerError> for CodegenError {
+    fn from(other: LowerError) -> CodegenError {
+        match other {
+            LowerError::Unsupported(what) => CodegenError::Unsupported(what),
+            LowerError::TypeMismatch { expected, found } => {
+                CodegenError::TypeMismatch { expected, found }
+            }
+        }
+    }
+}
+
+impl Display for CodegenError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            CodegenError::Unsupported(ref what) => write!(
+                f,
+                "{} aren't supported by the WebAssembly backend",
+                what
+            ),
+            CodegenError::TypeMismatch {
+                ref expected,
+                ref found,
+            } => write!(f, "Expected a {} but found a {}", expected, found),
+        }
+    }
+}
+
+impl Error for CodegenError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iec::testing::{self, Execution};
+    use wasmi::{Caller, Engine, Extern, Instance, Linker, Store};
+
+    /// The state shared with the host functions imported by the module.
+    #[derive(Debug, Default)]
+    struct Host {
+        now: i64,
+        events: Vec<&'static str>,
+        /// The address of a variable which `read_inputs()` sets to `now`.
+        input: Option<u32>,
+    }
+
+    struct Sandbox {
+        output: Output,
+        store: Store<Host>,
+        instance: Instance,
+    }
+
+    impl Sandbox {
+        fn new(output: Output) -> Sandbox {
+            Sandbox::with_host(output, Host::default())
+        }
+
+        fn with_host(output: Output, host: Host) -> Sandbox {
+            let engine = Engine::default();
+            let module = wasmi::Module::new(&engine, &output.wasm).unwrap();
+            let mut store = Store::new(&engine, host);
+            let mut linker = <Linker<Host>>::new(&engine);
+
+            linker
+                .func_wrap(abi::IMPORT_MODULE, abi::CLOCK, |c: Caller<Host>| {
+                    c.data().now
+                })
+                .unwrap();
+            linker
+                .func_wrap(
+                    abi::IMPORT_MODULE,
+                    abi::READ_INPUTS,
+                    |mut c: Caller<Host>| {
+                        c.data_mut().events.push("read");
+                        if let Some(address) = c.data().input {
+                            let now = c.data().now as u16;
+                            let memory = c
+                                .get_export(abi::MEMORY)
+                                .and_then(Extern::into_memory)
+                                .unwrap();
+                            let address = address as usize;
+                            memory.data_mut(&mut c)[address..address + 2]
+                                .copy_from_slice(&now.to_le_bytes());
+                        }
+                    },
+                )
+                .unwrap();
+            linker
+                .func_wrap(
+                    abi::IMPORT_MODULE,
+                    abi::WRITE_OUTPUTS,
+                    |mut c: Caller<Host>| {
+                        c.data_mut().events.push("write");
+                        // pretend the scan took a little while
+                        c.data_mut().now += 5;
+                    },
+                )
+                .unwrap();
+
+            let instance = linker
+                .instantiate(&mut store, &module)
+                .unwrap()
+                .start(&mut store)
+                .unwrap();
+
+            Sandbox {
+                output,
+                store,
+                instance,
+            }
+        }
+
+        fn run_cycle(&mut self) -> i32 {
+            self.instance
+                .get_typed_func::<(), i32>(&self.store, abi::RUN_CYCLE)
+                .unwrap()
+                .call(&mut self.store, ())
+                .unwrap()
+        }
+    }
+
+    impl Execution for Sandbox {
+        fn get(&self, path: &str) -> Option<Value> {
+            let memory =
+                self.instance.get_memory(&self.store, abi::MEMORY).unwrap();
+            self.output.get(memory.data(&self.store), path)
+        }
+    }
+
+    fn compare(src: &str, cycles: u64, paths: &[&str]) -> Sandbox {
+        testing::compare(src, cycles, paths, |cu| {
+            let mut sandbox = Sandbox::new(generate(cu).unwrap());
+            for _ in 0..cycles {
+                assert_eq!(sandbox.run_cycle(), status::OK);
+            }
+            Some(sandbox)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn function_blocks_and_functions() {
+        let src = "
+            FUNCTION_BLOCK counter
+                VAR_INPUT
+                    step: int;
+                END_VAR
+                VAR_OUTPUT
+                    total: int;
+                END_VAR
+            BEGIN
+                total := total + step;
+            END_FUNCTION_BLOCK
+
+            FUNCTION double : int
+            BEGIN
+                VAR_INPUT
+                    x: int;
+                END_VAR
+                double := x * 2;
+            END_FUNCTION
+
+            PROGRAM main
+                VAR
+                    slow: counter;
+                    fast: counter;
+                    copy: int;
+                    direct: int;
+                END_VAR
+                slow(1);
+                fast(step := double(5), total => copy);
+                direct := fast.total + slow.total;
+            END_PROGRAM";
+
+        compare(
+            src,
+            3,
+            &[
+                "main.slow.total",
+                "main.fast.total",
+                "main.copy",
+                "main.direct",
+            ],
+        );
+    }
+
+    #[test]
+    fn loops_and_branches() {
+        let src = "
+            PROGRAM main
+                VAR
+                    i: int;
+                    total: dint;
+                    evens: int;
+                    repeats: int;
+                    countdown: int;
+                END_VAR
+                total := 0;
+                evens := 0;
+                FOR i := 1 TO 100 DO
+                    IF i > 50 THEN
+                        EXIT;
+                    END_IF;
+                    IF i % 2 = 0 THEN
+                        evens := evens + 1;
+                    END_IF;
+                    total := total + i * i;
+                END_FOR;
+
+                countdown := 0;
+                FOR i := 10 TO 0 BY -3 DO
+                    countdown := countdown + i;
+                END_FOR;
+
+                repeats := 0;
+                WHILE repeats < 7 DO
+                    repeats := repeats + 1;
+                END_WHILE;
+            END_PROGRAM";
+
+        compare(
+            src,
+            2,
+            &[
+                "main.i",
+                "main.total",
+                "main.evens",
+                "main.countdown",
+                "main.repeats",
+            ],
+        );
+    }
+
+    #[test]
+    fn integer_arithmetic_wraps() {
+        let src = "
+            PROGRAM main
+                VAR
+                    small: sint;
+                    unsigned: usint;
+                    word_value: word;
+                    quotient: int;
+                    remainder: int;
+                    power: dint;
+                    wrapped: sint;
+                    negated: int;
+                    inverted: byte;
+                    flag: bool;
+                    mixed: bool;
+                END_VAR
+                small := small + 100;
+                unsigned := unsigned - 3;
+                word_value := word_value - 1;
+                quotient := -17 / 5;
+                remainder := -17 % 5;
+                power := 3 ** 5;
+                wrapped := small * 3;
+                negated := -(small);
+                inverted := NOT unsigned;
+                flag := (small < 0) XOR (unsigned > 200);
+                mixed := NOT flag AND (power >= 243);
+            END_PROGRAM";
+
+        compare(
+            src,
+            3,
+            &[
+                "main.small",
+                "main.unsigned",
+                "main.word_value",
+                "main.quotient",
+                "main.remainder",
+                "main.power",
+                "main.wrapped",
+                "main.negated",
+                "main.inverted",
+                "main.flag",
+                "main.mixed",
+            ],
+        );
+    }
+
+    #[test]
+    fn builtin_functions_are_inlined() {
+        let src = "
+            PROGRAM main
+                VAR
+                    a: int;
+                    b: int;
+                    c: int;
+                    d: int;
+                    e: int;
+                    bits: byte;
+                    rotated: word;
+                    shifted: word;
+                    cleared: byte;
+                END_VAR
+                a := abs(-42);
+                b := min(a, 7);
+                c := max(limit(0, a * 100, 500), 7);
+                d := sel(a > 10, 1, 2);
+                bits := 1;
+                bits := ror(bits, 1);
+                e := bits;
+                rotated := 32769;
+                rotated := rol(rotated, 17);
+                shifted := 3;
+                shifted := shl(shifted, 4);
+                cleared := 255;
+                cleared := shr(cleared, 9);
+            END_PROGRAM";
+
+        compare(
+            src,
+            1,
+            &[
+                "main.a",
+                "main.b",
+                "main.c",
+                "main.d",
+                "main.e",
+                "main.bits",
+                "main.rotated",
+                "main.shifted",
+                "main.cleared",
+            ],
+        );
+    }
+
+    #[test]
+    fn globals_are_shared_between_programs() {
+        let src = "
+            VAR_GLOBAL
+                shared: int;
+            END_VAR
+
+            PROGRAM first
+                VAR
+                    seen: int;
+                END_VAR
+                shared := shared + 5;
+                seen := shared;
+            END_PROGRAM
+
+            PROGRAM second
+                VAR
+                    seen: int;
+                END_VAR
+                seen := shared * 2;
+            END_PROGRAM";
+
+        let sandbox = compare(src, 4, &["shared", "first.seen", "second.seen"]);
+
+        let exported = sandbox
+            .instance
+            .get_global(&sandbox.store, abi::GLOBALS)
+            .unwrap()
+            .get(&sandbox.store);
+        assert_eq!(exported.i32(), Some(sandbox.output.memory.globals as i32));
+    }
+
+    #[test]
+    fn division_by_zero_aborts_the_cycle() {
+        let src = "
+            FUNCTION divide : int
+            BEGIN
+                VAR_INPUT
+                    x: int;
+                END_VAR
+                divide := 10 / x;
+            END_FUNCTION
+
+            PROGRAM main
+                VAR
+                    countdown: int;
+                    y: int;
+                END_VAR
+                countdown := countdown - 1;
+                y := divide(countdown + 3);
+            END_PROGRAM";
+        let (_, cu) = testing::analyse(src);
+        let mut sandbox = Sandbox::new(generate(&cu).unwrap());
+
+        assert_eq!(sandbox.run_cycle(), status::OK);
+        assert_eq!(sandbox.run_cycle(), status::OK);
+        assert_eq!(
+            sandbox.get("main.y").and_then(|v| v.as_integer()),
+            Some(10)
+        );
+
+        assert_eq!(sandbox.run_cycle(), status::DIVISION_BY_ZERO);
+        // the failed scan doesn't write its outputs
+        assert_eq!(
+            sandbox.store.data().events,
+            ["read", "write", "read", "write", "read"]
+        );
+
+        // the next scan carries on from where the failed one left off
+        assert_eq!(sandbox.run_cycle(), status::OK);
+        assert_eq!(
+            sandbox.get("main.y").and_then(|v| v.as_integer()),
+            Some(-10)
+        );
+    }
+
+    #[test]
+    fn inputs_are_read_before_the_scan() {
+        let src = "
+            VAR_GLOBAL
+                sensor: uint;
+            END_VAR
+
+            PROGRAM main
+                VAR
+                    total: udint;
+                END_VAR
+                total := total + sensor;
+            END_PROGRAM";
+        let (_, cu) = testing::analyse(src);
+        let output = generate(&cu).unwrap();
+        let host = Host {
+            now: 100,
+            input: output.address_of("sensor").map(|(address, _)| address),
+            ..Default::default()
+        };
+        let mut sandbox = Sandbox::with_host(output, host);
+
+        for _ in 0..3 {
+            assert_eq!(sandbox.run_cycle(), status::OK);
+        }
+
+        // the clock moves forward by 5ms every scan
+        assert_eq!(
+            sandbox.get("main.total").and_then(|v| v.as_integer()),
+            Some(100 + 105 + 110)
+        );
+        let cycle_time = sandbox
+            .instance
+            .get_global(&sandbox.store, abi::CYCLE_TIME)
+            .unwrap()
+            .get(&sandbox.store);
+        assert_eq!(cycle_time.i64(), Some(5));
+    }
+}
@@ -0,0 +1,870 @@
+//! Translating the HIR for a single POU into a WebAssembly function body.
+//!
+//! WebAssembly only has structured control flow, so each POU's basic blocks
+//! are placed inside a `loop` and a `br_table` jumps to whichever block
+//! should run next. Every intermediate value is given its own local, which
+//! keeps the translation close to the HIR and leaves register allocation to
+//! the runtime.
+
+use crate::{status, CodegenError, MemoryMap};
+use iec::analysis::reachable_blocks;
+use iec::codegen::{
+    self, BoolOp, Comparison, IntegerOp, Lower, RealOp, Tables, Trap,
+};
+use iec::const_eval::{IntegerType, Scalar};
+use iec::ecs::EntityId;
+use iec::hir::{CompilationUnit, Symbol, Terminator};
+use iec::layout::{FieldType, Layout, PouKind, PouLayout, VariableType};
+use iec::passes::register_builtins::Shift;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use wasm_encoder::{BlockType, Function as WasmFunction, MemArg, ValType};
+
+type Op = wasm_encoder::Instruction<'static>;
+
+/// The global holding the stack pointer used for function call frames.
+pub(crate) const STACK_POINTER: u32 = 0;
+
+/// Translate every POU in the [`Layout`], returning their function bodies in
+/// the same order.
+///
+/// POU `n` is expected to have the function index `first_index + n`.
+pub(crate) fn translate_pous(
+    unit: &CompilationUnit,
+    layout: &Layout,
+    memory: &MemoryMap,
+    first_index: u32,
+) -> Result<Vec<WasmFunction>, CodegenError> {
+    codegen::with_tables(unit, |tables| {
+        layout
+            .pous
+            .iter()
+            .enumerate()
+            .map(|(index, pou)| {
+                translate_pou(tables, layout, memory, first_index, index, pou)
+            })
+            .collect()
+    })
+}
+
+fn translate_pou(
+    tables: &Tables<'_>,
+    layout: &Layout,
+    memory: &MemoryMap,
+    first_index: u32,
+    index: usize,
+    pou: &PouLayout,
+) -> Result<WasmFunction, CodegenError> {
+    let (params, this) = match pou.kind {
+        PouKind::Program => {
+            let address =
+                memory.instances[index].expect("every program has an instance");
+            (
+                0,
+                Place::at(Base::Static, address, FieldType::Instance(index)),
+            )
+        }
+        _ => (1, Place::at(Base::Local(0), 0, FieldType::Instance(index))),
+    };
+
+    let translator = Translator {
+        tables,
+        layout,
+        memory,
+        first_index,
+        params,
+        locals: vec![ValType::I32],
+        code: Vec::new(),
+        this,
+        saved_stack_pointers: Vec::new(),
+    };
+    translator.translate(pou.entry_block)
+}
+
+/// The type of the local a [`Scalar`] is kept in.
+///
+/// Booleans are an `i32` which is either `0` or `1`, and integers are always
+/// kept as an `i64` which has been sign or zero extended from the integer's
+/// real width.
+fn val_type(scalar: Scalar) -> ValType {
+    match scalar {
+        Scalar::Bool => ValType::I32,
+        Scalar::Integer(_) => ValType::I64,
+        Scalar::Real => ValType::F64,
+    }
+}
+
+/// A value stored in a local.
+type Typed = codegen::Typed<u32>;
+
+/// What a [`Place`]'s offset is relative to.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Base {
+    /// The offset is an absolute address.
+    Static,
+    /// The offset is relative to the address stored in a local.
+    Local(u32),
+}
+
+/// A location in linear memory.
+#[derive(Debug, Copy, Clone)]
+struct Place {
+    base: Base,
+    offset: u32,
+    ty: FieldType,
+}
+
+impl Place {
+    fn at(base: Base, offset: u32, ty: FieldType) -> Place {
+        Place { base, offset, ty }
+    }
+}
+
+struct Translator<'t, 'a> {
+    tables: &'t Tables<'a>,
+    layout: &'t Layout,
+    memory: &'t MemoryMap,
+    first_index: u32,
+    /// The number of parameters the function takes.
+    params: u32,
+    /// The types of every local which isn't a parameter. The first is the
+    /// index of the next block to execute.
+    locals: Vec<ValType>,
+    code: Vec<Op>,
+    /// The POU's own instance data (or stack frame).
+    this: Place,
+    /// For each call in progress, the local holding the stack pointer to
+    /// restore once the callee's frame is no longer needed.
+    saved_stack_pointers: Vec<Option<u32>>,
+}
+
+impl<'t, 'a> Translator<'t, 'a> {
+    fn translate(
+        mut self,
+        entry: EntityId,
+    ) -> Result<WasmFunction, CodegenError> {
+        let order = reachable_blocks(entry, self.tables.blocks);
+        let indices: HashMap<EntityId, u32> = order
+            .iter()
+            .enumerate()
+            .map(|(i, &block)| (block, i as u32))
+            .collect();
+        let next = self.params;
+
+        // the entry block comes first, so it's selected by the zeroed local
+        self.emit(Op::Loop(BlockType::Empty));
+        for _ in 0..order.len() {
+            self.emit(Op::Block(BlockType::Empty));
+        }
+        self.emit(Op::LocalGet(next));
+        let targets: Vec<u32> = (0..order.len() as u32).collect();
+        self.emit(Op::BrTable(Cow::Owned(targets), 0));
+
+        for (i, &block) in order.iter().enumerate() {
+            self.emit(Op::End);
+            let dispatch = Dispatch {
+                next,
+                current: i as u32,
+                depth: (order.len() - 1 - i) as u32,
+                indices: &indices,
+            };
+            self.block(&dispatch, block)?;
+        }
+
+        // every block ends with a terminator, so we never fall off the end
+        self.emit(Op::End);
+        self.emit(Op::Unreachable);
+        self.emit(Op::End);
+
+        let mut function = WasmFunction::new_with_locals_types(self.locals);
+        for op in &self.code {
+            function.instruction(op);
+        }
+
+        Ok(function)
+    }
+
+    fn emit(&mut self, op: Op) {
+        self.code.push(op);
+    }
+
+    fn new_local(&mut self, ty: ValType) -> u32 {
+        self.locals.push(ty);
+        self.params + self.locals.len() as u32 - 1
+    }
+
+    /// Pop the value on top of the stack into a new local.
+    fn set(&mut self, ty: Scalar) -> Typed {
+        let local = self.new_local(val_type(ty));
+        self.emit(Op::LocalSet(local));
+        Typed::new(local, ty)
+    }
+
+    fn get(&mut self, typed: &Typed) {
+        self.emit(Op::LocalGet(typed.value));
+    }
+
+    fn block(
+        &mut self,
+        dispatch: &Dispatch<'_>,
+        id: EntityId,
+    ) -> Result<(), CodegenError> {
+        let block = self.tables.block(id);
+        self.instructions(block)?;
+
+        match block.terminator {
+            Terminator::Jump(target) => {
+                let target = dispatch.indices[&target];
+                // the next block's code comes straight after this one
+                if target != dispatch.current + 1 {
+                    self.emit(Op::I32Const(target as i32));
+                    self.emit(Op::LocalSet(dispatch.next));
+                    self.emit(Op::Br(dispatch.depth));
+                }
+            }
+            Terminator::Branch {
+                condition,
+                then,
+                otherwise,
+            } => {
+                let condition = self.condition(condition)?;
+                self.emit(Op::I32Const(dispatch.indices[&then] as i32));
+                self.emit(Op::I32Const(dispatch.indices[&otherwise] as i32));
+                self.emit(Op::LocalGet(condition));
+                self.emit(Op::Select);
+                self.emit(Op::LocalSet(dispatch.next));
+                self.emit(Op::Br(dispatch.depth));
+            }
+            Terminator::Return => {
+                self.emit(Op::I32Const(status::OK));
+                self.emit(Op::Return);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Push the address a [`Place`]'s offset is relative to.
+    fn base(&mut self, base: Base) {
+        match base {
+            Base::Static => self.emit(Op::I32Const(0)),
+            Base::Local(local) => self.emit(Op::LocalGet(local)),
+        }
+    }
+
+    /// Truncate the `i64` on top of the stack to `ty`'s width, then sign or
+    /// zero extend it back to 64 bits.
+    fn wrap(&mut self, ty: IntegerType) {
+        match (ty.bits(), ty.is_signed()) {
+            (64, _) => {}
+            (8, true) => self.emit(Op::I64Extend8S),
+            (16, true) => self.emit(Op::I64Extend16S),
+            (32, true) => self.emit(Op::I64Extend32S),
+            (bits, _) => self.zero_extend(bits),
+        }
+    }
+
+    /// Clear everything above the bottom `bits` bits of the `i64` on top of
+    /// the stack.
+    fn zero_extend(&mut self, bits: u32) {
+        if bits < 64 {
+            self.emit(Op::I64Const((1 << bits) - 1));
+            self.emit(Op::I64And);
+        }
+    }
+
+    /// Push the result of a division which never traps, assuming the
+    /// divisor isn't zero.
+    fn divide(&mut self, op: IntegerOp, l: &Typed, r: &Typed, unsigned: bool) {
+        if unsigned {
+            self.get(l);
+            self.get(r);
+            self.emit(match op {
+                IntegerOp::Divide => Op::I64DivU,
+                _ => Op::I64RemU,
+            });
+            return;
+        }
+
+        // i64::MIN / -1 overflows, which WebAssembly treats as a trap
+        self.get(r);
+        self.emit(Op::I64Const(-1));
+        self.emit(Op::I64Eq);
+        let minus_one = self.set(Scalar::Bool);
+
+        match op {
+            IntegerOp::Divide => {
+                self.emit(Op::I64Const(0));
+                self.get(l);
+                self.emit(Op::I64Sub);
+                self.get(l);
+                self.safe_divisor(r, &minus_one);
+                self.emit(Op::I64DivS);
+            }
+            _ => {
+                self.emit(Op::I64Const(0));
+                self.get(l);
+                self.safe_divisor(r, &minus_one);
+                self.emit(Op::I64RemS);
+            }
+        }
+
+        self.get(&minus_one);
+        self.emit(Op::Select);
+    }
+
+    /// Push the divisor, replacing `-1` with `1`.
+    fn safe_divisor(&mut self, r: &Typed, minus_one: &Typed) {
+        self.emit(Op::I64Const(1));
+        self.get(r);
+        self.get(minus_one);
+        self.emit(Op::Select);
+    }
+
+    /// Push `base ** exponent` with wrapping multiplication, using
+    /// exponentiation by squaring.
+    fn power(&mut self, base: &Typed, exponent: &Typed) {
+        let base = self.copy_local(base);
+        let exponent = self.copy_local(exponent);
+        self.emit(Op::I64Const(1));
+        let acc = self.set(base.ty);
+
+        self.emit(Op::Block(BlockType::Empty));
+        self.emit(Op::Loop(BlockType::Empty));
+        self.get(&exponent);
+        self.emit(Op::I64Eqz);
+        self.emit(Op::BrIf(1));
+
+        // if the exponent is odd, acc *= base
+        self.get(&acc);
+        self.get(&base);
+        self.emit(Op::I64Mul);
+        self.get(&acc);
+        self.get(&exponent);
+        self.emit(Op::I64Const(1));
+        self.emit(Op::I64And);
+        self.emit(Op::I32WrapI64);
+        self.emit(Op::Select);
+        self.emit(Op::LocalSet(acc.value));
+
+        self.get(&base);
+        self.get(&base);
+        self.emit(Op::I64Mul);
+        self.emit(Op::LocalSet(base.value));
+        self.get(&exponent);
+        self.emit(Op::I64Const(1));
+        self.emit(Op::I64ShrU);
+        self.emit(Op::LocalSet(exponent.value));
+        self.emit(Op::Br(0));
+        self.emit(Op::End);
+        self.emit(Op::End);
+
+        self.get(&acc);
+    }
+
+    /// Copy a value into a new local so it can be mutated.
+    fn copy_local(&mut self, typed: &Typed) -> Typed {
+        self.get(typed);
+        self.set(typed.ty)
+    }
+
+    /// Allocate a zeroed frame on the stack for a function call, returning
+    /// the frame and a local holding the stack pointer to restore afterwards.
+    fn new_frame(&mut self, pou: usize) -> (Place, u32) {
+        let size = self.layout.pous[pou].frame.size.div_ceil(8) * 8;
+        let frame = self.new_local(ValType::I32);
+
+        self.emit(Op::GlobalGet(STACK_POINTER));
+        self.emit(Op::LocalTee(frame));
+        self.emit(Op::I32Const(size as i32));
+        self.emit(Op::I32Add);
+        self.emit(Op::GlobalSet(STACK_POINTER));
+
+        for offset in (0..size).step_by(8) {
+            self.emit(Op::LocalGet(frame));
+            self.emit(Op::I64Const(0));
+            self.emit(Op::I64Store(mem_arg(offset, 8)));
+        }
+
+        (
+            Place::at(Base::Local(frame), 0, FieldType::Instance(pou)),
+            frame,
+        )
+    }
+
+    /// Give back the stack space used by the innermost call's frame.
+    fn restore_stack_pointer(&mut self) {
+        if let Some(&Some(saved)) = self.saved_stack_pointers.last() {
+            self.emit(Op::LocalGet(saved));
+            self.emit(Op::GlobalSet(STACK_POINTER));
+        }
+    }
+}
+
+impl<'t, 'a> Lower<'a> for Translator<'t, 'a> {
+    type Value = u32;
+    type Place = Place;
+
+    fn tables(&self) -> &Tables<'a> {
+        self.tables
+    }
+
+    fn place(&mut self, variable: EntityId) -> Place {
+        let location = self
+            .layout
+            .location(variable)
+            .expect("every variable has a location");
+        let field = self.layout.field(location);
+
+        match location.owner {
+            Some(_) => Place::at(
+                self.this.base,
+                self.this.offset + field.offset,
+                field.ty,
+            ),
+            None => Place::at(
+                Base::Static,
+                self.memory.globals + field.offset,
+                field.ty,
+            ),
+        }
+    }
+
+    fn member(&mut self, object: &Place, member: EntityId) -> Place {
+        let location = self
+            .layout
+            .location(member)
+            .expect("every variable has a location");
+        let field = self.layout.field(location);
+
+        Place::at(object.base, object.offset + field.offset, field.ty)
+    }
+
+    fn place_type(&self, place: &Place) -> VariableType {
+        place.ty.into()
+    }
+
+    fn load(&mut self, place: &Place, ty: Scalar) -> u32 {
+        self.base(place.base);
+        let offset = place.offset;
+        let op = match ty {
+            Scalar::Bool => Op::I32Load8U(mem_arg(offset, 1)),
+            Scalar::Real => Op::F64Load(mem_arg(offset, 8)),
+            Scalar::Integer(int) => {
+                let size = int.bits() / 8;
+                let arg = mem_arg(offset, size);
+                match (size, int.is_signed()) {
+                    (1, true) => Op::I64Load8S(arg),
+                    (1, false) => Op::I64Load8U(arg),
+                    (2, true) => Op::I64Load16S(arg),
+                    (2, false) => Op::I64Load16U(arg),
+                    (4, true) => Op::I64Load32S(arg),
+                    (4, false) => Op::I64Load32U(arg),
+                    _ => Op::I64Load(arg),
+                }
+            }
+        };
+        self.emit(op);
+
+        self.set(ty).value
+    }
+
+    /// Integer stores truncate, which is how values get wrapped to their
+    /// destination's width.
+    fn store(&mut self, place: &Place, value: &Typed) {
+        self.base(place.base);
+        self.get(value);
+
+        let offset = place.offset;
+        let op = match value.ty {
+            Scalar::Bool => Op::I32Store8(mem_arg(offset, 1)),
+            Scalar::Real => Op::F64Store(mem_arg(offset, 8)),
+            Scalar::Integer(int) => {
+                let size = int.bits() / 8;
+                let arg = mem_arg(offset, size);
+                match size {
+                    1 => Op::I64Store8(arg),
+                    2 => Op::I64Store16(arg),
+                    4 => Op::I64Store32(arg),
+                    _ => Op::I64Store(arg),
+                }
+            }
+        };
+        self.emit(op);
+    }
+
+    /// Copy an aggregate using the widest loads and stores possible.
+    fn copy(&mut self, dest: &Place, src: &Place) {
+        let size = self.layout.size_of(dest.ty);
+        let mut copied = 0;
+
+        for &width in &[8, 4, 2, 1] {
+            while size - copied >= width {
+                let from = mem_arg(src.offset + copied, 1);
+                let to = mem_arg(dest.offset + copied, 1);
+                let (load, store) = match width {
+                    8 => (Op::I64Load(from), Op::I64Store(to)),
+                    4 => (Op::I32Load(from), Op::I32Store(to)),
+                    2 => (Op::I32Load16U(from), Op::I32Store16(to)),
+                    _ => (Op::I32Load8U(from), Op::I32Store8(to)),
+                };
+
+                self.base(dest.base);
+                self.base(src.base);
+                self.emit(load);
+                self.emit(store);
+                copied += width;
+            }
+        }
+    }
+
+    fn boolean(&mut self, value: bool) -> u32 {
+        self.emit(Op::I32Const(i32::from(value)));
+        self.set(Scalar::Bool).value
+    }
+
+    fn integer(&mut self, value: i64, ty: IntegerType) -> u32 {
+        self.emit(Op::I64Const(value));
+        self.set(Scalar::Integer(ty)).value
+    }
+
+    fn real(&mut self, value: f64) -> u32 {
+        self.emit(Op::F64Const(value));
+        self.set(Scalar::Real).value
+    }
+
+    fn resize(&mut self, value: &Typed, to: IntegerType) -> u32 {
+        self.get(value);
+        self.wrap(to);
+        self.set(Scalar::Integer(to)).value
+    }
+
+    fn int_to_real(&mut self, value: &Typed) -> u32 {
+        self.get(value);
+        self.emit(match value.ty {
+            Scalar::Integer(int) if int.is_signed() => Op::F64ConvertI64S,
+            _ => Op::F64ConvertI64U,
+        });
+        self.set(Scalar::Real).value
+    }
+
+    fn is_zero(&mut self, value: &Typed) -> u32 {
+        self.get(value);
+        match value.ty {
+            Scalar::Real => {
+                self.emit(Op::F64Const(0.0));
+                self.emit(Op::F64Eq);
+            }
+            _ => self.emit(Op::I64Eqz),
+        }
+        self.set(Scalar::Bool).value
+    }
+
+    fn is_negative(&mut self, value: &Typed) -> u32 {
+        self.get(value);
+        self.emit(Op::I64Const(0));
+        self.emit(Op::I64LtS);
+        self.set(Scalar::Bool).value
+    }
+
+    /// Return the trap's status code from the current function if
+    /// `condition` is true.
+    fn trap_if(&mut self, condition: u32, trap: Trap) {
+        let code = match trap {
+            Trap::DivisionByZero => status::DIVISION_BY_ZERO,
+            Trap::NegativeExponent => status::NEGATIVE_EXPONENT,
+            Trap::InvalidOperands => status::INVALID_OPERANDS,
+        };
+
+        self.emit(Op::LocalGet(condition));
+        self.emit(Op::If(BlockType::Empty));
+        self.emit(Op::I32Const(code));
+        self.emit(Op::Return);
+        self.emit(Op::End);
+    }
+
+    /// Integers are always kept sign or zero extended to 64 bits, so they
+    /// can be compared directly.
+    fn compare_integers(
+        &mut self,
+        comparison: Comparison,
+        left: &Typed,
+        right: &Typed,
+        unsigned: bool,
+    ) -> u32 {
+        self.get(left);
+        self.get(right);
+        self.emit(integer_comparison(comparison, unsigned));
+        self.set(Scalar::Bool).value
+    }
+
+    fn compare_reals(
+        &mut self,
+        comparison: Comparison,
+        left: &u32,
+        right: &u32,
+    ) -> u32 {
+        self.emit(Op::LocalGet(*left));
+        self.emit(Op::LocalGet(*right));
+        self.emit(float_comparison(comparison));
+        self.set(Scalar::Bool).value
+    }
+
+    fn bool_binary(&mut self, op: BoolOp, left: &u32, right: &u32) -> u32 {
+        self.emit(Op::LocalGet(*left));
+        self.emit(Op::LocalGet(*right));
+        self.emit(match op {
+            BoolOp::And => Op::I32And,
+            BoolOp::Or => Op::I32Or,
+            BoolOp::Xor => Op::I32Xor,
+            BoolOp::Equal => Op::I32Eq,
+            BoolOp::NotEqual => Op::I32Ne,
+        });
+        self.set(Scalar::Bool).value
+    }
+
+    /// Integer arithmetic is done using 64 bits then wrapped, the same as
+    /// the interpreter does.
+    fn integer_arithmetic(
+        &mut self,
+        op: IntegerOp,
+        left: &Typed,
+        right: &Typed,
+        ty: IntegerType,
+        unsigned: bool,
+    ) -> u32 {
+        let instruction = match op {
+            IntegerOp::Add => Op::I64Add,
+            IntegerOp::Subtract => Op::I64Sub,
+            IntegerOp::Multiply => Op::I64Mul,
+            IntegerOp::And => Op::I64And,
+            IntegerOp::Or => Op::I64Or,
+            IntegerOp::Xor => Op::I64Xor,
+            IntegerOp::Divide | IntegerOp::Modulo => {
+                self.divide(op, left, right, unsigned);
+                self.wrap(ty);
+                return self.set(Scalar::Integer(ty)).value;
+            }
+            IntegerOp::Exponent => {
+                self.power(left, right);
+                self.wrap(ty);
+                return self.set(Scalar::Integer(ty)).value;
+            }
+        };
+
+        self.get(left);
+        self.get(right);
+        self.emit(instruction);
+        self.wrap(ty);
+        self.set(Scalar::Integer(ty)).value
+    }
+
+    fn real_arithmetic(&mut self, op: RealOp, left: &u32, right: &u32) -> u32 {
+        self.emit(Op::LocalGet(*left));
+        self.emit(Op::LocalGet(*right));
+        self.emit(match op {
+            RealOp::Add => Op::F64Add,
+            RealOp::Subtract => Op::F64Sub,
+            RealOp::Multiply => Op::F64Mul,
+            RealOp::Divide => Op::F64Div,
+        });
+        self.set(Scalar::Real).value
+    }
+
+    fn not(&mut self, value: &Typed) -> u32 {
+        self.get(value);
+        match value.ty {
+            Scalar::Integer(int) => {
+                self.emit(Op::I64Const(-1));
+                self.emit(Op::I64Xor);
+                self.wrap(int);
+            }
+            _ => self.emit(Op::I32Eqz),
+        }
+        self.set(value.ty).value
+    }
+
+    fn negate(&mut self, value: &Typed) -> u32 {
+        match value.ty {
+            Scalar::Integer(int) => {
+                self.emit(Op::I64Const(0));
+                self.get(value);
+                self.emit(Op::I64Sub);
+                self.wrap(int);
+            }
+            _ => {
+                self.get(value);
+                self.emit(Op::F64Neg);
+            }
+        }
+        self.set(value.ty).value
+    }
+
+    fn abs(&mut self, value: &Typed) -> u32 {
+        match value.ty {
+            Scalar::Integer(int) => {
+                self.emit(Op::I64Const(0));
+                self.get(value);
+                self.emit(Op::I64Sub);
+                self.wrap(int);
+                self.get(value);
+                self.get(value);
+                self.emit(Op::I64Const(0));
+                self.emit(Op::I64LtS);
+                self.emit(Op::Select);
+            }
+            _ => {
+                self.get(value);
+                self.emit(Op::F64Abs);
+            }
+        }
+        self.set(value.ty).value
+    }
+
+    fn select(
+        &mut self,
+        condition: &u32,
+        if_true: &Typed,
+        if_false: &Typed,
+    ) -> u32 {
+        self.get(if_true);
+        self.get(if_false);
+        self.emit(Op::LocalGet(*condition));
+        self.emit(Op::Select);
+        self.set(if_true.ty).value
+    }
+
+    fn shift(&mut self, shift: Shift, value: &Typed, amount: &Typed) -> u32 {
+        let ty = match value.ty {
+            Scalar::Integer(ty) => ty,
+            _ => unreachable!("only integers are shifted"),
+        };
+        let bits = i64::from(ty.bits());
+
+        // the bits we're shifting, without any sign extension
+        self.get(value);
+        self.zero_extend(ty.bits());
+        let bits_of_x = self.set(value.ty);
+
+        let (forwards, backwards) = if shift.is_left() {
+            (Op::I64Shl, Op::I64ShrU)
+        } else {
+            (Op::I64ShrU, Op::I64Shl)
+        };
+
+        if shift.is_rotate() {
+            self.get(amount);
+            self.emit(Op::I64Const(bits));
+            self.emit(Op::I64RemU);
+            let n = self.set(amount.ty);
+
+            // WebAssembly only looks at the bottom 6 bits of the
+            // amount, so rotating a LWORD by 0 shifts it by 64 (i.e. 0)
+            self.get(&bits_of_x);
+            self.get(&n);
+            self.emit(forwards);
+            self.get(&bits_of_x);
+            self.emit(Op::I64Const(bits));
+            self.get(&n);
+            self.emit(Op::I64Sub);
+            self.emit(backwards);
+            self.emit(Op::I64Or);
+        } else {
+            // shifting by the type's width (or more) clears every bit
+            self.get(&bits_of_x);
+            self.get(amount);
+            self.emit(forwards);
+            self.emit(Op::I64Const(0));
+            self.get(amount);
+            self.emit(Op::I64Const(bits));
+            self.emit(Op::I64LtU);
+            self.emit(Op::Select);
+        }
+
+        self.wrap(ty);
+        self.set(value.ty).value
+    }
+
+    fn pou_index(&self, symbol: Symbol) -> Option<usize> {
+        self.layout.pou_index(symbol)
+    }
+
+    fn begin_call(&mut self, pou: usize, instance: Option<Place>) -> Place {
+        let (frame, saved) = match instance {
+            Some(instance) => (instance, None),
+            None => {
+                let (frame, saved) = self.new_frame(pou);
+                (frame, Some(saved))
+            }
+        };
+
+        self.saved_stack_pointers.push(saved);
+        frame
+    }
+
+    fn invoke(&mut self, pou: usize, frame: &Place) {
+        self.base(frame.base);
+        if frame.offset != 0 {
+            self.emit(Op::I32Const(frame.offset as i32));
+            self.emit(Op::I32Add);
+        }
+        self.emit(Op::Call(self.first_index + pou as u32));
+        let status = self.new_local(ValType::I32);
+        self.emit(Op::LocalTee(status));
+        self.emit(Op::If(BlockType::Empty));
+        self.restore_stack_pointer();
+        self.emit(Op::LocalGet(status));
+        self.emit(Op::Return);
+        self.emit(Op::End);
+    }
+
+    fn end_call(&mut self, _pou: usize) {
+        self.restore_stack_pointer();
+        self.saved_stack_pointers.pop();
+    }
+}
+
+/// How to get from one block to another.
+struct Dispatch<'d> {
+    /// The local holding the index of the next block to execute.
+    next: u32,
+    /// The index of the block being translated.
+    current: u32,
+    /// How many labels need to be skipped to get back to the `loop`.
+    depth: u32,
+    indices: &'d HashMap<EntityId, u32>,
+}
+
+fn mem_arg(offset: u32, align: u32) -> MemArg {
+    MemArg {
+        offset: u64::from(offset),
+        align: align.trailing_zeros(),
+        memory_index: 0,
+    }
+}
+
+fn integer_comparison(comparison: Comparison, unsigned: bool) -> Op {
+    match (comparison, unsigned) {
+        (Comparison::Equal, _) => Op::I64Eq,
+        (Comparison::NotEqual, _) => Op::I64Ne,
+        (Comparison::Less, false) => Op::I64LtS,
+        (Comparison::Less, true) => Op::I64LtU,
+        (Comparison::LessOrEqual, false) => Op::I64LeS,
+        (Comparison::LessOrEqual, true) => Op::I64LeU,
+        (Comparison::Greater, false) => Op::I64GtS,
+        (Comparison::Greater, true) => Op::I64GtU,
+        (Comparison::GreaterOrEqual, false) => Op::I64GeS,
+        (Comparison::GreaterOrEqual, true) => Op::I64GeU,
+    }
+}
+
+fn float_comparison(comparison: Comparison) -> Op {
+    match comparison {
+        Comparison::Equal => Op::F64Eq,
+        Comparison::NotEqual => Op::F64Ne,
+        Comparison::Less => Op::F64Lt,
+        Comparison::LessOrEqual => Op::F64Le,
+        Comparison::Greater => Op::F64Gt,
+        Comparison::GreaterOrEqual => Op::F64Ge,
+    }
+}
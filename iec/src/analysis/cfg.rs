@@ -0,0 +1,316 @@
+use crate::ecs::{Container, EntityId};
+use crate::hir::{BasicBlock, Instruction, Symbol, Terminator};
+use heapsize_derive::HeapSizeOf;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+/// Find every block reachable from `entry`, in depth-first order (i.e. the
+/// order a backend would naturally write them out).
+///
+/// Unlike [`ControlFlowGraph::new()`], this doesn't need to know which item
+/// the blocks belong to, and skips any unreachable blocks.
+pub fn reachable_blocks(
+    entry: EntityId,
+    blocks: &Container<BasicBlock>,
+) -> Vec<EntityId> {
+    let mut order = Vec::new();
+    let mut to_visit = vec![entry];
+
+    while let Some(block) = to_visit.pop() {
+        if order.contains(&block) {
+            continue;
+        }
+        order.push(block);
+
+        let successors = blocks
+            .get(block)
+            .expect("terminators only refer to known blocks")
+            .terminator
+            .successors();
+        to_visit.extend(successors.into_iter().rev());
+    }
+
+    order
+}
+
+/// The edges between the [`BasicBlock`]s in a single item's body.
+#[derive(Debug, Clone, PartialEq, HeapSizeOf)]
+pub struct ControlFlowGraph {
+    entry: EntityId,
+    /// Every block belonging to the item, starting with the entry block.
+    blocks: Vec<EntityId>,
+    successors: HashMap<EntityId, Vec<EntityId>>,
+    predecessors: HashMap<EntityId, Vec<EntityId>>,
+}
+
+impl ControlFlowGraph {
+    /// Construct the graph for all blocks belonging to `parent`.
+    pub fn new(
+        parent: Symbol,
+        entry: EntityId,
+        blocks: &Container<BasicBlock>,
+    ) -> ControlFlowGraph {
+        let mut ids: Vec<EntityId> = blocks
+            .iter()
+            .filter(|(id, block)| block.parent == parent && *id != entry)
+            .map(|(id, _)| id)
+            .collect();
+        ids.sort();
+        ids.insert(0, entry);
+
+        let mut successors = HashMap::new();
+        let mut predecessors: HashMap<EntityId, Vec<EntityId>> =
+            ids.iter().map(|&id| (id, Vec::new())).collect();
+
+        for &id in &ids {
+            let mut next = blocks
+                .get(id)
+                .expect("all blocks in the graph exist")
+                .terminator
+                .successors();
+            // both sides of a branch may go to the same place
+            next.dedup();
+
+            for &succ in &next {
+                predecessors.entry(succ).or_default().push(id);
+            }
+            successors.insert(id, next);
+        }
+
+        ControlFlowGraph {
+            entry,
+            blocks: ids,
+            successors,
+            predecessors,
+        }
+    }
+
+    /// The block execution starts at.
+    pub fn entry(&self) -> EntityId {
+        self.entry
+    }
+
+    /// Every block in the graph, starting with the entry block.
+    pub fn blocks(&self) -> &[EntityId] {
+        &self.blocks
+    }
+
+    pub fn successors(&self, block: EntityId) -> &[EntityId] {
+        self.successors.get(&block).map_or(&[], |s| s.as_slice())
+    }
+
+    pub fn predecessors(&self, block: EntityId) -> &[EntityId] {
+        self.predecessors.get(&block).map_or(&[], |p| p.as_slice())
+    }
+
+    /// The blocks which return from the item.
+    pub fn exits(&self) -> Vec<EntityId> {
+        self.blocks
+            .iter()
+            .cloned()
+            .filter(|&block| self.successors(block).is_empty())
+            .collect()
+    }
+
+    /// The blocks reachable from the entry block, in reverse postorder (i.e.
+    /// a block is always visited before its successors, ignoring back edges).
+    pub fn reverse_postorder(&self) -> Vec<EntityId> {
+        let mut visited = HashSet::new();
+        let mut postorder = Vec::new();
+        // each block alongside the index of the next successor to visit
+        let mut stack = vec![(self.entry, 0)];
+        visited.insert(self.entry);
+
+        while let Some(&mut (block, ref mut next)) = stack.last_mut() {
+            match self.successors(block).get(*next) {
+                Some(&succ) => {
+                    *next += 1;
+                    if visited.insert(succ) {
+                        stack.push((succ, 0));
+                    }
+                }
+                None => {
+                    postorder.push(block);
+                    stack.pop();
+                }
+            }
+        }
+
+        postorder.reverse();
+        postorder
+    }
+
+    /// Blocks which can never be executed because there is no path to them
+    /// from the entry block.
+    pub fn unreachable_blocks(&self) -> Vec<EntityId> {
+        let reachable: HashSet<_> =
+            self.reverse_postorder().into_iter().collect();
+
+        self.blocks
+            .iter()
+            .cloned()
+            .filter(|block| !reachable.contains(block))
+            .collect()
+    }
+
+    /// Render the graph in Graphviz's DOT format.
+    pub fn to_dot(
+        &self,
+        name: &str,
+        blocks: &Container<BasicBlock>,
+        instructions: &Container<Instruction>,
+    ) -> String {
+        let index: HashMap<_, _> = self
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(i, &id)| (id, i))
+            .collect();
+        let mut dot = String::new();
+
+        writeln!(dot, "digraph \"{}\" {{", escape(name)).unwrap();
+        writeln!(dot, "    node [shape=box, fontname=monospace];").unwrap();
+
+        for (i, &id) in self.blocks.iter().enumerate() {
+            let block = blocks.get(id).expect("all blocks in the graph exist");
+            let mut label = format!("bb{}:\\l", i);
+
+            for &instruction in &block.instructions {
+                let instruction = instructions
+                    .get(instruction)
+                    .expect("all instructions exist");
+                write!(label, "{}\\l", escape(&format!("{:?}", instruction)))
+                    .unwrap();
+            }
+
+            writeln!(dot, "    bb{} [label=\"{}\"];", i, label).unwrap();
+        }
+
+        for (i, &id) in self.blocks.iter().enumerate() {
+            let block = blocks.get(id).expect("all blocks in the graph exist");
+
+            match block.terminator {
+                Terminator::Jump(target) => {
+                    writeln!(dot, "    bb{} -> bb{};", i, index[&target])
+                        .unwrap();
+                }
+                Terminator::Branch {
+                    then, otherwise, ..
+                } => {
+                    writeln!(
+                        dot,
+                        "    bb{} -> bb{} [label=\"true\"];",
+                        i, index[&then]
+                    )
+                    .unwrap();
+                    writeln!(
+                        dot,
+                        "    bb{} -> bb{} [label=\"false\"];",
+                        i, index[&otherwise]
+                    )
+                    .unwrap();
+                }
+                Terminator::Return => {}
+            }
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::hir::{Operand, Terminator};
+
+    /// Create the blocks for a graph where `edges[i]` lists the successors of
+    /// the `i`'th block.
+    pub(crate) fn graph(
+        edges: &[&[usize]],
+    ) -> (ControlFlowGraph, Container<BasicBlock>, Vec<EntityId>) {
+        let parent = Symbol::Program(EntityId::default());
+        let mut blocks = Container::default();
+        let ids: Vec<_> = edges
+            .iter()
+            .map(|_| {
+                blocks.insert(BasicBlock {
+                    parent,
+                    instructions: Vec::new(),
+                    terminator: Terminator::Return,
+                })
+            })
+            .collect();
+
+        for (i, successors) in edges.iter().enumerate() {
+            let terminator = match *successors {
+                [] => Terminator::Return,
+                [next] => Terminator::Jump(ids[*next]),
+                [then, otherwise] => Terminator::Branch {
+                    condition: Operand::Variable(EntityId::default()),
+                    then: ids[*then],
+                    otherwise: ids[*otherwise],
+                },
+                _ => unreachable!(),
+            };
+            blocks.get_mut(ids[i]).unwrap().terminator = terminator;
+        }
+
+        (ControlFlowGraph::new(parent, ids[0], &blocks), blocks, ids)
+    }
+
+    #[test]
+    fn reachable_blocks_are_visited_depth_first() {
+        // 0 -> {1, 3}, 1 -> 2, 2 -> 0, 3 -> (return), 4 is unreachable
+        let (_, blocks, ids) = graph(&[&[1, 3], &[2], &[0], &[], &[3]]);
+
+        let got = reachable_blocks(ids[0], &blocks);
+
+        assert_eq!(got, vec![ids[0], ids[1], ids[2], ids[3]]);
+    }
+
+    #[test]
+    fn predecessors_and_successors() {
+        // a diamond
+        let (cfg, _, ids) = graph(&[&[1, 2], &[3], &[3], &[]]);
+
+        assert_eq!(cfg.successors(ids[0]), &[ids[1], ids[2]]);
+        assert_eq!(cfg.predecessors(ids[3]), &[ids[1], ids[2]]);
+        assert!(cfg.predecessors(ids[0]).is_empty());
+        assert_eq!(cfg.exits(), vec![ids[3]]);
+    }
+
+    #[test]
+    fn reverse_postorder_visits_blocks_before_their_successors() {
+        let (cfg, _, ids) = graph(&[&[1, 2], &[3], &[3], &[]]);
+
+        let rpo = cfg.reverse_postorder();
+
+        assert_eq!(rpo.len(), 4);
+        assert_eq!(rpo[0], ids[0]);
+        assert_eq!(rpo[3], ids[3]);
+    }
+
+    #[test]
+    fn detect_unreachable_blocks() {
+        let (cfg, _, ids) = graph(&[&[], &[2], &[]]);
+
+        assert_eq!(cfg.unreachable_blocks(), vec![ids[1], ids[2]]);
+    }
+
+    #[test]
+    fn export_to_dot() {
+        let (cfg, blocks, _) = graph(&[&[1, 2], &[0], &[]]);
+
+        let dot = cfg.to_dot("main", &blocks, &Container::default());
+
+        assert!(dot.starts_with("digraph \"main\" {\n"));
+        assert!(dot.contains("bb0 -> bb1 [label=\"true\"];"));
+        assert!(dot.contains("bb0 -> bb2 [label=\"false\"];"));
+        assert!(dot.contains("bb1 -> bb0;"));
+    }
+}
@@ -0,0 +1,258 @@
+use super::ControlFlowGraph;
+use crate::ecs::{Container, EntityId};
+use crate::hir::{BasicBlock, Instruction, Terminator};
+use std::collections::HashMap;
+
+/// Which way facts flow through the control flow graph.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    /// From the entry block towards the exits (e.g. definite assignment).
+    Forward,
+    /// From the exits back towards the entry block (e.g. liveness).
+    Backward,
+}
+
+/// Something inside a [`BasicBlock`] which may affect a dataflow fact.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Step<'a> {
+    Instruction(EntityId, &'a Instruction),
+    Terminator(&'a Terminator),
+}
+
+/// A dataflow problem, describing how facts are transformed by each step in
+/// a block and combined where control flow merges.
+pub trait Analysis {
+    type Fact: Clone + PartialEq;
+    const DIRECTION: Direction;
+
+    /// The fact at the boundary of the graph (the entry block for a forward
+    /// analysis, or the exits for a backward analysis).
+    fn boundary(&self) -> Self::Fact;
+    /// The value every other block starts with, typically the identity for
+    /// [`Analysis::join()`].
+    fn initial(&self) -> Self::Fact;
+    /// Merge the fact flowing in along another edge.
+    fn join(&self, fact: &mut Self::Fact, other: &Self::Fact);
+    /// Update a fact to account for the effects of a single step.
+    fn transfer(&self, step: Step<'_>, fact: &mut Self::Fact);
+}
+
+/// The facts which hold immediately before and after each reachable block,
+/// in program order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Solution<F> {
+    before: HashMap<EntityId, F>,
+    after: HashMap<EntityId, F>,
+}
+
+impl<F> Solution<F> {
+    /// The fact holding immediately before the block's first instruction.
+    pub fn before(&self, block: EntityId) -> Option<&F> {
+        self.before.get(&block)
+    }
+
+    /// The fact holding immediately after the block's terminator.
+    pub fn after(&self, block: EntityId) -> Option<&F> {
+        self.after.get(&block)
+    }
+}
+
+/// Iterate over the reachable blocks in a graph until the facts stop
+/// changing.
+pub fn solve<A: Analysis>(
+    analysis: &A,
+    cfg: &ControlFlowGraph,
+    blocks: &Container<BasicBlock>,
+    instructions: &Container<Instruction>,
+) -> Solution<A::Fact> {
+    let mut order = cfg.reverse_postorder();
+    if A::DIRECTION == Direction::Backward {
+        order.reverse();
+    }
+
+    // the facts flowing into and out of each block, in the analysis'
+    // direction
+    let mut inputs = HashMap::new();
+    let mut outputs: HashMap<EntityId, A::Fact> = order
+        .iter()
+        .map(|&block| (block, analysis.initial()))
+        .collect();
+
+    let mut changed = true;
+
+    while changed {
+        changed = false;
+
+        for &block in &order {
+            let (incoming, is_boundary) = match A::DIRECTION {
+                Direction::Forward => {
+                    (cfg.predecessors(block), block == cfg.entry())
+                }
+                Direction::Backward => {
+                    let successors = cfg.successors(block);
+                    (successors, successors.is_empty())
+                }
+            };
+
+            let mut input = if is_boundary {
+                analysis.boundary()
+            } else {
+                analysis.initial()
+            };
+            for other in incoming {
+                if let Some(fact) = outputs.get(other) {
+                    analysis.join(&mut input, fact);
+                }
+            }
+
+            let mut output = input.clone();
+            let bb = blocks.get(block).expect("all blocks in the graph exist");
+            walk(analysis, bb, instructions, &mut output, |_, _| {});
+
+            if outputs[&block] != output {
+                outputs.insert(block, output);
+                changed = true;
+            }
+            inputs.insert(block, input);
+        }
+    }
+
+    match A::DIRECTION {
+        Direction::Forward => Solution {
+            before: inputs,
+            after: outputs,
+        },
+        Direction::Backward => Solution {
+            before: outputs,
+            after: inputs,
+        },
+    }
+}
+
+/// Apply each step in a block to `fact` in the analysis' direction, calling
+/// `visit` with the fact holding just before each step is applied.
+pub fn walk<A, V>(
+    analysis: &A,
+    block: &BasicBlock,
+    instructions: &Container<Instruction>,
+    fact: &mut A::Fact,
+    mut visit: V,
+) where
+    A: Analysis,
+    V: FnMut(Step<'_>, &A::Fact),
+{
+    let mut steps: Vec<_> = block
+        .instructions
+        .iter()
+        .map(|&id| {
+            let instruction =
+                instructions.get(id).expect("all instructions exist");
+            Step::Instruction(id, instruction)
+        })
+        .chain(std::iter::once(Step::Terminator(&block.terminator)))
+        .collect();
+
+    if A::DIRECTION == Direction::Backward {
+        steps.reverse();
+    }
+
+    for step in steps {
+        visit(step, fact);
+        analysis.transfer(step, fact);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::cfg::tests::graph;
+    use std::collections::BTreeSet;
+
+    /// Which blocks may have been executed before reaching this point?
+    struct Visited;
+
+    impl Analysis for Visited {
+        type Fact = BTreeSet<usize>;
+        const DIRECTION: Direction = Direction::Forward;
+
+        fn boundary(&self) -> Self::Fact {
+            BTreeSet::new()
+        }
+
+        fn initial(&self) -> Self::Fact {
+            BTreeSet::new()
+        }
+
+        fn join(&self, fact: &mut Self::Fact, other: &Self::Fact) {
+            fact.extend(other);
+        }
+
+        fn transfer(&self, step: Step<'_>, fact: &mut Self::Fact) {
+            if let Step::Terminator(t) = step {
+                fact.insert(t.successors().len());
+            }
+        }
+    }
+
+    /// How many returns may follow this point?
+    struct Returns;
+
+    impl Analysis for Returns {
+        type Fact = usize;
+        const DIRECTION: Direction = Direction::Backward;
+
+        fn boundary(&self) -> usize {
+            0
+        }
+
+        fn initial(&self) -> usize {
+            0
+        }
+
+        fn join(&self, fact: &mut usize, other: &usize) {
+            *fact = std::cmp::max(*fact, *other);
+        }
+
+        fn transfer(&self, step: Step<'_>, fact: &mut usize) {
+            if let Step::Terminator(Terminator::Return) = step {
+                *fact += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn forward_facts_flow_around_loops() {
+        // 0 -> 1 <-> 2, 1 -> 3
+        let (cfg, blocks, ids) = graph(&[&[1], &[2, 3], &[1], &[]]);
+
+        let solution = solve(&Visited, &cfg, &blocks, &Container::default());
+
+        assert_eq!(solution.before(ids[0]), Some(&BTreeSet::new()));
+        // block 1 can be reached from the entry (a jump) or from block 2
+        // (also a jump), and it branches
+        assert_eq!(
+            solution.after(ids[1]),
+            Some(&vec![1, 2].into_iter().collect())
+        );
+    }
+
+    #[test]
+    fn backward_facts_start_at_the_exits() {
+        let (cfg, blocks, ids) = graph(&[&[1, 2], &[2], &[]]);
+
+        let solution = solve(&Returns, &cfg, &blocks, &Container::default());
+
+        assert_eq!(solution.after(ids[2]), Some(&0));
+        assert_eq!(solution.before(ids[2]), Some(&1));
+        assert_eq!(solution.before(ids[0]), Some(&1));
+    }
+
+    #[test]
+    fn unreachable_blocks_are_ignored() {
+        let (cfg, blocks, ids) = graph(&[&[], &[0]]);
+
+        let solution = solve(&Visited, &cfg, &blocks, &Container::default());
+
+        assert!(solution.before(ids[1]).is_none());
+    }
+}
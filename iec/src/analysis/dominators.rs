@@ -0,0 +1,251 @@
+use super::ControlFlowGraph;
+use crate::ecs::EntityId;
+use heapsize_derive::HeapSizeOf;
+use std::collections::{HashMap, HashSet};
+
+/// A tree where each block's parent is its immediate dominator.
+///
+/// Block `a` *dominates* block `b` when every path from the entry to `b` must
+/// go through `a`. Similarly, `a` *post-dominates* `b` when every path from
+/// `b` to an exit must go through `a`.
+#[derive(Debug, Clone, PartialEq, HeapSizeOf)]
+pub struct DominatorTree {
+    /// The immediate dominator of every block in the tree, with `None` for
+    /// the roots.
+    idom: HashMap<EntityId, Option<EntityId>>,
+}
+
+impl DominatorTree {
+    /// Calculate the dominators of every block reachable from the entry.
+    pub fn dominators(cfg: &ControlFlowGraph) -> DominatorTree {
+        DominatorTree {
+            idom: immediate_dominators(
+                &[cfg.entry()],
+                |b| cfg.successors(b),
+                |b| cfg.predecessors(b),
+            ),
+        }
+    }
+
+    /// Calculate the post-dominators of every block which can reach an exit.
+    ///
+    /// Items may return from several places, so each exit block is the root
+    /// of its own tree.
+    pub fn post_dominators(cfg: &ControlFlowGraph) -> DominatorTree {
+        DominatorTree {
+            idom: immediate_dominators(
+                &cfg.exits(),
+                |b| cfg.predecessors(b),
+                |b| cfg.successors(b),
+            ),
+        }
+    }
+
+    /// Was this block included when calculating the tree?
+    pub fn contains(&self, block: EntityId) -> bool {
+        self.idom.contains_key(&block)
+    }
+
+    /// The closest block which strictly dominates `block`, if there is one.
+    pub fn immediate_dominator(&self, block: EntityId) -> Option<EntityId> {
+        self.idom.get(&block).cloned().and_then(|idom| idom)
+    }
+
+    /// Does `a` dominate `b`? Every block dominates itself.
+    pub fn dominates(&self, a: EntityId, b: EntityId) -> bool {
+        if !self.contains(a) || !self.contains(b) {
+            return false;
+        }
+
+        let mut current = Some(b);
+
+        while let Some(block) = current {
+            if block == a {
+                return true;
+            }
+            current = self.immediate_dominator(block);
+        }
+
+        false
+    }
+
+    pub fn strictly_dominates(&self, a: EntityId, b: EntityId) -> bool {
+        a != b && self.dominates(a, b)
+    }
+
+    /// The blocks immediately dominated by `block`.
+    pub fn children(&self, block: EntityId) -> Vec<EntityId> {
+        let mut children: Vec<_> = self
+            .idom
+            .iter()
+            .filter(|(_, &idom)| idom == Some(block))
+            .map(|(&child, _)| child)
+            .collect();
+        children.sort();
+        children
+    }
+}
+
+/// The iterative algorithm from *"A Simple, Fast Dominance Algorithm"* by
+/// Cooper, Harvey, and Kennedy.
+///
+/// A virtual root is placed above `roots` so graphs with several entry points
+/// (e.g. the reversed graph used for post-dominators) can be handled.
+fn immediate_dominators<'g, S, P>(
+    roots: &[EntityId],
+    successors: S,
+    predecessors: P,
+) -> HashMap<EntityId, Option<EntityId>>
+where
+    S: Fn(EntityId) -> &'g [EntityId],
+    P: Fn(EntityId) -> &'g [EntityId],
+{
+    // number each block in postorder, with the virtual root last
+    let mut postorder = Vec::new();
+    let mut number = HashMap::new();
+    let mut visited = HashSet::new();
+
+    for &root in roots {
+        if !visited.insert(root) {
+            continue;
+        }
+        let mut stack = vec![(root, 0)];
+
+        while let Some(&mut (block, ref mut next)) = stack.last_mut() {
+            match successors(block).get(*next) {
+                Some(&succ) => {
+                    *next += 1;
+                    if visited.insert(succ) {
+                        stack.push((succ, 0));
+                    }
+                }
+                None => {
+                    number.insert(block, postorder.len());
+                    postorder.push(block);
+                    stack.pop();
+                }
+            }
+        }
+    }
+
+    let virtual_root = postorder.len();
+    let mut idom = vec![None; virtual_root + 1];
+    idom[virtual_root] = Some(virtual_root);
+
+    let intersect = |idom: &[Option<usize>], mut a: usize, mut b: usize| {
+        while a != b {
+            while a < b {
+                a = idom[a].expect("only processed blocks are intersected");
+            }
+            while b < a {
+                b = idom[b].expect("only processed blocks are intersected");
+            }
+        }
+        a
+    };
+
+    let mut changed = true;
+
+    while changed {
+        changed = false;
+
+        for (n, &block) in postorder.iter().enumerate().rev() {
+            let mut preds: Vec<usize> = predecessors(block)
+                .iter()
+                .filter_map(|pred| number.get(pred).cloned())
+                .collect();
+            if roots.contains(&block) {
+                preds.push(virtual_root);
+            }
+
+            let mut new_idom = None;
+
+            for pred in preds {
+                if idom[pred].is_none() {
+                    continue;
+                }
+                new_idom = Some(match new_idom {
+                    Some(current) => intersect(&idom, pred, current),
+                    None => pred,
+                });
+            }
+
+            if new_idom.is_some() && idom[n] != new_idom {
+                idom[n] = new_idom;
+                changed = true;
+            }
+        }
+    }
+
+    postorder
+        .iter()
+        .enumerate()
+        .map(|(n, &block)| {
+            let parent = idom[n].expect("every block has been processed");
+            let parent = if parent == virtual_root {
+                None
+            } else {
+                Some(postorder[parent])
+            };
+            (block, parent)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::cfg::tests::graph;
+
+    #[test]
+    fn dominators_of_a_diamond() {
+        let (cfg, _, ids) = graph(&[&[1, 2], &[3], &[3], &[]]);
+
+        let doms = DominatorTree::dominators(&cfg);
+
+        assert_eq!(doms.immediate_dominator(ids[0]), None);
+        assert_eq!(doms.immediate_dominator(ids[1]), Some(ids[0]));
+        assert_eq!(doms.immediate_dominator(ids[2]), Some(ids[0]));
+        assert_eq!(doms.immediate_dominator(ids[3]), Some(ids[0]));
+        assert!(doms.dominates(ids[0], ids[3]));
+        assert!(!doms.dominates(ids[1], ids[3]));
+        assert_eq!(doms.children(ids[0]), vec![ids[1], ids[2], ids[3]]);
+    }
+
+    #[test]
+    fn dominators_with_a_loop() {
+        // 0 -> 1 <-> 2, 1 -> 3
+        let (cfg, _, ids) = graph(&[&[1], &[2, 3], &[1], &[]]);
+
+        let doms = DominatorTree::dominators(&cfg);
+
+        assert_eq!(doms.immediate_dominator(ids[2]), Some(ids[1]));
+        assert_eq!(doms.immediate_dominator(ids[3]), Some(ids[1]));
+        assert!(doms.strictly_dominates(ids[1], ids[2]));
+        assert!(!doms.strictly_dominates(ids[1], ids[1]));
+    }
+
+    #[test]
+    fn unreachable_blocks_arent_dominated() {
+        let (cfg, _, ids) = graph(&[&[], &[0]]);
+
+        let doms = DominatorTree::dominators(&cfg);
+
+        assert!(!doms.contains(ids[1]));
+        assert!(!doms.dominates(ids[0], ids[1]));
+    }
+
+    #[test]
+    fn post_dominators_with_multiple_exits() {
+        // 0 branches to two exits, 1 and 2 both go to 3
+        let (cfg, _, ids) = graph(&[&[1, 4], &[3], &[3], &[], &[]]);
+
+        let post_doms = DominatorTree::post_dominators(&cfg);
+
+        assert_eq!(post_doms.immediate_dominator(ids[1]), Some(ids[3]));
+        assert_eq!(post_doms.immediate_dominator(ids[3]), None);
+        assert_eq!(post_doms.immediate_dominator(ids[4]), None);
+        // neither exit is guaranteed to be executed
+        assert_eq!(post_doms.immediate_dominator(ids[0]), None);
+    }
+}
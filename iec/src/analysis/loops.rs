@@ -0,0 +1,119 @@
+use super::{ControlFlowGraph, DominatorTree};
+use crate::ecs::EntityId;
+use heapsize_derive::HeapSizeOf;
+use std::collections::BTreeMap;
+
+/// A natural loop, the set of blocks which can reach a back edge (an edge
+/// to a block which dominates its source) without going through the loop's
+/// header.
+#[derive(Debug, Clone, PartialEq, HeapSizeOf)]
+pub struct Loop {
+    /// The only block control can enter the loop through.
+    pub header: EntityId,
+    /// The blocks which jump back to the header.
+    pub latches: Vec<EntityId>,
+    /// Every block in the loop, including the header.
+    pub body: Vec<EntityId>,
+}
+
+impl Loop {
+    pub fn contains(&self, block: EntityId) -> bool {
+        self.body.binary_search(&block).is_ok()
+    }
+}
+
+/// Find the natural loops in a graph.
+///
+/// Loops sharing a header are merged, so there is at most one [`Loop`] per
+/// header. Loops are sorted by header.
+pub fn natural_loops(
+    cfg: &ControlFlowGraph,
+    dominators: &DominatorTree,
+) -> Vec<Loop> {
+    let mut latches: BTreeMap<EntityId, Vec<EntityId>> = BTreeMap::new();
+
+    for &block in cfg.blocks() {
+        for &succ in cfg.successors(block) {
+            if dominators.dominates(succ, block) {
+                latches.entry(succ).or_default().push(block);
+            }
+        }
+    }
+
+    latches
+        .into_iter()
+        .map(|(header, latches)| {
+            let mut body = vec![header];
+            let mut pending = latches.clone();
+
+            while let Some(block) = pending.pop() {
+                if body.contains(&block) {
+                    continue;
+                }
+                body.push(block);
+                pending.extend(
+                    cfg.predecessors(block)
+                        .iter()
+                        .filter(|&&pred| dominators.contains(pred)),
+                );
+            }
+
+            body.sort();
+            Loop {
+                header,
+                latches,
+                body,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::cfg::tests::graph;
+
+    #[test]
+    fn find_a_simple_loop() {
+        // 0 -> 1 -> 2 -> 1, 1 -> 3
+        let (cfg, _, ids) = graph(&[&[1], &[2, 3], &[1], &[]]);
+        let doms = DominatorTree::dominators(&cfg);
+
+        let loops = natural_loops(&cfg, &doms);
+
+        assert_eq!(
+            loops,
+            vec![Loop {
+                header: ids[1],
+                latches: vec![ids[2]],
+                body: vec![ids[1], ids[2]],
+            }]
+        );
+    }
+
+    #[test]
+    fn nested_loops() {
+        // 0 -> 1 -> 2 -> 3 -> 2, 3 -> 4 -> 1, 1 -> 5
+        let (cfg, _, ids) = graph(&[&[1], &[2, 5], &[3], &[2, 4], &[1], &[]]);
+        let doms = DominatorTree::dominators(&cfg);
+
+        let loops = natural_loops(&cfg, &doms);
+
+        assert_eq!(loops.len(), 2);
+        let outer = &loops[0];
+        let inner = &loops[1];
+        assert_eq!(outer.header, ids[1]);
+        assert_eq!(outer.body, vec![ids[1], ids[2], ids[3], ids[4]]);
+        assert_eq!(inner.header, ids[2]);
+        assert_eq!(inner.body, vec![ids[2], ids[3]]);
+        assert!(!inner.contains(ids[4]));
+    }
+
+    #[test]
+    fn no_loops_in_straight_line_code() {
+        let (cfg, _, _) = graph(&[&[1], &[]]);
+        let doms = DominatorTree::dominators(&cfg);
+
+        assert!(natural_loops(&cfg, &doms).is_empty());
+    }
+}
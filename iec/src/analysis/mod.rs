@@ -0,0 +1,106 @@
+//! Reusable analyses for the control flow graph formed by an item's
+//! [`BasicBlock`]s.
+//!
+//! Problems like liveness or definite assignment can be expressed as an
+//! [`dataflow::Analysis`] and solved over the graph with
+//! [`dataflow::solve()`].
+//!
+//! Analyses are computed once by the
+//! [`ControlFlowAnalysis`](crate::passes::ControlFlowAnalysis) pass and stored
+//! in the [`ControlFlowCache`] singleton, so later passes can share them.
+
+mod cfg;
+pub mod dataflow;
+mod dominators;
+mod loops;
+pub mod ssa;
+
+pub use self::cfg::{reachable_blocks, ControlFlowGraph};
+pub use self::dominators::DominatorTree;
+pub use self::loops::{natural_loops, Loop};
+pub use self::ssa::{SsaCache, SsaForm};
+
+use crate::ecs::{Container, EntityId};
+use crate::hir::{BasicBlock, Symbol};
+use heapsize_derive::HeapSizeOf;
+use std::collections::HashMap;
+use typename::TypeName;
+
+/// Everything we know about the control flow within an item's body.
+#[derive(Debug, Clone, PartialEq, HeapSizeOf)]
+pub struct ControlFlow {
+    pub graph: ControlFlowGraph,
+    pub dominators: DominatorTree,
+    pub post_dominators: DominatorTree,
+    pub loops: Vec<Loop>,
+    /// Blocks which can never be executed.
+    pub unreachable: Vec<EntityId>,
+}
+
+impl ControlFlow {
+    /// Analyse the blocks belonging to `parent`.
+    pub fn analyse(
+        parent: Symbol,
+        entry: EntityId,
+        blocks: &Container<BasicBlock>,
+    ) -> ControlFlow {
+        let graph = ControlFlowGraph::new(parent, entry, blocks);
+        let dominators = DominatorTree::dominators(&graph);
+        let post_dominators = DominatorTree::post_dominators(&graph);
+        let loops = natural_loops(&graph, &dominators);
+        let unreachable = graph.unreachable_blocks();
+
+        ControlFlow {
+            graph,
+            dominators,
+            post_dominators,
+            loops,
+            unreachable,
+        }
+    }
+
+    /// The innermost loop containing a block, if any.
+    pub fn innermost_loop(&self, block: EntityId) -> Option<&Loop> {
+        self.loops
+            .iter()
+            .filter(|l| l.contains(block))
+            .min_by_key(|l| l.body.len())
+    }
+}
+
+/// The [`ControlFlow`] for each item.
+///
+/// Passes which modify an item's [`BasicBlock`]s are responsible for
+/// invalidating its entry.
+#[derive(Debug, Default, Clone, PartialEq, TypeName, HeapSizeOf)]
+pub struct ControlFlowCache {
+    items: HashMap<Symbol, ControlFlow>,
+}
+
+impl ControlFlowCache {
+    pub fn get(&self, item: Symbol) -> Option<&ControlFlow> {
+        self.items.get(&item)
+    }
+
+    pub fn insert(&mut self, item: Symbol, control_flow: ControlFlow) {
+        self.items.insert(item, control_flow);
+    }
+
+    /// Forget the analysis for an item, typically because its blocks were
+    /// changed.
+    pub fn invalidate(&mut self, item: Symbol) {
+        self.items.remove(&item);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Symbol, &ControlFlow)> + '_ {
+        self.items.iter().map(|(&item, cf)| (item, cf))
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+}
@@ -0,0 +1,640 @@
+//! *Static Single Assignment* form.
+//!
+//! Instead of rewriting the [`Instruction`]s themselves, the SSA form is kept
+//! alongside them. Every assignment to a variable creates a new *version* of
+//! it, and each read is annotated with the version it sees. Places where
+//! several versions merge (e.g. after an `IF` statement or at the top of a
+//! loop) get a [`Phi`].
+
+use super::ControlFlow;
+use crate::ecs::{Container, EntityId};
+use crate::hir::{BasicBlock, Instruction, Symbol, Variable, VariableKind};
+use heapsize_derive::HeapSizeOf;
+use std::collections::{BTreeSet, HashMap};
+use typename::TypeName;
+
+/// A particular version of a variable.
+///
+/// Version `0` is the value the variable had when the item started
+/// executing.
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, HeapSizeOf,
+)]
+pub struct SsaValue {
+    pub variable: EntityId,
+    pub version: u32,
+}
+
+impl SsaValue {
+    pub fn initial(variable: EntityId) -> SsaValue {
+        SsaValue {
+            variable,
+            version: 0,
+        }
+    }
+}
+
+/// Where an [`SsaValue`] comes from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, HeapSizeOf)]
+pub enum Definition {
+    /// The value a variable had when the item started executing.
+    Entry,
+    /// A [`Phi`] at the start of a block.
+    Phi(EntityId),
+    /// An [`Instruction`] which writes to the variable.
+    Instruction(EntityId),
+}
+
+/// Select a version of a variable depending on which predecessor control
+/// came from.
+#[derive(Debug, Clone, PartialEq, HeapSizeOf)]
+pub struct Phi {
+    pub dest: SsaValue,
+    /// The version flowing in from each predecessor.
+    pub args: Vec<(EntityId, SsaValue)>,
+}
+
+/// The SSA form of an item's body.
+#[derive(Debug, Clone, PartialEq, HeapSizeOf)]
+pub struct SsaForm {
+    phis: HashMap<EntityId, Vec<Phi>>,
+    definitions: HashMap<SsaValue, Definition>,
+    /// The versions of each variable after a block's phis are evaluated.
+    entry_versions: HashMap<EntityId, Vec<SsaValue>>,
+    /// The versions read by each instruction.
+    uses: HashMap<EntityId, Vec<SsaValue>>,
+    /// The versions created by each instruction.
+    defs: HashMap<EntityId, Vec<SsaValue>>,
+    /// The versions read by each block's terminator.
+    terminator_uses: HashMap<EntityId, SsaValue>,
+}
+
+impl SsaForm {
+    /// Construct the SSA form for the reachable blocks in an item.
+    ///
+    /// Only variables which are `tracked` are given versions. Anything else
+    /// (e.g. globals, which may be changed by a call) is ignored.
+    pub fn construct<F>(
+        control_flow: &ControlFlow,
+        blocks: &Container<BasicBlock>,
+        instructions: &Container<Instruction>,
+        tracked: F,
+    ) -> SsaForm
+    where
+        F: Fn(EntityId) -> bool,
+    {
+        let graph = &control_flow.graph;
+        let reachable = graph.reverse_postorder();
+        let block = |id| blocks.get(id).expect("all blocks exist");
+        let instruction =
+            |id| instructions.get(id).expect("all instructions exist");
+
+        // find where each variable is assigned to
+        let mut assigned_in: HashMap<EntityId, BTreeSet<EntityId>> =
+            HashMap::new();
+
+        for &id in &reachable {
+            for &inst in &block(id).instructions {
+                for var in instruction(inst).writes() {
+                    if tracked(var) {
+                        assigned_in.entry(var).or_default().insert(id);
+                    }
+                }
+            }
+        }
+
+        // then place phis on the dominance frontier of each assignment
+        let frontiers = dominance_frontiers(control_flow, &reachable);
+        let mut phi_variables: HashMap<EntityId, BTreeSet<EntityId>> =
+            HashMap::new();
+
+        for (&var, defined_in) in &assigned_in {
+            let mut pending: Vec<_> = defined_in.iter().cloned().collect();
+            let mut has_phi = BTreeSet::new();
+
+            while let Some(b) = pending.pop() {
+                for &frontier in frontiers.get(&b).into_iter().flatten() {
+                    if has_phi.insert(frontier) {
+                        phi_variables.entry(frontier).or_default().insert(var);
+                        if !defined_in.contains(&frontier) {
+                            pending.push(frontier);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut variables: Vec<_> = assigned_in.keys().cloned().collect();
+        variables.sort();
+
+        let mut renamer = Renamer {
+            control_flow,
+            blocks,
+            instructions,
+            tracked: &tracked,
+            variables,
+            stacks: HashMap::new(),
+            counters: HashMap::new(),
+            ssa: SsaForm {
+                phis: phi_variables
+                    .into_iter()
+                    .map(|(b, vars)| {
+                        let phis = vars
+                            .into_iter()
+                            .map(|var| Phi {
+                                dest: SsaValue::initial(var),
+                                args: Vec::new(),
+                            })
+                            .collect();
+                        (b, phis)
+                    })
+                    .collect(),
+                definitions: HashMap::new(),
+                entry_versions: HashMap::new(),
+                uses: HashMap::new(),
+                defs: HashMap::new(),
+                terminator_uses: HashMap::new(),
+            },
+        };
+
+        renamer.rename(graph.entry());
+        renamer.ssa
+    }
+
+    /// The phis evaluated when entering a block.
+    pub fn phis(&self, block: EntityId) -> &[Phi] {
+        self.phis.get(&block).map(|p| p.as_slice()).unwrap_or(&[])
+    }
+
+    /// Where did this value come from?
+    pub fn definition(&self, value: SsaValue) -> Definition {
+        self.definitions
+            .get(&value)
+            .cloned()
+            .unwrap_or(Definition::Entry)
+    }
+
+    /// The version of a variable read by an instruction.
+    pub fn use_of(
+        &self,
+        instruction: EntityId,
+        variable: EntityId,
+    ) -> Option<SsaValue> {
+        find(self.uses.get(&instruction), variable)
+    }
+
+    /// The versions an instruction reads.
+    pub fn uses(&self, instruction: EntityId) -> &[SsaValue] {
+        self.uses
+            .get(&instruction)
+            .map(|u| u.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// The new versions created by an instruction.
+    pub fn defs(&self, instruction: EntityId) -> &[SsaValue] {
+        self.defs
+            .get(&instruction)
+            .map(|d| d.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// The version read by a block's terminator, if it reads anything.
+    pub fn terminator_use(&self, block: EntityId) -> Option<SsaValue> {
+        self.terminator_uses.get(&block).cloned()
+    }
+
+    /// The version of a variable which is visible immediately before an
+    /// instruction in `block` is executed.
+    pub fn version_before(
+        &self,
+        block: EntityId,
+        bb: &BasicBlock,
+        instruction: EntityId,
+        variable: EntityId,
+    ) -> SsaValue {
+        let preceding =
+            bb.instructions.iter().take_while(|&&i| i != instruction);
+        self.latest_version(block, preceding, variable)
+    }
+
+    /// The version of a variable which is visible when `block`'s terminator
+    /// is executed.
+    pub fn version_after(
+        &self,
+        block: EntityId,
+        bb: &BasicBlock,
+        variable: EntityId,
+    ) -> SsaValue {
+        self.latest_version(block, bb.instructions.iter(), variable)
+    }
+
+    fn latest_version<'i, I>(
+        &self,
+        block: EntityId,
+        instructions: I,
+        variable: EntityId,
+    ) -> SsaValue
+    where
+        I: Iterator<Item = &'i EntityId>,
+    {
+        let mut current = find(self.entry_versions.get(&block), variable)
+            .unwrap_or_else(|| SsaValue::initial(variable));
+
+        for inst in instructions {
+            if let Some(def) = find(self.defs.get(inst), variable) {
+                current = def;
+            }
+        }
+
+        current
+    }
+
+    /// Every version which is read by an instruction, terminator, or phi.
+    pub fn used_values(&self) -> BTreeSet<SsaValue> {
+        self.uses
+            .values()
+            .flatten()
+            .cloned()
+            .chain(self.terminator_uses.values().cloned())
+            .chain(
+                self.phis
+                    .values()
+                    .flatten()
+                    .flat_map(|phi| phi.args.iter().map(|&(_, arg)| arg)),
+            )
+            .collect()
+    }
+
+    /// How many versions were created (not counting the initial ones)?
+    pub fn value_count(&self) -> usize {
+        self.definitions.len()
+    }
+}
+
+fn find(
+    values: Option<&Vec<SsaValue>>,
+    variable: EntityId,
+) -> Option<SsaValue> {
+    values
+        .into_iter()
+        .flatten()
+        .find(|v| v.variable == variable)
+        .cloned()
+}
+
+/// Should this variable be given versions? Globals may be modified by any
+/// call and a `VAR_IN_OUT` may alias another variable, so their values can
+/// change without an assignment we can see.
+pub fn is_ssa_candidate(variable: &Variable) -> bool {
+    !matches!(variable.kind, VariableKind::Global | VariableKind::InOut)
+}
+
+/// The dominance frontier of each block, using the algorithm from *"A
+/// Simple, Fast Dominance Algorithm"*.
+fn dominance_frontiers(
+    control_flow: &ControlFlow,
+    reachable: &[EntityId],
+) -> HashMap<EntityId, BTreeSet<EntityId>> {
+    let graph = &control_flow.graph;
+    let doms = &control_flow.dominators;
+    let mut frontiers: HashMap<EntityId, BTreeSet<EntityId>> = HashMap::new();
+
+    for &b in reachable {
+        let preds: Vec<_> = graph
+            .predecessors(b)
+            .iter()
+            .cloned()
+            .filter(|&p| doms.contains(p))
+            .collect();
+        if preds.len() < 2 {
+            continue;
+        }
+
+        let idom = doms.immediate_dominator(b);
+
+        for pred in preds {
+            let mut runner = Some(pred);
+
+            while let Some(r) = runner {
+                if Some(r) == idom {
+                    break;
+                }
+                frontiers.entry(r).or_default().insert(b);
+                runner = doms.immediate_dominator(r);
+            }
+        }
+    }
+
+    frontiers
+}
+
+struct Renamer<'a, F> {
+    control_flow: &'a ControlFlow,
+    blocks: &'a Container<BasicBlock>,
+    instructions: &'a Container<Instruction>,
+    tracked: &'a F,
+    /// Every variable which is assigned to.
+    variables: Vec<EntityId>,
+    stacks: HashMap<EntityId, Vec<SsaValue>>,
+    counters: HashMap<EntityId, u32>,
+    ssa: SsaForm,
+}
+
+impl<'a, F: Fn(EntityId) -> bool> Renamer<'a, F> {
+    fn current(&self, variable: EntityId) -> SsaValue {
+        self.stacks
+            .get(&variable)
+            .and_then(|s| s.last())
+            .cloned()
+            .unwrap_or_else(|| SsaValue::initial(variable))
+    }
+
+    fn new_version(
+        &mut self,
+        variable: EntityId,
+        definition: Definition,
+    ) -> SsaValue {
+        let counter = self.counters.entry(variable).or_insert(0);
+        *counter += 1;
+        let value = SsaValue {
+            variable,
+            version: *counter,
+        };
+
+        self.stacks.entry(variable).or_default().push(value);
+        self.ssa.definitions.insert(value, definition);
+        value
+    }
+
+    /// Walk the dominator tree, giving each assignment a new version and
+    /// recording which version each read sees.
+    fn rename(&mut self, block: EntityId) {
+        let mut pushed = Vec::new();
+
+        let phi_count = self.ssa.phis(block).len();
+        for i in 0..phi_count {
+            let variable = self.ssa.phis[&block][i].dest.variable;
+            let value = self.new_version(variable, Definition::Phi(block));
+            self.ssa.phis.get_mut(&block).unwrap()[i].dest = value;
+            pushed.push(variable);
+        }
+
+        let entry_versions =
+            self.variables.iter().map(|&v| self.current(v)).collect();
+        self.ssa.entry_versions.insert(block, entry_versions);
+
+        let bb = self.blocks.get(block).expect("all blocks exist");
+
+        for &id in &bb.instructions {
+            let instruction =
+                self.instructions.get(id).expect("all instructions exist");
+
+            let uses: Vec<_> = instruction
+                .reads()
+                .into_iter()
+                .filter(|&v| (self.tracked)(v))
+                .map(|v| self.current(v))
+                .collect();
+            if !uses.is_empty() {
+                self.ssa.uses.insert(id, uses);
+            }
+
+            let mut defs = Vec::new();
+            for variable in instruction.writes() {
+                if (self.tracked)(variable) {
+                    defs.push(
+                        self.new_version(variable, Definition::Instruction(id)),
+                    );
+                    pushed.push(variable);
+                }
+            }
+            if !defs.is_empty() {
+                self.ssa.defs.insert(id, defs);
+            }
+        }
+
+        if let Some(variable) = bb.terminator.reads() {
+            if (self.tracked)(variable) {
+                let value = self.current(variable);
+                self.ssa.terminator_uses.insert(block, value);
+            }
+        }
+
+        for &succ in self.control_flow.graph.successors(block) {
+            let args: Vec<_> = self
+                .ssa
+                .phis(succ)
+                .iter()
+                .map(|phi| self.current(phi.dest.variable))
+                .collect();
+
+            if let Some(phis) = self.ssa.phis.get_mut(&succ) {
+                for (phi, arg) in phis.iter_mut().zip(args) {
+                    phi.args.push((block, arg));
+                }
+            }
+        }
+
+        for child in self.control_flow.dominators.children(block) {
+            self.rename(child);
+        }
+
+        for variable in pushed {
+            self.stacks.get_mut(&variable).and_then(|s| s.pop());
+        }
+    }
+}
+
+/// The [`SsaForm`] of each item, which is only constructed when optimising.
+///
+/// Like the [`ControlFlowCache`](super::ControlFlowCache), passes which
+/// modify an item's [`Instruction`]s are responsible for invalidating its
+/// entry.
+#[derive(Debug, Default, Clone, PartialEq, TypeName, HeapSizeOf)]
+pub struct SsaCache {
+    items: HashMap<Symbol, SsaForm>,
+}
+
+impl SsaCache {
+    pub fn get(&self, item: Symbol) -> Option<&SsaForm> {
+        self.items.get(&item)
+    }
+
+    pub fn insert(&mut self, item: Symbol, ssa: SsaForm) {
+        self.items.insert(item, ssa);
+    }
+
+    pub fn invalidate(&mut self, item: Symbol) {
+        self.items.remove(&item);
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::ControlFlowCache;
+    use crate::hir::{CompilationUnit, Operand};
+    use crate::passes::PassContext;
+    use crate::Diagnostics;
+
+    fn analyse(src: &str) -> CompilationUnit {
+        let ast: iec_syntax::File = src.parse().unwrap();
+        let mut diags = Diagnostics::new();
+        let cu =
+            crate::process(&ast, &mut PassContext::new_nop_logger(&mut diags));
+        assert!(!diags.has_errors(), "{:?}", diags);
+
+        cu
+    }
+
+    fn ssa_form(cu: &CompilationUnit) -> SsaForm {
+        let cache = cu.resources.get_singleton::<ControlFlowCache>();
+        let (_, control_flow) = cache.iter().next().unwrap();
+        let variables = cu.resources.get::<Variable>();
+
+        SsaForm::construct(
+            control_flow,
+            &cu.resources.get::<BasicBlock>(),
+            &cu.resources.get::<Instruction>(),
+            |v| variables.get(v).map(is_ssa_candidate).unwrap_or(false),
+        )
+    }
+
+    fn variable(cu: &CompilationUnit, name: &str) -> EntityId {
+        let variables = cu.resources.get::<Variable>();
+        let id = variables
+            .iter()
+            .find(|(_, v)| v.name.as_deref() == Some(name))
+            .map(|(id, _)| id)
+            .unwrap();
+        id
+    }
+
+    /// Find the instruction which copies one variable into another.
+    fn copy(cu: &CompilationUnit, dest: EntityId, src: EntityId) -> EntityId {
+        let instructions = cu.resources.get::<Instruction>();
+        let id = instructions
+            .iter()
+            .find(|(_, i)| {
+                **i == Instruction::Load {
+                    dest,
+                    src: Operand::Variable(src),
+                }
+            })
+            .map(|(id, _)| id)
+            .unwrap();
+        id
+    }
+
+    #[test]
+    fn phis_are_placed_where_assignments_merge() {
+        let src = "
+            PROGRAM main
+                VAR
+                    x: int;
+                    y: int;
+                END_VAR
+
+                IF y > 0 THEN
+                    x := 1;
+                END_IF;
+                y := x;
+            END_PROGRAM";
+        let cu = analyse(src);
+        let x = variable(&cu, "x");
+        let y = variable(&cu, "y");
+        let read = copy(&cu, y, x);
+
+        let ssa = ssa_form(&cu);
+
+        let version = ssa.use_of(read, x).unwrap();
+        let block = match ssa.definition(version) {
+            Definition::Phi(block) => block,
+            other => panic!("Expected a phi, found {:?}", other),
+        };
+        let phi = &ssa.phis(block)[0];
+        assert_eq!(phi.dest, version);
+        assert_eq!(phi.args.len(), 2);
+        // one arm comes straight from the entry, the other from the
+        // assignment
+        assert!(phi.args.iter().any(|&(_, arg)| arg == SsaValue::initial(x)));
+        assert!(phi.args.iter().any(|&(_, arg)| {
+            matches!(ssa.definition(arg), Definition::Instruction(_))
+        }));
+    }
+
+    #[test]
+    fn loops_need_a_phi_in_the_header() {
+        let src = "
+            PROGRAM main
+                VAR
+                    i: int;
+                END_VAR
+
+                WHILE i < 10 DO
+                    i := i + 1;
+                END_WHILE;
+            END_PROGRAM";
+        let cu = analyse(src);
+        let i = variable(&cu, "i");
+        let cache = cu.resources.get_singleton::<ControlFlowCache>();
+        let (_, control_flow) = cache.iter().next().unwrap();
+        let header = control_flow.loops[0].header;
+
+        let ssa = ssa_form(&cu);
+
+        let phis = ssa.phis(header);
+        assert!(phis.iter().any(|phi| phi.dest.variable == i));
+    }
+
+    #[test]
+    fn versions_before_an_instruction() {
+        let src = "
+            PROGRAM main
+                VAR
+                    x: int;
+                    y: int;
+                END_VAR
+
+                x := y;
+                y := x;
+                x := 1;
+            END_PROGRAM";
+        let cu = analyse(src);
+        let x = variable(&cu, "x");
+        let y = variable(&cu, "y");
+        let first = copy(&cu, x, y);
+        let second = copy(&cu, y, x);
+        let blocks = cu.resources.get::<BasicBlock>();
+        let (block, bb) = blocks
+            .iter()
+            .find(|(_, bb)| bb.instructions.contains(&first))
+            .unwrap();
+
+        let ssa = ssa_form(&cu);
+
+        assert_eq!(
+            ssa.version_before(block, bb, first, x),
+            SsaValue::initial(x)
+        );
+        assert_eq!(
+            ssa.version_before(block, bb, second, x),
+            ssa.defs(first)[0]
+        );
+        assert_eq!(ssa.use_of(second, x), Some(ssa.defs(first)[0]));
+        assert_eq!(
+            ssa.definition(ssa.defs(first)[0]),
+            Definition::Instruction(first)
+        );
+        assert!(ssa.phis(block).is_empty());
+    }
+}
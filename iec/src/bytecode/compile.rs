@@ -0,0 +1,529 @@
+use super::{Module, Op, Pou, PouKind, Slot, SlotType};
+use crate::const_eval::IntegerType;
+use crate::ecs::{Container, EntityId};
+use crate::hir::{
+    Argument, BasicBlock, CompilationUnit, Constant, Function, FunctionBlock,
+    GlobalVariables, Instruction, Operand, Program, Span, Symbol, Terminator,
+    Type, Variable,
+};
+use codespan::ByteSpan;
+use std::collections::HashMap;
+
+/// Lower a [`CompilationUnit`] into bytecode.
+///
+/// The [`CompilationUnit`] must have been analysed without any errors.
+pub fn compile(unit: &CompilationUnit) -> Module {
+    let resources = &unit.resources;
+    let tables = Tables {
+        programs: &resources.get(),
+        functions: &resources.get(),
+        function_blocks: &resources.get(),
+        globals: &resources.get(),
+        types: &resources.get(),
+        variables: &resources.get(),
+        blocks: &resources.get(),
+        instructions: &resources.get(),
+        constants: &resources.get(),
+        spans: &resources.get(),
+    };
+
+    tables.compile()
+}
+
+/// Every component needed while lowering.
+struct Tables<'a> {
+    programs: &'a Container<Program>,
+    functions: &'a Container<Function>,
+    function_blocks: &'a Container<FunctionBlock>,
+    globals: &'a Container<GlobalVariables>,
+    types: &'a Container<Type>,
+    variables: &'a Container<Variable>,
+    blocks: &'a Container<BasicBlock>,
+    instructions: &'a Container<Instruction>,
+    constants: &'a Container<Constant>,
+    spans: &'a Container<Span>,
+}
+
+/// A program, function block, or function which gets turned into a [`Pou`].
+struct Item<'a> {
+    symbol: Symbol,
+    kind: PouKind,
+    name: &'a str,
+    variables: &'a [EntityId],
+    /// A function's result variable and its type.
+    return_value: Option<(EntityId, EntityId)>,
+    entry_block: EntityId,
+}
+
+/// Where each variable lives.
+#[derive(Default)]
+struct Layout {
+    pou_indices: HashMap<Symbol, u16>,
+    /// The slot each variable occupies in its parent's frame.
+    slots: HashMap<EntityId, u16>,
+    globals: HashMap<EntityId, u16>,
+    /// The slot each function's result is stored in.
+    return_slots: HashMap<u16, u16>,
+}
+
+impl<'a> Tables<'a> {
+    fn compile(&self) -> Module {
+        let items = self.items();
+        let mut layout = Layout::default();
+
+        for (index, item) in items.iter().enumerate() {
+            layout.pou_indices.insert(item.symbol, index as u16);
+        }
+
+        let mut globals: Vec<EntityId> = self
+            .globals
+            .iter()
+            .flat_map(|(_, g)| g.variables.iter().cloned())
+            .collect();
+        globals.sort();
+        let globals = globals
+            .into_iter()
+            .enumerate()
+            .map(|(index, id)| {
+                layout.globals.insert(id, index as u16);
+                self.slot(id, &layout)
+            })
+            .collect();
+
+        let mut pous: Vec<Pou> = items
+            .iter()
+            .map(|item| self.declare(item, &mut layout))
+            .collect();
+
+        let mut constants = Vec::new();
+
+        for (item, pou) in items.iter().zip(pous.iter_mut()) {
+            if item.kind == PouKind::Builtin {
+                continue;
+            }
+
+            let mut lowering = Lowering {
+                tables: self,
+                layout: &layout,
+                constants: &mut constants,
+                code: Vec::new(),
+                spans: Vec::new(),
+                block_offsets: HashMap::new(),
+                fixups: Vec::new(),
+            };
+            lowering.body(item.entry_block);
+
+            pou.code = lowering.code;
+            pou.spans = lowering.spans;
+        }
+
+        Module {
+            constants,
+            globals,
+            pous,
+        }
+    }
+
+    /// Every item which gets turned into a [`Pou`], in a deterministic
+    /// order.
+    fn items(&self) -> Vec<Item<'a>> {
+        let mut programs: Vec<_> = self
+            .programs
+            .iter()
+            .map(|(id, p)| Item {
+                symbol: Symbol::Program(id),
+                kind: PouKind::Program,
+                name: &p.name,
+                variables: &p.variables,
+                return_value: None,
+                entry_block: p.entry_block,
+            })
+            .collect();
+        let mut function_blocks: Vec<_> = self
+            .function_blocks
+            .iter()
+            .map(|(id, fb)| Item {
+                symbol: Symbol::FunctionBlock(id),
+                kind: PouKind::FunctionBlock,
+                name: &fb.name,
+                variables: &fb.variables,
+                return_value: None,
+                entry_block: fb.entry_block,
+            })
+            .collect();
+        let mut functions: Vec<_> = self
+            .functions
+            .iter()
+            .map(|(id, f)| Item {
+                symbol: Symbol::Function(id),
+                // builtins don't have a body
+                kind: if f.entry_block.is_placeholder() {
+                    PouKind::Builtin
+                } else {
+                    PouKind::Function
+                },
+                name: &f.name,
+                variables: &f.variables,
+                return_value: Some((f.return_value, f.return_type)),
+                entry_block: f.entry_block,
+            })
+            .collect();
+        programs.sort_by_key(|item| EntityId::from(item.symbol));
+        function_blocks.sort_by_key(|item| EntityId::from(item.symbol));
+        functions.sort_by_key(|item| EntityId::from(item.symbol));
+
+        programs
+            .into_iter()
+            .chain(function_blocks)
+            .chain(functions)
+            .collect()
+    }
+
+    /// Create the [`Pou`] for an item and work out where its variables go.
+    fn declare(&self, item: &Item<'a>, layout: &mut Layout) -> Pou {
+        let Item {
+            symbol,
+            kind,
+            name,
+            variables: declared,
+            return_value,
+            ..
+        } = *item;
+
+        // declared variables come first, followed by any temporaries
+        let mut temporaries: Vec<EntityId> = self
+            .variables
+            .iter()
+            .filter(|(id, v)| v.parent == symbol && !declared.contains(id))
+            .map(|(id, _)| id)
+            .collect();
+        temporaries.sort();
+
+        let mut slots = Vec::new();
+
+        for id in declared.iter().cloned().chain(temporaries) {
+            layout.slots.insert(id, slots.len() as u16);
+            slots.push(self.slot(id, layout));
+        }
+
+        let return_slot = match return_value {
+            Some((id, _)) if !id.is_placeholder() => Some(layout.slots[&id]),
+            // builtins don't have a variable for their result, so give them
+            // an anonymous slot
+            Some((_, ty)) => {
+                slots.push(Slot {
+                    name: None,
+                    ty: self.slot_type(ty, layout),
+                });
+                Some(slots.len() as u16 - 1)
+            }
+            None => None,
+        };
+
+        if let Some(slot) = return_slot {
+            layout
+                .return_slots
+                .insert(layout.pou_indices[&symbol], slot);
+        }
+
+        Pou {
+            name: name.to_string(),
+            kind,
+            slots,
+            return_slot,
+            code: Vec::new(),
+            spans: Vec::new(),
+        }
+    }
+
+    fn slot(&self, variable: EntityId, layout: &Layout) -> Slot {
+        let var = self
+            .variables
+            .get(variable)
+            .expect("every variable is registered");
+
+        Slot {
+            name: var.name.clone(),
+            ty: self.slot_type(var.ty, layout),
+        }
+    }
+
+    fn slot_type(&self, ty: EntityId, layout: &Layout) -> SlotType {
+        if let Some(&index) = layout.pou_indices.get(&Symbol::FunctionBlock(ty))
+        {
+            return SlotType::Instance(index);
+        }
+
+        let name = self
+            .types
+            .get(ty)
+            .map(|t| t.name.to_lowercase())
+            .unwrap_or_default();
+
+        if let Some(ty) = IntegerType::from_name(&name) {
+            return SlotType::Integer(ty);
+        }
+
+        match name.as_str() {
+            "bool" => SlotType::Bool,
+            "real" | "lreal" => SlotType::Real,
+            "string" | "char" => SlotType::String,
+            // TIME and DATE are stored as a number of milliseconds
+            _ => SlotType::Integer(IntegerType::LInt),
+        }
+    }
+}
+
+/// Generates the code for a single item's body.
+struct Lowering<'t, 'a> {
+    tables: &'t Tables<'a>,
+    layout: &'t Layout,
+    constants: &'t mut Vec<Constant>,
+    code: Vec<Op>,
+    spans: Vec<(u32, ByteSpan)>,
+    block_offsets: HashMap<EntityId, u32>,
+    /// Jumps which need to be pointed at a block once we know where it
+    /// starts.
+    fixups: Vec<(usize, EntityId)>,
+}
+
+impl<'t, 'a> Lowering<'t, 'a> {
+    fn body(&mut self, entry: EntityId) {
+        let order = self.block_order(entry);
+
+        for (i, &block) in order.iter().enumerate() {
+            let next = order.get(i + 1).cloned();
+            self.block_offsets.insert(block, self.code.len() as u32);
+            self.block(block, next);
+        }
+
+        for (offset, block) in self.fixups.drain(..) {
+            let target = self.block_offsets[&block];
+            match self.code[offset] {
+                Op::Jump(ref mut t) | Op::JumpUnless(ref mut t) => *t = target,
+                _ => unreachable!("only jumps are fixed up"),
+            }
+        }
+    }
+
+    /// Order the reachable blocks so the `then` arm of a branch directly
+    /// follows it, letting us fall through instead of jumping.
+    fn block_order(&self, entry: EntityId) -> Vec<EntityId> {
+        let mut order = Vec::new();
+        let mut to_visit = vec![entry];
+
+        while let Some(block) = to_visit.pop() {
+            if order.contains(&block) {
+                continue;
+            }
+            order.push(block);
+
+            let successors = self.block_ref(block).terminator.successors();
+            to_visit.extend(successors.into_iter().rev());
+        }
+
+        order
+    }
+
+    fn block(&mut self, id: EntityId, next: Option<EntityId>) {
+        let block = self.block_ref(id);
+
+        for &instruction in &block.instructions {
+            if let Some(&Span(span)) = self.tables.spans.get(instruction) {
+                if self.spans.last().map(|&(_, s)| s) != Some(span) {
+                    self.spans.push((self.code.len() as u32, span));
+                }
+            }
+            let instruction = self
+                .tables
+                .instructions
+                .get(instruction)
+                .expect("blocks only contain known instructions");
+            self.instruction(instruction);
+        }
+
+        match block.terminator {
+            Terminator::Jump(target) => self.jump(target, next),
+            Terminator::Branch {
+                condition,
+                then,
+                otherwise,
+            } => {
+                self.push(condition);
+                self.fixups.push((self.code.len(), otherwise));
+                self.code.push(Op::JumpUnless(0));
+                self.jump(then, next);
+            }
+            Terminator::Return => self.code.push(Op::Return),
+        }
+    }
+
+    fn jump(&mut self, target: EntityId, next: Option<EntityId>) {
+        if next != Some(target) {
+            self.fixups.push((self.code.len(), target));
+            self.code.push(Op::Jump(0));
+        }
+    }
+
+    fn instruction(&mut self, instruction: &Instruction) {
+        match *instruction {
+            Instruction::Load { dest, src } => {
+                self.push(src);
+                self.store(dest);
+            }
+            Instruction::LoadMember {
+                dest,
+                object,
+                member,
+            } => {
+                self.load(object);
+                self.code.push(Op::GetMember(self.layout.slots[&member]));
+                self.store(dest);
+            }
+            Instruction::StoreMember {
+                object,
+                member,
+                value,
+            } => {
+                self.load(object);
+                self.push(value);
+                self.code.push(Op::SetMember(self.layout.slots[&member]));
+                self.store(object);
+            }
+            Instruction::Binary {
+                dest,
+                op,
+                left,
+                right,
+            } => {
+                self.push(left);
+                self.push(right);
+                self.code.push(Op::Binary(op));
+                self.store(dest);
+            }
+            Instruction::Unary { dest, op, value } => {
+                self.push(value);
+                self.code.push(Op::Unary(op));
+                self.store(dest);
+            }
+            Instruction::Call {
+                target,
+                instance,
+                ref args,
+                dest,
+            } => self.call(target, instance, args, dest),
+        }
+    }
+
+    fn call(
+        &mut self,
+        target: Symbol,
+        instance: Option<EntityId>,
+        args: &[Argument],
+        dest: Option<EntityId>,
+    ) {
+        let pou = self.layout.pou_indices[&target];
+
+        match instance {
+            Some(instance) => self.load(instance),
+            None => self.code.push(Op::NewFrame(pou)),
+        }
+
+        for arg in args {
+            match *arg {
+                Argument::Input { parameter, value } => {
+                    self.push(value);
+                    self.code
+                        .push(Op::SetMember(self.layout.slots[&parameter]));
+                }
+                Argument::InOut {
+                    parameter,
+                    variable,
+                } => {
+                    self.load(variable);
+                    self.code
+                        .push(Op::SetMember(self.layout.slots[&parameter]));
+                }
+                Argument::Output { .. } => {}
+            }
+        }
+
+        self.code.push(Op::Call(pou));
+
+        // copy outputs (and anything passed by reference) back out
+        for arg in args {
+            match *arg {
+                Argument::InOut {
+                    parameter,
+                    variable,
+                }
+                | Argument::Output {
+                    parameter,
+                    variable,
+                } => {
+                    self.code.push(Op::Dup);
+                    self.code
+                        .push(Op::GetMember(self.layout.slots[&parameter]));
+                    self.store(variable);
+                }
+                Argument::Input { .. } => {}
+            }
+        }
+
+        match (instance, dest) {
+            // function block instances are stored by value
+            (Some(instance), _) => self.store(instance),
+            (None, Some(dest)) => {
+                self.code
+                    .push(Op::GetMember(self.layout.return_slots[&pou]));
+                self.store(dest);
+            }
+            (None, None) => self.code.push(Op::Pop),
+        }
+    }
+
+    fn block_ref(&self, id: EntityId) -> &'a BasicBlock {
+        self.tables
+            .blocks
+            .get(id)
+            .expect("terminators only refer to known blocks")
+    }
+
+    fn push(&mut self, operand: Operand) {
+        match operand {
+            Operand::Variable(id) => self.load(id),
+            Operand::Constant(id) => {
+                let constant = self
+                    .tables
+                    .constants
+                    .get(id)
+                    .expect("operands only refer to known constants");
+                let index =
+                    match self.constants.iter().position(|c| c == constant) {
+                        Some(index) => index,
+                        None => {
+                            self.constants.push(constant.clone());
+                            self.constants.len() - 1
+                        }
+                    };
+                self.code.push(Op::Const(index as u32));
+            }
+        }
+    }
+
+    fn load(&mut self, variable: EntityId) {
+        match self.layout.globals.get(&variable) {
+            Some(&slot) => self.code.push(Op::LoadGlobal(slot)),
+            None => self.code.push(Op::LoadLocal(self.layout.slots[&variable])),
+        }
+    }
+
+    fn store(&mut self, variable: EntityId) {
+        match self.layout.globals.get(&variable) {
+            Some(&slot) => self.code.push(Op::StoreGlobal(slot)),
+            None => {
+                self.code.push(Op::StoreLocal(self.layout.slots[&variable]))
+            }
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use super::{Module, Op, Pou, PouKind, Slot};
+use std::fmt::Write;
+
+/// Generate a human-readable listing of a [`Module`].
+pub fn disassemble(module: &Module) -> String {
+    let mut out = String::new();
+    write_module(&mut out, module).expect("writing to a String never fails");
+    out
+}
+
+fn write_module(out: &mut String, module: &Module) -> std::fmt::Result {
+    if !module.constants.is_empty() {
+        writeln!(out, "constants:")?;
+        for (i, constant) in module.constants.iter().enumerate() {
+            writeln!(out, "    #{} = {:?}", i, constant)?;
+        }
+        writeln!(out)?;
+    }
+
+    if !module.globals.is_empty() {
+        writeln!(out, "globals:")?;
+        write_slots(out, &module.globals)?;
+        writeln!(out)?;
+    }
+
+    for (i, pou) in module.pous.iter().enumerate() {
+        if i > 0 {
+            writeln!(out)?;
+        }
+        write_pou(out, module, i, pou)?;
+    }
+
+    Ok(())
+}
+
+fn write_slots(out: &mut String, slots: &[Slot]) -> std::fmt::Result {
+    for (i, slot) in slots.iter().enumerate() {
+        let name = slot.name.as_deref().unwrap_or("<temp>");
+        writeln!(out, "    {:>4}  {}: {}", i, name, slot.ty)?;
+    }
+
+    Ok(())
+}
+
+fn write_pou(
+    out: &mut String,
+    module: &Module,
+    index: usize,
+    pou: &Pou,
+) -> std::fmt::Result {
+    let kind = match pou.kind {
+        PouKind::Program => "program",
+        PouKind::Function => "function",
+        PouKind::FunctionBlock => "function_block",
+        PouKind::Builtin => "builtin",
+    };
+    writeln!(out, "@{} {} {}", index, kind, pou.name)?;
+
+    if let Some(slot) = pou.return_slot {
+        writeln!(out, "  returns: {}", slot)?;
+    }
+
+    if !pou.slots.is_empty() {
+        writeln!(out, "  slots:")?;
+        write_slots(out, &pou.slots)?;
+    }
+
+    if !pou.code.is_empty() {
+        writeln!(out, "  code:")?;
+    }
+
+    for (offset, op) in pou.code.iter().enumerate() {
+        let comment = comment(module, pou, *op);
+        // padding only works on strings
+        let op = op.to_string();
+        if comment.is_empty() {
+            writeln!(out, "    {:04}  {}", offset, op)?;
+        } else {
+            writeln!(out, "    {:04}  {:<24}; {}", offset, op, comment)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Extra information which makes an instruction easier to read.
+fn comment(module: &Module, pou: &Pou, op: Op) -> String {
+    let slot_name = |slots: &[Slot], slot: u16| {
+        slots
+            .get(usize::from(slot))
+            .and_then(|s| s.name.clone())
+            .unwrap_or_default()
+    };
+    let pou_name = |index: u16| {
+        module
+            .pous
+            .get(usize::from(index))
+            .map(|p| p.name.clone())
+            .unwrap_or_default()
+    };
+
+    match op {
+        Op::Const(ix) => module
+            .constants
+            .get(ix as usize)
+            .map(|c| format!("{:?}", c))
+            .unwrap_or_default(),
+        Op::LoadLocal(slot) | Op::StoreLocal(slot) => {
+            slot_name(&pou.slots, slot)
+        }
+        Op::LoadGlobal(slot) | Op::StoreGlobal(slot) => {
+            slot_name(&module.globals, slot)
+        }
+        Op::NewFrame(index) | Op::Call(index) => pou_name(index),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::{Slot, SlotType};
+    use crate::const_eval::IntegerType;
+    use crate::hir::{BinaryOp, Constant};
+
+    #[test]
+    fn listing_includes_names_and_constants() {
+        let module = Module {
+            constants: vec![Constant::Integer(1)],
+            globals: Vec::new(),
+            pous: vec![Pou {
+                name: String::from("main"),
+                kind: PouKind::Program,
+                slots: vec![Slot {
+                    name: Some(String::from("count")),
+                    ty: SlotType::Integer(IntegerType::Int),
+                }],
+                return_slot: None,
+                code: vec![
+                    Op::LoadLocal(0),
+                    Op::Const(0),
+                    Op::Binary(BinaryOp::Add),
+                    Op::StoreLocal(0),
+                    Op::Return,
+                ],
+                spans: Vec::new(),
+            }],
+        };
+
+        let got = disassemble(&module);
+
+        let should_be = "\
+constants:
+    #0 = Integer(1)
+
+@0 program main
+  slots:
+       0  count: INT
+  code:
+    0000  load.local 0            ; count
+    0001  const #0                ; Integer(1)
+    0002  add
+    0003  store.local 0           ; count
+    0004  return
+";
+        assert_eq!(got, should_be);
+    }
+}
@@ -0,0 +1,557 @@
+//! Saving and loading a [`Module`].
+//!
+//! All numbers are little endian and every list is prefixed with its length
+//! as a `u32`:
+//!
+//! ```text
+//! module   := "IECB" version:u16 constant* slot* pou*
+//! constant := tag:u8 (bool:u8 | i64 | f64 | string)
+//! slot     := name:optional<string> type
+//! pou      := name:string kind:u8 slot* return_slot:optional<u16>
+//!             op* (offset:u32 start:u32 end:u32)*
+//! op       := opcode:u8 operand?
+//! ```
+
+use super::{Module, Op, Pou, PouKind, Slot, SlotType};
+use crate::const_eval::IntegerType;
+use crate::hir::{BinaryOp, Constant, UnaryOp};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use codespan::{ByteIndex, ByteSpan};
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read, Write};
+
+/// The bytes every serialized [`Module`] starts with.
+pub const MAGIC: &[u8; 4] = b"IECB";
+/// The version of the format written by [`Module::write_to()`]. This gets
+/// bumped whenever the format changes in an incompatible way.
+pub const FORMAT_VERSION: u16 = 1;
+/// The file extension conventionally used for saved modules.
+pub const EXTENSION: &str = "iecbc";
+
+const INTEGER_TYPES: [IntegerType; 12] = [
+    IntegerType::SInt,
+    IntegerType::Int,
+    IntegerType::DInt,
+    IntegerType::LInt,
+    IntegerType::USInt,
+    IntegerType::UInt,
+    IntegerType::UDInt,
+    IntegerType::ULInt,
+    IntegerType::Byte,
+    IntegerType::Word,
+    IntegerType::DWord,
+    IntegerType::LWord,
+];
+
+const BINARY_OPS: [BinaryOp; 15] = [
+    BinaryOp::Add,
+    BinaryOp::Subtract,
+    BinaryOp::Multiply,
+    BinaryOp::Divide,
+    BinaryOp::Modulo,
+    BinaryOp::Exponent,
+    BinaryOp::And,
+    BinaryOp::Or,
+    BinaryOp::Xor,
+    BinaryOp::Equals,
+    BinaryOp::NotEquals,
+    BinaryOp::LessThan,
+    BinaryOp::LessThanOrEqual,
+    BinaryOp::GreaterThan,
+    BinaryOp::GreaterThanOrEqual,
+];
+
+const UNARY_OPS: [UnaryOp; 2] = [UnaryOp::Not, UnaryOp::Negate];
+
+const POU_KINDS: [PouKind; 4] = [
+    PouKind::Program,
+    PouKind::Function,
+    PouKind::FunctionBlock,
+    PouKind::Builtin,
+];
+
+impl Module {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        self.write_to(&mut buffer)
+            .expect("writing to a Vec<u8> never fails");
+        buffer
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Module, DecodeError> {
+        let mut reader = bytes;
+        let module = Module::read_from(&mut reader)?;
+
+        if reader.is_empty() {
+            Ok(module)
+        } else {
+            Err(DecodeError::TrailingBytes(reader.len()))
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let w = &mut writer;
+        w.write_all(MAGIC)?;
+        w.write_u16::<LittleEndian>(FORMAT_VERSION)?;
+
+        write_list(w, &self.constants, write_constant)?;
+        write_list(w, &self.globals, write_slot)?;
+        write_list(w, &self.pous, write_pou)?;
+
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(mut reader: R) -> Result<Module, DecodeError> {
+        let r = &mut reader;
+
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(DecodeError::NotBytecode);
+        }
+
+        let version = r.read_u16::<LittleEndian>()?;
+        if version != FORMAT_VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+
+        Ok(Module {
+            constants: read_list(r, read_constant)?,
+            globals: read_list(r, read_slot)?,
+            pous: read_list(r, read_pou)?,
+        })
+    }
+}
+
+fn write_list<W, T, F>(w: &mut W, items: &[T], mut write: F) -> io::Result<()>
+where
+    W: Write,
+    F: FnMut(&mut W, &T) -> io::Result<()>,
+{
+    w.write_u32::<LittleEndian>(items.len() as u32)?;
+    for item in items {
+        write(w, item)?;
+    }
+    Ok(())
+}
+
+fn read_list<R, T, F>(r: &mut R, mut read: F) -> Result<Vec<T>, DecodeError>
+where
+    R: Read,
+    F: FnMut(&mut R) -> Result<T, DecodeError>,
+{
+    let len = r.read_u32::<LittleEndian>()?;
+    // don't trust the length when pre-allocating, it may be garbage
+    let mut items = Vec::with_capacity(len.min(1024) as usize);
+
+    for _ in 0..len {
+        items.push(read(r)?);
+    }
+
+    Ok(items)
+}
+
+fn write_string<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+    write_list(w, s.as_bytes(), |w, &b| w.write_u8(b))
+}
+
+fn read_string<R: Read>(r: &mut R) -> Result<String, DecodeError> {
+    let bytes = read_list(r, |r| Ok(r.read_u8()?))?;
+    String::from_utf8(bytes).map_err(|_| DecodeError::InvalidUtf8)
+}
+
+/// Look up an item by the index it was encoded as.
+fn lookup<T: Copy>(
+    table: &[T],
+    tag: u8,
+    what: &'static str,
+) -> Result<T, DecodeError> {
+    table
+        .get(usize::from(tag))
+        .cloned()
+        .ok_or(DecodeError::InvalidTag { what, tag })
+}
+
+fn tag_of<T: PartialEq>(table: &[T], item: &T) -> u8 {
+    table
+        .iter()
+        .position(|t| t == item)
+        .expect("the table contains every variant") as u8
+}
+
+fn write_constant<W: Write>(w: &mut W, constant: &Constant) -> io::Result<()> {
+    match *constant {
+        Constant::Boolean(b) => {
+            w.write_u8(0)?;
+            w.write_u8(u8::from(b))
+        }
+        Constant::Integer(i) => {
+            w.write_u8(1)?;
+            w.write_i64::<LittleEndian>(i)
+        }
+        Constant::Float(f) => {
+            w.write_u8(2)?;
+            w.write_f64::<LittleEndian>(f)
+        }
+        Constant::String(ref s) => {
+            w.write_u8(3)?;
+            write_string(w, s)
+        }
+    }
+}
+
+fn read_constant<R: Read>(r: &mut R) -> Result<Constant, DecodeError> {
+    match r.read_u8()? {
+        0 => Ok(Constant::Boolean(r.read_u8()? != 0)),
+        1 => Ok(Constant::Integer(r.read_i64::<LittleEndian>()?)),
+        2 => Ok(Constant::Float(r.read_f64::<LittleEndian>()?)),
+        3 => Ok(Constant::String(read_string(r)?)),
+        tag => Err(DecodeError::InvalidTag {
+            what: "constant",
+            tag,
+        }),
+    }
+}
+
+fn write_slot<W: Write>(w: &mut W, slot: &Slot) -> io::Result<()> {
+    match slot.name {
+        Some(ref name) => {
+            w.write_u8(1)?;
+            write_string(w, name)?;
+        }
+        None => w.write_u8(0)?,
+    }
+
+    match slot.ty {
+        SlotType::Bool => w.write_u8(0),
+        SlotType::Integer(ty) => {
+            w.write_u8(1)?;
+            w.write_u8(tag_of(&INTEGER_TYPES, &ty))
+        }
+        SlotType::Real => w.write_u8(2),
+        SlotType::String => w.write_u8(3),
+        SlotType::Instance(pou) => {
+            w.write_u8(4)?;
+            w.write_u16::<LittleEndian>(pou)
+        }
+    }
+}
+
+fn read_slot<R: Read>(r: &mut R) -> Result<Slot, DecodeError> {
+    let name = match r.read_u8()? {
+        0 => None,
+        _ => Some(read_string(r)?),
+    };
+
+    let ty = match r.read_u8()? {
+        0 => SlotType::Bool,
+        1 => SlotType::Integer(lookup(
+            &INTEGER_TYPES,
+            r.read_u8()?,
+            "integer type",
+        )?),
+        2 => SlotType::Real,
+        3 => SlotType::String,
+        4 => SlotType::Instance(r.read_u16::<LittleEndian>()?),
+        tag => {
+            return Err(DecodeError::InvalidTag {
+                what: "slot type",
+                tag,
+            })
+        }
+    };
+
+    Ok(Slot { name, ty })
+}
+
+fn write_pou<W: Write>(w: &mut W, pou: &Pou) -> io::Result<()> {
+    write_string(w, &pou.name)?;
+    w.write_u8(tag_of(&POU_KINDS, &pou.kind))?;
+    write_list(w, &pou.slots, write_slot)?;
+
+    match pou.return_slot {
+        Some(slot) => {
+            w.write_u8(1)?;
+            w.write_u16::<LittleEndian>(slot)?;
+        }
+        None => w.write_u8(0)?,
+    }
+
+    write_list(w, &pou.code, write_op)?;
+    write_list(w, &pou.spans, |w, &(offset, span)| {
+        w.write_u32::<LittleEndian>(offset)?;
+        w.write_u32::<LittleEndian>(span.start().0)?;
+        w.write_u32::<LittleEndian>(span.end().0)
+    })
+}
+
+fn read_pou<R: Read>(r: &mut R) -> Result<Pou, DecodeError> {
+    let name = read_string(r)?;
+    let kind = lookup(&POU_KINDS, r.read_u8()?, "POU kind")?;
+    let slots = read_list(r, read_slot)?;
+    let return_slot = match r.read_u8()? {
+        0 => None,
+        _ => Some(r.read_u16::<LittleEndian>()?),
+    };
+    let code = read_list(r, read_op)?;
+    let spans = read_list(r, |r| {
+        let offset = r.read_u32::<LittleEndian>()?;
+        let start = r.read_u32::<LittleEndian>()?;
+        let end = r.read_u32::<LittleEndian>()?;
+        Ok((offset, ByteSpan::new(ByteIndex(start), ByteIndex(end))))
+    })?;
+
+    Ok(Pou {
+        name,
+        kind,
+        slots,
+        return_slot,
+        code,
+        spans,
+    })
+}
+
+fn write_op<W: Write>(w: &mut W, op: &Op) -> io::Result<()> {
+    match *op {
+        Op::Const(ix) => {
+            w.write_u8(0)?;
+            w.write_u32::<LittleEndian>(ix)
+        }
+        Op::LoadLocal(slot) => {
+            w.write_u8(1)?;
+            w.write_u16::<LittleEndian>(slot)
+        }
+        Op::StoreLocal(slot) => {
+            w.write_u8(2)?;
+            w.write_u16::<LittleEndian>(slot)
+        }
+        Op::LoadGlobal(slot) => {
+            w.write_u8(3)?;
+            w.write_u16::<LittleEndian>(slot)
+        }
+        Op::StoreGlobal(slot) => {
+            w.write_u8(4)?;
+            w.write_u16::<LittleEndian>(slot)
+        }
+        Op::GetMember(slot) => {
+            w.write_u8(5)?;
+            w.write_u16::<LittleEndian>(slot)
+        }
+        Op::SetMember(slot) => {
+            w.write_u8(6)?;
+            w.write_u16::<LittleEndian>(slot)
+        }
+        Op::NewFrame(pou) => {
+            w.write_u8(7)?;
+            w.write_u16::<LittleEndian>(pou)
+        }
+        Op::Call(pou) => {
+            w.write_u8(8)?;
+            w.write_u16::<LittleEndian>(pou)
+        }
+        Op::Dup => w.write_u8(9),
+        Op::Pop => w.write_u8(10),
+        Op::Binary(op) => {
+            w.write_u8(11)?;
+            w.write_u8(tag_of(&BINARY_OPS, &op))
+        }
+        Op::Unary(op) => {
+            w.write_u8(12)?;
+            w.write_u8(tag_of(&UNARY_OPS, &op))
+        }
+        Op::Jump(target) => {
+            w.write_u8(13)?;
+            w.write_u32::<LittleEndian>(target)
+        }
+        Op::JumpUnless(target) => {
+            w.write_u8(14)?;
+            w.write_u32::<LittleEndian>(target)
+        }
+        Op::Return => w.write_u8(15),
+    }
+}
+
+fn read_op<R: Read>(r: &mut R) -> Result<Op, DecodeError> {
+    let op = match r.read_u8()? {
+        0 => Op::Const(r.read_u32::<LittleEndian>()?),
+        1 => Op::LoadLocal(r.read_u16::<LittleEndian>()?),
+        2 => Op::StoreLocal(r.read_u16::<LittleEndian>()?),
+        3 => Op::LoadGlobal(r.read_u16::<LittleEndian>()?),
+        4 => Op::StoreGlobal(r.read_u16::<LittleEndian>()?),
+        5 => Op::GetMember(r.read_u16::<LittleEndian>()?),
+        6 => Op::SetMember(r.read_u16::<LittleEndian>()?),
+        7 => Op::NewFrame(r.read_u16::<LittleEndian>()?),
+        8 => Op::Call(r.read_u16::<LittleEndian>()?),
+        9 => Op::Dup,
+        10 => Op::Pop,
+        11 => Op::Binary(lookup(&BINARY_OPS, r.read_u8()?, "binary operator")?),
+        12 => Op::Unary(lookup(&UNARY_OPS, r.read_u8()?, "unary operator")?),
+        13 => Op::Jump(r.read_u32::<LittleEndian>()?),
+        14 => Op::JumpUnless(r.read_u32::<LittleEndian>()?),
+        15 => Op::Return,
+        tag => {
+            return Err(DecodeError::InvalidTag {
+                what: "opcode",
+                tag,
+            })
+        }
+    };
+
+    Ok(op)
+}
+
+/// The reasons a [`Module`] couldn't be loaded.
+#[derive(Debug)]
+pub enum DecodeError {
+    Io(io::Error),
+    /// The data doesn't start with the [`MAGIC`] bytes.
+    NotBytecode,
+    /// The module was saved using an incompatible version of the format.
+    UnsupportedVersion(u16),
+    InvalidTag {
+        what: &'static str,
+        tag: u8,
+    },
+    InvalidUtf8,
+    /// There was data left over after reading the module.
+    TrailingBytes(usize),
+}
+
+impl From<io::Error> for DecodeError {
+    fn from(other: io::Error) -> DecodeError {
+        DecodeError::Io(other)
+    }
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            DecodeError::Io(ref e)
+                if e.kind() == io::ErrorKind::UnexpectedEof =>
+            {
+                write!(f, "The bytecode was truncated")
+            }
+            DecodeError::Io(ref e) => write!(f, "{}", e),
+            DecodeError::NotBytecode => write!(f, "This isn't a bytecode file"),
+            DecodeError::UnsupportedVersion(version) => write!(
+                f,
+                "Unable to load version {} bytecode, expected version {}",
+                version, FORMAT_VERSION
+            ),
+            DecodeError::InvalidTag { what, tag } => {
+                write!(f, "Invalid {} ({})", what, tag)
+            }
+            DecodeError::InvalidUtf8 => {
+                write!(f, "A string wasn't valid UTF-8")
+            }
+            DecodeError::TrailingBytes(n) => {
+                write!(f, "Found {} unexpected bytes at the end", n)
+            }
+        }
+    }
+}
+
+impl Error for DecodeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            DecodeError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::compile;
+    use crate::passes::PassContext;
+    use crate::Diagnostics;
+
+    fn compile_str(src: &str) -> Module {
+        let ast: iec_syntax::File = src.parse().unwrap();
+        let mut diags = Diagnostics::new();
+        let cu =
+            crate::process(&ast, &mut PassContext::new_nop_logger(&mut diags));
+        assert!(!diags.has_errors(), "{:?}", diags);
+
+        compile(&cu)
+    }
+
+    fn example() -> Module {
+        compile_str(
+            "
+            VAR_GLOBAL
+                flag: bool;
+            END_VAR
+
+            FUNCTION_BLOCK fb
+                VAR_OUTPUT
+                    out: word;
+                END_VAR
+            BEGIN
+                out := shl(out, 1);
+            END_FUNCTION_BLOCK
+
+            PROGRAM main
+                VAR
+                    x: int;
+                    instance: fb;
+                    name: string;
+                END_VAR
+                IF flag THEN
+                    x := -x;
+                END_IF;
+                instance();
+            END_PROGRAM",
+        )
+    }
+
+    #[test]
+    fn round_trip_a_compiled_module() {
+        let original = example();
+
+        let bytes = original.to_bytes();
+        let got = Module::from_bytes(&bytes).unwrap();
+
+        assert_eq!(got, original);
+    }
+
+    #[test]
+    fn reject_other_files() {
+        let err = Module::from_bytes(b"PK\x03\x04 definitely a zip file")
+            .unwrap_err();
+
+        assert!(matches!(err, DecodeError::NotBytecode));
+    }
+
+    #[test]
+    fn reject_newer_versions() {
+        let mut bytes = example().to_bytes();
+        bytes[4] = 0xff;
+
+        let err = Module::from_bytes(&bytes).unwrap_err();
+
+        assert!(
+            matches!(err, DecodeError::UnsupportedVersion(v) if v != FORMAT_VERSION)
+        );
+    }
+
+    #[test]
+    fn detect_truncated_and_padded_modules() {
+        let bytes = example().to_bytes();
+
+        let truncated = Module::from_bytes(&bytes[..bytes.len() - 3]);
+        assert_eq!(
+            truncated.unwrap_err().to_string(),
+            "The bytecode was truncated"
+        );
+
+        let mut padded = bytes.clone();
+        padded.extend(&[0, 0]);
+        assert!(matches!(
+            Module::from_bytes(&padded),
+            Err(DecodeError::TrailingBytes(2))
+        ));
+    }
+}
@@ -0,0 +1,201 @@
+//! A compact bytecode format and the stack-based virtual machine which runs
+//! it.
+//!
+//! A [`Module`] is lowered from the [`Instruction`] control flow graph of a
+//! fully analysed [`CompilationUnit`]. Every variable is resolved to a slot
+//! index ahead of time, so the [`Vm`] never needs to look anything up by
+//! name and the cost of each scan cycle only depends on the code being run.
+//!
+//! Modules can be saved to disk (see [`Module::to_bytes()`]) and must pass
+//! the [`verify()`] checks before they are executed.
+//!
+//! [`Instruction`]: crate::hir::Instruction
+//! [`CompilationUnit`]: crate::CompilationUnit
+
+mod compile;
+mod disassemble;
+mod encoding;
+mod verify;
+mod vm;
+
+pub use self::compile::compile;
+pub use self::disassemble::disassemble;
+pub use self::encoding::{DecodeError, EXTENSION, FORMAT_VERSION, MAGIC};
+pub use self::verify::{verify, VerifyError};
+pub use self::vm::{Cell, CycleStats, Frame, Trap, Vm, VmError};
+
+use crate::const_eval::IntegerType;
+use crate::hir::{BinaryOp, Constant, UnaryOp};
+use codespan::ByteSpan;
+use std::fmt::{self, Display, Formatter};
+
+/// A self-contained bytecode program.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Module {
+    /// Every literal used by the code.
+    pub constants: Vec<Constant>,
+    /// The layout of the global variables.
+    pub globals: Vec<Slot>,
+    /// The table of *Program Organisation Units* (programs, functions, and
+    /// function blocks). Programs are executed in the order they appear.
+    pub pous: Vec<Pou>,
+}
+
+impl Module {
+    /// Find a POU by name (case insensitive).
+    pub fn pou_index(&self, name: &str) -> Option<usize> {
+        self.pous
+            .iter()
+            .position(|p| p.name.eq_ignore_ascii_case(name))
+    }
+
+    /// The indices of every program, in the order they are executed.
+    pub fn programs(&self) -> impl Iterator<Item = usize> + '_ {
+        self.pous
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.kind == PouKind::Program)
+            .map(|(i, _)| i)
+    }
+}
+
+/// A *Program Organisation Unit*.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pou {
+    pub name: String,
+    pub kind: PouKind,
+    /// The layout of this POU's variables.
+    ///
+    /// For function blocks this is the layout of each instance, while
+    /// functions and programs get a fresh frame per call and per program
+    /// respectively.
+    pub slots: Vec<Slot>,
+    /// The slot a function stores its result in.
+    pub return_slot: Option<u16>,
+    pub code: Vec<Op>,
+    /// The source code each run of instructions came from, as
+    /// `(offset, span)` pairs sorted by offset.
+    pub spans: Vec<(u32, ByteSpan)>,
+}
+
+impl Pou {
+    /// Find where the instruction at `offset` came from.
+    pub fn span_at(&self, offset: usize) -> Option<ByteSpan> {
+        let index = match self
+            .spans
+            .binary_search_by_key(&offset, |&(o, _)| o as usize)
+        {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+
+        Some(self.spans[index].1)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PouKind {
+    Program,
+    Function,
+    FunctionBlock,
+    /// A standard library function, which the [`Vm`] implements natively.
+    Builtin,
+}
+
+/// A single variable in a [`Pou`]'s layout.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Slot {
+    /// The variable's name, if it isn't a compiler temporary.
+    pub name: Option<String>,
+    pub ty: SlotType,
+}
+
+/// The type of value stored in a [`Slot`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SlotType {
+    Bool,
+    Integer(IntegerType),
+    Real,
+    String,
+    /// A function block instance, with the function block's [`Pou`] index.
+    Instance(u16),
+}
+
+impl Display for SlotType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            SlotType::Bool => write!(f, "BOOL"),
+            SlotType::Integer(ty) => write!(f, "{}", ty),
+            SlotType::Real => write!(f, "LREAL"),
+            SlotType::String => write!(f, "STRING"),
+            SlotType::Instance(pou) => write!(f, "@{}", pou),
+        }
+    }
+}
+
+/// A single bytecode instruction.
+///
+/// Frames (function block instances and the variables for a function call)
+/// are values on the stack just like everything else, which means calling a
+/// function is done by pushing a fresh frame, setting its inputs, calling
+/// it, then reading its outputs.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Op {
+    /// Push a value from the constant pool.
+    Const(u32),
+    /// Push the value of one of the current frame's slots.
+    LoadLocal(u16),
+    /// Pop a value and store it in one of the current frame's slots.
+    StoreLocal(u16),
+    LoadGlobal(u16),
+    StoreGlobal(u16),
+    /// Pop a frame and push the value of one of its slots.
+    GetMember(u16),
+    /// Pop a value and store it in a slot of the frame now at the top of the
+    /// stack.
+    SetMember(u16),
+    /// Push a frame for calling a function, with every slot set to its
+    /// default value.
+    NewFrame(u16),
+    /// Execute a POU using the frame at the top of the stack.
+    Call(u16),
+    Dup,
+    Pop,
+    Binary(BinaryOp),
+    Unary(UnaryOp),
+    /// Continue execution at another offset.
+    Jump(u32),
+    /// Pop a boolean and jump if it is false.
+    JumpUnless(u32),
+    /// Return from the current POU. The stack must be empty (relative to
+    /// when the POU was called).
+    Return,
+}
+
+impl Display for Op {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            Op::Const(ix) => write!(f, "const #{}", ix),
+            Op::LoadLocal(slot) => write!(f, "load.local {}", slot),
+            Op::StoreLocal(slot) => write!(f, "store.local {}", slot),
+            Op::LoadGlobal(slot) => write!(f, "load.global {}", slot),
+            Op::StoreGlobal(slot) => write!(f, "store.global {}", slot),
+            Op::GetMember(slot) => write!(f, "get.member {}", slot),
+            Op::SetMember(slot) => write!(f, "set.member {}", slot),
+            Op::NewFrame(pou) => write!(f, "new.frame @{}", pou),
+            Op::Call(pou) => write!(f, "call @{}", pou),
+            Op::Dup => write!(f, "dup"),
+            Op::Pop => write!(f, "pop"),
+            Op::Binary(op) => {
+                write!(f, "{}", format!("{:?}", op).to_lowercase())
+            }
+            Op::Unary(op) => {
+                write!(f, "{}", format!("{:?}", op).to_lowercase())
+            }
+            Op::Jump(target) => write!(f, "jump {:04}", target),
+            Op::JumpUnless(target) => write!(f, "jump.unless {:04}", target),
+            Op::Return => write!(f, "return"),
+        }
+    }
+}
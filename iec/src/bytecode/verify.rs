@@ -0,0 +1,530 @@
+use super::{Module, Op, Pou, PouKind, Slot, SlotType};
+use crate::passes::register_builtins;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+/// Check that a [`Module`] is well formed so the [`super::Vm`] can execute
+/// it without any further checks.
+///
+/// Alongside making sure every index is in bounds, this checks that:
+///
+/// - the stack has the same shape no matter how an instruction is reached,
+///   and is empty when returning
+/// - frames and plain values are never mixed up
+/// - nothing is recursive, either by calling itself or by a function block
+///   containing an instance of itself
+pub fn verify(module: &Module) -> Result<(), VerifyError> {
+    for (i, slot) in module.globals.iter().enumerate() {
+        check_slot(module, slot).map_err(|message| VerifyError {
+            pou: None,
+            offset: None,
+            message: format!("global {}: {}", i, message),
+        })?;
+    }
+
+    for pou in &module.pous {
+        let error = |offset, message| VerifyError {
+            pou: Some(pou.name.clone()),
+            offset,
+            message,
+        };
+
+        for (i, slot) in pou.slots.iter().enumerate() {
+            check_slot(module, slot)
+                .map_err(|m| error(None, format!("slot {}: {}", i, m)))?;
+        }
+
+        check_signature(pou).map_err(|m| error(None, m))?;
+
+        if pou.kind != PouKind::Builtin {
+            Checker::new(module, pou)
+                .run()
+                .map_err(|(offset, m)| error(Some(offset), m))?;
+        }
+    }
+
+    check_recursion(module)
+}
+
+fn check_slot(module: &Module, slot: &Slot) -> Result<(), String> {
+    match slot.ty {
+        SlotType::Instance(pou) => match module.pous.get(usize::from(pou)) {
+            Some(p) if p.kind == PouKind::FunctionBlock => Ok(()),
+            Some(p) => Err(format!("\"{}\" isn't a function block", p.name)),
+            None => Err(format!("unknown POU, @{}", pou)),
+        },
+        _ => Ok(()),
+    }
+}
+
+fn check_signature(pou: &Pou) -> Result<(), String> {
+    let returns_value = match pou.kind {
+        PouKind::Function | PouKind::Builtin => true,
+        PouKind::Program | PouKind::FunctionBlock => false,
+    };
+
+    match pou.return_slot {
+        Some(slot) if !returns_value => {
+            return Err(format!("only functions have a return slot ({})", slot))
+        }
+        Some(slot) if usize::from(slot) >= pou.slots.len() => {
+            return Err(format!("the return slot ({}) is out of bounds", slot))
+        }
+        None if returns_value => {
+            return Err(String::from("functions need a return slot"))
+        }
+        _ => {}
+    }
+
+    if pou.kind == PouKind::Builtin {
+        if register_builtins::builtin_function(&pou.name).is_none() {
+            return Err(String::from("unknown builtin function"));
+        }
+        if !pou.code.is_empty() {
+            return Err(String::from("builtin functions can't contain code"));
+        }
+    }
+
+    Ok(())
+}
+
+/// What is stored in each position on the stack.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Kind {
+    Value,
+    Frame(u16),
+}
+
+impl Kind {
+    fn of(ty: SlotType) -> Kind {
+        match ty {
+            SlotType::Instance(pou) => Kind::Frame(pou),
+            _ => Kind::Value,
+        }
+    }
+}
+
+impl Display for Kind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            Kind::Value => write!(f, "a value"),
+            Kind::Frame(pou) => write!(f, "a frame for @{}", pou),
+        }
+    }
+}
+
+/// Abstractly interprets a [`Pou`]'s code to make sure the stack is always
+/// used correctly.
+struct Checker<'m> {
+    module: &'m Module,
+    pou: &'m Pou,
+    /// The stack on entry to each instruction, once it has been reached.
+    states: Vec<Option<Vec<Kind>>>,
+    to_visit: Vec<usize>,
+}
+
+impl<'m> Checker<'m> {
+    fn new(module: &'m Module, pou: &'m Pou) -> Checker<'m> {
+        Checker {
+            module,
+            pou,
+            states: vec![None; pou.code.len()],
+            to_visit: Vec::new(),
+        }
+    }
+
+    fn run(mut self) -> Result<(), (usize, String)> {
+        if self.pou.code.is_empty() {
+            return Err((0, String::from("there is no code")));
+        }
+
+        self.states[0] = Some(Vec::new());
+        self.to_visit.push(0);
+
+        while let Some(offset) = self.to_visit.pop() {
+            let mut stack =
+                self.states[offset].clone().expect("visited states are set");
+            let op = self.pou.code[offset];
+
+            let successors =
+                self.step(op, offset, &mut stack).map_err(|m| (offset, m))?;
+
+            for next in successors {
+                self.merge(next, &stack).map_err(|m| (offset, m))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn merge(&mut self, offset: usize, stack: &[Kind]) -> Result<(), String> {
+        match self.states.get(offset) {
+            None => Err(format!("jumps out of bounds ({})", offset)),
+            Some(Some(existing)) if existing.as_slice() != stack => Err(format!(
+                "the stack is different when reaching {:04} along different paths",
+                offset
+            )),
+            Some(Some(_)) => Ok(()),
+            Some(None) => {
+                self.states[offset] = Some(stack.to_vec());
+                self.to_visit.push(offset);
+                Ok(())
+            }
+        }
+    }
+
+    /// Apply an instruction's effect to the stack, returning the offsets
+    /// which may be executed next.
+    fn step(
+        &self,
+        op: Op,
+        offset: usize,
+        stack: &mut Vec<Kind>,
+    ) -> Result<Vec<usize>, String> {
+        let next = vec![offset + 1];
+
+        match op {
+            Op::Const(ix) => {
+                if ix as usize >= self.module.constants.len() {
+                    return Err(format!("unknown constant, #{}", ix));
+                }
+                stack.push(Kind::Value);
+            }
+            Op::LoadLocal(slot) => {
+                stack.push(Kind::of(slot_type(&self.pou.slots, slot)?));
+            }
+            Op::StoreLocal(slot) => {
+                let expected = Kind::of(slot_type(&self.pou.slots, slot)?);
+                pop_kind(stack, expected)?;
+            }
+            Op::LoadGlobal(slot) => {
+                stack.push(Kind::of(slot_type(&self.module.globals, slot)?));
+            }
+            Op::StoreGlobal(slot) => {
+                let expected = Kind::of(slot_type(&self.module.globals, slot)?);
+                pop_kind(stack, expected)?;
+            }
+            Op::GetMember(slot) => {
+                let pou = pop_frame(stack)?;
+                let ty =
+                    slot_type(&self.module.pous[usize::from(pou)].slots, slot)?;
+                stack.push(Kind::of(ty));
+            }
+            Op::SetMember(slot) => {
+                let value = pop(stack)?;
+                let pou = match stack.last() {
+                    Some(&Kind::Frame(pou)) => pou,
+                    Some(&other) => {
+                        return Err(format!(
+                            "expected a frame but found {}",
+                            other
+                        ))
+                    }
+                    None => return Err(String::from("the stack is empty")),
+                };
+                let ty =
+                    slot_type(&self.module.pous[usize::from(pou)].slots, slot)?;
+                if Kind::of(ty) != value {
+                    return Err(format!(
+                        "expected {} but found {}",
+                        Kind::of(ty),
+                        value
+                    ));
+                }
+            }
+            Op::NewFrame(pou) => match self.callee(pou)?.kind {
+                PouKind::Function | PouKind::Builtin => {
+                    stack.push(Kind::Frame(pou))
+                }
+                _ => {
+                    return Err(String::from("only functions get a new frame"))
+                }
+            },
+            Op::Call(pou) => {
+                if self.callee(pou)?.kind == PouKind::Program {
+                    return Err(String::from("programs can't be called"));
+                }
+                if stack.last() != Some(&Kind::Frame(pou)) {
+                    return Err(format!("expected a frame for @{}", pou));
+                }
+            }
+            Op::Dup => {
+                let top = pop(stack)?;
+                stack.push(top);
+                stack.push(top);
+            }
+            Op::Pop => {
+                pop(stack)?;
+            }
+            Op::Binary(_) => {
+                pop_kind(stack, Kind::Value)?;
+                pop_kind(stack, Kind::Value)?;
+                stack.push(Kind::Value);
+            }
+            Op::Unary(_) => {
+                pop_kind(stack, Kind::Value)?;
+                stack.push(Kind::Value);
+            }
+            Op::Jump(target) => return Ok(vec![target as usize]),
+            Op::JumpUnless(target) => {
+                pop_kind(stack, Kind::Value)?;
+                return Ok(vec![target as usize, offset + 1]);
+            }
+            Op::Return => {
+                if !stack.is_empty() {
+                    return Err(format!(
+                        "{} items were left on the stack",
+                        stack.len()
+                    ));
+                }
+                return Ok(Vec::new());
+            }
+        }
+
+        if offset + 1 >= self.pou.code.len() {
+            return Err(String::from("execution runs off the end of the code"));
+        }
+
+        Ok(next)
+    }
+
+    fn callee(&self, pou: u16) -> Result<&'m Pou, String> {
+        self.module
+            .pous
+            .get(usize::from(pou))
+            .ok_or_else(|| format!("unknown POU, @{}", pou))
+    }
+}
+
+fn slot_type(slots: &[Slot], slot: u16) -> Result<SlotType, String> {
+    slots
+        .get(usize::from(slot))
+        .map(|s| s.ty)
+        .ok_or_else(|| format!("unknown slot, {}", slot))
+}
+
+fn pop(stack: &mut Vec<Kind>) -> Result<Kind, String> {
+    stack
+        .pop()
+        .ok_or_else(|| String::from("the stack is empty"))
+}
+
+fn pop_kind(stack: &mut Vec<Kind>, expected: Kind) -> Result<(), String> {
+    let found = pop(stack)?;
+
+    if found == expected {
+        Ok(())
+    } else {
+        Err(format!("expected {} but found {}", expected, found))
+    }
+}
+
+fn pop_frame(stack: &mut Vec<Kind>) -> Result<u16, String> {
+    match pop(stack)? {
+        Kind::Frame(pou) => Ok(pou),
+        Kind::Value => Err(String::from("expected a frame but found a value")),
+    }
+}
+
+/// Make sure the graph of calls and function block instances doesn't
+/// contain any cycles.
+fn check_recursion(module: &Module) -> Result<(), VerifyError> {
+    let edges: Vec<Vec<u16>> = module
+        .pous
+        .iter()
+        .map(|pou| {
+            let calls = pou.code.iter().filter_map(|op| match *op {
+                Op::Call(callee) | Op::NewFrame(callee) => Some(callee),
+                _ => None,
+            });
+            let instances = pou.slots.iter().filter_map(|s| match s.ty {
+                SlotType::Instance(fb) => Some(fb),
+                _ => None,
+            });
+            calls.chain(instances).collect()
+        })
+        .collect();
+
+    let mut finished = HashSet::new();
+
+    for start in 0..module.pous.len() {
+        let mut path = Vec::new();
+        visit(start, &edges, &mut path, &mut finished).map_err(|cycle| {
+            let names: Vec<&str> = cycle
+                .iter()
+                .map(|&p| module.pous[p].name.as_str())
+                .collect();
+            VerifyError {
+                pou: Some(module.pous[cycle[0]].name.clone()),
+                offset: None,
+                message: format!(
+                    "recursion isn't allowed ({})",
+                    names.join(" -> ")
+                ),
+            }
+        })?;
+    }
+
+    Ok(())
+}
+
+fn visit(
+    pou: usize,
+    edges: &[Vec<u16>],
+    path: &mut Vec<usize>,
+    finished: &mut HashSet<usize>,
+) -> Result<(), Vec<usize>> {
+    if finished.contains(&pou) {
+        return Ok(());
+    }
+    if let Some(start) = path.iter().position(|&p| p == pou) {
+        let mut cycle = path[start..].to_vec();
+        cycle.push(pou);
+        return Err(cycle);
+    }
+
+    path.push(pou);
+    for &next in &edges[pou] {
+        visit(usize::from(next), edges, path, finished)?;
+    }
+    path.pop();
+    finished.insert(pou);
+
+    Ok(())
+}
+
+/// A [`Module`] failed verification.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyError {
+    /// The name of the POU containing the problem, if it isn't global.
+    pub pou: Option<String>,
+    /// The offset of the offending instruction.
+    pub offset: Option<usize>,
+    pub message: String,
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match (&self.pou, self.offset) {
+            (Some(pou), Some(offset)) => {
+                write!(f, "{} (in \"{}\" at {:04})", self.message, pou, offset)
+            }
+            (Some(pou), None) => write!(f, "{} (in \"{}\")", self.message, pou),
+            (None, _) => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl Error for VerifyError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::const_eval::IntegerType;
+    use crate::hir::{BinaryOp, Constant};
+
+    fn program(name: &str, code: Vec<Op>) -> Pou {
+        Pou {
+            name: String::from(name),
+            kind: PouKind::Program,
+            slots: vec![Slot {
+                name: Some(String::from("x")),
+                ty: SlotType::Integer(IntegerType::Int),
+            }],
+            return_slot: None,
+            code,
+            spans: Vec::new(),
+        }
+    }
+
+    fn module(pous: Vec<Pou>) -> Module {
+        Module {
+            constants: vec![Constant::Integer(1)],
+            globals: Vec::new(),
+            pous,
+        }
+    }
+
+    fn message(module: &Module) -> String {
+        verify(module).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn accept_well_formed_code() {
+        let m = module(vec![program(
+            "main",
+            vec![
+                Op::LoadLocal(0),
+                Op::Const(0),
+                Op::Binary(BinaryOp::Add),
+                Op::StoreLocal(0),
+                Op::Return,
+            ],
+        )]);
+
+        assert_eq!(verify(&m), Ok(()));
+    }
+
+    #[test]
+    fn detect_stack_underflow() {
+        let m = module(vec![program(
+            "main",
+            vec![Op::Const(0), Op::Binary(BinaryOp::Add), Op::Return],
+        )]);
+
+        assert_eq!(message(&m), "the stack is empty (in \"main\" at 0001)");
+    }
+
+    #[test]
+    fn returning_requires_an_empty_stack() {
+        let m = module(vec![program("main", vec![Op::Const(0), Op::Return])]);
+
+        assert_eq!(
+            message(&m),
+            "1 items were left on the stack (in \"main\" at 0001)"
+        );
+    }
+
+    #[test]
+    fn jumps_must_stay_in_bounds() {
+        let m = module(vec![program("main", vec![Op::Jump(42), Op::Return])]);
+
+        assert_eq!(
+            message(&m),
+            "jumps out of bounds (42) (in \"main\" at 0000)"
+        );
+    }
+
+    #[test]
+    fn the_stack_must_agree_at_merge_points() {
+        let m = module(vec![program(
+            "main",
+            vec![
+                Op::Const(0),
+                Op::JumpUnless(3),
+                Op::Const(0),
+                Op::StoreLocal(0),
+                Op::Return,
+            ],
+        )]);
+
+        assert!(message(&m).starts_with("the stack is different"));
+    }
+
+    #[test]
+    fn recursive_function_blocks_are_rejected() {
+        let mut fb = program("fb", vec![Op::Return]);
+        fb.kind = PouKind::FunctionBlock;
+        fb.slots.push(Slot {
+            name: Some(String::from("inner")),
+            ty: SlotType::Instance(0),
+        });
+        let m = module(vec![fb]);
+
+        assert_eq!(
+            message(&m),
+            "recursion isn't allowed (fb -> fb) (in \"fb\")"
+        );
+    }
+}
@@ -0,0 +1,896 @@
+use super::{verify, Module, Op, PouKind, SlotType, VerifyError};
+use crate::const_eval::{EvalError, IntegerType};
+use crate::hir::Constant;
+use crate::interpreter::{stdlib, value, Overflow, Value};
+use codespan::ByteSpan;
+use codespan_reporting::{Diagnostic, Label};
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, Instant};
+
+/// Something stored in a slot or on the stack.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cell {
+    Value(Value),
+    Frame(Frame),
+}
+
+impl Cell {
+    pub fn as_value(&self) -> Option<&Value> {
+        match *self {
+            Cell::Value(ref v) => Some(v),
+            Cell::Frame(_) => None,
+        }
+    }
+
+    fn type_name(&self) -> String {
+        match *self {
+            Cell::Value(ref v) => v.type_name(),
+            Cell::Frame(_) => String::from("function block instance"),
+        }
+    }
+}
+
+impl Display for Cell {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            Cell::Value(ref v) => write!(f, "{}", v),
+            Cell::Frame(_) => write!(f, "<instance>"),
+        }
+    }
+}
+
+/// The variables for a program, function call, or function block instance.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    /// The index of the [`super::Pou`] this frame belongs to.
+    pub pou: u16,
+    pub slots: Vec<Cell>,
+}
+
+impl Frame {
+    /// Create a frame with every slot set to its default value.
+    pub fn new(module: &Module, pou: u16) -> Frame {
+        let slots = module.pous[usize::from(pou)]
+            .slots
+            .iter()
+            .map(|slot| default_value(module, slot.ty))
+            .collect();
+
+        Frame { pou, slots }
+    }
+}
+
+fn default_value(module: &Module, ty: SlotType) -> Cell {
+    let value = match ty {
+        SlotType::Bool => Value::Bool(false),
+        SlotType::Integer(ty) => Value::Integer { value: 0, ty },
+        SlotType::Real => Value::Real(0.0),
+        SlotType::String => Value::String(String::new()),
+        SlotType::Instance(pou) => return Cell::Frame(Frame::new(module, pou)),
+    };
+
+    Cell::Value(value)
+}
+
+/// How much work was done during a single scan cycle.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct CycleStats {
+    /// The number of instructions executed.
+    pub instructions: u64,
+    pub elapsed: Duration,
+}
+
+/// A stack machine which executes a verified [`Module`], one scan cycle at
+/// a time.
+///
+/// # Examples
+///
+/// ```rust
+/// # use iec::Diagnostics;
+/// # use iec::bytecode::{self, Vm};
+/// # use iec::passes::PassContext;
+/// let src = "
+///     PROGRAM main
+///         VAR
+///             count: int;
+///         END_VAR
+///         count := count + 1;
+///     END_PROGRAM";
+/// let ast: iec_syntax::File = src.parse().unwrap();
+/// let mut diags = Diagnostics::new();
+/// let cu = iec::process(&ast, &mut PassContext::new_nop_logger(&mut diags));
+///
+/// let module = bytecode::compile(&cu);
+/// let mut vm = Vm::new(module).unwrap();
+/// vm.run_cycles(3).unwrap();
+///
+/// assert_eq!(vm.get("main.count").unwrap().to_string(), "3");
+/// ```
+#[derive(Debug, Clone)]
+pub struct Vm {
+    module: Module,
+    overflow: Overflow,
+    globals: Vec<Cell>,
+    /// The state of each program instance, in the order they are executed.
+    instances: Vec<ProgramInstance>,
+    stack: Vec<Cell>,
+    last_cycle: CycleStats,
+}
+
+impl Vm {
+    /// Prepare to execute a [`Module`], making sure it passes verification
+    /// first.
+    pub fn new(module: Module) -> Result<Vm, VerifyError> {
+        verify(&module)?;
+
+        let globals = module
+            .globals
+            .iter()
+            .map(|slot| default_value(&module, slot.ty))
+            .collect();
+        // every program starts with a single instance named after itself
+        let instances = module
+            .programs()
+            .map(|pou| ProgramInstance {
+                name: module.pous[pou].name.clone(),
+                frame: Frame::new(&module, pou as u16),
+            })
+            .collect();
+
+        Ok(Vm {
+            module,
+            overflow: Overflow::default(),
+            globals,
+            instances,
+            stack: Vec::new(),
+            last_cycle: CycleStats::default(),
+        })
+    }
+
+    /// Choose what happens when integer arithmetic overflows.
+    pub fn with_overflow(mut self, overflow: Overflow) -> Vm {
+        self.overflow = overflow;
+        self
+    }
+
+    pub fn module(&self) -> &Module {
+        &self.module
+    }
+
+    /// Statistics from the most recent scan cycle.
+    pub fn last_cycle(&self) -> CycleStats {
+        self.last_cycle
+    }
+
+    /// Make sure there is a program instance called `instance`, creating it
+    /// with default values if necessary. The same program may be
+    /// instantiated several times, and each instance keeps its own state.
+    pub fn instantiate(
+        &mut self,
+        instance: &str,
+        program: &str,
+    ) -> Result<(), Trap> {
+        let module = &self.module;
+        let pou = module
+            .programs()
+            .find(|&pou| module.pous[pou].name.eq_ignore_ascii_case(program))
+            .ok_or_else(|| Trap::UnknownProgram(program.to_string()))?;
+
+        match self.instance_index(instance) {
+            Some(index)
+                if usize::from(self.instances[index].frame.pou) == pou =>
+            {
+                Ok(())
+            }
+            Some(_) => Err(Trap::DuplicateInstance(instance.to_string())),
+            None => {
+                self.instances.push(ProgramInstance {
+                    name: instance.to_string(),
+                    frame: Frame::new(module, pou as u16),
+                });
+                Ok(())
+            }
+        }
+    }
+
+    /// Run every program instance once.
+    pub fn run_cycle(&mut self) -> Result<CycleStats, VmError> {
+        let start = Instant::now();
+        let mut machine = Machine {
+            module: &self.module,
+            overflow: self.overflow,
+            globals: &mut self.globals,
+            stack: &mut self.stack,
+            executed: 0,
+        };
+
+        for instance in &mut self.instances {
+            let result = machine.execute(&mut instance.frame);
+
+            if result.is_err() {
+                // leave things in a usable state
+                machine.stack.clear();
+            }
+            result?;
+        }
+
+        self.last_cycle = CycleStats {
+            instructions: machine.executed,
+            elapsed: start.elapsed(),
+        };
+        Ok(self.last_cycle)
+    }
+
+    pub fn run_cycles(&mut self, cycles: u64) -> Result<(), VmError> {
+        for _ in 0..cycles {
+            self.run_cycle()?;
+        }
+
+        Ok(())
+    }
+
+    /// Execute the body of a single program instance (e.g. when a task
+    /// scheduler decides which programs run each cycle).
+    pub fn run_program(&mut self, instance: &str) -> Result<(), VmError> {
+        let index = self.instance_index(instance).ok_or_else(|| VmError {
+            pou: instance.to_string(),
+            offset: 0,
+            span: None,
+            trap: Trap::UnknownProgram(instance.to_string()),
+        })?;
+
+        let mut machine = Machine {
+            module: &self.module,
+            overflow: self.overflow,
+            globals: &mut self.globals,
+            stack: &mut self.stack,
+            executed: 0,
+        };
+        let result = machine.execute(&mut self.instances[index].frame);
+
+        if result.is_err() {
+            machine.stack.clear();
+        }
+        result
+    }
+
+    /// Read a variable, using either `"instance.variable"` or the name of a
+    /// global variable. Function block members can be accessed with extra
+    /// dots (e.g. `"main.timer.q"`).
+    pub fn get(&self, path: &str) -> Option<&Cell> {
+        let (instance, pieces) = self.locate(path)?;
+        let (first, rest) = pieces.split_first()?;
+        let mut cell = match instance {
+            Some(index) => &self.instances[index].frame.slots[*first],
+            None => &self.globals[*first],
+        };
+
+        for &member in rest {
+            cell = match *cell {
+                Cell::Frame(ref frame) => &frame.slots[member],
+                Cell::Value(_) => return None,
+            };
+        }
+
+        Some(cell)
+    }
+
+    /// Overwrite a variable (e.g. to simulate an input), converting the value
+    /// to the variable's type.
+    pub fn set(&mut self, path: &str, value: Value) -> Result<(), Trap> {
+        let unknown = || Trap::UnknownVariable(path.to_string());
+        let (instance, pieces) = self.locate(path).ok_or_else(unknown)?;
+        let (first, rest) = pieces.split_first().ok_or_else(unknown)?;
+
+        let (mut slots, mut pou) = match instance {
+            Some(index) => {
+                let frame = &mut self.instances[index].frame;
+                (&mut frame.slots, Some(frame.pou))
+            }
+            None => (&mut self.globals, None),
+        };
+        let mut index = *first;
+
+        for &member in rest {
+            match slots[index] {
+                Cell::Frame(ref mut frame) => {
+                    pou = Some(frame.pou);
+                    slots = &mut frame.slots;
+                }
+                Cell::Value(_) => return Err(unknown()),
+            }
+            index = member;
+        }
+
+        let ty = match pou {
+            Some(pou) => self.module.pous[usize::from(pou)].slots[index].ty,
+            None => self.module.globals[index].ty,
+        };
+        slots[index] = convert(ty, Cell::Value(value), self.overflow)?;
+        Ok(())
+    }
+
+    /// Find the program instance (if any) a path belongs to and the slots it
+    /// passes through.
+    fn locate(&self, path: &str) -> Option<(Option<usize>, Vec<usize>)> {
+        // a direct address is one slot, even when it contains a dot
+        let pieces: Vec<&str> = if path.starts_with('%') {
+            vec![path]
+        } else {
+            path.split('.').collect()
+        };
+        let module = &self.module;
+
+        let instance = self.instance_index(pieces[0]);
+        let (mut slots, names) = match instance {
+            Some(index) => {
+                let pou = usize::from(self.instances[index].frame.pou);
+                (&module.pous[pou].slots, &pieces[1..])
+            }
+            None => (&module.globals, &pieces[..]),
+        };
+
+        let mut indices = Vec::new();
+
+        for name in names {
+            let index = slots.iter().position(|s| {
+                s.name
+                    .as_ref()
+                    .is_some_and(|n| n.eq_ignore_ascii_case(name))
+            })?;
+            indices.push(index);
+
+            if let SlotType::Instance(fb) = slots[index].ty {
+                slots = &module.pous[usize::from(fb)].slots;
+            }
+        }
+
+        Some((instance, indices))
+    }
+
+    fn instance_index(&self, name: &str) -> Option<usize> {
+        self.instances
+            .iter()
+            .position(|i| i.name.eq_ignore_ascii_case(name))
+    }
+}
+
+/// A named instance of a program.
+#[derive(Debug, Clone, PartialEq)]
+struct ProgramInstance {
+    name: String,
+    frame: Frame,
+}
+
+/// The state needed while executing code.
+struct Machine<'v> {
+    module: &'v Module,
+    overflow: Overflow,
+    globals: &'v mut [Cell],
+    stack: &'v mut Vec<Cell>,
+    executed: u64,
+}
+
+impl<'v> Machine<'v> {
+    fn execute(&mut self, frame: &mut Frame) -> Result<(), VmError> {
+        let module = self.module;
+        let pou = &module.pous[usize::from(frame.pou)];
+        let mut pc = 0;
+
+        loop {
+            let offset = pc;
+            let op = pou.code[offset];
+            pc += 1;
+            self.executed += 1;
+
+            let result = match op {
+                Op::Jump(target) => {
+                    pc = target as usize;
+                    Ok(())
+                }
+                Op::JumpUnless(target) => match self.pop_value().as_bool() {
+                    Some(true) => Ok(()),
+                    Some(false) => {
+                        pc = target as usize;
+                        Ok(())
+                    }
+                    None => Err(Trap::TypeMismatch {
+                        expected: String::from("BOOL"),
+                        found: String::from("a non-boolean value"),
+                    }),
+                },
+                Op::Return => return Ok(()),
+                Op::Call(callee) => self.call(callee),
+                other => self.step(other, frame),
+            };
+
+            if let Err(trap) = result {
+                // errors from a callee already know where they happened
+                let trap = match trap {
+                    Trap::Nested(inner) => return Err(*inner),
+                    other => other,
+                };
+                return Err(VmError {
+                    pou: pou.name.clone(),
+                    offset,
+                    span: pou.span_at(offset),
+                    trap,
+                });
+            }
+        }
+    }
+
+    /// Execute an instruction which doesn't affect control flow.
+    fn step(&mut self, op: Op, frame: &mut Frame) -> Result<(), Trap> {
+        let module = self.module;
+
+        match op {
+            Op::Const(ix) => {
+                let value = constant(&module.constants[ix as usize]);
+                self.stack.push(Cell::Value(value));
+            }
+            Op::LoadLocal(slot) => {
+                let cell = frame.slots[usize::from(slot)].clone();
+                self.stack.push(cell);
+            }
+            Op::StoreLocal(slot) => {
+                let ty = module.pous[usize::from(frame.pou)].slots
+                    [usize::from(slot)]
+                .ty;
+                let cell = convert(ty, self.pop(), self.overflow)?;
+                frame.slots[usize::from(slot)] = cell;
+            }
+            Op::LoadGlobal(slot) => {
+                let cell = self.globals[usize::from(slot)].clone();
+                self.stack.push(cell);
+            }
+            Op::StoreGlobal(slot) => {
+                let ty = module.globals[usize::from(slot)].ty;
+                let cell = convert(ty, self.pop(), self.overflow)?;
+                self.globals[usize::from(slot)] = cell;
+            }
+            Op::GetMember(slot) => {
+                let mut object = self.pop_frame();
+                let member = object.slots.swap_remove(usize::from(slot));
+                self.stack.push(member);
+            }
+            Op::SetMember(slot) => {
+                let cell = self.pop();
+                let overflow = self.overflow;
+                let object = match self.stack.last_mut() {
+                    Some(Cell::Frame(ref mut f)) => f,
+                    _ => unreachable!("the verifier ensures this is a frame"),
+                };
+                let callee = &module.pous[usize::from(object.pou)];
+                // builtins are generic, so their arguments are left as-is
+                let cell = if callee.kind == PouKind::Builtin {
+                    cell
+                } else {
+                    convert(callee.slots[usize::from(slot)].ty, cell, overflow)?
+                };
+                object.slots[usize::from(slot)] = cell;
+            }
+            Op::NewFrame(pou) => {
+                self.stack.push(Cell::Frame(Frame::new(module, pou)));
+            }
+            Op::Dup => {
+                let top = self.stack.last().expect("verified").clone();
+                self.stack.push(top);
+            }
+            Op::Pop => {
+                self.pop();
+            }
+            Op::Binary(op) => {
+                let right = self.pop_value();
+                let left = self.pop_value();
+                let value = value::binary(op, &left, &right, self.overflow)?;
+                self.stack.push(Cell::Value(value));
+            }
+            Op::Unary(op) => {
+                let operand = self.pop_value();
+                let value = value::unary(op, &operand, self.overflow)?;
+                self.stack.push(Cell::Value(value));
+            }
+            Op::Call(_) | Op::Jump(_) | Op::JumpUnless(_) | Op::Return => {
+                unreachable!("control flow is handled by the caller")
+            }
+        }
+
+        Ok(())
+    }
+
+    fn call(&mut self, callee: u16) -> Result<(), Trap> {
+        let module = self.module;
+        let mut frame = self.pop_frame();
+        let pou = &module.pous[usize::from(callee)];
+
+        if pou.kind == PouKind::Builtin {
+            let return_slot = usize::from(pou.return_slot.expect("verified"));
+            let args: Vec<Value> = frame
+                .slots
+                .iter()
+                .enumerate()
+                .filter(|&(i, _)| i != return_slot)
+                .filter_map(|(_, cell)| cell.as_value().cloned())
+                .collect();
+            let result = stdlib::call(&pou.name, &args, self.overflow)?;
+            frame.slots[return_slot] = Cell::Value(result);
+        } else {
+            self.execute(&mut frame)
+                .map_err(|e| Trap::Nested(Box::new(e)))?;
+        }
+
+        self.stack.push(Cell::Frame(frame));
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Cell {
+        self.stack.pop().expect("the verifier checks for underflow")
+    }
+
+    fn pop_value(&mut self) -> Value {
+        match self.pop() {
+            Cell::Value(v) => v,
+            Cell::Frame(_) => {
+                unreachable!("the verifier ensures this is a value")
+            }
+        }
+    }
+
+    fn pop_frame(&mut self) -> Frame {
+        match self.pop() {
+            Cell::Frame(f) => f,
+            Cell::Value(_) => {
+                unreachable!("the verifier ensures this is a frame")
+            }
+        }
+    }
+}
+
+fn constant(constant: &Constant) -> Value {
+    match *constant {
+        Constant::Boolean(b) => Value::Bool(b),
+        Constant::Integer(i) => {
+            let value = i128::from(i);
+            Value::Integer {
+                value,
+                ty: IntegerType::for_literal(value),
+            }
+        }
+        Constant::Float(f) => Value::Real(f),
+        Constant::String(ref s) => Value::String(s.clone()),
+    }
+}
+
+/// Convert a value so it can be stored in a slot.
+fn convert(ty: SlotType, cell: Cell, overflow: Overflow) -> Result<Cell, Trap> {
+    let value = match (ty, cell) {
+        (SlotType::Integer(ty), Cell::Value(Value::Integer { value, .. })) => {
+            overflow.apply(value, ty)
+        }
+        (SlotType::Real, Cell::Value(ref v @ Value::Integer { .. }))
+        | (SlotType::Real, Cell::Value(ref v @ Value::Real(_))) => {
+            Value::Real(v.as_real().expect("checked above"))
+        }
+        (SlotType::Bool, Cell::Value(v @ Value::Bool(_)))
+        | (SlotType::String, Cell::Value(v @ Value::String(_))) => v,
+        (SlotType::Instance(pou), Cell::Frame(frame)) if frame.pou == pou => {
+            return Ok(Cell::Frame(frame))
+        }
+        (ty, found) => {
+            return Err(Trap::TypeMismatch {
+                expected: ty.to_string(),
+                found: found.type_name(),
+            })
+        }
+    };
+
+    Ok(Cell::Value(value))
+}
+
+/// Why execution stopped.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trap {
+    /// An operation failed (e.g. division by zero).
+    Eval(EvalError),
+    /// A value couldn't be stored in a slot.
+    TypeMismatch {
+        expected: String,
+        found: String,
+    },
+    UnknownVariable(String),
+    UnknownProgram(String),
+    /// A program instance with this name already exists, but it's an
+    /// instance of a different program.
+    DuplicateInstance(String),
+    /// The error happened inside a called POU.
+    Nested(Box<VmError>),
+}
+
+impl From<EvalError> for Trap {
+    fn from(other: EvalError) -> Trap {
+        Trap::Eval(other)
+    }
+}
+
+impl Display for Trap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            Trap::Eval(ref e) => {
+                write!(f, "{}", e.to_diagnostic(ByteSpan::default()).message)
+            }
+            Trap::TypeMismatch {
+                ref expected,
+                ref found,
+            } => write!(f, "Expected {} but found {}", expected, found),
+            Trap::UnknownVariable(ref name) => {
+                write!(f, "Unknown variable, \"{}\"", name)
+            }
+            Trap::UnknownProgram(ref name) => {
+                write!(f, "Unknown program, \"{}\"", name)
+            }
+            Trap::DuplicateInstance(ref name) => {
+                write!(f, "The \"{}\" instance already exists", name)
+            }
+            Trap::Nested(ref inner) => write!(f, "{}", inner),
+        }
+    }
+}
+
+impl Error for Trap {}
+
+/// A [`Trap`] and where it happened.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmError {
+    /// The name of the POU being executed.
+    pub pou: String,
+    /// The offset of the instruction which failed.
+    pub offset: usize,
+    /// The source code the instruction was generated from, if known.
+    pub span: Option<ByteSpan>,
+    pub trap: Trap,
+}
+
+impl VmError {
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        let diag = match (self.span, &self.trap) {
+            (Some(span), Trap::Eval(e)) => return e.to_diagnostic(span),
+            (_, trap) => Diagnostic::new_error(trap.to_string()),
+        };
+
+        match self.span {
+            Some(span) => diag.with_label(Label::new_primary(span)),
+            None => diag,
+        }
+    }
+}
+
+impl Display for VmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (in \"{}\" at {:04})",
+            self.trap, self.pou, self.offset
+        )
+    }
+}
+
+impl Error for VmError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::compile;
+    use crate::passes::PassContext;
+    use crate::Diagnostics;
+
+    fn vm(src: &str) -> Vm {
+        let ast: iec_syntax::File = src.parse().unwrap();
+        let mut diags = Diagnostics::new();
+        let cu =
+            crate::process(&ast, &mut PassContext::new_nop_logger(&mut diags));
+        assert!(!diags.has_errors(), "{:?}", diags);
+
+        Vm::new(compile(&cu)).unwrap()
+    }
+
+    fn integer(vm: &Vm, path: &str) -> i128 {
+        vm.get(path)
+            .and_then(Cell::as_value)
+            .and_then(Value::as_integer)
+            .unwrap_or_else(|| panic!("\"{}\" isn't an integer", path))
+    }
+
+    #[test]
+    fn function_block_instances_keep_their_state() {
+        let src = "
+            FUNCTION_BLOCK counter
+                VAR_INPUT
+                    step: int;
+                END_VAR
+                VAR_OUTPUT
+                    total: int;
+                END_VAR
+            BEGIN
+                total := total + step;
+            END_FUNCTION_BLOCK
+
+            FUNCTION double : int
+            BEGIN
+                VAR_INPUT
+                    x: int;
+                END_VAR
+                double := x * 2;
+            END_FUNCTION
+
+            PROGRAM main
+                VAR
+                    slow: counter;
+                    fast: counter;
+                    copy: int;
+                END_VAR
+                slow(1);
+                fast(step := double(5), total => copy);
+            END_PROGRAM";
+        let mut vm = vm(src);
+
+        vm.run_cycles(3).unwrap();
+
+        assert_eq!(integer(&vm, "main.slow.total"), 3);
+        assert_eq!(integer(&vm, "main.fast.total"), 30);
+        assert_eq!(integer(&vm, "main.copy"), 30);
+    }
+
+    #[test]
+    fn loops_and_the_standard_library() {
+        let src = "
+            PROGRAM main
+                VAR
+                    i: int;
+                    total: int;
+                    repeats: int;
+                    biggest: int;
+                    bits: byte;
+                END_VAR
+                total := 0;
+                FOR i := 1 TO 10 DO
+                    IF i > 4 THEN
+                        EXIT;
+                    END_IF;
+                    total := total + i;
+                END_FOR;
+
+                repeats := 0;
+                REPEAT
+                    repeats := repeats + 1;
+                UNTIL repeats >= 3
+                END_REPEAT;
+
+                biggest := max(limit(0, total * 100, 500), 7);
+                bits := 1;
+                bits := ror(bits, 1);
+            END_PROGRAM";
+        let mut vm = vm(src);
+
+        vm.run_cycle().unwrap();
+
+        assert_eq!(integer(&vm, "main.total"), 10);
+        assert_eq!(integer(&vm, "main.repeats"), 3);
+        assert_eq!(integer(&vm, "main.biggest"), 500);
+        assert_eq!(integer(&vm, "main.bits"), 128);
+    }
+
+    #[test]
+    fn integers_wrap_or_saturate() {
+        let src = "
+            PROGRAM main
+                VAR
+                    x: sint;
+                END_VAR
+                x := x + 100;
+            END_PROGRAM";
+
+        let mut wrapping = vm(src);
+        wrapping.run_cycles(2).unwrap();
+        assert_eq!(integer(&wrapping, "main.x"), -56);
+
+        let mut saturating = vm(src).with_overflow(Overflow::Saturate);
+        saturating.run_cycles(2).unwrap();
+        assert_eq!(integer(&saturating, "main.x"), 127);
+    }
+
+    #[test]
+    fn globals_and_inputs() {
+        let src = "
+            VAR_GLOBAL
+                shared: int;
+            END_VAR
+
+            PROGRAM first
+                VAR
+                    input: int;
+                END_VAR
+                shared := shared + input;
+            END_PROGRAM
+
+            PROGRAM second
+                VAR
+                    seen: int;
+                END_VAR
+                seen := shared;
+            END_PROGRAM";
+        let mut vm = vm(src);
+
+        vm.set(
+            "first.input",
+            Value::Integer {
+                value: 5,
+                ty: IntegerType::LInt,
+            },
+        )
+        .unwrap();
+        vm.run_cycles(2).unwrap();
+
+        assert_eq!(integer(&vm, "shared"), 10);
+        assert_eq!(integer(&vm, "second.seen"), 10);
+        assert!(vm.last_cycle().instructions > 0);
+        assert_eq!(
+            vm.set("first.missing", Value::Bool(true)),
+            Err(Trap::UnknownVariable(String::from("first.missing")))
+        );
+    }
+
+    #[test]
+    fn errors_point_at_the_original_code() {
+        let src = "
+            FUNCTION divide : int
+            BEGIN
+                VAR_INPUT
+                    x: int;
+                END_VAR
+                divide := 10 / x;
+            END_FUNCTION
+
+            PROGRAM main
+                VAR
+                    y: int;
+                END_VAR
+                y := divide(0);
+            END_PROGRAM";
+        let mut vm = vm(src);
+
+        let err = vm.run_cycle().unwrap_err();
+
+        assert_eq!(err.pou, "divide");
+        assert_eq!(err.trap, Trap::Eval(EvalError::DivisionByZero));
+        let span = err.span.unwrap();
+        assert_eq!(
+            &src[span.start().to_usize()..span.end().to_usize()],
+            "10 / x"
+        );
+    }
+
+    #[test]
+    fn undeclared_direct_addresses_can_be_used() {
+        let mut vm = vm("
+            PROGRAM main
+                %QW4 := %IW2 + 1;
+                IF %IX0.1 THEN
+                    %QX0.1 := TRUE;
+                END_IF;
+            END_PROGRAM");
+
+        let input = Value::Integer {
+            value: 41,
+            ty: IntegerType::Word,
+        };
+        vm.set("%IW2", input).unwrap();
+        vm.set("%IX0.1", Value::Bool(true)).unwrap();
+        vm.run_cycle().unwrap();
+
+        assert_eq!(integer(&vm, "%QW4"), 42);
+        let output = vm.get("%QX0.1").and_then(Cell::as_value);
+        assert_eq!(output, Some(&Value::Bool(true)));
+    }
+}
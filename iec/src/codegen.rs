@@ -0,0 +1,878 @@
+//! The parts of code generation which are the same for every backend.
+//!
+//! A backend implements [`Lower`] by providing the primitives for emitting
+//! instructions (loads, stores, arithmetic, etc.), then gets the rest of the
+//! translation for free. Working out the type of each operand, implicit
+//! conversions, inlining builtins, and binding the arguments of a call are
+//! all done here, so every backend behaves the same way.
+
+use crate::const_eval::{IntegerType, Scalar};
+use crate::ecs::{Container, EntityId};
+use crate::hir::{
+    Argument, BasicBlock, BinaryOp, CompilationUnit, Constant, Function,
+    Instruction, Operand, Symbol, UnaryOp,
+};
+use crate::layout::VariableType;
+use crate::passes::register_builtins::{builtin_arguments, Builtin, Shift};
+use std::error::Error;
+use std::fmt::{self, Debug, Display, Formatter};
+
+/// The components needed while translating a POU's body.
+pub struct Tables<'a> {
+    pub functions: &'a Container<Function>,
+    pub blocks: &'a Container<BasicBlock>,
+    pub instructions: &'a Container<Instruction>,
+    pub constants: &'a Container<Constant>,
+}
+
+impl<'a> Tables<'a> {
+    pub fn block(&self, id: EntityId) -> &'a BasicBlock {
+        self.blocks
+            .get(id)
+            .expect("terminators only refer to known blocks")
+    }
+}
+
+/// Run a function with the [`Tables`] for a [`CompilationUnit`].
+pub fn with_tables<F, T>(unit: &CompilationUnit, thunk: F) -> T
+where
+    F: FnOnce(&Tables<'_>) -> T,
+{
+    let resources = &unit.resources;
+    let tables = Tables {
+        functions: &resources.get(),
+        blocks: &resources.get(),
+        instructions: &resources.get(),
+        constants: &resources.get(),
+    };
+
+    thunk(&tables)
+}
+
+/// A value produced by a backend, and its type.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Typed<V> {
+    pub value: V,
+    pub ty: Scalar,
+}
+
+impl<V> Typed<V> {
+    pub fn new(value: V, ty: Scalar) -> Typed<V> {
+        Typed { value, ty }
+    }
+}
+
+/// The result of reading a place.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Item<V, P> {
+    Scalar(Typed<V>),
+    /// Strings and function block instances are too big for a register, so
+    /// they are left in memory.
+    Aggregate(P),
+}
+
+/// The [`Item`] used by a particular [`Lower`] implementation.
+pub type ItemOf<'a, L> = Item<<L as Lower<'a>>::Value, <L as Lower<'a>>::Place>;
+
+/// Why execution can be aborted part way through a cycle.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Trap {
+    DivisionByZero,
+    NegativeExponent,
+    /// A builtin was given operands it can't handle (e.g. a negative shift).
+    InvalidOperands,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    pub fn from_op(op: BinaryOp) -> Option<Comparison> {
+        match op {
+            BinaryOp::Equals => Some(Comparison::Equal),
+            BinaryOp::NotEquals => Some(Comparison::NotEqual),
+            BinaryOp::LessThan => Some(Comparison::Less),
+            BinaryOp::LessThanOrEqual => Some(Comparison::LessOrEqual),
+            BinaryOp::GreaterThan => Some(Comparison::Greater),
+            BinaryOp::GreaterThanOrEqual => Some(Comparison::GreaterOrEqual),
+            _ => None,
+        }
+    }
+}
+
+/// Arithmetic on integers, which always wraps on overflow.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IntegerOp {
+    Add,
+    Subtract,
+    Multiply,
+    /// Division, where the divisor is known to be non-zero.
+    Divide,
+    /// The remainder of a division, where the divisor is known to be
+    /// non-zero.
+    Modulo,
+    /// Exponentiation, where the exponent is known to be non-negative.
+    Exponent,
+    And,
+    Or,
+    Xor,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RealOp {
+    Add,
+    Subtract,
+    Multiply,
+    /// Division, where the divisor is known to be non-zero.
+    Divide,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BoolOp {
+    And,
+    Or,
+    Xor,
+    Equal,
+    NotEqual,
+}
+
+/// Translating the HIR for a POU's body, where the backend provides the
+/// primitives for emitting instructions.
+///
+/// Primitives are only ever given operands of the types they expect (e.g.
+/// [`Lower::abs()`] is only used for signed integers and reals), so they
+/// can't fail.
+pub trait Lower<'a> {
+    /// A value which fits in a register.
+    type Value: Clone;
+    /// Something in memory which can be read from or written to.
+    type Place: Clone;
+
+    fn tables(&self) -> &Tables<'a>;
+
+    /// Where a variable is stored.
+    fn place(&mut self, variable: EntityId) -> Self::Place;
+    /// Where a field of a function block instance is stored.
+    fn member(&mut self, object: &Self::Place, member: EntityId)
+        -> Self::Place;
+    fn place_type(&self, place: &Self::Place) -> VariableType;
+    fn load(&mut self, place: &Self::Place, ty: Scalar) -> Self::Value;
+    /// Save a value which has already been converted to the place's type.
+    fn store(&mut self, place: &Self::Place, value: &Typed<Self::Value>);
+    /// Copy an aggregate from one place to another of the same type.
+    fn copy(&mut self, dest: &Self::Place, src: &Self::Place);
+
+    fn boolean(&mut self, value: bool) -> Self::Value;
+    fn integer(&mut self, value: i64, ty: IntegerType) -> Self::Value;
+    fn real(&mut self, value: f64) -> Self::Value;
+
+    /// Convert an integer to another integer type, wrapping if it doesn't
+    /// fit.
+    fn resize(
+        &mut self,
+        value: &Typed<Self::Value>,
+        to: IntegerType,
+    ) -> Self::Value;
+    fn int_to_real(&mut self, value: &Typed<Self::Value>) -> Self::Value;
+
+    /// Check whether an integer or real is zero.
+    fn is_zero(&mut self, value: &Typed<Self::Value>) -> Self::Value;
+    /// Check whether a signed integer is negative.
+    fn is_negative(&mut self, value: &Typed<Self::Value>) -> Self::Value;
+    /// Abort execution if `condition` is true.
+    fn trap_if(&mut self, condition: Self::Value, trap: Trap);
+
+    /// Compare two integers (possibly of different types), using their
+    /// unsigned representations if `unsigned` is set.
+    fn compare_integers(
+        &mut self,
+        comparison: Comparison,
+        left: &Typed<Self::Value>,
+        right: &Typed<Self::Value>,
+        unsigned: bool,
+    ) -> Self::Value;
+    fn compare_reals(
+        &mut self,
+        comparison: Comparison,
+        left: &Self::Value,
+        right: &Self::Value,
+    ) -> Self::Value;
+    fn bool_binary(
+        &mut self,
+        op: BoolOp,
+        left: &Self::Value,
+        right: &Self::Value,
+    ) -> Self::Value;
+    /// Integer arithmetic on two integers (possibly of different types),
+    /// wrapping the result to `ty`. Division uses the operands' unsigned
+    /// representations if `unsigned` is set.
+    fn integer_arithmetic(
+        &mut self,
+        op: IntegerOp,
+        left: &Typed<Self::Value>,
+        right: &Typed<Self::Value>,
+        ty: IntegerType,
+        unsigned: bool,
+    ) -> Self::Value;
+    fn real_arithmetic(
+        &mut self,
+        op: RealOp,
+        left: &Self::Value,
+        right: &Self::Value,
+    ) -> Self::Value;
+    /// Logical negation for booleans, or bitwise for integers.
+    fn not(&mut self, value: &Typed<Self::Value>) -> Self::Value;
+    fn negate(&mut self, value: &Typed<Self::Value>) -> Self::Value;
+    /// The absolute value of a signed integer or real.
+    fn abs(&mut self, value: &Typed<Self::Value>) -> Self::Value;
+    /// Pick between two values of the same type.
+    fn select(
+        &mut self,
+        condition: &Self::Value,
+        if_true: &Typed<Self::Value>,
+        if_false: &Typed<Self::Value>,
+    ) -> Self::Value;
+    /// Shift or rotate an integer by a non-negative amount, which may be
+    /// bigger than the integer's width.
+    fn shift(
+        &mut self,
+        shift: Shift,
+        value: &Typed<Self::Value>,
+        amount: &Typed<Self::Value>,
+    ) -> Self::Value;
+
+    /// The index of the POU a call refers to, or `None` for builtins.
+    fn pou_index(&self, symbol: Symbol) -> Option<usize>;
+    /// Start a call, returning the frame to pass arguments through. Function
+    /// blocks are called with their `instance`, while functions need a fresh
+    /// frame.
+    fn begin_call(
+        &mut self,
+        pou: usize,
+        instance: Option<Self::Place>,
+    ) -> Self::Place;
+    /// Call a POU, aborting execution if the call fails.
+    fn invoke(&mut self, pou: usize, frame: &Self::Place);
+    /// Finish a call, after its results have been copied out of the frame.
+    fn end_call(&mut self, _pou: usize) {}
+
+    /// Apply a binary operator to two aggregates, for backends which can
+    /// compare strings.
+    fn aggregate_binary(
+        &mut self,
+        _op: BinaryOp,
+        left: &Self::Place,
+        _right: &Self::Place,
+    ) -> Result<Typed<Self::Value>, LowerError> {
+        Err(LowerError::TypeMismatch {
+            expected: String::from("value"),
+            found: self.place_type(left).to_string(),
+        })
+    }
+
+    /// Translate every instruction in a block, leaving its terminator to the
+    /// backend.
+    fn instructions(&mut self, block: &BasicBlock) -> Result<(), LowerError> {
+        let instructions = self.tables().instructions;
+
+        for &id in &block.instructions {
+            let instruction = instructions
+                .get(id)
+                .expect("blocks only contain known instructions");
+            self.instruction(instruction)?;
+        }
+
+        Ok(())
+    }
+
+    fn instruction(
+        &mut self,
+        instruction: &Instruction,
+    ) -> Result<(), LowerError> {
+        match *instruction {
+            Instruction::Load { dest, src } => {
+                let value = self.operand(src)?;
+                let dest = self.place(dest);
+                self.write(&dest, value)
+            }
+            Instruction::LoadMember {
+                dest,
+                object,
+                member,
+            } => {
+                let object = self.place(object);
+                let member = self.member(&object, member);
+                let value = self.read(&member);
+                let dest = self.place(dest);
+                self.write(&dest, value)
+            }
+            Instruction::StoreMember {
+                object,
+                member,
+                value,
+            } => {
+                let value = self.operand(value)?;
+                let object = self.place(object);
+                let member = self.member(&object, member);
+                self.write(&member, value)
+            }
+            Instruction::Binary {
+                dest,
+                op,
+                left,
+                right,
+            } => {
+                let left = self.operand(left)?;
+                let right = self.operand(right)?;
+                let value = self.binary(op, left, right)?;
+                let dest = self.place(dest);
+                self.write(&dest, Item::Scalar(value))
+            }
+            Instruction::Unary { dest, op, value } => {
+                let value = self.operand(value)?;
+                let value = self.unary(op, value)?;
+                let dest = self.place(dest);
+                self.write(&dest, Item::Scalar(value))
+            }
+            Instruction::Call {
+                target,
+                instance,
+                ref args,
+                dest,
+            } => self.call(target, instance, args, dest),
+        }
+    }
+
+    /// The condition a [`crate::hir::Terminator::Branch`] switches on.
+    fn condition(
+        &mut self,
+        operand: Operand,
+    ) -> Result<Self::Value, LowerError> {
+        let condition = self.operand(operand)?;
+        self.scalar(condition, Scalar::Bool)
+    }
+
+    fn read(&mut self, place: &Self::Place) -> ItemOf<'a, Self> {
+        match self.place_type(place).scalar() {
+            Some(ty) => Item::Scalar(Typed::new(self.load(place, ty), ty)),
+            None => Item::Aggregate(place.clone()),
+        }
+    }
+
+    fn write(
+        &mut self,
+        place: &Self::Place,
+        item: ItemOf<'a, Self>,
+    ) -> Result<(), LowerError> {
+        let ty = self.place_type(place);
+
+        match (ty.scalar(), item) {
+            (Some(scalar), Item::Scalar(value)) => {
+                let value = self.convert(&value, scalar)?;
+                self.store(place, &Typed::new(value, scalar));
+                Ok(())
+            }
+            (None, Item::Aggregate(ref src)) if self.place_type(src) == ty => {
+                self.copy(place, src);
+                Ok(())
+            }
+            (_, item) => Err(LowerError::TypeMismatch {
+                expected: ty.to_string(),
+                found: match item {
+                    Item::Scalar(value) => value.ty.to_string(),
+                    Item::Aggregate(src) => self.place_type(&src).to_string(),
+                },
+            }),
+        }
+    }
+
+    fn operand(
+        &mut self,
+        operand: Operand,
+    ) -> Result<ItemOf<'a, Self>, LowerError> {
+        let constant = match operand {
+            Operand::Variable(id) => {
+                let place = self.place(id);
+                return Ok(self.read(&place));
+            }
+            Operand::Constant(id) => self
+                .tables()
+                .constants
+                .get(id)
+                .expect("operands only refer to known constants"),
+        };
+
+        let value = match *constant {
+            Constant::Boolean(b) => Typed::new(self.boolean(b), Scalar::Bool),
+            Constant::Integer(i) => {
+                let ty = IntegerType::for_literal(i128::from(i));
+                Typed::new(self.integer(i, ty), Scalar::Integer(ty))
+            }
+            Constant::Float(f) => Typed::new(self.real(f), Scalar::Real),
+            Constant::String(_) => {
+                return Err(LowerError::Unsupported(String::from(
+                    "STRING literals",
+                )))
+            }
+        };
+
+        Ok(Item::Scalar(value))
+    }
+
+    fn value(
+        &mut self,
+        item: ItemOf<'a, Self>,
+    ) -> Result<Typed<Self::Value>, LowerError> {
+        match item {
+            Item::Scalar(value) => Ok(value),
+            Item::Aggregate(place) => Err(LowerError::TypeMismatch {
+                expected: String::from("value"),
+                found: self.place_type(&place).to_string(),
+            }),
+        }
+    }
+
+    /// Get a scalar as a particular type without any conversions.
+    fn scalar(
+        &mut self,
+        item: ItemOf<'a, Self>,
+        ty: Scalar,
+    ) -> Result<Self::Value, LowerError> {
+        match item {
+            Item::Scalar(value) if value.ty == ty => Ok(value.value),
+            Item::Scalar(value) => Err(LowerError::TypeMismatch {
+                expected: ty.to_string(),
+                found: value.ty.to_string(),
+            }),
+            Item::Aggregate(place) => Err(LowerError::TypeMismatch {
+                expected: ty.to_string(),
+                found: self.place_type(&place).to_string(),
+            }),
+        }
+    }
+
+    /// Convert a value so it can be stored as `ty`, wrapping integers which
+    /// don't fit.
+    fn convert(
+        &mut self,
+        value: &Typed<Self::Value>,
+        ty: Scalar,
+    ) -> Result<Self::Value, LowerError> {
+        match (value.ty, ty) {
+            (from, to) if from == to => Ok(value.value.clone()),
+            (Scalar::Integer(_), Scalar::Integer(to)) => {
+                Ok(self.resize(value, to))
+            }
+            (Scalar::Integer(_), Scalar::Real) => Ok(self.int_to_real(value)),
+            (found, expected) => Err(LowerError::TypeMismatch {
+                expected: expected.to_string(),
+                found: found.to_string(),
+            }),
+        }
+    }
+
+    fn binary(
+        &mut self,
+        op: BinaryOp,
+        left: ItemOf<'a, Self>,
+        right: ItemOf<'a, Self>,
+    ) -> Result<Typed<Self::Value>, LowerError> {
+        if let (Item::Aggregate(l), Item::Aggregate(r)) = (&left, &right) {
+            return self.aggregate_binary(op, l, r);
+        }
+
+        let left = self.value(left)?;
+        let right = self.value(right)?;
+
+        match (left.ty, right.ty) {
+            (Scalar::Bool, Scalar::Bool) => {
+                let op = match op {
+                    BinaryOp::And => BoolOp::And,
+                    BinaryOp::Or => BoolOp::Or,
+                    BinaryOp::Xor => BoolOp::Xor,
+                    BinaryOp::Equals => BoolOp::Equal,
+                    BinaryOp::NotEquals => BoolOp::NotEqual,
+                    _ => return Err(invalid_operands(op, Scalar::Bool)),
+                };
+                let value = self.bool_binary(op, &left.value, &right.value);
+                Ok(Typed::new(value, Scalar::Bool))
+            }
+            (Scalar::Integer(_), Scalar::Integer(_)) => {
+                self.integer_binary(op, &left, &right)
+            }
+            (Scalar::Real, _) | (_, Scalar::Real) => {
+                let l = self.convert(&left, Scalar::Real)?;
+                let r = self.convert(&right, Scalar::Real)?;
+                self.real_binary(op, &l, &r)
+            }
+            (l, r) => Err(LowerError::TypeMismatch {
+                expected: l.to_string(),
+                found: r.to_string(),
+            }),
+        }
+    }
+
+    /// Integer arithmetic is done on the operands' common type, trapping on
+    /// division by zero and negative exponents.
+    fn integer_binary(
+        &mut self,
+        op: BinaryOp,
+        left: &Typed<Self::Value>,
+        right: &Typed<Self::Value>,
+    ) -> Result<Typed<Self::Value>, LowerError> {
+        let (lt, rt) = match (left.ty, right.ty) {
+            (Scalar::Integer(lt), Scalar::Integer(rt)) => (lt, rt),
+            _ => unreachable!("both operands are integers"),
+        };
+        let ty = lt.common(rt);
+        let unsigned = lt.compares_unsigned(rt);
+
+        if let Some(comparison) = Comparison::from_op(op) {
+            let value =
+                self.compare_integers(comparison, left, right, unsigned);
+            return Ok(Typed::new(value, Scalar::Bool));
+        }
+
+        let op = match op {
+            BinaryOp::Add => IntegerOp::Add,
+            BinaryOp::Subtract => IntegerOp::Subtract,
+            BinaryOp::Multiply => IntegerOp::Multiply,
+            BinaryOp::And => IntegerOp::And,
+            BinaryOp::Or => IntegerOp::Or,
+            BinaryOp::Xor => IntegerOp::Xor,
+            BinaryOp::Divide | BinaryOp::Modulo => {
+                let is_zero = self.is_zero(right);
+                self.trap_if(is_zero, Trap::DivisionByZero);
+
+                if op == BinaryOp::Divide {
+                    IntegerOp::Divide
+                } else {
+                    IntegerOp::Modulo
+                }
+            }
+            BinaryOp::Exponent => {
+                if rt.is_signed() {
+                    let negative = self.is_negative(right);
+                    self.trap_if(negative, Trap::NegativeExponent);
+                }
+                IntegerOp::Exponent
+            }
+            _ => return Err(invalid_operands(op, Scalar::Integer(ty))),
+        };
+
+        let value = self.integer_arithmetic(op, left, right, ty, unsigned);
+        Ok(Typed::new(value, Scalar::Integer(ty)))
+    }
+
+    fn real_binary(
+        &mut self,
+        op: BinaryOp,
+        left: &Self::Value,
+        right: &Self::Value,
+    ) -> Result<Typed<Self::Value>, LowerError> {
+        if let Some(comparison) = Comparison::from_op(op) {
+            let value = self.compare_reals(comparison, left, right);
+            return Ok(Typed::new(value, Scalar::Bool));
+        }
+
+        let op = match op {
+            BinaryOp::Add => RealOp::Add,
+            BinaryOp::Subtract => RealOp::Subtract,
+            BinaryOp::Multiply => RealOp::Multiply,
+            BinaryOp::Divide => {
+                let divisor = Typed::new(right.clone(), Scalar::Real);
+                let is_zero = self.is_zero(&divisor);
+                self.trap_if(is_zero, Trap::DivisionByZero);
+                RealOp::Divide
+            }
+            BinaryOp::Exponent => {
+                return Err(LowerError::Unsupported(String::from(
+                    "REAL exponents",
+                )))
+            }
+            _ => return Err(invalid_operands(op, Scalar::Real)),
+        };
+
+        let value = self.real_arithmetic(op, left, right);
+        Ok(Typed::new(value, Scalar::Real))
+    }
+
+    fn unary(
+        &mut self,
+        op: UnaryOp,
+        item: ItemOf<'a, Self>,
+    ) -> Result<Typed<Self::Value>, LowerError> {
+        let value = self.value(item)?;
+
+        let result = match (op, value.ty) {
+            (UnaryOp::Not, Scalar::Bool)
+            | (UnaryOp::Not, Scalar::Integer(_)) => self.not(&value),
+            (UnaryOp::Negate, Scalar::Integer(_))
+            | (UnaryOp::Negate, Scalar::Real) => self.negate(&value),
+            (_, ty) => {
+                return Err(LowerError::TypeMismatch {
+                    expected: String::from("number"),
+                    found: ty.to_string(),
+                })
+            }
+        };
+
+        Ok(Typed::new(result, value.ty))
+    }
+
+    /// Call a POU, copying arguments into its frame beforehand and copying
+    /// any outputs (and anything passed by reference) back out afterwards.
+    fn call(
+        &mut self,
+        target: Symbol,
+        instance: Option<EntityId>,
+        args: &[Argument],
+        dest: Option<EntityId>,
+    ) -> Result<(), LowerError> {
+        let pou = match self.pou_index(target) {
+            Some(pou) => pou,
+            None => return self.builtin(target, args, dest),
+        };
+        if let Symbol::Program(_) = target {
+            return Err(LowerError::Unsupported(String::from(
+                "Calls to programs",
+            )));
+        }
+
+        let instance = instance.map(|instance| self.place(instance));
+        let frame = self.begin_call(pou, instance);
+
+        for arg in args {
+            match *arg {
+                Argument::Input { parameter, value } => {
+                    let value = self.operand(value)?;
+                    let parameter = self.member(&frame, parameter);
+                    self.write(&parameter, value)?;
+                }
+                Argument::InOut {
+                    parameter,
+                    variable,
+                } => {
+                    let variable = self.place(variable);
+                    let value = self.read(&variable);
+                    let parameter = self.member(&frame, parameter);
+                    self.write(&parameter, value)?;
+                }
+                Argument::Output { .. } => {}
+            }
+        }
+
+        self.invoke(pou, &frame);
+
+        for arg in args {
+            match *arg {
+                Argument::InOut {
+                    parameter,
+                    variable,
+                }
+                | Argument::Output {
+                    parameter,
+                    variable,
+                } => {
+                    let parameter = self.member(&frame, parameter);
+                    let value = self.read(&parameter);
+                    let dest = self.place(variable);
+                    self.write(&dest, value)?;
+                }
+                Argument::Input { .. } => {}
+            }
+        }
+
+        if let (Some(dest), Some(result)) = (dest, self.return_value(target)) {
+            let result = self.member(&frame, result);
+            let value = self.read(&result);
+            let dest = self.place(dest);
+            self.write(&dest, value)?;
+        }
+
+        self.end_call(pou);
+
+        Ok(())
+    }
+
+    /// The variable a function's result is stored in, if it has one.
+    fn return_value(&self, target: Symbol) -> Option<EntityId> {
+        let function = match target {
+            Symbol::Function(id) => self.tables().functions.get(id)?,
+            _ => return None,
+        };
+
+        if function.variables.contains(&function.return_value) {
+            Some(function.return_value)
+        } else {
+            None
+        }
+    }
+
+    /// Builtins are always inlined instead of being called.
+    fn builtin(
+        &mut self,
+        target: Symbol,
+        args: &[Argument],
+        dest: Option<EntityId>,
+    ) -> Result<(), LowerError> {
+        let functions = self.tables().functions;
+        let function = match target {
+            Symbol::Function(id) => functions.get(id),
+            _ => None,
+        }
+        .expect("only builtin functions aren't laid out");
+
+        let operands = builtin_arguments(function, args).ok_or_else(|| {
+            LowerError::Unsupported(format!(
+                "Calls to \"{}\" with missing arguments",
+                function.name
+            ))
+        })?;
+        let mut values = Vec::new();
+        for operand in operands {
+            let item = self.operand(operand)?;
+            values.push(self.value(item)?);
+        }
+
+        let dest = dest.map(|d| self.place(d));
+        let result_type =
+            dest.as_ref().and_then(|d| self.place_type(d).scalar());
+        let result = self.builtin_value(&function.name, values, result_type)?;
+
+        match dest {
+            Some(dest) => self.write(&dest, Item::Scalar(result)),
+            None => Ok(()),
+        }
+    }
+
+    fn builtin_value(
+        &mut self,
+        name: &str,
+        args: Vec<Typed<Self::Value>>,
+        result_type: Option<Scalar>,
+    ) -> Result<Typed<Self::Value>, LowerError> {
+        match (Builtin::from_name(name), args.as_slice()) {
+            (Some(Builtin::Abs), [x]) => match x.ty {
+                Scalar::Integer(int) if !int.is_signed() => Ok(x.clone()),
+                Scalar::Integer(_) | Scalar::Real => {
+                    Ok(Typed::new(self.abs(x), x.ty))
+                }
+                Scalar::Bool => Err(invalid_operands("ABS", x.ty)),
+            },
+            (Some(Builtin::Min), [a, b]) => self.pick(a, b, true),
+            (Some(Builtin::Max), [a, b]) => self.pick(a, b, false),
+            (Some(Builtin::Limit), [mn, x, mx]) => {
+                let upper = self.pick(x, mx, true)?;
+                self.pick(mn, &upper, false)
+            }
+            (Some(Builtin::Sel), [g, in0, in1]) => {
+                let g = self.scalar(Item::Scalar(g.clone()), Scalar::Bool)?;
+                let ty = result_type.unwrap_or(in0.ty);
+                let in0 = Typed::new(self.convert(in0, ty)?, ty);
+                let in1 = Typed::new(self.convert(in1, ty)?, ty);
+                Ok(Typed::new(self.select(&g, &in1, &in0), ty))
+            }
+            (Some(Builtin::Shift(shift)), [x, n]) => {
+                let (ty, nt) = match (x.ty, n.ty) {
+                    (Scalar::Integer(ty), Scalar::Integer(nt)) => (ty, nt),
+                    _ => return Err(invalid_operands(shift.name(), x.ty)),
+                };
+
+                if nt.is_signed() {
+                    let negative = self.is_negative(n);
+                    self.trap_if(negative, Trap::InvalidOperands);
+                }
+
+                let value = self.shift(shift, x, n);
+                Ok(Typed::new(value, Scalar::Integer(ty)))
+            }
+            _ => Err(LowerError::Unsupported(format!(
+                "Calls to the \"{}\" builtin",
+                name
+            ))),
+        }
+    }
+
+    /// `MIN()` and `MAX()` become a comparison and a select. Integers are
+    /// compared using their original types, then widened to 64 bits so both
+    /// sides of the select have the same type.
+    fn pick(
+        &mut self,
+        a: &Typed<Self::Value>,
+        b: &Typed<Self::Value>,
+        smallest: bool,
+    ) -> Result<Typed<Self::Value>, LowerError> {
+        let comparison = if smallest {
+            Comparison::LessOrEqual
+        } else {
+            Comparison::GreaterOrEqual
+        };
+
+        match (a.ty, b.ty) {
+            (Scalar::Integer(at), Scalar::Integer(bt)) => {
+                let ty = Scalar::Integer(at.common(bt));
+                let unsigned = at.compares_unsigned(bt);
+                let keep_a = self.compare_integers(comparison, a, b, unsigned);
+
+                let wide = Scalar::Integer(IntegerType::LInt);
+                let l = Typed::new(self.convert(a, wide)?, wide);
+                let r = Typed::new(self.convert(b, wide)?, wide);
+                let picked = Typed::new(self.select(&keep_a, &l, &r), wide);
+
+                Ok(Typed::new(self.convert(&picked, ty)?, ty))
+            }
+            _ => {
+                let l =
+                    Typed::new(self.convert(a, Scalar::Real)?, Scalar::Real);
+                let r =
+                    Typed::new(self.convert(b, Scalar::Real)?, Scalar::Real);
+                let keep_a = self.compare_reals(comparison, &l.value, &r.value);
+
+                Ok(Typed::new(self.select(&keep_a, &l, &r), Scalar::Real))
+            }
+        }
+    }
+}
+
+/// Something in the HIR which a backend can't translate.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LowerError {
+    Unsupported(String),
+    /// An operation was applied to values of the wrong type. This should
+    /// have been caught during semantic analysis.
+    TypeMismatch {
+        expected: String,
+        found: String,
+    },
+}
+
+impl Display for LowerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            LowerError::Unsupported(ref what) => {
+                write!(f, "{} aren't supported", what)
+            }
+            LowerError::TypeMismatch {
+                ref expected,
+                ref found,
+            } => write!(f, "Expected a {} but found a {}", expected, found),
+        }
+    }
+}
+
+impl Error for LowerError {}
+
+fn invalid_operands<D: Debug>(operation: D, ty: Scalar) -> LowerError {
+    LowerError::TypeMismatch {
+        expected: format!("operand for {:?}", operation),
+        found: ty.to_string(),
+    }
+}
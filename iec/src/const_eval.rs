@@ -0,0 +1,600 @@
+//! Evaluation of constant expressions.
+//!
+//! Some parts of a program must be known at compile time (array bounds,
+//! `CASE` labels, subranges, `CONSTANT` initializers), and it's often useful
+//! to pre-compute arithmetic involving literals. This module implements the
+//! arithmetic using the same rules as the IEC 61131-3 integer types, so an
+//! expression which would overflow at runtime is also rejected here.
+
+use crate::hir::{BinaryOp, Constant, UnaryOp};
+use crate::passes::basic_blocks::binary_op;
+use codespan::ByteSpan;
+use codespan_reporting::{Diagnostic, Label};
+use iec_syntax::{AstNode, DottedIdentifier, Expression, LiteralKind};
+use std::fmt::{self, Display, Formatter};
+
+/// The integer types defined by IEC 61131-3.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum IntegerType {
+    SInt,
+    Int,
+    DInt,
+    LInt,
+    USInt,
+    UInt,
+    UDInt,
+    ULInt,
+    Byte,
+    Word,
+    DWord,
+    LWord,
+}
+
+impl IntegerType {
+    /// Look up an integer type by name (case insensitive).
+    pub fn from_name(name: &str) -> Option<IntegerType> {
+        let ty = match name.to_lowercase().as_str() {
+            "sint" => IntegerType::SInt,
+            "int" => IntegerType::Int,
+            "dint" => IntegerType::DInt,
+            "lint" => IntegerType::LInt,
+            "usint" => IntegerType::USInt,
+            "uint" => IntegerType::UInt,
+            "udint" => IntegerType::UDInt,
+            "ulint" => IntegerType::ULInt,
+            "byte" => IntegerType::Byte,
+            "word" => IntegerType::Word,
+            "dword" => IntegerType::DWord,
+            "lword" => IntegerType::LWord,
+            _ => return None,
+        };
+
+        Some(ty)
+    }
+
+    /// The integer type an untyped literal gets, which is `DINT` unless the
+    /// value is too big.
+    pub fn for_literal(value: i128) -> IntegerType {
+        [IntegerType::DInt, IntegerType::LInt, IntegerType::ULInt]
+            .iter()
+            .cloned()
+            .find(|ty| ty.contains(value))
+            .unwrap_or(IntegerType::ULInt)
+    }
+
+    pub fn bits(self) -> u32 {
+        match self {
+            IntegerType::SInt | IntegerType::USInt | IntegerType::Byte => 8,
+            IntegerType::Int | IntegerType::UInt | IntegerType::Word => 16,
+            IntegerType::DInt | IntegerType::UDInt | IntegerType::DWord => 32,
+            IntegerType::LInt | IntegerType::ULInt | IntegerType::LWord => 64,
+        }
+    }
+
+    pub fn is_signed(self) -> bool {
+        matches!(
+            self,
+            IntegerType::SInt
+                | IntegerType::Int
+                | IntegerType::DInt
+                | IntegerType::LInt
+        )
+    }
+
+    pub fn min(self) -> i128 {
+        if self.is_signed() {
+            -(1 << (self.bits() - 1))
+        } else {
+            0
+        }
+    }
+
+    pub fn max(self) -> i128 {
+        if self.is_signed() {
+            (1 << (self.bits() - 1)) - 1
+        } else {
+            (1 << self.bits()) - 1
+        }
+    }
+
+    /// Can this type hold `value`?
+    pub fn contains(self, value: i128) -> bool {
+        self.min() <= value && value <= self.max()
+    }
+
+    /// Reduce a value modulo the size of this type, the same way two's
+    /// complement arithmetic overflows.
+    pub fn wrap(self, value: i128) -> i128 {
+        let modulus = 1_i128 << self.bits();
+        let wrapped = value.rem_euclid(modulus);
+
+        if wrapped > self.max() {
+            wrapped - modulus
+        } else {
+            wrapped
+        }
+    }
+
+    /// Clamp a value to the range this type can hold.
+    pub fn saturate(self, value: i128) -> i128 {
+        value.clamp(self.min(), self.max())
+    }
+
+    /// The type both operands of a binary operation are implicitly converted
+    /// to (the wider of the two).
+    pub fn common(self, other: IntegerType) -> IntegerType {
+        if other.bits() > self.bits() {
+            other
+        } else {
+            self
+        }
+    }
+
+    /// Should a comparison between the two types be done on their unsigned
+    /// representations? Mixing signed and unsigned integers compares them as
+    /// signed.
+    pub fn compares_unsigned(self, other: IntegerType) -> bool {
+        !self.is_signed() && !other.is_signed()
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            IntegerType::SInt => "SINT",
+            IntegerType::Int => "INT",
+            IntegerType::DInt => "DINT",
+            IntegerType::LInt => "LINT",
+            IntegerType::USInt => "USINT",
+            IntegerType::UInt => "UINT",
+            IntegerType::UDInt => "UDINT",
+            IntegerType::ULInt => "ULINT",
+            IntegerType::Byte => "BYTE",
+            IntegerType::Word => "WORD",
+            IntegerType::DWord => "DWORD",
+            IntegerType::LWord => "LWORD",
+        }
+    }
+}
+
+impl Display for IntegerType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// The types a value can have while a backend is holding it in a register.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Scalar {
+    Bool,
+    Integer(IntegerType),
+    Real,
+}
+
+impl Display for Scalar {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            Scalar::Bool => write!(f, "BOOL"),
+            Scalar::Integer(int) => write!(f, "{}", int),
+            Scalar::Real => write!(f, "LREAL"),
+        }
+    }
+}
+
+/// A value which is known at compile time.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Integer { value: i128, ty: IntegerType },
+    Real(f64),
+    String(String),
+}
+
+impl Value {
+    /// Create an integer value, making sure it fits in the type.
+    pub fn integer(value: i128, ty: IntegerType) -> Result<Value, EvalError> {
+        if ty.contains(value) {
+            Ok(Value::Integer { value, ty })
+        } else {
+            Err(EvalError::Overflow(ty.name()))
+        }
+    }
+
+    /// Interpret a [`Constant`], using `ty` for integers (or the literal's
+    /// default type when there's no better information).
+    pub fn from_constant(
+        constant: &Constant,
+        ty: Option<IntegerType>,
+    ) -> Result<Value, EvalError> {
+        match *constant {
+            Constant::Boolean(b) => Ok(Value::Bool(b)),
+            Constant::Integer(i) => {
+                let value = i128::from(i);
+                let ty = ty.unwrap_or_else(|| IntegerType::for_literal(value));
+                Value::integer(value, ty)
+            }
+            Constant::Float(f) => Ok(Value::Real(f)),
+            Constant::String(ref s) => Ok(Value::String(s.clone())),
+        }
+    }
+
+    /// Convert back to a [`Constant`], if it can be represented.
+    pub fn to_constant(&self) -> Option<Constant> {
+        match *self {
+            Value::Bool(b) => Some(Constant::Boolean(b)),
+            Value::Integer { value, .. } => {
+                if i128::from(i64::MIN) <= value
+                    && value <= i128::from(i64::MAX)
+                {
+                    Some(Constant::Integer(value as i64))
+                } else {
+                    None
+                }
+            }
+            Value::Real(f) => Some(Constant::Float(f)),
+            Value::String(ref s) => Some(Constant::String(s.clone())),
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<i128> {
+        match *self {
+            Value::Integer { value, .. } => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Value::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    fn as_real(&self) -> Option<f64> {
+        match *self {
+            Value::Integer { value, .. } => Some(value as f64),
+            Value::Real(f) => Some(f),
+            _ => None,
+        }
+    }
+}
+
+/// Why a constant expression couldn't be evaluated.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalError {
+    DivisionByZero,
+    /// The result doesn't fit in the named type.
+    Overflow(&'static str),
+    NegativeExponent,
+    /// The operator can't be applied to these operands.
+    InvalidOperands,
+    /// The expression refers to something which isn't known at compile
+    /// time.
+    NotConstant,
+}
+
+impl EvalError {
+    pub fn to_diagnostic(&self, span: ByteSpan) -> Diagnostic {
+        let (message, label) = match *self {
+            EvalError::DivisionByZero => (
+                "Division by zero",
+                "attempted to divide by zero".to_string(),
+            ),
+            EvalError::Overflow(ty) => (
+                "Arithmetic overflow",
+                format!("the result doesn't fit in a {}", ty),
+            ),
+            EvalError::NegativeExponent => (
+                "Integers can't be raised to a negative power",
+                "the exponent is negative".to_string(),
+            ),
+            EvalError::InvalidOperands => (
+                "Invalid operands",
+                "this operation can't be applied to these values".to_string(),
+            ),
+            EvalError::NotConstant => (
+                "Expected a constant expression",
+                "this isn't known at compile time".to_string(),
+            ),
+        };
+
+        Diagnostic::new_error(message)
+            .with_label(Label::new_primary(span).with_message(label))
+    }
+}
+
+/// Evaluate an expression at compile time, using `names` to get the value of
+/// any named constants.
+pub fn evaluate<F>(expr: &Expression, names: &F) -> Result<Value, Diagnostic>
+where
+    F: Fn(&DottedIdentifier) -> Option<Value>,
+{
+    match *expr {
+        Expression::Literal(ref lit) => match lit.kind {
+            LiteralKind::Boolean(b) => Ok(Value::Bool(b)),
+            LiteralKind::Integer(i) => {
+                let value = i128::from(i);
+                Ok(Value::Integer {
+                    value,
+                    ty: IntegerType::for_literal(value),
+                })
+            }
+            LiteralKind::Float(f) => Ok(Value::Real(f)),
+            LiteralKind::String(ref s) => Ok(Value::String(s.clone())),
+        },
+        Expression::Variable(ref name) => names(name)
+            .ok_or_else(|| EvalError::NotConstant.to_diagnostic(name.span)),
+        Expression::DirectAddress(ref address) => {
+            Err(EvalError::NotConstant.to_diagnostic(address.span))
+        }
+        Expression::Binary(ref bin) => {
+            let left = evaluate(&bin.left, names)?;
+            let right = evaluate(&bin.right, names)?;
+
+            binary(binary_op(bin.op), &left, &right)
+                .map_err(|e| e.to_diagnostic(bin.span))
+        }
+        Expression::Unary(ref unary_expr) => {
+            let value = evaluate(&unary_expr.value, names)?;
+            let op = match unary_expr.op {
+                iec_syntax::UnaryOp::Not => UnaryOp::Not,
+                iec_syntax::UnaryOp::Negate => UnaryOp::Negate,
+            };
+
+            unary(op, &value).map_err(|e| e.to_diagnostic(unary_expr.span))
+        }
+        Expression::FunctionCall(ref call) => {
+            Err(EvalError::NotConstant.to_diagnostic(call.span()))
+        }
+    }
+}
+
+/// Apply a binary operator to two known values.
+pub fn binary(
+    op: BinaryOp,
+    left: &Value,
+    right: &Value,
+) -> Result<Value, EvalError> {
+    match (left, right) {
+        (
+            Value::Integer { value: l, ty: lt },
+            Value::Integer { value: r, ty: rt },
+        ) => integer_binary(op, *l, *r, lt.common(*rt)),
+        (Value::Bool(l), Value::Bool(r)) => bool_binary(op, *l, *r),
+        (Value::String(l), Value::String(r)) => {
+            compare(op, l, r).map(Value::Bool)
+        }
+        _ => match (left.as_real(), right.as_real()) {
+            (Some(l), Some(r)) => real_binary(op, l, r),
+            _ => Err(EvalError::InvalidOperands),
+        },
+    }
+}
+
+/// Apply a unary operator to a known value.
+pub fn unary(op: UnaryOp, value: &Value) -> Result<Value, EvalError> {
+    match (op, value) {
+        (UnaryOp::Not, &Value::Bool(b)) => Ok(Value::Bool(!b)),
+        (UnaryOp::Not, &Value::Integer { value, ty }) => {
+            let inverted = if ty.is_signed() {
+                !value
+            } else {
+                !value & ty.max()
+            };
+            Value::integer(inverted, ty)
+        }
+        (UnaryOp::Negate, &Value::Integer { value, ty }) => {
+            Value::integer(-value, ty)
+        }
+        (UnaryOp::Negate, &Value::Real(f)) => Ok(Value::Real(-f)),
+        _ => Err(EvalError::InvalidOperands),
+    }
+}
+
+fn integer_binary(
+    op: BinaryOp,
+    left: i128,
+    right: i128,
+    ty: IntegerType,
+) -> Result<Value, EvalError> {
+    let overflow = EvalError::Overflow(ty.name());
+
+    let value = match op {
+        BinaryOp::Add => left + right,
+        BinaryOp::Subtract => left - right,
+        BinaryOp::Multiply => left.checked_mul(right).ok_or(overflow)?,
+        // both of these truncate towards zero, and the remainder has the
+        // same sign as the dividend
+        BinaryOp::Divide | BinaryOp::Modulo if right == 0 => {
+            return Err(EvalError::DivisionByZero)
+        }
+        BinaryOp::Divide => left / right,
+        BinaryOp::Modulo => left % right,
+        BinaryOp::Exponent => {
+            if right < 0 {
+                return Err(EvalError::NegativeExponent);
+            }
+            if right > i128::from(u32::MAX) {
+                return Err(overflow);
+            }
+            left.checked_pow(right as u32).ok_or(overflow)?
+        }
+        BinaryOp::And => left & right,
+        BinaryOp::Or => left | right,
+        BinaryOp::Xor => left ^ right,
+        _ => return compare(op, &left, &right).map(Value::Bool),
+    };
+
+    Value::integer(value, ty)
+}
+
+fn real_binary(
+    op: BinaryOp,
+    left: f64,
+    right: f64,
+) -> Result<Value, EvalError> {
+    let value = match op {
+        BinaryOp::Add => left + right,
+        BinaryOp::Subtract => left - right,
+        BinaryOp::Multiply => left * right,
+        BinaryOp::Divide if right == 0.0 => {
+            return Err(EvalError::DivisionByZero)
+        }
+        BinaryOp::Divide => left / right,
+        BinaryOp::Exponent => left.powf(right),
+        BinaryOp::Modulo | BinaryOp::And | BinaryOp::Or | BinaryOp::Xor => {
+            return Err(EvalError::InvalidOperands)
+        }
+        _ => return compare(op, &left, &right).map(Value::Bool),
+    };
+
+    if value.is_infinite() && left.is_finite() && right.is_finite() {
+        Err(EvalError::Overflow("LREAL"))
+    } else {
+        Ok(Value::Real(value))
+    }
+}
+
+pub(crate) fn bool_binary(
+    op: BinaryOp,
+    left: bool,
+    right: bool,
+) -> Result<Value, EvalError> {
+    match op {
+        BinaryOp::And => Ok(Value::Bool(left && right)),
+        BinaryOp::Or => Ok(Value::Bool(left || right)),
+        BinaryOp::Xor => Ok(Value::Bool(left ^ right)),
+        BinaryOp::Equals => Ok(Value::Bool(left == right)),
+        BinaryOp::NotEquals => Ok(Value::Bool(left != right)),
+        _ => Err(EvalError::InvalidOperands),
+    }
+}
+
+pub(crate) fn compare<T: PartialOrd + ?Sized>(
+    op: BinaryOp,
+    left: &T,
+    right: &T,
+) -> Result<bool, EvalError> {
+    match op {
+        BinaryOp::Equals => Ok(left == right),
+        BinaryOp::NotEquals => Ok(left != right),
+        BinaryOp::LessThan => Ok(left < right),
+        BinaryOp::LessThanOrEqual => Ok(left <= right),
+        BinaryOp::GreaterThan => Ok(left > right),
+        BinaryOp::GreaterThanOrEqual => Ok(left >= right),
+        _ => Err(EvalError::InvalidOperands),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(src: &str) -> Result<Value, Diagnostic> {
+        let expr: Expression = src.parse().unwrap();
+        evaluate(&expr, &|name: &DottedIdentifier| {
+            if name.pieces.len() == 1 && name.pieces[0].value == "max_items" {
+                Some(Value::Integer {
+                    value: 10,
+                    ty: IntegerType::Int,
+                })
+            } else {
+                None
+            }
+        })
+    }
+
+    fn int(value: i128, ty: IntegerType) -> Value {
+        Value::Integer { value, ty }
+    }
+
+    #[test]
+    fn integer_ranges() {
+        assert_eq!(IntegerType::SInt.min(), -128);
+        assert_eq!(IntegerType::SInt.max(), 127);
+        assert_eq!(IntegerType::UInt.max(), 65535);
+        assert_eq!(IntegerType::LInt.min(), i128::from(i64::MIN));
+        assert_eq!(IntegerType::ULInt.max(), i128::from(u64::MAX));
+        assert_eq!(IntegerType::from_name("DWord"), Some(IntegerType::DWord));
+        assert_eq!(IntegerType::from_name("real"), None);
+    }
+
+    #[test]
+    fn wrapping_and_saturating() {
+        assert_eq!(IntegerType::SInt.wrap(128), -128);
+        assert_eq!(IntegerType::SInt.wrap(-129), 127);
+        assert_eq!(IntegerType::USInt.wrap(-1), 255);
+        assert_eq!(IntegerType::ULInt.wrap(-1), i128::from(u64::MAX));
+        assert_eq!(
+            IntegerType::LInt.wrap(i128::from(i64::MAX) + 1),
+            i128::from(i64::MIN)
+        );
+        assert_eq!(IntegerType::SInt.saturate(300), 127);
+        assert_eq!(IntegerType::UInt.saturate(-5), 0);
+        assert_eq!(IntegerType::Int.saturate(42), 42);
+    }
+
+    #[test]
+    fn evaluate_simple_arithmetic() {
+        let inputs = vec![
+            ("1 + 2 * 3", int(7, IntegerType::DInt)),
+            ("7 / 2", int(3, IntegerType::DInt)),
+            ("-7 / 2", int(-3, IntegerType::DInt)),
+            ("-7 % 2", int(-1, IntegerType::DInt)),
+            ("2 ** 10", int(1024, IntegerType::DInt)),
+            ("max_items - 1", int(9, IntegerType::DInt)),
+            ("1 < 2 AND NOT FALSE", Value::Bool(true)),
+        ];
+
+        for (src, should_be) in inputs {
+            let got = eval(src).unwrap();
+            assert_eq!(got, should_be, "{}", src);
+        }
+
+        let got = binary(
+            BinaryOp::Multiply,
+            &Value::Real(1.5),
+            &int(2, IntegerType::Int),
+        );
+        assert_eq!(got, Ok(Value::Real(3.0)));
+    }
+
+    #[test]
+    fn overflow_is_detected() {
+        let got = binary(
+            BinaryOp::Add,
+            &int(32767, IntegerType::Int),
+            &int(1, IntegerType::Int),
+        );
+        assert_eq!(got, Err(EvalError::Overflow("INT")));
+
+        let got = unary(UnaryOp::Negate, &int(5, IntegerType::UDInt));
+        assert_eq!(got, Err(EvalError::Overflow("UDINT")));
+
+        let got = unary(UnaryOp::Not, &int(0, IntegerType::Byte));
+        assert_eq!(got, Ok(int(255, IntegerType::Byte)));
+
+        let diag = eval("2147483647 + 1").unwrap_err();
+        assert_eq!(diag.message, "Arithmetic overflow");
+    }
+
+    #[test]
+    fn literals_are_widened_when_they_dont_fit() {
+        let got = eval("5000000000 + 1").unwrap();
+
+        assert_eq!(got, int(5_000_000_001, IntegerType::LInt));
+    }
+
+    #[test]
+    fn division_by_zero_points_at_the_expression() {
+        let diag = eval("10 / (max_items - 10)").unwrap_err();
+
+        assert_eq!(diag.message, "Division by zero");
+        assert_eq!(diag.labels.len(), 1);
+    }
+
+    #[test]
+    fn non_constant_expressions_are_rejected() {
+        let diag = eval("unknown + 1").unwrap_err();
+
+        assert_eq!(diag.message, "Expected a constant expression");
+    }
+}
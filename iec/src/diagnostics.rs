@@ -13,6 +13,11 @@ impl Diagnostics {
         self.0.push(diag);
     }
 
+    /// Add the diagnostics from somewhere else.
+    pub fn extend(&mut self, other: Diagnostics) {
+        self.0.extend(other.0);
+    }
+
     fn has(&self, severity: Severity) -> bool {
         self.0.iter().any(|diag| diag.severity >= severity)
     }
@@ -3,14 +3,18 @@
 
 use heapsize::HeapSizeOf;
 use heapsize_derive::HeapSizeOf;
+use serde::de::{DeserializeOwned, DeserializeSeed, Deserializer};
+use serde::ser::{Serialize, Serializer};
 use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
 use std::any::{Any, TypeId};
-use std::cell::{Ref, RefCell, RefMut};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
 use std::fmt::{self, Formatter};
-use std::rc::Rc;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{
+    Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    TryLockError,
+};
 use typename::TypeName;
 
 /// An opaque ID used to represent an entity.
@@ -18,21 +22,21 @@ use typename::TypeName;
 /// The normal method of creating an [`EntityId`] is to add it to a
 /// [`Container`] with [`Container::insert()`]. [`Default::default()`] yields a
 /// "placeholder" [`EntityId`] which can be used when a temporary [`EntityId`]
-/// is required so that the real value can be filled in at a later time.
+/// is required so that the real value can be filled in at a later time. The
+/// placeholder will never be equal to a real [`EntityId`].
+///
+/// When an entity is deleted its index may be reused, so each [`EntityId`]
+/// also records which "generation" of the index it refers to. That way
+/// handles to a deleted entity won't accidentally refer to whatever gets
+/// created in its place.
 #[derive(
-    Default,
-    Copy,
-    Clone,
-    PartialEq,
-    Eq,
-    Hash,
-    Ord,
-    PartialOrd,
-    HeapSizeOf,
-    Serialize,
-    Deserialize,
+    Default, Copy, Clone, PartialEq, Eq, Hash, Ord, PartialOrd, HeapSizeOf,
 )]
-pub struct EntityId(u32);
+pub struct EntityId {
+    index: u32,
+    /// Starts at 1 for real entities, 0 is reserved for the placeholder.
+    generation: u32,
+}
 
 impl EntityId {
     pub fn is_placeholder(&self) -> bool {
@@ -44,7 +48,31 @@ impl Debug for EntityId {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         // deliberately override Debug so we *don't* break lines on alternate
         // output (i.e. `println!("{:#?}", some_entity_id)`).
-        write!(f, "EntityId({})", self.0)
+        if self.is_placeholder() {
+            write!(f, "EntityId(placeholder)")
+        } else {
+            write!(f, "EntityId({}v{})", self.index, self.generation)
+        }
+    }
+}
+
+// EntityIds are often used as map keys, so they're serialized as a single
+// integer instead of a struct
+impl Serialize for EntityId {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        let packed = u64::from(self.generation) << 32 | u64::from(self.index);
+        ser.serialize_u64(packed)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for EntityId {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<EntityId, D::Error> {
+        let packed = u64::deserialize(de)?;
+
+        Ok(EntityId {
+            index: packed as u32,
+            generation: (packed >> 32) as u32,
+        })
     }
 }
 
@@ -55,25 +83,161 @@ impl slog::Value for EntityId {
         key: slog::Key,
         ser: &mut dyn slog::Serializer,
     ) -> slog::Result {
-        ser.emit_u32(key, self.0)
+        ser.emit_arguments(
+            key,
+            &format_args!("{}v{}", self.index, self.generation),
+        )
     }
 }
 
 /// Abstract component type.
-pub trait Component: TypeName + HeapSizeOf + Any + Debug + 'static {}
+///
+/// Components need to be [`Send`] and [`Sync`] so passes which don't
+/// conflict can be executed in parallel.
+pub trait Component:
+    TypeName + HeapSizeOf + Any + Debug + Send + Sync + 'static
+{
+}
 
-impl<C: TypeName + HeapSizeOf + Any + Debug + 'static> Component for C {}
+impl<C> Component for C where
+    C: TypeName + HeapSizeOf + Any + Debug + Send + Sync + 'static
+{
+}
+
+/// A read-only reference to a value inside a [`Resources`].
+pub type Ref<'a, T> = RwLockReadGuard<'a, T>;
+/// A mutable reference to a value inside a [`Resources`].
+pub type RefMut<'a, T> = RwLockWriteGuard<'a, T>;
+
+/// A thread-safe version of [`std::cell::RefCell`].
+///
+/// Accessing a value which is already being used elsewhere is a bug (the
+/// dispatcher never runs passes with conflicting storage in parallel), so
+/// this panics instead of blocking.
+#[derive(Debug, Default)]
+struct LockCell<T>(RwLock<T>);
+
+impl<T> LockCell<T> {
+    fn new(value: T) -> LockCell<T> {
+        LockCell(RwLock::new(value))
+    }
+
+    fn borrow(&self) -> Ref<'_, T> {
+        match self.0.try_read() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => {
+                panic!("Already mutably borrowed")
+            }
+        }
+    }
 
-/// A counter which generates atomically incrementing [`EntityId`]s.
-#[derive(Debug, Default, TypeName, HeapSizeOf)]
+    fn borrow_mut(&self) -> RefMut<'_, T> {
+        match self.0.try_write() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => panic!("Already borrowed"),
+        }
+    }
+}
+
+/// Keeps track of which [`EntityId`]s are alive, handing out the indices of
+/// deleted entities again.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+struct Allocator {
+    /// The current generation of each index.
+    generations: Vec<u32>,
+    /// Indices belonging to deleted entities, ready to be reused.
+    free: Vec<u32>,
+    /// Has anything been deleted since the last [`Resources::maintain()`]?
+    #[serde(skip)]
+    dirty: bool,
+}
+
+/// A shared generator for [`EntityId`]s.
+#[derive(Debug, Default, TypeName)]
 struct EntityGenerator {
-    last_id: AtomicUsize,
+    allocator: Mutex<Allocator>,
 }
 
 impl EntityGenerator {
+    fn allocator(&self) -> MutexGuard<'_, Allocator> {
+        // the allocator is always left in a consistent state, so it's fine
+        // to keep going when a thread panicked while holding the lock
+        self.allocator.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn from_allocator(allocator: Allocator) -> EntityGenerator {
+        EntityGenerator {
+            allocator: Mutex::new(allocator),
+        }
+    }
+
     pub fn next_id(&self) -> EntityId {
-        let next_id = self.last_id.fetch_add(1, Ordering::Relaxed);
-        EntityId(next_id as u32)
+        let mut allocator = self.allocator();
+
+        match allocator.free.pop() {
+            Some(index) => EntityId {
+                index,
+                generation: allocator.generations[index as usize],
+            },
+            None => {
+                let index = allocator.generations.len() as u32;
+                allocator.generations.push(1);
+                EntityId {
+                    index,
+                    generation: 1,
+                }
+            }
+        }
+    }
+
+    fn is_alive(&self, id: EntityId) -> bool {
+        let allocator = self.allocator();
+        allocator.generations.get(id.index as usize) == Some(&id.generation)
+    }
+
+    /// Does this [`EntityId`] refer to an entity which has since been
+    /// deleted?
+    fn is_stale(&self, id: EntityId) -> bool {
+        let allocator = self.allocator();
+
+        match allocator.generations.get(id.index as usize) {
+            Some(&current) => id.generation < current,
+            None => false,
+        }
+    }
+
+    /// Mark an entity as deleted, returning `false` if it was already dead.
+    fn delete(&self, id: EntityId) -> bool {
+        if !self.is_alive(id) {
+            return false;
+        }
+
+        let mut allocator = self.allocator();
+        allocator.generations[id.index as usize] += 1;
+        allocator.free.push(id.index);
+        allocator.dirty = true;
+        true
+    }
+
+    fn snapshot(&self) -> Allocator {
+        self.allocator().clone()
+    }
+
+    /// Check whether anything was deleted since last time, clearing the
+    /// flag.
+    fn take_dirty(&self) -> bool {
+        let mut allocator = self.allocator();
+        std::mem::replace(&mut allocator.dirty, false)
+    }
+}
+
+impl HeapSizeOf for EntityGenerator {
+    fn heap_size_of_children(&self) -> usize {
+        let allocator = self.allocator();
+        allocator.generations.heap_size_of_children()
+            + allocator.free.heap_size_of_children()
     }
 }
 
@@ -96,9 +260,9 @@ impl EntityGenerator {
 /// the component.
 #[derive(Default)]
 pub struct Resources {
-    counter: Rc<EntityGenerator>,
-    items: HashMap<TypeId, Box<Any>>,
-    singletons: HashMap<TypeId, Box<Any>>,
+    counter: Arc<EntityGenerator>,
+    items: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+    singletons: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
     vtables: HashMap<TypeId, ContainerVtable>,
 }
 
@@ -116,11 +280,12 @@ impl Resources {
         self.assert_not_already_registered::<C>();
 
         let type_id = TypeId::of::<C>();
-        let ids = Rc::clone(&self.counter);
+        let ids = Arc::clone(&self.counter);
         let container = Container::<C>::new(ids);
 
-        let boxed_container = Box::new(RefCell::new(container));
-        self.items.insert(type_id, boxed_container as Box<Any>);
+        let boxed_container = Box::new(LockCell::new(container));
+        self.items
+            .insert(type_id, boxed_container as Box<dyn Any + Send + Sync>);
         self.vtables
             .insert(type_id, ContainerVtable::for_component_container::<C>());
     }
@@ -131,7 +296,7 @@ impl Resources {
 
         let type_id = TypeId::of::<C>();
         self.singletons
-            .insert(type_id, Box::new(RefCell::new(C::default())));
+            .insert(type_id, Box::new(LockCell::new(C::default())));
         self.vtables
             .insert(type_id, ContainerVtable::for_singleton::<C>());
     }
@@ -164,7 +329,7 @@ impl Resources {
         }
     }
 
-    fn lookup<C: Component>(&self) -> &RefCell<Container<C>> {
+    fn lookup<C: Component>(&self) -> &LockCell<Container<C>> {
         let type_id = TypeId::of::<C>();
 
         let container = match self.items.get(&type_id) {
@@ -172,7 +337,7 @@ impl Resources {
             None => panic!("Unable to find the container for \"{}\", did you forget to register it?)", C::type_name()),
         };
 
-        match container.downcast_ref::<RefCell<Container<C>>>() {
+        match container.downcast_ref::<LockCell<Container<C>>>() {
             Some(c) => c,
             None => unreachable!(
                 "Something went really wrong when registering \"{}\"",
@@ -181,7 +346,7 @@ impl Resources {
         }
     }
 
-    fn lookup_singleton<C: Component>(&self) -> &RefCell<C> {
+    fn lookup_singleton<C: Component>(&self) -> &LockCell<C> {
         let type_id = TypeId::of::<C>();
 
         let container = match self.singletons.get(&type_id) {
@@ -189,7 +354,7 @@ impl Resources {
             None => panic!("Unable to find the \"{}\" singleton, did you forget to register it?)", C::type_name()),
         };
 
-        match container.downcast_ref::<RefCell<C>>() {
+        match container.downcast_ref::<LockCell<C>>() {
             Some(c) => c,
             None => unreachable!(
                 "Something went really wrong when registering \"{}\"",
@@ -228,6 +393,51 @@ impl Resources {
         let type_id = TypeId::of::<C>();
         self.vtables.contains_key(&type_id)
     }
+
+    /// Is this entity still alive?
+    pub fn is_alive(&self, id: EntityId) -> bool {
+        self.counter.is_alive(id)
+    }
+
+    /// Delete an entity, removing any components attached to it.
+    ///
+    /// Returns `false` if the entity had already been deleted.
+    pub fn delete(&mut self, id: EntityId) -> bool {
+        let deleted = self.counter.delete(id);
+
+        if deleted {
+            self.maintain();
+        }
+
+        deleted
+    }
+
+    /// Remove the components belonging to entities which were deleted (e.g.
+    /// by a [`Pass`] using [`Entities::delete()`]).
+    pub fn maintain(&self) {
+        if !self.counter.take_dirty() {
+            return;
+        }
+
+        for (type_id, container) in &self.items {
+            (self.vtables[type_id].retain_alive)(&**container, &self.counter);
+        }
+    }
+
+    /// Get something which will serialize every component in the
+    /// [`Registry`].
+    ///
+    /// Components which weren't added to the [`Registry`] (e.g. caches
+    /// which can be recalculated from the rest of the world) are skipped.
+    pub fn serializable<'a>(
+        &'a self,
+        registry: &'a Registry,
+    ) -> SerializableResources<'a> {
+        SerializableResources {
+            resources: self,
+            registry,
+        }
+    }
 }
 
 impl Debug for Resources {
@@ -269,12 +479,14 @@ impl HeapSizeOf for Resources {
 
 fn heap_size_of_any(
     item_heapsize: impl Fn(TypeId, &dyn Any) -> usize,
-    container: &HashMap<TypeId, Box<Any>>,
+    container: &HashMap<TypeId, Box<dyn Any + Send + Sync>>,
 ) -> usize {
     use std::mem::size_of;
 
     let overhead = container.capacity()
-        * (size_of::<Box<Any>>() + size_of::<TypeId>() + size_of::<usize>());
+        * (size_of::<Box<dyn Any + Send + Sync>>()
+            + size_of::<TypeId>()
+            + size_of::<usize>());
 
     let item_sizes = container.iter().fold(0, |n, (key, value)| {
         n + key.heap_size_of_children() + item_heapsize(*key, &**value)
@@ -287,12 +499,12 @@ fn heap_size_of_any(
 /// particular [`EntityId`].
 #[derive(Clone, TypeName)]
 pub struct Container<C: Component> {
-    counter: Rc<EntityGenerator>,
+    counter: Arc<EntityGenerator>,
     items: HashMap<EntityId, C>,
 }
 
 impl<C: Component> Container<C> {
-    fn new(counter: Rc<EntityGenerator>) -> Container<C> {
+    fn new(counter: Arc<EntityGenerator>) -> Container<C> {
         Container {
             counter,
             items: HashMap::new(),
@@ -315,6 +527,32 @@ impl<C: Component> Container<C> {
         id
     }
 
+    /// Attach a component to an entity which already exists (e.g. because it
+    /// was created by another [`Container`]), returning the component it
+    /// previously had.
+    ///
+    /// # Panics
+    ///
+    /// The entity mustn't have been deleted.
+    pub fn attach(&mut self, id: EntityId, item: C) -> Option<C> {
+        assert!(
+            !self.counter.is_stale(id),
+            "Attempted to attach a {} to {:?}, which was deleted",
+            C::type_name(),
+            id
+        );
+        self.items.insert(id, item)
+    }
+
+    /// Remove an entity's component, leaving the entity itself alive.
+    pub fn remove(&mut self, id: EntityId) -> Option<C> {
+        self.items.remove(&id)
+    }
+
+    pub fn contains(&self, id: EntityId) -> bool {
+        self.items.contains_key(&id)
+    }
+
     /// Iterate over all the components in this [`Container`].
     pub fn iter<'this>(
         &'this self,
@@ -366,14 +604,26 @@ impl<C: Component> HeapSizeOf for Container<C> {
 
 type DebugFunc = fn(container: &dyn Any, f: &mut Formatter) -> fmt::Result;
 type HeapsizeFunc = fn(container: &dyn Any) -> usize;
+type RetainAliveFunc = fn(container: &dyn Any, entities: &EntityGenerator);
+type ToValueFunc = fn(container: &dyn Any) -> Result<Value, serde_json::Error>;
+type FromValueFunc =
+    fn(
+        value: Value,
+        counter: &Arc<EntityGenerator>,
+    ) -> Result<Box<dyn Any + Send + Sync>, serde_json::Error>;
 
 /// A vtable used to store container metadata and helper functions.
 #[derive(Clone)]
 struct ContainerVtable {
     debug: DebugFunc,
     heap_size: HeapsizeFunc,
+    retain_alive: RetainAliveFunc,
+    /// Only set for containers registered with a [`Registry`].
+    to_value: Option<ToValueFunc>,
+    /// Only set for containers registered with a [`Registry`].
+    from_value: Option<FromValueFunc>,
     /// The [`TypeId`] for the expected container. The container is usually a
-    /// `RefCell<Container<C>>`.
+    /// `LockCell<Container<C>>`.
     container_type_id: TypeId,
     component_type_id: TypeId,
     component_name: String,
@@ -386,18 +636,27 @@ impl ContainerVtable {
     {
         ContainerVtable {
             debug: |c, f| {
-                c.downcast_ref::<RefCell<Container<C>>>()
+                c.downcast_ref::<LockCell<Container<C>>>()
                     .expect("Incorrect container type")
                     .borrow()
                     .fmt(f)
             },
             heap_size: |c| {
-                c.downcast_ref::<RefCell<Container<C>>>()
+                c.downcast_ref::<LockCell<Container<C>>>()
                     .expect("Incorrect container type")
                     .borrow()
                     .heap_size_of_children()
             },
-            container_type_id: TypeId::of::<RefCell<Container<C>>>(),
+            retain_alive: |c, entities| {
+                c.downcast_ref::<LockCell<Container<C>>>()
+                    .expect("Incorrect container type")
+                    .borrow_mut()
+                    .items
+                    .retain(|&id, _| entities.is_alive(id))
+            },
+            to_value: None,
+            from_value: None,
+            container_type_id: TypeId::of::<LockCell<Container<C>>>(),
             component_type_id: TypeId::of::<C>(),
             component_name: C::type_name(),
         }
@@ -409,23 +668,73 @@ impl ContainerVtable {
     {
         ContainerVtable {
             debug: |c, f| {
-                c.downcast_ref::<RefCell<C>>()
+                c.downcast_ref::<LockCell<C>>()
                     .expect("Incorrect singleton type")
                     .borrow()
                     .fmt(f)
             },
             heap_size: |c| {
-                c.downcast_ref::<RefCell<C>>()
+                c.downcast_ref::<LockCell<C>>()
                     .expect("Incorrect singleton type")
                     .borrow()
                     .heap_size_of_children()
             },
-            container_type_id: TypeId::of::<RefCell<C>>(),
+            // singletons aren't attached to an entity
+            retain_alive: |_, _| {},
+            to_value: None,
+            from_value: None,
+            container_type_id: TypeId::of::<LockCell<C>>(),
             component_type_id: TypeId::of::<C>(),
             component_name: C::type_name(),
         }
     }
 
+    fn for_serializable_container<C>() -> ContainerVtable
+    where
+        C: Component + Serialize + DeserializeOwned,
+    {
+        ContainerVtable {
+            to_value: Some(|c| {
+                let container = c
+                    .downcast_ref::<LockCell<Container<C>>>()
+                    .expect("Incorrect container type")
+                    .borrow();
+
+                // sort by ID so the output is deterministic
+                let mut items: Vec<_> = container.iter().collect();
+                items.sort_by_key(|&(id, _)| id);
+                serde_json::to_value(items)
+            }),
+            from_value: Some(|value, counter| {
+                let items: Vec<(EntityId, C)> = serde_json::from_value(value)?;
+                let mut container = Container::new(Arc::clone(counter));
+                container.items.extend(items);
+                Ok(Box::new(LockCell::new(container)))
+            }),
+            ..ContainerVtable::for_component_container::<C>()
+        }
+    }
+
+    fn for_serializable_singleton<C>() -> ContainerVtable
+    where
+        C: Component + Default + Serialize + DeserializeOwned,
+    {
+        ContainerVtable {
+            to_value: Some(|c| {
+                let singleton = c
+                    .downcast_ref::<LockCell<C>>()
+                    .expect("Incorrect singleton type")
+                    .borrow();
+                serde_json::to_value(&*singleton)
+            }),
+            from_value: Some(|value, _| {
+                let singleton: C = serde_json::from_value(value)?;
+                Ok(Box::new(LockCell::new(singleton)))
+            }),
+            ..ContainerVtable::for_singleton::<C>()
+        }
+    }
+
     fn debug<'a>(&self, container: &'a dyn Any) -> impl Debug + 'a {
         debug_assert_eq!(
             container.type_id(),
@@ -450,6 +759,9 @@ impl HeapSizeOf for ContainerVtable {
         let ContainerVtable {
             debug: _,
             heap_size: _,
+            retain_alive: _,
+            to_value: _,
+            from_value: _,
             container_type_id: _,
             component_type_id: _,
             ref component_name,
@@ -459,6 +771,165 @@ impl HeapSizeOf for ContainerVtable {
     }
 }
 
+/// The set of [`Component`]s which can be saved and loaded.
+///
+/// Serializing a [`Resources`] goes through a [`Registry`] because the
+/// [`Resources`] only knows its components as `Box<dyn Any + Send + Sync>`, so we need
+/// something which remembers how to (de)serialize each concrete type.
+#[derive(Default, Clone)]
+pub struct Registry {
+    components: HashMap<String, ContainerVtable>,
+    singletons: HashMap<String, ContainerVtable>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    /// Register a normal [`Component`].
+    pub fn register<C>(&mut self) -> &mut Registry
+    where
+        C: Component + Serialize + DeserializeOwned,
+    {
+        self.components.insert(
+            C::type_name(),
+            ContainerVtable::for_serializable_container::<C>(),
+        );
+        self
+    }
+
+    /// Register a singleton [`Component`].
+    pub fn register_singleton<C>(&mut self) -> &mut Registry
+    where
+        C: Component + Default + Serialize + DeserializeOwned,
+    {
+        self.singletons.insert(
+            C::type_name(),
+            ContainerVtable::for_serializable_singleton::<C>(),
+        );
+        self
+    }
+
+    fn lookup(&self, vtable: &ContainerVtable) -> Option<ToValueFunc> {
+        self.components
+            .get(&vtable.component_name)
+            .or_else(|| self.singletons.get(&vtable.component_name))
+            .filter(|v| v.component_type_id == vtable.component_type_id)
+            .and_then(|v| v.to_value)
+    }
+}
+
+impl Debug for Registry {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Registry")
+            .field("components", &self.components.keys())
+            .field("singletons", &self.singletons.keys())
+            .finish()
+    }
+}
+
+/// The on-disk representation of a [`Resources`], where each container has
+/// been converted into an intermediate [`Value`] by its vtable.
+#[derive(Serialize, Deserialize)]
+struct SavedResources {
+    entities: Allocator,
+    components: BTreeMap<String, Value>,
+    singletons: BTreeMap<String, Value>,
+}
+
+/// A [`Resources`] which can be serialized, created by
+/// [`Resources::serializable()`].
+#[derive(Debug)]
+pub struct SerializableResources<'a> {
+    resources: &'a Resources,
+    registry: &'a Registry,
+}
+
+impl<'a> SerializableResources<'a> {
+    fn save(
+        &self,
+        containers: &HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+    ) -> Result<BTreeMap<String, Value>, serde_json::Error> {
+        let mut saved = BTreeMap::new();
+
+        for (type_id, container) in containers {
+            let vtable = &self.resources.vtables[type_id];
+
+            if let Some(to_value) = self.registry.lookup(vtable) {
+                let value = to_value(&**container)?;
+                saved.insert(vtable.component_name.clone(), value);
+            }
+        }
+
+        Ok(saved)
+    }
+}
+
+impl<'a> Serialize for SerializableResources<'a> {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::Error;
+
+        let saved = SavedResources {
+            entities: self.resources.counter.snapshot(),
+            components: self
+                .save(&self.resources.items)
+                .map_err(S::Error::custom)?,
+            singletons: self
+                .save(&self.resources.singletons)
+                .map_err(S::Error::custom)?,
+        };
+
+        saved.serialize(ser)
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for &Registry {
+    type Value = Resources;
+
+    fn deserialize<D>(self, de: D) -> Result<Resources, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let saved: SavedResources = serde::Deserialize::deserialize(de)?;
+        let counter = Arc::new(EntityGenerator::from_allocator(saved.entities));
+        let mut resources = Resources {
+            counter: Arc::clone(&counter),
+            ..Default::default()
+        };
+
+        let sections = vec![
+            (saved.components, &self.components, &mut resources.items),
+            (
+                saved.singletons,
+                &self.singletons,
+                &mut resources.singletons,
+            ),
+        ];
+
+        for (values, vtables, containers) in sections {
+            for (name, value) in values {
+                let vtable = vtables.get(&name).ok_or_else(|| {
+                    D::Error::custom(format!("Unknown component, \"{}\"", name))
+                })?;
+                let from_value =
+                    vtable.from_value.expect("Registered with serde support");
+
+                let container =
+                    from_value(value, &counter).map_err(D::Error::custom)?;
+                containers.insert(vtable.component_type_id, container);
+                resources
+                    .vtables
+                    .insert(vtable.component_type_id, vtable.clone());
+            }
+        }
+
+        Ok(resources)
+    }
+}
+
 struct VtableDebug<'a> {
     func: DebugFunc,
     item: &'a dyn Any,
@@ -484,6 +955,55 @@ use crate::passes::Pass;
 pub trait FromResources<'r>: Sized {
     fn from_resources(r: &'r Resources) -> Self;
     fn ensure_registered(r: &mut Resources);
+    /// Record which components will be used, so things which don't conflict
+    /// can be given access at the same time.
+    fn accesses(accesses: &mut Accesses);
+}
+
+/// The set of components something reads from and writes to.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Accesses {
+    reads: Vec<TypeId>,
+    writes: Vec<TypeId>,
+}
+
+impl Accesses {
+    pub fn new() -> Accesses {
+        Accesses::default()
+    }
+
+    /// Get the [`Accesses`] needed by some [`FromResources`] type.
+    pub fn of<'r, F: FromResources<'r>>() -> Accesses {
+        let mut accesses = Accesses::new();
+        F::accesses(&mut accesses);
+        accesses
+    }
+
+    pub fn read<C: Component>(&mut self) {
+        self.reads.push(TypeId::of::<C>());
+    }
+
+    pub fn write<C: Component>(&mut self) {
+        self.writes.push(TypeId::of::<C>());
+    }
+
+    /// Would using both of these at the same time break the "many readers or
+    /// one writer" rule?
+    pub fn conflicts_with(&self, other: &Accesses) -> bool {
+        let overlaps = |writes: &[TypeId], other: &Accesses| {
+            writes
+                .iter()
+                .any(|w| other.reads.contains(w) || other.writes.contains(w))
+        };
+
+        overlaps(&self.writes, other) || overlaps(&other.writes, self)
+    }
+
+    /// Merge in the accesses from something else.
+    pub fn extend(&mut self, other: &Accesses) {
+        self.reads.extend(other.reads.iter().cloned());
+        self.writes.extend(other.writes.iter().cloned());
+    }
 }
 
 /// A read-only reference to a [`Container`] of [`Component`]s.
@@ -498,6 +1018,10 @@ impl<'r, C: Component> FromResources<'r> for Read<'r, C> {
     fn ensure_registered(r: &mut Resources) {
         r.ensure_registered::<C>();
     }
+
+    fn accesses(accesses: &mut Accesses) {
+        accesses.read::<C>();
+    }
 }
 
 impl<'r, C: Component> Deref for Read<'r, C> {
@@ -520,6 +1044,10 @@ impl<'r, C: Component> FromResources<'r> for ReadWrite<'r, C> {
     fn ensure_registered(r: &mut Resources) {
         r.ensure_registered::<C>();
     }
+
+    fn accesses(accesses: &mut Accesses) {
+        accesses.write::<C>();
+    }
 }
 
 impl<'r, C: Component> Deref for ReadWrite<'r, C> {
@@ -536,6 +1064,35 @@ impl<'r, C: Component> DerefMut for ReadWrite<'r, C> {
     }
 }
 
+/// Access to the set of living entities, letting a [`Pass`] delete them.
+///
+/// Deleted entities are only removed from each [`Container`] when the
+/// [`Resources`] are next maintained (i.e. after the [`Pass`] finishes).
+#[derive(Debug, TypeName)]
+pub struct Entities<'r>(&'r EntityGenerator);
+
+impl<'r> Entities<'r> {
+    pub fn is_alive(&self, id: EntityId) -> bool {
+        self.0.is_alive(id)
+    }
+
+    /// Delete an entity, returning `false` if it was already dead.
+    pub fn delete(&self, id: EntityId) -> bool {
+        self.0.delete(id)
+    }
+}
+
+impl<'r> FromResources<'r> for Entities<'r> {
+    fn from_resources(r: &'r Resources) -> Self {
+        Entities(&r.counter)
+    }
+
+    fn ensure_registered(_r: &mut Resources) {}
+
+    // the allocator does its own locking
+    fn accesses(_accesses: &mut Accesses) {}
+}
+
 /// An immutable reference to a singleton component.
 #[derive(Debug, TypeName)]
 pub struct Singleton<'r, T: Component + Default>(Ref<'r, T>);
@@ -548,6 +1105,10 @@ impl<'r, T: Component + Default> FromResources<'r> for Singleton<'r, T> {
     fn ensure_registered(r: &mut Resources) {
         r.ensure_singleton_registered::<T>();
     }
+
+    fn accesses(accesses: &mut Accesses) {
+        accesses.read::<T>();
+    }
 }
 
 impl<'r, C: Component + Default> Deref for Singleton<'r, C> {
@@ -570,6 +1131,10 @@ impl<'r, T: Component + Default> FromResources<'r> for SingletonMut<'r, T> {
     fn ensure_registered(r: &mut Resources) {
         r.ensure_singleton_registered::<T>();
     }
+
+    fn accesses(accesses: &mut Accesses) {
+        accesses.write::<T>();
+    }
 }
 
 impl<'r, C: Component + Default> Deref for SingletonMut<'r, C> {
@@ -613,19 +1178,256 @@ macro_rules! tuple_from_resource {
                     $letter::ensure_registered(r);
                 )*
             }
+
+            #[allow(unused_variables)]
+            fn accesses(accesses: &mut Accesses) {
+                $(
+                    $letter::accesses(accesses);
+                )*
+            }
         }
     };
 }
 
 tuple_from_resource!(A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P);
 
+/// Something which can take part in a [`Join`], giving access to the
+/// component attached to a particular entity.
+pub trait Joinable {
+    type Item;
+
+    /// The entities this can provide an item for, or `None` if any entity
+    /// is accepted (e.g. an optional component).
+    fn entities(&self) -> Option<Vec<EntityId>>;
+
+    fn fetch(&self, id: EntityId) -> Option<Self::Item>;
+}
+
+impl<'a, C: Component> Joinable for &'a Container<C> {
+    type Item = &'a C;
+
+    fn entities(&self) -> Option<Vec<EntityId>> {
+        Some(self.items.keys().cloned().collect())
+    }
+
+    fn fetch(&self, id: EntityId) -> Option<&'a C> {
+        self.get(id)
+    }
+}
+
+impl<'a, 'r, C: Component> Joinable for &'a Read<'r, C> {
+    type Item = &'a C;
+
+    fn entities(&self) -> Option<Vec<EntityId>> {
+        (&***self).entities()
+    }
+
+    fn fetch(&self, id: EntityId) -> Option<&'a C> {
+        self.get(id)
+    }
+}
+
+impl<'a, 'r, C: Component> Joinable for &'a ReadWrite<'r, C> {
+    type Item = &'a C;
+
+    fn entities(&self) -> Option<Vec<EntityId>> {
+        (&***self).entities()
+    }
+
+    fn fetch(&self, id: EntityId) -> Option<&'a C> {
+        self.get(id)
+    }
+}
+
+/// An optional component, yielding `None` for entities which don't have
+/// one instead of skipping them.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Maybe<J>(pub J);
+
+impl<J: Joinable> Joinable for Maybe<J> {
+    type Item = Option<J::Item>;
+
+    fn entities(&self) -> Option<Vec<EntityId>> {
+        None
+    }
+
+    fn fetch(&self, id: EntityId) -> Option<Self::Item> {
+        Some(self.0.fetch(id))
+    }
+}
+
+/// A [`Component`] which belongs to something else (e.g. a [`Variable`]
+/// is declared inside a program).
+///
+/// [`Variable`]: crate::hir::Variable
+pub trait HasParent {
+    type Parent: PartialEq;
+
+    fn parent(&self) -> Self::Parent;
+}
+
+/// The components in a [`Container`] with a particular parent, created by
+/// [`Container::children_of()`].
+#[derive(Debug)]
+pub struct ChildrenOf<'a, C: Component + HasParent> {
+    container: &'a Container<C>,
+    parent: C::Parent,
+}
+
+impl<'a, C: Component + HasParent> Joinable for ChildrenOf<'a, C> {
+    type Item = &'a C;
+
+    fn entities(&self) -> Option<Vec<EntityId>> {
+        let children = self
+            .container
+            .iter()
+            .filter(|(_, c)| c.parent() == self.parent)
+            .map(|(id, _)| id);
+
+        Some(children.collect())
+    }
+
+    fn fetch(&self, id: EntityId) -> Option<&'a C> {
+        self.container.get(id).filter(|c| c.parent() == self.parent)
+    }
+}
+
+impl<C: Component + HasParent> Container<C> {
+    /// Only look at the components belonging to `parent` when joining.
+    pub fn children_of(&self, parent: C::Parent) -> ChildrenOf<'_, C> {
+        ChildrenOf {
+            container: self,
+            parent,
+        }
+    }
+}
+
+/// Iterate over the entities which have every one of a set of components.
+///
+/// # Examples
+///
+/// ```rust
+/// # use iec::ecs::{Join, Maybe, Resources};
+/// # use iec::hir::{Span, Variable};
+/// # let mut resources = Resources::new();
+/// # resources.register::<Variable>();
+/// # resources.register::<Span>();
+/// let variables = resources.get::<Variable>();
+/// let spans = resources.get::<Span>();
+///
+/// for (id, (variable, span)) in (&*variables, Maybe(&*spans)).join() {
+///     println!("{:?} {:?} declared at {:?}", id, variable.name, span);
+/// }
+/// ```
+///
+/// Entities are visited in order of their [`EntityId`]. At least one of the
+/// components must be required, joining only [`Maybe`]s yields nothing.
+pub trait Join: Sized {
+    type Item;
+
+    fn join(self) -> JoinIter<Self>;
+}
+
+/// The iterator returned by [`Join::join()`].
+#[derive(Debug)]
+pub struct JoinIter<J> {
+    parts: J,
+    candidates: std::vec::IntoIter<EntityId>,
+}
+
+/// Figure out which entities need to be checked, preferring the
+/// smallest set of candidates.
+fn candidates(sets: Vec<Option<Vec<EntityId>>>) -> Vec<EntityId> {
+    let mut ids = sets
+        .into_iter()
+        .flatten()
+        .min_by_key(|set| set.len())
+        .unwrap_or_default();
+    ids.sort();
+    ids
+}
+
+macro_rules! tuple_join {
+    ($first:ident $(,$tail:tt)+) => {
+        tuple_join!(@IMPL $first $(, $tail)*);
+        tuple_join!($($tail),*);
+    };
+    ($first:ident) => {
+        tuple_join!(@IMPL $first);
+    };
+    (@IMPL $($letter:ident),*) => {
+        #[allow(non_snake_case)]
+        impl<$( $letter: Joinable, )*> Join for ( $( $letter, )* ) {
+            type Item = ( $( $letter::Item, )* );
+
+            fn join(self) -> JoinIter<Self> {
+                let ( $( ref $letter, )* ) = self;
+                let candidates = candidates(vec![$( $letter.entities() ),*]);
+
+                JoinIter {
+                    parts: self,
+                    candidates: candidates.into_iter(),
+                }
+            }
+        }
+
+        #[allow(non_snake_case)]
+        impl<$( $letter: Joinable, )*> Iterator for JoinIter<( $( $letter, )* )> {
+            type Item = (EntityId, ( $( $letter::Item, )* ));
+
+            fn next(&mut self) -> Option<Self::Item> {
+                let ( $( ref $letter, )* ) = self.parts;
+
+                for id in &mut self.candidates {
+                    $(
+                        let $letter = match $letter.fetch(id) {
+                            Some(item) => item,
+                            None => continue,
+                        };
+                    )*
+
+                    return Some((id, ( $( $letter, )* )));
+                }
+
+                None
+            }
+        }
+    };
+}
+
+tuple_join!(A, B, C, D, E, F, G, H);
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[derive(Debug, Default, Copy, Clone, PartialEq, TypeName, HeapSizeOf)]
+    #[derive(
+        Debug,
+        Default,
+        Copy,
+        Clone,
+        PartialEq,
+        TypeName,
+        HeapSizeOf,
+        Serialize,
+        Deserialize,
+    )]
     struct RandomComponent(u32);
 
+    #[derive(Debug, Default, Clone, PartialEq, TypeName, HeapSizeOf)]
+    struct NotSaved(u32);
+
+    #[derive(Debug, Clone, PartialEq, TypeName, HeapSizeOf)]
+    struct Child(&'static str);
+
+    impl HasParent for Child {
+        type Parent = &'static str;
+
+        fn parent(&self) -> &'static str {
+            self.0
+        }
+    }
+
     #[test]
     fn generate_valid_vtables() {
         let vtable =
@@ -634,7 +1436,7 @@ mod tests {
         assert!(vtable.component_name.ends_with("RandomComponent"));
         assert_eq!(vtable.component_type_id, TypeId::of::<RandomComponent>());
 
-        let container = RefCell::new(Container::default());
+        let container = LockCell::new(Container::default());
         container.borrow_mut().insert(RandomComponent(42));
 
         let debug_format = format!("{:?}", vtable.debug(&container));
@@ -642,7 +1444,7 @@ mod tests {
         assert_eq!(actual, debug_format);
 
         let got_heapsize = vtable.heap_size_of(&container);
-        let actual = container.heap_size_of_children();
+        let actual = container.borrow().heap_size_of_children();
         assert_eq!(got_heapsize, actual);
     }
 
@@ -657,7 +1459,7 @@ mod tests {
         assert_eq!(vtable.component_type_id, TypeId::of::<RandomComponent>());
         assert_eq!(
             vtable.container_type_id,
-            TypeId::of::<RefCell<Container<RandomComponent>>>()
+            TypeId::of::<LockCell<Container<RandomComponent>>>()
         );
     }
 
@@ -721,4 +1523,261 @@ mod tests {
         res.register::<RandomComponent>();
         res.register_singleton::<RandomComponent>();
     }
+
+    #[test]
+    fn save_and_reload_resources() {
+        let mut registry = Registry::new();
+        registry.register::<RandomComponent>();
+        let mut res = Resources::default();
+        res.register::<RandomComponent>();
+        res.register::<NotSaved>();
+        let first = res.get_mut::<RandomComponent>().insert(RandomComponent(1));
+        let second =
+            res.get_mut::<RandomComponent>().insert(RandomComponent(2));
+        res.get_mut::<NotSaved>().attach(first, NotSaved(3));
+
+        let json = serde_json::to_string(&res.serializable(&registry)).unwrap();
+        let mut de = serde_json::Deserializer::from_str(&json);
+        let got = registry.deserialize(&mut de).unwrap();
+
+        {
+            let components = got.get::<RandomComponent>();
+            assert_eq!(components.len(), 2);
+            assert_eq!(components.get(first), Some(&RandomComponent(1)));
+            assert_eq!(components.get(second), Some(&RandomComponent(2)));
+        }
+        assert!(!got.is_registered::<NotSaved>());
+
+        // new entities mustn't reuse the IDs of the ones we loaded
+        let third = got.get_mut::<RandomComponent>().insert(RandomComponent(3));
+        assert!(third != first && third != second);
+    }
+
+    #[test]
+    fn save_and_reload_a_singleton() {
+        let mut registry = Registry::new();
+        registry.register_singleton::<RandomComponent>();
+        let mut res = Resources::default();
+        res.register_singleton::<RandomComponent>();
+        res.get_singleton_mut::<RandomComponent>().0 = 42;
+
+        let json = serde_json::to_value(res.serializable(&registry)).unwrap();
+        let got = registry.deserialize(json).unwrap();
+
+        assert_eq!(got.get_singleton::<RandomComponent>().0, 42);
+    }
+
+    #[test]
+    fn unknown_components_are_an_error() {
+        let mut registry = Registry::new();
+        registry.register::<RandomComponent>();
+        let mut res = Resources::default();
+        res.register::<RandomComponent>();
+
+        let json = serde_json::to_value(res.serializable(&registry)).unwrap();
+        let got = Registry::new().deserialize(json);
+
+        assert!(got.is_err());
+    }
+
+    #[test]
+    fn join_two_containers() {
+        let mut res = Resources::default();
+        res.register::<RandomComponent>();
+        res.register::<NotSaved>();
+        let mut randoms = res.get_mut::<RandomComponent>();
+        let mut others = res.get_mut::<NotSaved>();
+        let first = randoms.insert(RandomComponent(1));
+        let _second = randoms.insert(RandomComponent(2));
+        let third = randoms.insert(RandomComponent(3));
+        others.attach(third, NotSaved(30));
+        others.attach(first, NotSaved(10));
+
+        let got: Vec<_> = (&*randoms, &*others).join().collect();
+
+        assert_eq!(
+            got,
+            vec![
+                (first, (&RandomComponent(1), &NotSaved(10))),
+                (third, (&RandomComponent(3), &NotSaved(30))),
+            ]
+        );
+    }
+
+    #[test]
+    fn join_with_optional_components() {
+        let mut res = Resources::default();
+        res.register::<RandomComponent>();
+        res.register::<NotSaved>();
+        let mut randoms = res.get_mut::<RandomComponent>();
+        let mut others = res.get_mut::<NotSaved>();
+        let first = randoms.insert(RandomComponent(1));
+        let second = randoms.insert(RandomComponent(2));
+        others.attach(second, NotSaved(20));
+
+        let got: Vec<_> = (&*randoms, Maybe(&*others)).join().collect();
+
+        assert_eq!(
+            got,
+            vec![
+                (first, (&RandomComponent(1), None)),
+                (second, (&RandomComponent(2), Some(&NotSaved(20)))),
+            ]
+        );
+        assert_eq!((Maybe(&*others),).join().count(), 0);
+    }
+
+    #[test]
+    fn join_the_children_of_a_parent() {
+        let mut res = Resources::default();
+        res.register::<Child>();
+        res.register::<RandomComponent>();
+        let mut children = res.get_mut::<Child>();
+        let mut randoms = res.get_mut::<RandomComponent>();
+        let first = children.insert(Child("a"));
+        let second = children.insert(Child("b"));
+        randoms.attach(first, RandomComponent(1));
+        randoms.attach(second, RandomComponent(2));
+
+        let got: Vec<_> = (children.children_of("b"), &*randoms)
+            .join()
+            .map(|(id, (_, random))| (id, random.0))
+            .collect();
+
+        assert_eq!(got, vec![(second, 2)]);
+    }
+
+    #[test]
+    fn the_placeholder_is_never_a_real_id() {
+        let mut container = Container::default();
+
+        let first = container.insert(RandomComponent(1));
+
+        assert!(!first.is_placeholder());
+        assert_ne!(first, EntityId::default());
+    }
+
+    #[test]
+    fn delete_an_entity() {
+        let mut res = Resources::default();
+        res.register::<RandomComponent>();
+        res.register::<NotSaved>();
+        let first = res.get_mut::<RandomComponent>().insert(RandomComponent(1));
+        let second =
+            res.get_mut::<RandomComponent>().insert(RandomComponent(2));
+        res.get_mut::<NotSaved>().attach(first, NotSaved(1));
+
+        assert!(res.delete(first));
+
+        assert!(!res.is_alive(first));
+        assert!(res.is_alive(second));
+        assert!(res.get::<RandomComponent>().get(first).is_none());
+        assert!(res.get::<NotSaved>().is_empty());
+        // deleting twice is a no-op
+        assert!(!res.delete(first));
+    }
+
+    #[test]
+    fn deleted_indices_are_reused_with_a_new_generation() {
+        let mut res = Resources::default();
+        res.register::<RandomComponent>();
+        let first = res.get_mut::<RandomComponent>().insert(RandomComponent(1));
+        res.delete(first);
+
+        let second =
+            res.get_mut::<RandomComponent>().insert(RandomComponent(2));
+
+        assert_eq!(first.index, second.index);
+        assert_ne!(first, second);
+        // the stale handle doesn't see the new entity
+        assert!(res.get::<RandomComponent>().get(first).is_none());
+        assert_eq!(
+            res.get::<RandomComponent>().get(second),
+            Some(&RandomComponent(2))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "which was deleted")]
+    fn attaching_to_a_deleted_entity_is_an_error() {
+        let mut res = Resources::default();
+        res.register::<RandomComponent>();
+        let first = res.get_mut::<RandomComponent>().insert(RandomComponent(1));
+        res.delete(first);
+
+        res.get_mut::<RandomComponent>()
+            .attach(first, RandomComponent(2));
+    }
+
+    #[test]
+    fn entities_deleted_by_a_system_are_cleaned_up_when_maintaining() {
+        let mut res = Resources::default();
+        res.register::<RandomComponent>();
+        let first = res.get_mut::<RandomComponent>().insert(RandomComponent(1));
+
+        {
+            let entities = Entities::from_resources(&res);
+            assert!(entities.delete(first));
+        }
+        assert_eq!(res.get::<RandomComponent>().len(), 1);
+
+        res.maintain();
+
+        assert!(res.get::<RandomComponent>().is_empty());
+    }
+
+    #[test]
+    fn deleted_entities_stay_deleted_after_reloading() {
+        let mut registry = Registry::new();
+        registry.register::<RandomComponent>();
+        let mut res = Resources::default();
+        res.register::<RandomComponent>();
+        let first = res.get_mut::<RandomComponent>().insert(RandomComponent(1));
+        res.delete(first);
+
+        let json = serde_json::to_value(res.serializable(&registry)).unwrap();
+        let got = registry.deserialize(json).unwrap();
+
+        assert!(!got.is_alive(first));
+        let second =
+            got.get_mut::<RandomComponent>().insert(RandomComponent(2));
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn resources_can_be_shared_between_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Resources>();
+
+        let mut res = Resources::default();
+        res.register::<RandomComponent>();
+        res.register::<NotSaved>();
+        let res = &res;
+
+        std::thread::scope(|scope| {
+            scope.spawn(move || {
+                res.get_mut::<RandomComponent>().insert(RandomComponent(1))
+            });
+            scope.spawn(move || res.get_mut::<NotSaved>().insert(NotSaved(2)));
+        });
+
+        assert_eq!(res.get::<RandomComponent>().len(), 1);
+        assert_eq!(res.get::<NotSaved>().len(), 1);
+    }
+
+    #[test]
+    fn conflicting_accesses() {
+        let reads = Accesses::of::<Read<'_, RandomComponent>>();
+        let writes = Accesses::of::<ReadWrite<'_, RandomComponent>>();
+        let other = Accesses::of::<(
+            Read<'_, NotSaved>,
+            SingletonMut<'_, RandomComponent>,
+        )>();
+
+        assert!(!reads.conflicts_with(&reads));
+        assert!(reads.conflicts_with(&writes));
+        assert!(writes.conflicts_with(&writes));
+        assert!(other.conflicts_with(&reads));
+        assert!(!Accesses::of::<Read<'_, NotSaved>>().conflicts_with(&writes));
+    }
 }
@@ -1,8 +1,14 @@
 //! The compiler's high-level intermediate representation.
 
-use crate::ecs::{EntityId, Resources};
+use crate::ecs::{EntityId, HasParent, Registry, Resources};
+use crate::passes::symbol_table::SymbolTable;
+use codespan::ByteSpan;
 use heapsize_derive::HeapSizeOf;
+use iec_syntax::{Area, DirectAddress, Retention, Size};
+use serde::de::{Deserialize, DeserializeSeed, Deserializer};
+use serde::ser::{Serialize, Serializer};
 use serde_derive::{Deserialize, Serialize};
+use std::time::Duration;
 use typename::TypeName;
 
 #[derive(Debug, TypeName, HeapSizeOf)]
@@ -10,25 +16,183 @@ pub struct CompilationUnit {
     pub resources: Resources,
 }
 
-#[derive(Debug, Clone, PartialEq, TypeName, HeapSizeOf)]
+impl CompilationUnit {
+    /// The [`Registry`] containing every component which gets saved when a
+    /// [`CompilationUnit`] is serialized.
+    ///
+    /// Analyses like the [`crate::analysis::ControlFlowCache`] aren't
+    /// included, they can be recalculated by re-running the corresponding
+    /// pass after loading.
+    pub fn registry() -> Registry {
+        let mut registry = Registry::new();
+
+        registry
+            .register::<Program>()
+            .register::<Function>()
+            .register::<FunctionBlock>()
+            .register::<GlobalVariables>()
+            .register::<Configuration>()
+            .register::<Resource>()
+            .register::<Task>()
+            .register::<ProgramInstance>()
+            .register::<Type>()
+            .register::<Variable>()
+            .register::<Span>()
+            .register::<Location>()
+            .register::<Retained>()
+            .register::<Constant>()
+            .register::<Instruction>()
+            .register::<BasicBlock>()
+            .register_singleton::<SymbolTable>()
+            .register_singleton::<ProcessImage>();
+
+        registry
+    }
+}
+
+impl Serialize for CompilationUnit {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        let registry = CompilationUnit::registry();
+        self.resources.serializable(&registry).serialize(ser)
+    }
+}
+
+impl<'de> Deserialize<'de> for CompilationUnit {
+    fn deserialize<D>(de: D) -> Result<CompilationUnit, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let registry = CompilationUnit::registry();
+        let resources = registry.deserialize(de)?;
+
+        Ok(CompilationUnit { resources })
+    }
+}
+
+#[derive(
+    Debug, Clone, PartialEq, TypeName, HeapSizeOf, Serialize, Deserialize,
+)]
 pub struct Program {
     pub name: String,
     pub variables: Vec<EntityId>,
+    /// The [`BasicBlock`] execution starts at.
+    pub entry_block: EntityId,
 }
 
-#[derive(Debug, Clone, PartialEq, TypeName, HeapSizeOf)]
+/// A stateless function.
+///
+/// A function's variables are created afresh every time it is called.
+#[derive(
+    Debug, Clone, PartialEq, TypeName, HeapSizeOf, Serialize, Deserialize,
+)]
 pub struct Function {
     pub name: String,
     pub variables: Vec<EntityId>,
+    /// The type of value this function returns.
+    pub return_type: EntityId,
+    /// The implicit variable (named after the function) the body assigns
+    /// its result to.
+    pub return_value: EntityId,
+    /// The [`BasicBlock`] execution starts at.
+    pub entry_block: EntityId,
 }
 
-#[derive(Debug, Clone, PartialEq, TypeName, HeapSizeOf)]
+/// A function block.
+///
+/// Each variable declared with a function block is stored inside the
+/// function block's instances, so their values are persisted across calls.
+/// Instructions inside the function block's body always refer to the
+/// variables of the instance being called.
+#[derive(
+    Debug, Clone, PartialEq, TypeName, HeapSizeOf, Serialize, Deserialize,
+)]
 pub struct FunctionBlock {
     pub name: String,
     pub variables: Vec<EntityId>,
+    /// The [`BasicBlock`] execution starts at.
+    pub entry_block: EntityId,
+}
+
+/// Variables which are visible from every program, function, and function
+/// block.
+#[derive(
+    Debug, Clone, PartialEq, TypeName, HeapSizeOf, Serialize, Deserialize,
+)]
+pub struct GlobalVariables {
+    pub variables: Vec<EntityId>,
+}
+
+/// The top-level description of a PLC.
+///
+/// Any `VAR_GLOBAL` blocks declared inside a configuration or its resources
+/// are merged into the [`GlobalVariables`], so they are listed here purely
+/// for reference.
+#[derive(
+    Debug, Clone, PartialEq, TypeName, HeapSizeOf, Serialize, Deserialize,
+)]
+pub struct Configuration {
+    pub name: String,
+    /// The [`Resource`]s this configuration is made of.
+    pub resources: Vec<EntityId>,
+    /// The [`Variable`]s declared at the configuration level.
+    pub globals: Vec<EntityId>,
+}
+
+/// Something which executes programs, typically a single CPU.
+#[derive(
+    Debug, Clone, PartialEq, TypeName, HeapSizeOf, Serialize, Deserialize,
+)]
+pub struct Resource {
+    pub name: String,
+    /// The processor this resource runs on (`RESOURCE name ON processor`).
+    pub processor: Option<String>,
+    /// The [`Task`]s declared in this resource.
+    pub tasks: Vec<EntityId>,
+    /// The [`ProgramInstance`]s declared in this resource, in order.
+    pub programs: Vec<EntityId>,
+    /// The [`Variable`]s declared at the resource level.
+    pub globals: Vec<EntityId>,
+}
+
+/// A task, which determines when its programs are executed.
+#[derive(
+    Debug, Clone, PartialEq, TypeName, HeapSizeOf, Serialize, Deserialize,
+)]
+pub struct Task {
+    pub name: String,
+    /// How often a periodic task is executed, in nanoseconds.
+    pub interval: Option<u64>,
+    /// The task's priority, where `0` is the most urgent.
+    pub priority: u32,
+    /// The global `BOOL` [`Variable`] whose rising edge triggers an event
+    /// task.
+    pub single: Option<EntityId>,
+}
+
+impl Task {
+    /// The priority used when a task doesn't specify one.
+    pub const DEFAULT_PRIORITY: u32 = 0;
+
+    pub fn interval(&self) -> Option<Duration> {
+        self.interval.map(Duration::from_nanos)
+    }
+}
+
+/// An instance of a [`Program`], optionally associated with a [`Task`].
+#[derive(
+    Debug, Clone, PartialEq, TypeName, HeapSizeOf, Serialize, Deserialize,
+)]
+pub struct ProgramInstance {
+    pub name: String,
+    /// The [`Task`] responsible for executing this instance.
+    pub task: Option<EntityId>,
+    /// The [`Program`] being instantiated.
+    pub program: EntityId,
 }
 
-#[derive(Debug, Clone, PartialEq, TypeName, HeapSizeOf)]
+#[derive(
+    Debug, Clone, PartialEq, TypeName, HeapSizeOf, Serialize, Deserialize,
+)]
 pub struct Type {
     pub name: String,
 }
@@ -39,20 +203,201 @@ pub struct Type {
 pub struct Variable {
     /// The item this variable is defined in.
     pub parent: Symbol,
-    /// The variable's type.
+    /// The variable's type. This may be a [`Type`] or a [`FunctionBlock`].
     pub ty: EntityId,
     /// The variable's name, if one exists.
     pub name: Option<String>,
+    pub kind: VariableKind,
+}
+
+impl HasParent for Variable {
+    type Parent = Symbol;
+
+    fn parent(&self) -> Symbol {
+        self.parent
+    }
 }
 
+/// The location in the source code an entity was created from.
+///
+/// This is attached to [`Variable`]s and [`Instruction`]s so later passes can
+/// point back at the code responsible.
+#[derive(
+    Debug, Copy, Clone, PartialEq, TypeName, HeapSizeOf, Serialize, Deserialize,
+)]
+pub struct Span(pub ByteSpan);
+
+/// The direct address a [`Variable`] is located at (`x AT %IX0.7 : BOOL`).
+#[derive(
+    Debug, Clone, PartialEq, TypeName, HeapSizeOf, Serialize, Deserialize,
+)]
+pub struct Location(pub DirectAddress);
+
+/// Marks a [`Variable`] declared in a `RETAIN` or `PERSISTENT` block, whose
+/// value should survive a restart.
 #[derive(
     Debug, Copy, Clone, PartialEq, TypeName, HeapSizeOf, Serialize, Deserialize,
 )]
+pub struct Retained(pub Retention);
+
+/// Where every located [`Variable`] lives in the process image, the block
+/// of memory shared with the outside world.
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    PartialEq,
+    TypeName,
+    HeapSizeOf,
+    Serialize,
+    Deserialize,
+)]
+pub struct ProcessImage {
+    /// Every I/O point, sorted by area and then by address.
+    pub points: Vec<IoPoint>,
+}
+
+impl ProcessImage {
+    /// The I/O points in a particular area.
+    pub fn area(&self, area: Area) -> impl Iterator<Item = &IoPoint> + '_ {
+        self.points.iter().filter(move |p| p.area == area)
+    }
+
+    /// The number of bytes needed to store an area.
+    pub fn size_in_bytes(&self, area: Area) -> u32 {
+        let bits = self.area(area).map(|p| p.bits().end).max().unwrap_or(0);
+        bits.div_ceil(8) as u32
+    }
+
+    /// Export the I/O map so it can be used by other tools.
+    pub fn to_json(&self) -> serde_json::Value {
+        let area = |area: Area| {
+            let points: Vec<_> = self
+                .area(area)
+                .map(|p| {
+                    serde_json::json!({
+                        "name": p.name,
+                        "address": p.address,
+                        "type": p.ty,
+                        "byte": p.byte,
+                        "bit": p.bit,
+                        "bits": p.size.bits(),
+                    })
+                })
+                .collect();
+
+            serde_json::json!({
+                "size": self.size_in_bytes(area),
+                "points": points,
+            })
+        };
+
+        serde_json::json!({
+            "inputs": area(Area::Input),
+            "outputs": area(Area::Output),
+            "memory": area(Area::Memory),
+        })
+    }
+}
+
+/// A single [`Variable`] in the [`ProcessImage`].
+#[derive(
+    Debug, Clone, PartialEq, TypeName, HeapSizeOf, Serialize, Deserialize,
+)]
+pub struct IoPoint {
+    pub variable: EntityId,
+    /// The variable's fully qualified name (e.g. `main.start_button`).
+    pub name: String,
+    /// The address as it would be written in the source code.
+    pub address: String,
+    /// The name of the variable's type.
+    pub ty: String,
+    pub area: Area,
+    pub size: Size,
+    /// The offset of the first byte from the start of the area.
+    pub byte: u32,
+    /// Which bit in `byte` this point refers to, for bit addresses.
+    pub bit: Option<u8>,
+}
+
+impl IoPoint {
+    /// The range of bits (relative to the start of the area) this point
+    /// occupies.
+    pub fn bits(&self) -> std::ops::Range<u64> {
+        let bit = self.bit.map_or(0, u64::from);
+        let start = u64::from(self.byte) * 8 + bit;
+        start..start + u64::from(self.size.bits())
+    }
+}
+
+/// How a [`Variable`] may be used.
+#[derive(
+    Debug,
+    Copy,
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    TypeName,
+    HeapSizeOf,
+    Serialize,
+    Deserialize,
+)]
+pub enum VariableKind {
+    /// A normal variable (`VAR`).
+    Local,
+    /// A parameter which is copied in by the caller (`VAR_INPUT`).
+    Input,
+    /// A value which can be read by the caller after a call (`VAR_OUTPUT`).
+    Output,
+    /// A parameter which is passed by reference (`VAR_IN_OUT`).
+    InOut,
+    /// A variable which only lives for the duration of a single call
+    /// (`VAR_TEMP`).
+    Temp,
+    /// The variable a function stores its result in.
+    ReturnValue,
+    /// A variable which is accessible from anywhere (`VAR_GLOBAL`).
+    Global,
+    /// A temporary introduced by the compiler.
+    Temporary,
+}
+
+impl VariableKind {
+    /// Can this variable be accessed from outside the item it was declared
+    /// in?
+    pub fn is_visible_to_caller(self) -> bool {
+        match self {
+            VariableKind::Input
+            | VariableKind::Output
+            | VariableKind::InOut => true,
+            VariableKind::Local
+            | VariableKind::Temp
+            | VariableKind::Global
+            | VariableKind::ReturnValue
+            | VariableKind::Temporary => false,
+        }
+    }
+}
+
+#[derive(
+    Debug,
+    Copy,
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    TypeName,
+    HeapSizeOf,
+    Serialize,
+    Deserialize,
+)]
 pub enum Symbol {
     Program(EntityId),
     Function(EntityId),
     FunctionBlock(EntityId),
     Type(EntityId),
+    GlobalVariables(EntityId),
 }
 
 impl From<Symbol> for EntityId {
@@ -61,7 +406,49 @@ impl From<Symbol> for EntityId {
             Symbol::Program(id)
             | Symbol::Type(id)
             | Symbol::Function(id)
-            | Symbol::FunctionBlock(id) => id,
+            | Symbol::FunctionBlock(id)
+            | Symbol::GlobalVariables(id) => id,
+        }
+    }
+}
+
+/// A constant value.
+#[derive(
+    Debug, Clone, PartialEq, TypeName, HeapSizeOf, Serialize, Deserialize,
+)]
+pub enum Constant {
+    Boolean(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+}
+
+/// Something which can be used as the input to an [`Instruction`].
+#[derive(
+    Debug,
+    Copy,
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    TypeName,
+    HeapSizeOf,
+    Serialize,
+    Deserialize,
+)]
+pub enum Operand {
+    /// The current value of a [`Variable`].
+    Variable(EntityId),
+    /// A [`Constant`].
+    Constant(EntityId),
+}
+
+impl Operand {
+    /// The variable this operand reads, if any.
+    pub fn variable(self) -> Option<EntityId> {
+        match self {
+            Operand::Variable(id) => Some(id),
+            Operand::Constant(_) => None,
         }
     }
 }
@@ -70,18 +457,381 @@ impl From<Symbol> for EntityId {
 #[derive(
     Debug,
     TypeName,
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    HeapSizeOf,
+    Serialize,
+    Deserialize,
+)]
+pub enum Instruction {
+    /// Copy a value into a variable (`dest := src`).
+    Load { dest: EntityId, src: Operand },
+    /// Read a member of an aggregate variable (`dest := object.member`).
+    LoadMember {
+        dest: EntityId,
+        object: EntityId,
+        member: EntityId,
+    },
+    /// Write to a member of an aggregate variable (`object.member := value`).
+    StoreMember {
+        object: EntityId,
+        member: EntityId,
+        value: Operand,
+    },
+    /// Apply a binary operator (`dest := left op right`).
+    Binary {
+        dest: EntityId,
+        op: BinaryOp,
+        left: Operand,
+        right: Operand,
+    },
+    /// Apply a unary operator (`dest := op value`).
+    Unary {
+        dest: EntityId,
+        op: UnaryOp,
+        value: Operand,
+    },
+    /// Invoke a function or function block.
+    ///
+    /// Inputs are copied into the callee before it is executed, then outputs
+    /// are copied back out afterwards. When calling a function, `dest`
+    /// receives a copy of the function's return value.
+    Call {
+        target: Symbol,
+        /// The function block instance being called, if calling a function
+        /// block.
+        instance: Option<EntityId>,
+        args: Vec<Argument>,
+        dest: Option<EntityId>,
+    },
+}
+
+impl Instruction {
+    /// The variables this instruction reads from.
+    ///
+    /// Updating a member of an aggregate also counts as reading the
+    /// aggregate, as do calls to a function block instance.
+    pub fn reads(&self) -> Vec<EntityId> {
+        match *self {
+            Instruction::Load { src, .. } => {
+                src.variable().into_iter().collect()
+            }
+            Instruction::LoadMember { object, .. } => vec![object],
+            Instruction::StoreMember { object, value, .. } => {
+                std::iter::once(object).chain(value.variable()).collect()
+            }
+            Instruction::Binary { left, right, .. } => left
+                .variable()
+                .into_iter()
+                .chain(right.variable())
+                .collect(),
+            Instruction::Unary { value, .. } => {
+                value.variable().into_iter().collect()
+            }
+            Instruction::Call {
+                instance, ref args, ..
+            } => instance
+                .into_iter()
+                .chain(args.iter().filter_map(|arg| match *arg {
+                    Argument::Input { value, .. } => value.variable(),
+                    Argument::InOut { variable, .. } => Some(variable),
+                    Argument::Output { .. } => None,
+                }))
+                .collect(),
+        }
+    }
+
+    /// The [`Operand`]s this instruction reads.
+    pub fn operands(&self) -> Vec<Operand> {
+        self.clone()
+            .operands_mut()
+            .into_iter()
+            .map(|op| *op)
+            .collect()
+    }
+
+    /// The values this instruction reads, which could be replaced with
+    /// another [`Operand`] without changing the instruction's meaning.
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match *self {
+            Instruction::Load { ref mut src, .. } => vec![src],
+            Instruction::StoreMember { ref mut value, .. }
+            | Instruction::Unary { ref mut value, .. } => vec![value],
+            Instruction::Binary {
+                ref mut left,
+                ref mut right,
+                ..
+            } => vec![left, right],
+            Instruction::Call { ref mut args, .. } => args
+                .iter_mut()
+                .filter_map(|arg| match *arg {
+                    Argument::Input { ref mut value, .. } => Some(value),
+                    Argument::InOut { .. } | Argument::Output { .. } => None,
+                })
+                .collect(),
+            Instruction::LoadMember { .. } => Vec::new(),
+        }
+    }
+
+    /// The variables this instruction writes to.
+    pub fn writes(&self) -> Vec<EntityId> {
+        match *self {
+            Instruction::Load { dest, .. }
+            | Instruction::LoadMember { dest, .. }
+            | Instruction::Binary { dest, .. }
+            | Instruction::Unary { dest, .. } => vec![dest],
+            Instruction::StoreMember { object, .. } => vec![object],
+            Instruction::Call {
+                instance,
+                ref args,
+                dest,
+                ..
+            } => instance
+                .into_iter()
+                .chain(dest)
+                .chain(args.iter().filter_map(|arg| match *arg {
+                    Argument::Input { .. } => None,
+                    Argument::InOut { variable, .. }
+                    | Argument::Output { variable, .. } => Some(variable),
+                }))
+                .collect(),
+        }
+    }
+}
+
+/// How a value is passed to one of a callee's parameters.
+#[derive(
+    Debug,
     Copy,
     Clone,
     PartialEq,
     Eq,
     Hash,
+    TypeName,
     HeapSizeOf,
     Serialize,
     Deserialize,
 )]
-pub enum Instruction {}
+pub enum Argument {
+    /// Copy a value into a `VAR_INPUT` before the call.
+    Input { parameter: EntityId, value: Operand },
+    /// Pass a variable by reference to a `VAR_IN_OUT`.
+    InOut {
+        parameter: EntityId,
+        variable: EntityId,
+    },
+    /// Copy a `VAR_OUTPUT` into a variable after the call.
+    Output {
+        parameter: EntityId,
+        variable: EntityId,
+    },
+}
 
+#[derive(
+    Debug,
+    Copy,
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    TypeName,
+    HeapSizeOf,
+    Serialize,
+    Deserialize,
+)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Exponent,
+    And,
+    Or,
+    Xor,
+    Equals,
+    NotEquals,
+    LessThan,
+    LessThanOrEqual,
+    GreaterThan,
+    GreaterThanOrEqual,
+}
+
+impl BinaryOp {
+    /// Does this operator always produce a boolean?
+    pub fn is_comparison(self) -> bool {
+        matches!(
+            self,
+            BinaryOp::Equals
+                | BinaryOp::NotEquals
+                | BinaryOp::LessThan
+                | BinaryOp::LessThanOrEqual
+                | BinaryOp::GreaterThan
+                | BinaryOp::GreaterThanOrEqual
+        )
+    }
+}
+
+#[derive(
+    Debug,
+    Copy,
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    TypeName,
+    HeapSizeOf,
+    Serialize,
+    Deserialize,
+)]
+pub enum UnaryOp {
+    Not,
+    Negate,
+}
+
+/// The instruction used to leave a [`BasicBlock`].
+#[derive(
+    Debug,
+    Copy,
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    TypeName,
+    HeapSizeOf,
+    Serialize,
+    Deserialize,
+)]
+pub enum Terminator {
+    /// Unconditionally jump to another block.
+    Jump(EntityId),
+    /// Jump to `then` if the condition is true, otherwise jump to
+    /// `otherwise`.
+    Branch {
+        condition: Operand,
+        then: EntityId,
+        otherwise: EntityId,
+    },
+    /// Return from the current program, function, or function block.
+    Return,
+}
+
+impl Terminator {
+    /// The blocks which may be executed after this one.
+    pub fn successors(&self) -> Vec<EntityId> {
+        match *self {
+            Terminator::Jump(target) => vec![target],
+            Terminator::Branch {
+                then, otherwise, ..
+            } => vec![then, otherwise],
+            Terminator::Return => Vec::new(),
+        }
+    }
+
+    /// The variable this terminator reads, if any.
+    pub fn reads(&self) -> Option<EntityId> {
+        match *self {
+            Terminator::Branch { condition, .. } => condition.variable(),
+            Terminator::Jump(_) | Terminator::Return => None,
+        }
+    }
+}
+
+/// A straight-line sequence of [`Instruction`]s with a single entry point
+/// and a single exit.
 #[derive(
     TypeName, Debug, Clone, PartialEq, HeapSizeOf, Serialize, Deserialize,
 )]
-pub struct BasicBlock {}
+pub struct BasicBlock {
+    /// The item this block belongs to.
+    pub parent: Symbol,
+    pub instructions: Vec<EntityId>,
+    pub terminator: Terminator,
+}
+
+impl HasParent for BasicBlock {
+    type Parent = Symbol;
+
+    fn parent(&self) -> Symbol {
+        self.parent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::ControlFlowCache;
+    use crate::ecs::Component;
+    use crate::passes::{self, PassContext};
+    use crate::Diagnostics;
+    use std::fmt::Debug;
+
+    fn sorted<C: Component + Clone>(
+        cu: &CompilationUnit,
+    ) -> Vec<(EntityId, C)> {
+        let container = cu.resources.get::<C>();
+        let mut items: Vec<_> =
+            container.iter().map(|(id, c)| (id, c.clone())).collect();
+        items.sort_by_key(|&(id, _)| id);
+        items
+    }
+
+    fn assert_same<C: Component + Clone + PartialEq + Debug>(
+        left: &CompilationUnit,
+        right: &CompilationUnit,
+    ) {
+        assert_eq!(sorted::<C>(left), sorted::<C>(right));
+    }
+
+    #[test]
+    fn save_and_reload_a_compilation_unit() {
+        let src = "
+            FUNCTION add_one : int
+            BEGIN
+                VAR_INPUT
+                    x: int;
+                END_VAR
+
+                add_one := x + 1;
+            END_FUNCTION
+
+            PROGRAM main
+                VAR
+                    i: int;
+                END_VAR
+
+                WHILE i < 10 DO
+                    i := add_one(i);
+                END_WHILE;
+            END_PROGRAM";
+        let ast: iec_syntax::File = src.parse().unwrap();
+        let mut diags = Diagnostics::new();
+        let mut ctx = PassContext::new_nop_logger(&mut diags);
+        let original = crate::process(&ast, &mut ctx);
+
+        let json = serde_json::to_string(&original).unwrap();
+        let mut got: CompilationUnit = serde_json::from_str(&json).unwrap();
+
+        assert_same::<Program>(&original, &got);
+        assert_same::<Function>(&original, &got);
+        assert_same::<Variable>(&original, &got);
+        assert_same::<Constant>(&original, &got);
+        assert_same::<Instruction>(&original, &got);
+        assert_same::<BasicBlock>(&original, &got);
+        assert_eq!(
+            *original.resources.get_singleton::<SymbolTable>(),
+            *got.resources.get_singleton::<SymbolTable>()
+        );
+
+        // analyses aren't saved, but they can be recalculated
+        passes::run_pass::<passes::ControlFlowAnalysis>(
+            &mut got.resources,
+            &(),
+            &mut ctx,
+        );
+        let cache = got.resources.get_singleton::<ControlFlowCache>();
+        assert_eq!(cache.iter().count(), 2);
+    }
+}
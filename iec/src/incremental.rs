@@ -0,0 +1,723 @@
+//! Incremental recompilation, for tools (e.g. an IDE) which need to check the
+//! same file again after every edit.
+//!
+//! A [`Session`] remembers the [`CompilationUnit`] from the last time it was
+//! updated, along with a fingerprint of each [`Item`] in the AST. When the
+//! next AST comes in, only the items which changed (or which refer to
+//! something that changed) go through symbol discovery, variable discovery,
+//! name resolution, and lowering again. Everything else is reused from the
+//! previous [`CompilationUnit`].
+//!
+//! Fingerprints are calculated with spans relative to the start of the item,
+//! so editing the code above an item just moves its spans instead of
+//! invalidating it.
+
+use crate::analysis::ControlFlowCache;
+use crate::ecs::{Entities, EntityId, FromResources, Resources};
+use crate::hir::{
+    BasicBlock, CompilationUnit, Instruction, Operand, Span, Symbol,
+    Terminator, Variable,
+};
+use crate::passes::symbol_table::SymbolTable;
+use crate::passes::{
+    self, BasicBlocks, ConstantFolding, ControlFlowAnalysis, DataflowLints,
+    NameResolution, PassContext, PassManager, PassOptions, ProcessImageLayout,
+    SymbolTableResolution, VariableDiscovery,
+};
+use crate::Diagnostics;
+use codespan::{ByteIndex, ByteOffset, ByteSpan};
+use iec_syntax::{AstNode, File, Item};
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+
+/// A long-lived compilation session which reuses as much work as possible
+/// between updates.
+#[derive(Debug, Default)]
+pub struct Session {
+    unit: Option<CompilationUnit>,
+    items: Vec<Fingerprint>,
+    /// Can the current [`CompilationUnit`] be updated in place? Half-analysed
+    /// code (i.e. after an error) is always recompiled from scratch.
+    reusable: bool,
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session::default()
+    }
+
+    /// The result of the most recent update, if there has been one.
+    pub fn unit(&self) -> Option<&CompilationUnit> {
+        self.unit.as_ref()
+    }
+
+    pub fn into_unit(self) -> Option<CompilationUnit> {
+        self.unit
+    }
+
+    /// Run semantic analysis over a new version of the file, recompiling
+    /// only the items affected by the changes since the last update.
+    ///
+    /// Every diagnostic for the file is reported, not just those for the
+    /// items which were recompiled.
+    pub fn update(&mut self, ast: &File, ctx: &mut PassContext<'_>) -> Changes {
+        let fingerprints: Vec<_> =
+            ast.items.iter().map(Fingerprint::of).collect();
+
+        let plan = if self.reusable {
+            Plan::new(&self.items, &fingerprints)
+        } else {
+            None
+        };
+
+        let mut diags = Diagnostics::new();
+        let changes = {
+            let mut ctx = PassContext {
+                diags: &mut diags,
+                logger: ctx.logger.new(slog::o!("stage" => "incremental")),
+            };
+
+            match (plan, self.unit.as_mut()) {
+                (Some(plan), Some(unit)) => {
+                    recompile(unit, ast, &fingerprints, &plan, &mut ctx)
+                }
+                _ => {
+                    self.unit = Some(passes::process(ast, &mut ctx));
+                    Changes {
+                        full_rebuild: true,
+                        recompiled: fingerprints
+                            .iter()
+                            .filter_map(|f| f.name.clone())
+                            .collect(),
+                        ..Default::default()
+                    }
+                }
+            }
+        };
+
+        slog::debug!(ctx.logger, "Updated the compilation unit";
+            "full-rebuild" => changes.full_rebuild,
+            "recompiled" => changes.recompiled.len(),
+            "reused" => changes.reused.len(),
+            "removed" => changes.removed.len());
+
+        self.reusable = !diags.has_errors();
+        self.items = fingerprints;
+        ctx.diags.extend(diags);
+
+        changes
+    }
+}
+
+/// What happened during a [`Session::update()`].
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Changes {
+    /// Was the file compiled from scratch?
+    pub full_rebuild: bool,
+    /// Items which went through semantic analysis again.
+    pub recompiled: Vec<String>,
+    /// Items whose HIR was reused from the previous [`CompilationUnit`].
+    pub reused: Vec<String>,
+    /// Items which no longer exist.
+    pub removed: Vec<String>,
+}
+
+/// Apply a [`Plan`] to the previous [`CompilationUnit`].
+fn recompile(
+    unit: &mut CompilationUnit,
+    ast: &File,
+    fingerprints: &[Fingerprint],
+    plan: &Plan,
+    ctx: &mut PassContext<'_>,
+) -> Changes {
+    let resources = &mut unit.resources;
+
+    for name in plan.affected.iter().chain(&plan.removed) {
+        tear_down(resources, name);
+    }
+    for (name, offset) in &plan.moved {
+        relocate(resources, name, *offset);
+    }
+
+    let mut changes = Changes {
+        removed: plan.removed.clone(),
+        ..Default::default()
+    };
+    let mut items = Vec::new();
+
+    for (item, fingerprint) in ast.items.iter().zip(fingerprints) {
+        let name = match fingerprint.name {
+            Some(ref name) => name,
+            None => continue,
+        };
+
+        if plan.affected.contains(&name.to_lowercase()) {
+            items.push(item.clone());
+            changes.recompiled.push(name.clone());
+        } else {
+            changes.reused.push(name.clone());
+        }
+    }
+
+    let subset = File {
+        items,
+        span: ast.span,
+    };
+    incremental_analysis()
+        .run(resources, &subset, &PassOptions::default(), ctx)
+        .expect("The incremental pipeline is always valid");
+
+    changes
+}
+
+/// The passes which are re-run for the items affected by a change.
+///
+/// Builtins and global variables are reused, while the process image and the
+/// passes after lowering work on the whole [`CompilationUnit`] and are cheap
+/// enough to re-run in full.
+fn incremental_analysis() -> PassManager<File> {
+    PassManager::new()
+        .assume_provided("builtins")
+        .add_with_input::<SymbolTableResolution>()
+        .add_with_input::<VariableDiscovery>()
+        .add::<ProcessImageLayout>()
+        .add_with_input::<NameResolution>()
+        .add_with_input::<BasicBlocks>()
+        .add::<ConstantFolding>()
+        .add::<ControlFlowAnalysis>()
+        .add::<DataflowLints>()
+}
+
+/// Delete everything which was generated for an item.
+fn tear_down(resources: &mut Resources, name: &str) {
+    let symbol = match resources
+        .get_singleton_mut::<SymbolTable>()
+        .remove_item(name)
+    {
+        Some(symbol) => symbol,
+        None => return,
+    };
+    resources
+        .get_singleton_mut::<ControlFlowCache>()
+        .invalidate(symbol);
+
+    let mut doomed = vec![EntityId::from(symbol)];
+    doomed.extend(variables_of(resources, symbol));
+
+    let blocks = resources.get::<BasicBlock>();
+    let instructions = resources.get::<Instruction>();
+
+    for (id, block) in blocks.iter().filter(|(_, b)| b.parent == symbol) {
+        doomed.push(id);
+
+        for &id in &block.instructions {
+            doomed.push(id);
+            let instruction =
+                instructions.get(id).expect("all instructions exist");
+            doomed.extend(
+                instruction.operands().into_iter().filter_map(constant),
+            );
+        }
+
+        if let Terminator::Branch { condition, .. } = block.terminator {
+            doomed.extend(constant(condition));
+        }
+    }
+
+    let entities = Entities::from_resources(resources);
+    for id in doomed {
+        entities.delete(id);
+    }
+
+    drop((blocks, instructions));
+    resources.maintain();
+}
+
+/// Shift the spans for everything generated from an item.
+fn relocate(resources: &mut Resources, name: &str, offset: ByteOffset) {
+    let mut symbols = resources.get_singleton_mut::<SymbolTable>();
+    symbols.relocate_item(name, offset);
+    let symbol = match symbols.get(name) {
+        Some(symbol) => symbol,
+        None => return,
+    };
+
+    let mut owned = variables_of(resources, symbol);
+    let blocks = resources.get::<BasicBlock>();
+    for (_, block) in blocks.iter().filter(|(_, b)| b.parent == symbol) {
+        owned.extend(block.instructions.iter().cloned());
+    }
+
+    let mut spans = resources.get_mut::<Span>();
+    for id in owned {
+        if let Some(span) = spans.get_mut(id) {
+            span.0 =
+                ByteSpan::new(span.0.start() + offset, span.0.end() + offset);
+        }
+    }
+}
+
+fn variables_of(resources: &Resources, symbol: Symbol) -> Vec<EntityId> {
+    let variables = resources.get::<Variable>();
+    variables
+        .iter()
+        .filter(|(_, v)| v.parent == symbol)
+        .map(|(id, _)| id)
+        .collect()
+}
+
+fn constant(operand: Operand) -> Option<EntityId> {
+    match operand {
+        Operand::Constant(id) => Some(id),
+        Operand::Variable(_) => None,
+    }
+}
+
+/// Which items need to be recompiled, and which can be reused.
+#[derive(Debug, Clone, PartialEq)]
+struct Plan {
+    /// The (lower-cased) names of items which must be recompiled.
+    affected: HashSet<String>,
+    /// Items which no longer exist.
+    removed: Vec<String>,
+    /// Reused items which are now at a different location in the file.
+    moved: Vec<(String, ByteOffset)>,
+}
+
+impl Plan {
+    /// Work out what needs to be recompiled, returning `None` if everything
+    /// should be compiled from scratch.
+    fn new(previous: &[Fingerprint], current: &[Fingerprint]) -> Option<Plan> {
+        // global variables are visible to everything, so any change to them
+        // (or even just moving them) invalidates the whole file
+        let globals = |fingerprints: &[Fingerprint]| -> Vec<(u64, ByteIndex)> {
+            fingerprints
+                .iter()
+                .filter(|f| f.name.is_none())
+                .map(|f| (f.hash, f.start))
+                .collect()
+        };
+        if globals(previous) != globals(current) {
+            return None;
+        }
+
+        let before: HashMap<_, _> = previous
+            .iter()
+            .filter_map(|f| Some((f.key()?, f)))
+            .collect();
+        let after: HashMap<_, _> =
+            current.iter().filter_map(|f| Some((f.key()?, f))).collect();
+
+        if after.len() != current.iter().filter(|f| f.name.is_some()).count() {
+            // duplicate names need the full diagnostics
+            return None;
+        }
+
+        let removed: Vec<_> = previous
+            .iter()
+            .filter(|f| f.key().is_some_and(|key| !after.contains_key(&key)))
+            .filter_map(|f| f.name.clone())
+            .collect();
+
+        let mut changed: HashSet<_> = after
+            .iter()
+            .filter(|(key, f)| {
+                before.get(*key).is_none_or(|b| b.hash != f.hash)
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        changed.extend(removed.iter().map(|name| name.to_lowercase()));
+
+        // anything referring to a changed item also needs recompiling, and so
+        // on transitively
+        let mut affected = changed;
+        loop {
+            let dependents: Vec<_> = after
+                .iter()
+                .filter(|(key, _)| !affected.contains(*key))
+                .filter(|(_, f)| {
+                    f.references.iter().any(|r| affected.contains(r))
+                })
+                .map(|(key, _)| key.clone())
+                .collect();
+
+            if dependents.is_empty() {
+                break;
+            }
+            affected.extend(dependents);
+        }
+
+        // configurations and global variables refer to items by entity (e.g.
+        // a program instance or a function block typed global), so they would
+        // be left pointing at deleted entities
+        let refers_to_affected = current
+            .iter()
+            .filter(|f| f.name.is_none())
+            .any(|f| f.references.iter().any(|r| affected.contains(r)));
+        if refers_to_affected {
+            return None;
+        }
+
+        let moved = after
+            .iter()
+            .filter(|(key, _)| !affected.contains(*key))
+            .filter_map(|(key, f)| {
+                let offset = f.start - before[key].start;
+                if offset == ByteOffset(0) {
+                    None
+                } else {
+                    Some((key.clone(), offset))
+                }
+            })
+            .collect();
+
+        affected.retain(|key| after.contains_key(key));
+
+        Some(Plan {
+            affected,
+            removed,
+            moved,
+        })
+    }
+}
+
+/// A summary of an [`Item`], used to detect when it has changed.
+#[derive(Debug, Clone, PartialEq)]
+struct Fingerprint {
+    /// The item's name, or `None` for a block of global variables or a
+    /// configuration.
+    name: Option<String>,
+    /// A hash of the item's contents, with spans made relative to `start`.
+    hash: u64,
+    start: ByteIndex,
+    /// The (lower-cased) names mentioned by the item.
+    references: HashSet<String>,
+}
+
+impl Fingerprint {
+    fn of(item: &Item) -> Fingerprint {
+        let start = item.span().start();
+        let mut value = serde_json::to_value(item)
+            .expect("AST nodes can always be serialized");
+        let mut references = HashSet::new();
+        normalise(&mut value, start, &mut references);
+
+        let mut hasher = DefaultHasher::new();
+        value.to_string().hash(&mut hasher);
+
+        let name = match item {
+            Item::Program(ref p) => Some(p.name.value.clone()),
+            Item::Function(ref f) => Some(f.name.value.clone()),
+            Item::FunctionBlock(ref fb) => Some(fb.name.value.clone()),
+            // configurations declare global variables and refer to other
+            // items, so they're treated like a block of globals
+            Item::VarBlock(_) | Item::Configuration(_) => None,
+        };
+
+        Fingerprint {
+            name,
+            hash: hasher.finish(),
+            start,
+            references,
+        }
+    }
+
+    /// The name used to look this item up, names are case-insensitive.
+    fn key(&self) -> Option<String> {
+        self.name.as_ref().map(|name| name.to_lowercase())
+    }
+}
+
+/// Walk the serialized form of an AST node, making each span relative to
+/// `start` and recording the names of any identifiers.
+fn normalise(
+    value: &mut Value,
+    start: ByteIndex,
+    references: &mut HashSet<String>,
+) {
+    match value {
+        Value::Object(ref mut fields) => {
+            // identifiers are the only nodes with just a value and a span
+            if fields.len() == 2 && fields.contains_key("span") {
+                if let Some(Value::String(name)) = fields.get("value") {
+                    references.insert(name.to_lowercase());
+                }
+            }
+
+            for (key, field) in fields.iter_mut() {
+                if key == "span" {
+                    relative_span(field, start);
+                } else {
+                    normalise(field, start, references);
+                }
+            }
+        }
+        Value::Array(ref mut items) => {
+            for item in items {
+                normalise(item, start, references);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn relative_span(span: &mut Value, start: ByteIndex) {
+    if let Value::Object(ref mut fields) = span {
+        for index in fields.values_mut() {
+            if let Some(n) = index.as_u64() {
+                *index = Value::from(n - u64::from(start.0));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ecs::Join;
+    use crate::hir::{Function, ProcessImage, Program, ProgramInstance};
+
+    const ORIGINAL: &str = "
+        FUNCTION add : int
+        BEGIN
+            VAR_INPUT
+                a: int;
+                b: int;
+            END_VAR
+            add := a + b;
+        END_FUNCTION
+
+        FUNCTION double : int
+        BEGIN
+            VAR_INPUT
+                x: int;
+            END_VAR
+            double := x * 2;
+        END_FUNCTION
+
+        PROGRAM main
+            VAR
+                total: int;
+            END_VAR
+            total := add(a := total, b := 1);
+        END_PROGRAM";
+
+    fn update(session: &mut Session, src: &str) -> (Changes, Diagnostics) {
+        let ast: File = src.parse().unwrap();
+        let mut diags = Diagnostics::new();
+        let changes =
+            session.update(&ast, &mut PassContext::new_nop_logger(&mut diags));
+
+        (changes, diags)
+    }
+
+    fn session() -> Session {
+        let mut session = Session::new();
+        let (changes, diags) = update(&mut session, ORIGINAL);
+        assert!(!diags.has_errors(), "{:?}", diags);
+        assert!(changes.full_rebuild);
+        session
+    }
+
+    fn symbol(session: &Session, name: &str) -> Symbol {
+        session
+            .unit()
+            .unwrap()
+            .resources
+            .get_singleton::<SymbolTable>()
+            .get(name)
+            .unwrap()
+    }
+
+    /// The name and span of every variable belonging to an item, which
+    /// should be the same no matter how the item was compiled.
+    fn variable_spans(
+        unit: &CompilationUnit,
+        name: &str,
+    ) -> Vec<(Option<String>, ByteSpan)> {
+        let symbols = unit.resources.get_singleton::<SymbolTable>();
+        let variables = unit.resources.get::<Variable>();
+        let spans = unit.resources.get::<Span>();
+        let symbol = symbols.get(name).unwrap();
+
+        let mut got: Vec<_> = (variables.children_of(symbol), &*spans)
+            .join()
+            .map(|(_, (v, span))| (v.name.clone(), span.0))
+            .collect();
+        got.sort_by_key(|(_, span)| span.start());
+        got
+    }
+
+    #[test]
+    fn unchanged_items_are_reused() {
+        let mut session = session();
+        let add = symbol(&session, "add");
+        let main = symbol(&session, "main");
+
+        let edited = ORIGINAL.replace("b := 1", "b := 2");
+        let (changes, diags) = update(&mut session, &edited);
+
+        assert!(!diags.has_errors(), "{:?}", diags);
+        assert!(!changes.full_rebuild);
+        assert_eq!(changes.recompiled, vec!["main"]);
+        assert_eq!(changes.reused, vec!["add", "double"]);
+        assert_eq!(symbol(&session, "add"), add);
+        assert_ne!(symbol(&session, "main"), main);
+        let unit = session.unit().unwrap();
+        assert!(!unit.resources.is_alive(main.into()));
+        assert_eq!(unit.resources.get::<Program>().len(), 1);
+    }
+
+    #[test]
+    fn dependents_are_recompiled() {
+        let mut session = session();
+
+        let edited = ORIGINAL.replace("add := a + b", "add := a - b");
+        let (changes, diags) = update(&mut session, &edited);
+
+        assert!(!diags.has_errors(), "{:?}", diags);
+        assert_eq!(changes.recompiled, vec!["add", "main"]);
+        assert_eq!(changes.reused, vec!["double"]);
+    }
+
+    #[test]
+    fn edits_above_an_item_only_move_it() {
+        let mut session = session();
+
+        let edited = ORIGINAL.replace("add := a + b;", "add := a + b;\n\n");
+        let (changes, _) = update(&mut session, &edited);
+
+        assert_eq!(changes.recompiled, vec!["add", "main"]);
+        assert_eq!(changes.reused, vec!["double"]);
+
+        let ast: File = edited.parse().unwrap();
+        let mut diags = Diagnostics::new();
+        let fresh =
+            passes::process(&ast, &mut PassContext::new_nop_logger(&mut diags));
+        let unit = session.unit().unwrap();
+        for name in &["add", "double", "main"] {
+            assert_eq!(
+                variable_spans(unit, name),
+                variable_spans(&fresh, name)
+            );
+        }
+    }
+
+    #[test]
+    fn removed_items_are_deleted() {
+        let mut session = session();
+        let double = symbol(&session, "double");
+
+        let start = ORIGINAL.find("FUNCTION double").unwrap();
+        let end = ORIGINAL.find("PROGRAM main").unwrap();
+        let edited = ORIGINAL.replace(&ORIGINAL[start..end], "");
+        let (changes, diags) = update(&mut session, &edited);
+
+        assert!(!diags.has_errors(), "{:?}", diags);
+        assert_eq!(changes.removed, vec!["double"]);
+        assert!(changes.recompiled.is_empty());
+        let unit = session.unit().unwrap();
+        assert!(unit
+            .resources
+            .get::<Function>()
+            .iter()
+            .all(|(_, f)| f.name != "double"));
+        assert!(!unit.resources.is_alive(double.into()));
+        assert!(unit
+            .resources
+            .get::<Variable>()
+            .iter()
+            .all(|(_, v)| v.parent != double));
+    }
+
+    #[test]
+    fn errors_force_a_full_rebuild() {
+        let mut session = session();
+
+        let broken = ORIGINAL.replace("a + b", "a + missing");
+        let (_, diags) = update(&mut session, &broken);
+        assert!(diags.has_errors());
+
+        let (changes, diags) = update(&mut session, ORIGINAL);
+        assert!(!diags.has_errors(), "{:?}", diags);
+        assert!(changes.full_rebuild);
+    }
+
+    #[test]
+    fn configured_programs_force_a_full_rebuild() {
+        let src = format!(
+            "{}
+            CONFIGURATION plc
+                RESOURCE cpu
+                    PROGRAM instance : main;
+                END_RESOURCE
+            END_CONFIGURATION",
+            ORIGINAL
+        );
+        let mut session = Session::new();
+        update(&mut session, &src);
+
+        let edited = src.replace("b := 1", "b := 2");
+        let (changes, diags) = update(&mut session, &edited);
+
+        assert!(!diags.has_errors(), "{:?}", diags);
+        assert!(changes.full_rebuild);
+        {
+            let resources = &session.unit().unwrap().resources;
+            let instances = resources.get::<ProgramInstance>();
+            assert_eq!(instances.len(), 1);
+            assert!(instances
+                .iter()
+                .all(|(_, instance)| resources.is_alive(instance.program)));
+        }
+
+        // editing something the configuration doesn't use is still
+        // incremental
+        let edited = edited.replace("double := x * 2", "double := x * 3");
+        let (changes, _) = update(&mut session, &edited);
+
+        assert!(!changes.full_rebuild);
+        assert_eq!(changes.recompiled, vec!["double"]);
+    }
+
+    #[test]
+    fn the_process_image_follows_recompiled_programs() {
+        let src = "
+            PROGRAM main
+                VAR
+                    start AT %IX0.1 : bool;
+                    running AT %QX0.0 : bool;
+                END_VAR
+                running := start;
+            END_PROGRAM";
+        let mut session = Session::new();
+        update(&mut session, src);
+
+        let edited = src.replace("running := start", "running := NOT start");
+        let (changes, diags) = update(&mut session, &edited);
+
+        assert!(!diags.has_errors(), "{:?}", diags);
+        assert_eq!(changes.recompiled, vec!["main"]);
+        let resources = &session.unit().unwrap().resources;
+        let image = resources.get_singleton::<ProcessImage>();
+        assert_eq!(image.points.len(), 2);
+        assert!(image
+            .points
+            .iter()
+            .all(|point| resources.is_alive(point.variable)));
+    }
+
+    #[test]
+    fn changing_globals_recompiles_everything() {
+        let mut session = session();
+
+        let edited =
+            format!("VAR_GLOBAL\n counter: int;\n END_VAR\n{}", ORIGINAL);
+        let (changes, diags) = update(&mut session, &edited);
+
+        assert!(!diags.has_errors(), "{:?}", diags);
+        assert!(changes.full_rebuild);
+    }
+}
@@ -0,0 +1,1309 @@
+//! A tree-walking interpreter for running Structured Text without any PLC
+//! hardware (e.g. from unit tests).
+//!
+//! The [`Interpreter`] walks the parsed AST directly, using the resolved
+//! [`CompilationUnit`] to figure out what each name refers to and what type
+//! each variable has. Program instances and global variables keep their state
+//! between cycles, as does each function block instance.
+
+pub(crate) mod stdlib;
+pub(crate) mod value;
+
+pub use self::value::{Instance, Overflow, Value};
+
+use crate::const_eval::{EvalError, IntegerType};
+use crate::ecs::{Container, EntityId, Ref};
+use crate::hir::{
+    BinaryOp, CompilationUnit, Function, FunctionBlock, GlobalVariables,
+    Program, Symbol, Type, UnaryOp, Variable, VariableKind,
+};
+use crate::passes::basic_blocks::binary_op;
+use crate::passes::name_resolution::Scope;
+use crate::passes::symbol_table::{Binding, ScopeId, SymbolTable};
+use codespan::ByteSpan;
+use codespan_reporting::{Diagnostic, Label};
+use iec_syntax::{
+    AstNode, DottedIdentifier, Expression, File, FunctionArg, FunctionCall,
+    Item, LiteralKind, Statement,
+};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+/// Executes Structured Text, one cycle at a time.
+///
+/// # Examples
+///
+/// ```rust
+/// # use iec::{Diagnostics, interpreter::{Interpreter, Value}};
+/// # use iec::passes::PassContext;
+/// let src = "
+///     PROGRAM main
+///         VAR
+///             count: int;
+///         END_VAR
+///         count := count + 1;
+///     END_PROGRAM";
+/// let ast: iec_syntax::File = src.parse().unwrap();
+/// let mut diags = Diagnostics::new();
+/// let cu = iec::process(&ast, &mut PassContext::new_nop_logger(&mut diags));
+///
+/// let mut interpreter = Interpreter::new(&ast, &cu);
+/// interpreter.run_cycles(3).unwrap();
+///
+/// assert_eq!(interpreter.get("main.count").unwrap().as_integer(), Some(3));
+/// ```
+pub struct Interpreter<'a> {
+    ast: &'a File,
+    overflow: Overflow,
+    symbols: Ref<'a, SymbolTable>,
+    types: Ref<'a, Container<Type>>,
+    variables: Ref<'a, Container<Variable>>,
+    programs: Ref<'a, Container<Program>>,
+    functions: Ref<'a, Container<Function>>,
+    function_blocks: Ref<'a, Container<FunctionBlock>>,
+    /// The body of each item, keyed by its lower-cased name.
+    bodies: HashMap<String, &'a [Statement]>,
+    globals: HashMap<EntityId, Value>,
+    /// The variables belonging to each program instance, in the order they
+    /// are executed.
+    instances: Vec<ProgramState>,
+}
+
+/// A named instance of a program and its variables.
+#[derive(Debug, Clone, PartialEq)]
+struct ProgramState {
+    name: String,
+    program: EntityId,
+    variables: HashMap<EntityId, Value>,
+}
+
+impl<'a> Interpreter<'a> {
+    /// Create an interpreter for a [`CompilationUnit`] which was analysed
+    /// without errors, with every variable set to its type's default value.
+    pub fn new(ast: &'a File, unit: &'a CompilationUnit) -> Interpreter<'a> {
+        let resources = &unit.resources;
+        let bodies = ast
+            .items
+            .iter()
+            .filter_map(|item| match item {
+                Item::Program(ref p) => Some((&p.name, &p.body)),
+                Item::Function(ref f) => Some((&f.name, &f.body)),
+                Item::FunctionBlock(ref fb) => Some((&fb.name, &fb.body)),
+                Item::VarBlock(_) | Item::Configuration(_) => None,
+            })
+            .map(|(name, body)| (name.value.to_lowercase(), body.as_slice()))
+            .collect();
+
+        let mut interpreter = Interpreter {
+            ast,
+            overflow: Overflow::default(),
+            symbols: resources.get_singleton(),
+            types: resources.get(),
+            variables: resources.get(),
+            programs: resources.get(),
+            functions: resources.get(),
+            function_blocks: resources.get(),
+            bodies,
+            globals: HashMap::new(),
+            instances: Vec::new(),
+        };
+
+        let globals: Vec<EntityId> = resources
+            .get::<GlobalVariables>()
+            .iter()
+            .flat_map(|(_, g)| g.variables.clone())
+            .collect();
+        interpreter.globals = interpreter.defaults(&globals);
+
+        // every program starts with a single instance named after itself
+        let names: Vec<&str> = interpreter.program_names().collect();
+        for name in names {
+            interpreter
+                .instantiate(name, name)
+                .expect("every program is registered");
+        }
+
+        interpreter
+    }
+
+    /// Make sure there is a program instance called `instance`, creating it
+    /// with default values if necessary. The same program may be
+    /// instantiated several times, and each instance keeps its own state.
+    pub fn instantiate(
+        &mut self,
+        instance: &str,
+        program: &str,
+    ) -> Result<(), RuntimeError> {
+        let id = match self.symbols.get(program) {
+            Some(Symbol::Program(id)) => id,
+            _ => return Err(RuntimeError::UnknownItem(program.to_string())),
+        };
+
+        match self.instance_index(instance) {
+            Some(index) if self.instances[index].program == id => Ok(()),
+            Some(_) => {
+                Err(RuntimeError::DuplicateInstance(instance.to_string()))
+            }
+            None => {
+                let variables = self.defaults(&self.program(id).variables);
+                self.instances.push(ProgramState {
+                    name: instance.to_string(),
+                    program: id,
+                    variables,
+                });
+                Ok(())
+            }
+        }
+    }
+
+    /// Choose what happens when integer arithmetic overflows.
+    pub fn with_overflow(mut self, overflow: Overflow) -> Interpreter<'a> {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Run every program instance once, in the order they were created.
+    pub fn run_cycle(&mut self) -> Result<(), RuntimeError> {
+        for index in 0..self.instances.len() {
+            self.run_instance(index)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn run_cycles(&mut self, cycles: u64) -> Result<(), RuntimeError> {
+        for _ in 0..cycles {
+            self.run_cycle()?;
+        }
+
+        Ok(())
+    }
+
+    /// Execute the body of a single program instance.
+    pub fn run_program(&mut self, instance: &str) -> Result<(), RuntimeError> {
+        let index = self
+            .instance_index(instance)
+            .ok_or_else(|| RuntimeError::UnknownItem(instance.to_string()))?;
+
+        self.run_instance(index)
+    }
+
+    fn run_instance(&mut self, index: usize) -> Result<(), RuntimeError> {
+        let id = self.instances[index].program;
+        let body = self.body(&self.program(id).name)?;
+
+        let mut locals = std::mem::take(&mut self.instances[index].variables);
+        let result = self.execute_item(Symbol::Program(id), body, &mut locals);
+        self.instances[index].variables = locals;
+
+        result
+    }
+
+    fn instance_index(&self, name: &str) -> Option<usize> {
+        self.instances
+            .iter()
+            .position(|i| i.name.eq_ignore_ascii_case(name))
+    }
+
+    /// The name of every program, in the order they were declared.
+    fn program_names(&self) -> impl Iterator<Item = &'a str> {
+        self.ast.items.iter().filter_map(|item| match item {
+            Item::Program(ref p) => Some(p.name.value.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Call a function with positional arguments, returning its result.
+    pub fn call_function(
+        &mut self,
+        name: &str,
+        args: &[Value],
+    ) -> Result<Value, RuntimeError> {
+        let id = match self.symbols.get(name) {
+            Some(Symbol::Function(id)) => id,
+            _ => return Err(RuntimeError::UnknownItem(name.to_string())),
+        };
+        let inputs: Vec<EntityId> = self
+            .parameters(Symbol::Function(id))
+            .into_iter()
+            .filter(|&p| self.variable(p).kind != VariableKind::Output)
+            .collect();
+
+        if args.len() > inputs.len() {
+            return Err(RuntimeError::UnknownItem(format!(
+                "{} (expected at most {} arguments)",
+                name,
+                inputs.len()
+            )));
+        }
+
+        let bound: Vec<_> =
+            inputs.into_iter().zip(args.iter().cloned()).collect();
+        let (result, _) =
+            self.invoke_function(id, bound, ByteSpan::default())?;
+
+        Ok(result.expect("functions always return a value"))
+    }
+
+    /// Read a variable, using either `"instance.variable"` or the name of a
+    /// global variable. Function block members can be accessed with extra
+    /// dots (e.g. `"main.timer.q"`).
+    pub fn get(&self, path: &str) -> Option<&Value> {
+        let (storage, pieces) = self.locate(path)?;
+        let locals = match storage {
+            Some(index) => &self.instances[index].variables,
+            None => &self.globals,
+        };
+
+        let (first, rest) = pieces.split_first()?;
+        let mut value = locals.get(first)?;
+
+        for member in rest {
+            value = match value {
+                Value::Instance(ref instance) => {
+                    instance.variables.get(member)?
+                }
+                _ => return None,
+            };
+        }
+
+        Some(value)
+    }
+
+    /// Overwrite a variable (e.g. to simulate an input), converting the value
+    /// to the variable's type.
+    pub fn set(
+        &mut self,
+        path: &str,
+        value: Value,
+    ) -> Result<(), RuntimeError> {
+        let unknown = || RuntimeError::UnknownItem(path.to_string());
+        let (storage, pieces) = self.locate(path).ok_or_else(unknown)?;
+        let target = *pieces.last().ok_or_else(unknown)?;
+        let value = self.convert(target, value, ByteSpan::default())?;
+
+        let locals = match storage {
+            Some(index) => &mut self.instances[index].variables,
+            None => &mut self.globals,
+        };
+
+        let slot = member_mut(locals, &pieces).ok_or_else(unknown)?;
+        *slot = value;
+        Ok(())
+    }
+
+    /// Find the program instance (if any) a path belongs to, and the
+    /// variables it passes through.
+    fn locate(&self, path: &str) -> Option<(Option<usize>, Vec<EntityId>)> {
+        // addresses like "%QX0.1" contain a dot but name a single variable
+        let pieces: Vec<&str> = if path.starts_with('%') {
+            vec![path]
+        } else {
+            path.split('.').collect()
+        };
+
+        let (instance, mut scope, names) = match self.instance_index(pieces[0])
+        {
+            Some(index) => {
+                let program = self.instances[index].program;
+                (Some(index), self.symbols.scope_of(program)?, &pieces[1..])
+            }
+            None => (None, self.symbols.global_scope(), &pieces[..]),
+        };
+
+        let mut resolved = Vec::new();
+
+        for name in names {
+            let id = match self.symbols.lookup_local(scope, name)?.binding {
+                Binding::Variable(id) => id,
+                Binding::Symbol(_) => return None,
+            };
+            resolved.push(id);
+            // members are looked up in the function block's own scope
+            if let Some(members) = self.symbols.scope_of(self.variable(id).ty) {
+                scope = members;
+            }
+        }
+
+        Some((instance, resolved))
+    }
+
+    fn body(&self, name: &str) -> Result<&'a [Statement], RuntimeError> {
+        self.bodies
+            .get(&name.to_lowercase())
+            .cloned()
+            .ok_or_else(|| RuntimeError::UnknownItem(name.to_string()))
+    }
+
+    fn program(&self, id: EntityId) -> &Program {
+        self.programs.get(id).expect("all programs are registered")
+    }
+
+    fn variable(&self, id: EntityId) -> &Variable {
+        self.variables
+            .get(id)
+            .expect("all variables are registered")
+    }
+
+    fn scope_of(&self, item: Symbol) -> ScopeId {
+        self.symbols
+            .scope_of(item.into())
+            .expect("every item has a scope")
+    }
+
+    /// Create the initial value for each variable.
+    fn defaults(&self, variables: &[EntityId]) -> HashMap<EntityId, Value> {
+        variables
+            .iter()
+            .filter(|id| !id.is_placeholder())
+            .map(|&id| (id, self.default_value(self.variable(id).ty)))
+            .collect()
+    }
+
+    fn default_value(&self, ty: EntityId) -> Value {
+        if let Some(fb) = self.function_blocks.get(ty) {
+            return Value::Instance(Instance {
+                function_block: ty,
+                variables: self.defaults(&fb.variables),
+            });
+        }
+
+        let name = self
+            .types
+            .get(ty)
+            .map(|t| t.name.to_lowercase())
+            .unwrap_or_default();
+
+        if let Some(ty) = IntegerType::from_name(&name) {
+            return Value::Integer { value: 0, ty };
+        }
+
+        match name.as_str() {
+            "bool" => Value::Bool(false),
+            "real" | "lreal" => Value::Real(0.0),
+            "string" | "char" => Value::String(String::new()),
+            // TIME and DATE are stored as a number of milliseconds
+            _ => Value::Integer {
+                value: 0,
+                ty: IntegerType::LInt,
+            },
+        }
+    }
+
+    /// Convert a value so it can be stored in a particular variable.
+    fn convert(
+        &self,
+        variable: EntityId,
+        value: Value,
+        span: ByteSpan,
+    ) -> Result<Value, RuntimeError> {
+        let expected = self.default_value(self.variable(variable).ty);
+
+        match (&expected, value) {
+            (&Value::Integer { ty, .. }, Value::Integer { value, .. }) => {
+                Ok(self.overflow.apply(value, ty))
+            }
+            (&Value::Real(_), value @ Value::Integer { .. })
+            | (&Value::Real(_), value @ Value::Real(_)) => {
+                Ok(Value::Real(value.as_real().expect("checked above")))
+            }
+            (&Value::Bool(_), value @ Value::Bool(_))
+            | (&Value::String(_), value @ Value::String(_)) => Ok(value),
+            (Value::Instance(e), Value::Instance(found))
+                if e.function_block == found.function_block =>
+            {
+                Ok(Value::Instance(found))
+            }
+            (_, found) => Err(RuntimeError::TypeMismatch {
+                expected: expected.type_name(),
+                found: found.type_name(),
+                span,
+            }),
+        }
+    }
+
+    fn execute_item(
+        &mut self,
+        item: Symbol,
+        body: &'a [Statement],
+        locals: &mut HashMap<EntityId, Value>,
+    ) -> Result<(), RuntimeError> {
+        let mut frame = Frame {
+            scope: self.scope_of(item),
+            locals,
+        };
+
+        self.block(&mut frame, body).map(|_| ())
+    }
+
+    fn block(
+        &mut self,
+        frame: &mut Frame<'_>,
+        statements: &'a [Statement],
+    ) -> Result<Flow, RuntimeError> {
+        for statement in statements {
+            match self.statement(frame, statement)? {
+                Flow::Continue => {}
+                flow => return Ok(flow),
+            }
+        }
+
+        Ok(Flow::Continue)
+    }
+
+    fn statement(
+        &mut self,
+        frame: &mut Frame<'_>,
+        statement: &'a Statement,
+    ) -> Result<Flow, RuntimeError> {
+        match statement {
+            Statement::Assignment(ref a) => {
+                let value = self.expression(frame, &a.value)?;
+                self.store(frame, &a.variable, value)?;
+            }
+            Statement::FunctionCall(ref call) => {
+                self.call(frame, call)?;
+            }
+            Statement::IfStatement(ref stmt) => {
+                if self.condition(frame, &stmt.condition)? {
+                    return self.block(frame, &stmt.body);
+                }
+            }
+            Statement::WhileLoop(ref stmt) => {
+                while self.condition(frame, &stmt.condition)? {
+                    match self.block(frame, &stmt.body)? {
+                        Flow::Continue => {}
+                        Flow::Exit => break,
+                        Flow::Return => return Ok(Flow::Return),
+                    }
+                }
+            }
+            Statement::RepeatLoop(ref stmt) => loop {
+                match self.block(frame, &stmt.body)? {
+                    Flow::Continue => {}
+                    Flow::Exit => break,
+                    Flow::Return => return Ok(Flow::Return),
+                }
+
+                if self.condition(frame, &stmt.condition)? {
+                    break;
+                }
+            },
+            Statement::ForLoop(ref stmt) => return self.for_loop(frame, stmt),
+            Statement::Exit(_) => return Ok(Flow::Exit),
+            Statement::Return(_) => return Ok(Flow::Return),
+        }
+
+        Ok(Flow::Continue)
+    }
+
+    fn for_loop(
+        &mut self,
+        frame: &mut Frame<'_>,
+        stmt: &'a iec_syntax::ForLoop,
+    ) -> Result<Flow, RuntimeError> {
+        let counter = DottedIdentifier::from(stmt.variable.clone());
+        let start = self.expression(frame, &stmt.start)?;
+        // the end and step are only evaluated once
+        let end = self.expression(frame, &stmt.end)?;
+        let step = match stmt.step {
+            Some(ref step) => self.expression(frame, step)?,
+            None => Value::Integer {
+                value: 1,
+                ty: IntegerType::DInt,
+            },
+        };
+        let counting_up = step.as_real().is_none_or(|s| s >= 0.0);
+        let keep_going = if counting_up {
+            BinaryOp::LessThanOrEqual
+        } else {
+            BinaryOp::GreaterThanOrEqual
+        };
+
+        self.store(frame, &counter, start)?;
+
+        loop {
+            let current = self.load(frame, &counter)?;
+            let condition = self.evaluate(
+                value::binary(keep_going, &current, &end, self.overflow),
+                stmt.span,
+            )?;
+            if condition.as_bool() != Some(true) {
+                break;
+            }
+
+            match self.block(frame, &stmt.body)? {
+                Flow::Continue => {}
+                Flow::Exit => break,
+                Flow::Return => return Ok(Flow::Return),
+            }
+
+            let current = self.load(frame, &counter)?;
+            let next = self.evaluate(
+                value::binary(BinaryOp::Add, &current, &step, self.overflow),
+                stmt.span,
+            )?;
+            self.store(frame, &counter, next)?;
+        }
+
+        Ok(Flow::Continue)
+    }
+
+    fn condition(
+        &mut self,
+        frame: &mut Frame<'_>,
+        expr: &'a Expression,
+    ) -> Result<bool, RuntimeError> {
+        match self.expression(frame, expr)? {
+            Value::Bool(b) => Ok(b),
+            other => Err(RuntimeError::TypeMismatch {
+                expected: String::from("BOOL"),
+                found: other.type_name(),
+                span: expr.span(),
+            }),
+        }
+    }
+
+    fn expression(
+        &mut self,
+        frame: &mut Frame<'_>,
+        expr: &'a Expression,
+    ) -> Result<Value, RuntimeError> {
+        match expr {
+            Expression::Literal(ref lit) => Ok(match lit.kind {
+                LiteralKind::Boolean(b) => Value::Bool(b),
+                LiteralKind::Integer(i) => {
+                    let value = i128::from(i);
+                    Value::Integer {
+                        value,
+                        ty: IntegerType::for_literal(value),
+                    }
+                }
+                LiteralKind::Float(f) => Value::Real(f),
+                LiteralKind::String(ref s) => Value::String(s.clone()),
+            }),
+            Expression::Variable(ref path) => self.load(frame, path),
+            Expression::DirectAddress(ref address) => {
+                self.load(frame, &address.to_path())
+            }
+            Expression::Binary(ref bin) => {
+                let left = self.expression(frame, &bin.left)?;
+                let right = self.expression(frame, &bin.right)?;
+                let op = binary_op(bin.op);
+                self.evaluate(
+                    value::binary(op, &left, &right, self.overflow),
+                    bin.span,
+                )
+            }
+            Expression::Unary(ref un) => {
+                let value = self.expression(frame, &un.value)?;
+                let op = match un.op {
+                    iec_syntax::UnaryOp::Not => UnaryOp::Not,
+                    iec_syntax::UnaryOp::Negate => UnaryOp::Negate,
+                };
+                self.evaluate(value::unary(op, &value, self.overflow), un.span)
+            }
+            Expression::FunctionCall(ref call) => self
+                .call(frame, call)?
+                .ok_or_else(|| RuntimeError::TypeMismatch {
+                    expected: String::from("a value"),
+                    found: String::from("a function block call"),
+                    span: call.span,
+                }),
+        }
+    }
+
+    fn evaluate(
+        &self,
+        result: Result<Value, EvalError>,
+        span: ByteSpan,
+    ) -> Result<Value, RuntimeError> {
+        result.map_err(|error| RuntimeError::Eval { error, span })
+    }
+
+    /// Resolve a path to the variable at its root followed by any members
+    /// being accessed.
+    fn resolve(
+        &self,
+        frame: &Frame<'_>,
+        path: &DottedIdentifier,
+    ) -> Result<Vec<EntityId>, RuntimeError> {
+        Scope::new(&self.symbols, &self.function_blocks, frame.scope)
+            .resolve(path, &self.variables)
+            .map_err(RuntimeError::Unresolved)
+    }
+
+    fn load(
+        &self,
+        frame: &Frame<'_>,
+        path: &DottedIdentifier,
+    ) -> Result<Value, RuntimeError> {
+        let pieces = self.resolve(frame, path)?;
+        let locals: &HashMap<EntityId, Value> =
+            if self.variable(pieces[0]).kind == VariableKind::Global {
+                &self.globals
+            } else {
+                frame.locals
+            };
+
+        let mut value = locals.get(&pieces[0]);
+        for member in &pieces[1..] {
+            value = match value {
+                Some(Value::Instance(ref instance)) => {
+                    instance.variables.get(member)
+                }
+                _ => None,
+            };
+        }
+
+        Ok(value.cloned().unwrap_or_else(|| {
+            self.default_value(self.variable(pieces[pieces.len() - 1]).ty)
+        }))
+    }
+
+    fn store(
+        &mut self,
+        frame: &mut Frame<'_>,
+        path: &DottedIdentifier,
+        value: Value,
+    ) -> Result<(), RuntimeError> {
+        let pieces = self.resolve(frame, path)?;
+        let target = pieces[pieces.len() - 1];
+        let value = self.convert(target, value, path.span)?;
+
+        let locals = if self.variable(pieces[0]).kind == VariableKind::Global {
+            &mut self.globals
+        } else {
+            &mut *frame.locals
+        };
+
+        let slot = member_mut(locals, &pieces)
+            .ok_or_else(|| RuntimeError::UnknownItem(dotted(path)))?;
+        *slot = value;
+        Ok(())
+    }
+
+    /// The parameters which can be passed to a function or function block,
+    /// in the order they were declared.
+    fn parameters(&self, callee: Symbol) -> Vec<EntityId> {
+        let variables = match callee {
+            Symbol::Function(id) => {
+                &self.functions.get(id).expect("exists").variables
+            }
+            Symbol::FunctionBlock(id) => {
+                &self.function_blocks.get(id).expect("exists").variables
+            }
+            _ => unreachable!("only functions and function blocks are called"),
+        };
+
+        variables
+            .iter()
+            .cloned()
+            .filter(|&id| self.variable(id).kind.is_visible_to_caller())
+            .collect()
+    }
+
+    /// Call a function or function block instance, returning the function's
+    /// result.
+    fn call(
+        &mut self,
+        frame: &mut Frame<'_>,
+        call: &'a FunctionCall,
+    ) -> Result<Option<Value>, RuntimeError> {
+        let (callee, instance) =
+            Scope::new(&self.symbols, &self.function_blocks, frame.scope)
+                .lookup_callee(&call.name, &self.variables)
+                .map_err(RuntimeError::Unresolved)?;
+        let binding = self.bind(frame, callee, call)?;
+
+        let (result, outputs) = match (callee, instance) {
+            (Symbol::Function(id), _) => {
+                self.invoke_function(id, binding.inputs, call.span)?
+            }
+            (Symbol::FunctionBlock(fb), Some(_)) => {
+                let path = DottedIdentifier::from(call.name.clone());
+                let mut state = match self.load(frame, &path)? {
+                    Value::Instance(state) => state,
+                    _ => unreachable!(
+                        "instances always hold function block state"
+                    ),
+                };
+                let outputs =
+                    self.invoke_function_block(fb, &mut state, binding.inputs)?;
+                self.store(frame, &path, Value::Instance(state))?;
+                (None, outputs)
+            }
+            _ => unreachable!("name resolution only returns callable things"),
+        };
+
+        for (parameter, path) in binding.outputs {
+            if let Some(value) = outputs.get(&parameter) {
+                self.store(frame, path, value.clone())?;
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Evaluate the arguments being passed to a function or function block.
+    fn bind(
+        &mut self,
+        frame: &mut Frame<'_>,
+        callee: Symbol,
+        call: &'a FunctionCall,
+    ) -> Result<Arguments<'a>, RuntimeError> {
+        let parameters = self.parameters(callee);
+        let mut positional = parameters
+            .iter()
+            .cloned()
+            .filter(|&p| self.variable(p).kind != VariableKind::Output)
+            .collect::<Vec<_>>()
+            .into_iter();
+        let names: Vec<(EntityId, String)> = parameters
+            .iter()
+            .filter_map(|&p| Some((p, self.variable(p).name.clone()?)))
+            .collect();
+        let find = |name: &str| {
+            names
+                .iter()
+                .find(|(_, n)| n.eq_ignore_ascii_case(name))
+                .map(|&(p, _)| p)
+        };
+
+        let mut binding = Arguments::default();
+
+        for arg in &call.args {
+            match arg {
+                FunctionArg::Bare(ref expr) => {
+                    let parameter = positional.next();
+                    let value = self.expression(frame, expr)?;
+                    if let Some(p) = parameter {
+                        binding.inputs.push((p, value));
+                    }
+                }
+                FunctionArg::Named(ref assign) => {
+                    let parameter = find(&dotted(&assign.variable));
+                    let value = self.expression(frame, &assign.value)?;
+                    if let Some(p) = parameter {
+                        // in-out parameters are copied back afterwards
+                        if self.variable(p).kind == VariableKind::InOut {
+                            if let Expression::Variable(ref path) = assign.value
+                            {
+                                binding.outputs.push((p, path));
+                            }
+                        }
+                        binding.inputs.push((p, value));
+                    }
+                }
+                FunctionArg::Output(ref out) => {
+                    if let Some(p) = find(&out.parameter.value) {
+                        binding.outputs.push((p, &out.variable));
+                    }
+                }
+            }
+        }
+
+        Ok(binding)
+    }
+
+    /// Call a function, returning its result and the final value of each of
+    /// its parameters.
+    fn invoke_function(
+        &mut self,
+        id: EntityId,
+        inputs: Vec<(EntityId, Value)>,
+        span: ByteSpan,
+    ) -> Result<(Option<Value>, HashMap<EntityId, Value>), RuntimeError> {
+        let function = self.functions.get(id).expect("the function exists");
+        let name = function.name.clone();
+        let return_value = function.return_value;
+
+        let body = match self.bodies.get(&name.to_lowercase()) {
+            Some(&body) => body,
+            None => {
+                // it's from the standard library
+                let args: Vec<Value> = function
+                    .variables
+                    .iter()
+                    .filter_map(|p| inputs.iter().find(|(i, _)| i == p))
+                    .map(|(_, v)| v.clone())
+                    .collect();
+                let result = self.evaluate(
+                    stdlib::call(&name, &args, self.overflow),
+                    span,
+                )?;
+                return Ok((Some(result), HashMap::new()));
+            }
+        };
+
+        let mut locals = self.defaults(&function.variables);
+        for (parameter, value) in inputs {
+            let value = self.convert(parameter, value, span)?;
+            locals.insert(parameter, value);
+        }
+
+        self.execute_item(Symbol::Function(id), body, &mut locals)?;
+
+        let result = locals.get(&return_value).cloned();
+        Ok((result, locals))
+    }
+
+    /// Call a function block instance, returning the value of each of its
+    /// variables afterwards.
+    fn invoke_function_block(
+        &mut self,
+        fb: EntityId,
+        state: &mut Instance,
+        inputs: Vec<(EntityId, Value)>,
+    ) -> Result<HashMap<EntityId, Value>, RuntimeError> {
+        let name = self
+            .function_blocks
+            .get(fb)
+            .expect("the function block exists")
+            .name
+            .clone();
+        let body = self.body(&name)?;
+
+        for (parameter, value) in inputs {
+            let value = self.convert(parameter, value, ByteSpan::default())?;
+            state.variables.insert(parameter, value);
+        }
+
+        self.execute_item(
+            Symbol::FunctionBlock(fb),
+            body,
+            &mut state.variables,
+        )?;
+
+        Ok(state.variables.clone())
+    }
+}
+
+fn dotted(path: &DottedIdentifier) -> String {
+    let pieces: Vec<&str> =
+        path.pieces.iter().map(|p| p.value.as_str()).collect();
+    pieces.join(".")
+}
+
+/// Follow a chain of members down to the variable at the end.
+fn member_mut<'v>(
+    locals: &'v mut HashMap<EntityId, Value>,
+    pieces: &[EntityId],
+) -> Option<&'v mut Value> {
+    let (first, rest) = pieces.split_first()?;
+    let mut value = locals.get_mut(first)?;
+
+    for member in rest {
+        value = match value {
+            Value::Instance(ref mut instance) => {
+                instance.variables.get_mut(member)?
+            }
+            _ => return None,
+        };
+    }
+
+    Some(value)
+}
+
+/// The variables belonging to the item currently being executed.
+struct Frame<'f> {
+    scope: ScopeId,
+    locals: &'f mut HashMap<EntityId, Value>,
+}
+
+/// What to do after executing a statement.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Flow {
+    Continue,
+    /// Break out of the innermost loop.
+    Exit,
+    /// Return from the current item.
+    Return,
+}
+
+/// The arguments passed to a call.
+#[derive(Default)]
+struct Arguments<'a> {
+    inputs: Vec<(EntityId, Value)>,
+    /// Parameters which should be copied back to the caller afterwards.
+    outputs: Vec<(EntityId, &'a DottedIdentifier)>,
+}
+
+/// Something went wrong while executing code.
+#[derive(Debug, Clone)]
+pub enum RuntimeError {
+    /// An operation failed (e.g. division by zero).
+    Eval { error: EvalError, span: ByteSpan },
+    /// A value couldn't be stored in a variable.
+    TypeMismatch {
+        expected: String,
+        found: String,
+        span: ByteSpan,
+    },
+    /// A name couldn't be resolved, which normally means the code had errors
+    /// during semantic analysis.
+    Unresolved(Diagnostic),
+    /// There is no program, function, or variable with this name.
+    UnknownItem(String),
+    /// A program instance with this name already exists, but it's an
+    /// instance of a different program.
+    DuplicateInstance(String),
+}
+
+impl RuntimeError {
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        match *self {
+            RuntimeError::Eval { ref error, span } => error.to_diagnostic(span),
+            RuntimeError::TypeMismatch {
+                ref expected,
+                ref found,
+                span,
+            } => Diagnostic::new_error("Type mismatch").with_label(
+                Label::new_primary(span).with_message(format!(
+                    "expected {} but found {}",
+                    expected, found
+                )),
+            ),
+            RuntimeError::Unresolved(ref diag) => diag.clone(),
+            RuntimeError::UnknownItem(ref name) => {
+                Diagnostic::new_error(format!("Unknown item, \"{}\"", name))
+            }
+            RuntimeError::DuplicateInstance(ref name) => Diagnostic::new_error(
+                format!("The \"{}\" instance already exists", name),
+            ),
+        }
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            RuntimeError::Eval { ref error, .. } => {
+                write!(
+                    f,
+                    "{}",
+                    error.to_diagnostic(ByteSpan::default()).message
+                )
+            }
+            RuntimeError::TypeMismatch {
+                ref expected,
+                ref found,
+                ..
+            } => write!(f, "Expected {} but found {}", expected, found),
+            RuntimeError::Unresolved(ref diag) => write!(f, "{}", diag.message),
+            RuntimeError::UnknownItem(ref name) => {
+                write!(f, "Unknown item, \"{}\"", name)
+            }
+            RuntimeError::DuplicateInstance(ref name) => {
+                write!(f, "The \"{}\" instance already exists", name)
+            }
+        }
+    }
+}
+
+impl Error for RuntimeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::passes::PassContext;
+    use crate::Diagnostics;
+
+    fn compile(src: &str) -> (File, CompilationUnit) {
+        let ast: File = src.parse().unwrap();
+        let mut diags = Diagnostics::new();
+        let cu =
+            crate::process(&ast, &mut PassContext::new_nop_logger(&mut diags));
+        assert!(!diags.has_errors(), "{:?}", diags);
+
+        (ast, cu)
+    }
+
+    fn integer(interpreter: &Interpreter<'_>, path: &str) -> i128 {
+        interpreter
+            .get(path)
+            .and_then(Value::as_integer)
+            .unwrap_or_else(|| panic!("\"{}\" isn't an integer", path))
+    }
+
+    #[test]
+    fn program_state_persists_between_cycles() {
+        let src = "
+            PROGRAM main
+                VAR
+                    count: int;
+                    ticked: bool;
+                END_VAR
+                count := count + 2;
+                ticked := NOT ticked;
+            END_PROGRAM";
+        let (ast, cu) = compile(src);
+        let mut interpreter = Interpreter::new(&ast, &cu);
+
+        interpreter.run_cycles(5).unwrap();
+
+        assert_eq!(integer(&interpreter, "main.count"), 10);
+        assert_eq!(interpreter.get("main.ticked"), Some(&Value::Bool(true)));
+    }
+
+    #[test]
+    fn call_user_defined_functions() {
+        let src = "
+            FUNCTION add_one : int
+            BEGIN
+                VAR_INPUT
+                    x: int;
+                END_VAR
+                add_one := x + 1;
+            END_FUNCTION
+
+            PROGRAM main
+                VAR
+                    n: int;
+                END_VAR
+                n := add_one(x := n);
+                n := add_one(n);
+            END_PROGRAM";
+        let (ast, cu) = compile(src);
+        let mut interpreter = Interpreter::new(&ast, &cu);
+
+        interpreter.run_cycles(3).unwrap();
+
+        assert_eq!(integer(&interpreter, "main.n"), 6);
+        let got = interpreter
+            .call_function(
+                "add_one",
+                &[Overflow::Wrap.apply(41, IntegerType::Int)],
+            )
+            .unwrap();
+        assert_eq!(got.as_integer(), Some(42));
+    }
+
+    #[test]
+    fn each_function_block_instance_has_its_own_state() {
+        let src = "
+            FUNCTION_BLOCK counter
+                VAR_INPUT
+                    step: int;
+                END_VAR
+                VAR_OUTPUT
+                    total: int;
+                END_VAR
+            BEGIN
+                total := total + step;
+            END_FUNCTION_BLOCK
+
+            PROGRAM main
+                VAR
+                    slow: counter;
+                    fast: counter;
+                    copy: int;
+                END_VAR
+                slow(1);
+                fast(step := 10, total => copy);
+            END_PROGRAM";
+        let (ast, cu) = compile(src);
+        let mut interpreter = Interpreter::new(&ast, &cu);
+
+        interpreter.run_cycles(3).unwrap();
+
+        assert_eq!(integer(&interpreter, "main.slow.total"), 3);
+        assert_eq!(integer(&interpreter, "main.fast.total"), 30);
+        assert_eq!(integer(&interpreter, "main.copy"), 30);
+    }
+
+    #[test]
+    fn integers_wrap_or_saturate() {
+        let src = "
+            PROGRAM main
+                VAR
+                    x: sint;
+                    y: usint;
+                END_VAR
+                x := x + 100;
+                y := y - 1;
+            END_PROGRAM";
+        let (ast, cu) = compile(src);
+
+        let mut wrapping = Interpreter::new(&ast, &cu);
+        wrapping.run_cycles(2).unwrap();
+        assert_eq!(integer(&wrapping, "main.x"), -56);
+        assert_eq!(integer(&wrapping, "main.y"), 254);
+
+        let mut saturating =
+            Interpreter::new(&ast, &cu).with_overflow(Overflow::Saturate);
+        saturating.run_cycles(2).unwrap();
+        assert_eq!(integer(&saturating, "main.x"), 127);
+        assert_eq!(integer(&saturating, "main.y"), 0);
+    }
+
+    #[test]
+    fn loops_exit_and_return() {
+        let src = "
+            FUNCTION first_multiple : int
+            BEGIN
+                VAR_INPUT
+                    of: int;
+                END_VAR
+                VAR
+                    i: int;
+                END_VAR
+                FOR i := 1 TO 100 DO
+                    IF i % of = 0 AND i > 10 THEN
+                        first_multiple := i;
+                        RETURN;
+                    END_IF;
+                END_FOR;
+                first_multiple := -1;
+            END_FUNCTION
+
+            PROGRAM main
+                VAR
+                    i: int;
+                    total: int;
+                    repeats: int;
+                    multiple: int;
+                    down: int;
+                END_VAR
+                i := 0;
+                WHILE TRUE DO
+                    i := i + 1;
+                    IF i > 4 THEN
+                        EXIT;
+                    END_IF;
+                    total := total + i;
+                END_WHILE;
+
+                REPEAT
+                    repeats := repeats + 1;
+                UNTIL repeats >= 3
+                END_REPEAT;
+
+                FOR i := 10 TO 1 BY -3 DO
+                    down := down + 1;
+                END_FOR;
+
+                multiple := first_multiple(7);
+            END_PROGRAM";
+        let (ast, cu) = compile(src);
+        let mut interpreter = Interpreter::new(&ast, &cu);
+
+        interpreter.run_cycle().unwrap();
+
+        assert_eq!(integer(&interpreter, "main.total"), 1 + 2 + 3 + 4);
+        assert_eq!(integer(&interpreter, "main.repeats"), 3);
+        assert_eq!(integer(&interpreter, "main.down"), 4);
+        assert_eq!(integer(&interpreter, "main.multiple"), 14);
+    }
+
+    #[test]
+    fn call_the_standard_library() {
+        let src = "
+            PROGRAM main
+                VAR
+                    a: int;
+                    b: int;
+                    c: int;
+                    d: byte;
+                END_VAR
+                a := abs(-5) + max(3, 7);
+                b := limit(0, 250, 100);
+                c := sel(TRUE, 1, 2);
+                d := rol(d + 1, 1);
+            END_PROGRAM";
+        let (ast, cu) = compile(src);
+        let mut interpreter = Interpreter::new(&ast, &cu);
+
+        interpreter.run_cycles(2).unwrap();
+
+        assert_eq!(integer(&interpreter, "main.a"), 12);
+        assert_eq!(integer(&interpreter, "main.b"), 100);
+        assert_eq!(integer(&interpreter, "main.c"), 2);
+        assert_eq!(integer(&interpreter, "main.d"), 6);
+    }
+
+    #[test]
+    fn division_by_zero_is_an_error() {
+        let src = "
+            PROGRAM main
+                VAR
+                    x: int;
+                    zero: int;
+                END_VAR
+                x := 10 / zero;
+            END_PROGRAM";
+        let (ast, cu) = compile(src);
+        let mut interpreter = Interpreter::new(&ast, &cu);
+
+        let err = interpreter.run_cycle().unwrap_err();
+
+        match err {
+            RuntimeError::Eval {
+                error: EvalError::DivisionByZero,
+                span,
+            } => assert_eq!(
+                &src[span.start().to_usize()..span.end().to_usize()],
+                "10 / zero"
+            ),
+            other => panic!("Unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn globals_are_shared_and_inputs_can_be_set() {
+        let src = "
+            VAR_GLOBAL
+                shared: int;
+            END_VAR
+
+            PROGRAM first
+                VAR
+                    input: int;
+                END_VAR
+                shared := shared + input;
+            END_PROGRAM
+
+            PROGRAM second
+                VAR
+                    seen: int;
+                END_VAR
+                seen := shared;
+            END_PROGRAM";
+        let (ast, cu) = compile(src);
+        let mut interpreter = Interpreter::new(&ast, &cu);
+
+        interpreter
+            .set("first.input", Overflow::Wrap.apply(5, IntegerType::LInt))
+            .unwrap();
+        interpreter.run_cycles(2).unwrap();
+
+        assert_eq!(integer(&interpreter, "shared"), 10);
+        assert_eq!(integer(&interpreter, "second.seen"), 10);
+        assert!(interpreter.set("first.missing", Value::Bool(true)).is_err());
+    }
+
+    #[test]
+    fn undeclared_direct_addresses_can_be_used() {
+        let src = "
+            PROGRAM main
+                %QW4 := %IW2 + 1;
+                IF %IX0.1 THEN
+                    %QX0.1 := TRUE;
+                END_IF;
+            END_PROGRAM";
+        let (ast, cu) = compile(src);
+        let mut interpreter = Interpreter::new(&ast, &cu);
+
+        let input = Value::Integer {
+            value: 41,
+            ty: IntegerType::Word,
+        };
+        interpreter.set("%IW2", input).unwrap();
+        interpreter.set("%IX0.1", Value::Bool(true)).unwrap();
+        interpreter.run_cycle().unwrap();
+
+        assert_eq!(integer(&interpreter, "%QW4"), 42);
+        assert_eq!(interpreter.get("%QX0.1"), Some(&Value::Bool(true)));
+    }
+}
@@ -0,0 +1,112 @@
+//! Implementations of the [`BUILTIN_FUNCTIONS`].
+//!
+//! [`BUILTIN_FUNCTIONS`]: crate::passes::register_builtins::BUILTIN_FUNCTIONS
+
+use super::value::{self, Overflow, Value};
+use crate::const_eval::{EvalError, IntegerType};
+use crate::hir::BinaryOp;
+
+/// Call a standard library function, with arguments in the order its inputs
+/// were declared.
+pub fn call(
+    name: &str,
+    args: &[Value],
+    overflow: Overflow,
+) -> Result<Value, EvalError> {
+    match (name.to_lowercase().as_str(), args) {
+        ("abs", [x]) => abs(x, overflow),
+        ("min", [a, b]) => min(a, b, overflow),
+        ("max", [a, b]) => max(a, b, overflow),
+        ("limit", [mn, x, mx]) => {
+            let upper = min(x, mx, overflow)?;
+            max(mn, &upper, overflow)
+        }
+        ("sel", [g, in0, in1]) => match g.as_bool() {
+            Some(false) => Ok(in0.clone()),
+            Some(true) => Ok(in1.clone()),
+            None => Err(EvalError::InvalidOperands),
+        },
+        ("shl", [x, n]) => shift(x, n, |bits, x, n| x << n.min(bits)),
+        ("shr", [x, n]) => shift(x, n, |bits, x, n| x >> n.min(bits)),
+        ("rol", [x, n]) => shift(x, n, |bits, x, n| {
+            let n = n % bits;
+            (x << n) | (x >> (bits - n))
+        }),
+        ("ror", [x, n]) => shift(x, n, |bits, x, n| {
+            let n = n % bits;
+            (x >> n) | (x << (bits - n))
+        }),
+        _ => Err(EvalError::InvalidOperands),
+    }
+}
+
+fn abs(x: &Value, overflow: Overflow) -> Result<Value, EvalError> {
+    match *x {
+        Value::Integer { value, ty } => Ok(overflow.apply(value.abs(), ty)),
+        Value::Real(f) => Ok(Value::Real(f.abs())),
+        _ => Err(EvalError::InvalidOperands),
+    }
+}
+
+fn min(a: &Value, b: &Value, overflow: Overflow) -> Result<Value, EvalError> {
+    pick(a, b, BinaryOp::LessThanOrEqual, overflow)
+}
+
+fn max(a: &Value, b: &Value, overflow: Overflow) -> Result<Value, EvalError> {
+    pick(a, b, BinaryOp::GreaterThanOrEqual, overflow)
+}
+
+/// Return `a` if `a op b` holds, otherwise `b`, converting integers to their
+/// common type.
+fn pick(
+    a: &Value,
+    b: &Value,
+    op: BinaryOp,
+    overflow: Overflow,
+) -> Result<Value, EvalError> {
+    let keep_a = value::binary(op, a, b, overflow)?
+        .as_bool()
+        .ok_or(EvalError::InvalidOperands)?;
+    let picked = if keep_a { a } else { b };
+
+    match (a, b, picked) {
+        (
+            Value::Integer { ty: at, .. },
+            Value::Integer { ty: bt, .. },
+            Value::Integer { value, .. },
+        ) => Ok(overflow.apply(*value, at.common(*bt))),
+        _ => Ok(picked.clone()),
+    }
+}
+
+/// Apply a bit shift to the unsigned representation of an integer.
+fn shift<F>(x: &Value, n: &Value, op: F) -> Result<Value, EvalError>
+where
+    F: Fn(u32, u128, u32) -> u128,
+{
+    let (value, ty) = match *x {
+        Value::Integer { value, ty } => (value, ty),
+        _ => return Err(EvalError::InvalidOperands),
+    };
+    let n = match n.as_integer() {
+        Some(n) if n >= 0 => n.min(128) as u32,
+        _ => return Err(EvalError::InvalidOperands),
+    };
+
+    let bits = ty.bits();
+    let mask = (1_u128 << bits) - 1;
+    let unsigned = IntegerType::wrap(unsigned_of(ty), value) as u128;
+    let shifted = op(bits, unsigned, n) & mask;
+
+    Ok(Overflow::Wrap.apply(shifted as i128, ty))
+}
+
+/// The unsigned type with the same width as `ty`.
+fn unsigned_of(ty: IntegerType) -> IntegerType {
+    match ty.bits() {
+        8 => IntegerType::USInt,
+        16 => IntegerType::UInt,
+        32 => IntegerType::UDInt,
+        _ => IntegerType::ULInt,
+    }
+}
@@ -0,0 +1,222 @@
+use crate::const_eval::{self, bool_binary, compare, EvalError, IntegerType};
+use crate::ecs::EntityId;
+use crate::hir::{BinaryOp, UnaryOp};
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+
+/// What to do when the result of integer arithmetic doesn't fit in its type.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Overflow {
+    /// Wrap around, the same way two's complement arithmetic does on most
+    /// PLCs.
+    #[default]
+    Wrap,
+    /// Clamp the result to the type's minimum or maximum.
+    Saturate,
+}
+
+impl Overflow {
+    /// Make sure an integer fits in `ty`.
+    pub fn apply(self, value: i128, ty: IntegerType) -> Value {
+        let value = match self {
+            Overflow::Wrap => ty.wrap(value),
+            Overflow::Saturate => ty.saturate(value),
+        };
+
+        Value::Integer { value, ty }
+    }
+}
+
+/// A value at runtime.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Integer {
+        value: i128,
+        ty: IntegerType,
+    },
+    Real(f64),
+    String(String),
+    /// The state of a function block instance.
+    Instance(Instance),
+}
+
+impl Value {
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Value::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<i128> {
+        match *self {
+            Value::Integer { value, .. } => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_real(&self) -> Option<f64> {
+        match *self {
+            Value::Integer { value, .. } => Some(value as f64),
+            Value::Real(f) => Some(f),
+            _ => None,
+        }
+    }
+
+    /// A short description of the value's type, for use in error messages.
+    pub fn type_name(&self) -> String {
+        match *self {
+            Value::Bool(_) => String::from("BOOL"),
+            Value::Integer { ty, .. } => ty.to_string(),
+            Value::Real(_) => String::from("LREAL"),
+            Value::String(_) => String::from("STRING"),
+            Value::Instance(_) => String::from("function block instance"),
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            Value::Bool(true) => write!(f, "TRUE"),
+            Value::Bool(false) => write!(f, "FALSE"),
+            Value::Integer { value, .. } => write!(f, "{}", value),
+            Value::Real(r) => write!(f, "{:?}", r),
+            Value::String(ref s) => write!(f, "'{}'", s),
+            Value::Instance(_) => write!(f, "<instance>"),
+        }
+    }
+}
+
+impl From<const_eval::Value> for Value {
+    fn from(other: const_eval::Value) -> Value {
+        match other {
+            const_eval::Value::Bool(b) => Value::Bool(b),
+            const_eval::Value::Integer { value, ty } => {
+                Value::Integer { value, ty }
+            }
+            const_eval::Value::Real(f) => Value::Real(f),
+            const_eval::Value::String(s) => Value::String(s),
+        }
+    }
+}
+
+/// The variables belonging to a single function block instance.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instance {
+    pub function_block: EntityId,
+    pub variables: HashMap<EntityId, Value>,
+}
+
+/// Apply a binary operator at runtime.
+pub fn binary(
+    op: BinaryOp,
+    left: &Value,
+    right: &Value,
+    overflow: Overflow,
+) -> Result<Value, EvalError> {
+    match (left, right) {
+        (
+            Value::Integer { value: l, ty: lt },
+            Value::Integer { value: r, ty: rt },
+        ) => integer_binary(op, *l, *r, lt.common(*rt), overflow),
+        (Value::Bool(l), Value::Bool(r)) => {
+            bool_binary(op, *l, *r).map(Value::from)
+        }
+        (Value::String(l), Value::String(r)) => {
+            compare(op, l, r).map(Value::Bool)
+        }
+        _ => match (left.as_real(), right.as_real()) {
+            (Some(l), Some(r)) => real_binary(op, l, r),
+            _ => Err(EvalError::InvalidOperands),
+        },
+    }
+}
+
+/// Apply a unary operator at runtime.
+pub fn unary(
+    op: UnaryOp,
+    value: &Value,
+    overflow: Overflow,
+) -> Result<Value, EvalError> {
+    match (op, value) {
+        (UnaryOp::Not, &Value::Bool(b)) => Ok(Value::Bool(!b)),
+        // inverting the bits never overflows, it just needs the sign fixed
+        // up for unsigned types
+        (UnaryOp::Not, &Value::Integer { value, ty }) => {
+            Ok(Overflow::Wrap.apply(!value, ty))
+        }
+        (UnaryOp::Negate, &Value::Integer { value, ty }) => {
+            Ok(overflow.apply(-value, ty))
+        }
+        (UnaryOp::Negate, &Value::Real(f)) => Ok(Value::Real(-f)),
+        _ => Err(EvalError::InvalidOperands),
+    }
+}
+
+fn integer_binary(
+    op: BinaryOp,
+    left: i128,
+    right: i128,
+    ty: IntegerType,
+    overflow: Overflow,
+) -> Result<Value, EvalError> {
+    // operands are at most 64 bits wide, so everything except
+    // exponentiation fits in an i128
+    let value = match op {
+        BinaryOp::Add => left + right,
+        BinaryOp::Subtract => left - right,
+        BinaryOp::Multiply => left * right,
+        BinaryOp::Divide | BinaryOp::Modulo if right == 0 => {
+            return Err(EvalError::DivisionByZero)
+        }
+        BinaryOp::Divide => left / right,
+        BinaryOp::Modulo => left % right,
+        BinaryOp::Exponent => {
+            if right < 0 {
+                return Err(EvalError::NegativeExponent);
+            }
+            let exponent = right.min(i128::from(u32::MAX)) as u32;
+
+            match (left.checked_pow(exponent), overflow) {
+                (Some(value), _) => value,
+                // 2^128 is a multiple of every type's modulus
+                (None, Overflow::Wrap) => left.wrapping_pow(exponent),
+                (None, Overflow::Saturate) if left < 0 && exponent % 2 == 1 => {
+                    ty.min()
+                }
+                (None, Overflow::Saturate) => ty.max(),
+            }
+        }
+        BinaryOp::And => left & right,
+        BinaryOp::Or => left | right,
+        BinaryOp::Xor => left ^ right,
+        _ => return compare(op, &left, &right).map(Value::Bool),
+    };
+
+    Ok(overflow.apply(value, ty))
+}
+
+fn real_binary(
+    op: BinaryOp,
+    left: f64,
+    right: f64,
+) -> Result<Value, EvalError> {
+    let value = match op {
+        BinaryOp::Add => left + right,
+        BinaryOp::Subtract => left - right,
+        BinaryOp::Multiply => left * right,
+        BinaryOp::Divide if right == 0.0 => {
+            return Err(EvalError::DivisionByZero)
+        }
+        BinaryOp::Divide => left / right,
+        BinaryOp::Exponent => left.powf(right),
+        BinaryOp::Modulo | BinaryOp::And | BinaryOp::Or | BinaryOp::Xor => {
+            return Err(EvalError::InvalidOperands)
+        }
+        _ => return compare(op, &left, &right).map(Value::Bool),
+    };
+
+    Ok(Value::Real(value))
+}
@@ -0,0 +1,762 @@
+//! Laying out variables in memory, for backends which generate native code.
+
+use crate::const_eval::{IntegerType, Scalar};
+use crate::ecs::{Container, EntityId};
+use crate::hir::{
+    Function, FunctionBlock, GlobalVariables, Program, Symbol, Type, Variable,
+};
+use crate::interpreter::Value;
+use crate::CompilationUnit;
+use byteorder::ByteOrder;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+/// Where every variable lives in memory.
+///
+/// Each POU's variables (including temporaries) are stored in a [`Struct`]
+/// using the platform's natural alignment, so instance data can be shared
+/// with C code. Function block instances are nested inline.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layout {
+    pub globals: Struct,
+    /// Programs, then function blocks, then functions. Builtin functions are
+    /// always inlined so they don't need a layout.
+    pub pous: Vec<PouLayout>,
+    locations: HashMap<EntityId, Location>,
+    pou_indices: HashMap<Symbol, usize>,
+}
+
+impl Layout {
+    /// Work out where everything in a [`CompilationUnit`] is stored.
+    ///
+    /// This fails if a variable's type can't be represented in memory.
+    pub fn new(unit: &CompilationUnit) -> Result<Layout, LayoutError> {
+        lay_out(Declarations::new(unit)?)
+    }
+
+    /// Lay out a set of [`Declarations`] which may have been adjusted for a
+    /// particular backend (e.g. one which keeps temporaries on the stack).
+    pub fn from_declarations(
+        declarations: Declarations,
+    ) -> Result<Layout, LayoutError> {
+        lay_out(declarations)
+    }
+
+    pub fn pou(&self, name: &str) -> Option<&PouLayout> {
+        self.pous.iter().find(|p| p.name.eq_ignore_ascii_case(name))
+    }
+
+    /// The index of the [`PouLayout`] for an item, if it has one.
+    pub fn pou_index(&self, symbol: Symbol) -> Option<usize> {
+        self.pou_indices.get(&symbol).cloned()
+    }
+
+    /// Find a variable using its dotted path (e.g. `main.counter.total` or
+    /// the name of a global).
+    pub fn resolve(&self, path: &str) -> Option<Resolved> {
+        // "%QX0.1" is a single field rather than a member access
+        let pieces: Vec<&str> = if path.starts_with('%') {
+            vec![path]
+        } else {
+            path.split('.').collect()
+        };
+
+        let (program, mut frame, names) = match self.pous.iter().position(|p| {
+            p.kind == PouKind::Program && p.name.eq_ignore_ascii_case(pieces[0])
+        }) {
+            Some(index) => (Some(index), &self.pous[index].frame, &pieces[1..]),
+            None => (None, &self.globals, &pieces[..]),
+        };
+
+        let (last, parents) = names.split_last()?;
+        let mut offset = 0;
+
+        for name in parents {
+            let field = frame.field(name)?;
+            match field.ty {
+                FieldType::Instance(fb) => {
+                    offset += field.offset;
+                    frame = &self.pous[fb].frame;
+                }
+                _ => return None,
+            }
+        }
+
+        let field = frame.field(last)?;
+
+        Some(Resolved {
+            program,
+            offset: offset + field.offset,
+            ty: field.ty,
+        })
+    }
+
+    pub fn location(&self, variable: EntityId) -> Option<Location> {
+        self.locations.get(&variable).cloned()
+    }
+
+    pub fn field(&self, location: Location) -> &Field {
+        let fields = match location.owner {
+            Some(pou) => &self.pous[pou].frame.fields,
+            None => &self.globals.fields,
+        };
+
+        &fields[location.field]
+    }
+
+    pub fn size_of(&self, ty: FieldType) -> u32 {
+        match ty {
+            FieldType::Bool => 1,
+            FieldType::Integer(int) => int.bits() / 8,
+            FieldType::Real => 8,
+            FieldType::Instance(pou) => self.pous[pou].frame.size,
+        }
+    }
+}
+
+/// Read a value from memory which was laid out using a [`Layout`], where
+/// numbers are stored with the byte order, `B`.
+///
+/// Function block instances can't be read directly, only their fields.
+pub fn read<B: ByteOrder>(
+    memory: &[u8],
+    offset: usize,
+    ty: FieldType,
+) -> Option<Value> {
+    match ty {
+        FieldType::Bool => Some(Value::Bool(*memory.get(offset)? != 0)),
+        FieldType::Integer(int) => {
+            let size = int.bits() as usize / 8;
+            let bytes = memory.get(offset..offset + size)?;
+            let value = if int.is_signed() {
+                i128::from(B::read_int(bytes, size))
+            } else {
+                i128::from(B::read_uint(bytes, size))
+            };
+
+            Some(Value::Integer { value, ty: int })
+        }
+        FieldType::Real => {
+            Some(Value::Real(B::read_f64(memory.get(offset..offset + 8)?)))
+        }
+        FieldType::Instance(_) => None,
+    }
+}
+
+/// The index of a variable's [`Field`], and the POU it belongs to (`None` for
+/// globals).
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Location {
+    pub owner: Option<usize>,
+    pub field: usize,
+}
+
+/// The result of [`Layout::resolve()`].
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Resolved {
+    /// The index of the program the variable belongs to, or `None` for
+    /// globals.
+    pub program: Option<usize>,
+    /// The variable's offset from the start of the program's instance data
+    /// (or the globals).
+    pub offset: u32,
+    pub ty: FieldType,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PouLayout {
+    pub name: String,
+    pub kind: PouKind,
+    pub symbol: Symbol,
+    pub entry_block: EntityId,
+    /// The instance data for a program or function block, or the stack frame
+    /// for a function.
+    pub frame: Struct,
+    /// The field a function's result is written to.
+    pub return_field: Option<usize>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PouKind {
+    Program,
+    Function,
+    FunctionBlock,
+}
+
+impl PouKind {
+    /// The name a POU's function is given in the generated code.
+    pub fn function_name(self, pou: &str) -> String {
+        let prefix = match self {
+            PouKind::Program => "program",
+            PouKind::Function => "fn",
+            PouKind::FunctionBlock => "fb",
+        };
+
+        format!("iec_{}_{}", prefix, pou.to_lowercase())
+    }
+}
+
+impl Display for PouKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            PouKind::Program => write!(f, "PROGRAM"),
+            PouKind::Function => write!(f, "FUNCTION"),
+            PouKind::FunctionBlock => write!(f, "FUNCTION_BLOCK"),
+        }
+    }
+}
+
+/// A C-compatible struct.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Struct {
+    pub fields: Vec<Field>,
+    pub size: u32,
+    pub align: u32,
+}
+
+impl Struct {
+    pub fn field(&self, name: &str) -> Option<&Field> {
+        self.fields.iter().find(|f| {
+            f.name
+                .as_ref()
+                .is_some_and(|n| n.eq_ignore_ascii_case(name))
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    /// The variable's name, or `None` for temporaries.
+    pub name: Option<String>,
+    pub ty: FieldType,
+    /// The field's offset from the start of the [`Struct`], in bytes.
+    pub offset: u32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FieldType {
+    Bool,
+    Integer(IntegerType),
+    /// A 64-bit float.
+    Real,
+    /// An inline function block instance, referring to its [`PouLayout`].
+    Instance(usize),
+}
+
+impl Display for FieldType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            FieldType::Bool => write!(f, "BOOL"),
+            FieldType::Integer(ty) => write!(f, "{}", ty),
+            FieldType::Real => write!(f, "LREAL"),
+            FieldType::Instance(pou) => write!(f, "instance of @{}", pou),
+        }
+    }
+}
+
+impl FieldType {
+    /// The type of the value stored in this field, if it fits in a
+    /// register.
+    pub fn scalar(self) -> Option<Scalar> {
+        match self {
+            FieldType::Bool => Some(Scalar::Bool),
+            FieldType::Integer(int) => Some(Scalar::Integer(int)),
+            FieldType::Real => Some(Scalar::Real),
+            FieldType::Instance(_) => None,
+        }
+    }
+}
+
+/// Every POU and variable which needs to be stored somewhere, before any of
+/// them have been laid out in memory.
+///
+/// Backends which don't use a [`Layout`] (e.g. because the generated code is
+/// compiled by something else) can use this to declare their types.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Declarations {
+    /// Programs, then function blocks, then functions. Builtin functions are
+    /// always inlined so they aren't included.
+    pub pous: Vec<PouDeclarations>,
+    pub globals: Vec<Declaration>,
+    pou_indices: HashMap<Symbol, usize>,
+    definition_order: Vec<usize>,
+}
+
+impl Declarations {
+    /// Find every POU and variable in a [`CompilationUnit`].
+    ///
+    /// This fails if a function block contains itself, because its instance
+    /// data would be infinitely large.
+    pub fn new(unit: &CompilationUnit) -> Result<Declarations, LayoutError> {
+        let resources = &unit.resources;
+        let builder = Builder {
+            programs: &resources.get(),
+            functions: &resources.get(),
+            function_blocks: &resources.get(),
+            globals: &resources.get(),
+            types: &resources.get(),
+            variables: &resources.get(),
+        };
+
+        builder.build()
+    }
+
+    /// The index of the [`PouDeclarations`] for an item, if it has one.
+    pub fn pou_index(&self, symbol: Symbol) -> Option<usize> {
+        self.pou_indices.get(&symbol).cloned()
+    }
+
+    /// Every POU, ordered so a POU always comes after the function blocks it
+    /// contains.
+    pub fn definition_order(&self) -> &[usize] {
+        &self.definition_order
+    }
+
+    /// Every declaration, including globals and temporaries.
+    pub fn iter(&self) -> impl Iterator<Item = &Declaration> + '_ {
+        self.pous
+            .iter()
+            .flat_map(|pou| pou.variables.iter().chain(&pou.temporaries))
+            .chain(&self.globals)
+    }
+}
+
+/// The variables belonging to a program, function block, or function.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PouDeclarations {
+    pub name: String,
+    pub kind: PouKind,
+    pub symbol: Symbol,
+    pub entry_block: EntityId,
+    /// The variables declared by the POU.
+    pub variables: Vec<Declaration>,
+    /// Temporaries introduced by the compiler.
+    pub temporaries: Vec<Declaration>,
+    /// The index of the variable a function's result is written to.
+    pub return_field: Option<usize>,
+}
+
+impl PouDeclarations {
+    /// The name of the POU's function in the generated code.
+    pub fn function_name(&self) -> String {
+        self.kind.function_name(&self.name)
+    }
+
+    /// The name of the struct containing the POU's variables.
+    pub fn type_name(&self) -> String {
+        format!("{}_t", self.function_name())
+    }
+
+    /// The statically allocated instance data for a program.
+    pub fn instance_name(&self) -> String {
+        format!("iec_instance_{}", self.name.to_lowercase())
+    }
+}
+
+/// A variable and the type it's stored as.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Declaration {
+    pub variable: EntityId,
+    /// The variable's name, or `None` for temporaries.
+    pub name: Option<String>,
+    pub ty: VariableType,
+}
+
+/// The types a variable can be stored as.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VariableType {
+    Bool,
+    Integer(IntegerType),
+    /// A 64-bit float.
+    Real,
+    /// A `STRING` (or `CHAR`), which not every backend can store.
+    String,
+    /// A function block instance, referring to its [`PouDeclarations`].
+    Instance(usize),
+}
+
+impl VariableType {
+    /// The type of this variable's value, if it fits in a register.
+    pub fn scalar(self) -> Option<Scalar> {
+        match self {
+            VariableType::Bool => Some(Scalar::Bool),
+            VariableType::Integer(int) => Some(Scalar::Integer(int)),
+            VariableType::Real => Some(Scalar::Real),
+            VariableType::String | VariableType::Instance(_) => None,
+        }
+    }
+
+    /// The [`FieldType`] used when storing this variable in a [`Struct`].
+    pub fn field_type(self) -> Result<FieldType, LayoutError> {
+        match self {
+            VariableType::Bool => Ok(FieldType::Bool),
+            VariableType::Integer(int) => Ok(FieldType::Integer(int)),
+            VariableType::Real => Ok(FieldType::Real),
+            VariableType::String => {
+                Err(LayoutError::Unsupported(String::from("STRING variables")))
+            }
+            VariableType::Instance(pou) => Ok(FieldType::Instance(pou)),
+        }
+    }
+}
+
+impl From<FieldType> for VariableType {
+    fn from(ty: FieldType) -> VariableType {
+        match ty {
+            FieldType::Bool => VariableType::Bool,
+            FieldType::Integer(int) => VariableType::Integer(int),
+            FieldType::Real => VariableType::Real,
+            FieldType::Instance(pou) => VariableType::Instance(pou),
+        }
+    }
+}
+
+impl Display for VariableType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            VariableType::String => write!(f, "STRING"),
+            other => match other.field_type() {
+                Ok(ty) => write!(f, "{}", ty),
+                Err(_) => unreachable!("only strings can't be fields"),
+            },
+        }
+    }
+}
+
+/// Lay out every POU in the [`Declarations`], making sure function blocks
+/// are laid out before anything which contains them.
+fn lay_out(declarations: Declarations) -> Result<Layout, LayoutError> {
+    let mut frames = vec![None; declarations.pous.len()];
+    for &index in declarations.definition_order() {
+        let pou = &declarations.pous[index];
+        let fields = pou.variables.iter().chain(&pou.temporaries);
+        frames[index] = Some(lay_out_struct(fields, &frames)?);
+    }
+
+    let globals = lay_out_struct(&declarations.globals, &frames)?;
+
+    let mut locations = HashMap::new();
+    for (index, pou) in declarations.pous.iter().enumerate() {
+        let fields = pou.variables.iter().chain(&pou.temporaries);
+        for (field, declaration) in fields.enumerate() {
+            let location = Location {
+                owner: Some(index),
+                field,
+            };
+            locations.insert(declaration.variable, location);
+        }
+    }
+    for (field, declaration) in declarations.globals.iter().enumerate() {
+        locations.insert(declaration.variable, Location { owner: None, field });
+    }
+
+    let pous = declarations
+        .pous
+        .into_iter()
+        .zip(frames)
+        .map(|(pou, frame)| PouLayout {
+            name: pou.name,
+            kind: pou.kind,
+            symbol: pou.symbol,
+            entry_block: pou.entry_block,
+            frame: frame.expect("every POU was laid out"),
+            return_field: pou.return_field,
+        })
+        .collect();
+
+    Ok(Layout {
+        globals,
+        pous,
+        locations,
+        pou_indices: declarations.pou_indices,
+    })
+}
+
+fn lay_out_struct<'d, I>(
+    declarations: I,
+    frames: &[Option<Struct>],
+) -> Result<Struct, LayoutError>
+where
+    I: IntoIterator<Item = &'d Declaration>,
+{
+    let mut fields = Vec::new();
+    let mut size = 0;
+    let mut align = 1;
+
+    for declaration in declarations {
+        let ty = declaration.ty.field_type()?;
+        let (field_size, field_align) = match ty {
+            FieldType::Bool => (1, 1),
+            FieldType::Integer(int) => (int.bits() / 8, int.bits() / 8),
+            FieldType::Real => (8, 8),
+            FieldType::Instance(fb) => {
+                let frame = frames[fb]
+                    .as_ref()
+                    .expect("nested function blocks are laid out first");
+                (frame.size, frame.align)
+            }
+        };
+
+        let offset = align_to(size, field_align);
+        fields.push(Field {
+            name: declaration.name.clone(),
+            ty,
+            offset,
+        });
+        size = offset + field_size;
+        align = align.max(field_align);
+    }
+
+    Ok(Struct {
+        fields,
+        size: align_to(size, align),
+        align,
+    })
+}
+
+/// A program, function block, or function which has a body (i.e. isn't a
+/// builtin).
+struct PouHeader {
+    symbol: Symbol,
+    kind: PouKind,
+    name: String,
+    variables: Vec<EntityId>,
+    return_value: Option<EntityId>,
+    entry_block: EntityId,
+}
+
+struct Builder<'a> {
+    programs: &'a Container<Program>,
+    functions: &'a Container<Function>,
+    function_blocks: &'a Container<FunctionBlock>,
+    globals: &'a Container<GlobalVariables>,
+    types: &'a Container<Type>,
+    variables: &'a Container<Variable>,
+}
+
+impl<'a> Builder<'a> {
+    fn build(&self) -> Result<Declarations, LayoutError> {
+        let headers = self.pous_with_bodies();
+        let pou_indices: HashMap<Symbol, usize> = headers
+            .iter()
+            .enumerate()
+            .map(|(i, header)| (header.symbol, i))
+            .collect();
+
+        let pous: Vec<PouDeclarations> = headers
+            .into_iter()
+            .map(|header| self.declare(header, &pou_indices))
+            .collect();
+
+        let mut global_variables: Vec<EntityId> = self
+            .globals
+            .iter()
+            .flat_map(|(_, g)| g.variables.iter().cloned())
+            .collect();
+        global_variables.sort();
+        let globals = global_variables
+            .into_iter()
+            .map(|id| self.declaration(id, &pou_indices))
+            .collect();
+
+        let mut definition_order = Vec::new();
+        for index in 0..pous.len() {
+            visit(index, &pous, &mut definition_order, &mut Vec::new())?;
+        }
+
+        Ok(Declarations {
+            pous,
+            globals,
+            pou_indices,
+            definition_order,
+        })
+    }
+
+    /// Every item with a body, in a deterministic order (programs, then
+    /// function blocks, then functions).
+    fn pous_with_bodies(&self) -> Vec<PouHeader> {
+        let mut programs: Vec<_> = self
+            .programs
+            .iter()
+            .map(|(id, p)| PouHeader {
+                symbol: Symbol::Program(id),
+                kind: PouKind::Program,
+                name: p.name.clone(),
+                variables: p.variables.clone(),
+                return_value: None,
+                entry_block: p.entry_block,
+            })
+            .collect();
+        let mut function_blocks: Vec<_> = self
+            .function_blocks
+            .iter()
+            .map(|(id, fb)| PouHeader {
+                symbol: Symbol::FunctionBlock(id),
+                kind: PouKind::FunctionBlock,
+                name: fb.name.clone(),
+                variables: fb.variables.clone(),
+                return_value: None,
+                entry_block: fb.entry_block,
+            })
+            .collect();
+        // builtins don't have a body
+        let mut functions: Vec<_> = self
+            .functions
+            .iter()
+            .filter(|(_, f)| !f.entry_block.is_placeholder())
+            .map(|(id, f)| PouHeader {
+                symbol: Symbol::Function(id),
+                kind: PouKind::Function,
+                name: f.name.clone(),
+                variables: f.variables.clone(),
+                return_value: Some(f.return_value),
+                entry_block: f.entry_block,
+            })
+            .collect();
+
+        let by_id = |pou: &PouHeader| EntityId::from(pou.symbol);
+        programs.sort_by_key(by_id);
+        function_blocks.sort_by_key(by_id);
+        functions.sort_by_key(by_id);
+
+        programs
+            .into_iter()
+            .chain(function_blocks)
+            .chain(functions)
+            .collect()
+    }
+
+    fn declare(
+        &self,
+        header: PouHeader,
+        pou_indices: &HashMap<Symbol, usize>,
+    ) -> PouDeclarations {
+        let mut temporaries: Vec<EntityId> = self
+            .variables
+            .iter()
+            .filter(|(id, v)| {
+                v.parent == header.symbol && !header.variables.contains(id)
+            })
+            .map(|(id, _)| id)
+            .collect();
+        temporaries.sort();
+
+        PouDeclarations {
+            return_field: header
+                .return_value
+                .and_then(|rv| header.variables.iter().position(|&v| v == rv)),
+            variables: header
+                .variables
+                .iter()
+                .map(|&id| self.declaration(id, pou_indices))
+                .collect(),
+            temporaries: temporaries
+                .into_iter()
+                .map(|id| self.declaration(id, pou_indices))
+                .collect(),
+            name: header.name,
+            kind: header.kind,
+            symbol: header.symbol,
+            entry_block: header.entry_block,
+        }
+    }
+
+    fn declaration(
+        &self,
+        variable: EntityId,
+        pou_indices: &HashMap<Symbol, usize>,
+    ) -> Declaration {
+        let v = self
+            .variables
+            .get(variable)
+            .expect("every variable is registered");
+
+        Declaration {
+            variable,
+            name: v.name.clone(),
+            ty: self.variable_type(v.ty, pou_indices),
+        }
+    }
+
+    fn variable_type(
+        &self,
+        ty: EntityId,
+        pou_indices: &HashMap<Symbol, usize>,
+    ) -> VariableType {
+        if let Some(&index) = pou_indices.get(&Symbol::FunctionBlock(ty)) {
+            return VariableType::Instance(index);
+        }
+
+        let name = self
+            .types
+            .get(ty)
+            .map(|t| t.name.to_lowercase())
+            .unwrap_or_default();
+
+        if let Some(int) = IntegerType::from_name(&name) {
+            return VariableType::Integer(int);
+        }
+
+        match name.as_str() {
+            "bool" => VariableType::Bool,
+            "real" | "lreal" => VariableType::Real,
+            "string" | "char" => VariableType::String,
+            // TIME and DATE are stored as a number of milliseconds
+            _ => VariableType::Integer(IntegerType::LInt),
+        }
+    }
+}
+
+/// Add a POU to the definition order, making sure any function blocks it
+/// contains come first.
+fn visit(
+    index: usize,
+    pous: &[PouDeclarations],
+    order: &mut Vec<usize>,
+    stack: &mut Vec<usize>,
+) -> Result<(), LayoutError> {
+    if order.contains(&index) {
+        return Ok(());
+    }
+    if stack.contains(&index) {
+        return Err(LayoutError::Unsupported(format!(
+            "Recursive function blocks (\"{}\")",
+            pous[index].name
+        )));
+    }
+
+    stack.push(index);
+    let pou = &pous[index];
+    for declaration in pou.variables.iter().chain(&pou.temporaries) {
+        if let VariableType::Instance(fb) = declaration.ty {
+            visit(fb, pous, order, stack)?;
+        }
+    }
+    stack.pop();
+
+    order.push(index);
+    Ok(())
+}
+
+/// Something that can't be laid out in memory.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LayoutError {
+    Unsupported(String),
+}
+
+impl Display for LayoutError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            LayoutError::Unsupported(ref what) => {
+                write!(f, "{} can't be laid out in memory", what)
+            }
+        }
+    }
+}
+
+impl Error for LayoutError {}
+
+fn align_to(offset: u32, align: u32) -> u32 {
+    offset.div_ceil(align) * align
+}
@@ -17,12 +17,21 @@
 //! [`specs`]: https://github.com/slide-rs/specs
 //! [`cranelift`]: https://github.com/CraneStation/cranelift
 
+pub mod analysis;
+pub mod bytecode;
+pub mod codegen;
+pub mod const_eval;
 mod diagnostics;
 pub mod ecs;
 pub mod hir;
+pub mod incremental;
+pub mod interpreter;
+pub mod layout;
 pub mod passes;
+#[cfg(feature = "testing")]
+pub mod testing;
 
 pub use crate::diagnostics::Diagnostics;
 pub use crate::ecs::EntityId;
 pub use crate::hir::CompilationUnit;
-pub use crate::passes::process;
+pub use crate::passes::{optimize, process, OptimizationLevel};
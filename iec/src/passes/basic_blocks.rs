@@ -1,9 +1,19 @@
-use super::symbol_table::SymbolTable;
+use super::name_resolution::Scope;
+use super::symbol_table::{ScopeId, SymbolTable};
 use super::{Pass, PassContext};
-use crate::ecs::{Container, EntityId, Read, ReadWrite, Singleton};
-use crate::hir::{BasicBlock, Instruction, Program, Symbol, Type, Variable};
+use crate::ecs::{Container, EntityId, ReadWrite, Singleton};
+use crate::hir::{
+    Argument, BasicBlock, BinaryOp, Constant, Function, FunctionBlock,
+    Instruction, Operand, Program, Span, Symbol, Terminator, UnaryOp, Variable,
+    VariableKind,
+};
 use crate::Diagnostics;
-use iec_syntax::Item;
+use codespan::ByteSpan;
+use codespan_reporting::{Diagnostic, Label};
+use iec_syntax::{
+    AstNode, BinOp, DottedIdentifier, Expression, FunctionArg, FunctionCall,
+    Identifier, Item, LiteralKind, Statement,
+};
 use typename::TypeName;
 
 #[derive(TypeName)]
@@ -13,44 +23,1163 @@ impl<'r> Pass<'r> for BasicBlocks {
     type Arg = iec_syntax::File;
     type Storage = (
         ReadWrite<'r, Program>,
-        Read<'r, Variable>,
+        ReadWrite<'r, Function>,
+        ReadWrite<'r, FunctionBlock>,
+        ReadWrite<'r, Variable>,
         Singleton<'r, SymbolTable>,
-        Read<'r, Type>,
+        ReadWrite<'r, Constant>,
         ReadWrite<'r, Instruction>,
         ReadWrite<'r, BasicBlock>,
+        ReadWrite<'r, Span>,
     );
     const DESCRIPTION: &'static str = "Convert item bodies into basic blocks";
+    const REQUIRES: &'static [&'static str] = &["resolved-names"];
+    const PROVIDES: &'static [&'static str] = &["basic-blocks"];
 
     fn run(ast: &Self::Arg, ctx: &mut PassContext<'_>, storage: Self::Storage) {
-        let (mut programs, variables, symbols, types, instructions, blocks) =
-            storage;
+        let (
+            mut programs,
+            mut functions,
+            mut function_blocks,
+            mut variables,
+            symbols,
+            mut constants,
+            mut instructions,
+            mut blocks,
+            mut spans,
+        ) = storage;
+
+        const ERR_MSG: &str = "the symbol table pass ensures this exists";
 
         for item in &ast.items {
             let (body, name) = match item {
                 Item::Program(ref p) => (&p.body, &p.name.value),
-                _ => unimplemented!(),
+                Item::Function(ref f) => (&f.body, &f.name.value),
+                Item::FunctionBlock(ref fb) => (&fb.body, &fb.name.value),
+                Item::VarBlock(_) | Item::Configuration(_) => continue,
+            };
+            let symbol = symbols.get(name).expect(ERR_MSG);
+            let scope = symbols.scope_of(symbol.into()).expect(ERR_MSG);
+
+            let mut cx = Context {
+                symbols: &symbols,
+                functions: &functions,
+                function_blocks: &function_blocks,
+                variables: &mut variables,
+                constants: &mut constants,
+                instructions: &mut instructions,
+                blocks: &mut blocks,
+                spans: &mut spans,
+                diags: ctx.diags,
             };
-            let symbol = symbols
-                .get(name)
-                .expect("the symbol table pass ensures this exists");
+            let block_count = cx.blocks.len();
+
+            let entry_block = to_basic_blocks(symbol, scope, body, &mut cx);
 
-            let entry_block = to_basic_blocks(
-                symbol,
-                body,
-                &variables,
-                &types,
-                &mut ctx.diags,
-            );
+            match symbol {
+                Symbol::Program(id) => {
+                    programs.get_mut(id).expect(ERR_MSG).entry_block =
+                        entry_block;
+                }
+                Symbol::Function(id) => {
+                    functions.get_mut(id).expect(ERR_MSG).entry_block =
+                        entry_block;
+                }
+                Symbol::FunctionBlock(id) => {
+                    function_blocks.get_mut(id).expect(ERR_MSG).entry_block =
+                        entry_block;
+                }
+                Symbol::Type(_) | Symbol::GlobalVariables(_) => unreachable!(),
+            }
+
+            slog::debug!(ctx.logger, "Lowered item";
+                "name" => name,
+                "entry-block" => entry_block,
+                "block-count" => blocks.len() - block_count);
         }
     }
 }
 
+/// Everything needed when lowering an item's body.
+struct Context<'a> {
+    symbols: &'a SymbolTable,
+    functions: &'a Container<Function>,
+    function_blocks: &'a Container<FunctionBlock>,
+    variables: &'a mut Container<Variable>,
+    constants: &'a mut Container<Constant>,
+    instructions: &'a mut Container<Instruction>,
+    blocks: &'a mut Container<BasicBlock>,
+    spans: &'a mut Container<Span>,
+    diags: &'a mut Diagnostics,
+}
+
+/// Lower an item's body into a graph of [`BasicBlock`]s, returning the entry
+/// block.
 fn to_basic_blocks(
     parent: Symbol,
-    body: &[iec_syntax::Statement],
-    variables: &Container<Variable>,
-    types: &Container<Type>,
-    diags: &mut Diagnostics,
+    scope: ScopeId,
+    body: &[Statement],
+    cx: &mut Context<'_>,
 ) -> EntityId {
-    unimplemented!()
+    let mut builder = Builder::new(parent, scope, cx);
+    let entry = builder.new_block();
+
+    builder.switch_to(entry);
+    builder.statements(body);
+    builder.terminate(Terminator::Return);
+
+    entry
+}
+
+/// Incrementally builds up the control flow graph for a single body.
+struct Builder<'c, 'a> {
+    parent: Symbol,
+    scope: Scope<'a>,
+    cx: &'c mut Context<'a>,
+    /// The block instructions are currently being added to. This will be
+    /// `None` immediately after a block is terminated.
+    current: Option<EntityId>,
+    /// Where an `EXIT` should jump to for each of the enclosing loops.
+    loop_exits: Vec<EntityId>,
+    /// The code currently being lowered, attached to each instruction.
+    span: ByteSpan,
+}
+
+impl<'c, 'a> Builder<'c, 'a> {
+    fn new(
+        parent: Symbol,
+        scope: ScopeId,
+        cx: &'c mut Context<'a>,
+    ) -> Builder<'c, 'a> {
+        let scope = Scope::new(cx.symbols, cx.function_blocks, scope);
+
+        Builder {
+            parent,
+            scope,
+            cx,
+            current: None,
+            loop_exits: Vec::new(),
+            span: ByteSpan::default(),
+        }
+    }
+
+    fn new_block(&mut self) -> EntityId {
+        self.cx.blocks.insert(BasicBlock {
+            parent: self.parent,
+            instructions: Vec::new(),
+            terminator: Terminator::Return,
+        })
+    }
+
+    /// Start adding instructions to another block, falling through from the
+    /// current block if it hasn't already been terminated.
+    fn switch_to(&mut self, block: EntityId) {
+        self.terminate(Terminator::Jump(block));
+        self.current = Some(block);
+    }
+
+    /// Finish the current block.
+    fn terminate(&mut self, terminator: Terminator) {
+        if let Some(current) = self.current.take() {
+            self.cx
+                .blocks
+                .get_mut(current)
+                .expect("we created this block")
+                .terminator = terminator;
+        }
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        let block = match self.current {
+            Some(block) => block,
+            None => {
+                // the previous block was terminated (e.g. by a RETURN), so
+                // anything following it is unreachable
+                let block = self.new_block();
+                self.current = Some(block);
+                block
+            }
+        };
+
+        let id = self.cx.instructions.insert(instruction);
+        self.cx.spans.attach(id, Span(self.span));
+        self.cx
+            .blocks
+            .get_mut(block)
+            .expect("we created this block")
+            .instructions
+            .push(id);
+    }
+
+    fn temporary(&mut self, ty: EntityId) -> EntityId {
+        self.cx.variables.insert(Variable {
+            parent: self.parent,
+            ty,
+            name: None,
+            kind: VariableKind::Temporary,
+        })
+    }
+
+    fn constant(&mut self, value: Constant) -> Operand {
+        Operand::Constant(self.cx.constants.insert(value))
+    }
+
+    fn builtin_type(&self, name: &str) -> EntityId {
+        match self.cx.symbols.get(name) {
+            Some(Symbol::Type(id)) => id,
+            _ => panic!("The \"{}\" builtin type wasn't registered", name),
+        }
+    }
+
+    fn type_of(&self, operand: Operand) -> EntityId {
+        match operand {
+            Operand::Variable(id) => {
+                self.cx
+                    .variables
+                    .get(id)
+                    .expect("all operands refer to known variables")
+                    .ty
+            }
+            Operand::Constant(id) => {
+                let name = match self.cx.constants.get(id) {
+                    Some(Constant::Boolean(_)) => "bool",
+                    Some(Constant::Integer(_)) => "dint",
+                    Some(Constant::Float(_)) => "lreal",
+                    Some(Constant::String(_)) => "string",
+                    None => {
+                        unreachable!("all operands refer to known constants")
+                    }
+                };
+                self.builtin_type(name)
+            }
+        }
+    }
+
+    fn error(&mut self, message: &str, ident: &Identifier) {
+        self.cx.diags.push(
+            Diagnostic::new_error(message)
+                .with_label(Label::new_primary(ident.span)),
+        );
+    }
+
+    fn statements(&mut self, statements: &[Statement]) {
+        for statement in statements {
+            self.statement(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        let outer = std::mem::replace(&mut self.span, statement.span());
+        self.lower_statement(statement);
+        self.span = outer;
+    }
+
+    fn lower_statement(&mut self, statement: &Statement) {
+        match statement {
+            Statement::Assignment(ref a) => {
+                if let Some(value) = self.expression(&a.value) {
+                    self.store(&a.variable, value);
+                }
+            }
+            Statement::FunctionCall(ref call) => {
+                self.call(call, false);
+            }
+            Statement::ForLoop(ref f) => self.for_loop(f),
+            Statement::WhileLoop(ref w) => self.while_loop(w),
+            Statement::RepeatLoop(ref r) => self.repeat_loop(r),
+            Statement::IfStatement(ref i) => self.if_statement(i),
+            Statement::Exit(ref e) => match self.loop_exits.last() {
+                Some(&after_loop) => {
+                    self.terminate(Terminator::Jump(after_loop))
+                }
+                None => self.cx.diags.push(
+                    Diagnostic::new_error(
+                        "EXIT can only be used inside a loop",
+                    )
+                    .with_label(Label::new_primary(e.span)),
+                ),
+            },
+            Statement::Return(_) => self.terminate(Terminator::Return),
+        }
+    }
+
+    fn if_statement(&mut self, stmt: &iec_syntax::IfStatement) {
+        let condition = match self.expression(&stmt.condition) {
+            Some(c) => c,
+            None => {
+                // still check the body for errors
+                self.statements(&stmt.body);
+                return;
+            }
+        };
+
+        let then = self.new_block();
+        let after = self.new_block();
+
+        self.terminate(Terminator::Branch {
+            condition,
+            then,
+            otherwise: after,
+        });
+        self.switch_to(then);
+        self.statements(&stmt.body);
+        self.switch_to(after);
+    }
+
+    fn while_loop(&mut self, stmt: &iec_syntax::WhileLoop) {
+        let header = self.new_block();
+        let body = self.new_block();
+        let after = self.new_block();
+
+        self.switch_to(header);
+        if let Some(condition) = self.expression(&stmt.condition) {
+            self.terminate(Terminator::Branch {
+                condition,
+                then: body,
+                otherwise: after,
+            });
+        }
+
+        self.switch_to(body);
+        self.loop_body(&stmt.body, after);
+        self.terminate(Terminator::Jump(header));
+        self.switch_to(after);
+    }
+
+    fn repeat_loop(&mut self, stmt: &iec_syntax::RepeatLoop) {
+        let body = self.new_block();
+        let after = self.new_block();
+
+        self.switch_to(body);
+        self.loop_body(&stmt.body, after);
+
+        if let Some(condition) = self.expression(&stmt.condition) {
+            self.terminate(Terminator::Branch {
+                condition,
+                then: after,
+                otherwise: body,
+            });
+        }
+        self.switch_to(after);
+    }
+
+    fn for_loop(&mut self, stmt: &iec_syntax::ForLoop) {
+        let counter = self.lookup_variable(&stmt.variable);
+        let start = self.expression(&stmt.start);
+        let end = self.expression(&stmt.end).map(|e| self.evaluate_once(e));
+        let step = match stmt.step {
+            Some(ref step) => {
+                self.expression(step).map(|s| self.evaluate_once(s))
+            }
+            None => Some(self.constant(Constant::Integer(1))),
+        };
+
+        let (counter, start, end, step) = match (counter, start, end, step) {
+            (Some(counter), Some(start), Some(end), Some(step)) => {
+                (counter, start, end, step)
+            }
+            _ => {
+                self.statements(&stmt.body);
+                return;
+            }
+        };
+
+        self.emit(Instruction::Load {
+            dest: counter,
+            src: start,
+        });
+
+        let header = self.new_block();
+        let body = self.new_block();
+        let after = self.new_block();
+
+        self.switch_to(header);
+        let condition = self.for_loop_condition(counter, end, step);
+        self.terminate(Terminator::Branch {
+            condition,
+            then: body,
+            otherwise: after,
+        });
+
+        self.switch_to(body);
+        self.loop_body(&stmt.body, after);
+        self.emit(Instruction::Binary {
+            dest: counter,
+            op: BinaryOp::Add,
+            left: Operand::Variable(counter),
+            right: step,
+        });
+        self.terminate(Terminator::Jump(header));
+        self.switch_to(after);
+    }
+
+    /// Generate the check done at the top of each iteration of a `FOR` loop.
+    ///
+    /// We count upwards when the step is positive and downwards when it's
+    /// negative, which means we need to check the step at runtime when it
+    /// isn't a constant.
+    fn for_loop_condition(
+        &mut self,
+        counter: EntityId,
+        end: Operand,
+        step: Operand,
+    ) -> Operand {
+        let counter = Operand::Variable(counter);
+        let constant_step = match step {
+            Operand::Constant(id) => match self.cx.constants.get(id) {
+                Some(&Constant::Integer(n)) => Some(n),
+                _ => None,
+            },
+            Operand::Variable(_) => None,
+        };
+
+        match constant_step {
+            Some(n) if n >= 0 => {
+                self.binary(BinaryOp::LessThanOrEqual, counter, end)
+            }
+            Some(_) => self.binary(BinaryOp::GreaterThanOrEqual, counter, end),
+            None => {
+                let zero = self.constant(Constant::Integer(0));
+                let counting_up =
+                    self.binary(BinaryOp::GreaterThanOrEqual, step, zero);
+                let below_end =
+                    self.binary(BinaryOp::LessThanOrEqual, counter, end);
+                let above_end =
+                    self.binary(BinaryOp::GreaterThanOrEqual, counter, end);
+
+                let up = self.binary(BinaryOp::And, counting_up, below_end);
+                let counting_down = self.unary(UnaryOp::Not, counting_up);
+                let down = self.binary(BinaryOp::And, counting_down, above_end);
+                self.binary(BinaryOp::Or, up, down)
+            }
+        }
+    }
+
+    fn loop_body(&mut self, body: &[Statement], after_loop: EntityId) {
+        self.loop_exits.push(after_loop);
+        self.statements(body);
+        self.loop_exits.pop();
+    }
+
+    /// Make sure an expression used by a loop is only evaluated once, even
+    /// if the variables it refers to are changed inside the loop.
+    fn evaluate_once(&mut self, operand: Operand) -> Operand {
+        match operand {
+            Operand::Constant(_) => operand,
+            Operand::Variable(_) => {
+                let ty = self.type_of(operand);
+                let temp = self.temporary(ty);
+                self.emit(Instruction::Load {
+                    dest: temp,
+                    src: operand,
+                });
+                Operand::Variable(temp)
+            }
+        }
+    }
+
+    fn expression(&mut self, expr: &Expression) -> Option<Operand> {
+        let outer = std::mem::replace(&mut self.span, expr.span());
+        let value = self.lower_expression(expr);
+        self.span = outer;
+        value
+    }
+
+    fn lower_expression(&mut self, expr: &Expression) -> Option<Operand> {
+        match expr {
+            Expression::Literal(ref lit) => {
+                let value = match lit.kind {
+                    LiteralKind::Boolean(b) => Constant::Boolean(b),
+                    LiteralKind::Integer(i) => Constant::Integer(i),
+                    LiteralKind::Float(f) => Constant::Float(f),
+                    LiteralKind::String(ref s) => Constant::String(s.clone()),
+                };
+                Some(self.constant(value))
+            }
+            Expression::Variable(ref path) => self.load(path),
+            Expression::DirectAddress(ref address) => {
+                self.load(&address.to_path())
+            }
+            Expression::Binary(ref bin) => {
+                let left = self.expression(&bin.left);
+                let right = self.expression(&bin.right);
+                Some(self.binary(binary_op(bin.op), left?, right?))
+            }
+            Expression::Unary(ref un) => {
+                let value = self.expression(&un.value)?;
+                let op = match un.op {
+                    iec_syntax::UnaryOp::Not => UnaryOp::Not,
+                    iec_syntax::UnaryOp::Negate => UnaryOp::Negate,
+                };
+                Some(self.unary(op, value))
+            }
+            Expression::FunctionCall(ref call) => self.call(call, true),
+        }
+    }
+
+    fn binary(
+        &mut self,
+        op: BinaryOp,
+        left: Operand,
+        right: Operand,
+    ) -> Operand {
+        let ty = if op.is_comparison() {
+            self.builtin_type("bool")
+        } else {
+            self.type_of(left)
+        };
+        let dest = self.temporary(ty);
+
+        self.emit(Instruction::Binary {
+            dest,
+            op,
+            left,
+            right,
+        });
+        Operand::Variable(dest)
+    }
+
+    fn unary(&mut self, op: UnaryOp, value: Operand) -> Operand {
+        let ty = self.type_of(value);
+        let dest = self.temporary(ty);
+
+        self.emit(Instruction::Unary { dest, op, value });
+        Operand::Variable(dest)
+    }
+
+    fn call(
+        &mut self,
+        call: &FunctionCall,
+        use_result: bool,
+    ) -> Option<Operand> {
+        let (target, instance) = self.lookup_callee(&call.name)?;
+        let parameters = match target {
+            Symbol::Function(id) => &self.cx.functions.get(id)?.variables,
+            Symbol::FunctionBlock(id) => {
+                &self.cx.function_blocks.get(id)?.variables
+            }
+            _ => unreachable!(),
+        };
+        let parameters: Vec<(EntityId, Variable)> = parameters
+            .iter()
+            .filter_map(|&id| Some((id, self.cx.variables.get(id)?.clone())))
+            .filter(|(_, var)| var.kind.is_visible_to_caller())
+            .collect();
+
+        // positional arguments are bound to the inputs in the order they
+        // were declared
+        let mut positional = parameters
+            .iter()
+            .filter(|(_, var)| var.kind != VariableKind::Output)
+            .map(|&(id, _)| id);
+        let mut args = Vec::new();
+        // members which were passed by reference via a temporary and need
+        // to be written back after the call
+        let mut write_backs = Vec::new();
+
+        for arg in &call.args {
+            match arg {
+                FunctionArg::Bare(ref expr) => match positional.next() {
+                    Some(parameter) => self.input_argument(
+                        parameter,
+                        expr,
+                        &mut args,
+                        &mut write_backs,
+                    ),
+                    None => self.cx.diags.push(
+                        Diagnostic::new_error("Too many arguments")
+                            .with_label(Label::new_primary(expr.span())),
+                    ),
+                },
+                FunctionArg::Named(ref assign) => {
+                    let parameter = match assign.variable.pieces.as_slice() {
+                        [name] => lookup_parameter(&parameters, name),
+                        _ => None,
+                    };
+
+                    match parameter {
+                        Some(p) if self.kind_of(p) == VariableKind::Output => {
+                            self.cx.diags.push(
+                                Diagnostic::new_error(
+                                    "Outputs must be assigned with \"=>\"",
+                                )
+                                .with_label(Label::new_primary(assign.span)),
+                            );
+                        }
+                        Some(p) => self.input_argument(
+                            p,
+                            &assign.value,
+                            &mut args,
+                            &mut write_backs,
+                        ),
+                        None => self.cx.diags.push(
+                            Diagnostic::new_error("Unknown parameter")
+                                .with_label(Label::new_primary(
+                                    assign.variable.span,
+                                )),
+                        ),
+                    }
+                }
+                FunctionArg::Output(ref out) => {
+                    match lookup_parameter(&parameters, &out.parameter) {
+                        Some(p) if self.kind_of(p) == VariableKind::Output => {
+                            if let Some(variable) =
+                                self.reference(&out.variable, &mut write_backs)
+                            {
+                                args.push(Argument::Output {
+                                    parameter: p,
+                                    variable,
+                                });
+                            }
+                        }
+                        Some(_) => self.error(
+                            "Only outputs can be assigned with \"=>\"",
+                            &out.parameter,
+                        ),
+                        None => self.error("Unknown parameter", &out.parameter),
+                    }
+                }
+            }
+        }
+
+        for &(id, ref var) in &parameters {
+            let passed = args.iter().any(|arg| match *arg {
+                Argument::InOut { parameter, .. } => parameter == id,
+                _ => false,
+            });
+
+            if var.kind == VariableKind::InOut && !passed {
+                self.cx.diags.push(
+                    Diagnostic::new_error(format!(
+                        "No value provided for the \"{}\" parameter",
+                        var.name.as_deref().unwrap_or("?")
+                    ))
+                    .with_label(Label::new_primary(call.span)),
+                );
+            }
+        }
+
+        let dest = match target {
+            Symbol::Function(id) if use_result => {
+                let return_type = self
+                    .cx
+                    .functions
+                    .get(id)
+                    .expect("the symbol table pass registers every function")
+                    .return_type;
+                Some(self.temporary(return_type))
+            }
+            Symbol::FunctionBlock(_) if use_result => {
+                self.error("Function blocks don't return a value", &call.name);
+                None
+            }
+            _ => None,
+        };
+
+        self.emit(Instruction::Call {
+            target,
+            instance,
+            args,
+            dest,
+        });
+
+        for (path, temp) in write_backs {
+            self.store(path, Operand::Variable(temp));
+        }
+
+        dest.map(Operand::Variable)
+    }
+
+    // Any names which can't be resolved will have already been reported by
+    // the NameResolution pass, so we can just skip them here.
+
+    fn lookup_callee(
+        &self,
+        name: &Identifier,
+    ) -> Option<(Symbol, Option<EntityId>)> {
+        self.scope.lookup_callee(name, self.cx.variables).ok()
+    }
+
+    fn lookup_variable(&self, name: &Identifier) -> Option<EntityId> {
+        self.scope.lookup_variable(name).ok()
+    }
+
+    fn lookup_member(
+        &self,
+        object: EntityId,
+        name: &Identifier,
+    ) -> Option<EntityId> {
+        self.scope
+            .lookup_member(object, name, self.cx.variables)
+            .ok()
+    }
+
+    fn variable(&self, id: EntityId) -> &Variable {
+        self.cx
+            .variables
+            .get(id)
+            .expect("all variables we use have been registered")
+    }
+
+    fn kind_of(&self, variable: EntityId) -> VariableKind {
+        self.variable(variable).kind
+    }
+
+    /// Pass an expression to a `VAR_INPUT` or `VAR_IN_OUT` parameter.
+    fn input_argument<'e>(
+        &mut self,
+        parameter: EntityId,
+        expr: &'e Expression,
+        args: &mut Vec<Argument>,
+        write_backs: &mut Vec<(&'e DottedIdentifier, EntityId)>,
+    ) {
+        if self.kind_of(parameter) != VariableKind::InOut {
+            if let Some(value) = self.expression(expr) {
+                args.push(Argument::Input { parameter, value });
+            }
+            return;
+        }
+
+        match expr {
+            Expression::Variable(ref path) => {
+                if let Some(variable) = self.reference(path, write_backs) {
+                    args.push(Argument::InOut {
+                        parameter,
+                        variable,
+                    });
+                }
+            }
+            other => self.cx.diags.push(
+                Diagnostic::new_error(
+                    "VAR_IN_OUT parameters must be passed a variable",
+                )
+                .with_label(Label::new_primary(other.span())),
+            ),
+        }
+    }
+
+    /// Get a variable which can be passed by reference to a callee.
+    ///
+    /// Members can't be referred to directly, so they are copied into a
+    /// temporary which must be written back after the call.
+    fn reference<'e>(
+        &mut self,
+        path: &'e DottedIdentifier,
+        write_backs: &mut Vec<(&'e DottedIdentifier, EntityId)>,
+    ) -> Option<EntityId> {
+        match self.load(path)? {
+            Operand::Variable(id) if path.pieces.len() == 1 => Some(id),
+            Operand::Variable(temp) => {
+                write_backs.push((path, temp));
+                Some(temp)
+            }
+            Operand::Constant(_) => unreachable!(),
+        }
+    }
+
+    /// Read the value at a (possibly dotted) path.
+    fn load(&mut self, path: &DottedIdentifier) -> Option<Operand> {
+        let (first, rest) = path
+            .pieces
+            .split_first()
+            .expect("the parser never emits empty paths");
+        let mut object = self.lookup_variable(first)?;
+
+        for piece in rest {
+            let member = self.lookup_member(object, piece)?;
+            let ty = self.cx.variables.get(member).expect("just found it").ty;
+            let dest = self.temporary(ty);
+            self.emit(Instruction::LoadMember {
+                dest,
+                object,
+                member,
+            });
+            object = dest;
+        }
+
+        Some(Operand::Variable(object))
+    }
+
+    /// Write a value to a (possibly dotted) path.
+    ///
+    /// Members are stored by value, so writing to `a.b.c` means we need to
+    /// read `a.b` into a temporary, update its `c` member, then write the
+    /// temporary back into `a.b`.
+    fn store(&mut self, path: &DottedIdentifier, value: Operand) -> Option<()> {
+        let (first, rest) = path
+            .pieces
+            .split_first()
+            .expect("the parser never emits empty paths");
+        let root = self.lookup_variable(first)?;
+
+        let (last, intermediate) = match rest.split_last() {
+            Some(pair) => pair,
+            None => {
+                self.emit(Instruction::Load {
+                    dest: root,
+                    src: value,
+                });
+                return Some(());
+            }
+        };
+
+        let mut parents = Vec::new();
+        let mut object = root;
+
+        for piece in intermediate {
+            let member = self.lookup_member(object, piece)?;
+            let ty = self.cx.variables.get(member).expect("just found it").ty;
+            let temp = self.temporary(ty);
+            self.emit(Instruction::LoadMember {
+                dest: temp,
+                object,
+                member,
+            });
+            parents.push((object, member, temp));
+            object = temp;
+        }
+
+        let member = self.lookup_member(object, last)?;
+        self.emit(Instruction::StoreMember {
+            object,
+            member,
+            value,
+        });
+
+        for (object, member, temp) in parents.into_iter().rev() {
+            self.emit(Instruction::StoreMember {
+                object,
+                member,
+                value: Operand::Variable(temp),
+            });
+        }
+
+        Some(())
+    }
+}
+
+fn lookup_parameter(
+    parameters: &[(EntityId, Variable)],
+    name: &Identifier,
+) -> Option<EntityId> {
+    let to_lower = name.value.to_lowercase();
+
+    parameters
+        .iter()
+        .find(|(_, var)| {
+            var.name.as_ref().map(|n| n.to_lowercase())
+                == Some(to_lower.clone())
+        })
+        .map(|&(id, _)| id)
+}
+
+pub(crate) fn binary_op(op: BinOp) -> BinaryOp {
+    match op {
+        BinOp::Add => BinaryOp::Add,
+        BinOp::Subtract => BinaryOp::Subtract,
+        BinOp::Multiply => BinaryOp::Multiply,
+        BinOp::Divide => BinaryOp::Divide,
+        BinOp::Modulo => BinaryOp::Modulo,
+        BinOp::Exponent => BinaryOp::Exponent,
+        BinOp::And => BinaryOp::And,
+        BinOp::Or => BinaryOp::Or,
+        BinOp::Xor => BinaryOp::Xor,
+        BinOp::Equals => BinaryOp::Equals,
+        BinOp::NotEquals => BinaryOp::NotEquals,
+        BinOp::LessThan => BinaryOp::LessThan,
+        BinOp::LessThanOrEqual => BinaryOp::LessThanOrEqual,
+        BinOp::GreaterThan => BinaryOp::GreaterThan,
+        BinOp::GreaterThanOrEqual => BinaryOp::GreaterThanOrEqual,
+        BinOp::Not => unreachable!("NOT is a unary operator"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::passes::PassContext;
+    use crate::CompilationUnit;
+
+    fn lower(src: &str) -> (CompilationUnit, Diagnostics) {
+        let ast: iec_syntax::File = src.parse().unwrap();
+        let mut diags = Diagnostics::new();
+        let cu =
+            crate::process(&ast, &mut PassContext::new_nop_logger(&mut diags));
+
+        (cu, diags)
+    }
+
+    fn entry_block(cu: &CompilationUnit) -> EntityId {
+        let programs = cu.resources.get::<Program>();
+        let (_, program) = programs.iter().next().unwrap();
+        program.entry_block
+    }
+
+    #[test]
+    fn lower_a_for_loop() {
+        let src = "
+            PROGRAM main
+                VAR
+                    i: int;
+                    count: int;
+                END_VAR
+
+                FOR i := 0 TO 7 DO
+                    count := count + i;
+                END_FOR;
+            END_PROGRAM";
+
+        let (cu, diags) = lower(src);
+
+        assert!(diags.is_empty(), "{:?}", diags);
+        let blocks = cu.resources.get::<BasicBlock>();
+        // entry, loop header, loop body, after the loop
+        assert_eq!(blocks.len(), 4);
+
+        let entry = blocks.get(entry_block(&cu)).unwrap();
+        assert_eq!(entry.instructions.len(), 1);
+        let header = match entry.terminator {
+            Terminator::Jump(header) => blocks.get(header).unwrap(),
+            other => panic!("Expected a jump, found {:?}", other),
+        };
+
+        match header.terminator {
+            Terminator::Branch { then, .. } => {
+                let body = blocks.get(then).unwrap();
+                assert_eq!(body.instructions.len(), 3);
+            }
+            other => panic!("Expected a branch, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn code_after_a_return_is_in_its_own_block() {
+        let src = "
+            PROGRAM main
+                VAR
+                    i: int;
+                END_VAR
+
+                i := 1;
+                RETURN;
+                i := 2;
+            END_PROGRAM";
+
+        let (cu, diags) = lower(src);
+
+        assert!(!diags.has_errors(), "{:?}", diags);
+        let blocks = cu.resources.get::<BasicBlock>();
+        assert_eq!(blocks.len(), 2);
+        let entry = blocks.get(entry_block(&cu)).unwrap();
+        assert_eq!(entry.terminator, Terminator::Return);
+    }
+
+    #[test]
+    fn exit_jumps_to_the_end_of_the_loop() {
+        let src = "
+            PROGRAM main
+                VAR
+                    i: int;
+                END_VAR
+
+                WHILE true DO
+                    REPEAT
+                        EXIT;
+                    UNTIL false END_REPEAT;
+                    i := 1;
+                END_WHILE;
+            END_PROGRAM";
+
+        let (cu, diags) = lower(src);
+
+        assert!(!diags.has_errors(), "{:?}", diags);
+        let blocks = cu.resources.get::<BasicBlock>();
+        let instructions = cu.resources.get::<Instruction>();
+
+        // the only block with instructions is the one after the REPEAT loop
+        let (after_repeat, block) = blocks
+            .iter()
+            .find(|(_, block)| !block.instructions.is_empty())
+            .unwrap();
+        assert!(matches!(
+            instructions.get(block.instructions[0]).unwrap(),
+            Instruction::Load { .. }
+        ));
+
+        // and the EXIT is the only way to get there
+        let jumps_to_after_repeat: Vec<_> = blocks
+            .iter()
+            .filter(|(_, block)| {
+                block.terminator.successors().contains(&after_repeat)
+            })
+            .collect();
+        assert_eq!(jumps_to_after_repeat.len(), 1);
+        let (_, exit_block) = jumps_to_after_repeat[0];
+        assert!(exit_block.instructions.is_empty());
+    }
+
+    #[test]
+    fn exit_outside_a_loop_is_an_error() {
+        let src = "PROGRAM main EXIT; END_PROGRAM";
+
+        let (_, diags) = lower(src);
+
+        assert!(diags.has_errors());
+        assert_eq!(diags.len(), 1);
+    }
+
+    #[test]
+    fn unknown_variables_are_reported() {
+        let src = "PROGRAM main x := y + 1; END_PROGRAM";
+
+        let (_, diags) = lower(src);
+
+        assert_eq!(diags.len(), 2);
+    }
+
+    #[test]
+    fn call_a_function_block_instance() {
+        let src = "
+            FUNCTION_BLOCK counter
+                VAR_INPUT
+                    enable: bool;
+                END_VAR
+                VAR_OUTPUT
+                    count: int;
+                END_VAR
+                VAR_IN_OUT
+                    total: int;
+                END_VAR
+            BEGIN
+                IF enable THEN
+                    count := count + 1;
+                    total := total + 1;
+                END_IF;
+            END_FUNCTION_BLOCK
+
+            PROGRAM main
+                VAR
+                    c: counter;
+                    n: int;
+                    total: int;
+                END_VAR
+
+                c(true, total);
+                n := c.count;
+            END_PROGRAM";
+
+        let (cu, diags) = lower(src);
+
+        assert!(!diags.has_errors(), "{:?}", diags);
+        let function_blocks = cu.resources.get::<FunctionBlock>();
+        let (fb_id, fb) = function_blocks.iter().next().unwrap();
+        assert!(!fb.entry_block.is_placeholder());
+
+        let instructions = cu.resources.get::<Instruction>();
+        let (target, instance, args) = instructions
+            .iter()
+            .find_map(|(_, instruction)| match instruction {
+                Instruction::Call {
+                    target,
+                    instance,
+                    ref args,
+                    ..
+                } => Some((*target, *instance, args.clone())),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(target, Symbol::FunctionBlock(fb_id));
+        assert!(instance.is_some());
+        assert_eq!(args.len(), 2);
+        assert!(matches!(args[0], Argument::Input { .. }));
+        assert!(matches!(args[1], Argument::InOut { .. }));
+
+        let reads_output = instructions.iter().any(|(_, i)| match i {
+            Instruction::LoadMember { object, .. } => Some(*object) == instance,
+            _ => false,
+        });
+        assert!(reads_output);
+    }
+
+    #[test]
+    fn functions_return_a_value() {
+        let src = "
+            FUNCTION add_one : int
+            BEGIN
+                VAR_INPUT
+                    x: int;
+                END_VAR
+                add_one := x + 1;
+            END_FUNCTION
+
+            PROGRAM main
+                VAR
+                    n: int;
+                END_VAR
+
+                n := add_one(x := n);
+            END_PROGRAM";
+
+        let (cu, diags) = lower(src);
+
+        assert!(diags.is_empty(), "{:?}", diags);
+        let functions = cu.resources.get::<Function>();
+        let (_, add_one) =
+            functions.iter().find(|(_, f)| f.name == "add_one").unwrap();
+        let variables = cu.resources.get::<Variable>();
+        let return_value = variables.get(add_one.return_value).unwrap();
+        assert_eq!(return_value.kind, VariableKind::ReturnValue);
+        assert_eq!(return_value.ty, add_one.return_type);
+
+        let blocks = cu.resources.get::<BasicBlock>();
+        let instructions = cu.resources.get::<Instruction>();
+        let body = blocks.get(add_one.entry_block).unwrap();
+        let last = body.instructions.last().unwrap();
+        assert_eq!(
+            instructions.get(*last).unwrap(),
+            &Instruction::Load {
+                dest: add_one.return_value,
+                src: match instructions.get(body.instructions[0]).unwrap() {
+                    Instruction::Binary { dest, .. } =>
+                        Operand::Variable(*dest),
+                    other => panic!("Unexpected instruction: {:?}", other),
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn function_blocks_cant_be_called_directly() {
+        let src = "
+            FUNCTION_BLOCK fb BEGIN END_FUNCTION_BLOCK
+            PROGRAM main fb(); END_PROGRAM";
+
+        let (_, diags) = lower(src);
+
+        assert_eq!(diags.len(), 1);
+    }
+
+    #[test]
+    fn in_out_parameters_need_a_variable() {
+        let src = "
+            FUNCTION_BLOCK fb
+                VAR_IN_OUT
+                    x: int;
+                END_VAR
+            BEGIN
+            END_FUNCTION_BLOCK
+
+            PROGRAM main
+                VAR
+                    inst: fb;
+                END_VAR
+                inst(x := 5);
+                inst();
+            END_PROGRAM";
+
+        let (_, diags) = lower(src);
+
+        // passing a literal means the first call is also missing an argument
+        assert_eq!(diags.len(), 3);
+    }
 }
@@ -0,0 +1,319 @@
+use super::{Pass, PassContext};
+use crate::analysis::ssa::{self, SsaForm, SsaValue};
+use crate::analysis::{ControlFlow, ControlFlowCache, SsaCache};
+use crate::ecs::{
+    Container, EntityId, Read, ReadWrite, Singleton, SingletonMut,
+};
+use crate::hir::{
+    BasicBlock, BinaryOp, Constant, Instruction, Operand, UnaryOp, Variable,
+    VariableKind,
+};
+use typename::TypeName;
+
+/// Reuse the result of an earlier calculation instead of doing the same
+/// work again (e.g. the second `a * b` in `x := a * b; y := a * b + 1`).
+#[derive(TypeName)]
+pub enum CommonSubexpressionElimination {}
+
+impl<'r> Pass<'r> for CommonSubexpressionElimination {
+    type Arg = ();
+    type Storage = (
+        Read<'r, Variable>,
+        Read<'r, Constant>,
+        ReadWrite<'r, Instruction>,
+        Read<'r, BasicBlock>,
+        Singleton<'r, ControlFlowCache>,
+        SingletonMut<'r, SsaCache>,
+    );
+    const DESCRIPTION: &'static str =
+        "Reuse the results of identical calculations";
+    const REQUIRES: &'static [&'static str] = &["control-flow"];
+
+    fn run(_: &Self::Arg, ctx: &mut PassContext<'_>, storage: Self::Storage) {
+        let (
+            variables,
+            constants,
+            mut instructions,
+            blocks,
+            cache,
+            mut ssa_cache,
+        ) = storage;
+        let tracked =
+            |v| variables.get(v).map(ssa::is_ssa_candidate).unwrap_or(false);
+
+        let mut items: Vec<_> = cache.iter().collect();
+        items.sort_by_key(|&(item, _)| EntityId::from(item));
+
+        for (item, control_flow) in items {
+            let ssa = SsaForm::construct(
+                control_flow,
+                &blocks,
+                &instructions,
+                tracked,
+            );
+
+            let mut eliminator = Eliminator {
+                control_flow,
+                ssa: &ssa,
+                variables: &variables,
+                constants: &constants,
+                blocks: &blocks,
+                instructions: &mut instructions,
+                available: Vec::new(),
+                eliminated: 0,
+            };
+            eliminator.visit(control_flow.graph.entry());
+            let eliminated = eliminator.eliminated;
+
+            if eliminated > 0 {
+                ssa_cache.invalidate(item);
+            }
+
+            slog::debug!(ctx.logger, "Eliminated common subexpressions";
+                "item" => format_args!("{:?}", item),
+                "eliminated" => eliminated);
+        }
+    }
+}
+
+/// Something an operator can be applied to, identified by its value rather
+/// than the variable it's stored in.
+#[derive(Debug, Clone, PartialEq)]
+enum Term {
+    Constant(Constant),
+    Value(SsaValue),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expression {
+    Binary(BinaryOp, Term, Term),
+    Unary(UnaryOp, Term),
+}
+
+struct Eliminator<'a> {
+    control_flow: &'a ControlFlow,
+    ssa: &'a SsaForm,
+    variables: &'a Container<Variable>,
+    constants: &'a Container<Constant>,
+    blocks: &'a Container<BasicBlock>,
+    instructions: &'a mut Container<Instruction>,
+    /// The expressions calculated by the blocks dominating the current one,
+    /// and the temporary holding each result.
+    available: Vec<(Expression, EntityId)>,
+    eliminated: usize,
+}
+
+impl<'a> Eliminator<'a> {
+    /// Walk the dominator tree, so an expression is only ever reused when
+    /// it's guaranteed to have been calculated already.
+    fn visit(&mut self, block: EntityId) {
+        let scope = self.available.len();
+        let blocks = self.blocks;
+        let bb = blocks.get(block).expect("all blocks exist");
+
+        for &id in &bb.instructions {
+            let instruction =
+                self.instructions.get(id).expect("all instructions exist");
+
+            let (dest, expression) = match self.expression(id, instruction) {
+                Some(found) => found,
+                None => continue,
+            };
+
+            let previous = self
+                .available
+                .iter()
+                .find(|(expr, _)| equivalent(expr, &expression))
+                .map(|&(_, temp)| temp);
+
+            match previous {
+                Some(temp) => {
+                    *self.instructions.get_mut(id).expect("exists") =
+                        Instruction::Load {
+                            dest,
+                            src: Operand::Variable(temp),
+                        };
+                    self.eliminated += 1;
+                }
+                None => self.available.push((expression, dest)),
+            }
+        }
+
+        for child in self.control_flow.dominators.children(block) {
+            self.visit(child);
+        }
+
+        self.available.truncate(scope);
+    }
+
+    fn expression(
+        &self,
+        id: EntityId,
+        instruction: &Instruction,
+    ) -> Option<(EntityId, Expression)> {
+        match *instruction {
+            Instruction::Binary {
+                dest,
+                op,
+                left,
+                right,
+            } if self.is_temporary(dest) => {
+                let left = self.term(id, left)?;
+                let right = self.term(id, right)?;
+                Some((dest, Expression::Binary(op, left, right)))
+            }
+            Instruction::Unary { dest, op, value }
+                if self.is_temporary(dest) =>
+            {
+                let value = self.term(id, value)?;
+                Some((dest, Expression::Unary(op, value)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Temporaries are only ever assigned once, so their value can't change
+    /// after the expression is calculated.
+    fn is_temporary(&self, variable: EntityId) -> bool {
+        self.variables
+            .get(variable)
+            .map(|v| v.kind == VariableKind::Temporary)
+            .unwrap_or(false)
+    }
+
+    fn term(&self, id: EntityId, operand: Operand) -> Option<Term> {
+        match operand {
+            Operand::Constant(c) => {
+                self.constants.get(c).cloned().map(Term::Constant)
+            }
+            // untracked variables may change behind our back
+            Operand::Variable(v) => self.ssa.use_of(id, v).map(Term::Value),
+        }
+    }
+}
+
+fn equivalent(left: &Expression, right: &Expression) -> bool {
+    if left == right {
+        return true;
+    }
+
+    match (left, right) {
+        (Expression::Binary(op, a, b), Expression::Binary(other, c, d)) => {
+            op == other && is_commutative(*op) && a == d && b == c
+        }
+        _ => false,
+    }
+}
+
+fn is_commutative(op: BinaryOp) -> bool {
+    matches!(
+        op,
+        BinaryOp::Add
+            | BinaryOp::Multiply
+            | BinaryOp::And
+            | BinaryOp::Or
+            | BinaryOp::Xor
+            | BinaryOp::Equals
+            | BinaryOp::NotEquals
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hir::CompilationUnit;
+    use crate::passes::{self, OptimizationLevel};
+    use crate::Diagnostics;
+
+    fn optimize(src: &str) -> CompilationUnit {
+        let ast: iec_syntax::File = src.parse().unwrap();
+        let mut diags = Diagnostics::new();
+        let mut ctx = PassContext::new_nop_logger(&mut diags);
+        let mut cu = crate::process(&ast, &mut ctx);
+        passes::optimize(&mut cu, OptimizationLevel::Full, &mut ctx);
+
+        cu
+    }
+
+    fn multiplications(cu: &CompilationUnit) -> usize {
+        let blocks = cu.resources.get::<BasicBlock>();
+        let instructions = cu.resources.get::<Instruction>();
+
+        blocks
+            .iter()
+            .flat_map(|(_, bb)| bb.instructions.iter())
+            .filter(|&&id| {
+                matches!(
+                    instructions.get(id),
+                    Some(Instruction::Binary {
+                        op: BinaryOp::Multiply,
+                        ..
+                    })
+                )
+            })
+            .count()
+    }
+
+    #[test]
+    fn repeated_calculations_are_reused() {
+        let src = "
+            PROGRAM main
+                VAR
+                    a: int;
+                    b: int;
+                    x: int;
+                    y: int;
+                END_VAR
+
+                x := a * b;
+                y := b * a + 1;
+            END_PROGRAM";
+
+        let cu = optimize(src);
+
+        assert_eq!(multiplications(&cu), 1);
+    }
+
+    #[test]
+    fn calculations_are_redone_when_an_operand_changes() {
+        let src = "
+            PROGRAM main
+                VAR
+                    a: int;
+                    b: int;
+                    x: int;
+                    y: int;
+                END_VAR
+
+                x := a * b;
+                a := 2;
+                y := a * b;
+            END_PROGRAM";
+
+        let cu = optimize(src);
+
+        assert_eq!(multiplications(&cu), 2);
+    }
+
+    #[test]
+    fn only_dominating_calculations_are_reused() {
+        let src = "
+            PROGRAM main
+                VAR
+                    a: int;
+                    b: int;
+                    x: int;
+                    y: int;
+                END_VAR
+
+                IF a > 0 THEN
+                    x := a * b;
+                END_IF;
+                y := a * b;
+            END_PROGRAM";
+
+        let cu = optimize(src);
+
+        assert_eq!(multiplications(&cu), 2);
+    }
+}
@@ -0,0 +1,583 @@
+use super::symbol_table::{Binding, SymbolTable};
+use super::{Pass, PassContext};
+use crate::ecs::{Container, EntityId, Read, ReadWrite, Singleton};
+use crate::hir::{
+    Configuration, ProgramInstance, Resource, Span, Symbol, Task, Type,
+    Variable, VariableKind,
+};
+use crate::Diagnostics;
+use codespan::ByteSpan;
+use codespan_reporting::{Diagnostic, Label};
+use iec_syntax::{Identifier, Item, LiteralKind, TaskValue, VarBlock};
+use std::collections::HashMap;
+use typename::TypeName;
+
+#[derive(TypeName)]
+pub enum ConfigurationResolution {}
+
+impl<'r> Pass<'r> for ConfigurationResolution {
+    type Arg = iec_syntax::File;
+    type Storage = (
+        Singleton<'r, SymbolTable>,
+        Read<'r, Variable>,
+        Read<'r, Type>,
+        ReadWrite<'r, Configuration>,
+        ReadWrite<'r, Resource>,
+        ReadWrite<'r, Task>,
+        ReadWrite<'r, ProgramInstance>,
+        ReadWrite<'r, Span>,
+    );
+    const DESCRIPTION: &'static str =
+        "Resolve the tasks and program instances in each configuration";
+    const REQUIRES: &'static [&'static str] = &["variables"];
+    const PROVIDES: &'static [&'static str] = &["configurations"];
+
+    fn run(ast: &Self::Arg, ctx: &mut PassContext<'_>, storage: Self::Storage) {
+        let (
+            symbols,
+            variables,
+            types,
+            mut configurations,
+            mut resources,
+            mut tasks,
+            mut instances,
+            mut spans,
+        ) = storage;
+
+        let mut resolver = Resolver {
+            symbols: &symbols,
+            variables: &variables,
+            types: &types,
+            resources: &mut resources,
+            tasks: &mut tasks,
+            instances: &mut instances,
+            spans: &mut spans,
+            diags: ctx.diags,
+        };
+        let mut names = Names::default();
+
+        for item in &ast.items {
+            let config = match item {
+                Item::Configuration(ref c) => c,
+                _ => continue,
+            };
+
+            if !names.declare(&config.name, resolver.diags) {
+                continue;
+            }
+
+            let configuration = resolver.configuration(config);
+            slog::debug!(ctx.logger, "Resolved configuration";
+                "name" => &configuration.name,
+                "resource-count" => configuration.resources.len());
+
+            let id = configurations.insert(configuration);
+            resolver.spans.attach(id, Span(config.span));
+        }
+    }
+}
+
+struct Resolver<'a> {
+    symbols: &'a SymbolTable,
+    variables: &'a Container<Variable>,
+    types: &'a Container<Type>,
+    resources: &'a mut Container<Resource>,
+    tasks: &'a mut Container<Task>,
+    instances: &'a mut Container<ProgramInstance>,
+    spans: &'a mut Container<Span>,
+    diags: &'a mut Diagnostics,
+}
+
+impl<'a> Resolver<'a> {
+    fn configuration(
+        &mut self,
+        config: &iec_syntax::Configuration,
+    ) -> Configuration {
+        let mut names = Names::default();
+        let mut resources = Vec::new();
+
+        for resource in &config.resources {
+            if names.declare(&resource.name, self.diags) {
+                let id = self.resource(resource);
+                self.spans.attach(id, Span(resource.span));
+                resources.push(id);
+            }
+        }
+
+        Configuration {
+            name: config.name.value.clone(),
+            resources,
+            globals: self.globals(&config.globals),
+        }
+    }
+
+    fn resource(&mut self, resource: &iec_syntax::Resource) -> EntityId {
+        let mut task_names = Names::default();
+        let mut tasks = Vec::new();
+        let mut task_ids = HashMap::new();
+
+        for task in &resource.tasks {
+            if !task_names.declare(&task.name, self.diags) {
+                continue;
+            }
+
+            if let Some(resolved) = self.task(task) {
+                let id = self.tasks.insert(resolved);
+                self.spans.attach(id, Span(task.span));
+                task_ids.insert(task.name.value.to_lowercase(), id);
+                tasks.push(id);
+            }
+        }
+
+        let mut instance_names = Names::default();
+        let mut programs = Vec::new();
+
+        for instance in &resource.programs {
+            if !instance_names.declare(&instance.name, self.diags) {
+                continue;
+            }
+
+            let task = match instance.task {
+                Some(ref name) => {
+                    match task_ids.get(&name.value.to_lowercase()) {
+                        Some(&id) => Some(id),
+                        // the task is invalid and has already been reported
+                        None if task_names.contains(name) => continue,
+                        None => {
+                            let msg = format!(
+                                "\"{}\" isn't declared in this resource",
+                                name.value
+                            );
+                            self.diags.push(
+                                Diagnostic::new_error("Unknown task")
+                                    .with_label(
+                                        Label::new_primary(name.span)
+                                            .with_message(msg),
+                                    ),
+                            );
+                            continue;
+                        }
+                    }
+                }
+                None => None,
+            };
+
+            if let Some(program) = self.program_type(&instance.program) {
+                let id = self.instances.insert(ProgramInstance {
+                    name: instance.name.value.clone(),
+                    task,
+                    program,
+                });
+                self.spans.attach(id, Span(instance.span));
+                programs.push(id);
+            }
+        }
+
+        self.resources.insert(Resource {
+            name: resource.name.value.clone(),
+            processor: resource.processor.as_ref().map(|p| p.value.clone()),
+            tasks,
+            programs,
+            globals: self.globals(&resource.globals),
+        })
+    }
+
+    fn task(&mut self, task: &iec_syntax::Task) -> Option<Task> {
+        let mut seen = Names::default();
+        let mut interval = None;
+        let mut priority = Task::DEFAULT_PRIORITY;
+        let mut single = None;
+        let mut valid = true;
+
+        for property in &task.properties {
+            if !seen.declare(&property.name, self.diags) {
+                valid = false;
+                continue;
+            }
+
+            match property.name.value.to_lowercase().as_str() {
+                "interval" => match property.value {
+                    TaskValue::Time(ref t) if t.nanoseconds > 0 => {
+                        interval = Some(t.nanoseconds)
+                    }
+                    TaskValue::Time(ref t) => {
+                        valid = false;
+                        self.error(
+                            "A task's interval must be greater than zero",
+                            t.span,
+                        );
+                    }
+                    ref other => {
+                        valid = false;
+                        self.error(
+                            "Expected a time literal (e.g. T#10ms)",
+                            value_span(other),
+                        );
+                    }
+                },
+                "priority" => match property.value {
+                    TaskValue::Literal(ref lit) => match lit.kind {
+                        LiteralKind::Integer(n)
+                            if n >= 0 && n <= i64::from(u32::MAX) =>
+                        {
+                            priority = n as u32
+                        }
+                        _ => {
+                            valid = false;
+                            self.error(
+                                "Priorities must be a non-negative integer",
+                                lit.span,
+                            );
+                        }
+                    },
+                    ref other => {
+                        valid = false;
+                        self.error(
+                            "Priorities must be a non-negative integer",
+                            value_span(other),
+                        );
+                    }
+                },
+                "single" => match property.value {
+                    TaskValue::Variable(ref var) => {
+                        match self.trigger(&var.pieces, var.span) {
+                            Some(id) => single = Some(id),
+                            None => valid = false,
+                        }
+                    }
+                    ref other => {
+                        valid = false;
+                        self.error(
+                            "Expected the name of a global BOOL variable",
+                            value_span(other),
+                        );
+                    }
+                },
+                _ => {
+                    valid = false;
+                    self.diags.push(
+                        Diagnostic::new_error("Unknown task property")
+                            .with_label(
+                                Label::new_primary(property.name.span)
+                                    .with_message(
+                                    "Expected INTERVAL, PRIORITY, or SINGLE",
+                                ),
+                            ),
+                    );
+                }
+            }
+        }
+
+        if valid && interval.is_none() && single.is_none() {
+            valid = false;
+            self.diags.push(
+                Diagnostic::new_error(
+                    "A task needs either an INTERVAL or a SINGLE trigger",
+                )
+                .with_label(Label::new_primary(task.name.span)),
+            );
+        }
+
+        if valid {
+            Some(Task {
+                name: task.name.value.clone(),
+                interval,
+                priority,
+                single,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Find the global `BOOL` variable used to trigger an event task.
+    fn trigger(
+        &mut self,
+        pieces: &[Identifier],
+        span: ByteSpan,
+    ) -> Option<EntityId> {
+        const MSG: &str = "Expected the name of a global BOOL variable";
+
+        let name = match pieces {
+            [name] => name,
+            _ => {
+                self.error(MSG, span);
+                return None;
+            }
+        };
+
+        let global_scope = self.symbols.global_scope();
+        let id = match self.symbols.lookup_local(global_scope, &name.value) {
+            Some(def) => match def.binding {
+                Binding::Variable(id) => id,
+                Binding::Symbol(_) => {
+                    self.error(MSG, span);
+                    return None;
+                }
+            },
+            None => {
+                self.diags.push(
+                    Diagnostic::new_error("Unknown variable")
+                        .with_label(Label::new_primary(span)),
+                );
+                return None;
+            }
+        };
+
+        let is_bool = self.variables.get(id).is_some_and(|v| {
+            v.kind == VariableKind::Global
+                && self.types.get(v.ty).is_some_and(|ty| ty.name == "bool")
+        });
+
+        if is_bool {
+            Some(id)
+        } else {
+            self.error(MSG, span);
+            None
+        }
+    }
+
+    fn program_type(&mut self, name: &Identifier) -> Option<EntityId> {
+        match self.symbols.get(&name.value) {
+            Some(Symbol::Program(id)) => Some(id),
+            Some(_) => {
+                self.error("Expected the name of a program", name.span);
+                None
+            }
+            None => {
+                self.diags.push(
+                    Diagnostic::new_error("Unknown program")
+                        .with_label(Label::new_primary(name.span)),
+                );
+                None
+            }
+        }
+    }
+
+    /// The [`Variable`]s created by variable discovery for a set of
+    /// `VAR_GLOBAL` blocks.
+    fn globals(&self, blocks: &[VarBlock]) -> Vec<EntityId> {
+        let global_scope = self.symbols.global_scope();
+
+        blocks
+            .iter()
+            .flat_map(|block| &block.declarations)
+            .filter_map(|decl| {
+                match self.symbols.lookup_local(global_scope, &decl.ident.value)
+                {
+                    // duplicates will point at the original declaration
+                    Some(def) if def.span == Some(decl.ident.span) => {
+                        match def.binding {
+                            Binding::Variable(id) => Some(id),
+                            Binding::Symbol(_) => None,
+                        }
+                    }
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    fn error(&mut self, message: &str, span: ByteSpan) {
+        self.diags.push(
+            Diagnostic::new_error(message).with_label(Label::new_primary(span)),
+        );
+    }
+}
+
+fn value_span(value: &TaskValue) -> ByteSpan {
+    match value {
+        TaskValue::Time(ref t) => t.span,
+        TaskValue::Literal(ref l) => l.span,
+        TaskValue::Variable(ref v) => v.span,
+    }
+}
+
+/// Names declared within a single configuration, resource, or task, used to
+/// detect duplicates.
+#[derive(Debug, Default)]
+struct Names(HashMap<String, ByteSpan>);
+
+impl Names {
+    /// Try to declare a name, emitting a diagnostic and returning `false` if
+    /// it's already been used.
+    fn declare(&mut self, ident: &Identifier, diags: &mut Diagnostics) -> bool {
+        let key = ident.value.to_lowercase();
+
+        match self.0.get(&key) {
+            Some(&original) => {
+                diags.push(
+                    Diagnostic::new_error("Name is already declared")
+                        .with_label(
+                            Label::new_primary(ident.span)
+                                .with_message("Duplicate declared here"),
+                        )
+                        .with_label(
+                            Label::new_secondary(original)
+                                .with_message("Original declared here"),
+                        ),
+                );
+                false
+            }
+            None => {
+                self.0.insert(key, ident.span);
+                true
+            }
+        }
+    }
+
+    fn contains(&self, ident: &Identifier) -> bool {
+        self.0.contains_key(&ident.value.to_lowercase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ecs::Resources;
+    use std::time::Duration;
+
+    fn analyse(src: &str) -> (Resources, Vec<String>) {
+        let ast: iec_syntax::File = src.parse().unwrap();
+        let mut diags = Diagnostics::new();
+        let cu =
+            crate::process(&ast, &mut PassContext::new_nop_logger(&mut diags));
+
+        let messages = diags
+            .diagnostics()
+            .iter()
+            .map(|d| d.message.clone())
+            .collect();
+
+        (cu.resources, messages)
+    }
+
+    const PROGRAMS: &str = "
+        PROGRAM main
+        VAR
+            x: int;
+        END_VAR
+            x := x + 1;
+        END_PROGRAM
+
+        FUNCTION add : int BEGIN
+        VAR_INPUT
+            a: int;
+        END_VAR
+            add := a;
+        END_FUNCTION
+    ";
+
+    #[test]
+    fn resolve_a_simple_configuration() {
+        let src = format!(
+            "{}
+            CONFIGURATION plc
+                VAR_GLOBAL
+                    start: bool;
+                END_VAR
+                RESOURCE cpu ON x86
+                    VAR_GLOBAL
+                        counter: int;
+                    END_VAR
+                    TASK fast(INTERVAL := T#10ms, PRIORITY := 1);
+                    TASK on_start(SINGLE := start);
+                    PROGRAM fast_main WITH fast : main;
+                    PROGRAM start_main WITH on_start : main;
+                    PROGRAM background : main;
+                END_RESOURCE
+            END_CONFIGURATION",
+            PROGRAMS
+        );
+
+        let (resources, messages) = analyse(&src);
+
+        assert!(messages.is_empty(), "{:?}", messages);
+        let configurations = resources.get::<Configuration>();
+        let (_, config) = configurations.iter().next().unwrap();
+        assert_eq!(config.name, "plc");
+        assert_eq!(config.globals.len(), 1);
+
+        let all_resources = resources.get::<Resource>();
+        let cpu = all_resources.get(config.resources[0]).unwrap();
+        assert_eq!(cpu.processor, Some(String::from("x86")));
+        assert_eq!(cpu.globals.len(), 1);
+        assert_eq!(cpu.programs.len(), 3);
+
+        let tasks = resources.get::<Task>();
+        let fast = tasks.get(cpu.tasks[0]).unwrap();
+        assert_eq!(fast.interval(), Some(Duration::from_millis(10)));
+        assert_eq!(fast.priority, 1);
+        let on_start = tasks.get(cpu.tasks[1]).unwrap();
+        assert_eq!(on_start.single, Some(config.globals[0]));
+
+        let instances = resources.get::<ProgramInstance>();
+        let fast_main = instances.get(cpu.programs[0]).unwrap();
+        assert_eq!(fast_main.task, Some(cpu.tasks[0]));
+        let background = instances.get(cpu.programs[2]).unwrap();
+        assert_eq!(background.task, None);
+    }
+
+    #[test]
+    fn detect_invalid_references() {
+        let src = format!(
+            "{}
+            CONFIGURATION plc
+                RESOURCE cpu
+                    TASK fast(INTERVAL := T#10ms);
+                    PROGRAM a WITH slow : main;
+                    PROGRAM b WITH fast : missing;
+                    PROGRAM c WITH fast : add;
+                    PROGRAM c WITH fast : main;
+                END_RESOURCE
+            END_CONFIGURATION",
+            PROGRAMS
+        );
+
+        let (_, messages) = analyse(&src);
+
+        assert_eq!(
+            messages,
+            vec![
+                "Unknown task",
+                "Unknown program",
+                "Expected the name of a program",
+                "Name is already declared",
+            ]
+        );
+    }
+
+    #[test]
+    fn detect_invalid_tasks() {
+        let src = "
+            VAR_GLOBAL
+                count: int;
+            END_VAR
+            CONFIGURATION plc
+                RESOURCE cpu
+                    TASK a(INTERVAL := 5);
+                    TASK b(PRIORITY := -1, INTERVAL := T#1s);
+                    TASK c(SINGLE := count);
+                    TASK d(PRIORITY := 2);
+                    TASK e(INTERVAL := T#1s, INTERVAL := T#2s);
+                    TASK f(INTERVAL := T#1s, CYCLE := 2);
+                    TASK a(INTERVAL := T#1s);
+                END_RESOURCE
+            END_CONFIGURATION";
+
+        let (_, messages) = analyse(src);
+
+        assert_eq!(
+            messages,
+            vec![
+                "Expected a time literal (e.g. T#10ms)",
+                "Priorities must be a non-negative integer",
+                "Expected the name of a global BOOL variable",
+                "A task needs either an INTERVAL or a SINGLE trigger",
+                "Name is already declared",
+                "Unknown task property",
+                "Name is already declared",
+            ]
+        );
+    }
+}
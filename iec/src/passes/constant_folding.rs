@@ -0,0 +1,459 @@
+use super::{Pass, PassContext};
+use crate::analysis::{ControlFlowCache, ControlFlowGraph};
+use crate::const_eval::{self, IntegerType, Value};
+use crate::ecs::{Container, EntityId, Read, ReadWrite, SingletonMut};
+use crate::hir::{
+    BasicBlock, Constant, Function, FunctionBlock, Instruction, Operand,
+    Program, Span, Symbol, Terminator, Type, Variable, VariableKind,
+};
+use crate::Diagnostics;
+use std::collections::HashMap;
+use typename::TypeName;
+
+/// Pre-compute operations whose operands are all known at compile time, and
+/// replace branches on a constant condition with a jump.
+#[derive(TypeName)]
+pub enum ConstantFolding {}
+
+impl<'r> Pass<'r> for ConstantFolding {
+    type Arg = ();
+    type Storage = (
+        Read<'r, Program>,
+        Read<'r, Function>,
+        Read<'r, FunctionBlock>,
+        Read<'r, Variable>,
+        Read<'r, Type>,
+        Read<'r, Span>,
+        ReadWrite<'r, Constant>,
+        ReadWrite<'r, Instruction>,
+        ReadWrite<'r, BasicBlock>,
+        SingletonMut<'r, ControlFlowCache>,
+    );
+    const DESCRIPTION: &'static str =
+        "Evaluate constant expressions and branches at compile time";
+    const REQUIRES: &'static [&'static str] = &["basic-blocks"];
+    const PROVIDES: &'static [&'static str] = &["constant-folding"];
+
+    fn run(_: &Self::Arg, ctx: &mut PassContext<'_>, storage: Self::Storage) {
+        let (
+            programs,
+            functions,
+            function_blocks,
+            variables,
+            types,
+            spans,
+            mut constants,
+            mut instructions,
+            mut blocks,
+            mut cache,
+        ) = storage;
+
+        let items: Vec<_> =
+            programs
+                .iter()
+                .map(|(id, p)| (Symbol::Program(id), p.entry_block))
+                .chain(
+                    functions
+                        .iter()
+                        .map(|(id, f)| (Symbol::Function(id), f.entry_block)),
+                )
+                .chain(function_blocks.iter().map(|(id, fb)| {
+                    (Symbol::FunctionBlock(id), fb.entry_block)
+                }))
+                .filter(|(_, entry)| !entry.is_placeholder())
+                .collect();
+
+        let mut folder = Folder {
+            variables: &variables,
+            types: &types,
+            spans: &spans,
+            constants: &mut constants,
+            instructions: &mut instructions,
+            diags: ctx.diags,
+            known: HashMap::new(),
+            folded: 0,
+        };
+
+        for (item, entry) in items {
+            let graph = ControlFlowGraph::new(item, entry, &blocks);
+
+            let mut branches_folded = 0;
+
+            // temporaries are always assigned before they are used, so
+            // visiting in reverse postorder sees each definition first
+            for block in graph.reverse_postorder() {
+                let bb = blocks.get_mut(block).expect("the block exists");
+
+                for &id in &bb.instructions {
+                    folder.fold_instruction(id);
+                }
+
+                if let Some(target) = folder.fold_terminator(&bb.terminator) {
+                    bb.terminator = Terminator::Jump(target);
+                    branches_folded += 1;
+                }
+            }
+
+            if branches_folded > 0 {
+                cache.invalidate(item);
+            }
+
+            slog::debug!(ctx.logger, "Folded constants";
+                "item" => format_args!("{:?}", item),
+                "branches-folded" => branches_folded);
+        }
+
+        slog::debug!(ctx.logger, "Constant folding complete";
+            "instructions-folded" => folder.folded);
+    }
+}
+
+struct Folder<'a> {
+    variables: &'a Container<Variable>,
+    types: &'a Container<Type>,
+    spans: &'a Container<Span>,
+    constants: &'a mut Container<Constant>,
+    instructions: &'a mut Container<Instruction>,
+    diags: &'a mut Diagnostics,
+    /// The values of compiler-generated temporaries which are known at
+    /// compile time. Temporaries are only ever assigned once, so this holds
+    /// for the entire item.
+    known: HashMap<EntityId, Value>,
+    folded: usize,
+}
+
+impl<'a> Folder<'a> {
+    fn fold_instruction(&mut self, id: EntityId) {
+        let instruction = self
+            .instructions
+            .get(id)
+            .cloned()
+            .expect("all instructions exist");
+
+        let folded = match instruction.clone() {
+            Instruction::Load { dest, src } => {
+                let src = self.propagate(src);
+
+                match self.value_of(src, self.integer_type(dest)) {
+                    Some(Ok(value)) => {
+                        self.remember(dest, &value);
+                        Instruction::Load { dest, src }
+                    }
+                    Some(Err(e)) => {
+                        self.error(id, &e);
+                        return;
+                    }
+                    None => Instruction::Load { dest, src },
+                }
+            }
+            Instruction::Binary {
+                dest,
+                op,
+                left,
+                right,
+            } => {
+                let left = self.propagate(left);
+                let right = self.propagate(right);
+                // comparisons produce a bool, so there's no destination type
+                // to evaluate the operands with
+                let ty = if op.is_comparison() {
+                    None
+                } else {
+                    self.integer_type(dest)
+                };
+
+                match (self.value_of(left, ty), self.value_of(right, ty)) {
+                    (Some(Ok(l)), Some(Ok(r))) => {
+                        match const_eval::binary(op, &l, &r) {
+                            Ok(value) => self.replace_with_load(dest, &value),
+                            Err(e) => {
+                                self.error(id, &e);
+                                return;
+                            }
+                        }
+                    }
+                    (Some(Err(e)), _) | (_, Some(Err(e))) => {
+                        self.error(id, &e);
+                        return;
+                    }
+                    _ => None,
+                }
+                .unwrap_or(Instruction::Binary {
+                    dest,
+                    op,
+                    left,
+                    right,
+                })
+            }
+            Instruction::Unary { dest, op, value } => {
+                let value = self.propagate(value);
+
+                match self.value_of(value, self.integer_type(dest)) {
+                    Some(Ok(v)) => match const_eval::unary(op, &v) {
+                        Ok(result) => self.replace_with_load(dest, &result),
+                        Err(e) => {
+                            self.error(id, &e);
+                            return;
+                        }
+                    },
+                    Some(Err(e)) => {
+                        self.error(id, &e);
+                        return;
+                    }
+                    None => None,
+                }
+                .unwrap_or(Instruction::Unary {
+                    dest,
+                    op,
+                    value,
+                })
+            }
+            Instruction::StoreMember {
+                object,
+                member,
+                value,
+            } => Instruction::StoreMember {
+                object,
+                member,
+                value: self.propagate(value),
+            },
+            other => other,
+        };
+
+        if folded != instruction {
+            self.folded += 1;
+            *self
+                .instructions
+                .get_mut(id)
+                .expect("all instructions exist") = folded;
+        }
+    }
+
+    /// If a branch's condition is known, which block will it always jump to?
+    fn fold_terminator(&mut self, terminator: &Terminator) -> Option<EntityId> {
+        match *terminator {
+            Terminator::Branch {
+                condition,
+                then,
+                otherwise,
+            } => {
+                let condition = self.propagate(condition);
+                let value = self.value_of(condition, None)?.ok()?;
+
+                if value.as_bool()? {
+                    Some(then)
+                } else {
+                    Some(otherwise)
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Replace a temporary with its value, if known.
+    fn propagate(&mut self, operand: Operand) -> Operand {
+        let value = match operand {
+            Operand::Variable(id) => self.known.get(&id),
+            Operand::Constant(_) => None,
+        };
+
+        match value.and_then(|v| v.to_constant()) {
+            Some(constant) => {
+                Operand::Constant(self.constants.insert(constant))
+            }
+            None => operand,
+        }
+    }
+
+    /// The value of an operand, if it's a constant, using `ty` as the type
+    /// for integers.
+    fn value_of(
+        &self,
+        operand: Operand,
+        ty: Option<IntegerType>,
+    ) -> Option<Result<Value, const_eval::EvalError>> {
+        match operand {
+            Operand::Constant(id) => {
+                let constant =
+                    self.constants.get(id).expect("all constants exist");
+                Some(Value::from_constant(constant, ty))
+            }
+            Operand::Variable(_) => None,
+        }
+    }
+
+    fn replace_with_load(
+        &mut self,
+        dest: EntityId,
+        value: &Value,
+    ) -> Option<Instruction> {
+        let constant = value.to_constant()?;
+        self.remember(dest, value);
+
+        Some(Instruction::Load {
+            dest,
+            src: Operand::Constant(self.constants.insert(constant)),
+        })
+    }
+
+    fn remember(&mut self, variable: EntityId, value: &Value) {
+        let is_temporary = self
+            .variables
+            .get(variable)
+            .map(|v| v.kind == VariableKind::Temporary)
+            .unwrap_or(false);
+
+        if is_temporary {
+            self.known.insert(variable, value.clone());
+        }
+    }
+
+    fn integer_type(&self, variable: EntityId) -> Option<IntegerType> {
+        let ty = self.variables.get(variable)?.ty;
+        let name = &self.types.get(ty)?.name;
+        IntegerType::from_name(name)
+    }
+
+    fn error(&mut self, instruction: EntityId, error: &const_eval::EvalError) {
+        let span = self
+            .spans
+            .get(instruction)
+            .expect("all instructions have a span");
+        self.diags.push(error.to_diagnostic(span.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hir::CompilationUnit;
+
+    fn fold(src: &str) -> (CompilationUnit, Diagnostics) {
+        let ast: iec_syntax::File = src.parse().unwrap();
+        let mut diags = Diagnostics::new();
+        let cu =
+            crate::process(&ast, &mut PassContext::new_nop_logger(&mut diags));
+
+        (cu, diags)
+    }
+
+    fn loaded_constants(cu: &CompilationUnit) -> Vec<Constant> {
+        let instructions = cu.resources.get::<Instruction>();
+        let constants = cu.resources.get::<Constant>();
+
+        instructions
+            .iter()
+            .filter_map(|(_, instruction)| match *instruction {
+                Instruction::Load {
+                    src: Operand::Constant(c),
+                    ..
+                } => constants.get(c).cloned(),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fold_arithmetic() {
+        let src = "
+            PROGRAM main
+                VAR
+                    x: int;
+                END_VAR
+
+                x := (1 + 2) * 3 - 4;
+            END_PROGRAM";
+
+        let (cu, diags) = fold(src);
+
+        assert!(!diags.has_errors(), "{:?}", diags);
+        assert!(loaded_constants(&cu).contains(&Constant::Integer(5)));
+        let instructions = cu.resources.get::<Instruction>();
+        assert!(instructions
+            .iter()
+            .all(|(_, i)| !matches!(i, Instruction::Binary { .. })));
+    }
+
+    #[test]
+    fn constant_branches_become_jumps() {
+        let src = "
+            PROGRAM main
+                VAR
+                    x: int;
+                END_VAR
+
+                IF 1 > 2 THEN
+                    x := 1;
+                END_IF;
+            END_PROGRAM";
+
+        let (cu, diags) = fold(src);
+
+        assert!(!diags.has_errors(), "{:?}", diags);
+        let blocks = cu.resources.get::<BasicBlock>();
+        assert!(blocks.iter().all(|(_, bb)| !matches!(
+            bb.terminator,
+            Terminator::Branch { .. }
+        )));
+        let cache = cu.resources.get_singleton::<ControlFlowCache>();
+        let (_, control_flow) = cache.iter().next().unwrap();
+        assert_eq!(control_flow.unreachable.len(), 1);
+    }
+
+    #[test]
+    fn operations_on_variables_are_left_alone() {
+        let src = "
+            PROGRAM main
+                VAR
+                    x: int;
+                END_VAR
+
+                x := x + 1;
+            END_PROGRAM";
+
+        let (cu, diags) = fold(src);
+
+        assert!(!diags.has_errors(), "{:?}", diags);
+        let instructions = cu.resources.get::<Instruction>();
+        assert!(instructions
+            .iter()
+            .any(|(_, i)| matches!(i, Instruction::Binary { .. })));
+    }
+
+    #[test]
+    fn division_by_zero_is_an_error() {
+        let src = "
+            PROGRAM main
+                VAR
+                    x: dint;
+                END_VAR
+
+                x := 10 / (5 - 5);
+            END_PROGRAM";
+
+        let (_, diags) = fold(src);
+
+        assert!(diags.has_errors());
+        let diags = diags.diagnostics();
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0].message, "Division by zero");
+    }
+
+    #[test]
+    fn values_must_fit_in_the_destination() {
+        let src = "
+            PROGRAM main
+                VAR
+                    x: sint;
+                END_VAR
+
+                x := 100 + 100;
+            END_PROGRAM";
+
+        let (_, diags) = fold(src);
+
+        let diags = diags.diagnostics();
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0].message, "Arithmetic overflow");
+    }
+}
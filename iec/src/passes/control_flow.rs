@@ -0,0 +1,129 @@
+use super::{Pass, PassContext};
+use crate::analysis::{ControlFlow, ControlFlowCache};
+use crate::ecs::{Read, SingletonMut};
+use crate::hir::{BasicBlock, Function, FunctionBlock, Program, Symbol};
+use typename::TypeName;
+
+#[derive(TypeName)]
+pub enum ControlFlowAnalysis {}
+
+impl<'r> Pass<'r> for ControlFlowAnalysis {
+    type Arg = ();
+    type Storage = (
+        Read<'r, Program>,
+        Read<'r, Function>,
+        Read<'r, FunctionBlock>,
+        Read<'r, BasicBlock>,
+        SingletonMut<'r, ControlFlowCache>,
+    );
+    const DESCRIPTION: &'static str =
+        "Analyse the control flow graph of each item's body";
+    const REQUIRES: &'static [&'static str] =
+        &["basic-blocks", "constant-folding"];
+    const PROVIDES: &'static [&'static str] = &["control-flow"];
+
+    fn run(_: &Self::Arg, ctx: &mut PassContext<'_>, storage: Self::Storage) {
+        let (programs, functions, function_blocks, blocks, mut cache) = storage;
+
+        let items =
+            programs
+                .iter()
+                .map(|(id, p)| (Symbol::Program(id), p.entry_block))
+                .chain(
+                    functions
+                        .iter()
+                        .map(|(id, f)| (Symbol::Function(id), f.entry_block)),
+                )
+                .chain(function_blocks.iter().map(|(id, fb)| {
+                    (Symbol::FunctionBlock(id), fb.entry_block)
+                }));
+
+        for (item, entry) in items {
+            if entry.is_placeholder() {
+                // the body was never lowered
+                continue;
+            }
+
+            let control_flow = ControlFlow::analyse(item, entry, &blocks);
+
+            slog::debug!(ctx.logger, "Analysed control flow";
+                "item" => format_args!("{:?}", item),
+                "block-count" => control_flow.graph.blocks().len(),
+                "loops" => control_flow.loops.len(),
+                "unreachable-blocks" => control_flow.unreachable.len());
+
+            cache.insert(item, control_flow);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hir::{CompilationUnit, Instruction};
+    use crate::Diagnostics;
+
+    fn analyse(src: &str) -> CompilationUnit {
+        let ast: iec_syntax::File = src.parse().unwrap();
+        let mut diags = Diagnostics::new();
+        let cu =
+            crate::process(&ast, &mut PassContext::new_nop_logger(&mut diags));
+        assert!(!diags.has_errors(), "{:?}", diags);
+
+        cu
+    }
+
+    fn control_flow(cu: &CompilationUnit) -> ControlFlow {
+        let cache = cu.resources.get_singleton::<ControlFlowCache>();
+        assert_eq!(cache.len(), 1);
+        let (_, control_flow) = cache.iter().next().unwrap();
+        control_flow.clone()
+    }
+
+    #[test]
+    fn loops_are_detected() {
+        let src = "
+            PROGRAM main
+                VAR
+                    i: int;
+                END_VAR
+
+                WHILE i < 10 DO
+                    i := i + 1;
+                END_WHILE;
+            END_PROGRAM";
+
+        let cu = analyse(src);
+        let control_flow = control_flow(&cu);
+
+        assert_eq!(control_flow.loops.len(), 1);
+        let header = control_flow.loops[0].header;
+        assert!(control_flow
+            .dominators
+            .dominates(control_flow.graph.entry(), header));
+        assert!(control_flow.unreachable.is_empty());
+
+        let blocks = cu.resources.get::<BasicBlock>();
+        let instructions = cu.resources.get::<Instruction>();
+        let dot = control_flow.graph.to_dot("main", &blocks, &instructions);
+        assert!(dot.contains("[label=\"true\"]"));
+    }
+
+    #[test]
+    fn code_after_a_return_is_unreachable() {
+        let src = "
+            PROGRAM main
+                VAR
+                    i: int;
+                END_VAR
+
+                RETURN;
+                i := 1;
+            END_PROGRAM";
+
+        let cu = analyse(src);
+        let control_flow = control_flow(&cu);
+
+        assert_eq!(control_flow.unreachable.len(), 1);
+    }
+}
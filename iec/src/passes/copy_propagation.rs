@@ -0,0 +1,287 @@
+use super::{Pass, PassContext};
+use crate::analysis::ssa::{self, Definition, SsaForm, SsaValue};
+use crate::analysis::{ControlFlow, ControlFlowCache, SsaCache};
+use crate::ecs::{
+    Container, EntityId, Read, ReadWrite, Singleton, SingletonMut,
+};
+use crate::hir::{BasicBlock, Instruction, Operand, Terminator, Variable};
+use typename::TypeName;
+
+/// Replace reads of a variable with the value it was copied from (e.g. `y`
+/// in `x := y; z := x + 1`), as long as that value hasn't changed in the
+/// meantime.
+#[derive(TypeName)]
+pub enum CopyPropagation {}
+
+impl<'r> Pass<'r> for CopyPropagation {
+    type Arg = ();
+    type Storage = (
+        Read<'r, Variable>,
+        ReadWrite<'r, Instruction>,
+        ReadWrite<'r, BasicBlock>,
+        Singleton<'r, ControlFlowCache>,
+        SingletonMut<'r, SsaCache>,
+    );
+    const DESCRIPTION: &'static str =
+        "Replace variables with the value they were copied from";
+    const REQUIRES: &'static [&'static str] = &["control-flow"];
+
+    fn run(_: &Self::Arg, ctx: &mut PassContext<'_>, storage: Self::Storage) {
+        let (variables, mut instructions, mut blocks, cache, mut ssa_cache) =
+            storage;
+        let tracked =
+            |v| variables.get(v).map(ssa::is_ssa_candidate).unwrap_or(false);
+
+        let mut items: Vec<_> = cache.iter().collect();
+        items.sort_by_key(|&(item, _)| EntityId::from(item));
+
+        for (item, control_flow) in items {
+            let mut replaced = 0;
+
+            // propagating one copy may expose another, so keep going until
+            // nothing changes
+            loop {
+                let ssa = SsaForm::construct(
+                    control_flow,
+                    &blocks,
+                    &instructions,
+                    tracked,
+                );
+                let mut propagator = Propagator {
+                    ssa: &ssa,
+                    instructions: &mut instructions,
+                    tracked: &tracked,
+                };
+                let changes = propagator.run(control_flow, &mut blocks);
+
+                if changes == 0 {
+                    break;
+                }
+                replaced += changes;
+            }
+
+            if replaced > 0 {
+                ssa_cache.invalidate(item);
+            }
+
+            slog::debug!(ctx.logger, "Propagated copies";
+                "item" => format_args!("{:?}", item),
+                "operands-replaced" => replaced);
+        }
+    }
+}
+
+struct Propagator<'a, F> {
+    ssa: &'a SsaForm,
+    instructions: &'a mut Container<Instruction>,
+    tracked: &'a F,
+}
+
+impl<'a, F: Fn(EntityId) -> bool> Propagator<'a, F> {
+    fn run(
+        &mut self,
+        control_flow: &ControlFlow,
+        blocks: &mut Container<BasicBlock>,
+    ) -> usize {
+        let mut changes = 0;
+
+        for block in control_flow.graph.reverse_postorder() {
+            let bb = blocks.get_mut(block).expect("all blocks exist");
+
+            for &id in &bb.instructions {
+                let mut instruction = self
+                    .instructions
+                    .get(id)
+                    .cloned()
+                    .expect("all instructions exist");
+
+                for operand in instruction.operands_mut() {
+                    let replacement = self.resolve(*operand, |var| {
+                        (
+                            self.ssa.use_of(id, var),
+                            self.ssa.version_before(block, bb, id, var),
+                        )
+                    });
+
+                    if let Some(replacement) = replacement {
+                        *operand = replacement;
+                        changes += 1;
+                    }
+                }
+
+                *self.instructions.get_mut(id).expect("exists") = instruction;
+            }
+
+            if let Terminator::Branch { condition, .. } = bb.terminator {
+                let replacement = self.resolve(condition, |var| {
+                    (
+                        self.ssa.terminator_use(block),
+                        self.ssa.version_after(block, bb, var),
+                    )
+                });
+
+                if let Some(replacement) = replacement {
+                    if let Terminator::Branch {
+                        ref mut condition, ..
+                    } = bb.terminator
+                    {
+                        *condition = replacement;
+                        changes += 1;
+                    }
+                }
+            }
+        }
+
+        changes
+    }
+
+    /// Follow a chain of copies back to the original value.
+    ///
+    /// `versions` gives the version of a variable which is read at the use
+    /// site, and the version which is visible there.
+    fn resolve<V>(&self, operand: Operand, versions: V) -> Option<Operand>
+    where
+        V: Fn(EntityId) -> (Option<SsaValue>, SsaValue),
+    {
+        let variable = operand.variable()?;
+        let mut current = versions(variable).0?;
+        let mut replacement = None;
+
+        while let Definition::Instruction(def) = self.ssa.definition(current) {
+            let src = match self.instructions.get(def) {
+                Some(&Instruction::Load { dest, src })
+                    if dest == current.variable =>
+                {
+                    src
+                }
+                _ => break,
+            };
+
+            match src {
+                Operand::Constant(_) => return Some(src),
+                Operand::Variable(original) => {
+                    if !(self.tracked)(original) {
+                        break;
+                    }
+                    // the copy may have been rewritten since the SSA form
+                    // was constructed
+                    let copied = match self.ssa.use_of(def, original) {
+                        Some(v) if v.variable == original => v,
+                        _ => break,
+                    };
+                    // and the original may have changed between the copy
+                    // and here
+                    if versions(original).1 != copied {
+                        break;
+                    }
+
+                    replacement = Some(src);
+                    current = copied;
+                }
+            }
+        }
+
+        replacement
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hir::CompilationUnit;
+    use crate::passes::{self, OptimizationLevel};
+    use crate::Diagnostics;
+
+    fn optimize(src: &str, level: OptimizationLevel) -> CompilationUnit {
+        let ast: iec_syntax::File = src.parse().unwrap();
+        let mut diags = Diagnostics::new();
+        let mut ctx = PassContext::new_nop_logger(&mut diags);
+        let mut cu = crate::process(&ast, &mut ctx);
+        passes::optimize(&mut cu, level, &mut ctx);
+        assert!(!diags.has_errors(), "{:?}", diags);
+
+        cu
+    }
+
+    fn variable(cu: &CompilationUnit, name: &str) -> EntityId {
+        let variables = cu.resources.get::<Variable>();
+        let id = variables
+            .iter()
+            .find(|(_, v)| v.name.as_deref() == Some(name))
+            .map(|(id, _)| id)
+            .unwrap();
+        id
+    }
+
+    fn reads_of(cu: &CompilationUnit, variable: EntityId) -> usize {
+        let blocks = cu.resources.get::<BasicBlock>();
+        let instructions = cu.resources.get::<Instruction>();
+
+        blocks
+            .iter()
+            .flat_map(|(_, bb)| bb.instructions.iter())
+            .filter(|&&id| {
+                instructions.get(id).unwrap().reads().contains(&variable)
+            })
+            .count()
+    }
+
+    #[test]
+    fn copies_are_propagated() {
+        let src = "
+            PROGRAM main
+                VAR
+                    a: int;
+                    b: int;
+                    c: int;
+                END_VAR
+
+                b := a;
+                c := b + 1;
+            END_PROGRAM";
+        let cu = optimize(src, OptimizationLevel::Basic);
+        let b = variable(&cu, "b");
+
+        // b is still assigned (programs keep their state between calls), but
+        // the addition now reads a directly
+        assert_eq!(reads_of(&cu, b), 0);
+        assert_eq!(reads_of(&cu, variable(&cu, "a")), 2);
+    }
+
+    #[test]
+    fn copies_arent_propagated_past_a_reassignment() {
+        let src = "
+            PROGRAM main
+                VAR
+                    a: int;
+                    b: int;
+                    c: int;
+                END_VAR
+
+                b := a;
+                a := 5;
+                c := b;
+            END_PROGRAM";
+        let cu = optimize(src, OptimizationLevel::Basic);
+
+        assert_eq!(reads_of(&cu, variable(&cu, "b")), 1);
+    }
+
+    #[test]
+    fn nothing_changes_without_optimisations() {
+        let src = "
+            PROGRAM main
+                VAR
+                    a: int;
+                    b: int;
+                    c: int;
+                END_VAR
+
+                b := a;
+                c := b + 1;
+            END_PROGRAM";
+        let cu = optimize(src, OptimizationLevel::None);
+
+        assert_eq!(reads_of(&cu, variable(&cu, "b")), 1);
+    }
+}
@@ -0,0 +1,516 @@
+use super::{Pass, PassContext};
+use crate::analysis::dataflow::{self, Analysis, Direction, Step};
+use crate::analysis::{ControlFlow, ControlFlowCache};
+use crate::ecs::{Container, EntityId, Join, Maybe, Read, Singleton};
+use crate::hir::{
+    Argument, BasicBlock, Instruction, Location, Span, Symbol, Variable,
+    VariableKind,
+};
+use crate::Diagnostics;
+use codespan_reporting::{Diagnostic, Label};
+use std::collections::{BTreeSet, HashSet};
+use typename::TypeName;
+
+#[derive(TypeName)]
+pub enum DataflowLints {}
+
+impl<'r> Pass<'r> for DataflowLints {
+    type Arg = ();
+    type Storage = (
+        Read<'r, Variable>,
+        Read<'r, BasicBlock>,
+        Read<'r, Instruction>,
+        Read<'r, Span>,
+        Read<'r, Location>,
+        Singleton<'r, ControlFlowCache>,
+    );
+    const DESCRIPTION: &'static str =
+        "Warn about variables which are used before being assigned, or never used at all";
+    const REQUIRES: &'static [&'static str] = &["control-flow"];
+
+    fn run(_: &Self::Arg, ctx: &mut PassContext<'_>, storage: Self::Storage) {
+        let (variables, blocks, instructions, spans, locations, cache) =
+            storage;
+
+        let mut items: Vec<_> = cache.iter().collect();
+        items.sort_by_key(|&(item, _)| EntityId::from(item));
+
+        for (item, control_flow) in items {
+            let cx = Context {
+                item,
+                control_flow,
+                variables: &variables,
+                blocks: &blocks,
+                instructions: &instructions,
+                spans: &spans,
+                locations: &locations,
+            };
+            let warnings_before = ctx.diags.len();
+
+            let never_read = unused_variables(&cx, ctx.diags);
+            definite_assignment(&cx, ctx.diags);
+            dead_stores(&cx, &never_read, ctx.diags);
+
+            slog::debug!(ctx.logger, "Checked variable usage";
+                "item" => format_args!("{:?}", item),
+                "warnings" => ctx.diags.len() - warnings_before);
+        }
+    }
+}
+
+/// Everything needed to check a single item.
+struct Context<'a> {
+    item: Symbol,
+    control_flow: &'a ControlFlow,
+    variables: &'a Container<Variable>,
+    blocks: &'a Container<BasicBlock>,
+    instructions: &'a Container<Instruction>,
+    spans: &'a Container<Span>,
+    locations: &'a Container<Location>,
+}
+
+impl<'a> Context<'a> {
+    fn variable(&self, id: EntityId) -> &'a Variable {
+        self.variables
+            .get(id)
+            .expect("all variables are registered")
+    }
+
+    /// The item's variables which match a predicate.
+    ///
+    /// Variables located at a direct address are skipped because they are
+    /// read and written by the outside world.
+    fn locals_where<P>(&self, predicate: P) -> BTreeSet<EntityId>
+    where
+        P: Fn(&Variable) -> bool,
+    {
+        (self.variables.children_of(self.item), Maybe(self.locations))
+            .join()
+            .filter(|(_, (v, location))| location.is_none() && predicate(v))
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    fn block(&self, id: EntityId) -> &'a BasicBlock {
+        self.blocks.get(id).expect("all blocks in the graph exist")
+    }
+
+    fn span(&self, id: EntityId) -> Option<Span> {
+        self.spans.get(id).cloned()
+    }
+
+    /// Label the declaration of a variable, if we know where it is.
+    fn declared_here(
+        &self,
+        diag: Diagnostic,
+        variable: EntityId,
+        message: &str,
+    ) -> Diagnostic {
+        match self.span(variable) {
+            Some(Span(span)) => diag
+                .with_label(Label::new_secondary(span).with_message(message)),
+            None => diag,
+        }
+    }
+
+    /// Visit every step in the item's reachable blocks, in the order they
+    /// appear, alongside the fact which holds just before that step.
+    fn visit<A, V>(&self, analysis: &A, mut visit: V)
+    where
+        A: Analysis,
+        V: FnMut(Step<'_>, &A::Fact),
+    {
+        let graph = &self.control_flow.graph;
+        let solution =
+            dataflow::solve(analysis, graph, self.blocks, self.instructions);
+
+        for block in graph.reverse_postorder() {
+            let mut fact = match A::DIRECTION {
+                Direction::Forward => solution.before(block),
+                Direction::Backward => solution.after(block),
+            }
+            .cloned()
+            .expect("every reachable block is solved");
+
+            dataflow::walk(
+                analysis,
+                self.block(block),
+                self.instructions,
+                &mut fact,
+                &mut visit,
+            );
+        }
+    }
+}
+
+/// Warn about local variables which are never used, or which are written to
+/// but never read, returning the variables which are never read.
+fn unused_variables(
+    cx: &Context<'_>,
+    diags: &mut Diagnostics,
+) -> HashSet<EntityId> {
+    let candidates = cx.locals_where(|v| {
+        v.name.is_some()
+            && (v.kind == VariableKind::Local || v.kind == VariableKind::Temp)
+    });
+
+    let mut reads = HashSet::new();
+    let mut writes = HashSet::new();
+
+    for &block in cx.control_flow.graph.blocks() {
+        let block = cx.block(block);
+
+        for &id in &block.instructions {
+            let instruction =
+                cx.instructions.get(id).expect("all instructions exist");
+            reads.extend(instruction.reads());
+            writes.extend(instruction.writes());
+        }
+        reads.extend(block.terminator.reads());
+    }
+
+    let mut never_read = HashSet::new();
+
+    for variable in candidates {
+        if reads.contains(&variable) {
+            continue;
+        }
+        never_read.insert(variable);
+
+        let message = if writes.contains(&variable) {
+            "Variable is written to but never read"
+        } else {
+            "Unused variable"
+        };
+
+        if let Some(Span(span)) = cx.span(variable) {
+            diags.push(
+                Diagnostic::new_warning(message)
+                    .with_label(Label::new_primary(span)),
+            );
+        }
+    }
+
+    never_read
+}
+
+/// Warn when `VAR_TEMP` variables or outputs may be read before anything has
+/// been assigned to them.
+fn definite_assignment(cx: &Context<'_>, diags: &mut Diagnostics) {
+    let tracked = cx.locals_where(|v| {
+        v.kind == VariableKind::Output || v.kind == VariableKind::Temp
+    });
+    if tracked.is_empty() {
+        return;
+    }
+
+    let analysis = DefiniteAssignment {
+        tracked: tracked.clone(),
+    };
+    let mut reported = HashSet::new();
+
+    cx.visit(&analysis, |step, assigned| {
+        let (reads, span) = match step {
+            Step::Instruction(id, instruction) => {
+                (instruction.reads(), cx.span(id))
+            }
+            Step::Terminator(terminator) => {
+                (terminator.reads().into_iter().collect(), None)
+            }
+        };
+
+        for variable in reads {
+            if !tracked.contains(&variable)
+                || assigned.contains(&variable)
+                || !reported.insert(variable)
+            {
+                continue;
+            }
+
+            let message = match cx.variable(variable).kind {
+                VariableKind::Output => {
+                    "Output may be read before it is assigned"
+                }
+                _ => "Variable may be read before it is assigned",
+            };
+            let mut diag = Diagnostic::new_warning(message);
+            if let Some(Span(span)) = span {
+                diag = diag.with_label(
+                    Label::new_primary(span).with_message("read here"),
+                );
+            }
+            diags.push(cx.declared_here(diag, variable, "declared here"));
+        }
+    });
+}
+
+/// A forward analysis tracking which variables have definitely been
+/// assigned to.
+struct DefiniteAssignment {
+    tracked: BTreeSet<EntityId>,
+}
+
+impl Analysis for DefiniteAssignment {
+    type Fact = BTreeSet<EntityId>;
+    const DIRECTION: Direction = Direction::Forward;
+
+    fn boundary(&self) -> Self::Fact {
+        BTreeSet::new()
+    }
+
+    fn initial(&self) -> Self::Fact {
+        self.tracked.clone()
+    }
+
+    fn join(&self, fact: &mut Self::Fact, other: &Self::Fact) {
+        fact.retain(|variable| other.contains(variable));
+    }
+
+    fn transfer(&self, step: Step<'_>, fact: &mut Self::Fact) {
+        if let Step::Instruction(_, instruction) = step {
+            fact.extend(
+                instruction
+                    .writes()
+                    .into_iter()
+                    .filter(|variable| self.tracked.contains(variable)),
+            );
+        }
+    }
+}
+
+/// Warn about assignments which are always overwritten (or go out of scope)
+/// before being read.
+///
+/// Only variables which are discarded when the item returns are checked, the
+/// values of other variables may be read by the caller or during the next
+/// call.
+fn dead_stores(
+    cx: &Context<'_>,
+    never_read: &HashSet<EntityId>,
+    diags: &mut Diagnostics,
+) {
+    let is_function = matches!(cx.item, Symbol::Function(_));
+    let tracked = cx.locals_where(|v| {
+        v.name.is_some()
+            && (v.kind == VariableKind::Temp
+                || (is_function && v.kind == VariableKind::Local))
+    });
+    if tracked.is_empty() {
+        return;
+    }
+
+    let analysis = Liveness;
+
+    cx.visit(&analysis, |step, live| {
+        let (id, instruction) = match step {
+            Step::Instruction(id, instruction) => (id, instruction),
+            Step::Terminator(_) => return,
+        };
+
+        for variable in overwritten(instruction) {
+            if tracked.contains(&variable)
+                && !live.contains(&variable)
+                && !never_read.contains(&variable)
+            {
+                let mut diag =
+                    Diagnostic::new_warning("Value assigned is never read");
+                if let Some(Span(span)) = cx.span(id) {
+                    diag = diag.with_label(Label::new_primary(span));
+                }
+                diags.push(cx.declared_here(diag, variable, "declared here"));
+            }
+        }
+    });
+}
+
+/// The variables an instruction completely replaces the value of.
+fn overwritten(instruction: &Instruction) -> Vec<EntityId> {
+    match *instruction {
+        Instruction::Load { dest, .. }
+        | Instruction::LoadMember { dest, .. }
+        | Instruction::Binary { dest, .. }
+        | Instruction::Unary { dest, .. } => vec![dest],
+        Instruction::StoreMember { .. } => Vec::new(),
+        Instruction::Call { dest, ref args, .. } => dest
+            .into_iter()
+            .chain(args.iter().filter_map(|arg| match *arg {
+                Argument::Output { variable, .. } => Some(variable),
+                Argument::Input { .. } | Argument::InOut { .. } => None,
+            }))
+            .collect(),
+    }
+}
+
+/// A backward analysis tracking which variables may be read before they are
+/// next written to.
+struct Liveness;
+
+impl Analysis for Liveness {
+    type Fact = BTreeSet<EntityId>;
+    const DIRECTION: Direction = Direction::Backward;
+
+    fn boundary(&self) -> Self::Fact {
+        BTreeSet::new()
+    }
+
+    fn initial(&self) -> Self::Fact {
+        BTreeSet::new()
+    }
+
+    fn join(&self, fact: &mut Self::Fact, other: &Self::Fact) {
+        fact.extend(other);
+    }
+
+    fn transfer(&self, step: Step<'_>, fact: &mut Self::Fact) {
+        match step {
+            Step::Instruction(_, instruction) => {
+                for variable in overwritten(instruction) {
+                    fact.remove(&variable);
+                }
+                fact.extend(instruction.reads());
+            }
+            Step::Terminator(terminator) => fact.extend(terminator.reads()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lint(src: &str) -> Vec<String> {
+        let ast: iec_syntax::File = src.parse().unwrap();
+        let mut diags = Diagnostics::new();
+        crate::process(&ast, &mut PassContext::new_nop_logger(&mut diags));
+        assert!(!diags.has_errors(), "{:?}", diags);
+
+        diags
+            .diagnostics()
+            .iter()
+            .map(|d| d.message.clone())
+            .collect()
+    }
+
+    #[test]
+    fn outputs_read_before_being_written() {
+        let src = "
+            FUNCTION_BLOCK counter
+                VAR_OUTPUT
+                    count: int;
+                END_VAR
+            BEGIN
+                count := count + 1;
+            END_FUNCTION_BLOCK";
+
+        let warnings = lint(src);
+
+        assert_eq!(warnings, vec!["Output may be read before it is assigned"]);
+    }
+
+    #[test]
+    fn assignment_on_every_path_is_fine() {
+        let src = "
+            FUNCTION_BLOCK toggle
+                VAR_INPUT
+                    input: bool;
+                END_VAR
+                VAR_OUTPUT
+                    output: bool;
+                END_VAR
+                VAR_TEMP
+                    temp: bool;
+                END_VAR
+            BEGIN
+                temp := input;
+                output := temp;
+                IF output THEN
+                    output := NOT output;
+                END_IF;
+            END_FUNCTION_BLOCK";
+
+        assert!(lint(src).is_empty());
+    }
+
+    #[test]
+    fn assignment_on_only_one_path() {
+        let src = "
+            FUNCTION_BLOCK maybe
+                VAR_INPUT
+                    input: bool;
+                END_VAR
+                VAR_TEMP
+                    temp: bool;
+                END_VAR
+                VAR_OUTPUT
+                    output: bool;
+                END_VAR
+            BEGIN
+                IF input THEN
+                    temp := TRUE;
+                END_IF;
+                output := temp;
+            END_FUNCTION_BLOCK";
+
+        let warnings = lint(src);
+
+        assert_eq!(
+            warnings,
+            vec!["Variable may be read before it is assigned"]
+        );
+    }
+
+    #[test]
+    fn unused_and_write_only_variables() {
+        let src = "
+            PROGRAM main
+                VAR
+                    unused: int;
+                    written: int;
+                END_VAR
+
+                written := 5;
+            END_PROGRAM";
+
+        let warnings = lint(src);
+
+        assert_eq!(
+            warnings,
+            vec!["Unused variable", "Variable is written to but never read"]
+        );
+    }
+
+    #[test]
+    fn overwritten_values_are_dead_stores() {
+        let src = "
+            FUNCTION double : int
+            BEGIN
+                VAR_INPUT
+                    x: int;
+                END_VAR
+                VAR
+                    temp: int;
+                END_VAR
+                temp := 1;
+                temp := x * 2;
+                double := temp;
+            END_FUNCTION";
+
+        let warnings = lint(src);
+
+        assert_eq!(warnings, vec!["Value assigned is never read"]);
+    }
+
+    #[test]
+    fn program_state_survives_between_calls() {
+        let src = "
+            PROGRAM main
+                VAR
+                    count: int;
+                END_VAR
+
+                count := count + 1;
+            END_PROGRAM";
+
+        assert!(lint(src).is_empty());
+    }
+}
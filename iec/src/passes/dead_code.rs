@@ -0,0 +1,236 @@
+use super::{Pass, PassContext};
+use crate::analysis::ssa::{self, SsaForm};
+use crate::analysis::{ControlFlowCache, SsaCache};
+use crate::ecs::{
+    Container, Entities, EntityId, Read, ReadWrite, Singleton, SingletonMut,
+};
+use crate::hir::{BasicBlock, Instruction, Symbol, Variable, VariableKind};
+use typename::TypeName;
+
+/// Remove instructions which calculate a value nobody will ever read.
+#[derive(TypeName)]
+pub enum DeadCodeElimination {}
+
+impl<'r> Pass<'r> for DeadCodeElimination {
+    type Arg = ();
+    type Storage = (
+        Entities<'r>,
+        Read<'r, Variable>,
+        Read<'r, Instruction>,
+        ReadWrite<'r, BasicBlock>,
+        Singleton<'r, ControlFlowCache>,
+        SingletonMut<'r, SsaCache>,
+    );
+    const DESCRIPTION: &'static str =
+        "Remove instructions whose results are never used";
+    const REQUIRES: &'static [&'static str] = &["control-flow"];
+
+    fn run(_: &Self::Arg, ctx: &mut PassContext<'_>, storage: Self::Storage) {
+        let (
+            entities,
+            variables,
+            instructions,
+            mut blocks,
+            cache,
+            mut ssa_cache,
+        ) = storage;
+        let tracked =
+            |v| variables.get(v).map(ssa::is_ssa_candidate).unwrap_or(false);
+
+        let mut items: Vec<_> = cache.iter().collect();
+        items.sort_by_key(|&(item, _)| EntityId::from(item));
+
+        for (item, control_flow) in items {
+            let mut removed = 0;
+
+            // removing an instruction may make the instructions it reads
+            // from dead too
+            loop {
+                let ssa = SsaForm::construct(
+                    control_flow,
+                    &blocks,
+                    &instructions,
+                    tracked,
+                );
+                let used = ssa.used_values();
+                let mut changes = 0;
+
+                for block in control_flow.graph.reverse_postorder() {
+                    let bb = blocks.get_mut(block).expect("all blocks exist");
+                    let before = bb.instructions.len();
+
+                    bb.instructions.retain(|&id| {
+                        let instruction =
+                            instructions.get(id).expect("all exist");
+                        let defs = ssa.defs(id);
+
+                        let is_dead = is_pure(instruction)
+                            && defs.len() == instruction.writes().len()
+                            && defs.iter().all(|value| {
+                                !used.contains(value)
+                                    && is_discarded(
+                                        item,
+                                        &variables,
+                                        value.variable,
+                                    )
+                            });
+
+                        if is_dead {
+                            entities.delete(id);
+                        }
+
+                        !is_dead
+                    });
+
+                    changes += before - bb.instructions.len();
+                }
+
+                if changes == 0 {
+                    break;
+                }
+                removed += changes;
+            }
+
+            if removed > 0 {
+                ssa_cache.invalidate(item);
+            }
+
+            slog::debug!(ctx.logger, "Removed dead code";
+                "item" => format_args!("{:?}", item),
+                "instructions-removed" => removed);
+        }
+    }
+}
+
+/// Does executing this instruction do anything other than assigning to its
+/// destination?
+fn is_pure(instruction: &Instruction) -> bool {
+    match *instruction {
+        Instruction::Load { .. }
+        | Instruction::LoadMember { .. }
+        | Instruction::Binary { .. }
+        | Instruction::Unary { .. } => true,
+        Instruction::StoreMember { .. } | Instruction::Call { .. } => false,
+    }
+}
+
+/// Is a variable's value thrown away when the item returns?
+fn is_discarded(
+    item: Symbol,
+    variables: &Container<Variable>,
+    variable: EntityId,
+) -> bool {
+    let kind = match variables.get(variable) {
+        Some(v) => v.kind,
+        None => return false,
+    };
+
+    match kind {
+        VariableKind::Temporary | VariableKind::Temp => true,
+        // functions don't keep their state between calls, but programs and
+        // function blocks do
+        VariableKind::Local | VariableKind::Input => {
+            matches!(item, Symbol::Function(_))
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hir::CompilationUnit;
+    use crate::passes::{self, OptimizationLevel};
+    use crate::Diagnostics;
+
+    fn optimize(src: &str) -> CompilationUnit {
+        let ast: iec_syntax::File = src.parse().unwrap();
+        let mut diags = Diagnostics::new();
+        let mut ctx = PassContext::new_nop_logger(&mut diags);
+        let mut cu = crate::process(&ast, &mut ctx);
+        passes::optimize(&mut cu, OptimizationLevel::Basic, &mut ctx);
+
+        cu
+    }
+
+    fn instruction_count(cu: &CompilationUnit) -> usize {
+        let blocks = cu.resources.get::<BasicBlock>();
+        let in_blocks =
+            blocks.iter().map(|(_, bb)| bb.instructions.len()).sum();
+
+        // dead instructions should be deleted, not just detached
+        assert_eq!(cu.resources.get::<Instruction>().len(), in_blocks);
+
+        in_blocks
+    }
+
+    #[test]
+    fn unused_function_locals_are_removed() {
+        let src = "
+            FUNCTION add_one : int
+            BEGIN
+                VAR_INPUT
+                    x: int;
+                END_VAR
+                VAR
+                    unused: int;
+                END_VAR
+
+                unused := x * 2;
+                add_one := x + 1;
+            END_FUNCTION";
+
+        let cu = optimize(src);
+
+        // just the addition and the assignment to the return value
+        assert_eq!(instruction_count(&cu), 2);
+    }
+
+    #[test]
+    fn program_state_is_kept() {
+        let src = "
+            PROGRAM main
+                VAR
+                    counter: int;
+                END_VAR
+
+                counter := counter + 1;
+            END_PROGRAM";
+
+        let cu = optimize(src);
+
+        assert_eq!(instruction_count(&cu), 2);
+    }
+
+    #[test]
+    fn calls_are_never_removed() {
+        let src = "
+            FUNCTION noop : int
+            BEGIN
+                noop := 1;
+            END_FUNCTION
+
+            FUNCTION caller : int
+            BEGIN
+                VAR
+                    unused: int;
+                END_VAR
+
+                unused := noop();
+                caller := 2;
+            END_FUNCTION";
+
+        let cu = optimize(src);
+        let blocks = cu.resources.get::<BasicBlock>();
+        let instructions = cu.resources.get::<Instruction>();
+
+        let calls = blocks
+            .iter()
+            .flat_map(|(_, bb)| bb.instructions.iter())
+            .filter(|&&id| {
+                matches!(instructions.get(id), Some(Instruction::Call { .. }))
+            })
+            .count();
+        assert_eq!(calls, 1);
+    }
+}
@@ -0,0 +1,325 @@
+use super::{Pass, PassContext};
+use crate::analysis::ssa::{self, Definition, SsaForm};
+use crate::analysis::{ControlFlow, ControlFlowCache, Loop, SsaCache};
+use crate::ecs::{
+    Container, EntityId, Read, ReadWrite, Singleton, SingletonMut,
+};
+use crate::hir::{
+    BasicBlock, BinaryOp, Instruction, Terminator, Variable, VariableKind,
+};
+use std::collections::HashMap;
+use typename::TypeName;
+
+/// Move calculations which give the same result on every iteration of a
+/// loop to just before the loop starts.
+#[derive(TypeName)]
+pub enum LoopInvariantCodeMotion {}
+
+impl<'r> Pass<'r> for LoopInvariantCodeMotion {
+    type Arg = ();
+    type Storage = (
+        Read<'r, Variable>,
+        Read<'r, Instruction>,
+        ReadWrite<'r, BasicBlock>,
+        Singleton<'r, ControlFlowCache>,
+        SingletonMut<'r, SsaCache>,
+    );
+    const DESCRIPTION: &'static str =
+        "Hoist loop-invariant calculations out of their loop";
+    const REQUIRES: &'static [&'static str] = &["control-flow"];
+
+    fn run(_: &Self::Arg, ctx: &mut PassContext<'_>, storage: Self::Storage) {
+        let (variables, instructions, mut blocks, cache, mut ssa_cache) =
+            storage;
+        let tracked =
+            |v| variables.get(v).map(ssa::is_ssa_candidate).unwrap_or(false);
+
+        let mut items: Vec<_> = cache.iter().collect();
+        items.sort_by_key(|&(item, _)| EntityId::from(item));
+
+        for (item, control_flow) in items {
+            // visit inner loops first so their invariants can bubble up
+            // through the outer loops
+            let mut loops: Vec<_> = control_flow.loops.iter().collect();
+            loops.sort_by_key(|l| l.body.len());
+
+            let mut hoisted = 0;
+
+            loop {
+                let ssa = SsaForm::construct(
+                    control_flow,
+                    &blocks,
+                    &instructions,
+                    tracked,
+                );
+                let cx = Context {
+                    ssa: &ssa,
+                    variables: &variables,
+                    instructions: &instructions,
+                    blocks: &blocks,
+                    locations: locations(control_flow, &blocks),
+                };
+
+                let moved = loops.iter().find_map(|l| {
+                    let preheader = preheader(control_flow, &blocks, l)?;
+                    let invariants = cx.invariants(l);
+
+                    if invariants.is_empty() {
+                        None
+                    } else {
+                        Some((preheader, invariants))
+                    }
+                });
+
+                let (preheader, invariants) = match moved {
+                    Some(m) => m,
+                    None => break,
+                };
+
+                for &(block, id) in &invariants {
+                    blocks
+                        .get_mut(block)
+                        .expect("all blocks exist")
+                        .instructions
+                        .retain(|&i| i != id);
+                }
+                blocks
+                    .get_mut(preheader)
+                    .expect("all blocks exist")
+                    .instructions
+                    .extend(invariants.iter().map(|&(_, id)| id));
+
+                hoisted += invariants.len();
+            }
+
+            if hoisted > 0 {
+                ssa_cache.invalidate(item);
+            }
+
+            slog::debug!(ctx.logger, "Hoisted loop invariants";
+                "item" => format_args!("{:?}", item),
+                "instructions-hoisted" => hoisted);
+        }
+    }
+}
+
+/// The block control always comes from when entering a loop, if there is
+/// exactly one and it only jumps to the loop's header.
+///
+/// Because we never add new blocks, loops without an existing preheader are
+/// left alone.
+fn preheader(
+    control_flow: &ControlFlow,
+    blocks: &Container<BasicBlock>,
+    l: &Loop,
+) -> Option<EntityId> {
+    let mut outside = control_flow
+        .graph
+        .predecessors(l.header)
+        .iter()
+        .filter(|&&pred| !l.contains(pred));
+
+    let candidate = *outside.next()?;
+    if outside.next().is_some() {
+        return None;
+    }
+
+    match blocks.get(candidate)?.terminator {
+        Terminator::Jump(target) if target == l.header => Some(candidate),
+        _ => None,
+    }
+}
+
+/// The block each reachable instruction is in.
+fn locations(
+    control_flow: &ControlFlow,
+    blocks: &Container<BasicBlock>,
+) -> HashMap<EntityId, EntityId> {
+    control_flow
+        .graph
+        .reverse_postorder()
+        .into_iter()
+        .flat_map(|block| {
+            let bb = blocks.get(block).expect("all blocks exist");
+            bb.instructions.iter().map(move |&id| (id, block))
+        })
+        .collect()
+}
+
+struct Context<'a> {
+    ssa: &'a SsaForm,
+    variables: &'a Container<Variable>,
+    instructions: &'a Container<Instruction>,
+    blocks: &'a Container<BasicBlock>,
+    locations: HashMap<EntityId, EntityId>,
+}
+
+impl<'a> Context<'a> {
+    /// The instructions in a loop which can be safely hoisted, and the block
+    /// they're currently in.
+    fn invariants(&self, l: &Loop) -> Vec<(EntityId, EntityId)> {
+        let mut found = Vec::new();
+
+        for &block in &l.body {
+            let bb = self.blocks.get(block).expect("all blocks exist");
+
+            for &id in &bb.instructions {
+                if self.is_invariant(l, id) {
+                    found.push((block, id));
+                }
+            }
+        }
+
+        found
+    }
+
+    fn is_invariant(&self, l: &Loop, id: EntityId) -> bool {
+        let instruction =
+            self.instructions.get(id).expect("all instructions exist");
+
+        let dest = match *instruction {
+            Instruction::Binary { dest, op, .. } if !may_trap(op) => dest,
+            Instruction::Unary { dest, .. }
+            | Instruction::Load { dest, .. } => dest,
+            _ => return false,
+        };
+
+        // a temporary is only assigned once, so moving it can't clobber an
+        // assignment made somewhere else
+        let is_temporary = self
+            .variables
+            .get(dest)
+            .map(|v| v.kind == VariableKind::Temporary)
+            .unwrap_or(false);
+        if !is_temporary {
+            return false;
+        }
+
+        // every variable we read must be tracked (so we know where it was
+        // assigned) and assigned outside the loop
+        let reads = instruction.reads();
+        let uses = self.ssa.uses(id);
+
+        reads.len() == uses.len()
+            && uses.iter().all(|&value| match self.ssa.definition(value) {
+                Definition::Entry => true,
+                Definition::Phi(block) => !l.contains(block),
+                Definition::Instruction(def) => self
+                    .locations
+                    .get(&def)
+                    .map(|&block| !l.contains(block))
+                    .unwrap_or(false),
+            })
+    }
+}
+
+/// Hoisting an operation means it may be executed when it wouldn't have
+/// been before, which isn't okay if it can fail (e.g. dividing by zero).
+fn may_trap(op: BinaryOp) -> bool {
+    matches!(op, BinaryOp::Divide | BinaryOp::Modulo | BinaryOp::Exponent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hir::CompilationUnit;
+    use crate::passes::{self, OptimizationLevel};
+    use crate::Diagnostics;
+
+    fn optimize(src: &str) -> CompilationUnit {
+        let ast: iec_syntax::File = src.parse().unwrap();
+        let mut diags = Diagnostics::new();
+        let mut ctx = PassContext::new_nop_logger(&mut diags);
+        let mut cu = crate::process(&ast, &mut ctx);
+        passes::optimize(&mut cu, OptimizationLevel::Full, &mut ctx);
+        assert!(!diags.has_errors(), "{:?}", diags);
+
+        cu
+    }
+
+    /// How many binary operations using `op` are inside the item's loop?
+    fn in_loop(cu: &CompilationUnit, op: BinaryOp) -> usize {
+        let cache = cu.resources.get_singleton::<ControlFlowCache>();
+        let (_, control_flow) = cache.iter().next().unwrap();
+        let blocks = cu.resources.get::<BasicBlock>();
+        let instructions = cu.resources.get::<Instruction>();
+
+        control_flow.loops[0]
+            .body
+            .iter()
+            .flat_map(|&b| blocks.get(b).unwrap().instructions.iter())
+            .filter(|&&id| match instructions.get(id) {
+                Some(&Instruction::Binary { op: other, .. }) => other == op,
+                _ => false,
+            })
+            .count()
+    }
+
+    #[test]
+    fn invariant_calculations_are_hoisted() {
+        let src = "
+            PROGRAM main
+                VAR
+                    a: int;
+                    b: int;
+                    i: int;
+                    total: int;
+                END_VAR
+
+                WHILE i < 10 DO
+                    total := total + a * b;
+                    i := i + 1;
+                END_WHILE;
+            END_PROGRAM";
+
+        let cu = optimize(src);
+
+        assert_eq!(in_loop(&cu, BinaryOp::Multiply), 0);
+        // the counter and total change every iteration
+        assert_eq!(in_loop(&cu, BinaryOp::Add), 2);
+    }
+
+    #[test]
+    fn operations_which_may_fail_stay_in_the_loop() {
+        let src = "
+            PROGRAM main
+                VAR
+                    a: int;
+                    b: int;
+                    i: int;
+                    total: int;
+                END_VAR
+
+                WHILE i < 10 DO
+                    total := total + a / b;
+                    i := i + 1;
+                END_WHILE;
+            END_PROGRAM";
+
+        let cu = optimize(src);
+
+        assert_eq!(in_loop(&cu, BinaryOp::Divide), 1);
+    }
+
+    #[test]
+    fn the_step_direction_is_only_checked_once() {
+        let src = "
+            PROGRAM main
+                VAR
+                    i: int;
+                    step: int;
+                    total: int;
+                END_VAR
+
+                FOR i := 0 TO 10 BY step DO
+                    total := total + i;
+                END_FOR;
+            END_PROGRAM";
+
+        let cu = optimize(src);
+
+        // "step >= 0" is hoisted, but "i >= 10" has to be checked on every
+        // iteration
+        assert_eq!(in_loop(&cu, BinaryOp::GreaterThanOrEqual), 1);
+    }
+}
@@ -0,0 +1,878 @@
+//! Running a sequence of [`Pass`]es in the right order.
+//!
+//! Each pass declares the things it [`Pass::REQUIRES`] and
+//! [`Pass::PROVIDES`]. The [`PassManager`] uses these to make sure a pass
+//! only runs after everything it depends on, and to figure out which passes
+//! can't run when another pass is skipped.
+//!
+//! Neighbouring passes which don't depend on each other and whose storage
+//! doesn't conflict (e.g. two passes which only read the same components)
+//! are grouped into a stage and executed in parallel, in the style of the
+//! `specs` dispatcher.
+
+use super::{execute_pass, Pass, PassContext};
+use crate::ecs::{Accesses, FromResources, Resources};
+use crate::Diagnostics;
+use heapsize::HeapSizeOf;
+use slog::Logger;
+use std::collections::{BTreeSet, HashSet};
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+
+/// A pipeline of passes which are all given the same input.
+pub struct PassManager<I: ?Sized> {
+    entries: Vec<Entry<I>>,
+    /// Things provided by a previous pipeline.
+    assumed: Vec<&'static str>,
+}
+
+struct Entry<I: ?Sized> {
+    name: &'static str,
+    description: &'static str,
+    requires: &'static [&'static str],
+    provides: &'static [&'static str],
+    accesses: Accesses,
+    register: fn(&mut Resources),
+    execute: fn(&Resources, &I, &mut PassContext<'_>) -> Duration,
+}
+
+impl<I: ?Sized> Entry<I> {
+    /// Can these two passes be executed at the same time?
+    fn is_independent_of(&self, other: &Entry<I>) -> bool {
+        let depends_on = |later: &Entry<I>, earlier: &Entry<I>| {
+            later.requires.iter().any(|r| earlier.provides.contains(r))
+        };
+
+        !depends_on(self, other)
+            && !depends_on(other, self)
+            && !self.accesses.conflicts_with(&other.accesses)
+    }
+}
+
+impl<I: ?Sized> PassManager<I> {
+    pub fn new() -> PassManager<I> {
+        PassManager {
+            entries: Vec::new(),
+            assumed: Vec::new(),
+        }
+    }
+
+    /// Add a pass which doesn't need the pipeline's input.
+    pub fn add<P>(self) -> PassManager<I>
+    where
+        P: for<'r> Pass<'r, Arg = ()>,
+    {
+        self.add_entry::<P>(execute_without_input::<P, I>)
+    }
+
+    /// Add a pass which is given the pipeline's input.
+    pub fn add_with_input<P>(self) -> PassManager<I>
+    where
+        P: for<'r> Pass<'r, Arg = I>,
+    {
+        self.add_entry::<P>(execute_with_input::<P, I>)
+    }
+
+    fn add_entry<P>(
+        mut self,
+        execute: fn(&Resources, &I, &mut PassContext<'_>) -> Duration,
+    ) -> PassManager<I>
+    where
+        P: for<'r> Pass<'r>,
+    {
+        self.entries.push(Entry {
+            name: short_name::<P>(),
+            description: <P as Pass<'static>>::DESCRIPTION,
+            requires: <P as Pass<'static>>::REQUIRES,
+            provides: <P as Pass<'static>>::PROVIDES,
+            accesses: Accesses::of::<<P as Pass<'static>>::Storage>(),
+            register: <P as Pass<'static>>::Storage::ensure_registered,
+            execute,
+        });
+        self
+    }
+
+    /// Declare that something was already provided by an earlier pipeline.
+    pub fn assume_provided(mut self, thing: &'static str) -> PassManager<I> {
+        self.assumed.push(thing);
+        self
+    }
+
+    /// The name of every pass in this pipeline, in the order they'll run.
+    pub fn names(&self) -> Result<Vec<&'static str>, PipelineError> {
+        Ok(self
+            .schedule()?
+            .into_iter()
+            .map(|ix| self.entries[ix].name)
+            .collect())
+    }
+
+    /// Sort the passes so each one comes after the passes providing the
+    /// things it requires. Passes which don't depend on each other run in
+    /// the order they were added.
+    fn schedule(&self) -> Result<Vec<usize>, PipelineError> {
+        for (ix, entry) in self.entries.iter().enumerate() {
+            // pass names are used by --skip-pass and --stop-after
+            if self.entries[..ix].iter().any(|e| e.name == entry.name) {
+                return Err(PipelineError::DuplicatePass(entry.name));
+            }
+        }
+
+        for entry in &self.entries {
+            for &thing in entry.requires {
+                let provided = self.assumed.contains(&thing)
+                    || self.entries.iter().any(|e| e.provides.contains(&thing));
+
+                if !provided {
+                    return Err(PipelineError::Unsatisfied {
+                        pass: entry.name,
+                        requirement: thing,
+                    });
+                }
+            }
+        }
+
+        let depends_on = |later: &Entry<I>, earlier: &Entry<I>| {
+            later.requires.iter().any(|r| earlier.provides.contains(r))
+        };
+
+        let mut pending: BTreeSet<usize> = (0..self.entries.len()).collect();
+        let mut order = Vec::new();
+
+        while !pending.is_empty() {
+            let next = pending.iter().cloned().find(|&candidate| {
+                pending.iter().all(|&other| {
+                    other == candidate
+                        || !depends_on(
+                            &self.entries[candidate],
+                            &self.entries[other],
+                        )
+                })
+            });
+
+            match next {
+                Some(ix) => {
+                    pending.remove(&ix);
+                    order.push(ix);
+                }
+                None => {
+                    let passes =
+                        pending.iter().map(|&ix| self.entries[ix].name);
+                    return Err(PipelineError::Cycle(passes.collect()));
+                }
+            }
+        }
+
+        Ok(order)
+    }
+}
+
+impl<I: ?Sized> PassManager<I> {
+    /// Split the selected passes into groups which can be executed in
+    /// parallel, keeping them in order.
+    fn stages(&self, selected: &[usize]) -> Vec<Vec<usize>> {
+        let mut stages: Vec<Vec<usize>> = Vec::new();
+
+        for &ix in selected {
+            let entry = &self.entries[ix];
+
+            match stages.last_mut() {
+                Some(stage)
+                    if stage.iter().all(|&other| {
+                        entry.is_independent_of(&self.entries[other])
+                    }) =>
+                {
+                    stage.push(ix)
+                }
+                _ => stages.push(vec![ix]),
+            }
+        }
+
+        stages
+    }
+}
+
+impl<I: ?Sized + Sync> PassManager<I> {
+    /// Run every pass in the pipeline, stopping early if a pass reports an
+    /// error.
+    pub fn run(
+        &self,
+        resources: &mut Resources,
+        input: &I,
+        options: &PassOptions,
+        ctx: &mut PassContext<'_>,
+    ) -> Result<Report, PipelineError> {
+        let mut report = Report::default();
+        let mut unavailable = HashSet::new();
+        let mut selected = Vec::new();
+
+        for ix in self.schedule()? {
+            let entry = &self.entries[ix];
+
+            let missing = entry
+                .requires
+                .iter()
+                .find(|thing| unavailable.contains(*thing));
+
+            if options.skips(entry.name) || missing.is_some() {
+                if let Some(thing) = missing {
+                    slog::debug!(ctx.logger, "Skipping pass";
+                        "pass" => entry.name,
+                        "missing" => *thing);
+                }
+                unavailable.extend(entry.provides.iter().cloned());
+                report.skipped.push(entry.name);
+                continue;
+            }
+
+            selected.push(ix);
+
+            if options.stops_after(entry.name) {
+                break;
+            }
+        }
+
+        for stage in self.stages(&selected) {
+            let errors =
+                self.run_stage(&stage, resources, input, ctx, &mut report);
+
+            if let Some(pass) = errors {
+                report.stopped = Some(Stop::Errors(pass));
+                break;
+            }
+
+            let requested = stage
+                .iter()
+                .map(|&ix| self.entries[ix].name)
+                .find(|name| options.stops_after(name));
+            if let Some(pass) = requested {
+                report.stopped = Some(Stop::Requested(pass));
+                break;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Run a group of independent passes, returning the first one which
+    /// reported an error.
+    ///
+    /// Errors which were already in `ctx.diags` are ignored, they don't mean
+    /// this stage failed.
+    fn run_stage(
+        &self,
+        stage: &[usize],
+        resources: &mut Resources,
+        input: &I,
+        ctx: &mut PassContext<'_>,
+        report: &mut Report,
+    ) -> Option<&'static str> {
+        for &ix in stage {
+            (self.entries[ix].register)(resources);
+        }
+
+        let stage_index = report.passes.last().map_or(0, |p| p.stage + 1);
+        let memory_before = resources.heap_size_of_children();
+        let shared: &Resources = resources;
+        let results: Vec<(Duration, Diagnostics)> = if stage.len() == 1 {
+            vec![self.execute(stage[0], shared, input, &ctx.logger)]
+        } else {
+            std::thread::scope(|scope| {
+                let handles: Vec<_> = stage
+                    .iter()
+                    .map(|&ix| {
+                        let logger = ctx.logger.clone();
+                        scope.spawn(move || {
+                            self.execute(ix, shared, input, &logger)
+                        })
+                    })
+                    .collect();
+
+                handles
+                    .into_iter()
+                    .map(|handle| match handle.join() {
+                        Ok(result) => result,
+                        Err(panic) => std::panic::resume_unwind(panic),
+                    })
+                    .collect()
+            })
+        };
+
+        resources.maintain();
+        let memory_after = resources.heap_size_of_children();
+
+        let mut culprit = None;
+
+        for (&ix, (duration, diags)) in stage.iter().zip(results) {
+            let entry = &self.entries[ix];
+
+            if culprit.is_none() && diags.has_errors() {
+                culprit = Some(entry.name);
+            }
+            ctx.diags.extend(diags);
+
+            report.passes.push(PassMetrics {
+                name: entry.name,
+                duration,
+                stage: stage_index,
+                memory_before,
+                memory_after,
+            });
+        }
+
+        culprit
+    }
+
+    /// Execute a single pass, collecting its diagnostics separately so they
+    /// can be merged back in a deterministic order.
+    fn execute(
+        &self,
+        ix: usize,
+        resources: &Resources,
+        input: &I,
+        logger: &Logger,
+    ) -> (Duration, Diagnostics) {
+        let entry = &self.entries[ix];
+        let mut diags = Diagnostics::new();
+        let mut ctx = PassContext {
+            diags: &mut diags,
+            logger: logger.new(slog::o!("pass" => entry.name)),
+        };
+
+        slog::debug!(ctx.logger, "Pass started";
+            "description" => entry.description);
+        let duration = (entry.execute)(resources, input, &mut ctx);
+        slog::debug!(ctx.logger, "Pass complete";
+            "execution-time" => format_args!("{}.{:06}s", duration.as_secs(), duration.subsec_micros()));
+
+        (duration, diags)
+    }
+}
+
+impl<I: ?Sized> Default for PassManager<I> {
+    fn default() -> PassManager<I> {
+        PassManager::new()
+    }
+}
+
+fn execute_with_input<P, I>(
+    r: &Resources,
+    input: &I,
+    ctx: &mut PassContext<'_>,
+) -> Duration
+where
+    P: for<'r> Pass<'r, Arg = I>,
+    I: ?Sized,
+{
+    execute_pass::<P>(r, input, ctx)
+}
+
+fn execute_without_input<P, I>(
+    r: &Resources,
+    _: &I,
+    ctx: &mut PassContext<'_>,
+) -> Duration
+where
+    P: for<'r> Pass<'r, Arg = ()>,
+    I: ?Sized,
+{
+    execute_pass::<P>(r, &(), ctx)
+}
+
+/// The pass's type name without the leading module path (e.g.
+/// `BasicBlocks`).
+pub(crate) fn short_name<P>() -> &'static str {
+    let full = std::any::type_name::<P>();
+    full.rsplit("::").next().unwrap_or(full)
+}
+
+/// Which passes the user wants to run.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PassOptions {
+    /// Passes which shouldn't be executed. Anything requiring a skipped pass
+    /// is skipped too.
+    pub skip: Vec<String>,
+    /// Stop the pipeline after this pass.
+    pub stop_after: Option<String>,
+}
+
+impl PassOptions {
+    pub fn skips(&self, pass: &str) -> bool {
+        self.skip.iter().any(|name| same_pass(name, pass))
+    }
+
+    pub fn stops_after(&self, pass: &str) -> bool {
+        self.stop_after
+            .as_ref()
+            .map(|name| same_pass(name, pass))
+            .unwrap_or(false)
+    }
+
+    /// Make sure every pass mentioned is in `known`.
+    pub fn validate(&self, known: &[&str]) -> Result<(), PipelineError> {
+        for name in self.skip.iter().chain(&self.stop_after) {
+            if !known.iter().any(|k| same_pass(name, k)) {
+                return Err(PipelineError::UnknownPass(name.clone()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Compare pass names, letting people write `basic-blocks` or
+/// `basic_blocks` instead of `BasicBlocks`.
+fn same_pass(name: &str, pass: &str) -> bool {
+    let normalize = |s: &str| {
+        s.chars()
+            .filter(|&c| c != '-' && c != '_')
+            .flat_map(char::to_lowercase)
+            .collect::<String>()
+    };
+
+    normalize(name) == normalize(pass)
+}
+
+/// What was measured while running a pass.
+///
+/// Passes in the same stage run in parallel, so memory can only be measured
+/// for the stage as a whole.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PassMetrics {
+    pub name: &'static str,
+    pub duration: Duration,
+    /// The index of the stage this pass was executed in.
+    pub stage: usize,
+    /// The memory used by the [`Resources`] before the pass's stage started.
+    pub memory_before: usize,
+    /// The memory used by the [`Resources`] after the pass's stage finished.
+    pub memory_after: usize,
+}
+
+/// Why a pipeline didn't run to completion.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Stop {
+    /// The pass reported at least one error.
+    Errors(&'static str),
+    /// The user asked to stop after this pass (`--stop-after`).
+    Requested(&'static str),
+}
+
+/// A summary of the passes which were executed.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Report {
+    pub passes: Vec<PassMetrics>,
+    pub skipped: Vec<&'static str>,
+    pub stopped: Option<Stop>,
+}
+
+impl Report {
+    /// Add the results of a later pipeline.
+    pub fn extend(&mut self, other: Report) {
+        let offset = self.passes.last().map_or(0, |p| p.stage + 1);
+        self.passes.extend(other.passes.into_iter().map(|mut pass| {
+            pass.stage += offset;
+            pass
+        }));
+        self.skipped.extend(other.skipped);
+        self.stopped = other.stopped;
+    }
+
+    pub fn total_duration(&self) -> Duration {
+        self.passes.iter().map(|p| p.duration).sum()
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let width = self
+            .passes
+            .iter()
+            .map(|p| p.name.len())
+            .chain(Some("Total".len()))
+            .max()
+            .unwrap_or_default();
+
+        writeln!(
+            f,
+            "{:<width$}  {:>5}  {:>12}  {:>12}  {:>12}",
+            "Pass",
+            "Stage",
+            "Time (ms)",
+            "Stage memory",
+            "Change",
+            width = width
+        )?;
+
+        for (i, pass) in self.passes.iter().enumerate() {
+            write!(
+                f,
+                "{:<width$}  {:>5}  {:>12.3}",
+                pass.name,
+                pass.stage,
+                millis(pass.duration),
+                width = width
+            )?;
+
+            // memory is only shown once for each stage
+            let first_in_stage =
+                i == 0 || self.passes[i - 1].stage != pass.stage;
+            if first_in_stage {
+                let change =
+                    pass.memory_after as i64 - pass.memory_before as i64;
+                write!(f, "  {:>12}  {:>+12}", pass.memory_after, change)?;
+            }
+            writeln!(f)?;
+        }
+
+        let memory = self.passes.last().map(|p| p.memory_after).unwrap_or(0);
+        write!(
+            f,
+            "{:<width$}  {:>5}  {:>12.3}  {:>12}",
+            "Total",
+            "",
+            millis(self.total_duration()),
+            memory,
+            width = width
+        )?;
+
+        for name in &self.skipped {
+            write!(f, "\n(skipped {})", name)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0
+        + f64::from(duration.subsec_nanos()) / 1e6
+}
+
+/// A problem with the way a pipeline was put together.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PipelineError {
+    /// Nothing provides something a pass requires.
+    Unsatisfied {
+        pass: &'static str,
+        requirement: &'static str,
+    },
+    /// These passes depend on each other.
+    Cycle(Vec<&'static str>),
+    /// The user asked for a pass which doesn't exist.
+    UnknownPass(String),
+    /// The same pass was added more than once.
+    DuplicatePass(&'static str),
+}
+
+impl Display for PipelineError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            PipelineError::Unsatisfied { pass, requirement } => write!(
+                f,
+                "The {} pass requires \"{}\", but nothing provides it",
+                pass, requirement
+            ),
+            PipelineError::Cycle(ref passes) => write!(
+                f,
+                "There is a dependency cycle between {}",
+                passes.join(", ")
+            ),
+            PipelineError::UnknownPass(ref name) => {
+                write!(f, "There is no pass called \"{}\"", name)
+            }
+            PipelineError::DuplicatePass(name) => {
+                write!(f, "The {} pass was added more than once", name)
+            }
+        }
+    }
+}
+
+impl Error for PipelineError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ecs::{Singleton, SingletonMut};
+    use crate::Diagnostics;
+    use codespan_reporting::Diagnostic;
+    use heapsize_derive::HeapSizeOf;
+    use typename::TypeName;
+
+    #[derive(Debug, Default, TypeName, HeapSizeOf)]
+    struct Marker;
+
+    macro_rules! test_pass {
+        ($name:ident, requires: [$($req:expr),*], provides: [$($prov:expr),*]) => {
+            test_pass!($name, requires: [$($req),*], provides: [$($prov),*], storage: (), |_ctx| {});
+        };
+        ($name:ident, requires: [$($req:expr),*], provides: [$($prov:expr),*], storage: $storage:ty, $body:expr) => {
+            #[derive(TypeName)]
+            enum $name {}
+
+            impl<'r> Pass<'r> for $name {
+                type Arg = ();
+                type Storage = $storage;
+                const DESCRIPTION: &'static str = stringify!($name);
+                const REQUIRES: &'static [&'static str] = &[$($req),*];
+                const PROVIDES: &'static [&'static str] = &[$($prov),*];
+
+                fn run(_: &(), ctx: &mut PassContext<'_>, _: Self::Storage) {
+                    let body: fn(&mut PassContext<'_>) = $body;
+                    body(ctx);
+                }
+            }
+        };
+    }
+
+    test_pass!(First, requires: [], provides: ["first"]);
+    test_pass!(Second, requires: ["first"], provides: ["second"]);
+    test_pass!(Third, requires: ["second"], provides: []);
+    test_pass!(Broken, requires: ["first"], provides: ["broken"], storage: SingletonMut<'r, Marker>, |ctx| {
+        ctx.diags.push(Diagnostic::new_error("Oops"))
+    });
+    test_pass!(Reader, requires: ["first"], provides: [], storage: Singleton<'r, Marker>, |ctx| {
+        ctx.diags.push(Diagnostic::new_warning("Reader"))
+    });
+    test_pass!(OtherReader, requires: ["first"], provides: [], storage: Singleton<'r, Marker>, |ctx| {
+        ctx.diags.push(Diagnostic::new_warning("OtherReader"))
+    });
+    test_pass!(Writer, requires: ["first"], provides: [], storage: SingletonMut<'r, Marker>, |_ctx| {});
+    test_pass!(Chicken, requires: ["egg"], provides: ["chicken"]);
+    test_pass!(Egg, requires: ["chicken"], provides: ["egg"]);
+
+    fn run(
+        pm: PassManager<()>,
+        options: &PassOptions,
+    ) -> (Report, Diagnostics) {
+        let mut diags = Diagnostics::new();
+        let mut resources = Resources::new();
+        let report = pm
+            .run(
+                &mut resources,
+                &(),
+                options,
+                &mut PassContext::new_nop_logger(&mut diags),
+            )
+            .unwrap();
+
+        (report, diags)
+    }
+
+    fn executed(report: &Report) -> Vec<&'static str> {
+        report.passes.iter().map(|p| p.name).collect()
+    }
+
+    #[test]
+    fn passes_are_sorted_by_their_dependencies() {
+        let pm = PassManager::<()>::new()
+            .add::<Third>()
+            .add::<Second>()
+            .add::<First>();
+
+        assert_eq!(pm.names().unwrap(), vec!["First", "Second", "Third"]);
+    }
+
+    #[test]
+    fn skipping_a_pass_skips_its_dependents() {
+        let pm = PassManager::<()>::new()
+            .add::<First>()
+            .add::<Second>()
+            .add::<Third>();
+        let options = PassOptions {
+            skip: vec![String::from("second")],
+            ..Default::default()
+        };
+
+        let (report, _) = run(pm, &options);
+
+        assert_eq!(executed(&report), vec!["First"]);
+        assert_eq!(report.skipped, vec!["Second", "Third"]);
+    }
+
+    #[test]
+    fn stop_after_a_pass() {
+        let pm = PassManager::<()>::new()
+            .add::<First>()
+            .add::<Second>()
+            .add::<Third>();
+        let options = PassOptions {
+            stop_after: Some(String::from("Second")),
+            ..Default::default()
+        };
+
+        let (report, _) = run(pm, &options);
+
+        assert_eq!(executed(&report), vec!["First", "Second"]);
+        assert_eq!(report.stopped, Some(Stop::Requested("Second")));
+    }
+
+    #[test]
+    fn errors_stop_the_pipeline() {
+        let pm = PassManager::<()>::new()
+            .add::<First>()
+            .add::<Broken>()
+            .add::<Writer>();
+
+        let (report, diags) = run(pm, &PassOptions::default());
+
+        assert!(diags.has_errors());
+        assert_eq!(executed(&report), vec!["First", "Broken"]);
+        assert_eq!(report.stopped, Some(Stop::Errors("Broken")));
+    }
+
+    #[test]
+    fn existing_errors_dont_stop_the_pipeline() {
+        let pm = PassManager::<()>::new().add::<First>().add::<Second>();
+        let mut diags = Diagnostics::new();
+        diags.push(Diagnostic::new_error("Reported by the caller"));
+
+        let report = pm
+            .run(
+                &mut Resources::new(),
+                &(),
+                &PassOptions::default(),
+                &mut PassContext::new_nop_logger(&mut diags),
+            )
+            .unwrap();
+
+        assert_eq!(executed(&report), vec!["First", "Second"]);
+        assert_eq!(report.stopped, None);
+    }
+
+    #[test]
+    fn detect_dependency_problems() {
+        let pm = PassManager::<()>::new().add::<Second>();
+        assert_eq!(
+            pm.names(),
+            Err(PipelineError::Unsatisfied {
+                pass: "Second",
+                requirement: "first",
+            })
+        );
+
+        let pm = PassManager::<()>::new()
+            .add::<Second>()
+            .assume_provided("first");
+        assert!(pm.names().is_ok());
+
+        let pm = PassManager::<()>::new().add::<Chicken>().add::<Egg>();
+        assert_eq!(
+            pm.names(),
+            Err(PipelineError::Cycle(vec!["Chicken", "Egg"]))
+        );
+
+        let pm = PassManager::<()>::new()
+            .add::<First>()
+            .add::<Second>()
+            .add::<First>();
+        assert_eq!(pm.names(), Err(PipelineError::DuplicatePass("First")));
+    }
+
+    #[test]
+    fn unknown_passes_are_reported() {
+        let options = PassOptions {
+            skip: vec![String::from("first"), String::from("fourth")],
+            ..Default::default()
+        };
+
+        let got = options.validate(&["First", "Second"]);
+
+        assert_eq!(
+            got,
+            Err(PipelineError::UnknownPass(String::from("fourth")))
+        );
+    }
+
+    #[test]
+    fn the_report_is_a_table() {
+        let pm = PassManager::<()>::new()
+            .add::<First>()
+            .add::<Reader>()
+            .add::<OtherReader>();
+        let (report, _) = run(pm, &PassOptions::default());
+
+        let table = report.to_string();
+
+        assert!(table.starts_with("Pass"));
+        assert!(table.contains("First"));
+        assert!(table.contains("Total"));
+        let stages: Vec<_> = report.passes.iter().map(|p| p.stage).collect();
+        assert_eq!(stages, vec![0, 1, 1]);
+        // memory is measured per stage, so it's only shown once
+        let columns = |name: &str| {
+            let row = table.lines().find(|l| l.starts_with(name)).unwrap();
+            row.split_whitespace().count()
+        };
+        assert_eq!(columns("Reader"), 5);
+        assert_eq!(columns("OtherReader"), 3);
+    }
+
+    #[test]
+    fn optimisation_levels_are_valid_pipelines() {
+        use crate::passes::{optimizations, OptimizationLevel};
+
+        assert!(optimizations(OptimizationLevel::None)
+            .names()
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            optimizations(OptimizationLevel::Basic).names().unwrap(),
+            vec!["CopyPropagation", "DeadCodeElimination", "SsaConstruction"]
+        );
+        assert_eq!(
+            optimizations(OptimizationLevel::Full).names().unwrap(),
+            vec![
+                "CopyPropagation",
+                "CommonSubexpressionElimination",
+                "LoopInvariantCodeMotion",
+                "DeadCodeElimination",
+                "SsaConstruction",
+            ]
+        );
+    }
+
+    #[test]
+    fn passes_which_dont_conflict_share_a_stage() {
+        let pm = PassManager::<()>::new()
+            .add::<First>()
+            .add::<Reader>()
+            .add::<OtherReader>()
+            .add::<Writer>()
+            .add::<Second>();
+        let names = |stage: &Vec<usize>| -> Vec<&str> {
+            stage.iter().map(|&ix| pm.entries[ix].name).collect()
+        };
+
+        let stages = pm.stages(&pm.schedule().unwrap());
+        let got: Vec<_> = stages.iter().map(names).collect();
+
+        assert_eq!(
+            got,
+            vec![
+                vec!["First"],
+                vec!["Reader", "OtherReader"],
+                vec!["Writer", "Second"],
+            ]
+        );
+    }
+
+    #[test]
+    fn parallel_passes_report_diagnostics_in_order() {
+        let pm = PassManager::<()>::new()
+            .add::<First>()
+            .add::<Reader>()
+            .add::<OtherReader>();
+
+        let (report, diags) = run(pm, &PassOptions::default());
+
+        assert_eq!(executed(&report), vec!["First", "Reader", "OtherReader"]);
+        let messages: Vec<_> = diags
+            .diagnostics()
+            .iter()
+            .map(|d| d.message.as_str())
+            .collect();
+        assert_eq!(messages, vec!["Reader", "OtherReader"]);
+    }
+}
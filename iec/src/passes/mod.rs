@@ -3,12 +3,38 @@
 //! updating the world.
 
 pub mod basic_blocks;
+pub mod common_subexpressions;
+pub mod configuration;
+pub mod constant_folding;
+pub mod control_flow;
+pub mod copy_propagation;
+pub mod dataflow_lints;
+pub mod dead_code;
+pub mod loop_invariants;
+pub mod manager;
+pub mod name_resolution;
+pub mod process_image;
 pub mod register_builtins;
+pub mod ssa_construction;
 pub mod symbol_table;
 pub mod variable_discovery;
 
 pub use self::basic_blocks::BasicBlocks;
+pub use self::common_subexpressions::CommonSubexpressionElimination;
+pub use self::configuration::ConfigurationResolution;
+pub use self::constant_folding::ConstantFolding;
+pub use self::control_flow::ControlFlowAnalysis;
+pub use self::copy_propagation::CopyPropagation;
+pub use self::dataflow_lints::DataflowLints;
+pub use self::dead_code::DeadCodeElimination;
+pub use self::loop_invariants::LoopInvariantCodeMotion;
+pub use self::manager::{
+    PassManager, PassMetrics, PassOptions, PipelineError, Report, Stop,
+};
+pub use self::name_resolution::NameResolution;
+pub use self::process_image::ProcessImageLayout;
 pub use self::register_builtins::RegisterBuiltins;
+pub use self::ssa_construction::SsaConstruction;
 pub use self::symbol_table::SymbolTableResolution;
 pub use self::variable_discovery::VariableDiscovery;
 
@@ -18,7 +44,9 @@ use crate::hir::CompilationUnit;
 use crate::Diagnostics;
 use heapsize::HeapSizeOf;
 use slog::{Discard, Logger};
-use std::time::Instant;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 use typename::TypeName;
 
 /// The "system" part of your typical Entity-Component-System application.
@@ -33,6 +61,11 @@ pub trait Pass<'r>: TypeName {
     type Storage: FromResources<'r>;
     /// A one-line description of what the pass is meant to do.
     const DESCRIPTION: &'static str;
+    /// The things which must have been set up by earlier passes before this
+    /// one can run.
+    const REQUIRES: &'static [&'static str] = &[];
+    /// The things this pass sets up for later passes.
+    const PROVIDES: &'static [&'static str] = &[];
 
     /// Execute the pass.
     fn run(args: &Self::Arg, ctx: &mut PassContext<'_>, storage: Self::Storage);
@@ -42,21 +75,63 @@ pub fn run_pass<'r, P: Pass<'r>>(
     r: &'r mut Resources,
     arg: &'r P::Arg,
     ctx: &mut PassContext<'_>,
-) {
+) -> PassMetrics {
     let mut ctx = ctx.with(slog::o!("pass" => P::type_name()));
+    let memory_before = r.heap_size_of_children();
     slog::debug!(ctx.logger, "Pass started";
         "description" => P::DESCRIPTION,
-        "resource-usage" => r.heap_size_of_children());
-    let start = Instant::now();
+        "resource-usage" => memory_before);
 
     P::Storage::ensure_registered(r);
-    let storage = P::Storage::from_resources(r);
-    P::run(arg, &mut ctx, storage);
+    let r: &'r Resources = r;
+    let duration = execute_pass::<P>(r, arg, &mut ctx);
+    r.maintain();
 
-    let duration = Instant::now() - start;
+    let memory_after = r.heap_size_of_children();
     slog::debug!(ctx.logger, "Pass complete";
         "execution-time" => format_args!("{}.{:06}s", duration.as_secs(), duration.subsec_micros()),
-        "resource-usage" => r.heap_size_of_children());
+        "resource-usage" => memory_after);
+
+    PassMetrics {
+        name: manager::short_name::<P>(),
+        duration,
+        stage: 0,
+        memory_before,
+        memory_after,
+    }
+}
+
+/// Execute a pass without needing exclusive access to the [`Resources`],
+/// returning how long it took.
+///
+/// The pass's storage must already be registered, and deleted entities are
+/// left for the caller to clean up with [`Resources::maintain()`].
+pub(crate) fn execute_pass<'r, P: Pass<'r>>(
+    r: &'r Resources,
+    arg: &'r P::Arg,
+    ctx: &mut PassContext<'_>,
+) -> Duration {
+    let start = Instant::now();
+
+    let storage = P::Storage::from_resources(r);
+    P::run(arg, ctx, storage);
+
+    Instant::now() - start
+}
+
+/// The passes making up semantic analysis.
+pub fn semantic_analysis() -> PassManager<iec_syntax::File> {
+    PassManager::new()
+        .add::<RegisterBuiltins>()
+        .add_with_input::<SymbolTableResolution>()
+        .add_with_input::<VariableDiscovery>()
+        .add_with_input::<ConfigurationResolution>()
+        .add::<ProcessImageLayout>()
+        .add_with_input::<NameResolution>()
+        .add_with_input::<BasicBlocks>()
+        .add::<ConstantFolding>()
+        .add::<ControlFlowAnalysis>()
+        .add::<DataflowLints>()
 }
 
 /// Process the provided AST and execute semantic analysis.
@@ -64,14 +139,136 @@ pub fn process(
     ast: &iec_syntax::File,
     ctx: &mut PassContext<'_>,
 ) -> CompilationUnit {
+    let (cu, _) = process_with_options(ast, &PassOptions::default(), ctx)
+        .expect("The semantic analysis pipeline is always valid");
+    cu
+}
+
+/// Process the provided AST, using [`PassOptions`] to control which passes
+/// are executed.
+///
+/// The pipeline stops at the first pass which reports an error, so later
+/// passes never see broken state.
+pub fn process_with_options(
+    ast: &iec_syntax::File,
+    options: &PassOptions,
+    ctx: &mut PassContext<'_>,
+) -> Result<(CompilationUnit, Report), PipelineError> {
     let mut resources = Resources::new();
+    let report = semantic_analysis().run(&mut resources, ast, options, ctx)?;
+
+    Ok((CompilationUnit { resources }, report))
+}
+
+/// How much effort should be spent optimising the code (`-O`).
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptimizationLevel {
+    /// Don't run any optimisation passes (`-O0`).
+    ///
+    /// Constant folding is part of semantic analysis (it reports errors like
+    /// dividing by a constant zero), so it always runs.
+    #[default]
+    None,
+    /// Cheap clean-ups which remove redundant copies and dead code (`-O1`).
+    Basic,
+    /// Everything, including common subexpression elimination and
+    /// loop-invariant code motion (`-O2`).
+    Full,
+}
+
+impl FromStr for OptimizationLevel {
+    type Err = String;
 
-    run_pass::<RegisterBuiltins>(&mut resources, &(), ctx);
-    run_pass::<SymbolTableResolution>(&mut resources, ast, ctx);
-    run_pass::<VariableDiscovery>(&mut resources, ast, ctx);
-    run_pass::<BasicBlocks>(&mut resources, ast, ctx);
+    fn from_str(s: &str) -> Result<OptimizationLevel, Self::Err> {
+        match s {
+            "0" => Ok(OptimizationLevel::None),
+            "1" => Ok(OptimizationLevel::Basic),
+            "2" => Ok(OptimizationLevel::Full),
+            _ => Err(format!(
+                "\"{}\" isn't a valid optimisation level, expected 0, 1, or 2",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for OptimizationLevel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let level = match *self {
+            OptimizationLevel::None => 0,
+            OptimizationLevel::Basic => 1,
+            OptimizationLevel::Full => 2,
+        };
+        write!(f, "O{}", level)
+    }
+}
+
+impl slog::Value for OptimizationLevel {
+    fn serialize(
+        &self,
+        _record: &slog::Record,
+        key: slog::Key,
+        ser: &mut dyn slog::Serializer,
+    ) -> slog::Result {
+        ser.emit_arguments(key, &format_args!("{}", self))
+    }
+}
+
+/// The optimisation passes selected by `level`.
+pub fn optimizations(level: OptimizationLevel) -> PassManager<()> {
+    let pm = PassManager::new().assume_provided("control-flow");
+
+    match level {
+        OptimizationLevel::None => pm,
+        OptimizationLevel::Basic => pm
+            .add::<CopyPropagation>()
+            .add::<DeadCodeElimination>()
+            .add::<SsaConstruction>(),
+        OptimizationLevel::Full => pm
+            .add::<CopyPropagation>()
+            .add::<CommonSubexpressionElimination>()
+            .add::<LoopInvariantCodeMotion>()
+            .add::<DeadCodeElimination>()
+            .add::<SsaConstruction>(),
+    }
+}
+
+/// Run the optimisation passes selected by `level` over a [`CompilationUnit`]
+/// which passed semantic analysis.
+pub fn optimize(
+    cu: &mut CompilationUnit,
+    level: OptimizationLevel,
+    ctx: &mut PassContext<'_>,
+) {
+    optimize_with_options(cu, level, &PassOptions::default(), ctx)
+        .expect("The optimisation pipeline is always valid");
+}
+
+/// Run the optimisation passes selected by `level`, using [`PassOptions`] to
+/// control which passes are executed.
+pub fn optimize_with_options(
+    cu: &mut CompilationUnit,
+    level: OptimizationLevel,
+    options: &PassOptions,
+    ctx: &mut PassContext<'_>,
+) -> Result<Report, PipelineError> {
+    optimizations(level).run(&mut cu.resources, &(), options, ctx)
+}
+
+/// The name of every pass the compiler knows about.
+pub fn pass_names() -> Vec<&'static str> {
+    let mut names = semantic_analysis().names().unwrap_or_default();
+
+    for name in optimizations(OptimizationLevel::Full)
+        .names()
+        .unwrap_or_default()
+    {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
 
-    CompilationUnit { resources }
+    names
 }
 
 /// Contextual information given to each pass.
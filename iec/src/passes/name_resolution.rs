@@ -0,0 +1,555 @@
+use super::symbol_table::{Binding, Definition, ScopeId, SymbolTable};
+use super::{Pass, PassContext};
+use crate::ecs::{Container, EntityId, Read, Singleton};
+use crate::hir::{FunctionBlock, Symbol, Variable, VariableKind};
+use crate::Diagnostics;
+use codespan_reporting::{Diagnostic, Label};
+use iec_syntax::{
+    DottedIdentifier, Expression, FunctionArg, FunctionCall, Identifier, Item,
+    Statement,
+};
+use typename::TypeName;
+
+#[derive(TypeName)]
+pub enum NameResolution {}
+
+impl<'r> Pass<'r> for NameResolution {
+    type Arg = iec_syntax::File;
+    type Storage = (
+        Singleton<'r, SymbolTable>,
+        Read<'r, Variable>,
+        Read<'r, FunctionBlock>,
+    );
+    const DESCRIPTION: &'static str =
+        "Make sure every name used in an item's body refers to something";
+    const REQUIRES: &'static [&'static str] = &["variables"];
+    const PROVIDES: &'static [&'static str] = &["resolved-names"];
+
+    fn run(ast: &Self::Arg, ctx: &mut PassContext<'_>, storage: Self::Storage) {
+        let (symbols, variables, function_blocks) = storage;
+
+        for item in &ast.items {
+            let (body, name) = match item {
+                Item::Program(ref p) => (&p.body, &p.name.value),
+                Item::Function(ref f) => (&f.body, &f.name.value),
+                Item::FunctionBlock(ref fb) => (&fb.body, &fb.name.value),
+                Item::VarBlock(_) | Item::Configuration(_) => continue,
+            };
+
+            const ERR_MSG: &str = "the symbol table pass ensures this exists";
+            let symbol = symbols.get(name).expect(ERR_MSG);
+            let scope = symbols.scope_of(symbol.into()).expect(ERR_MSG);
+
+            let scope = Scope::new(&symbols, &function_blocks, scope);
+            let errors_before = ctx.diags.len();
+            let mut visitor = Visitor {
+                scope: &scope,
+                variables: &variables,
+                diags: ctx.diags,
+            };
+            visitor.statements(body);
+
+            slog::debug!(ctx.logger, "Resolved names";
+                "name" => name,
+                "unresolved" => ctx.diags.len() - errors_before);
+        }
+    }
+}
+
+/// The names visible from inside a particular item's body.
+pub(crate) struct Scope<'a> {
+    symbols: &'a SymbolTable,
+    function_blocks: &'a Container<FunctionBlock>,
+    scope: ScopeId,
+}
+
+impl<'a> Scope<'a> {
+    pub fn new(
+        symbols: &'a SymbolTable,
+        function_blocks: &'a Container<FunctionBlock>,
+        scope: ScopeId,
+    ) -> Scope<'a> {
+        Scope {
+            symbols,
+            function_blocks,
+            scope,
+        }
+    }
+
+    /// Find the variable a name refers to, checking the item's own variables
+    /// before falling back to globals.
+    pub fn lookup_variable(
+        &self,
+        name: &Identifier,
+    ) -> Result<EntityId, Diagnostic> {
+        match self.symbols.lookup(self.scope, &name.value) {
+            Some(&Definition {
+                binding: Binding::Variable(id),
+                ..
+            }) => Ok(id),
+            Some(_) => Err(Diagnostic::new_error("Expected a variable")
+                .with_label(Label::new_primary(name.span))),
+            None => {
+                let candidates = self.symbols.visible(self.scope);
+                Err(unknown(
+                    "Unknown variable",
+                    name,
+                    candidates.iter().map(|def| &def.name),
+                ))
+            }
+        }
+    }
+
+    /// Find a field of a struct or one of the inputs or outputs of a function
+    /// block instance.
+    pub fn lookup_member(
+        &self,
+        object: EntityId,
+        name: &Identifier,
+        variables: &Container<Variable>,
+    ) -> Result<EntityId, Diagnostic> {
+        let ty = variables
+            .get(object)
+            .expect("we only look up members of known variables")
+            .ty;
+
+        let members = match self.symbols.scope_of(ty) {
+            Some(members) => members,
+            None => {
+                return Err(Diagnostic::new_error("This type has no members")
+                    .with_label(Label::new_primary(name.span)))
+            }
+        };
+
+        match self.symbols.lookup_local(members, &name.value) {
+            Some(&Definition {
+                binding: Binding::Variable(id),
+                ..
+            }) => {
+                let kind = variables
+                    .get(id)
+                    .expect("all variables in scope are registered")
+                    .kind;
+
+                if self.function_blocks.get(ty).is_some()
+                    && !kind.is_visible_to_caller()
+                {
+                    Err(Diagnostic::new_error(
+                        "Only inputs and outputs can be accessed from outside a function block",
+                    )
+                    .with_label(Label::new_primary(name.span)))
+                } else {
+                    Ok(id)
+                }
+            }
+            _ => Err(unknown(
+                "Unknown member",
+                name,
+                self.symbols.definitions(members).map(|def| &def.name),
+            )),
+        }
+    }
+
+    /// Resolve a (possibly dotted) path, returning the variable at its root
+    /// followed by each of the members being accessed.
+    pub fn resolve(
+        &self,
+        path: &DottedIdentifier,
+        variables: &Container<Variable>,
+    ) -> Result<Vec<EntityId>, Diagnostic> {
+        let (first, rest) = path
+            .pieces
+            .split_first()
+            .expect("the parser never emits empty paths");
+        let mut resolved = vec![self.lookup_variable(first)?];
+
+        for piece in rest {
+            let object = *resolved.last().expect("always non-empty");
+            resolved.push(self.lookup_member(object, piece, variables)?);
+        }
+
+        Ok(resolved)
+    }
+
+    /// Figure out what is being called, and the instance being used if it's
+    /// a function block.
+    pub fn lookup_callee(
+        &self,
+        name: &Identifier,
+        variables: &Container<Variable>,
+    ) -> Result<(Symbol, Option<EntityId>), Diagnostic> {
+        let mut current = Some(self.scope);
+
+        while let Some(scope) = current {
+            current = self.symbols.parent(scope);
+
+            let binding = match self.symbols.lookup_local(scope, &name.value) {
+                Some(def) => def.binding,
+                None => continue,
+            };
+
+            match binding {
+                Binding::Variable(var) => {
+                    let variable = variables
+                        .get(var)
+                        .expect("all variables in scope are registered");
+
+                    if self.function_blocks.get(variable.ty).is_some() {
+                        return Ok((
+                            Symbol::FunctionBlock(variable.ty),
+                            Some(var),
+                        ));
+                    } else if variable.kind != VariableKind::ReturnValue {
+                        return Err(not_callable(name));
+                    }
+                    // a function's return value shadows the function, but we
+                    // still want recursive calls to work
+                }
+                Binding::Symbol(Symbol::Function(id)) => {
+                    return Ok((Symbol::Function(id), None))
+                }
+                Binding::Symbol(Symbol::FunctionBlock(_)) => {
+                    return Err(Diagnostic::new_error(
+                        "Function blocks can only be called via an instance",
+                    )
+                    .with_label(Label::new_primary(name.span)))
+                }
+                Binding::Symbol(_) => return Err(not_callable(name)),
+            }
+        }
+
+        let callable = self.symbols.visible(self.scope);
+        let callable = callable.iter().filter(|def| match def.binding {
+            Binding::Symbol(sym) => matches!(sym, Symbol::Function(_)),
+            Binding::Variable(id) => variables
+                .get(id)
+                .is_some_and(|v| self.function_blocks.get(v.ty).is_some()),
+        });
+
+        Err(unknown(
+            "Unknown function",
+            name,
+            callable.map(|def| &def.name),
+        ))
+    }
+}
+
+fn not_callable(name: &Identifier) -> Diagnostic {
+    Diagnostic::new_error(
+        "Only functions and function block instances can be called",
+    )
+    .with_label(Label::new_primary(name.span))
+}
+
+/// Create a diagnostic for an unknown name, suggesting a similarly named
+/// candidate if one exists.
+fn unknown<'c, I>(message: &str, name: &Identifier, candidates: I) -> Diagnostic
+where
+    I: IntoIterator<Item = &'c String>,
+{
+    let mut label = Label::new_primary(name.span);
+
+    if let Some(suggestion) = did_you_mean(&name.value, candidates) {
+        label = label.with_message(format!("did you mean \"{}\"?", suggestion));
+    }
+
+    Diagnostic::new_error(message).with_label(label)
+}
+
+/// Find the candidate closest to `name`, if one is close enough to be a
+/// plausible typo.
+fn did_you_mean<'c, I>(name: &str, candidates: I) -> Option<&'c str>
+where
+    I: IntoIterator<Item = &'c String>,
+{
+    let name = name.to_lowercase();
+    let max_distance = std::cmp::max(1, name.chars().count() / 3);
+
+    candidates
+        .into_iter()
+        .map(|candidate| {
+            (edit_distance(&name, &candidate.to_lowercase()), candidate)
+        })
+        .filter(|&(distance, _)| distance <= max_distance)
+        .min_by_key(|&(distance, candidate)| (distance, candidate.clone()))
+        .map(|(_, candidate)| candidate.as_str())
+}
+
+/// The number of single character insertions, deletions, substitutions, or
+/// transpositions needed to turn one string into another (the "optimal
+/// string alignment" distance).
+fn edit_distance(left: &str, right: &str) -> usize {
+    let left: Vec<char> = left.chars().collect();
+    let right: Vec<char> = right.chars().collect();
+
+    // distances[i][j] is the distance between left[..i] and right[..j]
+    let mut distances = vec![vec![0; right.len() + 1]; left.len() + 1];
+
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in distances[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+
+    for i in 1..=left.len() {
+        for j in 1..=right.len() {
+            let cost = if left[i - 1] == right[j - 1] { 0 } else { 1 };
+            let mut best = (distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1)
+                .min(distances[i - 1][j - 1] + cost);
+
+            if i > 1
+                && j > 1
+                && left[i - 1] == right[j - 2]
+                && left[i - 2] == right[j - 1]
+            {
+                best = best.min(distances[i - 2][j - 2] + 1);
+            }
+
+            distances[i][j] = best;
+        }
+    }
+
+    distances[left.len()][right.len()]
+}
+
+/// Walks an item's body, making sure every name can be resolved.
+struct Visitor<'a, 's> {
+    scope: &'a Scope<'s>,
+    variables: &'a Container<Variable>,
+    diags: &'a mut Diagnostics,
+}
+
+impl<'a, 's> Visitor<'a, 's> {
+    fn check<T>(&mut self, result: Result<T, Diagnostic>) {
+        if let Err(diag) = result {
+            self.diags.push(diag);
+        }
+    }
+
+    fn statements(&mut self, statements: &[Statement]) {
+        for statement in statements {
+            self.statement(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        match statement {
+            Statement::Assignment(ref a) => {
+                self.expression(&a.value);
+                self.path(&a.variable);
+            }
+            Statement::FunctionCall(ref call) => self.call(call),
+            Statement::ForLoop(ref f) => {
+                let counter = self.scope.lookup_variable(&f.variable);
+                self.check(counter);
+                self.expression(&f.start);
+                self.expression(&f.end);
+                if let Some(ref step) = f.step {
+                    self.expression(step);
+                }
+                self.statements(&f.body);
+            }
+            Statement::WhileLoop(ref w) => {
+                self.expression(&w.condition);
+                self.statements(&w.body);
+            }
+            Statement::RepeatLoop(ref r) => {
+                self.statements(&r.body);
+                self.expression(&r.condition);
+            }
+            Statement::IfStatement(ref i) => {
+                self.expression(&i.condition);
+                self.statements(&i.body);
+            }
+            Statement::Exit(_) | Statement::Return(_) => {}
+        }
+    }
+
+    fn expression(&mut self, expr: &Expression) {
+        match expr {
+            Expression::Literal(_) => {}
+            Expression::Variable(ref path) => self.path(path),
+            Expression::DirectAddress(ref address) => {
+                self.path(&address.to_path())
+            }
+            Expression::Binary(ref bin) => {
+                self.expression(&bin.left);
+                self.expression(&bin.right);
+            }
+            Expression::Unary(ref un) => self.expression(&un.value),
+            Expression::FunctionCall(ref call) => self.call(call),
+        }
+    }
+
+    fn path(&mut self, path: &DottedIdentifier) {
+        let resolved = self.scope.resolve(path, self.variables);
+        self.check(resolved);
+    }
+
+    fn call(&mut self, call: &FunctionCall) {
+        let callee = self.scope.lookup_callee(&call.name, self.variables);
+        self.check(callee);
+
+        // parameter names are checked when binding arguments to the callee
+        for arg in &call.args {
+            match arg {
+                FunctionArg::Bare(ref expr) => self.expression(expr),
+                FunctionArg::Named(ref assign) => {
+                    self.expression(&assign.value)
+                }
+                FunctionArg::Output(ref out) => self.path(&out.variable),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hir::CompilationUnit;
+
+    fn resolve(src: &str) -> (CompilationUnit, Diagnostics) {
+        let ast: iec_syntax::File = src.parse().unwrap();
+        let mut diags = Diagnostics::new();
+        let cu =
+            crate::process(&ast, &mut PassContext::new_nop_logger(&mut diags));
+
+        (cu, diags)
+    }
+
+    fn label_messages(diags: &Diagnostics) -> Vec<String> {
+        diags
+            .diagnostics()
+            .iter()
+            .flat_map(|d| d.labels.iter())
+            .filter_map(|l| l.message.clone())
+            .collect()
+    }
+
+    #[test]
+    fn edit_distances() {
+        let inputs = vec![
+            ("", "", 0),
+            ("count", "count", 0),
+            ("count", "coutn", 1),
+            ("count", "cuotn", 2),
+            ("count", "cont", 1),
+            ("kitten", "sitting", 3),
+            ("", "abc", 3),
+        ];
+
+        for (left, right, should_be) in inputs {
+            assert_eq!(edit_distance(left, right), should_be);
+            assert_eq!(edit_distance(right, left), should_be);
+        }
+    }
+
+    #[test]
+    fn globals_are_visible_everywhere() {
+        let src = "
+            VAR_GLOBAL
+                total: int;
+            END_VAR
+
+            PROGRAM main
+                total := total + 1;
+            END_PROGRAM";
+
+        let (_, diags) = resolve(src);
+
+        assert!(diags.is_empty(), "{:?}", diags);
+    }
+
+    #[test]
+    fn suggest_similar_variable_names() {
+        let src = "
+            PROGRAM main
+                VAR
+                    count: int;
+                END_VAR
+
+                count := cont + 1;
+            END_PROGRAM";
+
+        let (_, diags) = resolve(src);
+
+        assert_eq!(diags.len(), 1);
+        assert_eq!(label_messages(&diags), vec!["did you mean \"count\"?"]);
+    }
+
+    #[test]
+    fn resolve_function_block_members() {
+        let src = "
+            FUNCTION_BLOCK timer
+                VAR_OUTPUT
+                    done: bool;
+                END_VAR
+                VAR
+                    elapsed: int;
+                END_VAR
+            BEGIN
+            END_FUNCTION_BLOCK
+
+            PROGRAM main
+                VAR
+                    t: timer;
+                    finished: bool;
+                    x: int;
+                END_VAR
+
+                finished := t.done;
+                x := t.elapsed;
+                finished := t.dnoe;
+            END_PROGRAM";
+
+        let (_, diags) = resolve(src);
+
+        assert_eq!(diags.len(), 2, "{:?}", diags);
+        assert_eq!(label_messages(&diags), vec!["did you mean \"done\"?"]);
+    }
+
+    #[test]
+    fn suggest_similar_function_names() {
+        let src = "
+            FUNCTION add_one : int
+            BEGIN
+                add_one := 1;
+            END_FUNCTION
+
+            PROGRAM main
+                add_on();
+            END_PROGRAM";
+
+        let (_, diags) = resolve(src);
+
+        assert_eq!(diags.len(), 1);
+        assert_eq!(label_messages(&diags), vec!["did you mean \"add_one\"?"]);
+    }
+
+    #[test]
+    fn locals_can_shadow_items() {
+        let src = "
+            FUNCTION_BLOCK counter
+                VAR_OUTPUT
+                    done: bool;
+                END_VAR
+            BEGIN
+            END_FUNCTION_BLOCK
+
+            PROGRAM main
+                VAR
+                    Counter: counter;
+                    finished: bool;
+                END_VAR
+
+                counter();
+                finished := COUNTER.done;
+            END_PROGRAM";
+
+        let (_, diags) = resolve(src);
+
+        assert!(!diags.has_errors(), "{:?}", diags);
+    }
+}
@@ -0,0 +1,333 @@
+use super::{Pass, PassContext};
+use crate::const_eval::IntegerType;
+use crate::ecs::{EntityId, Join, Read, SingletonMut};
+use crate::hir::{
+    IoPoint, Location, ProcessImage, Program, Symbol, Type, Variable,
+};
+use crate::Diagnostics;
+use codespan::ByteSpan;
+use codespan_reporting::{Diagnostic, Label};
+use iec_syntax::{DirectAddress, Size};
+use typename::TypeName;
+
+#[derive(TypeName)]
+pub enum ProcessImageLayout {}
+
+impl<'r> Pass<'r> for ProcessImageLayout {
+    type Arg = ();
+    type Storage = (
+        SingletonMut<'r, ProcessImage>,
+        Read<'r, Location>,
+        Read<'r, Variable>,
+        Read<'r, Type>,
+        Read<'r, Program>,
+    );
+    const DESCRIPTION: &'static str =
+        "Work out where each located variable lives in the process image";
+    const REQUIRES: &'static [&'static str] = &["variables"];
+    const PROVIDES: &'static [&'static str] = &["process-image"];
+
+    fn run(_: &Self::Arg, ctx: &mut PassContext<'_>, storage: Self::Storage) {
+        let (mut image, locations, variables, types, programs) = storage;
+
+        // go through the variables in the order they were declared so
+        // diagnostics are deterministic
+        let mut located: Vec<_> = (&locations, &variables).join().collect();
+        located.sort_by_key(|(_, (Location(address), _))| address.span.start());
+
+        let mut points = Vec::new();
+
+        for (id, (Location(address), variable)) in located {
+            let ty = types
+                .get(variable.ty)
+                .map(|t| t.name.as_str())
+                .unwrap_or_default();
+
+            let name = match variable.parent {
+                Symbol::Program(p) => {
+                    let program =
+                        programs.get(p).expect("all programs are registered");
+                    format!("{}.{}", program.name, variable_name(variable))
+                }
+                Symbol::GlobalVariables(_) => variable_name(variable),
+                _ => {
+                    ctx.diags.push(
+                        Diagnostic::new_error(
+                            "Only programs and global variables can be located at a direct address",
+                        )
+                        .with_label(Label::new_primary(address.span)),
+                    );
+                    continue;
+                }
+            };
+
+            if let Some(point) = io_point(id, name, ty, address, ctx.diags) {
+                points.push((point, address.span));
+            }
+        }
+
+        points.sort_by_key(|(point, _)| (point.area, point.bits().start));
+        check_for_overlaps(&points, ctx.diags);
+
+        slog::debug!(ctx.logger, "Laid out the process image";
+            "point-count" => points.len());
+        image.points = points.into_iter().map(|(point, _)| point).collect();
+    }
+}
+
+fn variable_name(variable: &Variable) -> String {
+    variable.name.clone().unwrap_or_default()
+}
+
+/// Check an address is well formed and the right size for its variable.
+fn io_point(
+    variable: EntityId,
+    name: String,
+    ty: &str,
+    address: &DirectAddress,
+    diags: &mut Diagnostics,
+) -> Option<IoPoint> {
+    let (byte, bit) = match (address.size, address.indices.as_slice()) {
+        (Size::Bit, &[byte, bit]) if bit < 8 => (byte, Some(bit as u8)),
+        (Size::Bit, &[_, _]) => {
+            return error(
+                "Bit numbers must be between 0 and 7",
+                address.span,
+                diags,
+            );
+        }
+        (Size::Bit, _) => {
+            return error(
+                "Bit addresses need a byte and a bit number (e.g. %IX0.7)",
+                address.span,
+                diags,
+            );
+        }
+        (_, &[byte]) => (byte, None),
+        (_, _) => {
+            return error(
+                "Only bit addresses can have a bit number (e.g. %IW4)",
+                address.span,
+                diags,
+            );
+        }
+    };
+
+    let bits = match type_bits(ty) {
+        Some(bits) => bits,
+        None => {
+            return error(
+                "Only elementary types can be located at a direct address",
+                address.span,
+                diags,
+            );
+        }
+    };
+
+    if bits != address.size.bits() {
+        diags.push(
+            Diagnostic::new_error(
+                "The address's size doesn't match the variable's type",
+            )
+            .with_label(
+                Label::new_primary(address.span).with_message(format!(
+                    "This refers to {} bits, but {} is {} bits",
+                    address.size.bits(),
+                    ty.to_uppercase(),
+                    bits
+                )),
+            ),
+        );
+        return None;
+    }
+
+    Some(IoPoint {
+        variable,
+        name,
+        address: address.to_string(),
+        ty: ty.to_string(),
+        area: address.area,
+        size: address.size,
+        byte,
+        bit,
+    })
+}
+
+fn error<T>(
+    message: &str,
+    span: ByteSpan,
+    diags: &mut Diagnostics,
+) -> Option<T> {
+    diags.push(
+        Diagnostic::new_error(message).with_label(Label::new_primary(span)),
+    );
+    None
+}
+
+/// How many bits are needed to store a value of this type, if it can be
+/// located at a direct address.
+fn type_bits(ty: &str) -> Option<u32> {
+    if let Some(int) = IntegerType::from_name(ty) {
+        return Some(int.bits());
+    }
+
+    match ty {
+        "bool" => Some(1),
+        "real" => Some(32),
+        // TIME and DATE are stored as a 64-bit number of milliseconds
+        "lreal" | "time" | "date" => Some(64),
+        _ => None,
+    }
+}
+
+/// Points must already be sorted by area and address.
+fn check_for_overlaps(points: &[(IoPoint, ByteSpan)], diags: &mut Diagnostics) {
+    for (i, (point, span)) in points.iter().enumerate() {
+        let clash = points[..i].iter().rev().find(|(other, _)| {
+            other.area == point.area && other.bits().end > point.bits().start
+        });
+
+        if let Some((other, other_span)) = clash {
+            diags.push(
+                Diagnostic::new_error("Overlapping direct addresses")
+                    .with_label(Label::new_primary(*span).with_message(
+                        format!("{} overlaps with {}", point.name, other.name),
+                    ))
+                    .with_label(
+                        Label::new_secondary(*other_span)
+                            .with_message("The other variable is located here"),
+                    ),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ecs::Resources;
+    use iec_syntax::Area;
+
+    fn layout(src: &str) -> (Resources, Vec<String>) {
+        let ast: iec_syntax::File = src.parse().unwrap();
+        let mut diags = Diagnostics::new();
+        let cu =
+            crate::process(&ast, &mut PassContext::new_nop_logger(&mut diags));
+
+        let messages = diags
+            .diagnostics()
+            .iter()
+            .map(|d| d.message.clone())
+            .collect();
+
+        (cu.resources, messages)
+    }
+
+    #[test]
+    fn lay_out_inputs_and_outputs() {
+        let src = "
+            VAR_GLOBAL
+                speed AT %QW4 : int;
+                setpoint AT %MD0 : dint;
+            END_VAR
+
+            PROGRAM main
+            VAR
+                start AT %IX0.1 : bool;
+                stop AT %IX0.0 : bool;
+                motor AT %QX0.0 : bool;
+            END_VAR
+                IF %IX0.1 AND NOT stop THEN
+                    %QX0.0 := TRUE;
+                END_IF;
+                speed := 100;
+            END_PROGRAM";
+
+        let (resources, messages) = layout(src);
+        assert!(messages.is_empty(), "{:?}", messages);
+
+        let image = resources.get_singleton::<ProcessImage>();
+        let inputs: Vec<_> =
+            image.area(Area::Input).map(|p| p.name.as_str()).collect();
+        assert_eq!(inputs, vec!["main.stop", "main.start"]);
+        let outputs: Vec<_> = image
+            .area(Area::Output)
+            .map(|p| p.address.as_str())
+            .collect();
+        assert_eq!(outputs, vec!["%QX0.0", "%QW4"]);
+        assert_eq!(image.size_in_bytes(Area::Input), 1);
+        assert_eq!(image.size_in_bytes(Area::Output), 6);
+        assert_eq!(image.size_in_bytes(Area::Memory), 4);
+
+        let json = image.to_json();
+        assert_eq!(json["inputs"]["points"][1]["name"], "main.start");
+        assert_eq!(json["inputs"]["points"][1]["bit"], 1);
+        assert_eq!(json["outputs"]["points"][1]["type"], "int");
+        assert_eq!(json["memory"]["size"], 4);
+    }
+
+    #[test]
+    fn detect_overlapping_and_badly_sized_addresses() {
+        let src = "
+            VAR_GLOBAL
+                a AT %IW0 : int;
+                b AT %IX1.3 : bool;
+                c AT %IX2 : bool;
+                d AT %QX0.9 : bool;
+                e AT %QX0.0 : int;
+                f AT %MB0 : byte;
+                g AT %MW0 : word;
+            END_VAR
+
+            PROGRAM main
+                %MX0.3 := TRUE;
+            END_PROGRAM";
+
+        let (_, messages) = layout(src);
+
+        assert_eq!(
+            messages,
+            vec![
+                "Bit addresses need a byte and a bit number (e.g. %IX0.7)",
+                "Bit numbers must be between 0 and 7",
+                "The address's size doesn't match the variable's type",
+                "Overlapping direct addresses",
+                "Overlapping direct addresses",
+                "Overlapping direct addresses",
+            ]
+        );
+    }
+
+    #[test]
+    fn undeclared_addresses_get_an_implicit_slot() {
+        let src = "
+            VAR_GLOBAL
+                start AT %IX0.0 : bool;
+            END_VAR
+
+            PROGRAM main
+                %QW4 := %IW2 + 1;
+                IF start AND %IX0.1 THEN
+                    %QX0.1 := TRUE;
+                    %QX0.1 := %IX0.1;
+                END_IF;
+            END_PROGRAM";
+
+        let (resources, messages) = layout(src);
+        assert!(messages.is_empty(), "{:?}", messages);
+
+        let image = resources.get_singleton::<ProcessImage>();
+        let inputs: Vec<_> = image
+            .area(Area::Input)
+            .map(|p| (p.name.as_str(), p.ty.as_str()))
+            .collect();
+        assert_eq!(
+            inputs,
+            vec![("start", "bool"), ("%IX0.1", "bool"), ("%IW2", "word")]
+        );
+        let outputs: Vec<_> =
+            image.area(Area::Output).map(|p| p.name.as_str()).collect();
+        assert_eq!(outputs, vec!["%QX0.1", "%QW4"]);
+        assert_eq!(image.size_in_bytes(Area::Input), 4);
+    }
+}
@@ -1,30 +1,238 @@
 use super::symbol_table::SymbolTable;
 use super::{Pass, PassContext};
-use crate::ecs::{ReadWrite, SingletonMut};
-use crate::hir::{Symbol, Type};
+use crate::ecs::{EntityId, ReadWrite, SingletonMut};
+use crate::hir::{
+    Argument, Function, Operand, Symbol, Type, Variable, VariableKind,
+};
+use std::collections::HashMap;
 use typename::TypeName;
 
 #[derive(TypeName)]
 pub enum RegisterBuiltins {}
 
 pub const BUILTIN_TYPES: &[&str] = &[
-    "byte", "word", "dword", "int", "dint", "real", "lreal", "time", "date",
-    "char", "string",
+    "bool", "byte", "word", "dword", "lword", "sint", "int", "dint", "lint",
+    "usint", "uint", "udint", "ulint", "real", "lreal", "time", "date", "char",
+    "string",
 ];
 
+/// A function from the standard library, which is implemented by the backend
+/// instead of in Structured Text.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BuiltinFunction {
+    pub name: &'static str,
+    /// Each input's name and type, in the order positional arguments are
+    /// bound.
+    pub inputs: &'static [(&'static str, &'static str)],
+    pub return_type: &'static str,
+}
+
+/// The standard library functions.
+///
+/// Most of these accept any numeric type (`ANY_NUM` or `ANY_BIT`), which is
+/// approximated by using the widest type in the family. Backends should use
+/// the types of the arguments actually passed in.
+pub const BUILTIN_FUNCTIONS: &[BuiltinFunction] = &[
+    BuiltinFunction {
+        name: "abs",
+        inputs: &[("in", "lint")],
+        return_type: "lint",
+    },
+    BuiltinFunction {
+        name: "min",
+        inputs: &[("in1", "lint"), ("in2", "lint")],
+        return_type: "lint",
+    },
+    BuiltinFunction {
+        name: "max",
+        inputs: &[("in1", "lint"), ("in2", "lint")],
+        return_type: "lint",
+    },
+    BuiltinFunction {
+        name: "limit",
+        inputs: &[("mn", "lint"), ("in", "lint"), ("mx", "lint")],
+        return_type: "lint",
+    },
+    BuiltinFunction {
+        name: "sel",
+        inputs: &[("g", "bool"), ("in0", "lint"), ("in1", "lint")],
+        return_type: "lint",
+    },
+    BuiltinFunction {
+        name: "shl",
+        inputs: &[("in", "lword"), ("n", "uint")],
+        return_type: "lword",
+    },
+    BuiltinFunction {
+        name: "shr",
+        inputs: &[("in", "lword"), ("n", "uint")],
+        return_type: "lword",
+    },
+    BuiltinFunction {
+        name: "rol",
+        inputs: &[("in", "lword"), ("n", "uint")],
+        return_type: "lword",
+    },
+    BuiltinFunction {
+        name: "ror",
+        inputs: &[("in", "lword"), ("n", "uint")],
+        return_type: "lword",
+    },
+];
+
+/// Look up a standard library function by name (case insensitive).
+pub fn builtin_function(name: &str) -> Option<&'static BuiltinFunction> {
+    BUILTIN_FUNCTIONS
+        .iter()
+        .find(|f| f.name.eq_ignore_ascii_case(name))
+}
+
+/// The [`BUILTIN_FUNCTIONS`] a backend needs to implement itself, so code
+/// generators can match on something more structured than a name.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Builtin {
+    Abs,
+    Min,
+    Max,
+    Limit,
+    Sel,
+    Shift(Shift),
+}
+
+impl Builtin {
+    pub fn from_name(name: &str) -> Option<Builtin> {
+        let builtin = match name.to_lowercase().as_str() {
+            "abs" => Builtin::Abs,
+            "min" => Builtin::Min,
+            "max" => Builtin::Max,
+            "limit" => Builtin::Limit,
+            "sel" => Builtin::Sel,
+            "shl" => Builtin::Shift(Shift::Left),
+            "shr" => Builtin::Shift(Shift::Right),
+            "rol" => Builtin::Shift(Shift::RotateLeft),
+            "ror" => Builtin::Shift(Shift::RotateRight),
+            _ => return None,
+        };
+
+        Some(builtin)
+    }
+}
+
+/// The bit shifting builtins, which operate on the unsigned representation
+/// of an integer.
+///
+/// Shifting by the integer's width (or more) clears every bit, rotating
+/// wraps the amount around, and a negative amount is an error.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Shift {
+    Left,
+    Right,
+    RotateLeft,
+    RotateRight,
+}
+
+impl Shift {
+    pub fn is_rotate(self) -> bool {
+        match self {
+            Shift::RotateLeft | Shift::RotateRight => true,
+            Shift::Left | Shift::Right => false,
+        }
+    }
+
+    /// Do bits move towards the most significant end?
+    pub fn is_left(self) -> bool {
+        match self {
+            Shift::Left | Shift::RotateLeft => true,
+            Shift::Right | Shift::RotateRight => false,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Shift::Left => "SHL",
+            Shift::Right => "SHR",
+            Shift::RotateLeft => "ROL",
+            Shift::RotateRight => "ROR",
+        }
+    }
+}
+
+/// Builtins are always inlined, so backends need the values passed to each
+/// input in the order the inputs were declared. Returns `None` if an input
+/// wasn't given a value.
+pub fn builtin_arguments(
+    function: &Function,
+    args: &[Argument],
+) -> Option<Vec<Operand>> {
+    function
+        .variables
+        .iter()
+        .map(|parameter| {
+            args.iter().find_map(|arg| match *arg {
+                Argument::Input {
+                    parameter: p,
+                    value,
+                } if p == *parameter => Some(value),
+                _ => None,
+            })
+        })
+        .collect()
+}
+
 impl<'r> Pass<'r> for RegisterBuiltins {
     type Arg = ();
-    type Storage = (SingletonMut<'r, SymbolTable>, ReadWrite<'r, Type>);
+    type Storage = (
+        SingletonMut<'r, SymbolTable>,
+        ReadWrite<'r, Type>,
+        ReadWrite<'r, Function>,
+        ReadWrite<'r, Variable>,
+    );
     const DESCRIPTION: &'static str = "Register builtin types and functions";
+    const PROVIDES: &'static [&'static str] = &["builtins"];
 
     fn run(_: &Self::Arg, _ctx: &mut PassContext<'_>, storage: Self::Storage) {
-        let (mut symbol_table, mut types) = storage;
+        let (mut symbol_table, mut types, mut functions, mut variables) =
+            storage;
+        let mut type_ids = HashMap::new();
 
         for name in BUILTIN_TYPES {
             let type_id = types.insert(Type {
                 name: name.to_string(),
             });
             symbol_table.insert(name, Symbol::Type(type_id));
+            type_ids.insert(*name, type_id);
+        }
+
+        for builtin in BUILTIN_FUNCTIONS {
+            // builtins have no body, so the entry block is left as a
+            // placeholder
+            let id = functions.insert(Function {
+                name: builtin.name.to_string(),
+                variables: Vec::new(),
+                return_type: type_ids[builtin.return_type],
+                return_value: EntityId::default(),
+                entry_block: EntityId::default(),
+            });
+            let symbol = Symbol::Function(id);
+
+            let inputs = builtin
+                .inputs
+                .iter()
+                .map(|&(name, ty)| {
+                    variables.insert(Variable {
+                        parent: symbol,
+                        ty: type_ids[ty],
+                        name: Some(name.to_string()),
+                        kind: VariableKind::Input,
+                    })
+                })
+                .collect();
+
+            functions
+                .get_mut(id)
+                .expect("we just inserted it")
+                .variables = inputs;
+            symbol_table.insert(builtin.name, symbol);
         }
     }
 }
@@ -0,0 +1,49 @@
+use super::{Pass, PassContext};
+use crate::analysis::ssa::{self, SsaForm};
+use crate::analysis::{ControlFlowCache, SsaCache};
+use crate::ecs::{Read, Singleton, SingletonMut};
+use crate::hir::{BasicBlock, Instruction, Variable};
+use typename::TypeName;
+
+/// Put each item's body into SSA form, for use by later stages.
+#[derive(TypeName)]
+pub enum SsaConstruction {}
+
+impl<'r> Pass<'r> for SsaConstruction {
+    type Arg = ();
+    type Storage = (
+        Read<'r, Variable>,
+        Read<'r, Instruction>,
+        Read<'r, BasicBlock>,
+        Singleton<'r, ControlFlowCache>,
+        SingletonMut<'r, SsaCache>,
+    );
+    const DESCRIPTION: &'static str = "Construct the SSA form of each item";
+    const REQUIRES: &'static [&'static str] = &["control-flow"];
+    const PROVIDES: &'static [&'static str] = &["ssa"];
+
+    fn run(_: &Self::Arg, ctx: &mut PassContext<'_>, storage: Self::Storage) {
+        let (variables, instructions, blocks, cache, mut ssa_cache) = storage;
+        let tracked =
+            |v| variables.get(v).map(ssa::is_ssa_candidate).unwrap_or(false);
+
+        for (item, control_flow) in cache.iter() {
+            if ssa_cache.get(item).is_some() {
+                continue;
+            }
+
+            let ssa = SsaForm::construct(
+                control_flow,
+                &blocks,
+                &instructions,
+                tracked,
+            );
+
+            slog::debug!(ctx.logger, "Constructed SSA form";
+                "item" => format_args!("{:?}", item),
+                "values" => ssa.value_count());
+
+            ssa_cache.insert(item, ssa);
+        }
+    }
+}
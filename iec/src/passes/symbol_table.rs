@@ -1,61 +1,308 @@
 use super::{Pass, PassContext};
-use crate::ecs::{Container, ReadWrite, SingletonMut};
+use crate::ecs::{Container, EntityId, ReadWrite, SingletonMut};
 use crate::hir::{Function, FunctionBlock, Program, Symbol};
+use codespan::{ByteOffset, ByteSpan};
 use codespan_reporting::{Diagnostic, Label};
 use heapsize_derive::HeapSizeOf;
-use iec_syntax::Item;
+use iec_syntax::{Identifier, Item};
 use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
 use typename::TypeName;
 
-/// A cache for looking up a component based on its identifier.
+/// A tree of lexical scopes, used to look up what a name refers to.
+///
+/// The root of the tree is the global scope, containing types, programs,
+/// functions, function blocks, and global variables. Each program, function,
+/// and function block gets its own child scope for the variables it declares,
+/// which doubles as the set of members visible on a function block instance.
+///
+/// Names are case-insensitive, and a name defined in an inner scope shadows
+/// any definitions with the same name in its ancestors.
 #[derive(
-    Debug,
-    Default,
-    Clone,
-    PartialEq,
-    TypeName,
-    HeapSizeOf,
-    Serialize,
-    Deserialize,
+    Debug, Clone, PartialEq, TypeName, HeapSizeOf, Serialize, Deserialize,
 )]
-pub struct SymbolTable(HashMap<String, Symbol>);
+pub struct SymbolTable {
+    scopes: Vec<Scope>,
+    /// The scope associated with each item.
+    owners: HashMap<EntityId, ScopeId>,
+    /// Scopes left behind by removed items, which can be handed out again.
+    #[serde(default)]
+    vacant: Vec<ScopeId>,
+}
 
 impl SymbolTable {
+    /// The outermost scope, which every other scope is nested inside.
+    pub fn global_scope(&self) -> ScopeId {
+        ScopeId(0)
+    }
+
+    /// Create a new scope nested inside `parent`, belonging to `owner`.
+    pub fn add_scope(&mut self, parent: ScopeId, owner: EntityId) -> ScopeId {
+        let scope = Scope {
+            parent: Some(parent),
+            names: HashMap::new(),
+        };
+
+        let id = match self.vacant.pop() {
+            Some(id) => {
+                *self.scope_mut(id) = scope;
+                id
+            }
+            None => {
+                self.scopes.push(scope);
+                ScopeId(self.scopes.len() - 1)
+            }
+        };
+        self.owners.insert(owner, id);
+        id
+    }
+
+    /// Remove a global item, along with everything defined in its scope.
+    ///
+    /// Any [`ScopeId`]s pointing at the item's scope must not be used
+    /// afterwards, the scope may be reused by the next call to
+    /// [`SymbolTable::add_scope()`].
+    pub fn remove_item(&mut self, name: &str) -> Option<Symbol> {
+        let global = self.global_scope();
+        let symbol = match self.lookup_local(global, name)?.binding {
+            Binding::Symbol(sym) => sym,
+            Binding::Variable(_) => return None,
+        };
+
+        self.scope_mut(global).names.remove(&name.to_lowercase());
+
+        if let Some(scope) = self.owners.remove(&symbol.into()) {
+            self.scope_mut(scope).names.clear();
+            self.vacant.push(scope);
+        }
+
+        Some(symbol)
+    }
+
+    /// Shift the spans for a global item and everything defined in its scope
+    /// by `offset`, typically because code before it was edited.
+    pub fn relocate_item(&mut self, name: &str, offset: ByteOffset) {
+        let global = self.global_scope();
+        let symbol =
+            match self.scope_mut(global).names.get_mut(&name.to_lowercase()) {
+                Some(def) => {
+                    def.relocate(offset);
+                    def.binding
+                }
+                None => return,
+            };
+
+        let scope = match symbol {
+            Binding::Symbol(sym) => self.scope_of(sym.into()),
+            Binding::Variable(_) => None,
+        };
+
+        if let Some(scope) = scope {
+            for def in self.scope_mut(scope).names.values_mut() {
+                def.relocate(offset);
+            }
+        }
+    }
+
+    /// The scope belonging to a particular item, if it has one.
+    pub fn scope_of(&self, owner: EntityId) -> Option<ScopeId> {
+        self.owners.get(&owner).cloned()
+    }
+
+    /// The scope `scope` is nested inside, if it isn't the global scope.
+    pub fn parent(&self, scope: ScopeId) -> Option<ScopeId> {
+        self.scope(scope).parent
+    }
+
+    /// Add a global symbol which doesn't come from the source code (e.g. a
+    /// builtin type).
     pub fn insert(&mut self, name: &str, sym: Symbol) {
-        self.0.insert(name.to_lowercase(), sym);
+        let global = self.global_scope();
+        self.scope_mut(global).names.insert(
+            name.to_lowercase(),
+            Definition {
+                name: name.to_string(),
+                binding: Binding::Symbol(sym),
+                span: None,
+            },
+        );
     }
 
+    /// Look up a global symbol.
     pub fn get(&self, name: &str) -> Option<Symbol> {
-        let name = name.to_lowercase();
-        self.0.get(&name).cloned()
+        match self.lookup_local(self.global_scope(), name)?.binding {
+            Binding::Symbol(sym) => Some(sym),
+            Binding::Variable(_) => None,
+        }
+    }
+
+    /// Define a name in the provided scope, failing if something with the
+    /// same name was already defined there.
+    ///
+    /// Shadowing a name from an outer scope is allowed.
+    pub fn define(
+        &mut self,
+        scope: ScopeId,
+        ident: &Identifier,
+        binding: Binding,
+    ) -> Result<(), Diagnostic> {
+        if let Some(diag) = self.check_for_duplicate(scope, ident) {
+            return Err(diag);
+        }
+
+        self.scope_mut(scope).names.insert(
+            ident.value.to_lowercase(),
+            Definition {
+                name: ident.value.clone(),
+                binding,
+                span: Some(ident.span),
+            },
+        );
+        Ok(())
     }
 
-    pub fn inner(&self) -> &HashMap<String, Symbol> {
-        &self.0
+    /// Find the definition a name refers to, starting at `scope` and working
+    /// outwards.
+    pub fn lookup(&self, scope: ScopeId, name: &str) -> Option<&Definition> {
+        let mut current = Some(scope);
+
+        while let Some(scope) = current {
+            if let Some(def) = self.lookup_local(scope, name) {
+                return Some(def);
+            }
+            current = self.parent(scope);
+        }
+
+        None
     }
 
-    pub fn inner_mut(&mut self) -> &mut HashMap<String, Symbol> {
-        &mut self.0
+    /// Find a name defined directly in `scope`, ignoring its ancestors.
+    pub fn lookup_local(
+        &self,
+        scope: ScopeId,
+        name: &str,
+    ) -> Option<&Definition> {
+        self.scope(scope).names.get(&name.to_lowercase())
     }
 
+    /// Everything defined directly in `scope`.
+    pub fn definitions(
+        &self,
+        scope: ScopeId,
+    ) -> impl Iterator<Item = &Definition> + '_ {
+        self.scope(scope).names.values()
+    }
+
+    /// Every definition visible from inside `scope`, including those which
+    /// have been shadowed.
+    pub fn visible(&self, scope: ScopeId) -> Vec<&Definition> {
+        let mut definitions = Vec::new();
+        let mut current = Some(scope);
+
+        while let Some(scope) = current {
+            definitions.extend(self.definitions(scope));
+            current = self.parent(scope);
+        }
+
+        definitions
+    }
+
+    /// Check whether defining `ident` in the global scope would clash with
+    /// an existing definition.
     pub fn check_for_duplicate_ident(
         &self,
-        ident: &iec_syntax::Identifier,
+        ident: &Identifier,
     ) -> Option<Diagnostic> {
-        if self.get(&ident.value).is_none() {
-            None
-        } else {
-            Some(
-                Diagnostic::new_error("Name is already declared").with_label(
-                    Label::new_primary(ident.span)
-                        .with_message("Duplicate declared here"),
-                ),
-            )
+        self.check_for_duplicate(self.global_scope(), ident)
+    }
+
+    /// Check whether defining `ident` in `scope` would clash with an existing
+    /// definition.
+    pub fn check_for_duplicate(
+        &self,
+        scope: ScopeId,
+        ident: &Identifier,
+    ) -> Option<Diagnostic> {
+        let original = self.lookup_local(scope, &ident.value)?;
+
+        let mut diag = Diagnostic::new_error("Name is already declared")
+            .with_label(
+                Label::new_primary(ident.span)
+                    .with_message("Duplicate declared here"),
+            );
+
+        if let Some(span) = original.span {
+            diag = diag.with_label(
+                Label::new_secondary(span)
+                    .with_message("Original declared here"),
+            );
+        }
+
+        Some(diag)
+    }
+
+    fn scope(&self, id: ScopeId) -> &Scope {
+        &self.scopes[id.0]
+    }
+
+    fn scope_mut(&mut self, id: ScopeId) -> &mut Scope {
+        &mut self.scopes[id.0]
+    }
+}
+
+impl Default for SymbolTable {
+    fn default() -> SymbolTable {
+        SymbolTable {
+            scopes: vec![Scope {
+                parent: None,
+                names: HashMap::new(),
+            }],
+            owners: HashMap::new(),
+            vacant: Vec::new(),
+        }
+    }
+}
+
+/// A handle to one of the scopes in a [`SymbolTable`].
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, Hash, HeapSizeOf, Serialize, Deserialize,
+)]
+pub struct ScopeId(usize);
+
+#[derive(Debug, Clone, PartialEq, HeapSizeOf, Serialize, Deserialize)]
+struct Scope {
+    parent: Option<ScopeId>,
+    /// The names defined in this scope, keyed by their lower-cased name.
+    names: HashMap<String, Definition>,
+}
+
+/// A name and the thing it refers to.
+#[derive(Debug, Clone, PartialEq, HeapSizeOf, Serialize, Deserialize)]
+pub struct Definition {
+    /// The name, as it was originally written.
+    pub name: String,
+    pub binding: Binding,
+    /// Where the name was defined, if it came from the source code.
+    pub span: Option<ByteSpan>,
+}
+
+impl Definition {
+    fn relocate(&mut self, offset: ByteOffset) {
+        if let Some(span) = self.span {
+            self.span =
+                Some(ByteSpan::new(span.start() + offset, span.end() + offset));
         }
     }
 }
 
+/// What a name may refer to.
+#[derive(Debug, Copy, Clone, PartialEq, HeapSizeOf, Serialize, Deserialize)]
+pub enum Binding {
+    Symbol(Symbol),
+    /// A [`crate::hir::Variable`].
+    Variable(EntityId),
+}
+
 #[derive(TypeName)]
 pub enum SymbolTableResolution {}
 
@@ -68,6 +315,8 @@ impl<'r> Pass<'r> for SymbolTableResolution {
         ReadWrite<'r, FunctionBlock>,
     );
     const DESCRIPTION: &'static str = "Find all know identifiers";
+    const REQUIRES: &'static [&'static str] = &["builtins"];
+    const PROVIDES: &'static [&'static str] = &["symbol-table"];
 
     fn run(
         arg: &iec_syntax::File,
@@ -95,6 +344,10 @@ impl<'r> Pass<'r> for SymbolTableResolution {
                     ctx,
                     &mut symbol_table,
                 ),
+                // global variables are resolved alongside all other variables
+                Item::VarBlock(_) => {}
+                // configurations are resolved once all variables are known
+                Item::Configuration(_) => {}
             }
         }
     }
@@ -114,9 +367,10 @@ fn register_program(
     let program = Program {
         name: p.name.value.clone(),
         variables: Vec::new(),
+        entry_block: EntityId::default(),
     };
     let program_id = programs.insert(program);
-    symbol_table.insert(&p.name.value, Symbol::Program(program_id));
+    declare(symbol_table, &p.name, Symbol::Program(program_id));
     slog::debug!(ctx.logger, "Found a program"; 
         "name" => &p.name.value,
         "id" => program_id);
@@ -136,10 +390,14 @@ fn register_function_block(
     let function_block = FunctionBlock {
         name: fb.name.value.clone(),
         variables: Vec::new(),
+        entry_block: EntityId::default(),
     };
     let function_block_id = function_blocks.insert(function_block);
-    symbol_table
-        .insert(&fb.name.value, Symbol::FunctionBlock(function_block_id));
+    declare(
+        symbol_table,
+        &fb.name,
+        Symbol::FunctionBlock(function_block_id),
+    );
     slog::debug!(ctx.logger, "Found a function block"; 
         "name" => &fb.name.value,
         "id" => function_block_id);
@@ -159,14 +417,26 @@ fn register_function(
     let function = Function {
         name: f.name.value.clone(),
         variables: Vec::new(),
+        return_type: EntityId::default(),
+        return_value: EntityId::default(),
+        entry_block: EntityId::default(),
     };
     let function_id = functions.insert(function);
-    symbol_table.insert(&f.name.value, Symbol::Function(function_id));
+    declare(symbol_table, &f.name, Symbol::Function(function_id));
     slog::debug!(ctx.logger, "Found a function"; 
         "name" => &f.name.value,
         "id" => function_id);
 }
 
+/// Add an item to the global scope and give it a scope of its own.
+fn declare(symbol_table: &mut SymbolTable, name: &Identifier, symbol: Symbol) {
+    let global = symbol_table.global_scope();
+    symbol_table
+        .define(global, name, Binding::Symbol(symbol))
+        .expect("we already checked for duplicates");
+    symbol_table.add_scope(global, symbol.into());
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -194,9 +464,10 @@ mod tests {
 
         // we should have updated the symbol table appropriately
         let symbol_table = resources.get_singleton::<SymbolTable>();
-        assert_eq!(symbol_table.0.len(), 2);
-        assert!(symbol_table.0.contains_key("main"));
-        assert!(symbol_table.0.contains_key("func"));
+        let global = symbol_table.global_scope();
+        assert_eq!(symbol_table.definitions(global).count(), 2);
+        assert!(symbol_table.lookup_local(global, "main").is_some());
+        assert!(symbol_table.lookup_local(global, "func").is_some());
 
         let programs = resources.get::<Program>();
         assert_eq!(programs.len(), 1);
@@ -211,4 +482,100 @@ mod tests {
         let func = function_blocks.get(symbol.into()).unwrap();
         assert_eq!(func.name, "FUnc");
     }
+
+    fn ident(name: &str, start: u32) -> Identifier {
+        Identifier {
+            value: name.to_string(),
+            span: ByteSpan::new(
+                codespan::ByteIndex(start),
+                codespan::ByteIndex(start + name.len() as u32),
+            ),
+        }
+    }
+
+    #[test]
+    fn inner_scopes_shadow_outer_ones() {
+        let mut symbols = SymbolTable::default();
+        let global = symbols.global_scope();
+        let ty = EntityId::default();
+        symbols.insert("timer", Symbol::Type(ty));
+        let program = symbols.add_scope(global, ty);
+        let local = Binding::Variable(EntityId::default());
+
+        symbols.define(program, &ident("Timer", 10), local).unwrap();
+
+        assert_eq!(symbols.lookup(program, "TIMER").unwrap().binding, local);
+        assert_eq!(symbols.get("timer"), Some(Symbol::Type(ty)));
+        assert_eq!(
+            symbols.lookup(global, "timer").unwrap().binding,
+            Binding::Symbol(Symbol::Type(ty))
+        );
+        assert_eq!(symbols.scope_of(ty), Some(program));
+        assert_eq!(symbols.parent(program), Some(global));
+    }
+
+    #[test]
+    fn duplicates_point_at_the_original_definition() {
+        let mut symbols = SymbolTable::default();
+        let global = symbols.global_scope();
+        let binding = Binding::Variable(EntityId::default());
+        let original = ident("x", 5);
+        symbols.define(global, &original, binding).unwrap();
+
+        let diag = symbols
+            .define(global, &ident("X", 20), binding)
+            .unwrap_err();
+
+        assert_eq!(diag.labels.len(), 2);
+        assert_eq!(diag.labels[1].span, original.span);
+    }
+
+    #[test]
+    fn removed_items_give_their_scope_back() {
+        let mut symbols = SymbolTable::default();
+        let global = symbols.global_scope();
+        let first = Symbol::Program(EntityId::default());
+        symbols
+            .define(global, &ident("main", 8), Binding::Symbol(first))
+            .unwrap();
+        let scope = symbols.add_scope(global, first.into());
+        let x = Binding::Variable(EntityId::default());
+        symbols.define(scope, &ident("x", 20), x).unwrap();
+
+        symbols.relocate_item("MAIN", ByteOffset(4));
+
+        let span = symbols.lookup(scope, "x").unwrap().span.unwrap();
+        assert_eq!(span, ident("x", 24).span);
+        assert_eq!(symbols.remove_item("main"), Some(first));
+        assert!(symbols.get("main").is_none());
+        assert!(symbols.scope_of(first.into()).is_none());
+
+        let reused = symbols.add_scope(global, EntityId::default());
+        assert_eq!(reused, scope);
+        assert_eq!(symbols.definitions(reused).count(), 0);
+    }
+
+    #[test]
+    fn duplicates_are_detected_across_files() {
+        let mut map = codespan::CodeMap::new();
+        let first = map
+            .add_filemap("first.st".into(), "PROGRAM main END_PROGRAM".into());
+        let second = map.add_filemap(
+            "second.st".into(),
+            "FUNCTION_BLOCK Main BEGIN END_FUNCTION_BLOCK".into(),
+        );
+        let ast = File::merge(vec![
+            File::from_filemap(&first).unwrap(),
+            File::from_filemap(&second).unwrap(),
+        ]);
+        let mut diags = Diagnostics::new();
+
+        crate::process(&ast, &mut PassContext::new_nop_logger(&mut diags));
+
+        let diags = diags.diagnostics();
+        assert_eq!(diags.len(), 1);
+        let labels = &diags[0].labels;
+        assert_eq!(second.src_slice(labels[0].span).unwrap(), "Main");
+        assert_eq!(first.src_slice(labels[1].span).unwrap(), "main");
+    }
 }
@@ -1,11 +1,16 @@
-use super::symbol_table::SymbolTable;
+use super::symbol_table::{Binding, ScopeId, SymbolTable};
 use super::{Pass, PassContext};
-use crate::ecs::{Container, EntityId, ReadWrite, Singleton};
-use crate::hir::{Function, FunctionBlock, Program, Symbol, Variable};
+use crate::ecs::{Container, EntityId, ReadWrite, SingletonMut};
+use crate::hir::{
+    Function, FunctionBlock, GlobalVariables, Location, Program, Retained,
+    Span, Symbol, Variable, VariableKind,
+};
 use crate::Diagnostics;
 use codespan_reporting::{Diagnostic, Label};
-use iec_syntax::Item;
-use std::collections::HashMap;
+use iec_syntax::{
+    DirectAddress, DottedIdentifier, Expression, FunctionArg, FunctionCall,
+    Identifier, Item, Statement, VarBlockKind,
+};
 use typename::TypeName;
 
 #[derive(TypeName)]
@@ -14,13 +19,19 @@ pub enum VariableDiscovery {}
 impl<'r> Pass<'r> for VariableDiscovery {
     type Arg = iec_syntax::File;
     type Storage = (
-        Singleton<'r, SymbolTable>,
+        SingletonMut<'r, SymbolTable>,
         ReadWrite<'r, Variable>,
         ReadWrite<'r, Program>,
         ReadWrite<'r, Function>,
         ReadWrite<'r, FunctionBlock>,
+        ReadWrite<'r, GlobalVariables>,
+        ReadWrite<'r, Span>,
+        ReadWrite<'r, Location>,
+        ReadWrite<'r, Retained>,
     );
     const DESCRIPTION: &'static str = "Resolve variable declarations in each program, function, or function block";
+    const REQUIRES: &'static [&'static str] = &["symbol-table"];
+    const PROVIDES: &'static [&'static str] = &["variables"];
 
     fn run(
         args: &Self::Arg,
@@ -28,29 +39,114 @@ impl<'r> Pass<'r> for VariableDiscovery {
         storage: Self::Storage,
     ) {
         let (
-            symbol_table,
+            mut symbol_table,
             mut variables,
             mut programs,
             mut functions,
             mut function_blocks,
+            mut global_variables,
+            mut spans,
+            mut locations,
+            mut retained,
         ) = storage;
 
+        // variables declared inside a configuration or resource are
+        // accessible from everywhere, just like top-level globals
+        let global_blocks: Vec<_> = args
+            .items
+            .iter()
+            .flat_map(|item| match item {
+                Item::VarBlock(ref block) => vec![block.clone()],
+                Item::Configuration(ref config) => config
+                    .globals
+                    .iter()
+                    .chain(config.resources.iter().flat_map(|r| &r.globals))
+                    .cloned()
+                    .collect(),
+                _ => Vec::new(),
+            })
+            .collect();
+
+        if !global_blocks.is_empty() {
+            let id = global_variables.insert(GlobalVariables {
+                variables: Vec::new(),
+            });
+            let global_scope = symbol_table.global_scope();
+            let variable_ids = resolve_variables(
+                Symbol::GlobalVariables(id),
+                global_scope,
+                &mut symbol_table,
+                &global_blocks,
+                &mut variables,
+                &mut spans,
+                ctx.diags,
+            );
+            annotate_variables(
+                global_scope,
+                &mut symbol_table,
+                &global_blocks,
+                &mut locations,
+                &mut retained,
+            );
+
+            slog::debug!(ctx.logger, "Analysed global variables";
+                "variable-count" => variable_ids.len());
+            global_variables
+                .get_mut(id)
+                .expect("we just inserted it")
+                .variables = variable_ids;
+        }
+
         for item in &args.items {
-            let (var_blocks, name) = match item {
-                Item::Program(ref p) => (&p.var_blocks, &p.name.value),
-                Item::Function(ref f) => (&f.var_blocks, &f.name.value),
-                Item::FunctionBlock(ref fb) => (&fb.var_blocks, &fb.name.value),
+            let (var_blocks, ident, return_type) = match item {
+                Item::Program(ref p) => (&p.var_blocks, &p.name, None),
+                Item::Function(ref f) => {
+                    (&f.var_blocks, &f.name, Some(&f.return_value))
+                }
+                Item::FunctionBlock(ref fb) => (&fb.var_blocks, &fb.name, None),
+                Item::VarBlock(_) | Item::Configuration(_) => continue,
             };
+            let name = &ident.value;
             let symbol = symbol_table.get(name)
                 .expect("We should have found all symbols when constructing the symbol table");
 
+            if !refers_to(symbol, item) {
+                // the duplicate name was already reported while building the
+                // symbol table, we just can't tell which variables are ours
+                ctx.diags.push(
+                    Diagnostic::new_error(
+                        "Unable to resolve this item's variables",
+                    )
+                    .with_label(
+                        Label::new_primary(ident.span).with_message(format!(
+                            "\"{}\" refers to a different kind of item",
+                            name
+                        )),
+                    ),
+                );
+                continue;
+            }
+
+            let scope = symbol_table
+                .scope_of(symbol.into())
+                .expect("Every item is given a scope alongside its symbol");
+
             let variable_ids = resolve_variables(
                 symbol,
-                &symbol_table,
+                scope,
+                &mut symbol_table,
                 var_blocks,
                 &mut variables,
+                &mut spans,
                 ctx.diags,
             );
+            annotate_variables(
+                scope,
+                &mut symbol_table,
+                var_blocks,
+                &mut locations,
+                &mut retained,
+            );
 
             slog::debug!(ctx.logger, "Analysed item";
                 "name" => name,
@@ -66,86 +162,394 @@ impl<'r> Pass<'r> for VariableDiscovery {
                     p.variables = variable_ids;
                 }
                 Symbol::Function(f) => {
+                    check_for_retained_locals(var_blocks, ctx.diags);
                     let f = functions.get_mut(f).expect(ERR_MSG);
                     f.variables = variable_ids;
+
+                    if let Some(ty) = return_type.and_then(|return_type| {
+                        resolve_type(&symbol_table, return_type, ctx.diags)
+                    }) {
+                        // functions return a value by assigning to a
+                        // variable with the same name as the function
+                        let return_value = variables.insert(Variable {
+                            parent: symbol,
+                            ty,
+                            name: Some(f.name.clone()),
+                            kind: VariableKind::ReturnValue,
+                        });
+                        spans.attach(return_value, Span(ident.span));
+                        f.return_type = ty;
+                        f.return_value = return_value;
+                        f.variables.push(return_value);
+
+                        // the return value shadows the function itself
+                        let defined = symbol_table.define(
+                            scope,
+                            ident,
+                            Binding::Variable(return_value),
+                        );
+                        if let Err(diag) = defined {
+                            ctx.diags.push(diag);
+                        }
+                    }
                 }
                 Symbol::FunctionBlock(fb) => {
                     let fb = function_blocks.get_mut(fb).expect(ERR_MSG);
                     fb.variables = variable_ids;
                 }
-                Symbol::Type(_) => unreachable!(),
+                // rejected by refers_to() above
+                Symbol::Type(_) | Symbol::GlobalVariables(_) => {}
             }
         }
+
+        declare_direct_addresses(
+            &args.items,
+            &mut symbol_table,
+            &mut variables,
+            &mut global_variables,
+            &mut spans,
+            &mut locations,
+        );
     }
 }
 
+/// Does the [`Symbol`] found when looking up an item's name actually refer
+/// to that item?
+fn refers_to(symbol: Symbol, item: &Item) -> bool {
+    matches!(
+        (symbol, item),
+        (Symbol::Program(_), Item::Program(_))
+            | (Symbol::Function(_), Item::Function(_))
+            | (Symbol::FunctionBlock(_), Item::FunctionBlock(_))
+    )
+}
+
+/// Create a [`Variable`] for each declaration, defining it in the item's
+/// scope.
 fn resolve_variables(
-    parent_scope: Symbol,
-    symbol_table: &SymbolTable,
+    parent: Symbol,
+    scope: ScopeId,
+    symbol_table: &mut SymbolTable,
     blocks: &[iec_syntax::VarBlock],
     variables: &mut Container<Variable>,
+    spans: &mut Container<Span>,
     diags: &mut Diagnostics,
 ) -> Vec<EntityId> {
-    let mut names = HashMap::new();
     let mut ids = Vec::new();
 
     for block in blocks {
         for decl in &block.declarations {
-            let name = &decl.ident.value;
-            let to_lower = name.to_lowercase();
-
-            if let Some(&original_span) = names.get(&to_lower) {
-                diags.push(
-                    Diagnostic::new_error("Duplicate variable declarations")
-                        .with_label(
-                            Label::new_primary(decl.ident.span)
-                                .with_message("Duplicate declared here"),
-                        )
-                        .with_label(
-                            Label::new_secondary(original_span).with_message(
-                                "Original variable was declared here",
-                            ),
-                        ),
-                );
+            if let Some(diag) =
+                symbol_table.check_for_duplicate(scope, &decl.ident)
+            {
+                diags.push(diag);
                 continue;
             }
 
-            let ty = symbol_table.get(&decl.ty.value);
+            let type_id = match symbol_table.get(&decl.ty.value) {
+                Some(Symbol::FunctionBlock(id)) => id,
+                _ => match resolve_type(symbol_table, &decl.ty, diags) {
+                    Some(id) => id,
+                    None => continue,
+                },
+            };
 
-            let type_id = match ty {
-                Some(Symbol::Type(id)) => id,
-                Some(_) => {
-                    diags.push(
-                        Diagnostic::new_error("Expected the name of a type")
-                            .with_label(Label::new_primary(decl.ty.span)),
-                    );
-                    continue;
+            let id = variables.insert(Variable {
+                parent,
+                ty: type_id,
+                name: Some(decl.ident.value.clone()),
+                kind: variable_kind(block.kind),
+            });
+            spans.attach(id, Span(decl.ident.span));
+            symbol_table
+                .define(scope, &decl.ident, Binding::Variable(id))
+                .expect("we already checked for duplicates");
+            ids.push(id);
+        }
+    }
+
+    ids
+}
+
+/// Attach a [`Location`] to each variable declared with `AT`, letting it be
+/// referred to by its address as well as its name, and mark variables from
+/// `RETAIN` or `PERSISTENT` blocks as [`Retained`].
+fn annotate_variables(
+    scope: ScopeId,
+    symbol_table: &mut SymbolTable,
+    blocks: &[iec_syntax::VarBlock],
+    locations: &mut Container<Location>,
+    retained: &mut Container<Retained>,
+) {
+    for block in blocks {
+        for decl in &block.declarations {
+            if let Some(id) = declared_variable(scope, symbol_table, decl) {
+                if block.retention.is_retained() {
+                    retained.attach(id, Retained(block.retention));
                 }
-                None => {
-                    diags.push(
-                        Diagnostic::new_error("Unknown type")
-                            .with_label(Label::new_primary(decl.ty.span)),
+                if let Some(ref address) = decl.location {
+                    locate_variable(
+                        scope,
+                        symbol_table,
+                        id,
+                        address,
+                        locations,
                     );
-                    continue;
                 }
+            }
+        }
+    }
+}
+
+/// Find the [`Variable`] created for a declaration.
+fn declared_variable(
+    scope: ScopeId,
+    symbol_table: &SymbolTable,
+    decl: &iec_syntax::Declaration,
+) -> Option<EntityId> {
+    match symbol_table.lookup_local(scope, &decl.ident.value) {
+        // a duplicate declaration will find the original variable
+        Some(def) if def.span == Some(decl.ident.span) => match def.binding {
+            Binding::Variable(id) => Some(id),
+            Binding::Symbol(_) => None,
+        },
+        _ => None,
+    }
+}
+
+fn locate_variable(
+    scope: ScopeId,
+    symbol_table: &mut SymbolTable,
+    id: EntityId,
+    address: &DirectAddress,
+    locations: &mut Container<Location>,
+) {
+    locations.attach(id, Location(address.clone()));
+
+    // addresses used more than once are reported when laying out the
+    // process image
+    let alias = Identifier {
+        value: address.to_string(),
+        span: address.span,
+    };
+    if symbol_table.lookup_local(scope, &alias.value).is_none() {
+        symbol_table
+            .define(scope, &alias, Binding::Variable(id))
+            .expect("we just checked for duplicates");
+    }
+}
+
+/// Give each direct address used in a body without an `AT` declaration (e.g.
+/// `%IW2 + 1`) an implicit global variable, so it is laid out in the process
+/// image like any other located variable.
+fn declare_direct_addresses(
+    items: &[Item],
+    symbol_table: &mut SymbolTable,
+    variables: &mut Container<Variable>,
+    global_variables: &mut Container<GlobalVariables>,
+    spans: &mut Container<Span>,
+    locations: &mut Container<Location>,
+) {
+    let global_scope = symbol_table.global_scope();
+
+    for item in items {
+        let (body, name) = match item {
+            Item::Program(ref p) => (&p.body, &p.name),
+            Item::Function(ref f) => (&f.body, &f.name),
+            Item::FunctionBlock(ref fb) => (&fb.body, &fb.name),
+            Item::VarBlock(_) | Item::Configuration(_) => continue,
+        };
+        let scope = match symbol_table
+            .get(&name.value)
+            .and_then(|symbol| symbol_table.scope_of(symbol.into()))
+        {
+            Some(scope) => scope,
+            None => continue,
+        };
+
+        let mut addresses = Vec::new();
+        direct_addresses(body, &mut addresses);
+
+        for address in addresses {
+            if symbol_table.lookup(scope, &address.to_string()).is_some() {
+                continue;
+            }
+            let ty = match symbol_table.get(implicit_type(address.size)) {
+                Some(Symbol::Type(id)) => id,
+                _ => continue,
             };
 
-            names.insert(to_lower, decl.ident.span);
+            let existing = global_variables.iter().next().map(|(id, _)| id);
+            let globals = match existing {
+                Some(id) => id,
+                None => global_variables.insert(GlobalVariables {
+                    variables: Vec::new(),
+                }),
+            };
             let id = variables.insert(Variable {
-                parent: parent_scope,
-                ty: type_id,
-                name: Some(name.clone()),
+                parent: Symbol::GlobalVariables(globals),
+                ty,
+                name: Some(address.to_string()),
+                kind: VariableKind::Global,
             });
-            ids.push(id);
+            spans.attach(id, Span(address.span));
+            locate_variable(
+                global_scope,
+                symbol_table,
+                id,
+                &address,
+                locations,
+            );
+            global_variables
+                .get_mut(globals)
+                .expect("we just found it")
+                .variables
+                .push(id);
         }
     }
+}
 
-    ids
+/// The type of a direct address which wasn't declared with `AT`.
+fn implicit_type(size: iec_syntax::Size) -> &'static str {
+    match size {
+        iec_syntax::Size::Bit => "bool",
+        iec_syntax::Size::Byte => "byte",
+        iec_syntax::Size::Word => "word",
+        iec_syntax::Size::DoubleWord => "dword",
+        iec_syntax::Size::LongWord => "lword",
+    }
+}
+
+/// Find every direct address read or written by some statements, in order.
+fn direct_addresses(statements: &[Statement], found: &mut Vec<DirectAddress>) {
+    fn path(path: &DottedIdentifier, found: &mut Vec<DirectAddress>) {
+        // assigning to an address turns it into a single-piece path
+        if let [ref ident] = path.pieces[..] {
+            if ident.value.starts_with('%') {
+                if let Ok(address) =
+                    DirectAddress::parse(&ident.value, ident.span)
+                {
+                    found.push(address);
+                }
+            }
+        }
+    }
+
+    fn call(call: &FunctionCall, found: &mut Vec<DirectAddress>) {
+        for arg in &call.args {
+            match arg {
+                FunctionArg::Bare(ref expr) => expression(expr, found),
+                FunctionArg::Named(ref assign) => {
+                    expression(&assign.value, found)
+                }
+                FunctionArg::Output(ref out) => path(&out.variable, found),
+            }
+        }
+    }
+
+    fn expression(expr: &Expression, found: &mut Vec<DirectAddress>) {
+        match expr {
+            Expression::Literal(_) | Expression::Variable(_) => {}
+            Expression::DirectAddress(ref address) => {
+                found.push(address.clone())
+            }
+            Expression::Binary(ref bin) => {
+                expression(&bin.left, found);
+                expression(&bin.right, found);
+            }
+            Expression::Unary(ref un) => expression(&un.value, found),
+            Expression::FunctionCall(ref c) => call(c, found),
+        }
+    }
+
+    for statement in statements {
+        match statement {
+            Statement::Assignment(ref a) => {
+                expression(&a.value, found);
+                path(&a.variable, found);
+            }
+            Statement::FunctionCall(ref c) => call(c, found),
+            Statement::ForLoop(ref f) => {
+                expression(&f.start, found);
+                expression(&f.end, found);
+                if let Some(ref step) = f.step {
+                    expression(step, found);
+                }
+                direct_addresses(&f.body, found);
+            }
+            Statement::WhileLoop(ref w) => {
+                expression(&w.condition, found);
+                direct_addresses(&w.body, found);
+            }
+            Statement::RepeatLoop(ref r) => {
+                direct_addresses(&r.body, found);
+                expression(&r.condition, found);
+            }
+            Statement::IfStatement(ref i) => {
+                expression(&i.condition, found);
+                direct_addresses(&i.body, found);
+            }
+            Statement::Exit(_) | Statement::Return(_) => {}
+        }
+    }
+}
+
+/// A function's locals are recreated every time it is called, so there is
+/// nothing to retain.
+fn check_for_retained_locals(
+    blocks: &[iec_syntax::VarBlock],
+    diags: &mut Diagnostics,
+) {
+    for block in blocks.iter().filter(|b| b.retention.is_retained()) {
+        diags.push(
+            Diagnostic::new_error(
+                "Functions can't have RETAIN or PERSISTENT variables",
+            )
+            .with_label(Label::new_primary(block.span)),
+        );
+    }
+}
+
+fn variable_kind(kind: VarBlockKind) -> VariableKind {
+    match kind {
+        VarBlockKind::Local => VariableKind::Local,
+        VarBlockKind::Input => VariableKind::Input,
+        VarBlockKind::Output => VariableKind::Output,
+        VarBlockKind::InputOutput => VariableKind::InOut,
+        VarBlockKind::Temp => VariableKind::Temp,
+        VarBlockKind::Global => VariableKind::Global,
+    }
+}
+
+fn resolve_type(
+    symbol_table: &SymbolTable,
+    name: &iec_syntax::Identifier,
+    diags: &mut Diagnostics,
+) -> Option<EntityId> {
+    match symbol_table.get(&name.value) {
+        Some(Symbol::Type(id)) => Some(id),
+        Some(_) => {
+            diags.push(
+                Diagnostic::new_error("Expected the name of a type")
+                    .with_label(Label::new_primary(name.span)),
+            );
+            None
+        }
+        None => {
+            diags.push(
+                Diagnostic::new_error("Unknown type")
+                    .with_label(Label::new_primary(name.span)),
+            );
+            None
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ecs::Join;
+    use iec_syntax::Retention;
 
     #[test]
     fn discover_some_variables() {
@@ -153,14 +557,16 @@ mod tests {
         let mut variables = Container::default();
         let mut diags = Diagnostics::new();
         let mut symbols = SymbolTable::default();
-
         symbols.insert("int", Symbol::Type(EntityId::default()));
+        let scope = symbols.global_scope();
 
         let got = resolve_variables(
             Symbol::Function(EntityId::default()),
-            &symbols,
+            scope,
+            &mut symbols,
             &[block],
             &mut variables,
+            &mut Container::default(),
             &mut diags,
         );
 
@@ -181,18 +587,23 @@ mod tests {
         let mut diags = Diagnostics::new();
         let mut symbols = SymbolTable::default();
         symbols.insert("int", Symbol::Type(EntityId::default()));
+        let scope = symbols.global_scope();
 
         let got = resolve_variables(
             Symbol::Function(EntityId::default()),
-            &symbols,
+            scope,
+            &mut symbols,
             &[block],
             &mut variables,
+            &mut Container::default(),
             &mut diags,
         );
 
         assert!(diags.has_errors());
         assert_eq!(got.len(), 1);
         assert_eq!(variables.len(), 1);
+        // the diagnostic should also point at the original declaration
+        assert_eq!(diags.diagnostics()[0].labels.len(), 2);
     }
 
     #[test]
@@ -200,13 +611,16 @@ mod tests {
         let block = iec_syntax::quote!(var { x: int; y: string; });
         let mut variables = Container::default();
         let mut diags = Diagnostics::new();
-        let symbols = SymbolTable::default();
+        let mut symbols = SymbolTable::default();
+        let scope = symbols.global_scope();
 
         let got = resolve_variables(
             Symbol::Function(EntityId::default()),
-            &symbols,
+            scope,
+            &mut symbols,
             &[block],
             &mut variables,
+            &mut Container::default(),
             &mut diags,
         );
 
@@ -215,4 +629,103 @@ mod tests {
         assert!(got.is_empty());
         assert!(variables.is_empty());
     }
+
+    #[test]
+    fn mark_retained_variables() {
+        let src = "
+            VAR_GLOBAL RETAIN
+                total : dint;
+            END_VAR
+
+            PROGRAM main
+            VAR
+                scratch : int;
+            END_VAR
+            VAR PERSISTENT
+                setpoint : int;
+            END_VAR
+            END_PROGRAM
+
+            FUNCTION add_one : int
+            BEGIN
+            VAR_INPUT RETAIN
+                x : int;
+            END_VAR
+                add_one := x + 1;
+            END_FUNCTION";
+        let ast: iec_syntax::File = src.parse().unwrap();
+        let mut diags = Diagnostics::new();
+        let cu =
+            crate::process(&ast, &mut PassContext::new_nop_logger(&mut diags));
+
+        let messages: Vec<_> =
+            diags.diagnostics().iter().map(|d| &d.message).collect();
+        assert_eq!(
+            messages,
+            vec!["Functions can't have RETAIN or PERSISTENT variables"]
+        );
+
+        let variables = cu.resources.get::<Variable>();
+        let retained = cu.resources.get::<Retained>();
+        let mut retained: Vec<_> = (&*variables, &*retained)
+            .join()
+            .map(|(_, (v, r))| (v.name.clone(), r.0))
+            .collect();
+        retained.sort_by(|a, b| a.0.cmp(&b.0));
+
+        assert_eq!(
+            retained,
+            vec![
+                (Some(String::from("setpoint")), Retention::Persistent),
+                (Some(String::from("total")), Retention::Retain),
+                (Some(String::from("x")), Retention::Retain),
+            ]
+        );
+    }
+
+    #[test]
+    fn functions_and_function_blocks_with_the_same_name() {
+        let src = "
+            FUNCTION thing : int
+            BEGIN
+            VAR_INPUT
+                x : int;
+            END_VAR
+                thing := x;
+            END_FUNCTION
+
+            FUNCTION_BLOCK thing
+            VAR_INPUT
+                y : int;
+            END_VAR
+            BEGIN
+            END_FUNCTION_BLOCK";
+        let ast: iec_syntax::File = src.parse().unwrap();
+        let mut resources = crate::ecs::Resources::new();
+        let mut diags = Diagnostics::new();
+        let mut ctx = PassContext::new_nop_logger(&mut diags);
+
+        // run the passes by hand so we keep going after the symbol table
+        // reports the duplicate name
+        crate::passes::run_pass::<crate::passes::RegisterBuiltins>(
+            &mut resources,
+            &(),
+            &mut ctx,
+        );
+        crate::passes::run_pass::<crate::passes::SymbolTableResolution>(
+            &mut resources,
+            &ast,
+            &mut ctx,
+        );
+        crate::passes::run_pass::<VariableDiscovery>(
+            &mut resources,
+            &ast,
+            &mut ctx,
+        );
+
+        let messages: Vec<_> =
+            diags.diagnostics().iter().map(|d| &d.message).collect();
+        assert_eq!(messages.len(), 2, "{:?}", messages);
+        assert_eq!(messages[1], "Unable to resolve this item's variables");
+    }
 }
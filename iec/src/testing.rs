@@ -0,0 +1,184 @@
+//! Helpers for making sure a backend's generated code behaves the same as the
+//! [`Interpreter`].
+//!
+//! These are only meant to be used by tests, so they panic instead of
+//! returning errors. Enable the `testing` feature to use them.
+
+use crate::interpreter::{Interpreter, Value};
+use crate::layout::{self, Declarations, Layout, PouKind};
+use crate::passes::PassContext;
+use crate::{CompilationUnit, Diagnostics};
+use byteorder::NativeEndian;
+use iec_syntax::File;
+use std::fmt::Write;
+
+/// Parse and analyse a program, making sure it doesn't contain any errors.
+pub fn analyse(src: &str) -> (File, CompilationUnit) {
+    let ast: File = src.parse().unwrap();
+    let mut diags = Diagnostics::new();
+    let cu = crate::process(&ast, &mut PassContext::new_nop_logger(&mut diags));
+    assert!(!diags.has_errors(), "{:?}", diags);
+
+    (ast, cu)
+}
+
+/// A program which was run by a backend, so its variables can be inspected.
+pub trait Execution {
+    /// Read a variable using its dotted path (e.g. `main.counter.total` or
+    /// the name of a global).
+    fn get(&self, path: &str) -> Option<Value>;
+}
+
+/// Run `src` for `cycles` cycles using both the [`Interpreter`] and
+/// `execute`, making sure they end up with the same value for every variable
+/// in `paths`.
+///
+/// `execute` should compile the program and run it for `cycles` cycles. It
+/// can return `None` to skip the comparison when the backend can't be used
+/// (e.g. because there is no C compiler installed).
+pub fn compare<E, F>(
+    src: &str,
+    cycles: u64,
+    paths: &[&str],
+    execute: F,
+) -> Option<E>
+where
+    E: Execution,
+    F: FnOnce(&CompilationUnit) -> Option<E>,
+{
+    let (ast, cu) = analyse(src);
+
+    let mut interpreter = Interpreter::new(&ast, &cu);
+    interpreter.run_cycles(cycles).unwrap();
+    let execution = execute(&cu)?;
+
+    for path in paths {
+        let expected = interpreter.get(path);
+        assert!(expected.is_some(), "\"{}\" doesn't exist", path);
+        assert_eq!(execution.get(path).as_ref(), expected, "{}", path);
+    }
+
+    Some(execution)
+}
+
+/// Generate a `main.c` for code which was compiled natively (e.g. by the C or
+/// LLVM backends).
+///
+/// It runs `cycles` cycles then prints the memory used by the global
+/// variables and each program instance, to be read by [`Snapshot::parse()`].
+/// If a cycle fails, the cycle number and status code are printed instead
+/// (e.g. `"3: 1"`) and the program exits with `1`.
+pub fn snapshot_main(cu: &CompilationUnit, cycles: u64) -> String {
+    let declarations = Declarations::new(cu).unwrap();
+    let layout = native_layout(declarations.clone());
+    let programs: Vec<_> = declarations
+        .pous
+        .iter()
+        .zip(&layout.pous)
+        .filter(|(pou, _)| pou.kind == PouKind::Program)
+        .map(|(pou, frame)| (pou.instance_name(), frame.frame.size))
+        .collect();
+
+    let mut main = String::from(
+        "#include <stddef.h>\n#include <stdint.h>\n#include <stdio.h>\n\n",
+    );
+    main.push_str("uint32_t iec_run_cycle(void);\n");
+    main.push_str("extern struct iec_memory iec_globals;\n");
+    for (instance, _) in &programs {
+        writeln!(main, "extern struct iec_memory {};", instance).unwrap();
+    }
+
+    main.push_str(
+        "\nstatic void dump(const void *memory, size_t size) {
+    const unsigned char *bytes = memory;
+    for (size_t i = 0; i < size; i++) {
+        printf(\"%02x\", bytes[i]);
+    }
+    printf(\"\\n\");
+}
+
+int main(void) {\n",
+    );
+    writeln!(main, "    for (int i = 0; i < {}; i++) {{", cycles).unwrap();
+    main.push_str(
+        "        uint32_t status = iec_run_cycle();
+        if (status != 0) {
+            printf(\"%d: %u\\n\", i + 1, status);
+            return 1;
+        }
+    }\n",
+    );
+    writeln!(main, "    dump(&iec_globals, {});", layout.globals.size).unwrap();
+    for (instance, size) in &programs {
+        writeln!(main, "    dump(&{}, {});", instance, size).unwrap();
+    }
+    main.push_str("    return 0;\n}\n");
+
+    main
+}
+
+/// The memory used by a natively compiled program, as printed by the `main()`
+/// from [`snapshot_main()`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    layout: Layout,
+    globals: Vec<u8>,
+    /// The memory for each program, indexed by [`Layout::pous`].
+    instances: Vec<Option<Vec<u8>>>,
+}
+
+impl Snapshot {
+    /// Read the memory printed by the `main()` from [`snapshot_main()`]
+    /// after every cycle succeeded.
+    pub fn parse(cu: &CompilationUnit, stdout: &str) -> Snapshot {
+        let layout = native_layout(Declarations::new(cu).unwrap());
+        let mut lines = stdout.lines().map(|line| {
+            (0..line.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&line[i..i + 2], 16).unwrap())
+                .collect::<Vec<u8>>()
+        });
+
+        let globals = lines.next().expect("the globals should be printed");
+        let instances = layout
+            .pous
+            .iter()
+            .map(|pou| match pou.kind {
+                PouKind::Program => lines.next(),
+                _ => None,
+            })
+            .collect();
+
+        Snapshot {
+            layout,
+            globals,
+            instances,
+        }
+    }
+}
+
+impl Execution for Snapshot {
+    fn get(&self, path: &str) -> Option<Value> {
+        let resolved = self.layout.resolve(path)?;
+        let memory = match resolved.program {
+            Some(pou) => self.instances[pou].as_ref()?,
+            None => &self.globals,
+        };
+
+        layout::read::<NativeEndian>(
+            memory,
+            resolved.offset as usize,
+            resolved.ty,
+        )
+    }
+}
+
+/// Natively compiled code keeps its temporaries on the stack rather than in a
+/// POU's instance data.
+fn native_layout(mut declarations: Declarations) -> Layout {
+    for pou in &mut declarations.pous {
+        pou.temporaries.clear();
+    }
+
+    Layout::from_declarations(declarations).unwrap()
+}
@@ -9,11 +9,18 @@ use codespan_reporting::termcolor::{ColorChoice, StandardStream};
 use codespan_reporting::{Diagnostic, Label};
 use failure::{Error, ResultExt};
 use heapsize::HeapSizeOf;
-use iec::passes::PassContext;
-use iec::{CompilationUnit, Diagnostics};
-use iec_syntax::File;
+use iec::bytecode::{self, Module, Vm};
+use iec::hir::{Configuration, ProcessImage, Program, Variable};
+use iec::interpreter::{Interpreter, Overflow};
+use iec::passes::{PassContext, PassOptions, Report, Stop};
+use iec::{CompilationUnit, Diagnostics, OptimizationLevel};
+use iec_codegen_cranelift::{Jit, PouKind};
+use iec_runtime::{Clock, Runtime, SimulatedClock, Target, TaskConfig};
+use iec_syntax::{File, Item};
 use slog::{Drain, Level, Logger};
 use slog_derive::KV;
+use std::fmt::{self, Display, Formatter};
+use std::path::Path;
 use std::str::FromStr;
 use std::time::Instant;
 use structopt::StructOpt;
@@ -43,28 +50,62 @@ fn main() {
 fn run(args: &Args, logger: &Logger) -> Result<(), Error> {
     slog::info!(logger, "Started the application"; &args);
     let start = Instant::now();
+    let options = args.pass_options();
+    options
+        .validate(&iec::passes::pass_names())
+        .context("Invalid pass selection")?;
+
+    if let Some(Command::Run {
+        ref files,
+        cycles,
+        saturate,
+        ..
+    }) = args.command
+    {
+        if files.iter().all(|f| is_bytecode(f)) {
+            let module = load_bytecode(files)?;
+            return execute(module, cycles, overflow(saturate), logger);
+        }
+    }
+
     let mut map = CodeMap::new();
+    let mut filemaps = Vec::new();
 
-    let fm = map
-        .add_filemap_from_disk(&args.file)
-        .context("Unable to read the file into memory")?;
+    for filename in args.files() {
+        let fm = map
+            .add_filemap_from_disk(filename)
+            .with_context(|_| format!("Unable to read \"{}\"", filename))?;
 
-    slog::debug!(logger, "Read the file to disk"; 
-        "filename" => fm.name().as_ref().display(),
-        "size" => fm.src().len());
+        slog::debug!(logger, "Read the file to disk";
+            "filename" => fm.name().as_ref().display(),
+            "size" => fm.src().len());
+        filemaps.push(fm);
+    }
 
     let syntax_logger = logger.new(slog::o!("stage" => "syntactic-analysis"));
-    slog::debug!(syntax_logger, "Starting syntactic analysis");
+    slog::debug!(syntax_logger, "Starting syntactic analysis";
+        "file-count" => filemaps.len());
     let start_syntax = Instant::now();
 
-    let file = match syntactic_analysis(&fm) {
-        Ok(f) => f,
-        Err(e) => {
-            let ss = StandardStream::stdout(ColorChoice::Auto);
-            codespan_reporting::emit(ss, &map, &e)?;
-            return Ok(());
+    let mut files = Vec::new();
+    let mut parse_errors = Vec::new();
+
+    for fm in &filemaps {
+        match syntactic_analysis(fm) {
+            Ok(f) => files.push(f),
+            Err(e) => parse_errors.push(e),
         }
-    };
+    }
+
+    if !parse_errors.is_empty() {
+        let mut ss = StandardStream::stdout(ColorChoice::Auto);
+        for e in &parse_errors {
+            codespan_reporting::emit(&mut ss, &map, e)?;
+        }
+        return Ok(());
+    }
+
+    let file = File::merge(files);
 
     let duration = Instant::now() - start_syntax;
     slog::debug!(syntax_logger, "Finished syntactic analysis"; 
@@ -77,13 +118,24 @@ fn run(args: &Args, logger: &Logger) -> Result<(), Error> {
     slog::debug!(semantic_logger, "Started semantic analysis");
     let start_semantics = Instant::now();
 
-    let cu = semantic_analysis(&file, &mut diags, logger);
+    let (mut cu, mut report) =
+        semantic_analysis(&file, &options, &mut diags, logger)?;
 
-    if diags.has_errors() {
-        let mut ss = StandardStream::stdout(ColorChoice::Auto);
-        for diagnostic in diags.diagnostics() {
-            codespan_reporting::emit(&mut ss, &map, diagnostic)?;
+    // a configuration decides which program instances each task executes
+    let tasks = match args.command {
+        Some(Command::Run { .. }) if has_configuration(&cu) => {
+            Some(iec_runtime::task_configs(&cu, &mut diags))
         }
+        _ => None,
+    };
+
+    let mut ss = StandardStream::stdout(ColorChoice::Auto);
+    for diagnostic in diags.diagnostics() {
+        codespan_reporting::emit(&mut ss, &map, diagnostic)?;
+    }
+
+    if diags.has_errors() {
+        args.print_timings(&report);
         return Ok(());
     }
 
@@ -92,77 +144,488 @@ fn run(args: &Args, logger: &Logger) -> Result<(), Error> {
         "execution-time" => format_args!("{}.{:03}s", duration.as_secs(), duration.subsec_millis()),
         "memory-usage" => cu.heap_size_of_children() + file.heap_size_of_children());
 
+    if let Some(Command::Run {
+        cycles,
+        saturate,
+        backend: Backend::Interpreter,
+        ..
+    }) = args.command
+    {
+        if let Some(tasks) = tasks {
+            let interpreter =
+                Interpreter::new(&file, &cu).with_overflow(overflow(saturate));
+            return schedule(interpreter, &cu, tasks, cycles, logger);
+        }
+        return interpret(&file, &cu, &map, cycles, overflow(saturate), logger);
+    }
+
+    if let Some(Stop::Requested(pass)) = report.stopped {
+        slog::info!(logger, "Stopping early"; "pass" => pass);
+        args.print_timings(&report);
+        return Ok(());
+    }
+
+    let opt_logger = logger.new(slog::o!("stage" => "optimisation"));
+    slog::debug!(opt_logger, "Started optimising";
+        "level" => args.opt_level);
+    let start_optimising = Instant::now();
+
+    report.extend(optimization(
+        &mut cu,
+        args.opt_level,
+        &options,
+        &mut diags,
+        &opt_logger,
+    )?);
+
+    let duration = Instant::now() - start_optimising;
+    slog::debug!(opt_logger, "Finished optimising";
+        "execution-time" => format_args!("{}.{:03}s", duration.as_secs(), duration.subsec_millis()),
+        "memory-usage" => cu.heap_size_of_children());
+
     let duration = Instant::now() - start;
     slog::info!(logger, "Compilation finished"; 
         "execution-time" => format_args!("{}.{:03}s", duration.as_secs(), duration.subsec_millis()));
 
+    args.print_timings(&report);
     slog::debug!(logger, "{:#?}", cu);
+
+    match args.command {
+        Some(Command::Run {
+            backend: Backend::Jit,
+            ..
+        }) if tasks.is_some() => Err(failure::err_msg(
+            "The JIT can't execute a configuration's tasks, use the \"vm\" or \"interpreter\" backend instead",
+        )),
+        Some(Command::Run {
+            cycles,
+            saturate,
+            backend: Backend::Jit,
+            ..
+        }) => jit(&cu, cycles, overflow(saturate), logger),
+        Some(Command::Run {
+            cycles, saturate, ..
+        }) if tasks.is_some() => {
+            let vm = Vm::new(bytecode::compile(&cu))
+                .context("The bytecode failed verification")?
+                .with_overflow(overflow(saturate));
+            let tasks = tasks.unwrap_or_default();
+            schedule(vm, &cu, tasks, cycles, logger)
+        }
+        Some(Command::Run {
+            cycles, saturate, ..
+        }) => {
+            let module = bytecode::compile(&cu);
+            execute_with_spans(module, cycles, overflow(saturate), &map, logger)
+        }
+        Some(Command::Build {
+            ref output, emit, ..
+        }) => build(&cu, output, emit, logger),
+        None => Ok(()),
+    }
+}
+
+fn overflow(saturate: bool) -> Overflow {
+    if saturate {
+        Overflow::Saturate
+    } else {
+        Overflow::Wrap
+    }
+}
+
+fn is_bytecode(filename: &str) -> bool {
+    Path::new(filename)
+        .extension()
+        .is_some_and(|ext| ext == bytecode::EXTENSION)
+}
+
+fn load_bytecode(files: &[String]) -> Result<Module, Error> {
+    match files {
+        [filename] => {
+            let f = std::fs::File::open(filename)
+                .with_context(|_| format!("Unable to open \"{}\"", filename))?;
+            let module = Module::read_from(std::io::BufReader::new(f))
+                .with_context(|_| format!("Unable to load \"{}\"", filename))?;
+            Ok(module)
+        }
+        _ => Err(failure::err_msg(
+            "Only one bytecode file can be run at a time",
+        )),
+    }
+}
+
+fn file_stem(filename: &str) -> &str {
+    Path::new(filename)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("iec")
+}
+
+/// Lower the [`CompilationUnit`] to bytecode (or machine code) and save it.
+fn build(
+    cu: &CompilationUnit,
+    output: &str,
+    emit: Emit,
+    logger: &Logger,
+) -> Result<(), Error> {
+    let contents = match emit {
+        Emit::Bytecode | Emit::Disassembly => {
+            let module = bytecode::compile(cu);
+            bytecode::verify(&module).context("Generated invalid bytecode")?;
+
+            if emit == Emit::Bytecode {
+                module.to_bytes()
+            } else {
+                bytecode::disassemble(&module).into_bytes()
+            }
+        }
+        Emit::C => {
+            let name = file_stem(output);
+            let code = iec_codegen_c::generate(cu, name)
+                .context("Unable to generate C")?;
+
+            // the header always lives next to the source file
+            let header =
+                Path::new(output).with_file_name(format!("{}.h", name));
+            std::fs::write(&header, &code.header).with_context(|_| {
+                format!("Unable to write to \"{}\"", header.display())
+            })?;
+
+            code.source.into_bytes()
+        }
+        Emit::Object => {
+            iec_codegen_cranelift::emit_object(cu, file_stem(output))
+                .context("Unable to generate machine code")?
+        }
+        Emit::Wasm => {
+            iec_codegen_wasm::generate(cu)
+                .context("Unable to generate WebAssembly")?
+                .wasm
+        }
+        Emit::LlvmIr => iec_codegen_llvm::generate(cu, file_stem(output))
+            .context("Unable to generate LLVM IR")?
+            .into_bytes(),
+        Emit::IoMap => {
+            let json = if cu.resources.is_registered::<ProcessImage>() {
+                cu.resources.get_singleton::<ProcessImage>().to_json()
+            } else {
+                ProcessImage::default().to_json()
+            };
+            format!("{:#}\n", json).into_bytes()
+        }
+    };
+
+    std::fs::write(output, &contents)
+        .with_context(|_| format!("Unable to write to \"{}\"", output))?;
+    slog::info!(logger, "Saved the compiled program";
+        "output" => output,
+        "emit" => %emit,
+        "size" => contents.len());
+
+    Ok(())
+}
+
+/// Execute a bytecode [`Module`] using the [`Vm`], printing the value of each
+/// program's variables afterwards.
+fn execute(
+    module: Module,
+    cycles: u64,
+    overflow: Overflow,
+    logger: &Logger,
+) -> Result<(), Error> {
+    execute_with_spans(module, cycles, overflow, &CodeMap::new(), logger)
+}
+
+fn execute_with_spans(
+    module: Module,
+    cycles: u64,
+    overflow: Overflow,
+    map: &CodeMap,
+    logger: &Logger,
+) -> Result<(), Error> {
+    let logger = logger.new(slog::o!("stage" => "vm"));
+    slog::debug!(logger, "Started executing"; "cycles" => cycles);
+    let start = Instant::now();
+
+    let mut vm = Vm::new(module)
+        .context("The bytecode failed verification")?
+        .with_overflow(overflow);
+
+    for _ in 0..cycles {
+        if let Err(e) = vm.run_cycle() {
+            // modules loaded from disk don't come with their source code
+            if e.span.and_then(|s| map.find_file(s.start())).is_some() {
+                let mut ss = StandardStream::stdout(ColorChoice::Auto);
+                codespan_reporting::emit(&mut ss, map, &e.to_diagnostic())?;
+            }
+            return Err(e).context("Execution failed")?;
+        }
+
+        let stats = vm.last_cycle();
+        slog::trace!(logger, "Finished a scan cycle";
+            "instructions" => stats.instructions,
+            "execution-time" => format_args!("{}us", stats.elapsed.as_micros()));
+    }
+
+    let duration = Instant::now() - start;
+    slog::debug!(logger, "Finished executing";
+        "execution-time" => format_args!("{}.{:03}s", duration.as_secs(), duration.subsec_millis()));
+
+    let module = vm.module();
+    for pou in module.programs().map(|ix| &module.pous[ix]) {
+        for name in pou.slots.iter().filter_map(|s| s.name.as_ref()) {
+            let path = format!("{}.{}", pou.name, name);
+            if let Some(cell) = vm.get(&path) {
+                println!("{} = {}", path, cell);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Compile the programs to machine code and execute them in memory, printing
+/// the value of each program's variables afterwards.
+fn jit(
+    cu: &CompilationUnit,
+    cycles: u64,
+    overflow: Overflow,
+    logger: &Logger,
+) -> Result<(), Error> {
+    if overflow != Overflow::Wrap {
+        return Err(failure::err_msg(
+            "The JIT only supports wrapping arithmetic",
+        ));
+    }
+
+    let logger = logger.new(slog::o!("stage" => "jit"));
+    slog::debug!(logger, "Started executing"; "cycles" => cycles);
+    let start = Instant::now();
+
+    let mut jit =
+        Jit::compile(cu).context("Unable to generate machine code")?;
+    jit.run_cycles(cycles).context("Execution failed")?;
+
+    let duration = Instant::now() - start;
+    slog::debug!(logger, "Finished executing";
+        "execution-time" => format_args!("{}.{:03}s", duration.as_secs(), duration.subsec_millis()));
+
+    let layout = jit.layout();
+    for pou in layout.pous.iter().filter(|p| p.kind == PouKind::Program) {
+        for name in pou.frame.fields.iter().filter_map(|f| f.name.as_ref()) {
+            let path = format!("{}.{}", pou.name, name);
+            if let Some(value) = jit.get(&path) {
+                println!("{} = {}", path, value);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn has_configuration(cu: &CompilationUnit) -> bool {
+    cu.resources.is_registered::<Configuration>()
+        && cu.resources.get::<Configuration>().iter().next().is_some()
+}
+
+/// Execute a configuration's tasks using the [`Runtime`], printing the value
+/// of each program instance's variables afterwards.
+///
+/// Time is simulated, so `scans` scan cycles are executed as quickly as
+/// possible.
+fn schedule<T: Target>(
+    target: T,
+    cu: &CompilationUnit,
+    tasks: Vec<TaskConfig>,
+    scans: u64,
+    logger: &Logger,
+) -> Result<(), Error> {
+    let logger = logger.new(slog::o!("stage" => "runtime"));
+    slog::debug!(logger, "Started executing";
+        "scans" => scans, "task-count" => tasks.len());
+    let start = Instant::now();
+
+    let mut runtime = Runtime::new(target, SimulatedClock::new());
+    let mut instances = Vec::new();
+
+    for task in tasks {
+        instances.extend(task.programs.iter().cloned());
+        runtime
+            .add_task(task)
+            .context("Invalid task configuration")?;
+    }
+
+    for _ in 0..scans {
+        let executed = runtime
+            .scan()
+            .map_err(|fault| failure::err_msg(fault.to_string()))
+            .context("Execution failed")?;
+        slog::trace!(logger, "Finished a scan cycle";
+            "time" => format_args!("{:?}", runtime.clock().now()),
+            "tasks" => format_args!("{:?}", executed));
+
+        let wakeup = runtime.next_wakeup();
+        runtime.clock().sleep_until(wakeup);
+    }
+
+    let duration = Instant::now() - start;
+    slog::debug!(logger, "Finished executing";
+        "execution-time" => format_args!("{}.{:03}s", duration.as_secs(), duration.subsec_millis()));
+
+    let programs = cu.resources.get::<Program>();
+    let variables = cu.resources.get::<Variable>();
+
+    for instance in instances {
+        let program = programs
+            .iter()
+            .map(|(_, p)| p)
+            .find(|p| p.name.eq_ignore_ascii_case(&instance.program));
+        let names = program
+            .into_iter()
+            .flat_map(|p| &p.variables)
+            .filter_map(|&v| variables.get(v).and_then(|v| v.name.as_ref()));
+
+        for name in names {
+            let path = format!("{}.{}", instance.name, name);
+            if let Some(value) = runtime.target().get(&path) {
+                println!("{} = {}", path, value);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Execute the compiled programs using the [`Interpreter`], printing the
+/// value of each program's variables afterwards.
+fn interpret(
+    file: &File,
+    cu: &CompilationUnit,
+    map: &CodeMap,
+    cycles: u64,
+    overflow: Overflow,
+    logger: &Logger,
+) -> Result<(), Error> {
+    let logger = logger.new(slog::o!("stage" => "interpreter"));
+    slog::debug!(logger, "Started executing"; "cycles" => cycles);
+    let start = Instant::now();
+
+    let mut interpreter = Interpreter::new(file, cu).with_overflow(overflow);
+
+    if let Err(e) = interpreter.run_cycles(cycles) {
+        let mut ss = StandardStream::stdout(ColorChoice::Auto);
+        codespan_reporting::emit(&mut ss, map, &e.to_diagnostic())?;
+        return Err(e).context("Execution failed")?;
+    }
+
+    let duration = Instant::now() - start;
+    slog::debug!(logger, "Finished executing";
+        "execution-time" => format_args!("{}.{:03}s", duration.as_secs(), duration.subsec_millis()));
+
+    for program in file.items.iter().filter_map(|item| match item {
+        Item::Program(ref p) => Some(p),
+        _ => None,
+    }) {
+        for decl in program.var_blocks.iter().flat_map(|b| &b.declarations) {
+            let path = format!("{}.{}", program.name.value, decl.ident.value);
+            if let Some(value) = interpreter.get(&path) {
+                println!("{} = {}", path, value);
+            }
+        }
+    }
+
     Ok(())
 }
 
 fn semantic_analysis(
     file: &File,
+    options: &PassOptions,
     diags: &mut Diagnostics,
     logger: &Logger,
-) -> CompilationUnit {
+) -> Result<(CompilationUnit, Report), Error> {
     let mut ctx = PassContext {
         diags,
         logger: logger.new(slog::o!("stage" => "semantic-analysis")),
     };
-    iec::process(file, &mut ctx)
+    let got = iec::passes::process_with_options(file, options, &mut ctx)?;
+    Ok(got)
 }
 
+fn optimization(
+    cu: &mut CompilationUnit,
+    level: OptimizationLevel,
+    options: &PassOptions,
+    diags: &mut Diagnostics,
+    logger: &Logger,
+) -> Result<Report, Error> {
+    let mut ctx = PassContext {
+        diags,
+        logger: logger.clone(),
+    };
+    let report =
+        iec::passes::optimize_with_options(cu, level, options, &mut ctx)?;
+    Ok(report)
+}
+
+/// Parse a file, making sure spans line up with its location in the
+/// [`CodeMap`].
 fn syntactic_analysis(file: &FileMap) -> Result<File, Diagnostic> {
-    let offset = ByteOffset(file.span().start().0 as i64 - 2);
-
-    iec_syntax::File::from_str(file.src())
-        .map_err(|e| e.map_location(|l| l - offset))
-        .map_err(|e| match e {
-            lalrpop_util::ParseError::InvalidToken { location } => {
-                Diagnostic::new_error("Invalid token").with_label(
-                    Label::new_primary(ByteSpan::from_offset(
-                        location,
-                        ByteOffset(1),
-                    )),
-                )
-            }
+    iec_syntax::File::from_filemap(file).map_err(|e| match e {
+        lalrpop_util::ParseError::InvalidToken { location } => {
+            Diagnostic::new_error("Invalid token").with_label(
+                Label::new_primary(ByteSpan::from_offset(
+                    location,
+                    ByteOffset(1),
+                )),
+            )
+        }
 
-            lalrpop_util::ParseError::ExtraToken {
-                token: (start, tok, end),
-            } => Diagnostic::new_error(format!(
-                "Encountered \"{}\" when it wasn't expected",
-                tok
-            ))
-            .with_label(Label::new_primary(ByteSpan::new(start, end))),
-
-            lalrpop_util::ParseError::UnrecognizedToken {
-                token: Some((start, tok, end)),
-                expected,
-            } => Diagnostic::new_error(format!(
-                "Found \"{}\" but was expecting {}",
-                tok,
-                expected.join(", ")
-            ))
-            .with_label(Label::new_primary(ByteSpan::new(start, end))),
-
-            lalrpop_util::ParseError::UnrecognizedToken {
-                token: None,
-                expected,
-            } => Diagnostic::new_error(format!(
-                "Expected one of {}",
-                expected.join(", ")
-            )),
-
-            lalrpop_util::ParseError::User { error } => {
-                Diagnostic::new_error(error.to_string())
-            }
-        })
+        lalrpop_util::ParseError::ExtraToken {
+            token: (start, tok, end),
+        } => Diagnostic::new_error(format!(
+            "Encountered \"{}\" when it wasn't expected",
+            tok
+        ))
+        .with_label(Label::new_primary(ByteSpan::new(start, end))),
+
+        lalrpop_util::ParseError::UnrecognizedToken {
+            token: Some((start, tok, end)),
+            expected,
+        } => Diagnostic::new_error(format!(
+            "Found \"{}\" but was expecting {}",
+            tok,
+            expected.join(", ")
+        ))
+        .with_label(Label::new_primary(ByteSpan::new(start, end))),
+
+        lalrpop_util::ParseError::UnrecognizedToken {
+            token: None,
+            expected,
+        } => Diagnostic::new_error(format!(
+            "Expected one of {}",
+            expected.join(", ")
+        )),
+
+        lalrpop_util::ParseError::User { error } => {
+            Diagnostic::new_error(error.to_string())
+        }
+    })
 }
 
 #[derive(Debug, Clone, PartialEq, StructOpt, KV)]
+#[structopt(raw(
+    setting = "structopt::clap::AppSettings::SubcommandsNegateReqs"
+))]
 pub struct Args {
-    #[structopt(help = "The file to compile")]
-    pub file: String,
+    #[structopt(
+        help = "The files to compile",
+        raw(required = "true", min_values = "1")
+    )]
+    #[slog(skip)]
+    pub files: Vec<String>,
+    #[structopt(subcommand)]
+    #[slog(skip)]
+    pub command: Option<Command>,
     #[structopt(
         short = "v",
         long = "verbose",
@@ -170,6 +633,181 @@ pub struct Args {
         help = "Generate more verbose output"
     )]
     pub verbosity: u32,
+    #[structopt(
+        short = "O",
+        default_value = "0",
+        help = "The optimisation level (0, 1, or 2)"
+    )]
+    pub opt_level: OptimizationLevel,
+    #[structopt(
+        long = "skip-pass",
+        number_of_values = 1,
+        help = "Don't run this pass, or anything which depends on it"
+    )]
+    #[slog(skip)]
+    pub skip_pass: Vec<String>,
+    #[structopt(long = "stop-after", help = "Stop compiling after this pass")]
+    #[slog(skip)]
+    pub stop_after: Option<String>,
+    #[structopt(
+        long = "time-passes",
+        help = "Print how long each pass took and how much memory it used"
+    )]
+    pub time_passes: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, StructOpt)]
+pub enum Command {
+    #[structopt(name = "run", about = "Compile and then execute the programs")]
+    Run {
+        #[structopt(
+            help = "The files to run",
+            raw(required = "true", min_values = "1")
+        )]
+        files: Vec<String>,
+        #[structopt(
+            long = "cycles",
+            default_value = "1",
+            help = "How many times each program should be executed, or the number of scans when running a configuration"
+        )]
+        cycles: u64,
+        #[structopt(
+            long = "saturate",
+            help = "Saturate integer arithmetic instead of wrapping on overflow"
+        )]
+        saturate: bool,
+        #[structopt(
+            long = "backend",
+            default_value = "interpreter",
+            raw(possible_values = "&[\"interpreter\", \"vm\", \"jit\"]"),
+            help = "How the programs should be executed"
+        )]
+        backend: Backend,
+    },
+    #[structopt(
+        name = "build",
+        about = "Compile the programs to bytecode or machine code"
+    )]
+    Build {
+        #[structopt(
+            help = "The files to compile",
+            raw(required = "true", min_values = "1")
+        )]
+        files: Vec<String>,
+        #[structopt(
+            short = "o",
+            long = "output",
+            help = "Where to save the compiled program"
+        )]
+        output: String,
+        #[structopt(
+            long = "emit",
+            default_value = "bytecode",
+            raw(
+                possible_values = "&[\"bytecode\", \"disassembly\", \"object\", \"c\", \"wasm\", \"llvm-ir\", \"io-map\"]"
+            ),
+            help = "The kind of output to generate"
+        )]
+        emit: Emit,
+    },
+}
+
+/// The different ways a program can be executed.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Backend {
+    /// Walk the AST directly.
+    Interpreter,
+    /// Compile to bytecode and run it in a virtual machine.
+    Vm,
+    /// Compile to machine code using Cranelift.
+    Jit,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Backend, String> {
+        match s {
+            "interpreter" => Ok(Backend::Interpreter),
+            "vm" => Ok(Backend::Vm),
+            "jit" => Ok(Backend::Jit),
+            _ => Err(format!("Unknown backend, \"{}\"", s)),
+        }
+    }
+}
+
+/// The kinds of output `iecc build` can generate.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Emit {
+    Bytecode,
+    /// A human-readable listing of the bytecode.
+    Disassembly,
+    /// An object file which can be linked with
+    /// [`iec_codegen_cranelift::RUNTIME`].
+    Object,
+    /// C99 source code, plus a header alongside it.
+    C,
+    /// A WebAssembly module.
+    Wasm,
+    /// Textual LLVM IR, which calls into [`iec_codegen_llvm::runtime`].
+    LlvmIr,
+    /// A JSON description of where each located variable lives in the
+    /// process image.
+    IoMap,
+}
+
+impl FromStr for Emit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Emit, String> {
+        match s {
+            "bytecode" => Ok(Emit::Bytecode),
+            "disassembly" => Ok(Emit::Disassembly),
+            "object" => Ok(Emit::Object),
+            "c" => Ok(Emit::C),
+            "wasm" => Ok(Emit::Wasm),
+            "llvm-ir" => Ok(Emit::LlvmIr),
+            "io-map" => Ok(Emit::IoMap),
+            _ => Err(format!("Unknown output kind, \"{}\"", s)),
+        }
+    }
+}
+
+impl Display for Emit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            Emit::Bytecode => write!(f, "bytecode"),
+            Emit::Disassembly => write!(f, "disassembly"),
+            Emit::Object => write!(f, "object"),
+            Emit::C => write!(f, "c"),
+            Emit::Wasm => write!(f, "wasm"),
+            Emit::LlvmIr => write!(f, "llvm-ir"),
+            Emit::IoMap => write!(f, "io-map"),
+        }
+    }
+}
+
+impl Args {
+    fn files(&self) -> &[String] {
+        match self.command {
+            Some(Command::Run { ref files, .. })
+            | Some(Command::Build { ref files, .. }) => files,
+            None => &self.files,
+        }
+    }
+
+    fn pass_options(&self) -> PassOptions {
+        PassOptions {
+            skip: self.skip_pass.clone(),
+            stop_after: self.stop_after.clone(),
+        }
+    }
+
+    fn print_timings(&self, report: &Report) {
+        if self.time_passes {
+            println!("{}", report);
+        }
+    }
 }
 
 fn create_logger(verbosity: u32) -> Logger {
@@ -0,0 +1,100 @@
+//! Sources of time for the scheduler.
+
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Something which can tell the time and wait for a deadline.
+///
+/// Times are measured relative to when the clock was created, so the
+/// scheduler never needs to deal with wall-clock time.
+pub trait Clock {
+    /// The time elapsed since the clock was created.
+    fn now(&self) -> Duration;
+
+    /// Block until [`Clock::now()`] is at least `deadline`.
+    fn sleep_until(&self, deadline: Duration);
+}
+
+/// A [`Clock`] which uses the operating system's monotonic clock.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> SystemClock {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> SystemClock {
+        SystemClock::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep_until(&self, deadline: Duration) {
+        let now = self.now();
+
+        if deadline > now {
+            std::thread::sleep(deadline - now);
+        }
+    }
+}
+
+/// A [`Clock`] which only moves when it's told to, for deterministic tests.
+///
+/// Cloning a [`SimulatedClock`] gives another handle to the same clock, so a
+/// test can keep one copy around while the scheduler owns the other. Sleeping
+/// jumps straight to the deadline.
+///
+/// # Examples
+///
+/// ```rust
+/// use iec_runtime::{Clock, SimulatedClock};
+/// use std::time::Duration;
+///
+/// let clock = SimulatedClock::new();
+/// let handle = clock.clone();
+///
+/// handle.advance(Duration::from_millis(5));
+/// clock.sleep_until(Duration::from_millis(20));
+///
+/// assert_eq!(handle.now(), Duration::from_millis(20));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SimulatedClock {
+    now: Rc<Cell<Duration>>,
+}
+
+impl SimulatedClock {
+    pub fn new() -> SimulatedClock {
+        SimulatedClock::default()
+    }
+
+    /// Move the clock forwards (e.g. to pretend some code took a while to
+    /// execute).
+    pub fn advance(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration);
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> Duration {
+        self.now.get()
+    }
+
+    fn sleep_until(&self, deadline: Duration) {
+        if deadline > self.now.get() {
+            self.now.set(deadline);
+        }
+    }
+}
@@ -0,0 +1,268 @@
+//! Turning the `CONFIGURATION`s declared in a program into tasks the
+//! [`crate::Runtime`] can execute.
+
+use crate::{TaskConfig, TaskKind};
+use codespan_reporting::{Diagnostic, Label};
+use iec::ecs::{Container, EntityId};
+use iec::hir::{
+    Configuration, Program, ProgramInstance, Resource, Span, Task, Variable,
+};
+use iec::{CompilationUnit, Diagnostics};
+use std::convert::TryFrom;
+use std::time::Duration;
+
+/// How often the background task for a resource's unassociated program
+/// instances is executed.
+pub const BACKGROUND_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Create a [`TaskConfig`] for every `TASK` declared in a configuration,
+/// executing the program instances associated with it.
+///
+/// Program instances without a `WITH` clause are executed by a background
+/// task with the lowest priority, named after their resource. Tasks whose
+/// priority can't be represented by the runtime are reported as errors and
+/// skipped.
+pub fn task_configs(
+    cu: &CompilationUnit,
+    diags: &mut Diagnostics,
+) -> Vec<TaskConfig> {
+    let resources = &cu.resources;
+    if !resources.is_registered::<Configuration>() {
+        return Vec::new();
+    }
+
+    let converter = Converter {
+        resources: &resources.get::<Resource>(),
+        tasks: &resources.get::<Task>(),
+        instances: &resources.get::<ProgramInstance>(),
+        programs: &resources.get::<Program>(),
+        variables: &resources.get::<Variable>(),
+        spans: &resources.get::<Span>(),
+    };
+    let mut configs = Vec::new();
+
+    for (_, configuration) in resources.get::<Configuration>().iter() {
+        for &resource in &configuration.resources {
+            converter.resource(resource, &mut configs, diags);
+        }
+    }
+
+    configs
+}
+
+struct Converter<'a> {
+    resources: &'a Container<Resource>,
+    tasks: &'a Container<Task>,
+    instances: &'a Container<ProgramInstance>,
+    programs: &'a Container<Program>,
+    variables: &'a Container<Variable>,
+    spans: &'a Container<Span>,
+}
+
+impl<'a> Converter<'a> {
+    fn resource(
+        &self,
+        id: EntityId,
+        configs: &mut Vec<TaskConfig>,
+        diags: &mut Diagnostics,
+    ) {
+        let resource = match self.resources.get(id) {
+            Some(r) => r,
+            None => return,
+        };
+
+        for &task in &resource.tasks {
+            if let Some(config) = self.task(task, &resource.programs, diags) {
+                configs.push(config);
+            }
+        }
+
+        let background: Vec<&ProgramInstance> = resource
+            .programs
+            .iter()
+            .filter_map(|&p| self.instances.get(p))
+            .filter(|instance| instance.task.is_none())
+            .collect();
+
+        if !background.is_empty() {
+            let mut config = TaskConfig::periodic(
+                resource.name.clone(),
+                BACKGROUND_INTERVAL,
+            );
+            for instance in background {
+                config = self.with_instance(config, instance);
+            }
+            configs.push(config);
+        }
+    }
+
+    fn task(
+        &self,
+        id: EntityId,
+        instances: &[EntityId],
+        diags: &mut Diagnostics,
+    ) -> Option<TaskConfig> {
+        let task = self.tasks.get(id)?;
+
+        let priority = match u8::try_from(task.priority) {
+            Ok(p) => p,
+            Err(_) => {
+                let mut diag = Diagnostic::new_error(format!(
+                    "The runtime only supports priorities up to {}",
+                    u8::MAX
+                ));
+                if let Some(span) = self.spans.get(id) {
+                    diag = diag.with_label(
+                        Label::new_primary(span.0).with_message(format!(
+                            "\"{}\" has a priority of {}",
+                            task.name, task.priority
+                        )),
+                    );
+                }
+                diags.push(diag);
+                return None;
+            }
+        };
+
+        let kind = match (task.interval(), task.single) {
+            (Some(interval), _) => TaskKind::Periodic(interval),
+            (None, Some(trigger)) => {
+                TaskKind::Event(self.variables.get(trigger)?.name.clone()?)
+            }
+            // already reported during configuration resolution
+            (None, None) => return None,
+        };
+
+        let mut config = TaskConfig {
+            name: task.name.clone(),
+            priority,
+            kind,
+            programs: Vec::new(),
+            watchdog: None,
+        };
+
+        for instance in instances.iter().filter_map(|&p| self.instances.get(p))
+        {
+            if instance.task == Some(id) {
+                config = self.with_instance(config, instance);
+            }
+        }
+
+        Some(config)
+    }
+
+    fn with_instance(
+        &self,
+        config: TaskConfig,
+        instance: &ProgramInstance,
+    ) -> TaskConfig {
+        match self.programs.get(instance.program) {
+            Some(program) => config
+                .with_instance(instance.name.clone(), program.name.clone()),
+            None => config,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Runtime, SimulatedClock};
+    use iec::interpreter::Interpreter;
+    use iec::testing::analyse;
+
+    const SRC: &str = "
+        PROGRAM counter
+            VAR
+                count: int;
+            END_VAR
+            count := count + 1;
+        END_PROGRAM
+
+        CONFIGURATION plc
+            VAR_GLOBAL
+                start: bool;
+            END_VAR
+            RESOURCE cpu
+                TASK fast(INTERVAL := T#10ms, PRIORITY := 1);
+                TASK slow(INTERVAL := T#25ms, PRIORITY := 2);
+                TASK on_start(SINGLE := start);
+                PROGRAM a WITH fast : counter;
+                PROGRAM b WITH slow : counter;
+                PROGRAM c WITH on_start : counter;
+                PROGRAM d : counter;
+            END_RESOURCE
+        END_CONFIGURATION";
+
+    #[test]
+    fn convert_tasks_and_program_instances() {
+        let (_, cu) = analyse(SRC);
+        let mut diags = Diagnostics::new();
+
+        let got = task_configs(&cu, &mut diags);
+
+        assert!(diags.diagnostics().is_empty());
+        let expected = vec![
+            TaskConfig::periodic("fast", Duration::from_millis(10))
+                .with_priority(1)
+                .with_instance("a", "counter"),
+            TaskConfig::periodic("slow", Duration::from_millis(25))
+                .with_priority(2)
+                .with_instance("b", "counter"),
+            TaskConfig::event("on_start", "start")
+                .with_priority(Task::DEFAULT_PRIORITY as u8)
+                .with_instance("c", "counter"),
+            TaskConfig::periodic("cpu", BACKGROUND_INTERVAL)
+                .with_instance("d", "counter"),
+        ];
+        assert_eq!(got, expected);
+    }
+
+    #[test]
+    fn a_configuration_drives_the_runtime() {
+        let (ast, cu) = analyse(SRC);
+        let mut diags = Diagnostics::new();
+        let interpreter = Interpreter::new(&ast, &cu);
+        let mut runtime = Runtime::new(interpreter, SimulatedClock::new());
+
+        for task in task_configs(&cu, &mut diags) {
+            runtime.add_task(task).unwrap();
+        }
+        runtime.run_until(Duration::from_millis(100)).unwrap();
+
+        let count = |path| runtime.target().get(path).unwrap().as_integer();
+        assert_eq!(count("a.count"), Some(10));
+        assert_eq!(count("b.count"), Some(4));
+        assert_eq!(count("c.count"), Some(0));
+        assert_eq!(count("d.count"), Some(100));
+    }
+
+    #[test]
+    fn priorities_must_fit_in_a_byte() {
+        let src = "
+            PROGRAM main
+            END_PROGRAM
+
+            CONFIGURATION plc
+                RESOURCE cpu
+                    TASK fast(INTERVAL := T#10ms, PRIORITY := 255);
+                    TASK slow(INTERVAL := T#10ms, PRIORITY := 256);
+                    PROGRAM a WITH fast : main;
+                    PROGRAM b WITH slow : main;
+                END_RESOURCE
+            END_CONFIGURATION";
+        let (_, cu) = analyse(src);
+        let mut diags = Diagnostics::new();
+
+        let got = task_configs(&cu, &mut diags);
+
+        assert_eq!(got.len(), 1);
+        assert_eq!(got[0].priority, 255);
+        let messages: Vec<_> =
+            diags.diagnostics().iter().map(|d| &d.message).collect();
+        assert_eq!(
+            messages,
+            vec!["The runtime only supports priorities up to 255"]
+        );
+    }
+}
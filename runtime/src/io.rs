@@ -0,0 +1,113 @@
+//! Getting values into and out of a [`Target`] at the start and end of each
+//! scan cycle.
+
+use crate::Target;
+use iec::interpreter::Value;
+use std::collections::BTreeMap;
+use std::error::Error;
+
+/// The hardware (or simulation) a PLC is connected to.
+///
+/// Inputs are read once at the start of a scan cycle and outputs are written
+/// once at the end, so programs always see a consistent snapshot of the
+/// outside world.
+pub trait Io {
+    /// Copy the current state of the inputs into the target.
+    fn read_inputs(
+        &mut self,
+        target: &mut dyn Target,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Copy the target's outputs to the outside world.
+    fn write_outputs(
+        &mut self,
+        target: &dyn Target,
+    ) -> Result<(), Box<dyn Error>>;
+}
+
+/// An [`Io`] which isn't connected to anything.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct NoIo;
+
+impl Io for NoIo {
+    fn read_inputs(
+        &mut self,
+        _target: &mut dyn Target,
+    ) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn write_outputs(
+        &mut self,
+        _target: &dyn Target,
+    ) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+/// An in-memory copy of the inputs and outputs, keyed by the variables they
+/// are connected to.
+///
+/// # Examples
+///
+/// ```rust
+/// use iec::interpreter::Value;
+/// use iec_runtime::ProcessImage;
+///
+/// let mut image = ProcessImage::new();
+/// image.set_input("main.start_button", Value::Bool(true));
+/// image.watch_output("main.motor");
+///
+/// // nothing has been written yet
+/// assert_eq!(image.output("main.motor"), None);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProcessImage {
+    inputs: BTreeMap<String, Value>,
+    outputs: BTreeMap<String, Option<Value>>,
+}
+
+impl ProcessImage {
+    pub fn new() -> ProcessImage {
+        ProcessImage::default()
+    }
+
+    /// Set the value copied into a variable at the start of every scan.
+    pub fn set_input<S: Into<String>>(&mut self, path: S, value: Value) {
+        self.inputs.insert(path.into(), value);
+    }
+
+    /// Start recording a variable's value at the end of every scan.
+    pub fn watch_output<S: Into<String>>(&mut self, path: S) {
+        self.outputs.entry(path.into()).or_insert(None);
+    }
+
+    /// The value an output had at the end of the most recent scan.
+    pub fn output(&self, path: &str) -> Option<&Value> {
+        self.outputs.get(path).and_then(Option::as_ref)
+    }
+}
+
+impl Io for ProcessImage {
+    fn read_inputs(
+        &mut self,
+        target: &mut dyn Target,
+    ) -> Result<(), Box<dyn Error>> {
+        for (path, value) in &self.inputs {
+            target.set(path, value.clone())?;
+        }
+
+        Ok(())
+    }
+
+    fn write_outputs(
+        &mut self,
+        target: &dyn Target,
+    ) -> Result<(), Box<dyn Error>> {
+        for (path, value) in &mut self.outputs {
+            *value = target.get(path);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,44 @@
+//! A runtime for hosting IEC 61131-3 programs, using a classic PLC scan
+//! cycle.
+//!
+//! Program instances are executed by a [`Target`] (the [`iec::interpreter`]
+//! or the bytecode [`iec::bytecode::Vm`]) and grouped into tasks. The same
+//! program can be instantiated several times, and each instance keeps its
+//! own state. Each scan:
+//!
+//! 1. reads the inputs from an [`Io`] implementation,
+//! 2. executes every task which is due, highest priority first, then
+//! 3. writes the outputs.
+//!
+//! Tasks can be periodic or triggered by the rising edge of a `BOOL`
+//! variable. The runtime measures how long each task takes ([`TaskStats`]),
+//! counts activations which were skipped because the previous cycle
+//! overran, and faults if a task exceeds its watchdog.
+//!
+//! The tasks and program instances declared in a `CONFIGURATION` can be
+//! turned into [`TaskConfig`]s with [`task_configs()`].
+//!
+//! Variables declared in a `RETAIN` or `PERSISTENT` block can be saved to
+//! disk with a [`RetainStore`] and restored when the PLC next starts.
+//!
+//! All timing goes through a [`Clock`], so tests can use a
+//! [`SimulatedClock`] to get deterministic results.
+
+mod clock;
+mod configuration;
+mod io;
+mod retain;
+mod scheduler;
+mod target;
+mod task;
+
+pub use crate::clock::{Clock, SimulatedClock, SystemClock};
+pub use crate::configuration::{task_configs, BACKGROUND_INTERVAL};
+pub use crate::io::{Io, NoIo, ProcessImage};
+pub use crate::retain::{
+    retained_variables, RestoreReport, RetainError, RetainStore,
+    RetainedVariable, SavedValue, SavedVariable, Snapshot,
+};
+pub use crate::scheduler::{ConfigError, Fault, Runtime};
+pub use crate::target::Target;
+pub use crate::task::{ProgramInstance, TaskConfig, TaskKind, TaskStats};
@@ -0,0 +1,621 @@
+//! Keeping `RETAIN` and `PERSISTENT` variables across a restart.
+//!
+//! A [`RetainStore`] saves a [`Snapshot`] of every retained variable to a
+//! versioned JSON file, and restores it the next time the PLC starts. The
+//! program may have been changed in the meantime, so variables are matched
+//! up by their qualified name and type, and anything which couldn't be
+//! restored is listed in the [`RestoreReport`].
+
+use crate::Target;
+use iec::const_eval::IntegerType;
+use iec::ecs::{Container, EntityId};
+use iec::hir::{
+    FunctionBlock, GlobalVariables, Program, Retained, Type, Variable,
+    VariableKind,
+};
+use iec::interpreter::Value;
+use iec::CompilationUnit;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A variable whose value should be saved when the PLC stops.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetainedVariable {
+    /// The variable's path, as passed to [`Target::get()`] (e.g.
+    /// `"main.counter.cv"`).
+    pub name: String,
+    /// The name of the variable's type (e.g. `"int"`).
+    pub ty: String,
+}
+
+/// Find every retained variable in a program, sorted by name.
+///
+/// Members of a retained function block instance are retained too. Temporary
+/// and `VAR_IN_OUT` variables are never saved because they don't outlive a
+/// single call.
+pub fn retained_variables(cu: &CompilationUnit) -> Vec<RetainedVariable> {
+    let resources = &cu.resources;
+    if !resources.is_registered::<Retained>() {
+        return Vec::new();
+    }
+
+    let finder = Finder {
+        variables: &resources.get::<Variable>(),
+        function_blocks: &resources.get::<FunctionBlock>(),
+        types: &resources.get::<Type>(),
+        retained: &resources.get::<Retained>(),
+    };
+    let mut found = Vec::new();
+
+    for (_, globals) in resources.get::<GlobalVariables>().iter() {
+        finder.visit(
+            &globals.variables,
+            "",
+            false,
+            &mut Vec::new(),
+            &mut found,
+        );
+    }
+    for (_, program) in resources.get::<Program>().iter() {
+        let prefix = format!("{}.", program.name.to_lowercase());
+        finder.visit(
+            &program.variables,
+            &prefix,
+            false,
+            &mut Vec::new(),
+            &mut found,
+        );
+    }
+
+    found.sort_by(|a, b| a.name.cmp(&b.name));
+    found
+}
+
+struct Finder<'a> {
+    variables: &'a Container<Variable>,
+    function_blocks: &'a Container<FunctionBlock>,
+    types: &'a Container<Type>,
+    retained: &'a Container<Retained>,
+}
+
+impl<'a> Finder<'a> {
+    fn visit(
+        &self,
+        ids: &[EntityId],
+        prefix: &str,
+        parent_retained: bool,
+        instances_of: &mut Vec<EntityId>,
+        found: &mut Vec<RetainedVariable>,
+    ) {
+        for &id in ids {
+            let variable = match self.variables.get(id) {
+                Some(v) => v,
+                None => continue,
+            };
+            let name = match (variable.kind, &variable.name) {
+                (VariableKind::Temp, _)
+                | (VariableKind::InOut, _)
+                | (VariableKind::ReturnValue, _)
+                | (VariableKind::Temporary, _)
+                | (_, None) => continue,
+                (_, Some(name)) => format!("{}{}", prefix, name.to_lowercase()),
+            };
+            let retained = parent_retained || self.retained.contains(id);
+
+            if let Some(fb) = self.function_blocks.get(variable.ty) {
+                // don't recurse forever if a function block contains itself
+                if instances_of.contains(&variable.ty) {
+                    continue;
+                }
+                instances_of.push(variable.ty);
+                let prefix = format!("{}.", name);
+                self.visit(
+                    &fb.variables,
+                    &prefix,
+                    retained,
+                    instances_of,
+                    found,
+                );
+                instances_of.pop();
+            } else if retained {
+                let ty = self
+                    .types
+                    .get(variable.ty)
+                    .map(|t| t.name.to_lowercase())
+                    .unwrap_or_default();
+                found.push(RetainedVariable { name, ty });
+            }
+        }
+    }
+}
+
+/// The saved values of every retained variable.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// The snapshot format, so files written by an older runtime can still
+    /// be understood.
+    pub version: u32,
+    pub variables: Vec<SavedVariable>,
+}
+
+impl Snapshot {
+    /// The format written by this version of the runtime.
+    pub const VERSION: u32 = 1;
+
+    /// Read the current value of each variable from a [`Target`].
+    ///
+    /// Variables the target doesn't know about are skipped.
+    pub fn capture(
+        target: &dyn Target,
+        variables: &[RetainedVariable],
+    ) -> Snapshot {
+        let variables = variables
+            .iter()
+            .filter_map(|var| {
+                let value = SavedValue::from_value(&target.get(&var.name)?)?;
+                Some(SavedVariable {
+                    name: var.name.clone(),
+                    ty: var.ty.clone(),
+                    value,
+                })
+            })
+            .collect();
+
+        Snapshot {
+            version: Snapshot::VERSION,
+            variables,
+        }
+    }
+
+    /// Copy the saved values back into a [`Target`].
+    ///
+    /// A saved value is only used if there is still a variable with the same
+    /// name and type, everything else keeps its initial value.
+    pub fn restore(
+        &self,
+        target: &mut dyn Target,
+        variables: &[RetainedVariable],
+    ) -> Result<RestoreReport, RetainError> {
+        let mut saved: BTreeMap<&str, &SavedVariable> = self
+            .variables
+            .iter()
+            .map(|var| (var.name.as_str(), var))
+            .collect();
+        let mut report = RestoreReport::default();
+
+        for var in variables {
+            match saved.remove(var.name.as_str()) {
+                Some(previous) if previous.ty == var.ty => {
+                    target.set(&var.name, previous.value.to_value()).map_err(
+                        |error| RetainError::Target {
+                            variable: var.name.clone(),
+                            error,
+                        },
+                    )?;
+                    report.restored.push(var.name.clone());
+                }
+                _ => report.reinitialised.push(var.name.clone()),
+            }
+        }
+
+        report.dropped = saved.keys().map(|name| name.to_string()).collect();
+        Ok(report)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self)
+            .expect("a snapshot is always serializable")
+    }
+
+    /// Parse a snapshot, checking it was written in a format we understand.
+    pub fn from_json(src: &str) -> Result<Snapshot, RetainError> {
+        let raw: serde_json::Value = serde_json::from_str(src)?;
+
+        match raw.get("version").and_then(serde_json::Value::as_u64) {
+            Some(version) if version == u64::from(Snapshot::VERSION) => {
+                Ok(serde_json::from_value(raw)?)
+            }
+            Some(version) => Err(RetainError::UnsupportedVersion(version)),
+            None => Err(RetainError::MissingVersion),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedVariable {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub value: SavedValue,
+}
+
+/// A variable's value, in a form which can be written to disk.
+///
+/// Every IEC integer type fits in either an `i64` or a `u64`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SavedValue {
+    Bool(bool),
+    Integer(i64),
+    Unsigned(u64),
+    Real(f64),
+    String(String),
+}
+
+impl SavedValue {
+    fn from_value(value: &Value) -> Option<SavedValue> {
+        match *value {
+            Value::Bool(b) => Some(SavedValue::Bool(b)),
+            Value::Integer { value, .. } => {
+                if value > i128::from(i64::MAX) {
+                    Some(SavedValue::Unsigned(value as u64))
+                } else {
+                    Some(SavedValue::Integer(value as i64))
+                }
+            }
+            Value::Real(r) => Some(SavedValue::Real(r)),
+            Value::String(ref s) => Some(SavedValue::String(s.clone())),
+            Value::Instance(_) => None,
+        }
+    }
+
+    /// The [`Target`] takes care of converting integers to the variable's
+    /// actual type.
+    fn to_value(&self) -> Value {
+        let integer = |value: i128| Value::Integer {
+            value,
+            ty: IntegerType::for_literal(value),
+        };
+
+        match *self {
+            SavedValue::Bool(b) => Value::Bool(b),
+            SavedValue::Integer(i) => integer(i128::from(i)),
+            SavedValue::Unsigned(u) => integer(i128::from(u)),
+            SavedValue::Real(r) => Value::Real(r),
+            SavedValue::String(ref s) => Value::String(s.clone()),
+        }
+    }
+}
+
+/// What happened to each retained variable when a [`Snapshot`] was restored.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RestoreReport {
+    /// Variables which were given their saved value.
+    pub restored: Vec<String>,
+    /// Saved variables which no longer exist in the program.
+    pub dropped: Vec<String>,
+    /// Variables which are new or whose type changed, and were left with
+    /// their initial value.
+    pub reinitialised: Vec<String>,
+}
+
+/// Saves and restores a program's retained variables using a file on disk.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use iec::interpreter::Interpreter;
+/// use iec::passes::PassContext;
+/// use iec::Diagnostics;
+/// use iec_runtime::RetainStore;
+///
+/// let src = "
+///     PROGRAM main
+///     VAR RETAIN
+///         count : int;
+///     END_VAR
+///         count := count + 1;
+///     END_PROGRAM";
+/// let ast: iec_syntax::File = src.parse().unwrap();
+/// let mut diags = Diagnostics::new();
+/// let cu = iec::process(&ast, &mut PassContext::new_nop_logger(&mut diags));
+///
+/// let store = RetainStore::new("retain.json", &cu);
+/// let mut interpreter = Interpreter::new(&ast, &cu);
+///
+/// let report = store.restore(&mut interpreter).unwrap();
+/// println!("Dropped {:?}", report.dropped);
+///
+/// interpreter.run_program("main").unwrap();
+/// store.save(&interpreter).unwrap();
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetainStore {
+    path: PathBuf,
+    variables: Vec<RetainedVariable>,
+}
+
+impl RetainStore {
+    pub fn new<P: Into<PathBuf>>(path: P, cu: &CompilationUnit) -> RetainStore {
+        RetainStore {
+            path: path.into(),
+            variables: retained_variables(cu),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn variables(&self) -> &[RetainedVariable] {
+        &self.variables
+    }
+
+    /// Read the snapshot file, if one has been saved.
+    pub fn load(&self) -> Result<Option<Snapshot>, RetainError> {
+        match fs::read_to_string(&self.path) {
+            Ok(src) => Snapshot::from_json(&src).map(Some),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Restore the saved values at startup. If nothing has been saved yet,
+    /// every variable is reinitialised.
+    pub fn restore(
+        &self,
+        target: &mut dyn Target,
+    ) -> Result<RestoreReport, RetainError> {
+        let snapshot = self.load()?.unwrap_or_else(|| Snapshot {
+            version: Snapshot::VERSION,
+            variables: Vec::new(),
+        });
+
+        snapshot.restore(target, &self.variables)
+    }
+
+    /// Save the current value of every retained variable.
+    ///
+    /// The snapshot is written to a temporary file and renamed over the
+    /// original, so losing power part way through won't corrupt the
+    /// previous snapshot.
+    pub fn save(&self, target: &dyn Target) -> Result<(), RetainError> {
+        let snapshot = Snapshot::capture(target, &self.variables);
+
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+        fs::write(&temp, snapshot.to_json())?;
+        fs::rename(&temp, &self.path)?;
+
+        Ok(())
+    }
+}
+
+/// Saving or restoring a [`Snapshot`] failed.
+#[derive(Debug)]
+pub enum RetainError {
+    Io(io::Error),
+    Malformed(serde_json::Error),
+    /// The snapshot was written by a newer (or unknown) version of the
+    /// runtime.
+    UnsupportedVersion(u64),
+    MissingVersion,
+    /// The [`Target`] rejected a saved value.
+    Target {
+        variable: String,
+        error: Box<dyn Error>,
+    },
+}
+
+impl Display for RetainError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            RetainError::Io(ref e) => {
+                write!(f, "Unable to access the snapshot file: {}", e)
+            }
+            RetainError::Malformed(ref e) => {
+                write!(f, "The snapshot is malformed: {}", e)
+            }
+            RetainError::UnsupportedVersion(version) => write!(
+                f,
+                "Version {} snapshots aren't supported (expected version {})",
+                version,
+                Snapshot::VERSION
+            ),
+            RetainError::MissingVersion => {
+                write!(f, "The snapshot doesn't say which version it is")
+            }
+            RetainError::Target {
+                ref variable,
+                ref error,
+            } => write!(f, "Unable to restore \"{}\": {}", variable, error),
+        }
+    }
+}
+
+impl Error for RetainError {}
+
+impl From<io::Error> for RetainError {
+    fn from(other: io::Error) -> RetainError {
+        RetainError::Io(other)
+    }
+}
+
+impl From<serde_json::Error> for RetainError {
+    fn from(other: serde_json::Error) -> RetainError {
+        RetainError::Malformed(other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iec::bytecode::{self, Vm};
+    use iec::interpreter::Interpreter;
+    use iec::passes::PassContext;
+    use iec::Diagnostics;
+
+    const ORIGINAL: &str = "
+        FUNCTION_BLOCK counter
+        VAR_INPUT
+            step : int;
+        END_VAR
+        VAR RETAIN
+            cv : dint;
+        END_VAR
+        VAR
+            calls : int;
+        END_VAR
+        BEGIN
+            cv := cv + step;
+            calls := calls + 1;
+        END_FUNCTION_BLOCK
+
+        VAR_GLOBAL PERSISTENT
+            setpoint : real;
+        END_VAR
+
+        PROGRAM main
+        VAR
+            parts : counter;
+            scans : int;
+        END_VAR
+        VAR RETAIN
+            batches : counter;
+            running : bool;
+            total : ulint;
+        END_VAR
+            parts(step := 1);
+            batches(step := 10);
+            scans := scans + 1;
+            running := TRUE;
+        END_PROGRAM";
+
+    fn compile(src: &str) -> (iec_syntax::File, CompilationUnit) {
+        let ast: iec_syntax::File = src.parse().unwrap();
+        let mut diags = Diagnostics::new();
+        let cu =
+            iec::process(&ast, &mut PassContext::new_nop_logger(&mut diags));
+        assert!(!diags.has_errors(), "{:?}", diags);
+
+        (ast, cu)
+    }
+
+    fn temp_file(test_name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "iec-runtime-{}-{}",
+            test_name,
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("retain.json")
+    }
+
+    #[test]
+    fn find_all_retained_variables() {
+        let (_, cu) = compile(ORIGINAL);
+
+        let got: Vec<_> = retained_variables(&cu)
+            .into_iter()
+            .map(|v| format!("{}: {}", v.name, v.ty))
+            .collect();
+
+        assert_eq!(
+            got,
+            vec![
+                "main.batches.calls: int",
+                "main.batches.cv: dint",
+                "main.batches.step: int",
+                "main.parts.cv: dint",
+                "main.running: bool",
+                "main.total: ulint",
+                "setpoint: real",
+            ]
+        );
+    }
+
+    #[test]
+    fn values_survive_a_restart() {
+        let (ast, cu) = compile(ORIGINAL);
+        let path = temp_file("restart");
+        let store = RetainStore::new(&path, &cu);
+
+        let mut before = Interpreter::new(&ast, &cu);
+        let report = store.restore(&mut before).unwrap();
+        assert!(report.restored.is_empty());
+        assert_eq!(report.reinitialised.len(), store.variables().len());
+        for _ in 0..3 {
+            before.run_program("main").unwrap();
+        }
+        // too big to fit in an i64
+        let total = Value::Integer {
+            value: i128::from(u64::MAX),
+            ty: IntegerType::ULInt,
+        };
+        Target::set(&mut before, "main.total", total).unwrap();
+        Target::set(&mut before, "setpoint", Value::Real(42.5)).unwrap();
+        store.save(&before).unwrap();
+
+        // the snapshot should also work with a different target
+        let mut after = Vm::new(bytecode::compile(&cu)).unwrap();
+        let report = store.restore(&mut after).unwrap();
+        assert_eq!(report.restored.len(), store.variables().len());
+        assert!(report.dropped.is_empty());
+        assert_eq!(Target::get(&after, "setpoint"), Some(Value::Real(42.5)));
+
+        after.run_program("main").unwrap();
+        let get = |path: &str| Target::get(&after, path).unwrap();
+        assert_eq!(get("main.parts.cv").as_integer(), Some(4));
+        assert_eq!(get("main.batches.cv").as_integer(), Some(40));
+        assert_eq!(get("main.batches.calls").as_integer(), Some(4));
+        // volatile variables start again from scratch
+        assert_eq!(get("main.parts.calls").as_integer(), Some(1));
+        assert_eq!(get("main.scans").as_integer(), Some(1));
+        assert_eq!(get("main.total").as_integer(), Some(i128::from(u64::MAX)));
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn match_variables_after_the_program_changes() {
+        let (ast, cu) = compile(ORIGINAL);
+        let mut interpreter = Interpreter::new(&ast, &cu);
+        interpreter.run_program("main").unwrap();
+        interpreter.set("setpoint", Value::Real(42.5)).unwrap();
+        let snapshot =
+            Snapshot::capture(&interpreter, &retained_variables(&cu));
+
+        // total became a LINT, running was removed, and a new variable was
+        // added
+        let changed = ORIGINAL
+            .replace("total : ulint", "total : lint")
+            .replace("running : bool", "faults : int")
+            .replace("running := TRUE;", "");
+        let (ast, cu) = compile(&changed);
+        let mut interpreter = Interpreter::new(&ast, &cu);
+        let snapshot = Snapshot::from_json(&snapshot.to_json()).unwrap();
+
+        let report = snapshot
+            .restore(&mut interpreter, &retained_variables(&cu))
+            .unwrap();
+
+        assert_eq!(report.dropped, vec!["main.running"]);
+        assert_eq!(report.reinitialised, vec!["main.faults", "main.total"]);
+        assert_eq!(report.restored.len(), 5);
+        assert_eq!(interpreter.get("setpoint").unwrap().as_real(), Some(42.5));
+        assert_eq!(
+            interpreter.get("main.total").unwrap().as_integer(),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn reject_unknown_snapshot_versions() {
+        let src = r#"{ "version": 99, "variables": [] }"#;
+
+        match Snapshot::from_json(src) {
+            Err(RetainError::UnsupportedVersion(99)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        match Snapshot::from_json("{}") {
+            Err(RetainError::MissingVersion) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+}
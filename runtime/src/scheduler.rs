@@ -0,0 +1,768 @@
+//! The scan cycle itself.
+
+use crate::{
+    Clock, Io, NoIo, SystemClock, Target, TaskConfig, TaskKind, TaskStats,
+};
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+
+/// Executes program instances according to their [`TaskConfig`]s.
+///
+/// Each scan reads the inputs, executes every task which is due (highest
+/// priority first), then writes the outputs. Tasks aren't preempted, so a
+/// long-running task delays any task which becomes due while it executes.
+///
+/// # Examples
+///
+/// ```rust
+/// use iec::interpreter::Interpreter;
+/// use iec::passes::PassContext;
+/// use iec::Diagnostics;
+/// use iec_runtime::{Runtime, SimulatedClock, TaskConfig};
+/// use std::time::Duration;
+///
+/// let src = "
+///     PROGRAM main
+///         VAR
+///             count: int;
+///         END_VAR
+///         count := count + 1;
+///     END_PROGRAM";
+/// let ast: iec_syntax::File = src.parse().unwrap();
+/// let mut diags = Diagnostics::new();
+/// let cu = iec::process(&ast, &mut PassContext::new_nop_logger(&mut diags));
+///
+/// let interpreter = Interpreter::new(&ast, &cu);
+/// let mut runtime = Runtime::new(interpreter, SimulatedClock::new());
+/// runtime
+///     .add_task(
+///         TaskConfig::periodic("fast", Duration::from_millis(10))
+///             .with_program("main"),
+///     )
+///     .unwrap();
+///
+/// runtime.run_until(Duration::from_millis(100)).unwrap();
+///
+/// let count = runtime.target().get("main.count").unwrap();
+/// assert_eq!(count.as_integer(), Some(10));
+/// ```
+pub struct Runtime<T, C = SystemClock, I = NoIo> {
+    target: T,
+    clock: C,
+    io: I,
+    tasks: Vec<Task>,
+    poll_interval: Duration,
+    scans: u64,
+}
+
+impl<T: Target, C: Clock> Runtime<T, C> {
+    pub fn new(target: T, clock: C) -> Runtime<T, C> {
+        Runtime {
+            target,
+            clock,
+            io: NoIo,
+            tasks: Vec::new(),
+            poll_interval: Runtime::<T, C>::DEFAULT_POLL_INTERVAL,
+            scans: 0,
+        }
+    }
+}
+
+impl<T: Target, C: Clock, I: Io> Runtime<T, C, I> {
+    /// How often event triggers are checked when no periodic task is due.
+    pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+    /// Connect the runtime to some inputs and outputs.
+    pub fn with_io<J: Io>(self, io: J) -> Runtime<T, C, J> {
+        Runtime {
+            target: self.target,
+            clock: self.clock,
+            io,
+            tasks: self.tasks,
+            poll_interval: self.poll_interval,
+            scans: self.scans,
+        }
+    }
+
+    /// Change how often event triggers are checked.
+    ///
+    /// # Panics
+    ///
+    /// The interval must be non-zero.
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        assert!(interval > Duration::from_secs(0));
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Register a task, making sure it doesn't conflict with the existing
+    /// ones and creating any program instances it needs.
+    pub fn add_task(&mut self, config: TaskConfig) -> Result<(), ConfigError> {
+        if self.task_index(&config.name).is_some() {
+            return Err(ConfigError::DuplicateTask(config.name));
+        }
+        if config.kind == TaskKind::Periodic(Duration::from_secs(0)) {
+            return Err(ConfigError::ZeroInterval(config.name));
+        }
+
+        for instance in &config.programs {
+            let existing = self.tasks.iter().find(|t| {
+                t.config
+                    .programs
+                    .iter()
+                    .any(|p| p.name.eq_ignore_ascii_case(&instance.name))
+            });
+
+            // a program instance can only be associated with one task
+            if let Some(existing) = existing {
+                return Err(ConfigError::ProgramAlreadyAssigned {
+                    program: instance.name.clone(),
+                    task: existing.config.name.clone(),
+                });
+            }
+        }
+
+        for instance in &config.programs {
+            self.target
+                .instantiate(&instance.name, &instance.program)
+                .map_err(|error| ConfigError::InvalidInstance {
+                    instance: instance.name.clone(),
+                    reason: error.to_string(),
+                })?;
+        }
+
+        let next_due = self.clock.now();
+        self.tasks.push(Task {
+            config,
+            stats: TaskStats::default(),
+            next_due,
+            previous_trigger: false,
+            pending: false,
+        });
+        Ok(())
+    }
+
+    pub fn target(&self) -> &T {
+        &self.target
+    }
+
+    pub fn target_mut(&mut self) -> &mut T {
+        &mut self.target
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    pub fn io(&self) -> &I {
+        &self.io
+    }
+
+    pub fn io_mut(&mut self) -> &mut I {
+        &mut self.io
+    }
+
+    /// The number of scans which executed at least one task.
+    pub fn scans(&self) -> u64 {
+        self.scans
+    }
+
+    /// Cycle time measurements for a task.
+    pub fn stats(&self, task: &str) -> Option<&TaskStats> {
+        self.task_index(task).map(|i| &self.tasks[i].stats)
+    }
+
+    /// Activate a task during the next scan, regardless of its trigger.
+    pub fn trigger(&mut self, task: &str) -> Result<(), ConfigError> {
+        let index = self
+            .task_index(task)
+            .ok_or_else(|| ConfigError::UnknownTask(task.to_string()))?;
+        self.tasks[index].pending = true;
+
+        Ok(())
+    }
+
+    /// Do a single scan, returning the names of the tasks which were
+    /// executed.
+    pub fn scan(&mut self) -> Result<Vec<String>, Fault> {
+        self.io.read_inputs(&mut self.target).map_err(Fault::Io)?;
+
+        let now = self.clock.now();
+        let mut ready = Vec::new();
+
+        for (index, task) in self.tasks.iter_mut().enumerate() {
+            let due = match task.config.kind {
+                TaskKind::Periodic(_) => task.next_due <= now,
+                TaskKind::Event(ref trigger) => {
+                    let level = self
+                        .target
+                        .get(trigger)
+                        .and_then(|v| v.as_bool())
+                        .unwrap_or(false);
+                    let rising_edge = level && !task.previous_trigger;
+                    task.previous_trigger = level;
+                    rising_edge
+                }
+            };
+
+            if due || task.pending {
+                ready.push(index);
+            }
+        }
+
+        // ties are broken by the order tasks were added
+        ready.sort_by_key(|&index| (self.tasks[index].config.priority, index));
+
+        for &index in &ready {
+            self.execute(index)?;
+        }
+
+        if ready.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        self.io.write_outputs(&self.target).map_err(Fault::Io)?;
+        self.scans += 1;
+
+        Ok(ready
+            .into_iter()
+            .map(|index| self.tasks[index].config.name.clone())
+            .collect())
+    }
+
+    fn execute(&mut self, index: usize) -> Result<(), Fault> {
+        let task = &mut self.tasks[index];
+        let start = self.clock.now();
+
+        for instance in &task.config.programs {
+            self.target.execute(&instance.name).map_err(|error| {
+                Fault::Program {
+                    task: task.config.name.clone(),
+                    instance: instance.name.clone(),
+                    error,
+                }
+            })?;
+        }
+
+        let end = self.clock.now();
+        let elapsed = end - start;
+        task.stats.record(elapsed);
+        task.pending = false;
+
+        if let TaskKind::Periodic(interval) = task.config.kind {
+            task.next_due += interval;
+
+            // skip any activations we were too slow to make
+            while task.next_due <= end {
+                task.next_due += interval;
+                task.stats.overruns += 1;
+            }
+        }
+
+        match task.config.watchdog {
+            Some(limit) if elapsed > limit => Err(Fault::Watchdog {
+                task: task.config.name.clone(),
+                elapsed,
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// When the next scan should happen.
+    pub fn next_wakeup(&self) -> Duration {
+        let now = self.clock.now();
+        let mut wakeup = None;
+        let mut polling = false;
+
+        for task in &self.tasks {
+            if task.pending {
+                return now;
+            }
+
+            match task.config.kind {
+                TaskKind::Periodic(_) => {
+                    wakeup =
+                        Some(wakeup.map_or(task.next_due, |w: Duration| {
+                            w.min(task.next_due)
+                        }));
+                }
+                TaskKind::Event(_) => polling = true,
+            }
+        }
+
+        // event triggers need to be checked regularly
+        if polling || wakeup.is_none() {
+            let poll = now + self.poll_interval;
+            wakeup = Some(wakeup.map_or(poll, |w| w.min(poll)));
+        }
+
+        wakeup.map_or(now, |w| w.max(now))
+    }
+
+    /// Keep scanning until the clock reaches `deadline`, sleeping between
+    /// scans.
+    pub fn run_until(&mut self, deadline: Duration) -> Result<(), Fault> {
+        while self.clock.now() < deadline {
+            self.scan()?;
+            let wakeup = self.next_wakeup().min(deadline);
+            self.clock.sleep_until(wakeup);
+        }
+
+        Ok(())
+    }
+
+    fn task_index(&self, name: &str) -> Option<usize> {
+        self.tasks
+            .iter()
+            .position(|t| t.config.name.eq_ignore_ascii_case(name))
+    }
+}
+
+/// A task and its scheduling state.
+#[derive(Debug, Clone, PartialEq)]
+struct Task {
+    config: TaskConfig,
+    stats: TaskStats,
+    /// When a periodic task should next be executed.
+    next_due: Duration,
+    /// The trigger's value during the previous scan, used to detect rising
+    /// edges.
+    previous_trigger: bool,
+    /// The task was activated manually.
+    pending: bool,
+}
+
+/// A task configuration was rejected.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    DuplicateTask(String),
+    /// A periodic task would need to execute infinitely often.
+    ZeroInterval(String),
+    ProgramAlreadyAssigned {
+        program: String,
+        task: String,
+    },
+    /// The target couldn't create a program instance (e.g. because the
+    /// program doesn't exist).
+    InvalidInstance {
+        instance: String,
+        reason: String,
+    },
+    UnknownTask(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            ConfigError::DuplicateTask(ref name) => {
+                write!(f, "The \"{}\" task is already defined", name)
+            }
+            ConfigError::ZeroInterval(ref name) => {
+                write!(f, "The \"{}\" task has an interval of zero", name)
+            }
+            ConfigError::ProgramAlreadyAssigned {
+                ref program,
+                ref task,
+            } => write!(
+                f,
+                "The \"{}\" program is already executed by the \"{}\" task",
+                program, task
+            ),
+            ConfigError::InvalidInstance {
+                ref instance,
+                ref reason,
+            } => write!(
+                f,
+                "Unable to create the \"{}\" program instance: {}",
+                instance, reason
+            ),
+            ConfigError::UnknownTask(ref name) => {
+                write!(f, "Unknown task, \"{}\"", name)
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+/// Something went wrong during a scan, and the runtime should be stopped.
+#[derive(Debug)]
+pub enum Fault {
+    /// A program instance failed to execute (e.g. it divided by zero).
+    Program {
+        task: String,
+        instance: String,
+        error: Box<dyn Error>,
+    },
+    /// Reading the inputs or writing the outputs failed.
+    Io(Box<dyn Error>),
+    /// A task took longer than its watchdog allows.
+    Watchdog {
+        task: String,
+        elapsed: Duration,
+        limit: Duration,
+    },
+}
+
+impl Display for Fault {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            Fault::Program {
+                ref task,
+                ref instance,
+                ref error,
+            } => write!(
+                f,
+                "The \"{}\" program instance (in the \"{}\" task) failed: {}",
+                instance, task, error
+            ),
+            Fault::Io(ref error) => write!(f, "I/O failed: {}", error),
+            Fault::Watchdog {
+                ref task,
+                elapsed,
+                limit,
+            } => write!(
+                f,
+                "The \"{}\" task took {:?}, exceeding its watchdog of {:?}",
+                task, elapsed, limit
+            ),
+        }
+    }
+}
+
+impl Error for Fault {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            Fault::Program { ref error, .. } | Fault::Io(ref error) => {
+                Some(error.as_ref())
+            }
+            Fault::Watchdog { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ProcessImage, SimulatedClock};
+    use iec::bytecode::{self, Vm};
+    use iec::interpreter::{Interpreter, Value};
+    use iec::testing::analyse;
+    use std::collections::HashMap;
+
+    /// A [`Target`] which records when each program was executed and
+    /// pretends each one takes a fixed amount of time.
+    #[derive(Debug, Default)]
+    struct FakeTarget {
+        clock: SimulatedClock,
+        durations: HashMap<String, Duration>,
+        log: Vec<(u64, String)>,
+    }
+
+    impl FakeTarget {
+        fn new(clock: &SimulatedClock) -> FakeTarget {
+            FakeTarget {
+                clock: clock.clone(),
+                ..Default::default()
+            }
+        }
+
+        fn takes(mut self, program: &str, ms: u64) -> FakeTarget {
+            self.durations
+                .insert(program.to_string(), Duration::from_millis(ms));
+            self
+        }
+
+        fn executions(&self, program: &str) -> Vec<u64> {
+            self.log
+                .iter()
+                .filter(|(_, p)| p == program)
+                .map(|&(t, _)| t)
+                .collect()
+        }
+    }
+
+    impl Target for FakeTarget {
+        fn instantiate(
+            &mut self,
+            _instance: &str,
+            _program: &str,
+        ) -> Result<(), Box<dyn Error>> {
+            Ok(())
+        }
+
+        fn execute(&mut self, program: &str) -> Result<(), Box<dyn Error>> {
+            let now = self.clock.now().as_millis() as u64;
+            self.log.push((now, program.to_string()));
+
+            if let Some(&duration) = self.durations.get(program) {
+                self.clock.advance(duration);
+            }
+            Ok(())
+        }
+
+        fn get(&self, _path: &str) -> Option<Value> {
+            None
+        }
+
+        fn set(
+            &mut self,
+            path: &str,
+            _value: Value,
+        ) -> Result<(), Box<dyn Error>> {
+            Err(format!("Unknown variable, \"{}\"", path).into())
+        }
+    }
+
+    fn periodic(name: &str, ms: u64, program: &str) -> TaskConfig {
+        TaskConfig::periodic(name, Duration::from_millis(ms))
+            .with_program(program)
+    }
+
+    #[test]
+    fn periodic_tasks_run_at_their_interval() {
+        let clock = SimulatedClock::new();
+        let mut runtime = Runtime::new(FakeTarget::new(&clock), clock);
+        runtime.add_task(periodic("fast", 10, "fast_prog")).unwrap();
+        runtime.add_task(periodic("slow", 25, "slow_prog")).unwrap();
+
+        runtime.run_until(Duration::from_millis(60)).unwrap();
+
+        let target = runtime.target();
+        assert_eq!(target.executions("fast_prog"), vec![0, 10, 20, 30, 40, 50]);
+        assert_eq!(target.executions("slow_prog"), vec![0, 25, 50]);
+        assert_eq!(runtime.stats("fast").unwrap().activations, 6);
+        assert_eq!(runtime.scans(), 7);
+    }
+
+    #[test]
+    fn higher_priority_tasks_go_first() {
+        let clock = SimulatedClock::new();
+        let target = FakeTarget::new(&clock).takes("logging", 2);
+        let mut runtime = Runtime::new(target, clock);
+        runtime
+            .add_task(periodic("background", 10, "logging").with_priority(5))
+            .unwrap();
+        runtime
+            .add_task(periodic("control", 10, "pid").with_priority(0))
+            .unwrap();
+
+        let executed = runtime.scan().unwrap();
+
+        assert_eq!(executed, vec!["control", "background"]);
+        assert_eq!(runtime.target().executions("pid"), vec![0]);
+        assert_eq!(
+            runtime.stats("background").unwrap().last,
+            Duration::from_millis(2)
+        );
+    }
+
+    #[test]
+    fn slow_tasks_skip_activations() {
+        let clock = SimulatedClock::new();
+        let target = FakeTarget::new(&clock).takes("main", 25);
+        let mut runtime = Runtime::new(target, clock);
+        runtime.add_task(periodic("task", 10, "main")).unwrap();
+
+        runtime.run_until(Duration::from_millis(100)).unwrap();
+
+        // 0-25 misses 10 and 20, 30-55 misses 40 and 50, and so on
+        assert_eq!(runtime.target().executions("main"), vec![0, 30, 60, 90]);
+        let stats = runtime.stats("task").unwrap();
+        assert_eq!(stats.overruns, 8);
+        assert_eq!(stats.max, Duration::from_millis(25));
+    }
+
+    #[test]
+    fn the_watchdog_faults_when_a_task_takes_too_long() {
+        let clock = SimulatedClock::new();
+        let target = FakeTarget::new(&clock).takes("main", 15);
+        let mut runtime = Runtime::new(target, clock);
+        runtime
+            .add_task(
+                periodic("task", 10, "main")
+                    .with_watchdog(Duration::from_millis(12)),
+            )
+            .unwrap();
+
+        match runtime.scan() {
+            Err(Fault::Watchdog { task, elapsed, .. }) => {
+                assert_eq!(task, "task");
+                assert_eq!(elapsed, Duration::from_millis(15));
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn invalid_task_configurations_are_rejected() {
+        let clock = SimulatedClock::new();
+        let mut runtime = Runtime::new(FakeTarget::new(&clock), clock);
+        runtime.add_task(periodic("task", 10, "main")).unwrap();
+
+        assert_eq!(
+            runtime.add_task(periodic("TASK", 5, "other")),
+            Err(ConfigError::DuplicateTask(String::from("TASK")))
+        );
+        assert_eq!(
+            runtime.add_task(periodic("second", 5, "MAIN")),
+            Err(ConfigError::ProgramAlreadyAssigned {
+                program: String::from("MAIN"),
+                task: String::from("task"),
+            })
+        );
+        assert_eq!(
+            runtime.add_task(periodic("third", 0, "other")),
+            Err(ConfigError::ZeroInterval(String::from("third")))
+        );
+        assert_eq!(
+            runtime.trigger("missing"),
+            Err(ConfigError::UnknownTask(String::from("missing")))
+        );
+    }
+
+    #[test]
+    fn event_tasks_run_on_a_rising_edge() {
+        let src = "
+            VAR_GLOBAL
+                alarm: bool;
+            END_VAR
+
+            PROGRAM handler
+                VAR
+                    count: int;
+                END_VAR
+                count := count + 1;
+            END_PROGRAM";
+        let (ast, cu) = analyse(src);
+        let mut io = ProcessImage::new();
+        io.set_input("alarm", Value::Bool(false));
+        io.watch_output("handler.count");
+
+        let interpreter = Interpreter::new(&ast, &cu);
+        let mut runtime =
+            Runtime::new(interpreter, SimulatedClock::new()).with_io(io);
+        runtime
+            .add_task(
+                TaskConfig::event("on_alarm", "alarm").with_program("handler"),
+            )
+            .unwrap();
+
+        assert!(runtime.scan().unwrap().is_empty());
+        runtime.io_mut().set_input("alarm", Value::Bool(true));
+        assert_eq!(runtime.scan().unwrap(), vec!["on_alarm"]);
+        // the trigger is still high, but that isn't a rising edge
+        assert!(runtime.scan().unwrap().is_empty());
+        runtime.trigger("on_alarm").unwrap();
+        runtime.run_until(Duration::from_millis(10)).unwrap();
+
+        let count = runtime.io().output("handler.count").unwrap();
+        assert_eq!(count.as_integer(), Some(2));
+    }
+
+    #[test]
+    fn errors_from_the_vm_stop_the_runtime() {
+        let src = "
+            PROGRAM main
+                VAR
+                    countdown: int;
+                    result: int;
+                END_VAR
+                countdown := countdown + 1;
+                result := 10 / (3 - countdown);
+            END_PROGRAM";
+        let (_, cu) = analyse(src);
+        let vm = Vm::new(bytecode::compile(&cu)).unwrap();
+        let mut runtime = Runtime::new(vm, SimulatedClock::new());
+        runtime.add_task(periodic("task", 10, "main")).unwrap();
+
+        let fault = runtime.run_until(Duration::from_secs(1)).unwrap_err();
+
+        match fault {
+            Fault::Program { task, instance, .. } => {
+                assert_eq!(
+                    (task.as_str(), instance.as_str()),
+                    ("task", "main")
+                );
+            }
+            other => panic!("Unexpected fault: {:?}", other),
+        }
+        assert_eq!(runtime.clock().now(), Duration::from_millis(20));
+    }
+
+    #[test]
+    fn each_program_instance_has_its_own_state() {
+        let src = "
+            PROGRAM counter
+                VAR
+                    count: int;
+                END_VAR
+                count := count + 1;
+            END_PROGRAM";
+        let (ast, cu) = analyse(src);
+        let vm = Vm::new(bytecode::compile(&cu)).unwrap();
+        let interpreter = Interpreter::new(&ast, &cu);
+
+        fn schedule<T: Target>(target: T) -> Runtime<T, SimulatedClock> {
+            let mut runtime = Runtime::new(target, SimulatedClock::new());
+            runtime
+                .add_task(
+                    TaskConfig::periodic("fast", Duration::from_millis(10))
+                        .with_instance("a", "counter"),
+                )
+                .unwrap();
+            runtime
+                .add_task(
+                    TaskConfig::periodic("slow", Duration::from_millis(25))
+                        .with_instance("b", "counter"),
+                )
+                .unwrap();
+            runtime.run_until(Duration::from_millis(100)).unwrap();
+            runtime
+        }
+
+        let interpreter = schedule(interpreter);
+        let vm = schedule(vm);
+
+        for runtime in &[interpreter.target() as &dyn Target, vm.target()] {
+            assert_eq!(runtime.get("a.count").unwrap().as_integer(), Some(10));
+            assert_eq!(runtime.get("b.count").unwrap().as_integer(), Some(4));
+            // the default instance is never executed
+            assert_eq!(
+                runtime.get("counter.count").unwrap().as_integer(),
+                Some(0)
+            );
+        }
+    }
+
+    #[test]
+    fn instances_must_refer_to_a_known_program() {
+        let src = "
+            PROGRAM main
+            END_PROGRAM
+            PROGRAM other
+            END_PROGRAM";
+        let (ast, cu) = analyse(src);
+        let interpreter = Interpreter::new(&ast, &cu);
+        let mut runtime = Runtime::new(interpreter, SimulatedClock::new());
+        let task = |name| TaskConfig::periodic(name, Duration::from_millis(10));
+
+        assert_eq!(
+            runtime.add_task(task("first").with_instance("a", "missing")),
+            Err(ConfigError::InvalidInstance {
+                instance: String::from("a"),
+                reason: String::from("Unknown item, \"missing\""),
+            })
+        );
+        // "main" is already the name of main's default instance
+        assert_eq!(
+            runtime.add_task(task("second").with_instance("main", "other")),
+            Err(ConfigError::InvalidInstance {
+                instance: String::from("main"),
+                reason: String::from("The \"main\" instance already exists"),
+            })
+        );
+    }
+}
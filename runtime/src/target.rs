@@ -0,0 +1,89 @@
+//! The things a [`crate::Runtime`] can execute programs with.
+
+use iec::bytecode::{Cell, Vm};
+use iec::interpreter::{Interpreter, Value};
+use std::error::Error;
+
+/// An execution engine hosting one or more PROGRAM instances.
+///
+/// Every program instance keeps its own state between calls to
+/// [`Target::execute()`], so the same program can be executed by several
+/// tasks without them interfering. Global variables are shared between all
+/// instances.
+///
+/// Only the [`Interpreter`] and the bytecode [`Vm`] implement this trait.
+/// The Cranelift JIT always executes every program once per cycle, and the
+/// C, LLVM, and WebAssembly backends generate code which is driven by the
+/// host application, so none of them can be scheduled by a
+/// [`crate::Runtime`].
+pub trait Target {
+    /// Make sure there is an instance of `program` called `instance`,
+    /// creating it if necessary.
+    ///
+    /// Each program starts with a single instance named after itself.
+    fn instantiate(
+        &mut self,
+        instance: &str,
+        program: &str,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Execute a single program instance's body once.
+    fn execute(&mut self, instance: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Read a variable, using either `"instance.variable"` or the name of a
+    /// global variable.
+    fn get(&self, path: &str) -> Option<Value>;
+
+    /// Overwrite a variable, converting the value to the variable's type.
+    fn set(&mut self, path: &str, value: Value) -> Result<(), Box<dyn Error>>;
+}
+
+impl<'a> Target for Interpreter<'a> {
+    fn instantiate(
+        &mut self,
+        instance: &str,
+        program: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        Interpreter::instantiate(self, instance, program)?;
+        Ok(())
+    }
+
+    fn execute(&mut self, instance: &str) -> Result<(), Box<dyn Error>> {
+        self.run_program(instance)?;
+        Ok(())
+    }
+
+    fn get(&self, path: &str) -> Option<Value> {
+        Interpreter::get(self, path).cloned()
+    }
+
+    fn set(&mut self, path: &str, value: Value) -> Result<(), Box<dyn Error>> {
+        Interpreter::set(self, path, value)?;
+        Ok(())
+    }
+}
+
+impl Target for Vm {
+    fn instantiate(
+        &mut self,
+        instance: &str,
+        program: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        Vm::instantiate(self, instance, program)?;
+        Ok(())
+    }
+
+    fn execute(&mut self, instance: &str) -> Result<(), Box<dyn Error>> {
+        self.run_program(instance)?;
+        Ok(())
+    }
+
+    fn get(&self, path: &str) -> Option<Value> {
+        Vm::get(self, path).and_then(Cell::as_value).cloned()
+    }
+
+    fn set(&mut self, path: &str, value: Value) -> Result<(), Box<dyn Error>> {
+        Vm::set(self, path, value)?;
+        Ok(())
+    }
+}
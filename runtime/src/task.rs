@@ -0,0 +1,159 @@
+//! Configuring when programs are executed.
+
+use std::time::Duration;
+
+/// A task, which executes a list of program instances whenever it's
+/// activated.
+///
+/// Like IEC 61131-3, lower numbers mean a higher priority and `0` is the
+/// most urgent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskConfig {
+    pub name: String,
+    pub priority: u8,
+    pub kind: TaskKind,
+    /// The program instances executed each time the task is activated, in
+    /// order.
+    pub programs: Vec<ProgramInstance>,
+    /// The longest a single activation may take before the runtime faults.
+    pub watchdog: Option<Duration>,
+}
+
+impl TaskConfig {
+    /// The lowest priority, used when one isn't specified.
+    pub const DEFAULT_PRIORITY: u8 = u8::MAX;
+
+    /// A task which is activated every `interval`.
+    pub fn periodic<S: Into<String>>(
+        name: S,
+        interval: Duration,
+    ) -> TaskConfig {
+        TaskConfig::new(name, TaskKind::Periodic(interval))
+    }
+
+    /// A task which is activated on the rising edge of a `BOOL` variable.
+    pub fn event<S, T>(name: S, trigger: T) -> TaskConfig
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        TaskConfig::new(name, TaskKind::Event(trigger.into()))
+    }
+
+    fn new<S: Into<String>>(name: S, kind: TaskKind) -> TaskConfig {
+        TaskConfig {
+            name: name.into(),
+            priority: TaskConfig::DEFAULT_PRIORITY,
+            kind,
+            programs: Vec::new(),
+            watchdog: None,
+        }
+    }
+
+    pub fn with_priority(mut self, priority: u8) -> TaskConfig {
+        self.priority = priority;
+        self
+    }
+
+    /// Execute the instance named after `program`.
+    pub fn with_program<S: Into<String>>(self, program: S) -> TaskConfig {
+        let program = program.into();
+        self.with_instance(program.clone(), program)
+    }
+
+    /// Execute a named instance of `program`, so the same program can be
+    /// executed by several tasks with separate state.
+    pub fn with_instance<S, P>(mut self, instance: S, program: P) -> TaskConfig
+    where
+        S: Into<String>,
+        P: Into<String>,
+    {
+        self.programs.push(ProgramInstance {
+            name: instance.into(),
+            program: program.into(),
+        });
+        self
+    }
+
+    pub fn with_watchdog(mut self, limit: Duration) -> TaskConfig {
+        self.watchdog = Some(limit);
+        self
+    }
+}
+
+/// A named instance of a program, executed by a task.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgramInstance {
+    /// The instance's name, used when reading its variables (e.g.
+    /// `"instance.variable"`).
+    pub name: String,
+    pub program: String,
+}
+
+/// What causes a task to be activated.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TaskKind {
+    /// Activate the task at a fixed interval.
+    Periodic(Duration),
+    /// Activate the task whenever the `BOOL` variable at this path goes from
+    /// `FALSE` to `TRUE`. Triggers are checked after the inputs are read.
+    Event(String),
+}
+
+/// Cycle time measurements for a single task.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct TaskStats {
+    /// The number of times the task has been executed.
+    pub activations: u64,
+    /// The number of periodic activations which were skipped because the
+    /// previous one hadn't finished in time.
+    pub overruns: u64,
+    /// How long the most recent activation took.
+    pub last: Duration,
+    pub min: Duration,
+    pub max: Duration,
+    pub total: Duration,
+}
+
+impl TaskStats {
+    pub(crate) fn record(&mut self, elapsed: Duration) {
+        if self.activations == 0 || elapsed < self.min {
+            self.min = elapsed;
+        }
+        self.max = self.max.max(elapsed);
+        self.last = elapsed;
+        self.total += elapsed;
+        self.activations += 1;
+    }
+
+    /// The mean time taken by each activation.
+    pub fn average(&self) -> Option<Duration> {
+        if self.activations == 0 {
+            return None;
+        }
+
+        let nanos = self.total.as_nanos() / u128::from(self.activations);
+        Some(Duration::from_nanos(nanos as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_track_the_cycle_time() {
+        let mut stats = TaskStats::default();
+        assert_eq!(stats.average(), None);
+
+        for &ms in &[4, 2, 6] {
+            stats.record(Duration::from_millis(ms));
+        }
+
+        assert_eq!(stats.activations, 3);
+        assert_eq!(stats.last, Duration::from_millis(6));
+        assert_eq!(stats.min, Duration::from_millis(2));
+        assert_eq!(stats.max, Duration::from_millis(6));
+        assert_eq!(stats.average(), Some(Duration::from_millis(4)));
+    }
+}
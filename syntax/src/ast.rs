@@ -3,6 +3,8 @@ use heapsize::HeapSizeOf;
 use heapsize_derive::HeapSizeOf;
 use serde_derive::{Deserialize, Serialize};
 use std::any::Any;
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
 
 pub trait AstNode: Any + HeapSizeOf {
     fn span(&self) -> ByteSpan;
@@ -20,6 +22,8 @@ sum_type::sum_type! {
         Program,
         Function,
         FunctionBlock,
+        VarBlock,
+        Configuration,
     }
 }
 
@@ -105,6 +109,9 @@ impl From<Identifier> for DottedIdentifier {
 pub struct Declaration {
     pub ident: Identifier,
     pub ty: Identifier,
+    /// The direct address this variable is located at
+    /// (`name AT %IX0.0 : BOOL`).
+    pub location: Option<DirectAddress>,
     pub span: ByteSpan,
 }
 
@@ -114,7 +121,132 @@ impl Declaration {
         ty: Identifier,
         span: ByteSpan,
     ) -> Declaration {
-        Declaration { ident, ty, span }
+        Declaration {
+            ident,
+            ty,
+            location: None,
+            span,
+        }
+    }
+}
+
+/// A directly represented variable, such as `%IX0.7`, `%QW4`, or `%MD10`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, HeapSizeOf)]
+pub struct DirectAddress {
+    pub area: Area,
+    pub size: Size,
+    /// The numbers after the prefix (e.g. `[0, 7]` for `%IX0.7`).
+    pub indices: Vec<u32>,
+    pub span: ByteSpan,
+}
+
+impl DirectAddress {
+    /// Parse an address's text (e.g. `%IX0.7`), the inverse of
+    /// [`DirectAddress::to_path()`].
+    pub fn parse(
+        text: &str,
+        span: ByteSpan,
+    ) -> Result<DirectAddress, &'static str> {
+        crate::utils::parse_direct_address(text, span)
+    }
+
+    /// The name this address is known by when it's used like a variable.
+    pub fn to_path(&self) -> DottedIdentifier {
+        Identifier {
+            value: self.to_string(),
+            span: self.span,
+        }
+        .into()
+    }
+}
+
+impl Display for DirectAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "%{}{}", self.area.prefix(), self.size.prefix())?;
+
+        for (i, index) in self.indices.iter().enumerate() {
+            if i > 0 {
+                write!(f, ".")?;
+            }
+            write!(f, "{}", index)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Which part of the process image a [`DirectAddress`] refers to.
+#[derive(
+    Debug,
+    Copy,
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+    HeapSizeOf,
+)]
+pub enum Area {
+    Input,
+    Output,
+    Memory,
+}
+
+impl Area {
+    pub fn prefix(self) -> char {
+        match self {
+            Area::Input => 'I',
+            Area::Output => 'Q',
+            Area::Memory => 'M',
+        }
+    }
+}
+
+/// How much data a [`DirectAddress`] refers to.
+#[derive(
+    Debug,
+    Copy,
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+    HeapSizeOf,
+)]
+pub enum Size {
+    /// A single bit (`X`, or no size prefix at all).
+    Bit,
+    Byte,
+    Word,
+    DoubleWord,
+    LongWord,
+}
+
+impl Size {
+    pub fn prefix(self) -> char {
+        match self {
+            Size::Bit => 'X',
+            Size::Byte => 'B',
+            Size::Word => 'W',
+            Size::DoubleWord => 'D',
+            Size::LongWord => 'L',
+        }
+    }
+
+    pub fn bits(self) -> u32 {
+        match self {
+            Size::Bit => 1,
+            Size::Byte => 8,
+            Size::Word => 16,
+            Size::DoubleWord => 32,
+            Size::LongWord => 64,
+        }
     }
 }
 
@@ -133,6 +265,7 @@ sum_type::sum_type! {
         Binary(BinaryExpression),
         Unary(UnaryExpression),
         FunctionCall(FunctionCall),
+        DirectAddress(DirectAddress),
     }
 }
 
@@ -241,6 +374,16 @@ pub struct FunctionCall {
 pub enum FunctionArg {
     Bare(Expression),
     Named(Assignment),
+    Output(OutputAssignment),
+}
+
+/// Copying one of a function's outputs into a variable after it is called
+/// (`parameter => variable`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, HeapSizeOf)]
+pub struct OutputAssignment {
+    pub parameter: Identifier,
+    pub variable: DottedIdentifier,
+    pub span: ByteSpan,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, HeapSizeOf)]
@@ -270,6 +413,7 @@ pub struct RepeatLoop {
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, HeapSizeOf)]
 pub struct VarBlock {
     pub kind: VarBlockKind,
+    pub retention: Retention,
     pub declarations: Vec<Declaration>,
     pub span: ByteSpan,
 }
@@ -280,6 +424,105 @@ pub enum VarBlockKind {
     Input,
     Output,
     InputOutput,
+    Temp,
+    Global,
+}
+
+/// Whether a block's variables should survive a restart of the PLC.
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, HeapSizeOf,
+)]
+pub enum Retention {
+    /// Reinitialised every time the PLC starts (the default, or
+    /// `NON_RETAIN`).
+    Volatile,
+    /// Kept across a warm restart (e.g. a power cycle).
+    Retain,
+    /// Kept across a cold restart (e.g. downloading a new program).
+    Persistent,
+    RetainPersistent,
+}
+
+impl Retention {
+    /// Should variables with this retention be saved and restored by the
+    /// runtime?
+    pub fn is_retained(self) -> bool {
+        self != Retention::Volatile
+    }
+}
+
+impl Default for Retention {
+    fn default() -> Retention {
+        Retention::Volatile
+    }
+}
+
+/// The top-level description of a PLC, tying programs to the resources and
+/// tasks they execute on.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, HeapSizeOf)]
+pub struct Configuration {
+    pub name: Identifier,
+    pub globals: Vec<VarBlock>,
+    pub resources: Vec<Resource>,
+    pub span: ByteSpan,
+}
+
+/// Something capable of executing programs (e.g. a CPU), optionally tied to
+/// a particular processor with `RESOURCE name ON processor`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, HeapSizeOf)]
+pub struct Resource {
+    pub name: Identifier,
+    pub processor: Option<Identifier>,
+    pub globals: Vec<VarBlock>,
+    pub tasks: Vec<Task>,
+    pub programs: Vec<ProgramInstance>,
+    pub span: ByteSpan,
+}
+
+/// A `TASK name(INTERVAL := T#10ms, PRIORITY := 1);` declaration.
+///
+/// The properties are kept as written and checked during semantic analysis.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, HeapSizeOf)]
+pub struct Task {
+    pub name: Identifier,
+    pub properties: Vec<TaskProperty>,
+    pub span: ByteSpan,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, HeapSizeOf)]
+pub struct TaskProperty {
+    pub name: Identifier,
+    pub value: TaskValue,
+    pub span: ByteSpan,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, HeapSizeOf)]
+pub enum TaskValue {
+    Time(TimeLiteral),
+    Literal(Literal),
+    Variable(DottedIdentifier),
+}
+
+/// A duration literal, such as `T#1s` or `TIME#1h_30m`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, HeapSizeOf)]
+pub struct TimeLiteral {
+    pub nanoseconds: u64,
+    pub span: ByteSpan,
+}
+
+impl TimeLiteral {
+    pub fn as_duration(&self) -> Duration {
+        Duration::from_nanos(self.nanoseconds)
+    }
+}
+
+/// Creating an instance of a program (`PROGRAM name WITH task : Type;`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, HeapSizeOf)]
+pub struct ProgramInstance {
+    pub name: Identifier,
+    pub task: Option<Identifier>,
+    pub program: Identifier,
+    pub span: ByteSpan,
 }
 
 macro_rules! impl_ast_node {
@@ -325,12 +568,23 @@ impl_ast_node!(
     Function,
     DottedIdentifier,
     IfStatement,
+    OutputAssignment,
+    Configuration,
+    Resource,
+    Task,
+    TaskProperty,
+    TimeLiteral,
+    ProgramInstance,
+    DirectAddress,
 );
-impl_ast_node!(Item => Function | FunctionBlock | Program);
-impl_ast_node!(Expression => Literal | Binary | Unary | Variable | FunctionCall);
+impl_ast_node!(Item => Function | FunctionBlock | Program | VarBlock |
+    Configuration);
+impl_ast_node!(Expression => Literal | Binary | Unary | Variable | FunctionCall |
+    DirectAddress);
 impl_ast_node!(Statement => FunctionCall | Assignment | Return | ForLoop |
     WhileLoop | RepeatLoop | Exit | IfStatement);
-impl_ast_node!(FunctionArg => Bare | Named);
+impl_ast_node!(FunctionArg => Bare | Named | Output);
+impl_ast_node!(TaskValue => Time | Literal | Variable);
 
 #[cfg(test)]
 mod tests {
@@ -356,6 +610,7 @@ mod tests {
     parse_test!(example_decl, DeclParser, "x: Bool" => Declaration {
         ident: Identifier { value: "x".to_string(), span: s(0, 1) },
         ty: Identifier { value: "Bool".to_string(), span: s(3, 7) },
+        location: None,
         span: s(0, 7),
     });
 
@@ -387,6 +642,148 @@ mod tests {
         span: s(0, 19),
     }));
 
+    parse_test!(function_call_with_output, ExprParser, "foo(x => y.z)" => Expression::FunctionCall(FunctionCall {
+        name: Identifier { value: "foo".to_string(), span: s(0, 3) },
+        args: vec![
+            FunctionArg::Output(OutputAssignment {
+                parameter: Identifier { value: "x".to_string(), span: s(4, 5) },
+                variable: DottedIdentifier {
+                    pieces: vec![
+                        Identifier { value: "y".to_string(), span: s(9, 10) },
+                        Identifier { value: "z".to_string(), span: s(11, 12) },
+                    ],
+                    span: s(9, 12),
+                },
+                span: s(4, 12),
+            }),
+        ],
+        span: s(0, 13),
+    }));
+
+    parse_test!(in_out_var_block, BlockParser, "var_in_out i: INT; end_var" => VarBlock {
+        kind: VarBlockKind::InputOutput,
+        retention: Retention::Volatile,
+        declarations: vec![Declaration {
+            ident: Identifier {
+                value: String::from("i"),
+                span: s(11, 12),
+            },
+            ty: Identifier {
+                value: String::from("INT"),
+                span: s(14, 17),
+            },
+            location: None,
+            span: s(11, 17),
+        }],
+        span: s(0, 26),
+    });
+
+    parse_test!(temp_var_block, BlockParser, "var_temp i: INT; end_var" => VarBlock {
+        kind: VarBlockKind::Temp,
+        retention: Retention::Volatile,
+        declarations: vec![Declaration {
+            ident: Identifier {
+                value: String::from("i"),
+                span: s(9, 10),
+            },
+            ty: Identifier {
+                value: String::from("INT"),
+                span: s(12, 15),
+            },
+            location: None,
+            span: s(9, 15),
+        }],
+        span: s(0, 24),
+    });
+
+    parse_test!(global_var_block, FileParser, "var_global i: INT; end_var" => File {
+        items: vec![Item::VarBlock(VarBlock {
+            kind: VarBlockKind::Global,
+            retention: Retention::Volatile,
+            declarations: vec![Declaration {
+                ident: Identifier {
+                    value: String::from("i"),
+                    span: s(11, 12),
+                },
+                ty: Identifier {
+                    value: String::from("INT"),
+                    span: s(14, 17),
+                },
+                location: None,
+                span: s(11, 17),
+            }],
+            span: s(0, 26),
+        })],
+        span: s(0, 26),
+    });
+
+    parse_test!(located_decl, DeclParser, "start AT %IX0.7 : BOOL" => Declaration {
+        ident: Identifier { value: "start".to_string(), span: s(0, 5) },
+        ty: Identifier { value: "BOOL".to_string(), span: s(18, 22) },
+        location: Some(DirectAddress {
+            area: Area::Input,
+            size: Size::Bit,
+            indices: vec![0, 7],
+            span: s(9, 15),
+        }),
+        span: s(0, 22),
+    });
+
+    parse_test!(direct_address_expression, ExprParser, "%mw10" => Expression::DirectAddress(DirectAddress {
+        area: Area::Memory,
+        size: Size::Word,
+        indices: vec![10],
+        span: s(0, 5),
+    }));
+
+    parse_test!(assign_to_direct_address, AssignmentParser, "%QX0.1 := TRUE" => Assignment {
+        variable: Identifier { value: "%QX0.1".to_string(), span: s(0, 6) }.into(),
+        value: Expression::Literal(Literal::new(true, s(10, 14))),
+        span: s(0, 14),
+    });
+
+    parse_test!(time_literal,TimeLiteralParser, "T#1m_30s" => TimeLiteral {
+        nanoseconds: 90_000_000_000,
+        span: s(0, 8),
+    });
+
+    parse_test!(task_declaration, TaskParser, "TASK fast(INTERVAL := T#10ms, PRIORITY := 1);" => Task {
+        name: Identifier { value: "fast".to_string(), span: s(5, 9) },
+        properties: vec![
+            TaskProperty {
+                name: Identifier { value: "INTERVAL".to_string(), span: s(10, 18) },
+                value: TaskValue::Time(TimeLiteral { nanoseconds: 10_000_000, span: s(22, 28) }),
+                span: s(10, 28),
+            },
+            TaskProperty {
+                name: Identifier { value: "PRIORITY".to_string(), span: s(30, 38) },
+                value: TaskValue::Literal(Literal::new(1, s(42, 43))),
+                span: s(30, 43),
+            },
+        ],
+        span: s(0, 45),
+    });
+
+    parse_test!(configuration_with_program_instance, ConfigurationParser,
+        "CONFIGURATION plc RESOURCE cpu ON x86 PROGRAM main WITH fast : Main; END_RESOURCE END_CONFIGURATION" => Configuration {
+        name: Identifier { value: "plc".to_string(), span: s(14, 17) },
+        globals: Vec::new(),
+        resources: vec![Resource {
+            name: Identifier { value: "cpu".to_string(), span: s(27, 30) },
+            processor: Some(Identifier { value: "x86".to_string(), span: s(34, 37) }),
+            globals: Vec::new(),
+            tasks: Vec::new(),
+            programs: vec![ProgramInstance {
+                name: Identifier { value: "main".to_string(), span: s(46, 50) },
+                task: Some(Identifier { value: "fast".to_string(), span: s(56, 60) }),
+                program: Identifier { value: "Main".to_string(), span: s(63, 67) },
+                span: s(38, 68),
+            }],
+            span: s(18, 81),
+        }],
+        span: s(0, 99),
+    });
+
     parse_test!(binary_op, ExprParser, "5+5" => Expression::Binary(BinaryExpression {
         left: Box::new(Expression::Literal(Literal {
             kind: LiteralKind::Integer(5),
@@ -512,6 +909,7 @@ mod tests {
 
     parse_test!(single_var_block, BlockParser, "var i: INT; end_var" => VarBlock {
         kind: VarBlockKind::Local,
+        retention: Retention::Volatile,
         declarations: vec![Declaration {
             ident: Identifier {
                 value: String::from("i"),
@@ -521,11 +919,30 @@ mod tests {
                 value: String::from("INT"),
                 span: s(7, 10),
             },
+            location: None,
             span: s(4, 10),
         }],
         span: s(0, 19),
     });
 
+    parse_test!(retained_var_block, BlockParser, "var retain persistent i: INT; end_var" => VarBlock {
+        kind: VarBlockKind::Local,
+        retention: Retention::RetainPersistent,
+        declarations: vec![Declaration {
+            ident: Identifier {
+                value: String::from("i"),
+                span: s(22, 23),
+            },
+            ty: Identifier {
+                value: String::from("INT"),
+                span: s(25, 28),
+            },
+            location: None,
+            span: s(22, 28),
+        }],
+        span: s(0, 37),
+    });
+
     const EXAMPLE_PROGRAM: &str = "
 PROGRAM main
     VAR
@@ -547,6 +964,7 @@ END_PROGRAM";
             },
             var_blocks: vec![VarBlock {
                 kind: VarBlockKind::Local,
+                retention: Retention::Volatile,
                 declarations: vec![Declaration {
                     ident: Identifier {
                         value: String::from("i"),
@@ -556,6 +974,7 @@ END_PROGRAM";
                         value: String::from("INT"),
                         span: s(34, 37),
                     },
+                    location: None,
                     span: s(30, 37),
                 }],
                 span: s(18, 50),
@@ -1,6 +1,3 @@
-#[macro_use]
-extern crate lalrpop_util;
-
 #[macro_export]
 macro_rules! defer {
     ($value:expr, $ty:ident, $( $variant:ident )|* => |$name:ident| $eval:block) => {
@@ -23,10 +20,77 @@ pub type ParseError = lalrpop_util::ParseError<ByteIndex, String, &'static str>;
 
 lalrpop_util::lalrpop_mod!(
     #[allow(dead_code)]
-    grammar
+    generated,
+    "/grammar.rs"
 );
 
-use codespan::ByteIndex;
+/// The parsers generated from `grammar.lalrpop`.
+///
+/// Spans are normally relative to the start of the text being parsed, use
+/// `parse_at()` when that text is part of something bigger (e.g. a file in a
+/// [`codespan::CodeMap`]).
+#[allow(dead_code)]
+mod grammar {
+    use crate::ast::*;
+    pub use crate::generated::Token;
+
+    pub type ParseError<'input> =
+        lalrpop_util::ParseError<usize, Token<'input>, &'static str>;
+
+    macro_rules! parsers {
+        ($( $parser:ident => $output:ty; )*) => {
+            $(
+                pub struct $parser(crate::generated::$parser);
+
+                impl $parser {
+                    pub fn new() -> $parser {
+                        $parser(crate::generated::$parser::new())
+                    }
+
+                    pub fn parse<'input>(
+                        &self,
+                        input: &'input str,
+                    ) -> Result<$output, ParseError<'input>> {
+                        self.parse_at(0, input)
+                    }
+
+                    /// Parse some text which starts `offset` bytes in,
+                    /// shifting spans and error locations accordingly.
+                    pub fn parse_at<'input>(
+                        &self,
+                        offset: usize,
+                        input: &'input str,
+                    ) -> Result<$output, ParseError<'input>> {
+                        self.0
+                            .parse(offset, input)
+                            .map_err(|e| e.map_location(|loc| offset + loc))
+                    }
+                }
+            )*
+        };
+    }
+
+    parsers! {
+        AssignmentParser => Assignment;
+        BlockParser => VarBlock;
+        ConfigurationParser => Configuration;
+        DeclParser => Declaration;
+        DirectAddressParser => DirectAddress;
+        ExprParser => Expression;
+        FileParser => File;
+        IdentParser => Identifier;
+        IfParser => IfStatement;
+        IterationStatementParser => Statement;
+        LitParser => Literal;
+        ProgramParser => Program;
+        StatementsParser => Vec<Statement>;
+        StmtParser => Statement;
+        TaskParser => Task;
+        TimeLiteralParser => TimeLiteral;
+    }
+}
+
+use codespan::{ByteIndex, ByteSpan, FileMap};
 
 macro_rules! impl_from_str {
     ($name:ident => $parser:ident) => {
@@ -54,3 +118,92 @@ impl_from_str! {
     Expression => ExprParser;
     Statement => StmtParser;
 }
+
+impl File {
+    /// Parse a file which was added to a [`codespan::CodeMap`], so every span
+    /// (and the location of any parse error) lines up with the file's
+    /// position in the [`codespan::CodeMap`].
+    pub fn from_filemap<S: AsRef<str>>(
+        filemap: &FileMap<S>,
+    ) -> Result<File, ParseError> {
+        let offset = filemap.span().start().0 as usize;
+
+        grammar::FileParser::new()
+            .parse_at(offset, filemap.src())
+            .map_err(|e| e.map_location(|loc| ByteIndex(loc as u32)))
+            .map_err(|e| e.map_token(|tok| tok.to_string()))
+    }
+
+    /// Combine several files into one (e.g. all the files in a project) so
+    /// they can be compiled together.
+    ///
+    /// The files should come from the same [`codespan::CodeMap`] (see
+    /// [`File::from_filemap()`]), otherwise their spans will overlap.
+    pub fn merge<I>(files: I) -> File
+    where
+        I: IntoIterator<Item = File>,
+    {
+        let mut items = Vec::new();
+        let mut span: Option<ByteSpan> = None;
+
+        for file in files {
+            items.extend(file.items);
+            span = Some(match span {
+                Some(span) => span.to(file.span),
+                None => file.span,
+            });
+        }
+
+        File {
+            items,
+            span: span.unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codespan::CodeMap;
+
+    #[test]
+    fn spans_are_relative_to_the_codemap() {
+        let mut map = CodeMap::new();
+        let first =
+            map.add_filemap("first.st".into(), "PROGRAM a END_PROGRAM".into());
+        let second =
+            map.add_filemap("second.st".into(), "PROGRAM b END_PROGRAM".into());
+
+        let a = File::from_filemap(&first).unwrap();
+        let b = File::from_filemap(&second).unwrap();
+
+        assert_eq!(a.span, first.span());
+        assert_eq!(b.span, second.span());
+        let name = match b.items[0] {
+            Item::Program(ref p) => p.name.span,
+            _ => unreachable!(),
+        };
+        assert_eq!(second.src_slice(name).unwrap(), "b");
+
+        let merged = File::merge(vec![a, b]);
+        assert_eq!(merged.items.len(), 2);
+        assert_eq!(merged.span, first.span().to(second.span()));
+    }
+
+    #[test]
+    fn parse_errors_point_into_the_right_file() {
+        let mut map = CodeMap::new();
+        map.add_filemap("first.st".into(), "PROGRAM a END_PROGRAM".into());
+        let second = map.add_filemap("second.st".into(), "PROGRAM ;".into());
+
+        let location = match File::from_filemap(&second).unwrap_err() {
+            lalrpop_util::ParseError::UnrecognizedToken {
+                token: Some((start, _, _)),
+                ..
+            } => start,
+            other => panic!("Unexpected error: {:?}", other),
+        };
+
+        assert_eq!(location, second.span().start() + codespan::ByteOffset(8));
+    }
+}
@@ -52,6 +52,7 @@ macro_rules! quote {
     };
     (var { $($tail:tt)* }) => {
         $crate::VarBlock {
+            retention: $crate::Retention::Volatile,
             kind: $crate::VarBlockKind::Local,
             declarations: $crate::quote!($($tail)*),
             span: Default::default(),
@@ -84,6 +85,7 @@ macro_rules! quote {
         $crate::Declaration {
             ident: $crate::quote!(@IDENT $name),
             ty: $crate::quote!(@IDENT $type),
+            location: None,
             span: Default::default(),
         }
     };
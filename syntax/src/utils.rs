@@ -1,4 +1,7 @@
-use crate::{BinOp, BinaryExpression, Expression, UnaryExpression, UnaryOp};
+use crate::{
+    Area, BinOp, BinaryExpression, DirectAddress, Expression, Size,
+    UnaryExpression, UnaryOp,
+};
 use codespan::{ByteIndex, ByteSpan};
 
 pub(crate) fn s(start: usize, end: usize) -> ByteSpan {
@@ -30,3 +33,141 @@ where
         span,
     })
 }
+
+/// Parse the text of a time literal (e.g. `T#1h_30m` or `TIME#1.5s`) into a
+/// number of nanoseconds.
+pub(crate) fn parse_time(text: &str) -> Result<u64, &'static str> {
+    const UNITS: &[(&str, f64)] = &[
+        ("ms", 1e6),
+        ("us", 1e3),
+        ("ns", 1.0),
+        ("d", 86_400e9),
+        ("h", 3_600e9),
+        ("m", 60e9),
+        ("s", 1e9),
+    ];
+
+    let body = match text.find('#') {
+        Some(ix) => &text[ix + 1..],
+        None => return Err("Time literals must start with T# or TIME#"),
+    };
+    let mut rest: String = body
+        .chars()
+        .filter(|&c| c != '_')
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    if rest.is_empty() {
+        return Err("Time literals need at least one component");
+    }
+
+    let mut total = 0.0;
+
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .ok_or("Time literal components need a unit")?;
+        let number: f64 = rest[..digits]
+            .parse()
+            .map_err(|_| "Invalid number in time literal")?;
+
+        let after = &rest[digits..];
+        let &(unit, scale) = UNITS
+            .iter()
+            .find(|(unit, _)| after.starts_with(unit))
+            .ok_or("Unknown unit in time literal")?;
+
+        total += number * scale;
+        rest = after[unit.len()..].to_string();
+    }
+
+    if total > u64::MAX as f64 {
+        return Err("Time literal is too large");
+    }
+
+    Ok(total.round() as u64)
+}
+
+/// Parse the text of a directly represented variable (e.g. `%IX0.7`).
+pub(crate) fn parse_direct_address(
+    text: &str,
+    span: ByteSpan,
+) -> Result<DirectAddress, &'static str> {
+    let text = text.trim_start_matches('%').to_ascii_uppercase();
+    let mut chars = text.chars();
+
+    let area = match chars.next() {
+        Some('I') => Area::Input,
+        Some('Q') => Area::Output,
+        Some('M') => Area::Memory,
+        _ => return Err("Direct addresses must start with %I, %Q, or %M"),
+    };
+
+    let rest = chars.as_str();
+    let (size, rest) = match rest.chars().next() {
+        Some('X') => (Size::Bit, &rest[1..]),
+        Some('B') => (Size::Byte, &rest[1..]),
+        Some('W') => (Size::Word, &rest[1..]),
+        Some('D') => (Size::DoubleWord, &rest[1..]),
+        Some('L') => (Size::LongWord, &rest[1..]),
+        // addresses without a size prefix refer to a single bit
+        _ => (Size::Bit, rest),
+    };
+
+    let indices = rest
+        .split('.')
+        .map(|index| index.parse())
+        .collect::<Result<Vec<u32>, _>>()
+        .map_err(|_| "Invalid index in direct address")?;
+
+    Ok(DirectAddress {
+        area,
+        size,
+        indices,
+        span,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_time_literals() {
+        let inputs = vec![
+            ("T#1s", Ok(1_000_000_000)),
+            ("t#100ms", Ok(100_000_000)),
+            ("TIME#1h_30m", Ok(5_400_000_000_000)),
+            ("T#1.5s", Ok(1_500_000_000)),
+            ("T#1d2h3m4s5ms6us7ns", Ok(93_784_005_006_007)),
+            ("T#5", Err("Time literal components need a unit")),
+            ("T#5x", Err("Unknown unit in time literal")),
+            ("T#", Err("Time literals need at least one component")),
+        ];
+
+        for (src, should_be) in inputs {
+            assert_eq!(parse_time(src), should_be, "{}", src);
+        }
+    }
+
+    #[test]
+    fn parse_direct_addresses() {
+        let inputs = vec![
+            ("%IX0.7", Area::Input, Size::Bit, vec![0, 7]),
+            ("%i1.2", Area::Input, Size::Bit, vec![1, 2]),
+            ("%QW4", Area::Output, Size::Word, vec![4]),
+            ("%MD10", Area::Memory, Size::DoubleWord, vec![10]),
+            ("%qb3", Area::Output, Size::Byte, vec![3]),
+        ];
+
+        for (src, area, size, indices) in inputs {
+            let got = parse_direct_address(src, ByteSpan::default()).unwrap();
+            assert_eq!(got.area, area, "{}", src);
+            assert_eq!(got.size, size, "{}", src);
+            assert_eq!(got.indices, indices, "{}", src);
+        }
+
+        let err = parse_direct_address("%IX99999999999", ByteSpan::default());
+        assert_eq!(err, Err("Invalid index in direct address"));
+    }
+}